@@ -0,0 +1,169 @@
+// Native benchmarks for the ledger hot paths. Stable memory is backed by an
+// in-memory vector off-wasm, so these measure the pure-Rust cost only.
+//
+//   cargo bench --bench hot_paths
+//
+// Baselines are recorded in docs/BENCHMARKS.md.
+
+use candid::{Nat, Principal};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use icrc151::transaction::StoredTxV1;
+use icrc151::types::{hash_allowance_key, hash_balance_key, Account, StoredTokenMetadata};
+use icrc151::{runtime, state, Icrc151TransferArgs};
+
+
+fn account(id: u32) -> Account {
+    let mut bytes = [0u8; 10];
+    bytes[6..10].copy_from_slice(&id.to_be_bytes());
+    Account {
+        owner: Principal::from_slice(&bytes),
+        subaccount: None,
+    }
+}
+
+
+fn metadata(fee: u128) -> StoredTokenMetadata {
+    StoredTokenMetadata {
+        name: "Benchmark Token".to_string(),
+        symbol: "BENCH".to_string(),
+        decimals: 8,
+        total_supply: 0,
+        fee,
+        fee_recipient: account(u32::MAX),
+        logo: Some("data:image/png;base64,iVBORw0KGgo=".to_string()),
+        description: Some("Token used by the native benchmark suite".to_string()),
+        created_at: 0,
+        controller: account(u32::MAX).owner,
+        fee_token: None,
+        require_explicit_fee: None,
+        fee_free_consolidation: None,
+        immutable: None,
+        scheduled_fee: None,
+        burn_addresses: None,
+        non_circulating_accounts: None,
+        rounding_mode: None,
+        structured_memos_required: None,
+        max_holders: None,
+    }
+}
+
+
+fn bench_key_derivation(c: &mut Criterion) {
+    let token_id = [7u8; 32];
+    let owner = account(1);
+    let spender = Account {
+        owner: account(2).owner,
+        subaccount: Some(vec![3u8; 32]),
+    };
+    let owner_key = owner.to_key();
+    let spender_key = spender.to_key();
+
+    let mut group = c.benchmark_group("key_derivation");
+    group.bench_function("account_to_key", |b| b.iter(|| black_box(&spender).to_key()));
+    group.bench_function("hash_balance_key", |b| {
+        b.iter(|| hash_balance_key(black_box(token_id), black_box(owner_key)))
+    });
+    group.bench_function("hash_allowance_key", |b| {
+        b.iter(|| hash_allowance_key(black_box(token_id), black_box(owner_key), black_box(spender_key)))
+    });
+    group.finish();
+}
+
+
+fn bench_encoding(c: &mut Criterion) {
+    let tx = StoredTxV1::new_transfer([7u8; 32], [1u8; 32], [2u8; 32], 1_000, 10, 1_700_000_000_000_000_000, Some(b"invoice 42"));
+    let tx_bytes = tx.to_bytes();
+    let meta = metadata(10);
+    let meta_bytes = meta.encode();
+
+    let mut group = c.benchmark_group("encoding");
+    group.bench_function("stored_tx_encode", |b| b.iter(|| black_box(&tx).to_bytes()));
+    group.bench_function("stored_tx_decode", |b| {
+        b.iter(|| StoredTxV1::from_bytes(black_box(&tx_bytes)))
+    });
+    group.bench_function("metadata_encode", |b| b.iter(|| black_box(&meta).encode()));
+    group.bench_function("metadata_decode", |b| {
+        b.iter(|| StoredTokenMetadata::decode(black_box(&meta_bytes)).unwrap())
+    });
+    group.finish();
+}
+
+
+// Map sizes grow cumulatively within one process since the state is global.
+fn bench_balances(c: &mut Criterion) {
+    let token_id = [9u8; 32];
+    let mut populated = 0u32;
+
+    state::register_token(token_id, metadata(0));
+    let mut group = c.benchmark_group("balances");
+    for size in [10_000u32, 100_000, 1_000_000] {
+        for id in populated..size {
+            state::apply_movements(token_id, &[], &[(account(id).to_key(), 1_000)], 1_000).unwrap();
+        }
+        populated = size;
+
+        let probe = account(size / 2).to_key();
+        group.bench_with_input(BenchmarkId::new("get_balance", size), &probe, |b, key| {
+            b.iter(|| state::get_balance(token_id, *key))
+        });
+        let peer = account(size / 2 + 1).to_key();
+        group.bench_with_input(BenchmarkId::new("apply_movements", size), &probe, |b, key| {
+            let mut forward = true;
+            b.iter(|| {
+                let (from, to) = if forward { (*key, peer) } else { (peer, *key) };
+                forward = !forward;
+                state::apply_movements(token_id, &[(from, 1)], &[(to, 1)], 0).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+
+fn bench_transfer(c: &mut Criterion) {
+    let token_id = [11u8; 32];
+    let sender = account(1);
+    state::register_token(token_id, metadata(10));
+    state::apply_movements(token_id, &[], &[(sender.to_key(), u128::MAX / 4)], (u128::MAX / 4) as i128).unwrap();
+    runtime::set_caller(sender.owner);
+
+    let probe = Icrc151TransferArgs {
+        token_id,
+        from_subaccount: None,
+        to: account(2),
+        amount: Nat::from(100u64),
+        fee: Some(Nat::from(10u64)),
+        memo: None,
+        created_at_time: None,
+        private_memo: None,
+        amount_mode: None, use_sponsorship: None, args_version: None,
+    };
+    assert!(matches!(icrc151::transfer(probe), icrc151::TransferResult::Ok(_)));
+
+    let mut recipient = 0u32;
+    c.bench_function("transfer", |b| {
+        b.iter_batched(
+            || {
+                recipient = recipient.wrapping_add(1);
+                runtime::advance_time(1);
+                Icrc151TransferArgs {
+                    token_id,
+                    from_subaccount: None,
+                    to: account(1_000_000 + (recipient % 1_000)),
+                    amount: Nat::from(100u64),
+                    fee: Some(Nat::from(10u64)),
+                    memo: None,
+                    created_at_time: None,
+                    private_memo: None,
+                    amount_mode: None, use_sponsorship: None, args_version: None,
+                }
+            },
+            icrc151::transfer,
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+
+criterion_group!(benches, bench_key_derivation, bench_encoding, bench_balances, bench_transfer);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+use sha2::{Digest, Sha256};
+use std::{env, fs, path::Path};
+
+
+const CANDID_PATH: &str = "candid/icrc151.did";
+
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", CANDID_PATH);
+    println!("cargo:rerun-if-env-changed=ICRC151_GIT_COMMIT");
+
+    let candid = fs::read(CANDID_PATH).expect("candid/icrc151.did is missing");
+    let hash: [u8; 32] = Sha256::digest(&candid).into();
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("build_info.rs");
+    fs::write(out, format!("pub const CANDID_HASH: [u8; 32] = {:?};\n", hash)).unwrap();
+}
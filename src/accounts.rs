@@ -0,0 +1,424 @@
+use crate::types::{constants, Account, AccountKey, IncomingPolicy, IncomingPolicyConfig, OfficialAccount, Subaccount, TokenId};
+use crate::state;
+use crate::runtime;
+use crate::validation::validate_account;
+use candid::{CandidType, Nat};
+use serde::{Deserialize, Serialize};
+
+
+pub const MAX_PURGE_ACCOUNTS: usize = 10;
+// Allowance entries inspected per direction; accounts with more must run
+// cleanup_my_allowances first.
+const MAX_PURGE_ALLOWANCE_ENTRIES: usize = 500;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PurgeBlocker {
+    InvalidAccount(String),
+    NonZeroBalance { token_id: TokenId, balance: Nat },
+    FeeRecipient { token_id: TokenId },
+    ProtocolTreasury,
+    AllowanceGranted { token_id: TokenId, spender: Option<Account> },
+    AllowanceReceived { token_id: TokenId, owner: Option<Account> },
+    TooManyAllowanceEntries,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PurgeReport {
+    pub account: Account,
+    pub purged: bool,
+    pub registry_entry_removed: bool,
+    pub allowance_entries_removed: u64,
+    pub spender_subaccount_removed: bool,
+    pub blockers: Vec<PurgeBlocker>,
+}
+
+
+// Removes ledger-side state about the caller's empty accounts. Transaction
+// history is immutable and is never touched.
+#[ic_cdk::update]
+pub fn purge_my_account(subaccounts: Vec<Option<Subaccount>>) -> Result<Vec<PurgeReport>, String> {
+    if subaccounts.is_empty() {
+        return Err("At least one subaccount is required".to_string());
+    }
+    if subaccounts.len() > MAX_PURGE_ACCOUNTS {
+        return Err(format!("Too many subaccounts (max {})", MAX_PURGE_ACCOUNTS));
+    }
+
+    let caller = runtime::caller();
+    Ok(subaccounts
+        .into_iter()
+        .map(|subaccount| purge_account_internal(&Account { owner: caller, subaccount: subaccount.map(|s| s.to_vec()) }))
+        .collect())
+}
+
+
+fn purge_account_internal(account: &Account) -> PurgeReport {
+    let mut report = PurgeReport {
+        account: account.clone(),
+        purged: false,
+        registry_entry_removed: false,
+        allowance_entries_removed: 0,
+        spender_subaccount_removed: false,
+        blockers: Vec::new(),
+    };
+
+    if let Err(e) = validate_account(account) {
+        report.blockers.push(PurgeBlocker::InvalidAccount(e.to_string()));
+        return report;
+    }
+
+    let account_key = account.to_key();
+    let granted = state::list_owner_allowances_all_tokens(account_key, MAX_PURGE_ALLOWANCE_ENTRIES + 1);
+    let received = state::list_spender_allowances(account_key, None, None, MAX_PURGE_ALLOWANCE_ENTRIES + 1);
+    report.blockers = purge_blockers(account_key, &granted, &received);
+    if !report.blockers.is_empty() {
+        return report;
+    }
+
+    // Every remaining entry is expired, so removing it cannot move funds.
+    for (token_id, spender_key) in &granted {
+        remove_dead_allowance(*token_id, account_key, *spender_key);
+        report.allowance_entries_removed += 1;
+    }
+    for (token_id, owner_key) in &received {
+        remove_dead_allowance(*token_id, *owner_key, account_key);
+        report.allowance_entries_removed += 1;
+    }
+    state::add_allowances_cleaned_up(report.allowance_entries_removed);
+
+    report.spender_subaccount_removed = state::remove_spender_subaccount(account);
+    report.registry_entry_removed = state::remove_registered_account(account_key);
+    report.purged = true;
+    report
+}
+
+
+fn purge_blockers(
+    account_key: AccountKey,
+    granted: &[(TokenId, AccountKey)],
+    received: &[(TokenId, AccountKey)],
+) -> Vec<PurgeBlocker> {
+    let mut blockers = Vec::new();
+
+    for token_id in state::list_token_ids() {
+        let balance = state::get_balance(token_id, account_key);
+        if balance > 0 {
+            blockers.push(PurgeBlocker::NonZeroBalance { token_id, balance: Nat::from(balance) });
+        }
+        if let Ok(metadata) = state::get_token_metadata(token_id) {
+            if metadata.fee_recipient.to_key() == account_key {
+                blockers.push(PurgeBlocker::FeeRecipient { token_id });
+            }
+        }
+    }
+
+    if state::get_protocol_treasury().is_some_and(|t| t.to_key() == account_key) {
+        blockers.push(PurgeBlocker::ProtocolTreasury);
+    }
+
+    if granted.len() > MAX_PURGE_ALLOWANCE_ENTRIES || received.len() > MAX_PURGE_ALLOWANCE_ENTRIES {
+        blockers.push(PurgeBlocker::TooManyAllowanceEntries);
+        return blockers;
+    }
+
+    for (token_id, spender_key) in granted {
+        if allowance_is_active(*token_id, account_key, *spender_key) {
+            blockers.push(PurgeBlocker::AllowanceGranted {
+                token_id: *token_id,
+                spender: state::get_registered_account(*spender_key),
+            });
+        }
+    }
+    for (token_id, owner_key) in received {
+        if allowance_is_active(*token_id, *owner_key, account_key) {
+            blockers.push(PurgeBlocker::AllowanceReceived {
+                token_id: *token_id,
+                owner: state::get_registered_account(*owner_key),
+            });
+        }
+    }
+
+    blockers
+}
+
+
+fn allowance_is_active(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) -> bool {
+    state::get_allowance(token_id, owner_key, spender_key) > 0
+        && !state::is_allowance_expired(state::get_allowance_expiry(token_id, owner_key, spender_key))
+}
+
+
+fn remove_dead_allowance(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) {
+    state::set_allowance(token_id, owner_key, spender_key, 0);
+    state::remove_allowance_expiry(token_id, owner_key, spender_key);
+}
+
+
+// Applies to the exact account, so each subaccount has its own policy.
+#[ic_cdk::update]
+pub fn set_incoming_policy(subaccount: Option<Subaccount>, mut config: IncomingPolicyConfig) -> Result<(), String> {
+    let account = Account { owner: runtime::caller(), subaccount: subaccount.map(|s| s.to_vec()) };
+    validate_account(&account).map_err(|e| e.to_string())?;
+
+    if let IncomingPolicy::Allowlist(tokens) = &mut config.policy {
+        if tokens.len() > constants::MAX_INCOMING_ALLOWLIST {
+            return Err(format!("Too many allowlisted tokens (max {})", constants::MAX_INCOMING_ALLOWLIST));
+        }
+        tokens.sort();
+        tokens.dedup();
+    }
+
+    state::set_incoming_policy(account.to_key(), config);
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_incoming_policy(account: Account) -> IncomingPolicyConfig {
+    state::get_incoming_policy(account.to_key())
+}
+
+
+// Consulted before crediting `amount` of `token_id` to a recipient.
+pub(crate) fn accepts_incoming(token_id: TokenId, to_key: AccountKey, controller_mint: bool) -> bool {
+    if !official_account_accepts(token_id, to_key) {
+        return false;
+    }
+    state::get_incoming_policy(to_key).accepts(&token_id, state::get_balance(token_id, to_key) > 0, controller_mint)
+}
+
+
+// A protected official account takes only tokens its administrator controls,
+// so tokens dusted onto it cannot pass for the ledger's own.
+fn official_account_accepts(token_id: TokenId, to_key: AccountKey) -> bool {
+    match state::get_official_account(to_key) {
+        Some(official) if official.protected => state::get_token_metadata(token_id)
+            .is_ok_and(|metadata| metadata.controller == official.administrator),
+        _ => true,
+    }
+}
+
+
+fn describe_account(account: &Account) -> String {
+    match &account.subaccount {
+        Some(subaccount) => format!("{}.{}", account.owner, crate::allowances::hex_encode(subaccount)),
+        None => account.owner.to_string(),
+    }
+}
+
+
+// Registers or updates an official account such as a fee recipient or the
+// protocol treasury, with the caller as its administrator. Unlike an incoming
+// policy, which an account's owner sets, this is the controllers' registry.
+#[ic_cdk::update]
+pub fn set_official_account(account: Account, protected: bool) -> Result<(), String> {
+    state::require_controller()?;
+    validate_account(&account).map_err(|e| e.to_string())?;
+
+    let account_key = account.to_key();
+    let mut accounts = state::list_official_accounts();
+    let previous = accounts.iter().position(|o| o.account.to_key() == account_key).map(|i| accounts.remove(i));
+    if previous.is_none() && accounts.len() >= constants::MAX_OFFICIAL_ACCOUNTS {
+        return Err(format!("Too many official accounts (max {})", constants::MAX_OFFICIAL_ACCOUNTS));
+    }
+
+    let administrator = runtime::caller();
+    state::record_admin_action("set_official_account", format!(
+        "{}: protected {:?} -> {}, administrator {}",
+        describe_account(&account), previous.as_ref().map(|o| o.protected), protected, administrator
+    ));
+    accounts.push(OfficialAccount {
+        account,
+        administrator,
+        protected,
+        registered_at: previous.map_or_else(runtime::time, |o| o.registered_at),
+    });
+    state::set_official_accounts(accounts);
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn remove_official_account(account: Account) -> Result<(), String> {
+    state::require_controller()?;
+
+    let account_key = account.to_key();
+    let mut accounts = state::list_official_accounts();
+    let count = accounts.len();
+    accounts.retain(|o| o.account.to_key() != account_key);
+    if accounts.len() == count {
+        return Err("Not an official account".to_string());
+    }
+    state::set_official_accounts(accounts);
+    state::record_admin_action("remove_official_account", describe_account(&account));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn list_official_accounts() -> Vec<OfficialAccount> {
+    state::list_official_accounts()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowances::{approve, Icrc151ApproveArgs, ApproveResult};
+    use crate::test_utils::{register_test_token, subaccount_of, test_account, test_subaccount};
+
+    #[test]
+    fn test_incoming_policy() {
+        use crate::operations::{mint_tokens_v2, transfer_internal, TransferError};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let (held, other, listed) = (register_test_token(1, 0), register_test_token(2, 0), register_test_token(3, 0));
+        let sender = test_account(2);
+        let recipient = test_subaccount(3, 9);
+        for token in [held, other, listed] {
+            state::seed_balance(token, sender.to_key(), 1_000);
+        }
+        state::seed_balance(held, recipient.to_key(), 1);
+        assert_eq!(get_incoming_policy(recipient.clone()), IncomingPolicyConfig::default());
+        let send = |token: TokenId, to: &Account| {
+            runtime::advance_time(1);
+            transfer_internal(token, sender.clone(), to.clone(), 10, None, None, None)
+        };
+
+        runtime::set_caller(recipient.owner);
+        let deny_unknown = IncomingPolicyConfig { policy: IncomingPolicy::DenyUnknown, allow_controller_mints: false };
+        set_incoming_policy(subaccount_of(&recipient), deny_unknown.clone()).unwrap();
+        assert_eq!(get_incoming_policy(recipient.clone()), deny_unknown);
+        send(held, &recipient).unwrap();
+        assert!(matches!(
+            send(other, &recipient),
+            Err(TransferError::RecipientRefused)
+        ));
+        // The policy covers only that subaccount.
+        send(other, &test_account(3)).unwrap();
+
+        let allowlist = IncomingPolicy::Allowlist(vec![listed, listed]);
+        set_incoming_policy(subaccount_of(&recipient), IncomingPolicyConfig { policy: allowlist, allow_controller_mints: false }).unwrap();
+        assert_eq!(get_incoming_policy(recipient.clone()).policy, IncomingPolicy::Allowlist(vec![listed]));
+        send(listed, &recipient).unwrap();
+        assert!(send(held, &recipient).is_err());
+        let too_many = IncomingPolicy::Allowlist((0..=constants::MAX_INCOMING_ALLOWLIST as u32).map(|i| [i as u8; 32]).collect());
+        assert!(set_incoming_policy(None, IncomingPolicyConfig { policy: too_many, allow_controller_mints: false }).is_err());
+
+        // Mints bypass the policy only when the recipient allows it.
+        runtime::set_caller(controller);
+        assert!(mint_tokens_v2(other, recipient.clone(), Nat::from(5u64), None).is_err());
+        runtime::set_caller(recipient.owner);
+        set_incoming_policy(subaccount_of(&recipient), IncomingPolicyConfig { allow_controller_mints: true, ..get_incoming_policy(recipient.clone()) }).unwrap();
+        runtime::set_caller(controller);
+        mint_tokens_v2(other, recipient.clone(), Nat::from(5u64), None).unwrap();
+        assert_eq!(state::get_balance(other, recipient.to_key()), 5);
+
+        runtime::set_caller(recipient.owner);
+        set_incoming_policy(subaccount_of(&recipient), IncomingPolicyConfig::default()).unwrap();
+        send(held, &recipient).unwrap();
+    }
+
+    #[test]
+    fn test_protected_official_accounts() {
+        use crate::operations::{mint_tokens_v2, transfer_internal, TransferError};
+        use crate::test_utils::{fee_recipient, test_metadata};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let own = register_test_token(1, 0);
+        let foreign = [2; 32];
+        state::register_token(foreign, crate::types::StoredTokenMetadata { controller: test_account(9).owner, ..test_metadata(0) });
+        let (sender, user, treasury) = (test_account(2), test_account(3), test_subaccount(0xFE, 1));
+        for token in [own, foreign] {
+            state::seed_balance(token, sender.to_key(), 1_000);
+        }
+        let send = |token: TokenId, to: &Account| {
+            runtime::advance_time(1);
+            transfer_internal(token, sender.clone(), to.clone(), 10, None, None, None)
+        };
+
+        set_official_account(fee_recipient(), true).unwrap();
+        set_official_account(treasury.clone(), false).unwrap();
+        assert_eq!(list_official_accounts().len(), 2);
+        let entry = state::get_official_account(fee_recipient().to_key()).unwrap();
+        assert_eq!((entry.administrator, entry.protected), (controller, true));
+
+        send(own, &fee_recipient()).unwrap();
+        assert!(matches!(send(foreign, &fee_recipient()), Err(TransferError::RecipientRefused)));
+        assert!(mint_tokens_v2(foreign, fee_recipient(), Nat::from(5u64), None).is_err());
+        assert_eq!(state::get_balance(foreign, fee_recipient().to_key()), 0);
+        // Unprotected official accounts and ordinary accounts take any token.
+        send(foreign, &treasury).unwrap();
+        send(foreign, &user).unwrap();
+
+        // Protection can be switched per account, and survives an upgrade.
+        set_official_account(treasury.clone(), true).unwrap();
+        state::simulate_upgrade();
+        assert!(send(foreign, &treasury).is_err());
+        set_official_account(treasury.clone(), false).unwrap();
+        send(foreign, &treasury).unwrap();
+        let last = state::list_admin_actions(0, usize::MAX).pop().unwrap();
+        assert_eq!(last.action, "set_official_account");
+        assert!(last.detail.contains("protected Some(true) -> false"));
+
+        runtime::set_caller(user.owner);
+        assert!(set_official_account(user.clone(), true).is_err());
+        assert!(remove_official_account(fee_recipient()).is_err());
+        runtime::set_caller(controller);
+        remove_official_account(fee_recipient()).unwrap();
+        assert!(remove_official_account(fee_recipient()).is_err());
+        send(foreign, &fee_recipient()).unwrap();
+        assert_eq!(list_official_accounts().len(), 1);
+    }
+
+    #[test]
+    fn test_purge_my_account() {
+        let token = register_test_token(1, 0);
+        let owner = test_subaccount(2, 7);
+        let empty = test_account(2);
+        let spender = test_account(3);
+        runtime::set_caller(owner.owner);
+
+        state::register_account(&empty).unwrap();
+        state::seed_balance(token, owner.to_key(), 100);
+        let approve_args = Icrc151ApproveArgs {
+            token_id: token,
+            from_subaccount: subaccount_of(&owner),
+            spender: spender.clone(),
+            amount: candid::Nat::from(50u64),
+            expected_allowance: None,
+            expires_at: Some(runtime::time() + 10),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+            private_memo: None,
+            args_version: None,
+        };
+        assert!(matches!(approve(approve_args), ApproveResult::Ok(_)));
+
+        let reports = purge_my_account(vec![subaccount_of(&owner), None]).unwrap();
+        assert!(!reports[0].purged);
+        assert!(reports[0].blockers.contains(&PurgeBlocker::NonZeroBalance { token_id: token, balance: Nat::from(100u64) }));
+        assert!(reports[0].blockers.contains(&PurgeBlocker::AllowanceGranted { token_id: token, spender: Some(spender.clone()) }));
+        assert!(reports[1].purged);
+        assert!(reports[1].registry_entry_removed);
+        assert_eq!(state::get_registered_account(empty.to_key()), None);
+
+        // Once drained and the allowance has expired the account can go.
+        state::seed_balance(token, owner.to_key(), 0);
+        runtime::advance_time(20);
+        let reports = purge_my_account(vec![subaccount_of(&owner)]).unwrap();
+        assert!(reports[0].purged, "{:?}", reports[0].blockers);
+        assert_eq!(reports[0].allowance_entries_removed, 1);
+        assert_eq!(state::get_allowance_expiry(token, owner.to_key(), spender.to_key()), None);
+        assert!(state::list_spender_allowances(spender.to_key(), None, None, 10).is_empty());
+        assert_eq!(state::get_registered_account(owner.to_key()), None);
+
+        assert!(purge_my_account(vec![None; MAX_PURGE_ACCOUNTS + 1]).is_err());
+    }
+}
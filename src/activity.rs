@@ -0,0 +1,164 @@
+use crate::queries::{get_transactions, QueryError};
+use crate::runtime;
+use crate::state;
+use crate::transaction::StoredTxV1;
+use crate::types::constants;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+
+// How busy the ledger is, for bots that poll for new blocks. The IC cannot
+// hold a call open until a block arrives, so instead of waiting, pollers are
+// told how long to wait before asking again. The rate is a heap-only
+// exponentially weighted estimate: every upgrade starts it over from idle.
+
+
+// Time constant of the estimate: a burst stops counting after a few of these.
+pub const ACTIVITY_WINDOW_NS: u64 = 60 * 1_000_000_000;
+pub const MIN_POLL_DELAY_NS: u64 = 1_000_000_000;
+pub const MAX_POLL_DELAY_NS: u64 = 60 * 1_000_000_000;
+
+
+thread_local! {
+    // Blocks per second as of LAST_UPDATE.
+    static RATE: Cell<f64> = const { Cell::new(0.0) };
+    static LAST_UPDATE: Cell<u64> = const { Cell::new(0) };
+    static LAST_TX_AT: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ActivityEstimate {
+    pub tx_per_second: f64,
+    pub log_length: u64,
+    // Since the last upgrade.
+    pub last_tx_at: Option<u64>,
+    pub recommended_poll_delay_ns: u64,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum WaitForTxResult {
+    // Blocks from `after_index` on, at most one page.
+    Transactions(Vec<StoredTxV1>),
+    Empty { log_length: u64, retry_after_ns: u64 },
+}
+
+
+fn decayed_rate(now: u64) -> f64 {
+    let elapsed = now.saturating_sub(LAST_UPDATE.with(|l| l.get()));
+    RATE.with(|r| r.get()) * (-(elapsed as f64) / ACTIVITY_WINDOW_NS as f64).exp()
+}
+
+
+// Called for every block appended. Each block adds 1/window to the decayed
+// rate, so a steady stream settles at its true rate.
+pub(crate) fn record_block(now: u64) {
+    let rate = decayed_rate(now) + 1_000_000_000.0 / ACTIVITY_WINDOW_NS as f64;
+    RATE.with(|r| r.set(rate));
+    LAST_UPDATE.with(|l| l.set(now));
+    LAST_TX_AT.with(|l| l.set(Some(now)));
+}
+
+
+// The expected gap to the next block, within [MIN_POLL_DELAY_NS, MAX_POLL_DELAY_NS].
+fn recommended_delay(rate: f64) -> u64 {
+    if rate <= 0.0 {
+        return MAX_POLL_DELAY_NS;
+    }
+    let expected_gap_ns = 1_000_000_000.0 / rate;
+    (expected_gap_ns.min(MAX_POLL_DELAY_NS as f64) as u64).max(MIN_POLL_DELAY_NS)
+}
+
+
+#[ic_cdk::query]
+pub fn get_activity_estimate() -> ActivityEstimate {
+    let rate = decayed_rate(runtime::time());
+    ActivityEstimate {
+        tx_per_second: rate,
+        log_length: state::get_transaction_count(),
+        last_tx_at: LAST_TX_AT.with(|l| l.get()),
+        recommended_poll_delay_ns: recommended_delay(rate),
+    }
+}
+
+
+// `after_index` is the log length the caller has already seen. An update call,
+// so the answer is never behind a lagging replica; it returns at once either
+// way. `timeout_hint_ns`, when not 0, caps the suggested retry delay.
+#[ic_cdk::update]
+pub fn wait_for_tx(after_index: u64, timeout_hint_ns: u64) -> Result<WaitForTxResult, QueryError> {
+    let log_length = state::get_transaction_count();
+    if log_length > after_index {
+        let page = get_transactions(None, Some(after_index), Some(constants::MAX_TRANSACTIONS_PAGE), None, None)?;
+        return Ok(WaitForTxResult::Transactions(page));
+    }
+
+    let mut retry_after_ns = get_activity_estimate().recommended_poll_delay_ns;
+    if timeout_hint_ns > 0 {
+        retry_after_ns = retry_after_ns.min(timeout_hint_ns);
+    }
+    Ok(WaitForTxResult::Empty { log_length, retry_after_ns })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::transfer_internal;
+    use crate::test_utils::{register_test_token, test_account};
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn reset() {
+        RATE.with(|r| r.set(0.0));
+        LAST_UPDATE.with(|l| l.set(0));
+        LAST_TX_AT.with(|l| l.set(None));
+    }
+
+    #[test]
+    fn test_idle_and_busy_ledgers() {
+        reset();
+        let token = register_test_token(1, 0);
+        let (from, to) = (test_account(1), test_account(2));
+        state::seed_balance(token, from.to_key(), 1_000_000);
+
+        let idle = get_activity_estimate();
+        assert_eq!((idle.tx_per_second, idle.last_tx_at), (0.0, None));
+        assert_eq!(idle.recommended_poll_delay_ns, MAX_POLL_DELAY_NS);
+        let log_length = state::get_transaction_count();
+        match wait_for_tx(log_length, 0).unwrap() {
+            WaitForTxResult::Empty { log_length: seen, retry_after_ns } => {
+                assert_eq!((seen, retry_after_ns), (log_length, MAX_POLL_DELAY_NS));
+            }
+            other => panic!("idle ledger returned {:?}", other),
+        }
+        match wait_for_tx(log_length, 5 * SECOND).unwrap() {
+            WaitForTxResult::Empty { retry_after_ns, .. } => assert_eq!(retry_after_ns, 5 * SECOND),
+            other => panic!("idle ledger returned {:?}", other),
+        }
+
+        // Five blocks a second for two windows settles close to five a second.
+        for _ in 0..2 * 60 * 5 {
+            runtime::advance_time(SECOND / 5);
+            transfer_internal(token, from.clone(), to.clone(), 1, None, None, None).unwrap();
+        }
+        let busy = get_activity_estimate();
+        assert!((4.0..=5.5).contains(&busy.tx_per_second), "{}", busy.tx_per_second);
+        assert_eq!(busy.last_tx_at, Some(runtime::time()));
+        assert_eq!(busy.recommended_poll_delay_ns, MIN_POLL_DELAY_NS);
+
+        let WaitForTxResult::Transactions(blocks) = wait_for_tx(log_length, 0).unwrap() else { panic!("no blocks") };
+        assert_eq!(blocks.len(), 600);
+        let WaitForTxResult::Transactions(blocks) = wait_for_tx(busy.log_length - 2, 0).unwrap() else { panic!("no blocks") };
+        assert_eq!(blocks.len(), 2);
+
+        // Once traffic stops the estimate decays and the delay grows back.
+        runtime::advance_time(2 * ACTIVITY_WINDOW_NS);
+        let quieter = get_activity_estimate();
+        assert!(quieter.tx_per_second < busy.tx_per_second / 5.0);
+        assert!(quieter.recommended_poll_delay_ns > MIN_POLL_DELAY_NS);
+        runtime::advance_time(20 * ACTIVITY_WINDOW_NS);
+        assert_eq!(get_activity_estimate().recommended_poll_delay_ns, MAX_POLL_DELAY_NS);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::allowances::hex_encode;
+use crate::context::OperationContext;
+use crate::operations::{generic_error, TransferError};
+use crate::rounding::mul_div_rem;
+use crate::runtime;
+use crate::state;
+use crate::transaction::StoredTxV1;
+use crate::types::{Airdrop, AccountKey, OpKind, TokenId};
+use candid::CandidType;
+use num_traits::cast::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+
+// Rewards minted to the holders of a balance snapshot in proportion to what
+// they held, e.g. one REWARD per hundred GOV. Each recipient gets a mint of
+// its own, with the memo `icrc151:airdrop:<snapshot id>`, so the distribution
+// reads in the log like any other. An airdrop runs in chunks and resumes
+// where the last chunk stopped; once it completes, the same snapshot cannot
+// pay the same reward token again.
+
+
+pub const DEFAULT_AIRDROP_CHUNK: u32 = 200;
+pub const MAX_AIRDROP_CHUNK: u32 = 500;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum AirdropError {
+    // The snapshot has already paid this reward token.
+    AlreadyCompleted(Box<Airdrop>),
+    // The airdrop stopped somewhere else; resume from `expected`.
+    CursorMismatch { expected: Option<Vec<u8>> },
+    // Nothing in this chunk was minted, and the airdrop did not move on.
+    Rejected(TransferError),
+}
+
+impl From<TransferError> for AirdropError {
+    fn from(error: TransferError) -> Self {
+        AirdropError::Rejected(error)
+    }
+}
+
+
+fn to_u128(value: &candid::Nat, what: &str) -> Result<u128, TransferError> {
+    value.0.to_u128().ok_or_else(|| generic_error(400, format!("{} exceeds maximum value (u128::MAX)", what)))
+}
+
+
+// Adds what rounding down withheld from one recipient. Both remainders are
+// below the denominator, so their sum carries at most one whole unit.
+fn add_dust(airdrop: &mut Airdrop, remainder: u128) {
+    let (sum, carried) = airdrop.dust_remainder.overflowing_add(remainder);
+    if carried || sum >= airdrop.denominator {
+        airdrop.dust += 1;
+        airdrop.dust_remainder = sum.wrapping_sub(airdrop.denominator);
+    } else {
+        airdrop.dust_remainder = sum;
+    }
+}
+
+
+// The first call starts the airdrop with `cursor` None; every later one passes
+// the cursor the last returned and the same terms. A chunk mints to at most
+// `limit` snapshot entries and is applied whole or not at all: the reward
+// token's gates, holder limit and supply are checked for the chunk before
+// anything is credited. Holders below `min_holding`, owed less than one unit,
+// or whose incoming policy refuses controller mints are skipped.
+#[allow(clippy::too_many_arguments)]
+#[ic_cdk::update]
+pub fn execute_airdrop(
+    source_snapshot_id: u64,
+    source_token: TokenId,
+    reward_token: TokenId,
+    numerator: candid::Nat,
+    denominator: candid::Nat,
+    min_holding: candid::Nat,
+    cursor: Option<Vec<u8>>,
+    limit: Option<u32>,
+) -> Result<Airdrop, AirdropError> {
+    state::require_controller().map_err(|message| generic_error(403, message))?;
+    let numerator = to_u128(&numerator, "Numerator")?;
+    let denominator = to_u128(&denominator, "Denominator")?;
+    let min_holding = to_u128(&min_holding, "Minimum holding")?;
+    if numerator == 0 || denominator == 0 {
+        return Err(generic_error(400, "Numerator and denominator must be greater than 0").into());
+    }
+
+    let snapshot = state::get_balance_snapshot(source_snapshot_id)
+        .ok_or_else(|| generic_error(404, format!("No balance snapshot {}", source_snapshot_id)))?;
+    if snapshot.token_id != source_token {
+        return Err(generic_error(400, "The snapshot is of another token").into());
+    }
+    if !snapshot.complete {
+        return Err(generic_error(409, "The snapshot is still being copied").into());
+    }
+
+    let mut airdrop = match state::get_airdrop(source_snapshot_id, reward_token) {
+        Some(airdrop) if airdrop.completed_at.is_some() => return Err(AirdropError::AlreadyCompleted(Box::new(airdrop))),
+        Some(airdrop) => airdrop,
+        None => Airdrop {
+            snapshot_id: source_snapshot_id,
+            source_token,
+            reward_token,
+            numerator,
+            denominator,
+            min_holding,
+            cursor: None,
+            recipients: 0,
+            skipped: 0,
+            total_minted: 0,
+            dust: 0,
+            dust_remainder: 0,
+            started_at: runtime::time(),
+            completed_at: None,
+        },
+    };
+    if (airdrop.numerator, airdrop.denominator, airdrop.min_holding) != (numerator, denominator, min_holding) {
+        return Err(generic_error(409, "The airdrop was started with other terms").into());
+    }
+    if cursor.as_deref() != airdrop.cursor.as_ref().map(|c| c.as_slice()) {
+        return Err(AirdropError::CursorMismatch { expected: airdrop.cursor.map(|c| c.to_vec()) });
+    }
+
+    let ctx = OperationContext::privileged(OpKind::Mint, reward_token, runtime::caller(), None).map_err(TransferError::from)?;
+    if ctx.metadata.is_immutable() {
+        return Err(TransferError::from(state::MetadataError::TokenImmutable).into());
+    }
+
+    let limit = limit.unwrap_or(DEFAULT_AIRDROP_CHUNK).clamp(1, MAX_AIRDROP_CHUNK) as usize;
+    let entries = state::list_snapshot_balances(source_snapshot_id, airdrop.cursor, limit);
+    let mut credits: Vec<(AccountKey, u128)> = Vec::with_capacity(entries.len());
+    for (account_key, balance) in &entries {
+        if *balance == 0 {
+            continue;
+        }
+        if *balance < min_holding || !crate::accounts::accepts_incoming(reward_token, *account_key, true) {
+            airdrop.skipped += 1;
+            continue;
+        }
+        let (reward, remainder) = mul_div_rem(*balance, numerator, denominator)
+            .map_err(|e| generic_error(400, e.to_string()))?;
+        add_dust(&mut airdrop, remainder);
+        if reward == 0 {
+            airdrop.skipped += 1;
+            continue;
+        }
+        credits.push((*account_key, reward));
+    }
+
+    let minted = credits.iter().try_fold(0u128, |total, (_, reward)| total.checked_add(*reward))
+        .and_then(|total| i128::try_from(total).ok())
+        .ok_or_else(|| generic_error(400, "The chunk's rewards overflow the total supply"))?;
+    state::apply_movements(reward_token, &[], &credits, minted).map_err(TransferError::from)?;
+
+    let memo = format!("icrc151:airdrop:{}", source_snapshot_id);
+    for (account_key, reward) in &credits {
+        let tx = StoredTxV1::new_mint(reward_token, *account_key, *reward, ctx.timestamp, Some(memo.as_bytes()));
+        state::add_transaction_with_memo(tx, Some(memo.as_bytes()));
+        state::increment_tx_count();
+    }
+
+    airdrop.recipients += credits.len() as u64;
+    airdrop.total_minted = airdrop.total_minted.saturating_add(minted as u128);
+    if let Some((last, _)) = entries.last() {
+        airdrop.cursor = Some(*last);
+    }
+    if entries.len() < limit {
+        airdrop.completed_at = Some(runtime::time());
+        state::record_admin_action("execute_airdrop", format!(
+            "snapshot {}, reward {}: {} recipients, {} minted, {} dust",
+            source_snapshot_id, hex_encode(&reward_token), airdrop.recipients, airdrop.total_minted, airdrop.dust
+        ));
+    }
+    state::set_airdrop(airdrop.clone());
+    Ok(airdrop)
+}
+
+
+#[ic_cdk::query]
+pub fn get_airdrop(snapshot_id: u64, reward_token: TokenId) -> Option<Airdrop> {
+    state::get_airdrop(snapshot_id, reward_token)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshots::{continue_balance_snapshot, open_balance_snapshot};
+    use crate::test_utils::{register_test_token, test_account, test_metadata, test_subaccount};
+    use crate::types::{Account, IncomingPolicy, IncomingPolicyConfig, StoredTokenMetadata};
+    use candid::Nat;
+
+    fn holder(i: u32) -> Account {
+        test_subaccount((i / 256) as u8, (i % 256) as u8)
+    }
+
+    fn snapshot_of(token: TokenId, balances: &[(AccountKey, u128)]) -> u64 {
+        for chunk in balances.chunks(500) {
+            let total = chunk.iter().map(|(_, amount)| amount).sum::<u128>();
+            state::apply_movements(token, &[], chunk, total as i128).unwrap();
+        }
+        let id = open_balance_snapshot(token).unwrap().id;
+        assert!(continue_balance_snapshot(id, Some(5_000)).unwrap().complete);
+        id
+    }
+
+    fn run(id: u64, source: TokenId, reward: TokenId, cursor: Option<Vec<u8>>, limit: u32) -> Result<Airdrop, AirdropError> {
+        execute_airdrop(id, source, reward, Nat::from(1u32), Nat::from(100u32), Nat::from(50u32), cursor, Some(limit))
+    }
+
+    #[test]
+    fn test_airdrop_pays_thousands_of_holders_in_chunks() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let (source, reward) = (register_test_token(1, 0), register_test_token(2, 0));
+        let balances: Vec<(AccountKey, u128)> = (0..3_000).map(|i| (holder(i).to_key(), 7 * i as u128)).collect();
+        let id = snapshot_of(source, &balances);
+        let txs_before = state::get_transaction_count();
+
+        let mut cursor = None;
+        let mut chunks = 0;
+        let airdrop = loop {
+            let airdrop = run(id, source, reward, cursor, 500).unwrap();
+            chunks += 1;
+            if airdrop.completed_at.is_some() {
+                break airdrop;
+            }
+            cursor = airdrop.cursor.map(|c| c.to_vec());
+        };
+        // 2,999 holders: the sixth chunk comes up short and completes.
+        assert_eq!(chunks, 6);
+
+        let eligible: Vec<u128> = balances.iter().map(|(_, b)| *b).filter(|b| *b >= 50).collect();
+        let owed: u128 = eligible.iter().map(|b| b / 100).sum();
+        let withheld: u128 = eligible.iter().map(|b| b % 100).sum();
+        for (key, balance) in &balances {
+            let expected = if *balance >= 50 { balance / 100 } else { 0 };
+            assert_eq!(state::get_balance(reward, *key), expected);
+        }
+        assert_eq!(airdrop.total_minted, owed);
+        assert_eq!(state::get_token_metadata(reward).unwrap().total_supply, owed);
+        assert_eq!((airdrop.dust, airdrop.dust_remainder), (withheld / 100, withheld % 100));
+        let recipients = eligible.iter().filter(|b| **b >= 100).count() as u64;
+        assert_eq!(airdrop.recipients, recipients);
+        assert_eq!(airdrop.skipped, 2_999 - recipients);
+        assert_eq!(state::get_transaction_count() - txs_before, recipients);
+        let tx = state::get_transaction(txs_before).unwrap();
+        assert_eq!(tx.token_id, reward);
+        assert_eq!(state::get_airdrop(id, reward).unwrap().completed_at, airdrop.completed_at);
+
+        // A completed airdrop cannot pay again.
+        assert!(matches!(run(id, source, reward, None, 500), Err(AirdropError::AlreadyCompleted(_))));
+        assert_eq!(state::get_token_metadata(reward).unwrap().total_supply, owed);
+    }
+
+    #[test]
+    fn test_airdrop_resumes_only_where_it_stopped_and_on_the_same_terms() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let (source, reward) = (register_test_token(1, 0), register_test_token(2, 0));
+        let balances: Vec<(AccountKey, u128)> = (0..10).map(|i| (holder(i).to_key(), 1_000)).collect();
+        let id = snapshot_of(source, &balances);
+
+        let first = run(id, source, reward, None, 4).unwrap();
+        assert_eq!(first.recipients, 4);
+        assert!(matches!(run(id, source, reward, None, 4), Err(AirdropError::CursorMismatch { expected }) if expected == first.cursor.map(|c| c.to_vec())));
+        let changed = execute_airdrop(id, source, reward, Nat::from(2u32), Nat::from(100u32), Nat::from(50u32), first.cursor.map(|c| c.to_vec()), None);
+        assert!(matches!(changed, Err(AirdropError::Rejected(TransferError::GenericError { .. }))));
+        assert!(matches!(run(id, reward, reward, None, 4), Err(AirdropError::Rejected(_))));
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(run(id, source, reward, first.cursor.map(|c| c.to_vec()), 4).is_err());
+        runtime::set_caller(controller);
+        let rest = run(id, source, reward, first.cursor.map(|c| c.to_vec()), 100).unwrap();
+        assert!(rest.completed_at.is_some());
+        assert_eq!((rest.recipients, rest.total_minted), (10, 100));
+    }
+
+    #[test]
+    fn test_refused_airdrop_chunk_mints_nothing() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let source = register_test_token(1, 0);
+        let reward = [2; 32];
+        state::register_token(reward, StoredTokenMetadata { max_holders: Some(2), ..test_metadata(0) });
+        let balances: Vec<(AccountKey, u128)> = (0..4).map(|i| (holder(i).to_key(), 1_000)).collect();
+        let id = snapshot_of(source, &balances);
+
+        // A holder whose incoming policy refuses the reward is skipped.
+        state::set_incoming_policy(balances[0].0, IncomingPolicyConfig { policy: IncomingPolicy::DenyUnknown, allow_controller_mints: false });
+        let refused = run(id, source, reward, None, 10);
+        assert!(matches!(refused, Err(AirdropError::Rejected(TransferError::HolderLimitReached { max_holders: 2 }))));
+        assert_eq!(state::get_token_metadata(reward).unwrap().total_supply, 0);
+        assert!(state::get_airdrop(id, reward).is_none());
+
+        state::update_token_max_holders(reward, Some(3)).unwrap();
+        let airdrop = run(id, source, reward, None, 10).unwrap();
+        assert_eq!((airdrop.recipients, airdrop.skipped, airdrop.total_minted), (3, 1, 30));
+        assert_eq!(state::get_balance(reward, balances[0].0), 0);
+    }
+}
@@ -1,7 +1,12 @@
-use crate::types::{Account, TokenId};
+use crate::types::{constants::UNLIMITED_ALLOWANCE, Account, AccountKey, AdminExpiredAllowance, Memo, OpKind, Subaccount, TokenId};
 use crate::state;
-use crate::validation::{validate_approve_params, validate_account, validate_token_id, ValidationError};
-use crate::transaction::StoredTxV1;
+use crate::runtime;
+use crate::trace;
+use crate::failures::{self, FailedOperation};
+use crate::context::{ContextError, OperationContext};
+use crate::validation::{validate_approve_params, validate_account, validate_token_id, check_args_version, ValidationError};
+use crate::transaction::{StoredTxV1, OP_APPROVE, OP_BURN, OP_TRANSFER_FROM};
+use crate::fees::{FeeLeg, FeeLegError, Postings};
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 use num_traits::cast::ToPrimitive;
@@ -14,10 +19,33 @@ pub struct Icrc151ApproveArgs {
     pub amount: candid::Nat,
     pub expires_at: Option<u64>,
     pub expected_allowance: Option<candid::Nat>,
-    pub memo: Option<Vec<u8>>,
+    pub memo: Option<Memo>,
     pub fee: Option<candid::Nat>,
-    pub from_subaccount: Option<Vec<u8>>,
+    pub from_subaccount: Option<Subaccount>,
     pub created_at_time: Option<u64>,
+    // Shows the memo only to the owner, the spender and controllers.
+    pub private_memo: Option<bool>,
+    // See validation::ARGS_VERSION.
+    pub args_version: Option<u16>,
+}
+
+
+// Arguments of increase_allowance and decrease_allowance. `expires_at` replaces
+// the allowance's expiry; None keeps the current one.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Icrc151AllowanceChangeArgs {
+    pub token_id: TokenId,
+    pub spender: Account,
+    pub delta: candid::Nat,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Memo>,
+    pub fee: Option<candid::Nat>,
+    pub from_subaccount: Option<Subaccount>,
+    pub created_at_time: Option<u64>,
+    // Shows the memo only to the owner, the spender and controllers.
+    pub private_memo: Option<bool>,
+    // See validation::ARGS_VERSION.
+    pub args_version: Option<u16>,
 }
 
 
@@ -28,10 +56,60 @@ pub enum ApproveResult {
 }
 
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ApproveResultV2 {
+    Ok(TxReceipt),
+    Err(ApproveError),
+}
+
+
+// A TxReceipt with the allowance the adjustment left in place.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AllowanceChangeReceipt {
+    pub tx_index: u64,
+    pub token_seq: u64,
+    pub allowance: candid::Nat,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum AllowanceChangeResult {
+    Ok(AllowanceChangeReceipt),
+    Err(ApproveError),
+}
+
+
+// How an approval changes the stored allowance.
+#[derive(Clone, Copy, Debug)]
+enum AllowanceUpdate {
+    Set(u128),
+    Increase(u128),
+    // Clamps at zero rather than failing.
+    Decrease(u128),
+}
+
+impl AllowanceUpdate {
+    fn amount(self) -> u128 {
+        match self {
+            AllowanceUpdate::Set(amount) | AllowanceUpdate::Increase(amount) | AllowanceUpdate::Decrease(amount) => amount,
+        }
+    }
+
+    fn op_name(self) -> &'static str {
+        match self {
+            AllowanceUpdate::Set(_) => "approve",
+            AllowanceUpdate::Increase(_) => "increase_allowance",
+            AllowanceUpdate::Decrease(_) => "decrease_allowance",
+        }
+    }
+}
+
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub enum ApproveError {
     BadFee { expected_fee: candid::Nat },
     InsufficientFunds { balance: candid::Nat },
+    InsufficientFeeFunds { fee_token: TokenId, balance: candid::Nat },
     AllowanceChanged { current_allowance: candid::Nat },
     Expired { ledger_time: u64 },
     TooOld,
@@ -39,6 +117,61 @@ pub enum ApproveError {
     Duplicate { duplicate_of: u64 },
     TemporarilyUnavailable,
     GenericError { error_code: candid::Nat, message: String },
+    UnsupportedArgsVersion { supported: u16 },
+    OperationPaused { op: OpKind },
+}
+
+impl From<FeeLegError> for ApproveError {
+    fn from(err: FeeLegError) -> Self {
+        match err {
+            FeeLegError::InsufficientFunds { fee_token, balance } => ApproveError::InsufficientFeeFunds {
+                fee_token,
+                balance: candid::Nat::from(balance),
+            },
+            FeeLegError::Overflow => ApproveError::GenericError {
+                error_code: candid::Nat::from(500u64),
+                message: "Fee recipient balance overflow".to_string(),
+            },
+            FeeLegError::HolderLimitReached { max_holders } => ApproveError::GenericError {
+                error_code: candid::Nat::from(409u64),
+                message: format!("HolderLimitReached: the token is limited to {} holders", max_holders),
+            },
+        }
+    }
+}
+
+impl From<state::MovementError> for ApproveError {
+    fn from(err: state::MovementError) -> Self {
+        match err {
+            state::MovementError::InsufficientBalance { balance, .. } => ApproveError::InsufficientFunds {
+                balance: candid::Nat::from(balance),
+            },
+            state::MovementError::HolderLimitReached { max_holders } => FeeLegError::HolderLimitReached { max_holders }.into(),
+            other => ApproveError::GenericError {
+                error_code: candid::Nat::from(500u64),
+                message: other.to_string(),
+            },
+        }
+    }
+}
+
+impl From<state::MetadataError> for ApproveError {
+    fn from(err: state::MetadataError) -> Self {
+        match err {
+            state::MetadataError::NotFound => ApproveError::GenericError {
+                error_code: candid::Nat::from(404u64),
+                message: "Token not found".to_string(),
+            },
+            state::MetadataError::Corrupted(_) => ApproveError::GenericError {
+                error_code: candid::Nat::from(503u64),
+                message: "Token metadata is corrupted; the token is quarantined".to_string(),
+            },
+            state::MetadataError::TokenImmutable => ApproveError::GenericError {
+                error_code: candid::Nat::from(403u64),
+                message: err.to_string(),
+            },
+        }
+    }
 }
 
 impl From<ValidationError> for ApproveError {
@@ -50,37 +183,87 @@ impl From<ValidationError> for ApproveError {
     }
 }
 
+impl From<ContextError> for ApproveError {
+    fn from(err: ContextError) -> Self {
+        match err {
+            ContextError::Maintenance => ApproveError::TemporarilyUnavailable,
+            ContextError::Paused(op) => ApproveError::OperationPaused { op },
+            ContextError::ImportPending => ApproveError::GenericError {
+                error_code: candid::Nat::from(409u64),
+                message: err.to_string(),
+            },
+            ContextError::InvalidToken(e) => e.into(),
+            ContextError::Metadata(e) => e.into(),
+            ContextError::TooOld => ApproveError::TooOld,
+            ContextError::CreatedInFuture { ledger_time } => ApproveError::CreatedInFuture { ledger_time },
+            ContextError::BadFee { expected_fee } => ApproveError::BadFee { expected_fee: candid::Nat::from(expected_fee) },
+        }
+    }
+}
+
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Icrc151TransferFromArgs {
     pub token_id: TokenId,
-    pub spender_subaccount: Option<Vec<u8>>,
+    pub spender_subaccount: Option<Subaccount>,
     pub from: Account,
     pub to: Account,
     pub amount: candid::Nat,
     pub fee: Option<candid::Nat>,
-    pub memo: Option<Vec<u8>>,
+    pub memo: Option<Memo>,
     pub created_at_time: Option<u64>,
+    // Shows the memo only to the owner, the recipient, the spender and controllers.
+    pub private_memo: Option<bool>,
+    // See validation::ARGS_VERSION.
+    pub args_version: Option<u16>,
 }
 
 
-pub use crate::operations::{TransferResult, TransferError};
+pub use crate::operations::{TransferResult, TransferResultV2, TransferError, TxReceipt};
 
 
 #[ic_cdk::update]
 pub fn approve(args: Icrc151ApproveArgs) -> ApproveResult {
-    let caller = ic_cdk::caller();
+    state::record_legacy_call("approve");
+    handle_approve(args)
+}
+
+
+fn handle_approve(args: Icrc151ApproveArgs) -> ApproveResult {
+    let watch = failures::watch(FailedOperation::Approve, args.token_id, &args, Some(&args.amount));
+    let result = approve_with_args(args);
+    if let ApproveResult::Err(error) = &result {
+        failures::record(watch, error);
+    }
+    result
+}
+
+
+fn approve_with_args(args: Icrc151ApproveArgs) -> ApproveResult {
+    match approve_checked(args, None) {
+        Ok(tx_index) => ApproveResult::Ok(tx_index),
+        Err(err) => ApproveResult::Err(err),
+    }
+}
+
+
+// `script_position` is set for the operations of execute_script.
+pub(crate) fn approve_checked(args: Icrc151ApproveArgs, script_position: Option<u32>) -> Result<u64, ApproveError> {
+    if let Err(supported) = check_args_version(args.args_version) {
+        return Err(ApproveError::UnsupportedArgsVersion { supported });
+    }
+    let caller = runtime::caller();
     
 
     let owner_account = Account {
         owner: caller,
-        subaccount: args.from_subaccount.clone(),
+        subaccount: args.from_subaccount.map(|s| s.to_vec()),
     };
     
 
     let amount = match args.amount.0.to_u128() {
         Some(a) => a,
-        None => return ApproveResult::Err(ApproveError::GenericError {
+        None => return Err(ApproveError::GenericError {
             error_code: candid::Nat::from(400u64),
             message: "Amount exceeds maximum value (u128::MAX)".to_string(),
         }),
@@ -89,7 +272,7 @@ pub fn approve(args: Icrc151ApproveArgs) -> ApproveResult {
     let fee = match args.fee.as_ref() {
         Some(f) => match f.0.to_u128() {
             Some(val) => Some(val),
-            None => return ApproveResult::Err(ApproveError::GenericError {
+            None => return Err(ApproveError::GenericError {
                 error_code: candid::Nat::from(400u64),
                 message: "Fee exceeds maximum value (u128::MAX)".to_string(),
             }),
@@ -100,7 +283,7 @@ pub fn approve(args: Icrc151ApproveArgs) -> ApproveResult {
     let expected_allowance = match args.expected_allowance.as_ref() {
         Some(a) => match a.0.to_u128() {
             Some(val) => Some(val),
-            None => return ApproveResult::Err(ApproveError::GenericError {
+            None => return Err(ApproveError::GenericError {
                 error_code: candid::Nat::from(400u64),
                 message: "Expected allowance exceeds maximum value (u128::MAX)".to_string(),
             }),
@@ -108,23 +291,23 @@ pub fn approve(args: Icrc151ApproveArgs) -> ApproveResult {
         None => None,
     };
 
-    match approve_internal(
-        args.token_id,
+    let ctx = OperationContext::new(OpKind::Approve, args.token_id, caller, args.created_at_time)?
+        .in_script(script_position);
+    execute_approve(
+        &ctx,
         owner_account,
         args.spender,
-        amount,
+        AllowanceUpdate::Set(amount),
         args.expires_at,
         expected_allowance,
         fee,
         args.memo.as_deref(),
-        args.created_at_time,
-    ) {
-        Ok(tx_index) => ApproveResult::Ok(tx_index),
-        Err(err) => ApproveResult::Err(err),
-    }
+        args.private_memo.unwrap_or(false),
+    )
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn approve_internal(
     token_id: TokenId,
     owner: Account,
@@ -135,45 +318,101 @@ fn approve_internal(
     fee: Option<u128>,
     memo: Option<&[u8]>,
     created_at_time: Option<u64>,
+    private_memo: bool,
 ) -> Result<u64, ApproveError> {
+    let ctx = OperationContext::new(OpKind::Approve, token_id, owner.owner, created_at_time)?;
+    execute_approve(&ctx, owner, spender, AllowanceUpdate::Set(amount), expires_at, expected_allowance, fee, memo, private_memo)
+}
 
-    validate_token_id(&token_id)?;
-
-
-    let metadata = state::get_token_metadata(token_id)
-        .ok_or(ApproveError::GenericError {
-            error_code: candid::Nat::from(404u64),
-            message: "Token not found".to_string(),
-        })?;
 
-    let expected_fee = metadata.fee;
-    let fee_amount = fee.unwrap_or(expected_fee);
+#[allow(clippy::too_many_arguments)]
+fn adjust_allowance_internal(
+    token_id: TokenId,
+    owner: Account,
+    spender: Account,
+    update: AllowanceUpdate,
+    expires_at: Option<u64>,
+    fee: Option<u128>,
+    memo: Option<&[u8]>,
+    created_at_time: Option<u64>,
+    private_memo: bool,
+) -> Result<u64, ApproveError> {
+    let ctx = OperationContext::new(OpKind::Approve, token_id, owner.owner, created_at_time)?;
+    execute_approve(&ctx, owner, spender, update, expires_at, None, fee, memo, private_memo)
+}
 
 
-    if let Some(provided_fee) = fee {
-        if provided_fee != expected_fee {
-            return Err(ApproveError::BadFee {
-                expected_fee: candid::Nat::from(expected_fee),
-            });
-        }
+#[allow(clippy::too_many_arguments)]
+fn execute_approve(
+    ctx: &OperationContext,
+    owner: Account,
+    spender: Account,
+    update: AllowanceUpdate,
+    expires_at: Option<u64>,
+    expected_allowance: Option<u128>,
+    fee: Option<u128>,
+    memo: Option<&[u8]>,
+    private_memo: bool,
+) -> Result<u64, ApproveError> {
+    if !trace::is_enabled() {
+        return apply_approve(ctx, owner, spender, update, expires_at, expected_allowance, fee, memo, private_memo);
     }
+    let accounts = [owner.to_key(), spender.to_key()];
+    let result = apply_approve(ctx, owner, spender, update, expires_at, expected_allowance, fee, memo, private_memo);
+    trace::record(update.op_name(), ctx.token_id, &accounts, &[update.amount()], &result);
+    result
+}
 
-    validate_approve_params(&owner, &spender, amount, Some(fee_amount), memo)?;
-    
 
-    let timestamp = created_at_time.unwrap_or_else(|| ic_cdk::api::time());
-    if let Some(provided_time) = created_at_time {
-        let current_time = ic_cdk::api::time();
+#[allow(clippy::too_many_arguments)]
+fn apply_approve(
+    ctx: &OperationContext,
+    owner: Account,
+    spender: Account,
+    update: AllowanceUpdate,
+    expires_at: Option<u64>,
+    expected_allowance: Option<u128>,
+    fee: Option<u128>,
+    memo: Option<&[u8]>,
+    private_memo: bool,
+) -> Result<u64, ApproveError> {
+    let token_id = ctx.token_id;
+    let fee_amount = ctx.standard_fee(fee)?;
+    validate_approve_params(&owner, &spender, update.amount(), Some(fee_amount), memo)?;
+    let timestamp = ctx.timestamp;
+    let owner_key = owner.to_key();
+    let spender_key = spender.to_key();
+
 
-        if provided_time > current_time + crate::types::constants::MAX_FUTURE_DRIFT {
-            return Err(ApproveError::CreatedInFuture { ledger_time: current_time });
-        }
+    // Checked first: a retry must come back as Duplicate, not as the
+    // AllowanceChanged or Expired its own first attempt would now cause.
+    let dedup_key = ctx.dedup_key(match update {
+        AllowanceUpdate::Set(amount) => state::compute_dedup_key(
+            OP_APPROVE,
+            owner.owner,
+            token_id,
+            timestamp,
+            memo,
+            Some(spender_key),
+            amount,
+        ),
+        AllowanceUpdate::Increase(delta) | AllowanceUpdate::Decrease(delta) => state::compute_allowance_change_dedup_key(
+            owner.owner,
+            token_id,
+            timestamp,
+            memo,
+            spender_key,
+            delta,
+            matches!(update, AllowanceUpdate::Increase(_)),
+        ),
+    });
 
-        if provided_time < current_time.saturating_sub(crate::types::constants::MAX_PAST_DRIFT) {
-            return Err(ApproveError::TooOld);
-        }
+    if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
+        return Err(ApproveError::Duplicate {
+            duplicate_of: duplicate_tx_index,
+        });
     }
-    
+
 
     if let Some(exp_time) = expires_at {
         if exp_time <= timestamp {
@@ -182,73 +421,94 @@ fn approve_internal(
     }
     
 
-    let owner_key = owner.to_key();
-    let spender_key = spender.to_key();
-    
-
-    let current_allowance = state::get_allowance(token_id, owner_key, spender_key);
+    // Expected allowances are compared with what the spender could still use,
+    // so an expired approval counts as zero. Adjustments start from zero too
+    // once the old allowance has expired, and keep its expiry otherwise.
+    let current_expiry = state::get_allowance_expiry(token_id, owner_key, spender_key);
+    let expired = state::is_allowance_expired(current_expiry);
+    let live_allowance = if expired { 0 } else { state::get_allowance(token_id, owner_key, spender_key) };
     if let Some(expected) = expected_allowance {
-        if current_allowance != expected {
+        if live_allowance != expected {
             return Err(ApproveError::AllowanceChanged {
-                current_allowance: candid::Nat::from(current_allowance),
+                current_allowance: candid::Nat::from(live_allowance),
             });
         }
     }
+    let amount = match update {
+        AllowanceUpdate::Set(amount) => amount,
+        AllowanceUpdate::Increase(_) if live_allowance == UNLIMITED_ALLOWANCE => UNLIMITED_ALLOWANCE,
+        AllowanceUpdate::Increase(delta) => live_allowance.checked_add(delta).ok_or_else(|| ApproveError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: "Allowance would exceed maximum value (u128::MAX)".to_string(),
+        })?,
+        AllowanceUpdate::Decrease(delta) => live_allowance.saturating_sub(delta),
+    };
+    // A zero allowance has nothing to expire, so it never keeps an expiry.
+    let new_expiry = match update {
+        _ if amount == 0 => None,
+        AllowanceUpdate::Set(_) => expires_at,
+        AllowanceUpdate::Increase(_) | AllowanceUpdate::Decrease(_) => {
+            expires_at.or(current_expiry.filter(|_| !expired))
+        }
+    };
     
 
-    let owner_balance = if fee_amount > 0 {
-        let balance = state::get_balance(token_id, owner_key);
+    let fee_token = ctx.fee_token();
+    let fee_recipient_key = ctx.fee_recipient_key();
+
+    // Fees are only checked here and charged once nothing else can fail.
+    let fee_leg = if fee_token != token_id && fee_amount > 0 {
+        Some(FeeLeg::prepare(fee_token, owner_key, fee_recipient_key, fee_amount)?)
+    } else {
+        None
+    };
+
+    let mut postings = Postings::new(token_id);
+    if fee_token == token_id && fee_amount > 0 {
+        let balance = state::get_spendable_balance(token_id, owner_key);
         if balance < fee_amount {
             return Err(ApproveError::InsufficientFunds {
                 balance: candid::Nat::from(balance),
             });
         }
-        balance
-    } else {
-        0
-    };
 
-    let fee_recipient_key = metadata.fee_recipient.to_key();
-    let fee_balance = state::get_balance(token_id, fee_recipient_key);
-    let new_fee_balance = if fee_amount > 0 {
-        fee_balance.checked_add(fee_amount)
-            .ok_or(ApproveError::GenericError {
-                error_code: candid::Nat::from(500u64),
-                message: "Fee recipient balance overflow".to_string(),
-            })?
-    } else {
-        fee_balance
-    };
-
-    if fee_amount > 0 {
-        state::set_balance(token_id, owner_key, owner_balance - fee_amount);
-        state::set_balance(token_id, fee_recipient_key, new_fee_balance);
+        postings.post_fee(owner_key, fee_recipient_key, fee_amount);
+        postings.check_credits()?;
     }
-    
 
-    let dedup_key = state::compute_dedup_key(
-        owner.owner,
-        token_id,
-        timestamp,
-        memo,
-    );
 
-    if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
-        return Err(ApproveError::Duplicate {
-            duplicate_of: duplicate_tx_index,
-        });
+    // Nothing has been written yet; from here on every step mutates state.
+    state::register_account(&owner).map_err(|e| ApproveError::GenericError {
+        error_code: candid::Nat::from(400u64),
+        message: e,
+    })?;
+    state::register_account(&spender).map_err(|e| ApproveError::GenericError {
+        error_code: candid::Nat::from(400u64),
+        message: e,
+    })?;
+    if amount > 0 {
+        state::record_spender_subaccount(&spender).map_err(|e| ApproveError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: e,
+        })?;
     }
 
-
+    postings.apply();
+    if let Some(leg) = fee_leg {
+        leg.apply();
+    }
     state::set_allowance(token_id, owner_key, spender_key, amount);
 
 
-    if let Some(exp_time) = expires_at {
-        state::set_allowance_expiry(token_id, owner_key, spender_key, exp_time);
+    // An approval replaces any previous expiry, including one set by a
+    // controller; an adjustment keeps it unless given a new one.
+    match new_expiry {
+        Some(exp_time) => state::set_allowance_expiry(token_id, owner_key, spender_key, exp_time),
+        None => state::remove_allowance_expiry(token_id, owner_key, spender_key),
     }
 
 
-    let tx = StoredTxV1::new_approve(
+    let mut tx = StoredTxV1::new_approve(
         token_id,
         owner_key,
         spender_key,
@@ -257,32 +517,326 @@ fn approve_internal(
         timestamp,
         memo,
     );
+    if fee_token != token_id {
+        tx = tx.with_fee_token(fee_token);
+    }
+
+    let tx_index = match memo {
+        Some(memo) if private_memo => state::add_transaction_with_private_memo(tx, memo, &[owner.owner, spender.owner]),
+        _ => state::add_transaction_with_memo(tx, memo),
+    };
+    state::increment_tx_count();
+
+
+    state::record_transaction_dedup(dedup_key, tx_index);
+
+    Ok(tx_index)
+}
+
+
+// Stops a spender from pulling by expiring the allowance now. The amount is
+// kept for forensics and the owner can approve again at any time.
+#[ic_cdk::update]
+pub fn admin_expire_allowance(
+    token_id: TokenId,
+    owner: Account,
+    spender: Account,
+    reason: String,
+) -> Result<AdminExpiredAllowance, String> {
+    state::require_controller()?;
+    validate_token_id(&token_id).map_err(|e| e.to_string())?;
+    validate_account(&owner).map_err(|e| e.to_string())?;
+    validate_account(&spender).map_err(|e| e.to_string())?;
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    if reason.len() > crate::types::constants::MAX_ADMIN_REASON_LEN {
+        return Err(format!("Reason too long (max {} bytes)", crate::types::constants::MAX_ADMIN_REASON_LEN));
+    }
+    state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+
+    let owner_key = owner.to_key();
+    let spender_key = spender.to_key();
+    let amount = state::get_allowance(token_id, owner_key, spender_key);
+    let previous_expiry = state::get_allowance_expiry(token_id, owner_key, spender_key);
+    if amount == 0 {
+        return Err("No allowance to expire".to_string());
+    }
+    if state::is_allowance_expired(previous_expiry) {
+        return Err("Allowance is already expired".to_string());
+    }
+
+    let now = runtime::time();
+    state::set_allowance_expiry(token_id, owner_key, spender_key, now);
+    let audit_index = state::record_admin_action(
+        "admin_expire_allowance",
+        format!("token {} owner {} spender {}: {}", hex_encode(&token_id), hex_encode(&owner_key), hex_encode(&spender_key), reason),
+    );
+
+    let entry = AdminExpiredAllowance {
+        audit_index,
+        token_id,
+        owner,
+        spender,
+        amount,
+        previous_expiry,
+        expired_at: now,
+        caller: runtime::caller(),
+        reason,
+    };
+    state::record_admin_expired_allowance(entry.clone());
+    Ok(entry)
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CleanupAllowancesArgs {
+    pub token_id: TokenId,
+    pub from_subaccount: Option<Subaccount>,
+    pub older_than_ns: Option<u64>,
+    pub dust_threshold: Option<candid::Nat>,
+    pub start_after: Option<Account>,
+    pub limit: Option<u64>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CleanupAllowancesResult {
+    pub removed: u64,
+    pub next_cursor: Option<Account>,
+}
+
+
+pub const MAX_SPENDER_SUBACCOUNTS: usize = 100;
+
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+const DEFAULT_CLEANUP_LIMIT: u64 = 100;
+const MAX_CLEANUP_LIMIT: u64 = 500;
+
+
+// Removes the caller's allowances that expired at least `older_than_ns` ago or
+// are below `dust_threshold`. No approve transactions are recorded: the entries
+// can no longer move funds in any meaningful way.
+#[ic_cdk::update]
+pub fn cleanup_my_allowances(args: CleanupAllowancesArgs) -> Result<CleanupAllowancesResult, String> {
+    let owner = Account {
+        owner: runtime::caller(),
+        subaccount: args.from_subaccount.map(|s| s.to_vec()),
+    };
+    validate_account(&owner).map_err(|e| e.to_string())?;
+    validate_token_id(&args.token_id).map_err(|e| e.to_string())?;
+
+    let dust_threshold = match args.dust_threshold {
+        Some(d) => d.0.to_u128().ok_or("Dust threshold exceeds maximum value (u128::MAX)".to_string())?,
+        None => 0,
+    };
+    let start_after = args.start_after.as_ref().map(|a| a.to_key());
+    let limit = args.limit.unwrap_or(DEFAULT_CLEANUP_LIMIT).min(MAX_CLEANUP_LIMIT);
+
+    Ok(cleanup_allowances_internal(
+        args.token_id,
+        &owner,
+        args.older_than_ns.unwrap_or(0),
+        dust_threshold,
+        start_after,
+        limit as usize,
+    ))
+}
+
+
+fn cleanup_allowances_internal(
+    token_id: TokenId,
+    owner: &Account,
+    older_than_ns: u64,
+    dust_threshold: u128,
+    start_after: Option<crate::types::AccountKey>,
+    limit: usize,
+) -> CleanupAllowancesResult {
+    let owner_key = owner.to_key();
+    let cutoff = runtime::time().saturating_sub(older_than_ns);
+
+    let spender_keys = state::list_owner_allowances(owner_key, token_id, start_after, limit);
+    let mut removed = 0u64;
+
+    for spender_key in &spender_keys {
+        let allowance = state::get_allowance(token_id, owner_key, *spender_key);
+        let expiry = state::get_allowance_expiry(token_id, owner_key, *spender_key);
+        let expired = expiry.is_some_and(|exp| exp <= cutoff);
+
+        if expired || allowance < dust_threshold {
+            state::set_allowance(token_id, owner_key, *spender_key, 0);
+            state::remove_allowance_expiry(token_id, owner_key, *spender_key);
+            removed += 1;
+        }
+    }
+
+    state::add_allowances_cleaned_up(removed);
+
+    let next_cursor = if spender_keys.len() == limit {
+        spender_keys.last().and_then(|key| state::get_registered_account(*key))
+    } else {
+        None
+    };
+
+    CleanupAllowancesResult { removed, next_cursor }
+}
+
+
+#[ic_cdk::update]
+pub fn approve_v2(args: Icrc151ApproveArgs) -> ApproveResultV2 {
+    match handle_approve(args) {
+        ApproveResult::Ok(tx_index) => ApproveResultV2::Ok(crate::operations::tx_receipt(tx_index)),
+        ApproveResult::Err(e) => ApproveResultV2::Err(e),
+    }
+}
+
+
+// Zeroes the caller's allowance for `spender` and drops its expiry, charging
+// the token's fee and logging an approve of 0. Revoking an allowance that does
+// not exist succeeds all the same.
+#[ic_cdk::update]
+pub fn revoke_allowance(
+    token_id: TokenId,
+    spender: Account,
+    from_subaccount: Option<Subaccount>,
+) -> ApproveResultV2 {
+    let args = (token_id, &spender, &from_subaccount);
+    let watch = failures::watch(FailedOperation::Approve, token_id, &args, None);
+    let result = revoke_with_args(token_id, spender, from_subaccount);
+    if let Err(error) = &result {
+        failures::record(watch, error);
+    }
+    match result {
+        Ok(tx_index) => ApproveResultV2::Ok(crate::operations::tx_receipt(tx_index)),
+        Err(e) => ApproveResultV2::Err(e),
+    }
+}
+
+
+fn revoke_with_args(
+    token_id: TokenId,
+    spender: Account,
+    from_subaccount: Option<Subaccount>,
+) -> Result<u64, ApproveError> {
+    let owner = Account {
+        owner: runtime::caller(),
+        subaccount: from_subaccount.map(|s| s.to_vec()),
+    };
+    // The fee is passed explicitly so tokens that require one still accept it.
+    // A token without metadata fails in approve_internal as usual.
+    let fee = state::get_token_metadata(token_id).ok().map(|metadata| metadata.fee);
+    match approve_internal(token_id, owner, spender, 0, None, None, fee, None, None, false) {
+        // Two revokes at the same ledger time are one revoke.
+        Err(ApproveError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        result => result,
+    }
+}
+
+
+// Adds `delta` to the allowance. Going past u128::MAX is a GenericError; an
+// unlimited allowance stays unlimited.
+#[ic_cdk::update]
+pub fn increase_allowance(args: Icrc151AllowanceChangeArgs) -> AllowanceChangeResult {
+    handle_allowance_change(args, AllowanceUpdate::Increase)
+}
+
 
-    let tx_index = state::add_transaction(tx);
-    state::increment_tx_count();
+// Takes `delta` off the allowance, stopping at zero.
+#[ic_cdk::update]
+pub fn decrease_allowance(args: Icrc151AllowanceChangeArgs) -> AllowanceChangeResult {
+    handle_allowance_change(args, AllowanceUpdate::Decrease)
+}
 
 
-    if let Some(memo_bytes) = memo {
-        if memo_bytes.len() > 32 {
-            state::store_extended_memo(tx_index, memo_bytes.to_vec());
+fn handle_allowance_change(args: Icrc151AllowanceChangeArgs, update: fn(u128) -> AllowanceUpdate) -> AllowanceChangeResult {
+    let watch = failures::watch(FailedOperation::Approve, args.token_id, &args, Some(&args.delta));
+    let result = allowance_change_with_args(args, update);
+    if let Err(error) = &result {
+        failures::record(watch, error);
+    }
+    match result {
+        Ok(tx_index) => {
+            let TxReceipt { tx_index, token_seq } = crate::operations::tx_receipt(tx_index);
+            let allowance = state::get_transaction(tx_index).map_or(0, |tx| tx.get_amount());
+            AllowanceChangeResult::Ok(AllowanceChangeReceipt { tx_index, token_seq, allowance: candid::Nat::from(allowance) })
         }
+        Err(e) => AllowanceChangeResult::Err(e),
     }
+}
 
 
-    state::record_transaction_dedup(dedup_key, tx_index);
+fn allowance_change_with_args(args: Icrc151AllowanceChangeArgs, update: fn(u128) -> AllowanceUpdate) -> Result<u64, ApproveError> {
+    if let Err(supported) = check_args_version(args.args_version) {
+        return Err(ApproveError::UnsupportedArgsVersion { supported });
+    }
+    let owner_account = Account {
+        owner: runtime::caller(),
+        subaccount: args.from_subaccount.map(|s| s.to_vec()),
+    };
+    let delta = args.delta.0.to_u128().ok_or_else(|| ApproveError::GenericError {
+        error_code: candid::Nat::from(400u64),
+        message: "Delta exceeds maximum value (u128::MAX)".to_string(),
+    })?;
+    let fee = match args.fee.as_ref() {
+        Some(f) => Some(f.0.to_u128().ok_or_else(|| ApproveError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: "Fee exceeds maximum value (u128::MAX)".to_string(),
+        })?),
+        None => None,
+    };
 
-    Ok(tx_index)
+    adjust_allowance_internal(
+        args.token_id,
+        owner_account,
+        args.spender,
+        update(delta),
+        args.expires_at,
+        fee,
+        args.memo.as_deref(),
+        args.created_at_time,
+        args.private_memo.unwrap_or(false),
+    )
+}
+
+
+#[ic_cdk::update]
+pub fn transfer_from_v2(args: Icrc151TransferFromArgs) -> TransferResultV2 {
+    handle_transfer_from(args).into()
 }
 
 
 #[ic_cdk::update]
 pub fn transfer_from(args: Icrc151TransferFromArgs) -> TransferResult {
-    let caller = ic_cdk::caller();
+    state::record_legacy_call("transfer_from");
+    handle_transfer_from(args)
+}
+
+
+fn handle_transfer_from(args: Icrc151TransferFromArgs) -> TransferResult {
+    let watch = failures::watch(FailedOperation::TransferFrom, args.token_id, &args, Some(&args.amount));
+    let result = transfer_from_with_args(args);
+    if let TransferResult::Err(error) = &result {
+        failures::record(watch, error);
+    }
+    result
+}
+
+
+fn transfer_from_with_args(args: Icrc151TransferFromArgs) -> TransferResult {
+    if let Err(supported) = check_args_version(args.args_version) {
+        return TransferResult::Err(TransferError::UnsupportedArgsVersion { supported });
+    }
+    let caller = runtime::caller();
     
 
     let spender_account = Account {
         owner: caller,
-        subaccount: args.spender_subaccount.clone(),
+        subaccount: args.spender_subaccount.map(|s| s.to_vec()),
     };
     
 
@@ -314,6 +868,7 @@ pub fn transfer_from(args: Icrc151TransferFromArgs) -> TransferResult {
         fee,
         args.memo.as_deref(),
         args.created_at_time,
+        args.private_memo.unwrap_or(false),
     ) {
         Ok(tx_index) => TransferResult::Ok(tx_index),
         Err(err) => TransferResult::Err(err),
@@ -321,6 +876,7 @@ pub fn transfer_from(args: Icrc151TransferFromArgs) -> TransferResult {
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn transfer_from_internal(
     token_id: TokenId,
     spender: Account,
@@ -330,13 +886,46 @@ fn transfer_from_internal(
     fee: Option<u128>,
     memo: Option<&[u8]>,
     created_at_time: Option<u64>,
+    private_memo: bool,
 ) -> Result<u64, TransferError> {
+    let ctx = OperationContext::new(OpKind::TransferFrom, token_id, spender.owner, created_at_time)?;
+    execute_transfer_from(&ctx, spender, from, to, amount, fee, memo, private_memo)
+}
 
-    validate_token_id(&token_id).map_err(|e| TransferError::GenericError {
-        error_code: candid::Nat::from(400u64),
-        message: e.to_string(),
-    })?;
-    
+
+#[allow(clippy::too_many_arguments)]
+fn execute_transfer_from(
+    ctx: &OperationContext,
+    spender: Account,
+    from: Account,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<&[u8]>,
+    private_memo: bool,
+) -> Result<u64, TransferError> {
+    if !trace::is_enabled() {
+        return apply_transfer_from(ctx, spender, from, to, amount, fee, memo, private_memo);
+    }
+    let accounts = [from.to_key(), to.to_key(), spender.to_key()];
+    let result = apply_transfer_from(ctx, spender, from, to, amount, fee, memo, private_memo);
+    trace::record("transfer_from", ctx.token_id, &accounts, &[amount], &result);
+    result
+}
+
+
+#[allow(clippy::too_many_arguments)]
+fn apply_transfer_from(
+    ctx: &OperationContext,
+    spender: Account,
+    from: Account,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<&[u8]>,
+    private_memo: bool,
+) -> Result<u64, TransferError> {
+    let token_id = ctx.token_id;
     validate_account(&spender).map_err(|e| TransferError::GenericError {
         error_code: candid::Nat::from(400u64),
         message: e.to_string(),
@@ -359,79 +948,53 @@ fn transfer_from_internal(
         });
     }
 
+    crate::memo::validate_structured_memo(&ctx.metadata, memo).map_err(|message| TransferError::GenericError {
+        error_code: candid::Nat::from(400u64),
+        message,
+    })?;
 
-    let metadata = state::get_token_metadata(token_id)
-        .ok_or(TransferError::GenericError {
-            error_code: candid::Nat::from(404u64),
-            message: "Token not found".to_string(),
-        })?;
-
-    let expected_fee = metadata.fee;
-    let fee_amount = fee.unwrap_or(expected_fee);
-
-
-    if let Some(provided_fee) = fee {
-        if provided_fee != expected_fee {
-            return Err(TransferError::BadFee {
-                expected_fee: candid::Nat::from(expected_fee),
-            });
-        }
-    }
-    
-
-    let timestamp = created_at_time.unwrap_or_else(|| ic_cdk::api::time());
-    if let Some(provided_time) = created_at_time {
-        let current_time = ic_cdk::api::time();
 
-        if provided_time > current_time + crate::types::constants::MAX_FUTURE_DRIFT {
-            return Err(TransferError::CreatedInFuture { ledger_time: current_time });
-        }
+    let fee_amount = ctx.standard_fee(fee)?;
+    let timestamp = ctx.timestamp;
 
-        if provided_time < current_time.saturating_sub(crate::types::constants::MAX_PAST_DRIFT) {
-            return Err(TransferError::TooOld);
-        }
-    }
-    
 
     let spender_key = spender.to_key();
     let from_key = from.to_key();
     let to_key = to.to_key();
     
 
-    let expiry = state::get_allowance_expiry(token_id, from_key, spender_key);
-    if state::is_allowance_expired(expiry) {
-        return Err(TransferError::GenericError {
-            error_code: candid::Nat::from(403u64),
-            message: "Allowance expired".to_string(),
-        });
-    }
-
+    let fee_recipient_key = ctx.fee_recipient_key();
+    let fee_token = ctx.fee_token();
+    let primary_fee = if fee_token == token_id { fee_amount } else { 0 };
 
-    let current_allowance = state::get_allowance(token_id, from_key, spender_key);
-    let total_amount = amount.checked_add(fee_amount)
+    let total_amount = amount.checked_add(primary_fee)
         .ok_or(TransferError::GenericError {
             error_code: candid::Nat::from(400u64),
             message: "Amount + fee overflow".to_string(),
         })?;
+    let current_allowance = check_allowance(token_id, from_key, &spender, total_amount)?;
 
-    if current_allowance < total_amount {
-        return Err(TransferError::InsufficientFunds {
-            balance: candid::Nat::from(current_allowance),
-        });
-    }
-
-    let from_balance = state::get_balance(token_id, from_key);
+    let from_balance = state::get_spendable_balance(token_id, from_key);
     if from_balance < total_amount {
         return Err(TransferError::InsufficientFunds {
             balance: candid::Nat::from(from_balance),
         });
     }
 
+    let fee_leg = if fee_token != token_id && fee_amount > 0 {
+        Some(FeeLeg::prepare(fee_token, from_key, fee_recipient_key, fee_amount)?)
+    } else {
+        None
+    };
+
     let dedup_key = state::compute_dedup_key(
+        OP_TRANSFER_FROM,
         spender.owner,
         token_id,
         timestamp,
         memo,
+        Some(to_key),
+        amount,
     );
 
     if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
@@ -440,34 +1003,30 @@ fn transfer_from_internal(
         });
     }
 
-    let to_balance = state::get_balance(token_id, to_key);
-    let new_to_balance = to_balance.checked_add(amount)
+    if !crate::accounts::accepts_incoming(token_id, to_key, false) {
+        return Err(TransferError::RecipientRefused);
+    }
+
+    state::get_balance(token_id, to_key).checked_add(amount)
         .ok_or(TransferError::GenericError {
             error_code: candid::Nat::from(500u64),
             message: "Recipient balance overflow".to_string(),
         })?;
 
-    let fee_recipient_key = metadata.fee_recipient.to_key();
-    let fee_balance = state::get_balance(token_id, fee_recipient_key);
-    let new_fee_balance = if fee_amount > 0 {
-        fee_balance.checked_add(fee_amount)
-            .ok_or(TransferError::GenericError {
-                error_code: candid::Nat::from(500u64),
-                message: "Fee recipient balance overflow".to_string(),
-            })?
-    } else {
-        fee_balance
-    };
+    let mut postings = Postings::new(token_id);
+    postings.debit(from_key, amount);
+    postings.credit(to_key, amount);
+    postings.post_fee(from_key, fee_recipient_key, primary_fee);
+    postings.check_credits()?;
 
-    state::set_balance(token_id, from_key, from_balance - total_amount);
-    state::set_balance(token_id, to_key, new_to_balance);
-    state::set_allowance(token_id, from_key, spender_key, current_allowance - total_amount);
-    if fee_amount > 0 {
-        state::set_balance(token_id, fee_recipient_key, new_fee_balance);
+    postings.apply();
+    draw_down_allowance(token_id, from_key, spender_key, current_allowance, total_amount);
+    if let Some(leg) = fee_leg {
+        leg.apply();
     }
 
 
-    let tx = StoredTxV1::new_transfer_from(
+    let mut tx = StoredTxV1::new_transfer_from(
         token_id,
         from_key,
         to_key,
@@ -477,18 +1036,173 @@ fn transfer_from_internal(
         timestamp,
         memo,
     );
+    if fee_token != token_id {
+        tx = tx.with_fee_token(fee_token);
+    }
 
-    let tx_index = state::add_transaction(tx);
+    let tx_index = match memo {
+        Some(memo) if private_memo => state::add_transaction_with_private_memo(tx, memo, &[from.owner, to.owner, spender.owner]),
+        _ => state::add_transaction_with_memo(tx, memo),
+    };
     state::increment_tx_count();
 
 
-    if let Some(memo_bytes) = memo {
-        if memo_bytes.len() > 32 {
-            state::store_extended_memo(tx_index, memo_bytes.to_vec());
+    state::record_transaction_dedup(dedup_key, tx_index);
+
+    Ok(tx_index)
+}
+
+
+// The spender's allowance from `from_key`, once it is known to cover `total`.
+// An expired allowance is deleted on sight rather than left for the sweep.
+fn check_allowance(token_id: TokenId, from_key: AccountKey, spender: &Account, total: u128) -> Result<u128, TransferError> {
+    let spender_key = spender.to_key();
+    let expiry = state::get_allowance_expiry(token_id, from_key, spender_key);
+    if let Some(expires_at) = expiry.filter(|_| state::is_allowance_expired(expiry)) {
+        state::drop_expired_allowance(token_id, from_key, spender_key);
+        return Err(TransferError::AllowanceExpired { expires_at });
+    }
+
+    let current_allowance = state::get_allowance(token_id, from_key, spender_key);
+    if current_allowance == 0 {
+        let granted = state::find_principal_allowances(token_id, from_key, spender.owner, MAX_SPENDER_SUBACCOUNTS);
+        if let Some((other, _, _)) = granted.first() {
+            let hint = match other.named_subaccount() {
+                Some(sub) => format!("subaccount {}", hex_encode(sub)),
+                None => "the default subaccount".to_string(),
+            };
+            return Err(TransferError::GenericError {
+                error_code: candid::Nat::from(403u64),
+                message: format!("No allowance for this spender subaccount; the owner approved {} of the caller", hint),
+            });
         }
     }
 
+    if current_allowance < total {
+        return Err(TransferError::InsufficientFunds {
+            balance: candid::Nat::from(current_allowance),
+        });
+    }
+    Ok(current_allowance)
+}
+
+
+// An unlimited allowance is never drawn down.
+fn draw_down_allowance(token_id: TokenId, from_key: AccountKey, spender_key: AccountKey, current_allowance: u128, total: u128) {
+    if current_allowance == UNLIMITED_ALLOWANCE {
+        return;
+    }
+    let remaining_allowance = current_allowance - total;
+    state::set_allowance(token_id, from_key, spender_key, remaining_allowance);
+    // A spent allowance has nothing left to expire.
+    if remaining_allowance == 0 {
+        state::remove_allowance_expiry(token_id, from_key, spender_key);
+    }
+}
+
+
+// Burns from an owner's account on the caller's allowance, e.g. for a bridge
+// the owner approved. The allowance is checked and drawn down as for
+// transfer_from. Like every burn it pays no fee, so the allowance goes down
+// by the amount alone.
+#[ic_cdk::update]
+pub fn burn_from(
+    token_id: TokenId,
+    from: Account,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+    created_at_time: Option<u64>,
+) -> Result<u64, TransferError> {
+    let watch = failures::watch(FailedOperation::BurnFrom, token_id, &(token_id, &from, &amount, &memo, &created_at_time), Some(&amount));
+    let spender = Account { owner: runtime::caller(), subaccount: None };
+    let result = amount.0.to_u128()
+        .ok_or_else(|| TransferError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: "Amount exceeds maximum value (u128::MAX)".to_string(),
+        })
+        .and_then(|amount| burn_from_internal(token_id, spender, from, amount, memo.as_deref(), created_at_time));
+    if let Err(error) = &result {
+        failures::record(watch, error);
+    }
+    result
+}
+
+
+fn burn_from_internal(
+    token_id: TokenId,
+    spender: Account,
+    from: Account,
+    amount: u128,
+    memo: Option<&[u8]>,
+    created_at_time: Option<u64>,
+) -> Result<u64, TransferError> {
+    let ctx = OperationContext::new(OpKind::Burn, token_id, spender.owner, created_at_time)?;
+    if !trace::is_enabled() {
+        return apply_burn_from(&ctx, spender, from, amount, memo);
+    }
+    let accounts = [from.to_key(), spender.to_key()];
+    let result = apply_burn_from(&ctx, spender, from, amount, memo);
+    trace::record("burn_from", ctx.token_id, &accounts, &[amount], &result);
+    result
+}
+
+
+fn apply_burn_from(
+    ctx: &OperationContext,
+    spender: Account,
+    from: Account,
+    amount: u128,
+    memo: Option<&[u8]>,
+) -> Result<u64, TransferError> {
+    let token_id = ctx.token_id;
+    for account in [&spender, &from] {
+        validate_account(account).map_err(|e| TransferError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: e.to_string(),
+        })?;
+    }
+    if amount == 0 {
+        return Err(TransferError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: "Amount must be greater than 0".to_string(),
+        });
+    }
+    crate::memo::validate_structured_memo(&ctx.metadata, memo).map_err(|message| TransferError::GenericError {
+        error_code: candid::Nat::from(400u64),
+        message,
+    })?;
+
+    let spender_key = spender.to_key();
+    let from_key = from.to_key();
+
+    // Keyed on the spender, like transfer_from, and on the account burned from.
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(
+        OP_BURN,
+        spender.owner,
+        token_id,
+        ctx.timestamp,
+        memo,
+        Some(from_key),
+        amount,
+    ));
+    if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
+        return Err(TransferError::Duplicate { duplicate_of: duplicate_tx_index });
+    }
+
+    let current_allowance = check_allowance(token_id, from_key, &spender, amount)?;
+    let from_balance = state::get_spendable_balance(token_id, from_key);
+    if from_balance < amount {
+        return Err(TransferError::InsufficientFunds {
+            balance: candid::Nat::from(from_balance),
+        });
+    }
+
+    crate::operations::burn_movement(token_id, from_key, amount)?;
+    draw_down_allowance(token_id, from_key, spender_key, current_allowance, amount);
 
+    let tx = StoredTxV1::new_burn(token_id, from_key, amount, ctx.timestamp, memo).with_spender(spender_key);
+    let tx_index = state::add_transaction_with_memo(tx, memo);
+    state::increment_tx_count();
     state::record_transaction_dedup(dedup_key, tx_index);
 
     Ok(tx_index)
@@ -510,10 +1224,12 @@ mod tests {
             amount: candid::Nat::from(1000u64),
             expires_at: None,
             expected_allowance: Some(candid::Nat::from(0u64)),
-            memo: Some(b"test".to_vec()),
+            memo: Some(Memo(b"test".to_vec())),
             fee: Some(candid::Nat::from(10u64)),
             from_subaccount: None,
             created_at_time: None,
+            private_memo: None,
+            args_version: None,
         };
         
 
@@ -544,8 +1260,10 @@ mod tests {
             },
             amount: candid::Nat::from(1000u64),
             fee: Some(candid::Nat::from(10u64)),
-            memo: Some(b"transfer_from_test".to_vec()),
+            memo: Some(Memo(b"transfer_from_test".to_vec())),
             created_at_time: None,
+            private_memo: None,
+            args_version: None,
         };
         
 
@@ -576,4 +1294,677 @@ mod tests {
         assert!(validate_token_id(&token_id).is_ok());
         assert!(validate_approve_params(&owner, &spender, 1000, Some(10), None).is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_allowances_received_listing() {
+        use crate::queries::get_allowances_received;
+        use crate::test_utils::{register_test_token, test_account, test_subaccount};
+
+        let token = register_test_token(1, 0);
+        let other_token = register_test_token(2, 0);
+        let spender = test_account(9);
+        let now = runtime::time();
+
+        approve_internal(token, test_account(1), spender.clone(), 100, None, None, None, None, None, false).unwrap();
+        approve_internal(token, test_subaccount(2, 7), spender.clone(), 200, None, None, None, None, None, false).unwrap();
+        approve_internal(other_token, test_account(3), spender.clone(), 300, None, None, None, None, None, false).unwrap();
+        approve_internal(token, test_account(4), spender.clone(), 400, Some(now + 10), None, None, None, None, false).unwrap();
+        approve_internal(token, test_account(5), test_account(8), 500, None, None, None, None, None, false).unwrap();
+
+        let page = get_allowances_received(spender.clone(), None, None, None).unwrap();
+        assert_eq!(page.allowances.len(), 4);
+        assert!(page.next_cursor.is_none());
+
+        let page = get_allowances_received(spender.clone(), Some(other_token), None, None).unwrap();
+        assert_eq!(page.allowances.len(), 1);
+        assert_eq!(page.allowances[0].owner, test_account(3));
+        assert_eq!(page.allowances[0].allowance, 300);
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = get_allowances_received(spender.clone(), Some(token), cursor, Some(1)).unwrap();
+            seen.extend(page.allowances.into_iter().map(|a| a.owner));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(seen.len(), 3);
+        assert!(seen.contains(&test_subaccount(2, 7)));
+
+        runtime::advance_time(10);
+        let page = get_allowances_received(spender.clone(), Some(token), None, None).unwrap();
+        assert_eq!(page.allowances.len(), 2);
+
+        approve_internal(token, test_account(1), spender.clone(), 0, None, None, None, None, None, false).unwrap();
+        let page = get_allowances_received(spender, Some(token), None, None).unwrap();
+        assert_eq!(page.allowances.len(), 1);
+    }
+
+    #[test]
+    fn test_require_explicit_fee() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 10);
+        let free = register_test_token(2, 0);
+        let owner = test_account(1);
+        let spender = test_account(2);
+        state::seed_balance(token, owner.to_key(), 1000);
+        state::seed_balance(free, owner.to_key(), 1000);
+        state::update_token_require_explicit_fee(token, true).unwrap();
+        state::update_token_require_explicit_fee(free, true).unwrap();
+
+        match approve_internal(token, owner.clone(), spender.clone(), 500, None, None, None, None, None, false) {
+            Err(ApproveError::BadFee { expected_fee }) => assert_eq!(expected_fee, candid::Nat::from(10u64)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        approve_internal(token, owner.clone(), spender.clone(), 500, None, None, Some(10), None, None, false).unwrap();
+
+        match transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 100, None, None, None, false) {
+            Err(TransferError::BadFee { expected_fee }) => assert_eq!(expected_fee, candid::Nat::from(10u64)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 100, Some(10), None, None, false).unwrap();
+
+        // Zero-fee tokens are exempt: there is no fee that could change under the caller.
+        approve_internal(free, owner.clone(), spender.clone(), 500, None, None, None, None, None, false).unwrap();
+        transfer_from_internal(free, spender.clone(), owner.clone(), spender.clone(), 100, None, None, None, false).unwrap();
+
+        state::update_token_require_explicit_fee(token, false).unwrap();
+        runtime::advance_time(1);
+        transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 100, None, None, None, false).unwrap();
+    }
+
+    #[test]
+    fn test_retried_approve_charges_one_fee() {
+        use crate::test_utils::{fee_recipient, register_test_token, test_account};
+
+        let token = register_test_token(1, 10);
+        let (owner, spender) = (test_account(1), test_account(2));
+        state::seed_balance(token, owner.to_key(), 1000);
+        let now = runtime::time();
+        let approve = || approve_internal(
+            token, owner.clone(), spender.clone(), 500, Some(now + 100), Some(0), None, None, Some(now), false,
+        );
+
+        let tx_index = approve().unwrap();
+        // The first attempt changed the allowance; the retry is still a duplicate.
+        assert!(matches!(approve(), Err(ApproveError::Duplicate { duplicate_of }) if duplicate_of == tx_index));
+        runtime::advance_time(200);
+        assert!(matches!(approve(), Err(ApproveError::Duplicate { duplicate_of }) if duplicate_of == tx_index));
+        assert_eq!(state::get_balance(token, owner.to_key()), 990);
+        assert_eq!(state::get_balance(token, fee_recipient().to_key()), 10);
+        assert_eq!(state::get_transaction_count(), tx_index + 1);
+    }
+
+    #[test]
+    fn test_failed_approve_charges_no_fee() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let gas = register_test_token(2, 0);
+        let token = register_test_token(1, 10);
+        let (owner, spender) = (test_account(1), test_account(2));
+        state::seed_balance(token, owner.to_key(), 1000);
+        let now = runtime::time();
+
+        approve_internal(token, owner.clone(), spender.clone(), 500, None, None, None, Some(b"a"), Some(now), false).unwrap();
+        assert!(matches!(
+            approve_internal(token, owner.clone(), spender.clone(), 500, None, None, None, Some(b"a"), Some(now), false),
+            Err(ApproveError::Duplicate { .. })
+        ));
+        assert_eq!(state::get_balance(token, owner.to_key()), 990);
+
+        state::update_token_fee_token(token, Some(gas)).unwrap();
+        state::seed_balance(gas, owner.to_key(), 100);
+        assert!(matches!(
+            approve_internal(token, owner.clone(), spender.clone(), 500, None, None, None, Some(b"a"), Some(now), false),
+            Err(ApproveError::Duplicate { .. })
+        ));
+        assert_eq!(state::get_balance(gas, owner.to_key()), 100);
+        assert!(matches!(
+            transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 5000, None, Some(b"a"), Some(now), false),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
+        transfer_from_internal(token, spender.clone(), owner, spender, 100, None, Some(b"a"), Some(now), false).unwrap();
+    }
+
+    #[test]
+    fn test_admin_expire_allowance() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let controller = test_account(0xFE).owner;
+        let (owner, spender) = (test_account(2), test_account(3));
+        state::init_state(controller);
+        state::seed_balance(token, owner.to_key(), 1_000);
+        approve_internal(token, owner.clone(), spender.clone(), 1_000_000_000, Some(u64::MAX), None, None, None, None, false).unwrap();
+
+        runtime::set_caller(spender.owner);
+        assert!(admin_expire_allowance(token, owner.clone(), spender.clone(), "compromised".to_string()).is_err());
+
+        runtime::set_caller(controller);
+        assert!(admin_expire_allowance(token, owner.clone(), spender.clone(), " ".to_string()).is_err());
+        let entry = admin_expire_allowance(token, owner.clone(), spender.clone(), "compromised".to_string()).unwrap();
+        assert_eq!(entry.previous_expiry, Some(u64::MAX));
+        assert!(admin_expire_allowance(token, owner.clone(), spender.clone(), "again".to_string()).is_err());
+
+        let result = transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 1, None, None, None, false);
+        assert!(matches!(result, Err(TransferError::AllowanceExpired { expires_at }) if expires_at == runtime::time()));
+        assert_eq!(state::get_allowance(token, owner.to_key(), spender.to_key()), 0);
+        assert_eq!(crate::queries::list_admin_expired_allowances(0, 10), vec![entry.clone()]);
+        assert_eq!(state::list_admin_actions(entry.audit_index, 1)[0].action, "admin_expire_allowance");
+
+        // The owner can approve again, without an expiry.
+        runtime::advance_time(1);
+        approve_internal(token, owner.clone(), spender.clone(), 10, None, None, None, None, None, false).unwrap();
+        transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 10, None, None, None, false).unwrap();
+        assert_eq!(state::get_balance(token, spender.to_key()), 10);
+    }
+
+    #[test]
+    fn test_expired_allowance_reads_and_compares_as_zero() {
+        use crate::queries::{get_allowance, get_allowance_details};
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let (owner, spender) = (test_account(2), test_account(3));
+        state::seed_balance(token, owner.to_key(), 1_000);
+        let expires_at = runtime::time() + 10;
+        approve_internal(token, owner.clone(), spender.clone(), 500, Some(expires_at), None, None, None, None, false).unwrap();
+        runtime::set_caller(owner.owner);
+        assert_eq!(get_allowance(token, owner.clone(), spender.clone()).unwrap(), 500);
+
+        // Past the expiry the stored 500 is still there, but reads as zero.
+        runtime::advance_time(10);
+        assert_eq!(state::get_allowance(token, owner.to_key(), spender.to_key()), 500);
+        assert_eq!(get_allowance(token, owner.clone(), spender.clone()).unwrap(), 0);
+        let details = get_allowance_details(token, owner.clone(), spender.clone()).unwrap();
+        assert_eq!((details.allowance, details.expires_at), (0, Some(expires_at)));
+
+        // Expecting the stale amount is a change; expecting zero is not.
+        let stale = approve_internal(token, owner.clone(), spender.clone(), 300, None, Some(500), None, None, None, false);
+        assert!(matches!(stale, Err(ApproveError::AllowanceChanged { current_allowance }) if current_allowance == 0u64));
+        approve_internal(token, owner.clone(), spender.clone(), 300, None, Some(0), None, None, None, false).unwrap();
+        let details = get_allowance_details(token, owner.clone(), spender.clone()).unwrap();
+        assert_eq!((details.allowance, details.expires_at), (300, None));
+    }
+
+    #[test]
+    fn test_reapprove_replaces_or_clears_expiry() {
+        use crate::queries::get_allowance_details;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let (owner, spender) = (test_account(2), test_account(3));
+        let now = runtime::time();
+        let expiry = || get_allowance_details(token, owner.clone(), spender.clone()).unwrap().expires_at;
+
+        approve_internal(token, owner.clone(), spender.clone(), 500, Some(now + 100), None, None, None, None, false).unwrap();
+        assert_eq!(expiry(), Some(now + 100));
+        approve_internal(token, owner.clone(), spender.clone(), 600, Some(now + 200), None, None, None, None, false).unwrap();
+        assert_eq!(expiry(), Some(now + 200));
+        assert_eq!(state::count_expired_allowances(now + 150, 10), 0);
+
+        approve_internal(token, owner.clone(), spender.clone(), 400, None, None, None, None, None, false).unwrap();
+        assert_eq!(expiry(), None);
+        assert_eq!(state::count_expired_allowances(u64::MAX, 10), 0);
+
+        // Long after the old expiry the allowance still works.
+        runtime::advance_time(1_000);
+        state::seed_balance(token, owner.to_key(), 100);
+        transfer_from_internal(token, spender.clone(), owner.clone(), spender, 10, None, None, None, false).unwrap();
+        assert_eq!(state::get_allowance(token, owner.to_key(), test_account(3).to_key()), 390);
+    }
+
+    #[test]
+    fn test_adjust_allowance_clamps_keeps_expiry_and_charges_fee() {
+        use crate::queries::get_allowance_details;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 10);
+        let (owner, spender) = (test_account(2), test_account(3));
+        state::seed_balance(token, owner.to_key(), 1_000);
+        let now = runtime::time();
+        let details = || get_allowance_details(token, owner.clone(), spender.clone()).unwrap();
+        let adjust = |update, expires_at| adjust_allowance_internal(token, owner.clone(), spender.clone(), update, expires_at, None, None, None, false);
+
+        approve_internal(token, owner.clone(), spender.clone(), 500, Some(now + 100), None, None, None, None, false).unwrap();
+        let tx_index = adjust(AllowanceUpdate::Increase(100), None).unwrap();
+        assert_eq!(state::get_transaction(tx_index).unwrap().get_amount(), 600);
+        assert_eq!((details().allowance, details().expires_at), (600, Some(now + 100)));
+        assert_eq!(state::get_balance(token, owner.to_key()), 980);
+
+        adjust(AllowanceUpdate::Increase(0), Some(now + 200)).unwrap();
+        assert_eq!(details().expires_at, Some(now + 200));
+
+        let overflow = adjust(AllowanceUpdate::Increase(u128::MAX), None);
+        assert!(matches!(overflow, Err(ApproveError::GenericError { .. })));
+        assert_eq!(details().allowance, 600);
+
+        let tx_index = adjust(AllowanceUpdate::Decrease(1_000), None).unwrap();
+        assert_eq!(state::get_transaction(tx_index).unwrap().get_amount(), 0);
+        assert_eq!(details().allowance, 0);
+        assert_eq!(state::get_balance(token, owner.to_key()), 960);
+    }
+
+    #[test]
+    fn test_revoke_and_full_spend_leave_no_expiry() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 10);
+        let (owner, spender) = (test_account(2), test_account(3));
+        state::seed_balance(token, owner.to_key(), 1_000);
+        let now = runtime::time();
+        let revoke = || {
+            runtime::set_caller(owner.owner);
+            revoke_allowance(token, spender.clone(), None)
+        };
+
+        approve_internal(token, owner.clone(), spender.clone(), 500, Some(now + 100), None, None, None, None, false).unwrap();
+        let ApproveResultV2::Ok(receipt) = revoke() else { panic!("revoke failed") };
+        let tx = state::get_transaction(receipt.tx_index).unwrap();
+        assert_eq!((tx.op, tx.get_amount()), (OP_APPROVE, 0));
+        assert_eq!(state::get_allowance(token, owner.to_key(), spender.to_key()), 0);
+        assert_eq!(state::get_allowance_expiry(token, owner.to_key(), spender.to_key()), None);
+        assert_eq!(state::count_expired_allowances(u64::MAX, 10), 0);
+        assert_eq!(state::get_balance(token, owner.to_key()), 980);
+
+        // Nothing left to revoke, and a repeat at the same time, both succeed.
+        runtime::advance_time(1);
+        let ApproveResultV2::Ok(again) = revoke() else { panic!("revoke failed") };
+        assert!(matches!(revoke(), ApproveResultV2::Ok(repeat) if repeat == again));
+        assert_eq!(state::get_balance(token, owner.to_key()), 970);
+
+        // The standard fee is charged without being named, even where approve
+        // has to name it.
+        state::update_token_require_explicit_fee(token, true).unwrap();
+        runtime::advance_time(1);
+        assert!(matches!(revoke(), ApproveResultV2::Ok(_)));
+        assert_eq!(state::get_balance(token, owner.to_key()), 960);
+        state::update_token_require_explicit_fee(token, false).unwrap();
+
+        approve_internal(token, owner.clone(), spender.clone(), 110, Some(now + 100), None, None, None, None, false).unwrap();
+        transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 100, None, None, None, false).unwrap();
+        assert_eq!(state::get_allowance_expiry(token, owner.to_key(), spender.to_key()), None);
+        assert_eq!(state::count_expired_allowances(u64::MAX, 10), 0);
+    }
+
+    #[test]
+    fn test_burn_from_draws_down_the_allowance() {
+        use crate::operations::mint_internal;
+        use crate::queries::{get_allowance_at, AllowanceAt};
+        use crate::test_utils::{register_test_token, test_account};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 10);
+        let (owner, bridge, other) = (test_account(2), test_account(3), test_account(4));
+        mint_internal(token, owner.clone(), 1_000, None, None).unwrap();
+        let now = runtime::time();
+        approve_internal(token, owner.clone(), bridge.clone(), 300, Some(now + 100), None, None, None, None, false).unwrap();
+        let allowance = || state::get_allowance(token, owner.to_key(), bridge.to_key());
+        let burn = |amount: u64, at| burn_from(token, owner.clone(), candid::Nat::from(amount), None, Some(at));
+
+        runtime::set_caller(other.owner);
+        assert!(matches!(burn(1, now), Err(TransferError::InsufficientFunds { balance }) if balance == 0u64));
+
+        // No fee: the allowance and the balance go down by the amount alone.
+        runtime::set_caller(bridge.owner);
+        let tx_index = burn(100, now).unwrap();
+        assert_eq!(allowance(), 200);
+        assert_eq!(state::get_balance(token, owner.to_key()), 1_000 - 10 - 100);
+        assert_eq!(state::get_token_metadata(token).unwrap().total_supply, 1_000 - 100);
+        let tx = state::get_transaction(tx_index).unwrap();
+        assert_eq!((tx.op, tx.get_fee(), tx.from_key, tx.spender_key), (OP_BURN, 0, owner.to_key(), bridge.to_key()));
+        assert!(tx.has_spender() && tx.get_burn_target().is_none());
+        assert!(matches!(burn(100, now), Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == tx_index));
+        assert!(matches!(burn(201, now + 1), Err(TransferError::InsufficientFunds { balance }) if balance == 200u64));
+        assert!(matches!(
+            get_allowance_at(token, owner.clone(), bridge.clone(), tx_index, None),
+            Ok(AllowanceAt::Found { allowance: 200, .. })
+        ));
+
+        // Spending it all clears the expiry; an expired allowance burns nothing.
+        burn(200, now + 1).unwrap();
+        assert_eq!((allowance(), state::get_allowance_expiry(token, owner.to_key(), bridge.to_key())), (0, None));
+        runtime::set_caller(owner.owner);
+        approve_internal(token, owner.clone(), bridge.clone(), 50, Some(now + 100), None, None, None, None, false).unwrap();
+        runtime::advance_time(100);
+        runtime::set_caller(bridge.owner);
+        let result = burn(10, runtime::time());
+        assert!(matches!(result, Err(TransferError::AllowanceExpired { expires_at }) if expires_at == now + 100));
+        assert_eq!(allowance(), 0);
+
+        runtime::set_caller(owner.owner);
+        approve_internal(token, owner.clone(), bridge.clone(), UNLIMITED_ALLOWANCE, None, None, None, None, None, false).unwrap();
+        runtime::set_caller(bridge.owner);
+        burn(10, runtime::time()).unwrap();
+        assert_eq!(allowance(), UNLIMITED_ALLOWANCE);
+        assert_eq!(state::get_token_metadata(token).unwrap().total_supply, 1_000 - 310);
+    }
+
+    #[test]
+    fn test_unlimited_allowance_is_never_drawn_down() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 10);
+        let (owner, spender) = (test_account(2), test_account(3));
+        state::seed_balance(token, owner.to_key(), 1_000);
+        let allowance = || state::get_allowance(token, owner.to_key(), spender.to_key());
+
+        approve_internal(token, owner.clone(), spender.clone(), UNLIMITED_ALLOWANCE, None, None, None, None, None, false).unwrap();
+        for _ in 0..5 {
+            runtime::advance_time(1);
+            transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 100, None, None, None, false).unwrap();
+            assert_eq!(allowance(), UNLIMITED_ALLOWANCE);
+        }
+        assert_eq!(state::get_balance(token, owner.to_key()), 440);
+        assert_eq!(crate::queries::get_allowance(token, owner.clone(), spender.clone()).unwrap(), u128::MAX);
+
+        // The owner's balance still limits what is pulled.
+        runtime::advance_time(1);
+        let result = transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 431, None, None, None, false);
+        assert!(matches!(result, Err(TransferError::InsufficientFunds { balance }) if balance == 440u64));
+
+        // Increasing keeps it unlimited; decreasing turns it into an ordinary one.
+        runtime::advance_time(1);
+        adjust_allowance_internal(token, owner.clone(), spender.clone(), AllowanceUpdate::Increase(1), None, None, None, None, false).unwrap();
+        assert_eq!(allowance(), UNLIMITED_ALLOWANCE);
+        runtime::advance_time(1);
+        adjust_allowance_internal(token, owner.clone(), spender.clone(), AllowanceUpdate::Decrease(1), None, None, None, None, false).unwrap();
+        transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 100, None, None, None, false).unwrap();
+        assert_eq!(allowance(), u128::MAX - 111);
+    }
+
+    #[test]
+    fn test_approvals_of_different_spenders_in_one_window_are_not_duplicates() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let (owner, first, second) = (test_account(2), test_account(3), test_account(4));
+        let now = runtime::time();
+        let approve = |spender: &Account, amount| approve_internal(token, owner.clone(), spender.clone(), amount, None, None, None, None, Some(now), false);
+
+        let first_index = approve(&first, 100).unwrap();
+        approve(&second, 100).unwrap();
+        approve(&second, 200).unwrap();
+        assert_eq!(state::get_allowance(token, owner.to_key(), first.to_key()), 100);
+        assert_eq!(state::get_allowance(token, owner.to_key(), second.to_key()), 200);
+        assert!(matches!(approve(&first, 100), Err(ApproveError::Duplicate { duplicate_of }) if duplicate_of == first_index));
+    }
+
+    #[test]
+    fn test_adjust_allowance_dedup_and_expired_start() {
+        use crate::queries::get_allowance_details;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let (owner, spender) = (test_account(2), test_account(3));
+        let now = runtime::time();
+        let details = || get_allowance_details(token, owner.clone(), spender.clone()).unwrap();
+        let adjust = |update| adjust_allowance_internal(token, owner.clone(), spender.clone(), update, None, None, Some(b"a"), Some(now), false);
+
+        // Same window, memo and number: each kind of change is new once.
+        approve_internal(token, owner.clone(), spender.clone(), 5, Some(now + 10), None, None, Some(b"a"), Some(now), false).unwrap();
+        let increased = adjust(AllowanceUpdate::Increase(5)).unwrap();
+        adjust(AllowanceUpdate::Decrease(5)).unwrap();
+        assert!(matches!(adjust(AllowanceUpdate::Increase(5)), Err(ApproveError::Duplicate { duplicate_of }) if duplicate_of == increased));
+        assert_eq!(details().allowance, 5);
+
+        // Once expired, an adjustment starts from zero and drops the old expiry.
+        runtime::advance_time(10);
+        adjust_allowance_internal(token, owner.clone(), spender.clone(), AllowanceUpdate::Increase(7), None, None, None, None, false).unwrap();
+        assert_eq!((details().allowance, details().expires_at), (7, None));
+    }
+
+    #[test]
+    fn test_transfer_from_reports_and_drops_expired_allowance() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let (owner, spender) = (test_account(2), test_account(3));
+        state::seed_balance(token, owner.to_key(), 1_000);
+        let expires_at = runtime::time() + 100;
+        approve_internal(token, owner.clone(), spender.clone(), 500, Some(expires_at), None, None, None, None, false).unwrap();
+        transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 10, None, None, None, false).unwrap();
+
+        runtime::advance_time(100);
+        let result = transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 10, None, None, None, false);
+        assert!(matches!(result, Err(TransferError::AllowanceExpired { expires_at: at }) if at == expires_at));
+        assert_eq!(state::get_allowance(token, owner.to_key(), spender.to_key()), 0);
+        assert_eq!(state::get_allowance_expiry(token, owner.to_key(), spender.to_key()), None);
+        assert_eq!(state::count_expired_allowances(u64::MAX, 10), 0);
+        assert_eq!(state::get_balance(token, spender.to_key()), 10);
+
+        // With the allowance gone, a retry finds none rather than an expired one.
+        let retry = transfer_from_internal(token, spender.clone(), owner.clone(), spender.clone(), 10, None, None, None, false);
+        assert!(matches!(retry, Err(TransferError::InsufficientFunds { balance }) if balance == 0u64));
+        assert_eq!(state::get_allowance_expiry(token, owner.to_key(), spender.to_key()), None);
+        assert_eq!(state::count_expired_allowances(u64::MAX, 10), 0);
+    }
+
+    #[test]
+    fn test_balance_changes_follow_account_index() {
+        use crate::operations::transfer_internal;
+        use crate::queries::get_balance_changes;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 10);
+        let gas = register_test_token(2, 0);
+        let paid_in_gas = register_test_token(3, 5);
+        state::update_token_fee_token(paid_in_gas, Some(gas)).unwrap();
+        let (owner, spender, recipient) = (test_account(2), test_account(3), test_account(4));
+        let tokens = [token, gas, paid_in_gas];
+        for t in tokens {
+            state::seed_balance(t, owner.to_key(), 1_000);
+        }
+
+        let start = state::get_transaction_count();
+        approve_internal(token, owner.clone(), spender.clone(), 500, None, None, None, None, None, false).unwrap();
+        runtime::advance_time(1);
+        transfer_from_internal(token, spender.clone(), owner.clone(), recipient.clone(), 100, None, None, None, false).unwrap();
+        transfer_internal(paid_in_gas, owner.clone(), spender.clone(), 50, None, None, None).unwrap();
+        transfer_internal(token, recipient.clone(), owner.clone(), 30, None, None, None).unwrap();
+
+        let page = get_balance_changes(owner.clone(), start, None).unwrap();
+        assert!(page.complete);
+        assert_eq!(page.next_since_tx_index, state::get_transaction_count());
+        let deltas: Vec<(TokenId, i64, u64)> = page.changes.iter()
+            .map(|c| (c.token_id, c.delta.0.to_i64().unwrap(), c.tx_index - start))
+            .collect();
+        assert_eq!(deltas, vec![
+            (token, -10, 0),
+            (token, -110, 1),
+            (paid_in_gas, -50, 2),
+            (gas, -5, 2),
+            (token, 30, 3),
+        ]);
+        for t in tokens {
+            let net: i64 = deltas.iter().filter(|d| d.0 == t).map(|d| d.1).sum();
+            let current = page.balances.iter().find(|b| b.token_id == t).unwrap().balance;
+            assert_eq!(current as i64, 1_000 + net);
+        }
+
+        // Both sides of transfer_from; the spender only sees what it received.
+        let received = get_balance_changes(recipient.clone(), start, None).unwrap().changes;
+        assert_eq!(received.iter().map(|c| c.delta.0.to_i64().unwrap()).collect::<Vec<_>>(), vec![100, -40]);
+        let spent = get_balance_changes(spender, start, None).unwrap().changes;
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent[0].tx_index - start, 2);
+
+        // Paging resumes where the previous page stopped.
+        let first = get_balance_changes(owner.clone(), start, Some(2)).unwrap();
+        assert!(!first.complete);
+        assert_eq!(first.changes.len(), 2);
+        let rest = get_balance_changes(owner, first.next_since_tx_index, Some(2)).unwrap();
+        assert_eq!(rest.changes.len(), 3);
+        assert!(!rest.complete);
+    }
+
+    #[test]
+    fn test_balance_changes_credit_fee_income() {
+        use crate::operations::transfer_internal;
+        use crate::queries::get_balance_changes;
+        use crate::test_utils::{fee_recipient, register_test_token, test_account};
+
+        let token = register_test_token(1, 10);
+        let gas = register_test_token(2, 0);
+        let paid_in_gas = register_test_token(3, 5);
+        state::update_token_fee_token(paid_in_gas, Some(gas)).unwrap();
+        let (alice, bob, treasury, collector) = (test_account(2), test_account(3), test_account(4), fee_recipient());
+        let tokens = [token, gas, paid_in_gas];
+        for t in tokens {
+            state::seed_balance(t, alice.to_key(), 1_000);
+            state::seed_balance(t, collector.to_key(), 1_000);
+        }
+
+        let start = state::get_transaction_count();
+        transfer_internal(token, alice.clone(), bob.clone(), 100, None, None, None).unwrap();
+        // The collector pays the fee to itself: 100 leaves, the fee comes back.
+        transfer_internal(token, collector.clone(), bob.clone(), 100, None, None, None).unwrap();
+        state::set_protocol_commission(1_000, &treasury).unwrap();
+        transfer_internal(token, alice.clone(), bob.clone(), 200, None, None, None).unwrap();
+        transfer_internal(token, collector.clone(), alice.clone(), 100, None, None, None).unwrap();
+        transfer_internal(paid_in_gas, alice.clone(), bob.clone(), 50, None, None, None).unwrap();
+        approve_internal(token, alice.clone(), bob.clone(), 500, None, None, None, None, None, false).unwrap();
+
+        let deltas = |account: &Account| -> Vec<(TokenId, i64, u64)> {
+            get_balance_changes(account.clone(), start, None).unwrap().changes.iter()
+                .map(|c| (c.token_id, c.delta.0.to_i64().unwrap(), c.tx_index - start))
+                .collect()
+        };
+        assert_eq!(deltas(&collector), vec![
+            (token, 10, 0),
+            (token, -100, 1),
+            (token, 9, 2),
+            (token, -101, 3),
+            (gas, 5, 4),
+            (token, 9, 5),
+        ]);
+        assert_eq!(deltas(&treasury), vec![(token, 1, 2), (token, 1, 3), (token, 1, 5)]);
+
+        // Every account's deltas add up to its balance change.
+        for account in [&alice, &bob, &collector, &treasury] {
+            let changes = deltas(account);
+            for t in tokens {
+                let net: i64 = changes.iter().filter(|d| d.0 == t).map(|d| d.1).sum();
+                let seeded = if *account == alice || *account == collector { 1_000 } else { 0 };
+                assert_eq!(state::get_balance(t, account.to_key()) as i64, seeded + net, "{:?} {:?}", account, t);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cleanup_my_allowances() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let other_token = register_test_token(2, 0);
+        let owner = test_account(1);
+        let now = runtime::time();
+
+        approve_internal(token, owner.clone(), test_account(2), 5, None, None, None, None, Some(now), false).unwrap();
+        approve_internal(token, owner.clone(), test_account(3), 1_000, Some(now + 10), None, None, None, Some(now - 1), false).unwrap();
+        approve_internal(token, owner.clone(), test_account(4), 1_000, None, None, None, None, Some(now - 2), false).unwrap();
+        approve_internal(other_token, owner.clone(), test_account(2), 5, None, None, None, None, None, false).unwrap();
+        let tx_count = state::get_transaction_count();
+
+        runtime::advance_time(100);
+
+        // Expired for 90ns only, so a one-second grace keeps it.
+        let result = cleanup_allowances_internal(token, &owner, 1_000_000_000, 10, None, 100);
+        assert_eq!(result.removed, 1);
+        assert_eq!(state::get_allowance(token, owner.to_key(), test_account(2).to_key()), 0);
+        assert_eq!(state::get_allowance(token, owner.to_key(), test_account(3).to_key()), 1_000);
+
+        // Page through one entry at a time.
+        let first = cleanup_allowances_internal(token, &owner, 0, 0, None, 1);
+        assert_eq!(first.removed, 1);
+        assert_eq!(first.next_cursor, Some(test_account(3)));
+        let second = cleanup_allowances_internal(token, &owner, 0, 0, first.next_cursor.map(|a| a.to_key()), 1);
+        assert_eq!(second.removed, 0);
+        assert_eq!(second.next_cursor, Some(test_account(4)));
+        let last = cleanup_allowances_internal(token, &owner, 0, 0, second.next_cursor.map(|a| a.to_key()), 1);
+        assert_eq!(last.next_cursor, None);
+
+        assert_eq!(state::get_allowance(token, owner.to_key(), test_account(3).to_key()), 0);
+        assert_eq!(state::get_allowance_expiry(token, owner.to_key(), test_account(3).to_key()), None);
+        assert_eq!(state::get_allowance(token, owner.to_key(), test_account(4).to_key()), 1_000);
+        assert_eq!(state::get_allowance(other_token, owner.to_key(), test_account(2).to_key()), 5);
+        assert_eq!(state::get_allowances_cleaned_up(), 2);
+        assert_eq!(state::get_transaction_count(), tx_count);
+    }
+
+    #[test]
+    fn test_find_my_allowance_subaccounts() {
+        use crate::queries::find_my_allowance;
+        use crate::test_utils::{register_test_token, test_account, test_subaccount};
+
+        let token = register_test_token(1, 0);
+        let owner = test_account(1);
+        let other_owner = test_account(2);
+        let spender = test_account(9);
+        let named = test_subaccount(9, 5);
+        let now = runtime::time();
+        state::seed_balance(token, owner.to_key(), 1000);
+
+        approve_internal(token, owner.clone(), named.clone(), 300, None, None, None, None, Some(now), false).unwrap();
+        approve_internal(token, other_owner.clone(), spender.clone(), 100, None, None, None, None, Some(now), false).unwrap();
+
+        runtime::set_caller(spender.owner);
+        let found = find_my_allowance(token, owner.clone()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].spender, named);
+        assert_eq!(found[0].allowance, 300);
+
+        let found = find_my_allowance(token, other_owner.clone()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].spender, spender);
+
+        // Spending from the default subaccount points at the named one.
+        match transfer_from_internal(token, spender.clone(), owner.clone(), test_account(3), 10, None, None, None, false) {
+            Err(TransferError::GenericError { error_code, message }) => {
+                assert_eq!(error_code, candid::Nat::from(403u64));
+                assert!(message.contains(&hex_encode(&[5u8; 32])));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        transfer_from_internal(token, named.clone(), owner.clone(), test_account(3), 10, None, None, None, false).unwrap();
+
+        // Without any grant the original error stays.
+        assert!(matches!(
+            transfer_from_internal(token, spender.clone(), test_account(4), test_account(3), 10, None, None, None, false),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_zero_subaccount_aliases_default_account() {
+        use crate::queries::find_my_allowance;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let owner = test_account(1);
+        let spender = test_account(9);
+        let zero = |account: &Account| Account { owner: account.owner, subaccount: Some(vec![0u8; 32]) };
+        state::seed_balance(token, owner.to_key(), 1000);
+
+        assert!(approve_internal(token, owner.clone(), zero(&owner), 100, None, None, None, None, None, false).is_err());
+        assert!(approve_internal(token, zero(&owner), owner.clone(), 100, None, None, None, None, None, false).is_err());
+
+        // Approving the zero subaccount is approving the default account: one
+        // entry, found once, not listed as a named subaccount.
+        approve_internal(token, owner.clone(), zero(&spender), 100, None, None, None, None, None, false).unwrap();
+        assert!(state::list_spender_subaccounts(spender.owner, 10).is_empty());
+        runtime::set_caller(spender.owner);
+        let found = find_my_allowance(token, owner.clone()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].spender.clone(), found[0].allowance), (spender.clone(), 100));
+        transfer_from_internal(token, spender.clone(), owner.clone(), test_account(3), 10, None, None, None, false).unwrap();
+        // Spending from the zero subaccount draws on the same allowance.
+        transfer_from_internal(token, spender.clone(), zero(&owner), owner.clone(), 10, None, None, None, false).unwrap();
+        assert_eq!(state::get_allowance(token, owner.to_key(), spender.to_key()), 80);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::queries::QueryError;
+use crate::state;
+use crate::types::TokenId;
+use crate::validation::validate_token_id;
+use num_traits::cast::ToPrimitive;
+
+
+// Amounts are integers in the token's smallest unit; `decimals` only says
+// where the point goes when showing them. Both directions work on the digit
+// string rather than on 10^decimals, so any u128 and any decimals (including
+// more than u128 has digits for) are handled without overflow.
+
+
+// Longest text parse_token_amount looks at: u128::MAX has 39 digits, plus a
+// point and room for leading or trailing zeros.
+pub const MAX_AMOUNT_TEXT_LEN: usize = 100;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    Negative,
+    // Anything but digits and at most one point.
+    InvalidFormat,
+    // Non-zero digits past the token's decimals.
+    TooPrecise { decimals: u8 },
+    Overflow,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Amount is empty"),
+            ParseError::Negative => write!(f, "Amount cannot be negative"),
+            ParseError::InvalidFormat => write!(f, "Amount must be digits with at most one decimal point"),
+            ParseError::TooPrecise { decimals } => write!(f, "Amount has more than {} decimal places", decimals),
+            ParseError::Overflow => write!(f, "Amount exceeds maximum value (u128::MAX)"),
+        }
+    }
+}
+
+
+// 1234500000 with 8 decimals is "12.345": trailing zeros of the fraction are
+// dropped, and so is the point when nothing is left after it.
+pub fn format_amount(amount: u128, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+
+    let padded = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = padded.split_at(padded.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+
+// The inverse of format_amount. Leading zeros, a leading or trailing point
+// (".5", "5.") and zeros past the token's decimals are accepted; signs,
+// separators, exponents and surrounding whitespace are not.
+pub fn parse_amount(text: &str, decimals: u8) -> Result<u128, ParseError> {
+    if text.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if text.starts_with('-') {
+        return Err(ParseError::Negative);
+    }
+
+    let (whole, fraction) = match text.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (text, ""),
+    };
+    let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !all_digits(whole) || !all_digits(fraction) {
+        return Err(ParseError::InvalidFormat);
+    }
+
+    let (fraction, excess) = fraction.split_at(fraction.len().min(decimals as usize));
+    if excess.bytes().any(|b| b != b'0') {
+        return Err(ParseError::TooPrecise { decimals });
+    }
+
+    let missing_zeros = decimals as usize - fraction.len();
+    let mut digits = whole.bytes().chain(fraction.bytes()).chain(std::iter::repeat_n(b'0', missing_zeros));
+    digits.try_fold(0u128, |value, digit| {
+        value.checked_mul(10)?.checked_add((digit - b'0') as u128)
+    }).ok_or(ParseError::Overflow)
+}
+
+
+fn token_decimals(token_id: TokenId) -> Result<u8, QueryError> {
+    validate_token_id(&token_id)?;
+    Ok(state::get_token_metadata(token_id)?.decimals)
+}
+
+
+// format_amount with the token's decimals.
+#[ic_cdk::query]
+pub fn format_token_amount(token_id: TokenId, amount: candid::Nat) -> Result<String, QueryError> {
+    let decimals = token_decimals(token_id)?;
+    let amount = amount.0.to_u128().ok_or_else(|| QueryError::InvalidInput(ParseError::Overflow.to_string()))?;
+    Ok(format_amount(amount, decimals))
+}
+
+
+// parse_amount with the token's decimals.
+#[ic_cdk::query]
+pub fn parse_token_amount(token_id: TokenId, text: String) -> Result<u128, QueryError> {
+    let decimals = token_decimals(token_id)?;
+    if text.len() > MAX_AMOUNT_TEXT_LEN {
+        return Err(QueryError::InvalidInput(format!("Amount text too long (max {} bytes)", MAX_AMOUNT_TEXT_LEN)));
+    }
+    parse_amount(&text, decimals).map_err(|e| QueryError::InvalidInput(e.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u128 = u128::MAX;
+
+    #[test]
+    fn test_format_amount() {
+        let cases: &[(u128, u8, &str)] = &[
+            (1_234_500_000, 8, "12.345"),
+            (1_234_500_000, 0, "1234500000"),
+            (0, 0, "0"),
+            (0, 8, "0"),
+            (1, 8, "0.00000001"),
+            (10, 1, "1"),
+            (100_000_000, 8, "1"),
+            (120_000_000, 8, "1.2"),
+            (5, 3, "0.005"),
+            (MAX, 0, "340282366920938463463374607431768211455"),
+            (MAX, 18, "340282366920938463463.374607431768211455"),
+            (MAX, 38, "3.40282366920938463463374607431768211455"),
+            (MAX, 39, "0.340282366920938463463374607431768211455"),
+            (MAX - 455, 3, "340282366920938463463374607431768211"),
+            (MAX - 455, 9, "340282366920938463463374607431.768211"),
+            (1, 255, &format!("0.{}1", "0".repeat(254))),
+        ];
+        for (amount, decimals, expected) in cases {
+            assert_eq!(format_amount(*amount, *decimals), *expected, "{} with {} decimals", amount, decimals);
+        }
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        let cases: &[(&str, u8, u128)] = &[
+            ("12.345", 8, 1_234_500_000),
+            ("0", 0, 0),
+            ("0.0", 8, 0),
+            ("000012.34500", 8, 1_234_500_000),
+            ("12.34500000000", 8, 1_234_500_000),
+            (".5", 1, 5),
+            ("5.", 1, 50),
+            ("7", 0, 7),
+            ("7.000", 0, 7),
+            ("0.00000001", 8, 1),
+            ("340282366920938463463374607431768211455", 0, MAX),
+            ("340282366920938463463.374607431768211455", 18, MAX),
+            ("0340282366920938463463.3746074317682114550", 18, MAX),
+            ("3.40282366920938463463374607431768211455", 38, MAX),
+            ("0.340282366920938463463374607431768211455", 39, MAX),
+            ("340282366920938463463.374607431768211454", 18, MAX - 1),
+        ];
+        for (text, decimals, expected) in cases {
+            assert_eq!(parse_amount(text, *decimals), Ok(*expected), "{:?} with {} decimals", text, decimals);
+        }
+    }
+
+    #[test]
+    fn test_parse_amount_rejects() {
+        let cases: &[(&str, u8, ParseError)] = &[
+            ("", 8, ParseError::Empty),
+            ("-1", 8, ParseError::Negative),
+            ("-0", 8, ParseError::Negative),
+            ("+1", 8, ParseError::InvalidFormat),
+            (".", 8, ParseError::InvalidFormat),
+            ("1.2.3", 8, ParseError::InvalidFormat),
+            (" 1", 8, ParseError::InvalidFormat),
+            ("1 ", 8, ParseError::InvalidFormat),
+            ("1,000", 8, ParseError::InvalidFormat),
+            ("1_000", 8, ParseError::InvalidFormat),
+            ("1e8", 8, ParseError::InvalidFormat),
+            ("0x10", 8, ParseError::InvalidFormat),
+            ("١", 0, ParseError::InvalidFormat),
+            ("0.000000001", 8, ParseError::TooPrecise { decimals: 8 }),
+            ("1.5", 0, ParseError::TooPrecise { decimals: 0 }),
+            ("1.50001", 2, ParseError::TooPrecise { decimals: 2 }),
+            ("340282366920938463463374607431768211456", 0, ParseError::Overflow),
+            ("340282366920938463463.374607431768211456", 18, ParseError::Overflow),
+            ("340282366920938463464", 18, ParseError::Overflow),
+            ("1", 39, ParseError::Overflow),
+            ("99999999999999999999999999999999999999999", 0, ParseError::Overflow),
+        ];
+        for (text, decimals, expected) in cases {
+            assert_eq!(parse_amount(text, *decimals), Err(expected.clone()), "{:?} with {} decimals", text, decimals);
+        }
+    }
+
+    #[test]
+    fn test_format_then_parse_round_trips() {
+        let amounts = [0, 1, 9, 10, 1_000, 1_234_500_000, u64::MAX as u128, u64::MAX as u128 + 1, MAX / 2, MAX - 1, MAX];
+        for decimals in [0u8, 1, 2, 6, 8, 18, 30, 38, 39, 40, 77, 255] {
+            for amount in amounts {
+                assert_eq!(parse_amount(&format_amount(amount, decimals), decimals), Ok(amount), "{} with {} decimals", amount, decimals);
+            }
+        }
+    }
+
+    #[test]
+    fn test_token_amount_queries_use_metadata_decimals() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        state::init_state(test_account(0xFE).owner);
+        let token = register_test_token(1, 0);
+        let decimals = state::get_token_metadata(token).unwrap().decimals;
+        let one = 10u128.pow(decimals as u32);
+
+        assert_eq!(format_token_amount(token, candid::Nat::from(one + one / 2)).unwrap(), "1.5");
+        assert_eq!(parse_token_amount(token, "1.5".to_string()).unwrap(), one + one / 2);
+        assert!(matches!(parse_token_amount(token, "-1".to_string()), Err(QueryError::InvalidInput(_))));
+        assert!(matches!(parse_token_amount(token, "1".repeat(MAX_AMOUNT_TEXT_LEN + 1)), Err(QueryError::InvalidInput(_))));
+        let too_big = candid::Nat::from(MAX) + candid::Nat::from(1u64);
+        assert!(matches!(format_token_amount(token, too_big), Err(QueryError::InvalidInput(_))));
+        assert!(matches!(format_token_amount([9u8; 32], candid::Nat::from(1u64)), Err(QueryError::TokenNotFound)));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::allowances::hex_encode;
+use crate::runtime;
+use crate::state;
+use crate::types::{ApiAccessGrant, ApiPermissions, StoredTokenMetadata, TokenId};
+use crate::validation::validate_admin_principal;
+use candid::Principal;
+
+
+// Token-scoped access for integration backends that should not hold a wallet
+// identity of their own. Grants come from a controller or the token's
+// controller and never carry more than the granter holds. The granter's
+// authority is checked again on every use, so a controller that is removed
+// takes its grants' mint and read rights with it.
+
+
+// What `who` holds for the token: controllers everything, auditors private
+// reads, and the token's controller disbursement from its own accounts.
+fn authority(metadata: &StoredTokenMetadata, who: &Principal) -> ApiPermissions {
+    let controller = state::is_controller(who);
+    ApiPermissions {
+        can_mint: controller,
+        can_query_private: controller || state::is_auditor(who),
+        can_disburse: controller || metadata.controller == *who,
+    }
+}
+
+
+pub(crate) fn can_manage(metadata: &StoredTokenMetadata, who: &Principal) -> bool {
+    state::is_controller(who) || metadata.controller == *who
+}
+
+
+// The principal's grant for the token if it is unexpired and both it and its
+// granter hold the permission.
+pub(crate) fn permits(token_id: TokenId, principal: &Principal, permission: fn(&ApiPermissions) -> bool) -> Option<ApiAccessGrant> {
+    let grant = state::get_api_grant(token_id, principal)?;
+    if grant.is_expired(runtime::time()) || !permission(&grant.permissions) {
+        return None;
+    }
+    let metadata = state::get_token_metadata(token_id).ok()?;
+    permission(&authority(&metadata, &grant.granted_by)).then_some(grant)
+}
+
+
+pub(crate) fn require_minter(token_id: TokenId) -> Result<(), String> {
+    check_minter(token_id, &runtime::caller())
+}
+
+
+pub(crate) fn check_minter(token_id: TokenId, who: &Principal) -> Result<(), String> {
+    if state::is_controller(who) || permits(token_id, who, |p| p.can_mint).is_some() {
+        return Ok(());
+    }
+    Err("Only controller can perform this operation".to_string())
+}
+
+
+// The principal whose accounts `who` may disburse from, besides its own.
+pub(crate) fn disburses_for(token_id: TokenId, who: &Principal) -> Option<Principal> {
+    permits(token_id, who, |p| p.can_disburse).map(|grant| grant.granted_by)
+}
+
+
+// Granting to a principal that already has a grant for the token replaces it.
+#[ic_cdk::update]
+pub fn grant_token_api_access(
+    token_id: TokenId,
+    principal: candid::Principal,
+    permissions: ApiPermissions,
+    expires_at: Option<u64>,
+) -> Result<(), String> {
+    let caller = runtime::caller();
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    if !can_manage(&metadata, &caller) {
+        return Err("Only a controller or the token's controller can grant API access".to_string());
+    }
+    validate_admin_principal(&principal).map_err(|e| e.to_string())?;
+    if permissions.is_empty() {
+        return Err("At least one permission is required".to_string());
+    }
+    if !permissions.within(&authority(&metadata, &caller)) {
+        return Err("Cannot grant a permission the caller does not hold".to_string());
+    }
+    let now = runtime::time();
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err("Expiry must be in the future".to_string());
+    }
+    if state::get_api_grant(token_id, &principal).is_some_and(|existing| existing.granted_by != caller) && !state::is_controller(&caller) {
+        return Err("The principal already holds a grant from someone else".to_string());
+    }
+
+    state::set_api_grant(token_id, ApiAccessGrant {
+        principal,
+        permissions,
+        granted_by: caller,
+        granted_at: now,
+        expires_at,
+    })?;
+    state::record_admin_action("grant_token_api_access", format!(
+        "{}: {} {:?} until {:?}", hex_encode(&token_id), principal.to_text(), permissions, expires_at
+    ));
+    Ok(())
+}
+
+
+// Controllers can revoke any grant, others only the ones they made.
+#[ic_cdk::update]
+pub fn revoke_token_api_access(token_id: TokenId, principal: candid::Principal) -> Result<(), String> {
+    let caller = runtime::caller();
+    let grant = state::get_api_grant(token_id, &principal).ok_or("No API grant for this principal")?;
+    if grant.granted_by != caller && !state::is_controller(&caller) {
+        return Err("Only a controller or the granter can revoke API access".to_string());
+    }
+
+    state::remove_api_grant(token_id, &principal);
+    state::record_admin_action("revoke_token_api_access", format!(
+        "{}: {}", hex_encode(&token_id), principal.to_text()
+    ));
+    Ok(())
+}
+
+
+// Expired grants stay listed until revoked.
+#[ic_cdk::query]
+pub fn list_token_api_access(token_id: TokenId) -> Result<Vec<ApiAccessGrant>, String> {
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    if !can_manage(&metadata, &runtime::caller()) {
+        return Err("Only a controller or the token's controller can list API access".to_string());
+    }
+    Ok(state::list_api_grants(token_id))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{disburse_from, mint_tokens, DisburseError, Payout};
+    use crate::queries::{get_admin_audit_log, get_balance};
+    use crate::test_utils::{register_test_token, test_account, test_metadata};
+    use candid::Nat;
+
+    #[test]
+    fn test_api_principal_mints_within_its_token() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let (token, other_token) = (register_test_token(1, 0), register_test_token(2, 0));
+        let (api, alice) = (test_account(9).owner, test_account(1));
+        let mint = ApiPermissions { can_mint: true, ..Default::default() };
+
+        runtime::set_caller(controller);
+        let expires_at = runtime::time() + 100;
+        assert!(grant_token_api_access(token, api, ApiPermissions::default(), None).is_err());
+        assert!(grant_token_api_access(token, api, mint, Some(runtime::time())).is_err());
+        grant_token_api_access(token, api, mint, Some(expires_at)).unwrap();
+
+        runtime::set_caller(api);
+        mint_tokens(token, alice.clone(), Nat::from(100u64), None).unwrap();
+        assert_eq!(state::get_balance(token, alice.to_key()), 100);
+        assert!(mint_tokens(other_token, alice.clone(), Nat::from(100u64), None).is_err());
+        assert!(grant_token_api_access(token, test_account(8).owner, mint, None).is_err());
+        assert!(list_token_api_access(token).is_err());
+
+        runtime::advance_time(100);
+        assert!(mint_tokens(token, alice.clone(), Nat::from(100u64), None).is_err());
+
+        runtime::set_caller(controller);
+        let grants = list_token_api_access(token).unwrap();
+        assert_eq!(grants.len(), 1);
+        assert_eq!((grants[0].principal, grants[0].granted_by, grants[0].expires_at), (api, controller, Some(expires_at)));
+        assert!(list_token_api_access(other_token).unwrap().is_empty());
+
+        grant_token_api_access(token, api, mint, None).unwrap();
+        runtime::set_caller(api);
+        mint_tokens(token, alice.clone(), Nat::from(1u64), None).unwrap();
+        runtime::set_caller(controller);
+        revoke_token_api_access(token, api).unwrap();
+        assert!(revoke_token_api_access(token, api).is_err());
+        runtime::set_caller(api);
+        runtime::advance_time(1);
+        assert!(mint_tokens(token, alice, Nat::from(1u64), None).is_err());
+
+        let actions: Vec<_> = get_admin_audit_log(0, 100).into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, ["grant_token_api_access", "grant_token_api_access", "revoke_token_api_access"]);
+    }
+
+    #[test]
+    fn test_api_grants_never_exceed_the_granter() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let ledger_token = register_test_token(1, 0);
+        let issuer = test_account(5);
+        let issued_token = [3u8; 32];
+        state::register_token(issued_token, StoredTokenMetadata { controller: issuer.owner, ..test_metadata(0) });
+        state::seed_balance(issued_token, issuer.to_key(), 1_000);
+        let (api, alice) = (test_account(9).owner, test_account(1));
+
+        // The token's controller may hand out disbursement from its own
+        // accounts, but not minting or private reads it does not hold itself.
+        runtime::set_caller(issuer.owner);
+        let disburse = ApiPermissions { can_disburse: true, ..Default::default() };
+        assert!(grant_token_api_access(issued_token, api, ApiPermissions { can_mint: true, ..disburse }, None).is_err());
+        assert!(grant_token_api_access(issued_token, api, ApiPermissions { can_query_private: true, ..Default::default() }, None).is_err());
+        assert!(grant_token_api_access(ledger_token, api, disburse, None).is_err());
+        grant_token_api_access(issued_token, api, disburse, None).unwrap();
+        assert_eq!(list_token_api_access(issued_token).unwrap().len(), 1);
+
+        runtime::set_caller(api);
+        let payouts = vec![Payout { to: alice.clone(), amount: Nat::from(10u64), memo: None }];
+        disburse_from(issued_token, issuer.clone(), payouts.clone(), None, None).unwrap();
+        assert_eq!(state::get_balance(issued_token, alice.to_key()), 10);
+        state::seed_balance(issued_token, test_account(6).to_key(), 1_000);
+        assert!(matches!(
+            disburse_from(issued_token, test_account(6), payouts.clone(), None, None),
+            Err(DisburseError::Rejected(crate::operations::TransferError::GenericError { error_code, .. })) if error_code == 403u64
+        ));
+        assert!(disburse_from(ledger_token, issuer.clone(), payouts, None, None).is_err());
+
+        // A controller's read grant covers its token only.
+        runtime::set_caller(controller);
+        crate::privacy::set_privacy_mode(true).unwrap();
+        let reader = test_account(7).owner;
+        grant_token_api_access(ledger_token, reader, ApiPermissions { can_query_private: true, ..Default::default() }, None).unwrap();
+        assert!(revoke_token_api_access(issued_token, reader).is_err());
+        runtime::set_caller(reader);
+        assert_eq!(get_balance(ledger_token, alice.clone(), None).unwrap(), 0);
+        assert!(crate::queries::list_holders(ledger_token, None, None, None).is_ok());
+        assert!(get_balance(issued_token, alice, None).is_err());
+
+        // A controller may replace the issuer's grant; the issuer cannot
+        // replace or revoke one it did not make.
+        runtime::set_caller(controller);
+        grant_token_api_access(issued_token, api, ApiPermissions { can_mint: true, ..Default::default() }, None).unwrap();
+        runtime::set_caller(issuer.owner);
+        assert!(grant_token_api_access(issued_token, api, disburse, None).is_err());
+        assert!(revoke_token_api_access(issued_token, api).is_err());
+    }
+}
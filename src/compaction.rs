@@ -0,0 +1,268 @@
+use crate::types::{memory_ids, StoredCompaction, StructureId};
+use crate::state;
+use crate::runtime;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// StableBTreeMaps never give pages back, so a map that churned (pruned dedup
+// entries, cleared allowances) keeps its peak footprint. A compaction copies
+// the live entries into another region a chunk at a time, then binds the map
+// to the copy. Writes to already-copied keys are repeated in the copy, so the
+// ledger keeps running meanwhile.
+
+pub const MAX_COMPACTION_BUDGET: u64 = 10_000;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CompactionStatus {
+    pub target: StructureId,
+    pub region: u8,
+    pub copied: u64,
+    // Live entries in the structure now.
+    pub entries: u64,
+    pub complete: bool,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StructureBinding {
+    pub structure: StructureId,
+    pub region: u8,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MemoryLayout {
+    pub bindings: Vec<StructureBinding>,
+    pub reusable_regions: Vec<u8>,
+    pub compaction: Option<CompactionStatus>,
+}
+
+
+fn status(compaction: &StoredCompaction, complete: bool) -> CompactionStatus {
+    CompactionStatus {
+        target: compaction.target,
+        region: compaction.region,
+        copied: compaction.copied,
+        entries: state::structure_len(compaction.target),
+        complete,
+    }
+}
+
+
+// A region some map left behind, or else the first compaction region nothing
+// is bound to.
+fn take_region() -> Result<u8, String> {
+    let mut reusable = state::reusable_regions();
+    if let Some(region) = reusable.pop() {
+        state::set_reusable_regions(reusable);
+        return Ok(region);
+    }
+
+    let bound: Vec<u8> = StructureId::ALL.iter().map(|s| state::bound_region(s.home_memory_id())).collect();
+    (memory_ids::COMPACTION_REGIONS_START..=memory_ids::COMPACTION_REGIONS_END)
+        .find(|region| !bound.contains(region))
+        .ok_or_else(|| "No memory region left for compaction".to_string())
+}
+
+
+fn release_region(region: u8) {
+    let mut reusable = state::reusable_regions();
+    reusable.push(region);
+    state::set_reusable_regions(reusable);
+}
+
+
+// Copies up to `budget` entries of `target`. Call repeatedly until `complete`;
+// the first call starts the compaction, and progress survives upgrades. Only
+// one structure is compacted at a time.
+#[ic_cdk::update]
+pub fn compact_structure(target: StructureId, budget: u64) -> Result<CompactionStatus, String> {
+    state::require_controller()?;
+
+    if budget == 0 || budget > MAX_COMPACTION_BUDGET {
+        return Err(format!("budget must be between 1 and {}", MAX_COMPACTION_BUDGET));
+    }
+
+    let mut compaction = match state::get_compaction() {
+        Some(c) if c.target != target => {
+            return Err(format!("Compaction of {:?} is in progress", c.target));
+        }
+        Some(c) => c,
+        None => {
+            let region = take_region()?;
+            state::clear_compaction_region(target, region);
+            state::record_admin_action("compact_structure", format!("{:?} started in region {}", target, region));
+            StoredCompaction { target, region, cursor: None, copied: 0, started_at: runtime::time() }
+        }
+    };
+
+    let (copied, last) = state::copy_structure_entries(target, compaction.region, compaction.cursor.as_deref(), budget);
+    compaction.copied = compaction.copied.saturating_add(copied);
+    if last.is_some() {
+        compaction.cursor = last;
+    }
+    if copied == budget {
+        state::set_compaction(Some(&compaction));
+        return Ok(status(&compaction, false));
+    }
+
+    // Everything is copied. The counts can only differ if a write skipped the
+    // tracked path; the copy is then dropped rather than bound.
+    state::set_compaction(None);
+    let entries = state::structure_len(target);
+    let copy_entries = state::compaction_region_len(target, compaction.region);
+    if copy_entries != entries {
+        release_region(compaction.region);
+        state::record_admin_action("compact_structure", format!("{:?} abandoned: copy has {} of {} entries", target, copy_entries, entries));
+        return Err(format!("Copy of {:?} has {} entries, expected {}; compaction abandoned", target, copy_entries, entries));
+    }
+
+    let previous = state::bound_region(target.home_memory_id());
+    state::bind_structure(target, compaction.region);
+    release_region(previous);
+    state::record_admin_action("compact_structure", format!("{:?} moved from region {} to {} ({} entries)", target, previous, compaction.region, entries));
+    Ok(status(&compaction, true))
+}
+
+
+// Stops the running compaction; its region goes back to the reusable list.
+#[ic_cdk::update]
+pub fn cancel_compaction() -> Result<(), String> {
+    state::require_controller()?;
+
+    let compaction = state::get_compaction().ok_or_else(|| "No compaction in progress".to_string())?;
+    state::set_compaction(None);
+    release_region(compaction.region);
+    state::record_admin_action("cancel_compaction", format!("{:?} after {} entries", compaction.target, compaction.copied));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_memory_layout() -> MemoryLayout {
+    MemoryLayout {
+        bindings: StructureId::ALL
+            .iter()
+            .map(|s| StructureBinding { structure: *s, region: state::bound_region(s.home_memory_id()) })
+            .collect(),
+        reusable_regions: state::reusable_regions(),
+        compaction: state::get_compaction().map(|c| status(&c, false)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{register_test_token, test_account};
+    use ic_stable_structures::Memory;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_compaction_survives_churn_and_an_upgrade() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        let owner = test_account(1).to_key();
+        let spender = |i: u32| {
+            let mut key = [0u8; 32];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            key
+        };
+
+        // What get_allowance should return for every spender ever approved.
+        let mut expected: BTreeMap<u32, u128> = BTreeMap::new();
+        let set = |expected: &mut BTreeMap<u32, u128>, i: u32, amount: u128| {
+            state::set_allowance(token, owner, spender(i), amount);
+            expected.insert(i, amount);
+        };
+        for i in 0..400 {
+            set(&mut expected, i, 1 + i as u128);
+        }
+        for i in (0..400).filter(|i| i % 4 != 0) {
+            set(&mut expected, i, 0);
+        }
+        let home_pages = state::memory(memory_ids::ALLOWANCE_STORAGE).size();
+
+        assert!(compact_structure(StructureId::Allowances, 0).is_err());
+        let status = compact_structure(StructureId::Allowances, 30).unwrap();
+        assert_eq!((status.region, status.copied, status.entries, status.complete), (200, 30, 100, false));
+        assert!(compact_structure(StructureId::DedupMap, 30).is_err());
+        runtime::set_caller(test_account(2).owner);
+        assert!(compact_structure(StructureId::Allowances, 30).is_err());
+        runtime::set_caller(controller);
+
+        // Churn on both sides of the cursor: updates, removals, new keys, re-adds.
+        let churn = |expected: &mut BTreeMap<u32, u128>, round: u128| {
+            for i in (0..400).step_by(8) {
+                set(expected, i, 1_000 * round + i as u128);
+            }
+            for i in (4..400).step_by(24) {
+                set(expected, i, 0);
+            }
+            for i in (1..400).step_by(40) {
+                set(expected, i, round);
+            }
+            for i in 400 + 10 * round as u32..410 + 10 * round as u32 {
+                set(expected, i, i as u128);
+            }
+        };
+        churn(&mut expected, 1);
+        compact_structure(StructureId::Allowances, 30).unwrap();
+
+        state::simulate_upgrade();
+        assert_eq!(get_memory_layout().compaction.map(|c| (c.region, c.copied)), Some((200, 60)));
+        churn(&mut expected, 2);
+
+        let mut status = compact_structure(StructureId::Allowances, 30).unwrap();
+        while !status.complete {
+            churn(&mut expected, 3);
+            status = compact_structure(StructureId::Allowances, 30).unwrap();
+        }
+        let live = expected.values().filter(|a| **a > 0).count() as u64;
+        assert_eq!(status.entries, live);
+        assert_eq!(state::get_compaction(), None);
+
+        let check = |expected: &BTreeMap<u32, u128>| {
+            for (i, amount) in expected {
+                assert_eq!(state::get_allowance(token, owner, spender(*i)), *amount, "spender {}", i);
+            }
+        };
+        check(&expected);
+        let layout = get_memory_layout();
+        assert!(layout.bindings.contains(&StructureBinding { structure: StructureId::Allowances, region: 200 }));
+        assert_eq!(layout.reusable_regions, vec![memory_ids::ALLOWANCE_STORAGE]);
+
+        // The binding holds across an upgrade, and writes land in the new region.
+        state::simulate_upgrade();
+        check(&expected);
+        set(&mut expected, 7, 77);
+        assert_eq!(state::compaction_region_len(StructureId::Allowances, 200), live + 1);
+
+        // The next compaction reuses the old home region without growing it.
+        let mut status = compact_structure(StructureId::Allowances, 10_000).unwrap();
+        assert!(status.complete);
+        assert_eq!(status.region, memory_ids::ALLOWANCE_STORAGE);
+        assert_eq!(state::memory(memory_ids::ALLOWANCE_STORAGE).size(), home_pages);
+        let layout = get_memory_layout();
+        assert!(layout.bindings.contains(&StructureBinding { structure: StructureId::Allowances, region: memory_ids::ALLOWANCE_STORAGE }));
+        assert_eq!(layout.reusable_regions, vec![200]);
+        assert!(state::memory_bindings().is_empty());
+        state::simulate_upgrade();
+        check(&expected);
+
+        // A cancelled compaction hands its region back.
+        status = compact_structure(StructureId::AllowanceExpiry, 1).unwrap();
+        assert_eq!((status.region, status.complete), (200, true));
+        assert_eq!(get_memory_layout().reusable_regions, vec![memory_ids::ALLOWANCE_EXPIRY_INDEX]);
+        compact_structure(StructureId::SpenderAllowances, 1).unwrap();
+        assert_eq!(get_memory_layout().compaction.map(|c| c.region), Some(memory_ids::ALLOWANCE_EXPIRY_INDEX));
+        cancel_compaction().unwrap();
+        assert_eq!(get_memory_layout().reusable_regions, vec![memory_ids::ALLOWANCE_EXPIRY_INDEX]);
+        assert_eq!(state::bound_region(memory_ids::SPENDER_ALLOWANCES), memory_ids::SPENDER_ALLOWANCES);
+        assert!(cancel_compaction().is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::fees;
+use crate::operations::MAINTENANCE_MODE_MESSAGE;
+use crate::runtime;
+use crate::state;
+use crate::types::{AccountKey, OpKind, StoredTokenMetadata, TokenId};
+use crate::validation::{validate_token_id, ValidationError};
+use candid::Principal;
+
+
+// The checks every mutation makes before touching balances, done once per
+// call: maintenance and paused operations, the token and its metadata, and
+// created_at_time. Each path used to repeat them in its own order, which is
+// how mint and burn came to take any created_at_time. Batches build one
+// context and share it.
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ContextError {
+    Maintenance,
+    Paused(OpKind),
+    // The token is still being imported; see import::import_token.
+    ImportPending,
+    InvalidToken(ValidationError),
+    Metadata(state::MetadataError),
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    BadFee { expected_fee: u128 },
+}
+
+impl std::fmt::Display for ContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextError::Maintenance => write!(f, "{}", MAINTENANCE_MODE_MESSAGE),
+            ContextError::Paused(op) => write!(f, "{:?} operations are paused", op),
+            ContextError::ImportPending => write!(f, "The token's import is not finalized yet"),
+            ContextError::InvalidToken(e) => write!(f, "{}", e),
+            ContextError::Metadata(e) => write!(f, "{}", e),
+            ContextError::TooOld => write!(f, "Transaction too old"),
+            ContextError::CreatedInFuture { ledger_time } => write!(f, "Transaction created in the future (ledger time {})", ledger_time),
+            ContextError::BadFee { expected_fee } => write!(f, "Bad fee, expected {}", expected_fee),
+        }
+    }
+}
+
+impl From<ContextError> for String {
+    fn from(err: ContextError) -> Self {
+        err.to_string()
+    }
+}
+
+
+pub(crate) struct OperationContext {
+    pub token_id: TokenId,
+    pub caller: Principal,
+    pub metadata: StoredTokenMetadata,
+    // created_at_time once checked against the drift window, or now.
+    pub timestamp: u64,
+    // The operation's index within an execute_script call.
+    pub script_position: Option<u32>,
+    // The caller asked for a fee sponsorship to pay the fee.
+    pub use_sponsorship: bool,
+}
+
+impl OperationContext {
+    pub fn new(op: OpKind, token_id: TokenId, caller: Principal, created_at_time: Option<u64>) -> Result<Self, ContextError> {
+        let metadata = check_gates(op, token_id, false)?;
+        let timestamp = checked_timestamp(created_at_time)?;
+        Ok(OperationContext { token_id, caller, metadata, timestamp, script_position: None, use_sponsorship: false })
+    }
+
+
+    // For controller mints and burns, which maintenance mode lets through. A
+    // paused operation kind stops them all the same.
+    pub fn privileged(op: OpKind, token_id: TokenId, caller: Principal, created_at_time: Option<u64>) -> Result<Self, ContextError> {
+        let metadata = check_gates(op, token_id, true)?;
+        let timestamp = checked_timestamp(created_at_time)?;
+        Ok(OperationContext { token_id, caller, metadata, timestamp, script_position: None, use_sponsorship: false })
+    }
+
+
+    pub fn in_script(self, script_position: Option<u32>) -> Self {
+        OperationContext { script_position, ..self }
+    }
+
+
+    pub fn with_sponsorship(self, use_sponsorship: bool) -> Self {
+        OperationContext { use_sponsorship, ..self }
+    }
+
+
+    // Script operations are deduplicated by position as well; see
+    // state::scope_dedup_key_to_script.
+    pub fn dedup_key(&self, dedup_key: state::DedupKey) -> state::DedupKey {
+        match self.script_position {
+            Some(position) => state::scope_dedup_key_to_script(dedup_key, position),
+            None => dedup_key,
+        }
+    }
+
+
+    // The fee to charge when the token expects `expected_fee`. A fee the caller
+    // names must match it, and tokens that require one reject None.
+    pub fn resolve_fee(&self, expected_fee: u128, provided: Option<u128>) -> Result<u128, ContextError> {
+        let mismatch = provided.is_some_and(|fee| fee != expected_fee);
+        if mismatch || (provided.is_none() && fees::requires_explicit_fee(&self.metadata)) {
+            return Err(ContextError::BadFee { expected_fee });
+        }
+        Ok(expected_fee)
+    }
+
+
+    pub fn standard_fee(&self, provided: Option<u128>) -> Result<u128, ContextError> {
+        self.resolve_fee(self.metadata.fee, provided)
+    }
+
+
+    pub fn fee_token(&self) -> TokenId {
+        fees::resolve_fee_token(self.token_id, &self.metadata)
+    }
+
+
+    // Whether fees come out of the operation's own token rather than a fee token.
+    pub fn fee_in_token(&self) -> bool {
+        self.fee_token() == self.token_id
+    }
+
+
+    pub fn fee_recipient_key(&self) -> AccountKey {
+        self.metadata.fee_recipient.to_key()
+    }
+}
+
+
+// Whether `op` may run on the token at all, before the caller or its
+// arguments matter. `privileged` is for the mints and burns maintenance mode
+// lets through. Also read by permissions::get_effective_permissions.
+pub(crate) fn check_gates(op: OpKind, token_id: TokenId, privileged: bool) -> Result<StoredTokenMetadata, ContextError> {
+    if !privileged && state::is_maintenance_mode() {
+        return Err(ContextError::Maintenance);
+    }
+    if state::is_op_paused(op) {
+        return Err(ContextError::Paused(op));
+    }
+    validate_token_id(&token_id).map_err(ContextError::InvalidToken)?;
+    let metadata = state::get_token_metadata(token_id).map_err(ContextError::Metadata)?;
+    if state::is_import_pending(token_id) {
+        return Err(ContextError::ImportPending);
+    }
+    Ok(metadata)
+}
+
+
+fn checked_timestamp(created_at_time: Option<u64>) -> Result<u64, ContextError> {
+    let current_time = runtime::time();
+    let Some(provided_time) = created_at_time else {
+        return Ok(current_time);
+    };
+
+    if provided_time > current_time.saturating_add(state::max_future_drift()) {
+        return Err(ContextError::CreatedInFuture { ledger_time: current_time });
+    }
+    if provided_time < current_time.saturating_sub(state::max_past_drift()) {
+        return Err(ContextError::TooOld);
+    }
+    Ok(provided_time)
+}
@@ -0,0 +1,234 @@
+use crate::state;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// What one operation costs the ledger, so integrators budget from the same
+// numbers. Defaults come from the measurement in the tests below; controllers
+// replace them with figures from replica benchmarks, which are kept in
+// SYSTEM_STATE and survive upgrades. Batch kinds are per item.
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationKind {
+    Transfer,
+    TransferFrom,
+    Approve,
+    Mint,
+    Burn,
+    // One payout of a disburse call.
+    DisbursePayout,
+    // One source subaccount of a consolidate_subaccounts call.
+    ConsolidationEntry,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OperationCost {
+    pub instructions_estimate: u64,
+    pub stable_writes: u32,
+    pub stable_reads: u32,
+}
+
+
+pub const OPERATION_KINDS: [OperationKind; 7] = [
+    OperationKind::Transfer,
+    OperationKind::TransferFrom,
+    OperationKind::Approve,
+    OperationKind::Mint,
+    OperationKind::Burn,
+    OperationKind::DisbursePayout,
+    OperationKind::ConsolidationEntry,
+];
+
+
+// Stable reads and writes are calls into stable memory, not bytes.
+// Instruction counts are rough until a controller sets replica measurements.
+fn default_cost(op: OperationKind) -> OperationCost {
+    let (instructions_estimate, stable_reads, stable_writes) = match op {
+        OperationKind::Transfer => (1_500_000, 280, 182),
+        OperationKind::TransferFrom => (2_000_000, 392, 242),
+        OperationKind::Approve => (1_200_000, 287, 187),
+        OperationKind::Mint => (1_200_000, 322, 163),
+        OperationKind::Burn => (1_200_000, 371, 209),
+        OperationKind::DisbursePayout => (900_000, 471, 242),
+        OperationKind::ConsolidationEntry => (1_300_000, 653, 349),
+    };
+    OperationCost { instructions_estimate, stable_writes, stable_reads }
+}
+
+
+#[ic_cdk::query]
+pub fn estimate_operation_cost(op: OperationKind) -> OperationCost {
+    state::get_operation_costs().into_iter()
+        .find(|(kind, _)| *kind == op)
+        .map_or_else(|| default_cost(op), |(_, cost)| cost)
+}
+
+
+#[ic_cdk::update]
+pub fn set_operation_cost(op: OperationKind, cost: OperationCost) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = estimate_operation_cost(op);
+    let mut costs = state::get_operation_costs();
+    costs.retain(|(kind, _)| *kind != op);
+    costs.push((op, cost));
+    state::set_operation_costs(&costs);
+    state::record_admin_action("set_operation_cost", format!("{:?}: {:?} -> {:?}", op, previous, cost));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowances::{approve, transfer_from, ApproveResult, Icrc151ApproveArgs, Icrc151TransferFromArgs};
+    use crate::operations::{
+        burn_tokens, consolidate_subaccounts, disburse, mint_tokens, transfer, Icrc151TransferArgs, Payout, TransferResult,
+    };
+    use crate::runtime;
+    use crate::test_utils::{measure, register_test_token, test_account};
+    use crate::types::Subaccount;
+    use candid::Nat;
+
+    // Items per measured batch; the per-item cost is the difference between
+    // this batch and a single item, spread over the extra items.
+    const BATCH: u32 = 5;
+
+    fn transfer_args(token: crate::types::TokenId, to: u8) -> Icrc151TransferArgs {
+        Icrc151TransferArgs {
+            token_id: token, from_subaccount: None, to: test_account(to), amount: Nat::from(10u64),
+            fee: None, memo: None, created_at_time: None, private_memo: None, amount_mode: None, use_sponsorship: None, args_version: None,
+        }
+    }
+
+    fn payouts(n: u32) -> Vec<Payout> {
+        (0..n).map(|i| Payout { to: test_account(20 + i as u8), amount: Nat::from(10u64), memo: None }).collect()
+    }
+
+    // Funded subaccounts of the caller, numbered from `first`.
+    fn subaccounts(token: crate::types::TokenId, first: u8, n: u32) -> Vec<Subaccount> {
+        (0..n).map(|i| {
+            let sub = Subaccount([first + i as u8; 32]);
+            let from = crate::types::Account { owner: runtime::caller(), subaccount: Some(sub.to_vec()) };
+            state::seed_balance(token, from.to_key(), 1_000);
+            sub
+        }).collect()
+    }
+
+    fn per_item(single: OperationCost, batch: OperationCost) -> OperationCost {
+        let extra = BATCH - 1;
+        OperationCost {
+            instructions_estimate: batch.instructions_estimate.saturating_sub(single.instructions_estimate) / extra as u64,
+            stable_writes: batch.stable_writes.saturating_sub(single.stable_writes) / extra,
+            stable_reads: batch.stable_reads.saturating_sub(single.stable_reads) / extra,
+        }
+    }
+
+    // The measurement procedure: a fresh ledger with one token, one warm-up
+    // call so first-use allocations are not counted, then the measured call.
+    // Run inside a canister build for instruction counts; natively only the
+    // stable memory counts are meaningful.
+    pub(crate) fn measure_operation(op: OperationKind) -> OperationCost {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        let (owner, spender) = (test_account(1), test_account(2));
+        mint_tokens(token, owner.clone(), Nat::from(1_000_000u64), None).unwrap();
+        runtime::advance_time(1);
+
+        let approve_args = |amount: u64| Icrc151ApproveArgs {
+            token_id: token, spender: spender.clone(), amount: Nat::from(amount), expires_at: None,
+            expected_allowance: None, memo: None, fee: None, from_subaccount: None, created_at_time: None,
+            private_memo: None, args_version: None,
+        };
+        let transfer_from_args = |to: u8| Icrc151TransferFromArgs {
+            token_id: token, spender_subaccount: None, from: owner.clone(), to: test_account(to),
+            amount: Nat::from(10u64), fee: None, memo: None, created_at_time: None, private_memo: None, args_version: None,
+        };
+
+        let warm_then_measure = |call: &mut dyn FnMut(u8)| {
+            call(3);
+            runtime::advance_time(1);
+            measure(|| call(4))
+        };
+        match op {
+            OperationKind::Transfer => {
+                runtime::set_caller(owner.owner);
+                warm_then_measure(&mut |to| { assert!(matches!(transfer(transfer_args(token, to)), TransferResult::Ok(_))); })
+            }
+            OperationKind::Approve => {
+                runtime::set_caller(owner.owner);
+                let mut amount = 100;
+                warm_then_measure(&mut |_| { amount += 1; assert!(matches!(approve(approve_args(amount)), ApproveResult::Ok(_))); })
+            }
+            OperationKind::TransferFrom => {
+                runtime::set_caller(owner.owner);
+                assert!(matches!(approve(approve_args(1_000)), ApproveResult::Ok(_)));
+                runtime::set_caller(spender.owner);
+                warm_then_measure(&mut |to| { assert!(matches!(transfer_from(transfer_from_args(to)), TransferResult::Ok(_))); })
+            }
+            OperationKind::Mint => {
+                warm_then_measure(&mut |to| { mint_tokens(token, test_account(to), Nat::from(10u64), None).unwrap(); })
+            }
+            OperationKind::Burn => {
+                runtime::set_caller(owner.owner);
+                warm_then_measure(&mut |_| { burn_tokens(token, Nat::from(10u64), None, None, None, None).unwrap(); })
+            }
+            OperationKind::DisbursePayout => {
+                runtime::set_caller(owner.owner);
+                disburse(token, None, payouts(BATCH), None, None).unwrap();
+                runtime::advance_time(1);
+                let single = measure(|| { disburse(token, None, payouts(1), None, None).unwrap(); });
+                runtime::advance_time(1);
+                let batch = measure(|| { disburse(token, None, payouts(BATCH), None, None).unwrap(); });
+                per_item(single, batch)
+            }
+            OperationKind::ConsolidationEntry => {
+                runtime::set_caller(owner.owner);
+                consolidate_subaccounts(token, subaccounts(token, 1, BATCH), None).unwrap();
+                runtime::advance_time(1);
+                let single_from = subaccounts(token, 10, 1);
+                let single = measure(|| { consolidate_subaccounts(token, single_from, None).unwrap(); });
+                runtime::advance_time(1);
+                let batch_from = subaccounts(token, 20, BATCH);
+                let batch = measure(|| { consolidate_subaccounts(token, batch_from, None).unwrap(); });
+                per_item(single, batch)
+            }
+        }
+    }
+
+    // Fails with the measured table when the stable access defaults drift by
+    // more than a quarter; paste the printed figures into default_cost.
+    #[test]
+    fn test_defaults_match_measurement() {
+        let measured: Vec<_> = OPERATION_KINDS.iter().map(|&op| (op, measure_operation(op))).collect();
+        let within = |default: u32, measured: u32| default.abs_diff(measured) * 4 <= measured.max(4);
+        let drifted = measured.iter().any(|&(op, cost)| {
+            let default = default_cost(op);
+            !within(default.stable_reads, cost.stable_reads) || !within(default.stable_writes, cost.stable_writes)
+        });
+        assert!(!drifted, "operation cost defaults drifted, measured (op, reads, writes): {:?}",
+            measured.iter().map(|(op, c)| (*op, c.stable_reads, c.stable_writes)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_controller_overrides_estimates() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let cost = OperationCost { instructions_estimate: 42, stable_writes: 1, stable_reads: 2 };
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(set_operation_cost(OperationKind::Mint, cost).is_err());
+        assert_eq!(estimate_operation_cost(OperationKind::Mint), default_cost(OperationKind::Mint));
+
+        runtime::set_caller(controller);
+        set_operation_cost(OperationKind::Mint, cost).unwrap();
+        set_operation_cost(OperationKind::Mint, cost).unwrap();
+        assert_eq!(estimate_operation_cost(OperationKind::Mint), cost);
+        assert_eq!(estimate_operation_cost(OperationKind::Burn), default_cost(OperationKind::Burn));
+        assert_eq!(state::get_operation_costs().len(), 1);
+    }
+}
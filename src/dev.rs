@@ -0,0 +1,145 @@
+use crate::operations::{create_token_internal, mint_internal, CreateTokenArgs};
+use crate::runtime;
+use crate::state;
+use crate::types::{constants, derive_token_id_v2, memory_ids, Account, StoredPrincipal, TokenId};
+use candid::CandidType;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use num_traits::cast::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+// Only compiled with the `dev` feature. Release builds have neither the
+// endpoint nor the init argument.
+
+
+// Per caller per day, in the token's smallest units.
+pub const DEV_FAUCET_DAILY_CAP: u128 = 100_000_000_000_000;
+pub const DEV_TOKEN_NAMESPACE: &[u8] = b"dev/faucet";
+
+
+thread_local! {
+    // Key: caller (30) ++ day (u64 BE). Value: amount claimed that day, all tokens.
+    static FAUCET_CLAIMS: RefCell<StableBTreeMap<[u8; 38], u128, state::Memory>> = RefCell::new(
+        StableBTreeMap::init(state::memory(memory_ids::DEV_FAUCET_CLAIMS))
+    );
+}
+
+
+// See state::warm_up.
+pub(crate) fn warm_up() {
+    if state::holds_data(memory_ids::DEV_FAUCET_CLAIMS) {
+        FAUCET_CLAIMS.with(|_| ());
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DevInitArgs {
+    pub non_production: bool,
+    pub ledger: Option<crate::genesis::InitArgs>,
+}
+
+
+fn claim_key(caller: &StoredPrincipal, day: u64) -> [u8; 38] {
+    let mut key = [0u8; 38];
+    key[..30].copy_from_slice(&caller.to_bytes());
+    key[30..].copy_from_slice(&day.to_be_bytes());
+    key
+}
+
+
+// The DEV token id is derived from the ledger's own id, so it needs no storage.
+pub fn dev_token_id() -> TokenId {
+    let ledger = runtime::canister_id();
+    derive_token_id_v2(ledger, ledger, DEV_TOKEN_NAMESPACE)
+}
+
+
+fn ensure_dev_token() -> Result<TokenId, String> {
+    let token_id = dev_token_id();
+    if !state::token_exists(token_id) {
+        create_token_internal(runtime::canister_id(), CreateTokenArgs {
+            name: "Dev Token".to_string(),
+            symbol: "DEV".to_string(),
+            decimals: 8,
+            initial_supply: None,
+            fee: None,
+            logo: None,
+            description: Some("Created by dev_faucet on a non-production ledger".to_string()),
+            namespace: Some(DEV_TOKEN_NAMESPACE.to_vec()),
+            args_version: None,
+        })
+        .map_err(|e| format!("Failed to create the DEV token: {:?}", e))?;
+    }
+    Ok(token_id)
+}
+
+
+// Mints to the caller without controller checks, on ledgers initialized as
+// non-production only.
+#[ic_cdk::update]
+pub fn dev_faucet(token_id: Option<TokenId>, amount: candid::Nat) -> Result<u64, String> {
+    if !state::is_non_production() {
+        return Err("dev_faucet is only available on non-production ledgers".to_string());
+    }
+    let caller = runtime::caller();
+    let stored_caller = StoredPrincipal::from_principal(&caller)?;
+    let amount = amount.0.to_u128().ok_or("Amount exceeds maximum value (u128::MAX)".to_string())?;
+
+    let key = claim_key(&stored_caller, runtime::time() / constants::NANOS_PER_DAY);
+    let claimed = FAUCET_CLAIMS.with(|c| c.borrow().get(&key).unwrap_or(0));
+    if claimed.saturating_add(amount) > DEV_FAUCET_DAILY_CAP {
+        return Err(format!(
+            "Daily faucet cap reached ({} of {} claimed today)",
+            claimed, DEV_FAUCET_DAILY_CAP
+        ));
+    }
+
+    let token_id = match token_id {
+        Some(token_id) => token_id,
+        None => ensure_dev_token()?,
+    };
+    // The running total keeps repeated claims in one round from deduplicating.
+    let memo = format!("dev_faucet:{}", claimed);
+    let tx_index = mint_internal(token_id, Account { owner: caller, subaccount: None }, amount, Some(memo.as_bytes()), None)
+        .map_err(|e| format!("Faucet mint failed: {:?}", e))?;
+
+    FAUCET_CLAIMS.with(|c| c.borrow_mut().insert(key, claimed + amount));
+    Ok(tx_index)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+    use crate::test_utils::{register_test_token, test_account};
+
+    #[test]
+    fn test_dev_faucet() {
+        state::init_state(test_account(0xFE).owner);
+        let user = test_account(1);
+        runtime::set_caller(user.owner);
+        assert!(dev_faucet(None, Nat::from(10u64)).is_err());
+
+        state::set_non_production();
+        dev_faucet(None, Nat::from(10u64)).unwrap();
+        dev_faucet(None, Nat::from(5u64)).unwrap();
+        let dev_token = dev_token_id();
+        assert_eq!(state::get_token_metadata(dev_token).unwrap().symbol, "DEV");
+        assert_eq!(state::get_balance(dev_token, user.to_key()), 15);
+
+        let token = register_test_token(2, 0);
+        dev_faucet(Some(token), Nat::from(DEV_FAUCET_DAILY_CAP - 15)).unwrap();
+        assert_eq!(state::get_balance(token, user.to_key()), DEV_FAUCET_DAILY_CAP - 15);
+        assert!(dev_faucet(None, Nat::from(1u64)).is_err());
+
+        // The cap is per caller and resets the next day.
+        runtime::set_caller(test_account(2).owner);
+        dev_faucet(None, Nat::from(1u64)).unwrap();
+        runtime::set_caller(user.owner);
+        runtime::advance_time(constants::NANOS_PER_DAY);
+        dev_faucet(None, Nat::from(1u64)).unwrap();
+        assert_eq!(state::get_balance(dev_token, user.to_key()), 16);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::runtime;
+use crate::state;
+use crate::types::{StoredFailure, StoredPrincipal, TokenId};
+use candid::{CandidType, Encode, Principal};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use num_traits::cast::ToPrimitive;
+use std::fmt::Debug;
+
+
+// The last failed update calls, so support can answer "did this user's
+// transfer fail, and why" from the ledger itself. Off by default. While on, a
+// failure costs one stable write into a fixed ring of slots; successful calls
+// pay one flag read either way. Records hold the error's name, never its
+// message, and a truncated digest of the arguments, never the arguments, so
+// memos stay out of the log. Amounts are kept only when the controller asks.
+
+
+pub const MAX_FAILURE_RECORDS: u64 = 1_000;
+pub const MAX_FAILURE_PAGE: u32 = 1_000;
+
+const FLAG_ENABLED: u64 = 1;
+const FLAG_RECORD_AMOUNTS: u64 = 2;
+
+
+thread_local! {
+    // Next sequence number, found from the log on first use after an upgrade.
+    static NEXT_SEQ: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailedOperation {
+    Transfer,
+    TransferFrom,
+    Approve,
+    Mint,
+    Burn,
+    BurnFrom,
+    Disburse,
+    Consolidate,
+    // The tip of a relayed transfer whose main transfer went through.
+    RelayerTip,
+}
+
+
+impl FailedOperation {
+    // Stored codes; append new operations, never renumber.
+    fn code(self) -> u8 {
+        match self {
+            FailedOperation::Transfer => 1,
+            FailedOperation::TransferFrom => 2,
+            FailedOperation::Approve => 3,
+            FailedOperation::Mint => 4,
+            FailedOperation::Burn => 5,
+            FailedOperation::BurnFrom => 6,
+            FailedOperation::Disburse => 7,
+            FailedOperation::Consolidate => 8,
+            FailedOperation::RelayerTip => 9,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(FailedOperation::Transfer),
+            2 => Some(FailedOperation::TransferFrom),
+            3 => Some(FailedOperation::Approve),
+            4 => Some(FailedOperation::Mint),
+            5 => Some(FailedOperation::Burn),
+            6 => Some(FailedOperation::BurnFrom),
+            7 => Some(FailedOperation::Disburse),
+            8 => Some(FailedOperation::Consolidate),
+            9 => Some(FailedOperation::RelayerTip),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FailureRecord {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub operation: FailedOperation,
+    pub token_id: TokenId,
+    // The error variant, or the leading words of a text error.
+    pub error: String,
+    // The first 8 bytes of SHA-256 over the Candid-encoded arguments.
+    pub args_digest: [u8; 8],
+    pub amount: Option<candid::Nat>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailureLogSettings {
+    pub enabled: bool,
+    pub record_amounts: bool,
+}
+
+
+// What is known of a call before it runs, kept in case it fails. None while
+// the log is off.
+pub(crate) struct Watch {
+    operation: FailedOperation,
+    token_id: TokenId,
+    args_digest: [u8; 8],
+    amount: Option<u128>,
+}
+
+
+pub(crate) fn watch<A: CandidType>(
+    operation: FailedOperation,
+    token_id: TokenId,
+    args: &A,
+    amount: Option<&candid::Nat>,
+) -> Option<Watch> {
+    let flags = state::get_failure_log_flags();
+    if flags & FLAG_ENABLED == 0 {
+        return None;
+    }
+    use sha2::{Digest, Sha256};
+    let encoded = Encode!(args).unwrap_or_default();
+    let mut args_digest = [0u8; 8];
+    args_digest.copy_from_slice(&Sha256::digest(&encoded)[..8]);
+    let amount = match flags & FLAG_RECORD_AMOUNTS {
+        0 => None,
+        _ => amount.and_then(|a| a.0.to_u128()),
+    };
+    Some(Watch { operation, token_id, args_digest, amount })
+}
+
+
+pub(crate) fn record<E: Debug>(watch: Option<Watch>, error: &E) {
+    let Some(watch) = watch else { return };
+    let Ok(caller) = StoredPrincipal::from_principal(&runtime::caller()) else { return };
+    let seq = NEXT_SEQ.with(|s| {
+        let seq = s.get().unwrap_or_else(|| state::list_failures().iter().map(|f| f.seq + 1).max().unwrap_or(0));
+        s.set(Some(seq + 1));
+        seq
+    });
+    state::set_failure((seq % MAX_FAILURE_RECORDS) as u32, StoredFailure {
+        seq,
+        timestamp: runtime::time(),
+        caller,
+        operation: watch.operation.code(),
+        token_id: watch.token_id,
+        error: error_label(&format!("{:?}", error)),
+        args_digest: watch.args_digest,
+        amount: watch.amount,
+    });
+}
+
+
+pub(crate) fn track<T, E: Debug>(watch: Option<Watch>, result: Result<T, E>) -> Result<T, E> {
+    if let Err(error) = &result {
+        record(watch, error);
+    }
+    result
+}
+
+
+// `InsufficientFunds { balance: .. }` becomes `InsufficientFunds`, and a text
+// error keeps its words up to the first digit or punctuation, which drops
+// amounts and identifiers.
+fn error_label(debug: &str) -> String {
+    let label: String = debug.trim_start_matches('"')
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic() || *c == ' ')
+        .take(StoredFailure::MAX_ERROR_LEN)
+        .collect();
+    label.trim_end().to_string()
+}
+
+
+#[ic_cdk::update]
+pub fn set_failure_log(settings: FailureLogSettings) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = get_failure_log_settings();
+    let flags = (settings.enabled as u64 * FLAG_ENABLED) | (settings.record_amounts as u64 * FLAG_RECORD_AMOUNTS);
+    state::set_failure_log_flags(flags);
+    state::record_admin_action("set_failure_log", format!("{:?} -> {:?}", previous, settings));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_failure_log_settings() -> FailureLogSettings {
+    let flags = state::get_failure_log_flags();
+    FailureLogSettings { enabled: flags & FLAG_ENABLED != 0, record_amounts: flags & FLAG_RECORD_AMOUNTS != 0 }
+}
+
+
+// Newest first. With a filter, only failures of that caller.
+#[ic_cdk::query]
+pub fn get_recent_failures(filter: Option<candid::Principal>, limit: Option<u32>) -> Result<Vec<FailureRecord>, String> {
+    state::require_controller()?;
+
+    let limit = limit.unwrap_or(MAX_FAILURE_PAGE).min(MAX_FAILURE_PAGE) as usize;
+    let mut failures = state::list_failures();
+    failures.sort_by_key(|f| std::cmp::Reverse(f.seq));
+    Ok(failures.into_iter()
+        .filter_map(|f| Some(FailureRecord {
+            seq: f.seq,
+            timestamp: f.timestamp,
+            caller: f.caller.to_principal().ok()?,
+            operation: FailedOperation::from_code(f.operation)?,
+            token_id: f.token_id,
+            error: f.error,
+            args_digest: f.args_digest,
+            amount: f.amount.map(candid::Nat::from),
+        }))
+        .filter(|f| filter.is_none_or(|caller| f.caller == caller))
+        .take(limit)
+        .collect())
+}
+
+
+#[ic_cdk::update]
+pub fn clear_failures() -> Result<(), String> {
+    state::require_controller()?;
+
+    let cleared = state::clear_failures();
+    state::record_admin_action("clear_failures", format!("{} records", cleared));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{burn_tokens, mint_tokens, transfer, Icrc151TransferArgs, TransferResult};
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Nat;
+
+    fn transfer_args(token: TokenId, amount: u64, memo: &[u8]) -> Icrc151TransferArgs {
+        Icrc151TransferArgs {
+            token_id: token, from_subaccount: None, to: test_account(2), amount: Nat::from(amount),
+            fee: None, memo: Some(crate::types::Memo(memo.to_vec())), created_at_time: None, private_memo: None,
+            amount_mode: None, use_sponsorship: None, args_version: None,
+        }
+    }
+
+    #[test]
+    fn test_failures_recorded_while_enabled() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        mint_tokens(token, test_account(1), Nat::from(100u64), None).unwrap();
+        let (alice, bob) = (test_account(1).owner, test_account(3).owner);
+
+        // Off: nothing is kept.
+        runtime::set_caller(alice);
+        assert!(matches!(transfer(transfer_args(token, 500, b"order 1")), TransferResult::Err(_)));
+        runtime::set_caller(controller);
+        assert!(get_recent_failures(None, None).unwrap().is_empty());
+
+        set_failure_log(FailureLogSettings { enabled: true, record_amounts: false }).unwrap();
+        runtime::set_caller(alice);
+        let args = transfer_args(token, 500, b"secret order 2");
+        let digest = watch(FailedOperation::Transfer, token, &args, None).unwrap().args_digest;
+        assert!(matches!(transfer(args), TransferResult::Err(_)));
+        assert!(matches!(transfer(transfer_args(token, 10, b"ok")), TransferResult::Ok(_)));
+        runtime::set_caller(bob);
+        assert!(burn_tokens(token, Nat::from(5u64), None, None, None, None).is_err());
+
+        runtime::set_caller(controller);
+        let failures = get_recent_failures(None, None).unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!((failures[0].caller, failures[0].operation), (bob, FailedOperation::Burn));
+        assert_eq!(failures[0].error, "InsufficientFunds");
+        let failure = &failures[1];
+        assert_eq!((failure.caller, failure.operation, failure.token_id), (alice, FailedOperation::Transfer, token));
+        assert_eq!((failure.error.as_str(), failure.args_digest, failure.amount.clone()), ("InsufficientFunds", digest, None));
+        assert!(failures[0].seq > failure.seq);
+        assert_eq!(get_recent_failures(Some(alice), Some(10)).unwrap(), vec![failure.clone()]);
+
+        // Amounts only on request.
+        set_failure_log(FailureLogSettings { enabled: true, record_amounts: true }).unwrap();
+        runtime::set_caller(alice);
+        runtime::advance_time(1);
+        assert!(matches!(transfer(transfer_args(token, 700, b"order 3")), TransferResult::Err(_)));
+        runtime::set_caller(controller);
+        assert_eq!(get_recent_failures(None, Some(1)).unwrap()[0].amount, Some(Nat::from(700u64)));
+
+        // Controllers only.
+        runtime::set_caller(alice);
+        assert!(get_recent_failures(None, None).is_err());
+        assert!(clear_failures().is_err());
+        assert!(set_failure_log(FailureLogSettings { enabled: false, record_amounts: false }).is_err());
+
+        runtime::set_caller(controller);
+        clear_failures().unwrap();
+        assert!(get_recent_failures(None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failure_log_is_a_bounded_ring() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        set_failure_log(FailureLogSettings { enabled: true, record_amounts: false }).unwrap();
+
+        runtime::set_caller(test_account(1).owner);
+        let total = MAX_FAILURE_RECORDS + 5;
+        for _ in 0..total {
+            assert!(matches!(transfer(transfer_args(token, 1, b"ring")), TransferResult::Err(_)));
+        }
+        let kept = state::list_failures();
+        assert_eq!(kept.len() as u64, MAX_FAILURE_RECORDS);
+        assert_eq!(kept.iter().map(|f| f.seq).min(), Some(5));
+
+        runtime::set_caller(controller);
+        let newest = get_recent_failures(None, Some(3)).unwrap();
+        assert_eq!(newest.iter().map(|f| f.seq).collect::<Vec<_>>(), vec![total - 1, total - 2, total - 3]);
+        assert_eq!(get_recent_failures(None, None).unwrap().len() as u64, MAX_FAILURE_PAGE.min(MAX_FAILURE_RECORDS as u32) as u64);
+    }
+
+    #[test]
+    fn test_stored_failure_is_fixed_size() {
+        use ic_stable_structures::Storable;
+        let failure = StoredFailure {
+            seq: 7,
+            timestamp: 9,
+            caller: StoredPrincipal::from_principal(&test_account(1).owner).unwrap(),
+            operation: FailedOperation::Disburse.code(),
+            token_id: [3u8; 32],
+            error: "x".repeat(StoredFailure::MAX_ERROR_LEN),
+            args_digest: [1u8; 8],
+            amount: Some(u128::MAX),
+        };
+        let bytes = failure.to_bytes();
+        assert_eq!(bytes.len(), 137);
+        assert_eq!(StoredFailure::from_bytes(bytes), failure);
+        assert_eq!(error_label("\"Insufficient balance: 100\""), "Insufficient balance");
+        assert_eq!(error_label("BadFee { expected_fee: Nat(10) }"), "BadFee");
+    }
+}
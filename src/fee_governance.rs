@@ -0,0 +1,268 @@
+use crate::types::{constants, PendingFeeChange, PendingThresholdChange, StoredFeeGovernance, TokenId};
+use crate::state;
+use crate::runtime;
+use crate::validation::validate_token_economics;
+#[cfg(feature = "notifications")]
+use crate::notifications::{publish_metadata_change, MetadataChangeKind};
+
+// Two-controller rule for large fee increases. With a threshold set, a
+// set_token_fee call raising a fee by more than threshold_bps of the current
+// fee is parked until a different controller confirms it. Smaller changes and
+// decreases apply at once.
+
+
+pub const FEE_CHANGE_CONFIRMATION_WINDOW_NS: u64 = constants::NANOS_PER_DAY;
+pub const MAX_PENDING_FEE_CHANGES: usize = 50;
+
+
+// Any increase from a zero fee exceeds every threshold.
+fn exceeds_threshold(previous_fee: u128, new_fee: u128, threshold_bps: u32) -> bool {
+    if new_fee <= previous_fee {
+        return false;
+    }
+    let increase = new_fee - previous_fee;
+    increase.saturating_mul(10_000) > previous_fee.saturating_mul(threshold_bps as u128)
+}
+
+
+// Whether an increase from `previous_fee` to `new_fee` is above the current threshold.
+pub(crate) fn requires_confirmation(previous_fee: u128, new_fee: u128) -> bool {
+    state::get_fee_governance().threshold_bps
+        .is_some_and(|threshold_bps| exceeds_threshold(previous_fee, new_fee, threshold_bps))
+}
+
+
+fn prune_expired(governance: &mut StoredFeeGovernance, now: u64) {
+    governance.pending.retain(|p| p.expires_at >= now);
+    if governance.pending_threshold.as_ref().is_some_and(|p| p.expires_at < now) {
+        governance.pending_threshold = None;
+    }
+}
+
+
+// Called by set_token_fee once the new fee is validated. Ok means the caller
+// applies the fee now; otherwise the change is parked and the error names it.
+pub(crate) fn gate_fee_change(token_id: TokenId, previous_fee: u128, new_fee: u128) -> Result<(), String> {
+    let now = runtime::time();
+    let mut governance = state::get_fee_governance();
+    prune_expired(&mut governance, now);
+    // A new fee for the token supersedes whatever was waiting for it.
+    governance.pending.retain(|p| p.token_id != token_id);
+
+    let Some(threshold_bps) = governance.threshold_bps else {
+        state::set_fee_governance(&governance);
+        return Ok(());
+    };
+    if !exceeds_threshold(previous_fee, new_fee, threshold_bps) {
+        state::set_fee_governance(&governance);
+        return Ok(());
+    }
+
+    if governance.pending.len() >= MAX_PENDING_FEE_CHANGES {
+        return Err(format!("Too many pending fee changes (max {})", MAX_PENDING_FEE_CHANGES));
+    }
+    let change = PendingFeeChange {
+        id: governance.next_id,
+        token_id,
+        previous_fee,
+        new_fee,
+        proposed_by: runtime::caller(),
+        proposed_at: now,
+        expires_at: now.saturating_add(FEE_CHANGE_CONFIRMATION_WINDOW_NS),
+    };
+    governance.next_id += 1;
+    governance.pending.push(change.clone());
+    state::set_fee_governance(&governance);
+    state::record_admin_action("propose_fee_change", format!("#{}: {} -> {}", change.id, previous_fee, new_fee));
+
+    Err(format!(
+        "Fee increase exceeds the {} bps threshold; pending change {} needs confirm_fee_change by another controller before {}",
+        threshold_bps, change.id, change.expires_at
+    ))
+}
+
+
+#[ic_cdk::update]
+pub fn confirm_fee_change(token_id: TokenId, pending_id: u64) -> Result<(), String> {
+    state::require_controller()?;
+
+    let now = runtime::time();
+    let mut governance = state::get_fee_governance();
+    let position = governance.pending.iter()
+        .position(|p| p.id == pending_id && p.token_id == token_id)
+        .ok_or("No such pending fee change for this token".to_string())?;
+    let change = governance.pending[position].clone();
+    if change.expires_at < now {
+        return Err(format!("Pending fee change expired at {}", change.expires_at));
+    }
+    if change.proposed_by == runtime::caller() {
+        return Err("A fee change must be confirmed by a different controller".to_string());
+    }
+
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    validate_token_economics(metadata.decimals, change.new_fee, 0, None).map_err(|e| e.to_string())?;
+    state::update_token_fee(token_id, change.new_fee)?;
+
+    governance.pending.remove(position);
+    state::set_fee_governance(&governance);
+    state::record_admin_action("confirm_fee_change", format!("#{}: {} -> {}", change.id, change.previous_fee, change.new_fee));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::FeeChanged);
+    Ok(())
+}
+
+
+// Any controller may cancel, including the proposer.
+#[ic_cdk::update]
+pub fn cancel_fee_change(token_id: TokenId, pending_id: u64) -> Result<(), String> {
+    state::require_controller()?;
+
+    let mut governance = state::get_fee_governance();
+    let position = governance.pending.iter()
+        .position(|p| p.id == pending_id && p.token_id == token_id)
+        .ok_or("No such pending fee change for this token".to_string())?;
+    governance.pending.remove(position);
+    state::set_fee_governance(&governance);
+    state::record_admin_action("cancel_fee_change", format!("#{}", pending_id));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn list_pending_fee_changes() -> Vec<PendingFeeChange> {
+    let now = runtime::time();
+    state::get_fee_governance().pending.into_iter().filter(|p| p.expires_at >= now).collect()
+}
+
+
+// None turns the rule off. Tightening applies at once; loosening (a higher
+// threshold or turning it off) needs a different controller to repeat the call
+// within the confirmation window, or one compromised key could lift the rule.
+#[ic_cdk::update]
+pub fn set_fee_change_threshold(threshold_bps: Option<u32>) -> Result<(), String> {
+    state::require_controller()?;
+
+    let now = runtime::time();
+    let caller = runtime::caller();
+    let mut governance = state::get_fee_governance();
+    prune_expired(&mut governance, now);
+    let previous = governance.threshold_bps;
+
+    if threshold_bps.is_some() && state::list_controllers().len() < 2 {
+        return Err("The fee change threshold needs at least two controllers".to_string());
+    }
+
+    let loosens = match (previous, threshold_bps) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(old), Some(new)) => new > old,
+    };
+    if loosens {
+        let confirmed = governance.pending_threshold.as_ref()
+            .is_some_and(|p| p.threshold_bps == threshold_bps && p.proposed_by != caller);
+        if !confirmed {
+            let expires_at = now.saturating_add(FEE_CHANGE_CONFIRMATION_WINDOW_NS);
+            governance.pending_threshold = Some(PendingThresholdChange { threshold_bps, proposed_by: caller, expires_at });
+            state::set_fee_governance(&governance);
+            state::record_admin_action("propose_fee_change_threshold", format!("{:?} -> {:?}", previous, threshold_bps));
+            return Err(format!(
+                "Loosening the threshold needs another controller to make the same call before {}",
+                expires_at
+            ));
+        }
+    }
+
+    governance.threshold_bps = threshold_bps;
+    governance.pending_threshold = None;
+    state::set_fee_governance(&governance);
+    state::record_admin_action("set_fee_change_threshold", format!("{:?} -> {:?}", previous, threshold_bps));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_fee_change_threshold() -> Option<u32> {
+    state::get_fee_governance().threshold_bps
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::set_token_fee;
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Nat;
+
+    #[test]
+    fn test_large_fee_increase_needs_second_controller() {
+        let (first, second) = (test_account(0xFE).owner, test_account(0xFD).owner);
+        state::init_state(first);
+        let token = register_test_token(1, 100);
+
+        runtime::set_caller(first);
+        assert!(set_fee_change_threshold(Some(5_000)).is_err());
+        state::add_controller_internal(second).unwrap();
+        set_fee_change_threshold(Some(5_000)).unwrap();
+
+        // Up to +50% and any decrease apply at once.
+        set_token_fee(token, Nat::from(150u64)).unwrap();
+        set_token_fee(token, Nat::from(10u64)).unwrap();
+        assert_eq!(state::get_token_metadata(token).unwrap().fee, 10);
+
+        assert!(set_token_fee(token, Nat::from(1_000u64)).is_err());
+        assert_eq!(state::get_token_metadata(token).unwrap().fee, 10);
+        let pending = list_pending_fee_changes();
+        assert_eq!((pending.len(), pending[0].new_fee, pending[0].proposed_by), (1, 1_000, first));
+        let id = pending[0].id;
+
+        assert!(confirm_fee_change(token, id).unwrap_err().contains("different controller"));
+        runtime::set_caller(test_account(3).owner);
+        assert!(confirm_fee_change(token, id).is_err());
+        runtime::set_caller(second);
+        assert!(confirm_fee_change([9; 32], id).is_err());
+        confirm_fee_change(token, id).unwrap();
+        assert_eq!(state::get_token_metadata(token).unwrap().fee, 1_000);
+        assert!(list_pending_fee_changes().is_empty());
+        assert!(confirm_fee_change(token, id).is_err());
+
+        // Expired confirmations do nothing.
+        assert!(set_token_fee(token, Nat::from(5_000u64)).is_err());
+        let id = list_pending_fee_changes()[0].id;
+        runtime::advance_time(FEE_CHANGE_CONFIRMATION_WINDOW_NS + 1);
+        assert!(list_pending_fee_changes().is_empty());
+        runtime::set_caller(first);
+        assert!(confirm_fee_change(token, id).unwrap_err().contains("expired"));
+        assert_eq!(state::get_token_metadata(token).unwrap().fee, 1_000);
+
+        // Cancelled by either controller.
+        assert!(set_token_fee(token, Nat::from(5_000u64)).is_err());
+        let id = list_pending_fee_changes()[0].id;
+        runtime::set_caller(second);
+        cancel_fee_change(token, id).unwrap();
+        runtime::set_caller(first);
+        assert!(confirm_fee_change(token, id).is_err());
+    }
+
+    #[test]
+    fn test_loosening_threshold_needs_second_controller() {
+        let (first, second) = (test_account(0xFE).owner, test_account(0xFD).owner);
+        state::init_state(first);
+        state::add_controller_internal(second).unwrap();
+        let token = register_test_token(1, 0);
+
+        runtime::set_caller(first);
+        set_fee_change_threshold(Some(1_000)).unwrap();
+        set_fee_change_threshold(Some(500)).unwrap();
+        assert!(set_token_fee(token, Nat::from(1u64)).is_err());
+
+        assert!(set_fee_change_threshold(None).is_err());
+        assert!(set_fee_change_threshold(None).is_err());
+        assert_eq!(get_fee_change_threshold(), Some(500));
+        runtime::set_caller(second);
+        set_fee_change_threshold(None).unwrap();
+        assert_eq!(get_fee_change_threshold(), None);
+        set_token_fee(token, Nat::from(1_000u64)).unwrap();
+        assert!(list_pending_fee_changes().is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::types::{AccountKey, StoredTokenMetadata, TokenId};
+use crate::rounding::{mul_div, RoundingMode};
+use crate::state;
+use crate::transaction::{StoredTxV1, OP_MINT};
+
+
+pub fn resolve_fee_token(token_id: TokenId, metadata: &StoredTokenMetadata) -> TokenId {
+    metadata.fee_token.unwrap_or(token_id)
+}
+
+
+// A token in strict mode rejects `fee: None` so a fee change between quote and
+// submit cannot silently charge more. A zero fee is exempt: nothing can change under the caller.
+pub fn requires_explicit_fee(metadata: &StoredTokenMetadata) -> bool {
+    metadata.require_explicit_fee.unwrap_or(false) && metadata.fee > 0
+}
+
+
+pub fn validate_fee_token_config(token_id: TokenId, fee_token: Option<TokenId>) -> Result<(), String> {
+    let fee_token = match fee_token {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    if fee_token == token_id {
+        return Err("Fee token cannot point at the token itself".to_string());
+    }
+
+    let fee_metadata = state::get_token_metadata(fee_token).map_err(|e| match e {
+        state::MetadataError::NotFound => "Fee token not found".to_string(),
+        other => other.to_string(),
+    })?;
+
+    if fee_metadata.fee_token.is_some() {
+        return Err("Fee token must charge its own fees in itself".to_string());
+    }
+
+    let used_as_fee_token = state::list_token_ids().into_iter()
+        .filter_map(|id| state::get_token_metadata(id).ok())
+        .any(|m| m.fee_token == Some(token_id));
+    if used_as_fee_token {
+        return Err("Token is used as a fee token by other tokens".to_string());
+    }
+
+    Ok(())
+}
+
+
+pub const MAX_COMMISSION_BPS: u16 = 10_000;
+
+
+// Commission is rounded by the token's mode; whatever is left of the fee goes
+// to the token's recipient, so the two shares always add up to the fee.
+pub fn split_fee(fee_amount: u128, commission_bps: u16, mode: RoundingMode) -> (u128, u128) {
+    let bps = commission_bps.min(MAX_COMMISSION_BPS) as u128;
+    // At most the whole fee, which cannot overflow.
+    let commission = mul_div(fee_amount, bps, MAX_COMMISSION_BPS as u128, mode).unwrap_or(fee_amount);
+    (fee_amount - commission, commission)
+}
+
+
+// The treasury's share of a block's fee under the commission set now, as
+// post_fee split it. Only valid while the block is being appended.
+pub fn current_commission(tx: &StoredTxV1) -> Option<(AccountKey, u128)> {
+    if !tx.has_fee() || tx.op == OP_MINT {
+        return None;
+    }
+    let treasury = state::get_protocol_treasury()?;
+    let fee_token = tx.get_fee_token().unwrap_or(tx.token_id);
+    let (_, to_treasury) = split_fee(tx.get_fee(), state::get_protocol_commission_bps(), state::get_token_rounding_mode(fee_token));
+    (to_treasury > 0).then(|| (treasury.to_key(), to_treasury))
+}
+
+
+// Who a block's fee was credited to: the token's fee recipient, which never
+// changes, less the treasury's share when a commission applied.
+pub fn fee_credits_with(tx: &StoredTxV1, commission: Option<(AccountKey, u128)>) -> Vec<(AccountKey, u128)> {
+    if !tx.has_fee() || tx.op == OP_MINT {
+        return Vec::new();
+    }
+    let Ok(metadata) = state::get_token_metadata(tx.token_id) else {
+        return Vec::new();
+    };
+    let to_treasury = commission.map_or(0, |(_, share)| share);
+    let mut credits = vec![(metadata.fee_recipient.to_key(), tx.get_fee().saturating_sub(to_treasury))];
+    credits.extend(commission);
+    credits.retain(|(_, credit)| *credit > 0);
+    credits
+}
+
+
+// The same for a block in the log, whose commission was recorded on append.
+pub fn fee_credits(tx_index: u64, tx: &StoredTxV1) -> Vec<(AccountKey, u128)> {
+    fee_credits_with(tx, state::get_fee_commission(tx_index))
+}
+
+
+type Leg = (AccountKey, u128);
+
+
+// The balance changes of one operation in one token, netted per account so an
+// account that both pays and receives (a fee recipient sending or receiving) is
+// written once. Callers check funds and overflow before applying.
+#[derive(Debug, Clone)]
+pub struct Postings {
+    token_id: TokenId,
+    // (account, debits, credits)
+    entries: Vec<(AccountKey, u128, u128)>,
+    fee_stats: (u128, u128),
+}
+
+impl Postings {
+    pub fn new(token_id: TokenId) -> Self {
+        Self { token_id, entries: Vec::new(), fee_stats: (0, 0) }
+    }
+
+    fn entry(&mut self, account_key: AccountKey) -> &mut (AccountKey, u128, u128) {
+        let position = match self.entries.iter().position(|(key, _, _)| *key == account_key) {
+            Some(position) => position,
+            None => {
+                self.entries.push((account_key, 0, 0));
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[position]
+    }
+
+    pub fn debit(&mut self, account_key: AccountKey, amount: u128) {
+        let entry = self.entry(account_key);
+        entry.1 = entry.1.saturating_add(amount);
+    }
+
+    pub fn credit(&mut self, account_key: AccountKey, amount: u128) {
+        let entry = self.entry(account_key);
+        entry.2 = entry.2.saturating_add(amount);
+    }
+
+    // Debits `fee_amount` from the payer and credits the fee recipient and, when a
+    // commission is configured, the protocol treasury. A share that goes back to
+    // the payer cancels out and does not count toward the collected-fee stats.
+    pub fn post_fee(&mut self, payer_key: AccountKey, recipient_key: AccountKey, fee_amount: u128) {
+        if fee_amount == 0 {
+            return;
+        }
+
+        let treasury_key = state::get_protocol_treasury().map(|t| t.to_key());
+        let (to_recipient, to_treasury) = match treasury_key {
+            Some(_) => split_fee(fee_amount, state::get_protocol_commission_bps(), state::get_token_rounding_mode(self.token_id)),
+            None => (fee_amount, 0),
+        };
+
+        self.debit(payer_key, fee_amount);
+        self.credit(recipient_key, to_recipient);
+        if recipient_key != payer_key {
+            self.fee_stats.0 += to_recipient;
+        }
+        if let Some(treasury_key) = treasury_key {
+            self.credit(treasury_key, to_treasury);
+            if treasury_key != payer_key {
+                self.fee_stats.1 += to_treasury;
+            }
+        }
+    }
+
+    // Debits and credits, as apply_movements takes them.
+    fn legs(&self) -> (Vec<Leg>, Vec<Leg>) {
+        let debits = self.entries.iter().map(|(key, debit, _)| (*key, *debit)).collect();
+        let credits = self.entries.iter().map(|(key, _, credit)| (*key, *credit)).collect();
+        (debits, credits)
+    }
+
+    // Fails if any account's net credit would overflow its balance or the
+    // credits would take the token past its holder limit. A shortfall is the
+    // caller's to name: only a fee leg's postings are pure fee.
+    pub fn check_credits(&self) -> Result<(), state::MovementError> {
+        let (debits, credits) = self.legs();
+        state::check_movements(self.token_id, &debits, &credits, 0)
+    }
+
+    // Callers check funds and check_credits first, so a failure here is a bug
+    // in the postings and traps, undoing the whole call.
+    pub fn apply(self) {
+        let (debits, credits) = self.legs();
+        if let Err(e) = state::apply_movements(self.token_id, &debits, &credits, 0) {
+            panic!("Postings failed to apply: {}", e);
+        }
+
+        if self.fee_stats != (0, 0) {
+            state::record_fee_stats(self.token_id, self.fee_stats.0, self.fee_stats.1);
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeeLegError {
+    InsufficientFunds { fee_token: TokenId, balance: u128 },
+    Overflow,
+    HolderLimitReached { max_holders: u64 },
+}
+
+
+// Fee debit/credit in a token other than the one being moved. Prepared with
+// reads only so the caller can bail out before any balance is written.
+#[derive(Debug, Clone)]
+pub struct FeeLeg {
+    pub fee_token: TokenId,
+    postings: Postings,
+}
+
+impl FeeLeg {
+    pub fn prepare(
+        fee_token: TokenId,
+        payer_key: AccountKey,
+        recipient_key: AccountKey,
+        fee_amount: u128,
+    ) -> Result<Self, FeeLegError> {
+        Self::prepare_each(fee_token, payer_key, recipient_key, fee_amount, 1)
+    }
+
+    // `count` fees of `fee_amount`, one per block, each split on its own so
+    // the commission matches what fee_credits reads back per block.
+    pub fn prepare_each(
+        fee_token: TokenId,
+        payer_key: AccountKey,
+        recipient_key: AccountKey,
+        fee_amount: u128,
+        count: u64,
+    ) -> Result<Self, FeeLegError> {
+        let total = fee_amount.checked_mul(count as u128).ok_or(FeeLegError::Overflow)?;
+        let payer_balance = state::get_spendable_balance(fee_token, payer_key);
+        if payer_balance < total {
+            return Err(FeeLegError::InsufficientFunds {
+                fee_token,
+                balance: payer_balance,
+            });
+        }
+
+        let mut postings = Postings::new(fee_token);
+        for _ in 0..count {
+            postings.post_fee(payer_key, recipient_key, fee_amount);
+        }
+        postings.check_credits().map_err(|e| match e {
+            state::MovementError::HolderLimitReached { max_holders } => FeeLegError::HolderLimitReached { max_holders },
+            state::MovementError::InsufficientBalance { balance, .. } => FeeLegError::InsufficientFunds { fee_token, balance },
+            _ => FeeLegError::Overflow,
+        })?;
+
+        Ok(Self { fee_token, postings })
+    }
+
+    pub fn apply(self) {
+        self.postings.apply();
+    }
+}
@@ -0,0 +1,149 @@
+use crate::reconciliation::first_block_from;
+use crate::runtime;
+use crate::state;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// The IC does not reorganize blocks, so nothing here changes what the ledger
+// accepts. Bridges and other off-chain consumers still want to wait before
+// acting on a block; the policy gives them all the same rule, applied by the
+// ledger rather than reimplemented by each client.
+
+
+// Blocks the finality scan reads past the point the drift bound already
+// settles. Stopping there only makes the answer lower, never wrong, and a
+// later call starts further along.
+pub const MAX_FINALITY_SCAN: u64 = 10_000;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FinalityPolicy {
+    // Blocks that must follow a block before it is final.
+    pub min_depth: u64,
+    // How far a block's timestamp must lie behind ledger time.
+    pub min_age_ns: u64,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FinalizedHeight {
+    pub tx_index: u64,
+    pub timestamp: u64,
+}
+
+
+pub(crate) fn finality_policy() -> FinalityPolicy {
+    let (min_depth, min_age_ns) = state::get_finality_policy();
+    FinalityPolicy { min_depth, min_age_ns }
+}
+
+
+// One past the last final block. A block is final when at least min_depth
+// blocks follow it and neither it nor any block before it has a timestamp
+// within min_age_ns of now. Timestamps come from created_at_time and are not
+// ordered, so age is judged on the whole prefix; that keeps the height from
+// ever moving back.
+pub(crate) fn finalized_end() -> u64 {
+    let policy = finality_policy();
+    let by_depth = state::get_transaction_count().saturating_sub(policy.min_depth);
+    if policy.min_age_ns == 0 {
+        return by_depth;
+    }
+    let Some(cutoff) = runtime::time().checked_sub(policy.min_age_ns) else {
+        return 0;
+    };
+
+    // Every block before this one is at or before the cutoff.
+    let start = first_block_from(cutoff.saturating_add(1)).min(by_depth);
+    let scan_end = by_depth.min(start.saturating_add(MAX_FINALITY_SCAN));
+    (start..scan_end)
+        .find(|&tx_index| state::get_transaction(tx_index).is_none_or(|tx| tx.get_timestamp() > cutoff))
+        .unwrap_or(scan_end)
+}
+
+
+#[ic_cdk::update]
+pub fn set_finality_policy(policy: FinalityPolicy) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = finality_policy();
+    state::set_finality_policy(policy.min_depth, policy.min_age_ns);
+    state::record_admin_action("set_finality_policy", format!("{:?} -> {:?}", previous, policy));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_finality_policy() -> FinalityPolicy {
+    finality_policy()
+}
+
+
+// The highest final block, or None while no block is final.
+#[ic_cdk::query]
+pub fn get_finalized_height() -> Option<FinalizedHeight> {
+    let tx_index = finalized_end().checked_sub(1)?;
+    state::get_transaction(tx_index).map(|tx| FinalizedHeight { tx_index, timestamp: tx.get_timestamp() })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::mint_internal;
+    use crate::queries::{get_token_tx_by_seq, get_transaction_range, get_transactions};
+    use crate::test_utils::{register_test_token, test_account};
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_finalized_height_follows_policy() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        runtime::set_caller(controller);
+        assert_eq!(get_finalized_height(), None);
+
+        let start = runtime::time();
+        for _ in 0..5 {
+            mint_internal(token, test_account(1), 10, None, None).unwrap();
+            runtime::advance_time(SECOND);
+        }
+        // By default every block is final.
+        assert_eq!(get_finalized_height(), Some(FinalizedHeight { tx_index: 4, timestamp: start + 4 * SECOND }));
+        assert_eq!(get_transactions(None, None, None, None, Some(true)).unwrap().len(), 5);
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(set_finality_policy(FinalityPolicy { min_depth: 1, min_age_ns: 0 }).is_err());
+        runtime::set_caller(controller);
+        set_finality_policy(FinalityPolicy { min_depth: 1, min_age_ns: 0 }).unwrap();
+        assert_eq!(get_finalized_height().unwrap().tx_index, 3);
+
+        // Ledger time is start + 5s, so blocks stamped after start + 2.5s are too new.
+        set_finality_policy(FinalityPolicy { min_depth: 1, min_age_ns: 5 * SECOND / 2 }).unwrap();
+        assert_eq!(get_finality_policy(), FinalityPolicy { min_depth: 1, min_age_ns: 5 * SECOND / 2 });
+        assert_eq!(get_finalized_height().unwrap().tx_index, 2);
+
+        // An old timestamp behind a block that is not final yet does not make it final.
+        mint_internal(token, test_account(2), 10, None, Some(start)).unwrap();
+        assert_eq!(get_finalized_height().unwrap().tx_index, 2);
+
+        assert_eq!(get_transactions(None, None, None, None, Some(true)).unwrap().len(), 3);
+        assert_eq!(get_transactions(None, None, None, None, None).unwrap().len(), 6);
+        assert_eq!(get_transaction_range(0, 10, None, Some(true)).unwrap().len(), 3);
+        assert!(get_token_tx_by_seq(token, 4, Some(true)).unwrap().is_none());
+        assert!(get_token_tx_by_seq(token, 4, None).unwrap().is_some());
+
+        // Once the window passes, everything but the tip is final.
+        runtime::advance_time(3 * SECOND);
+        assert_eq!(get_finalized_height(), Some(FinalizedHeight { tx_index: 4, timestamp: start + 4 * SECOND }));
+
+        set_finality_policy(FinalityPolicy { min_depth: 0, min_age_ns: u64::MAX }).unwrap();
+        assert_eq!(get_finalized_height(), None);
+        assert!(get_transactions(None, None, None, None, Some(true)).unwrap().is_empty());
+
+        let actions: Vec<_> = crate::queries::get_admin_audit_log(0, 10).into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, ["set_finality_policy"; 3]);
+    }
+}
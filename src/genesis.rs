@@ -0,0 +1,192 @@
+use crate::operations::{create_token_internal, CreateTokenArgs};
+use crate::state;
+use crate::types::constants;
+use crate::validation::validate_admin_principal;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use num_traits::cast::ToPrimitive;
+
+
+// Install and upgrade arguments. Without them, init makes the installer the
+// only controller with default settings, and an upgrade changes nothing.
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    // Empty means the installer at install time and no change on upgrade. The
+    // first principal is the primary controller: the fee recipient and initial
+    // supply holder of tokens created here.
+    pub controllers: Vec<Principal>,
+    pub default_token_fee: Option<candid::Nat>,
+    // Off at install when absent; an upgrade without it leaves the mode alone.
+    pub maintenance: Option<bool>,
+    // Install only.
+    pub tokens: Vec<CreateTokenArgs>,
+    // How far created_at_time may lie behind and ahead of ledger time, in nanoseconds.
+    pub tx_window: Option<(u64, u64)>,
+}
+
+
+fn validate_settings(args: &InitArgs) -> Result<Option<u128>, String> {
+    for controller in &args.controllers {
+        validate_admin_principal(controller).map_err(|e| e.to_string())?;
+    }
+    if let Some((past, future)) = args.tx_window {
+        if past == 0 || past > constants::MAX_TX_WINDOW_NS || future > constants::MAX_TX_WINDOW_NS {
+            return Err(format!(
+                "tx_window must be at most {} ns on either side, with a non-zero past side",
+                constants::MAX_TX_WINDOW_NS
+            ));
+        }
+    }
+    args.default_token_fee.as_ref()
+        .map(|fee| fee.0.to_u128().ok_or("Default token fee exceeds maximum value (u128::MAX)".to_string()))
+        .transpose()
+}
+
+
+fn apply_settings(args: &InitArgs, default_token_fee: Option<u128>) {
+    if let Some(fee) = default_token_fee {
+        state::set_default_token_fee(fee);
+    }
+    if let Some((past, future)) = args.tx_window {
+        state::set_tx_window(past, future);
+    }
+    if let Some(maintenance) = args.maintenance {
+        state::set_maintenance_mode(maintenance);
+    }
+}
+
+
+// Everything is checked before anything is written, but a token can still fail
+// to create; init traps on any error, which undoes the whole install.
+pub(crate) fn apply_init_args(installer: Principal, args: Option<InitArgs>) -> Result<(), String> {
+    let args = args.unwrap_or_default();
+    let default_token_fee = validate_settings(&args)?;
+
+    let controllers = match args.controllers.is_empty() {
+        true => vec![installer],
+        false => args.controllers.clone(),
+    };
+    state::init_state(controllers[0]);
+    for controller in &controllers[1..] {
+        state::add_controller_internal(*controller)?;
+    }
+    apply_settings(&args, default_token_fee);
+
+    for token in args.tokens {
+        let symbol = token.symbol.clone();
+        create_token_internal(controllers[0], token)
+            .map_err(|e| format!("Failed to create token {}: {:?}", symbol, e))?;
+    }
+    Ok(())
+}
+
+
+// Settings only: an upgrade never creates tokens. post_upgrade traps on any
+// error, which rejects the upgrade.
+pub(crate) fn apply_upgrade_args(args: InitArgs) -> Result<(), String> {
+    if !args.tokens.is_empty() {
+        return Err("Tokens can only be listed at install; use create_token_with_args".to_string());
+    }
+    let default_token_fee = validate_settings(&args)?;
+
+    if !args.controllers.is_empty() {
+        state::replace_controllers(&args.controllers)?;
+    }
+    apply_settings(&args, default_token_fee);
+    state::record_admin_action("upgrade_settings", format!(
+        "controllers: {}, default_token_fee: {:?}, maintenance: {:?}, tx_window: {:?}",
+        args.controllers.len(), default_token_fee, args.maintenance, args.tx_window
+    ));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{transfer_internal, TransferError};
+    use crate::queries::get_ledger_config;
+    use crate::runtime;
+    use crate::test_utils::test_account;
+    use crate::types::Account;
+    use candid::Nat;
+
+    fn token_args(symbol: &str, initial_supply: Option<u64>, fee: Option<u64>) -> CreateTokenArgs {
+        CreateTokenArgs {
+            name: format!("{} Token", symbol),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            initial_supply: initial_supply.map(Nat::from),
+            fee: fee.map(Nat::from),
+            logo: None,
+            description: None,
+            namespace: Some(symbol.as_bytes().to_vec()),
+            args_version: None,
+        }
+    }
+
+    #[test]
+    fn test_init_without_args_keeps_installer_as_controller() {
+        let installer = test_account(0xFE).owner;
+        apply_init_args(installer, None).unwrap();
+        assert_eq!(state::list_controllers(), vec![installer]);
+        assert_eq!(state::get_controller(), Some(installer));
+        assert!(!state::is_maintenance_mode());
+        let config = get_ledger_config();
+        assert_eq!(config.default_token_fee, constants::DEFAULT_TOKEN_FEE);
+        assert_eq!((config.max_past_drift_ns, config.max_future_drift_ns), (constants::MAX_PAST_DRIFT, constants::MAX_FUTURE_DRIFT));
+        assert_eq!(state::get_token_count(), 0);
+    }
+
+    #[test]
+    fn test_init_and_upgrade_args() {
+        let (installer, first, second) = (test_account(0xFE).owner, test_account(0xF1).owner, test_account(0xF2).owner);
+        let minute = 60_000_000_000;
+        let args = InitArgs {
+            controllers: vec![first, second],
+            default_token_fee: Some(Nat::from(25u64)),
+            maintenance: Some(true),
+            tokens: vec![token_args("AAA", Some(1_000), None), token_args("BBB", None, Some(3))],
+            tx_window: Some((2 * minute, minute)),
+        };
+        assert!(apply_init_args(installer, Some(InitArgs { controllers: vec![Principal::anonymous()], ..args.clone() })).is_err());
+        assert!(apply_init_args(installer, Some(InitArgs { tx_window: Some((0, minute)), ..args.clone() })).is_err());
+        apply_init_args(installer, Some(args.clone())).unwrap();
+
+        let controllers = state::list_controllers();
+        assert_eq!((controllers.len(), state::get_controller()), (2, Some(first)));
+        assert!(!state::is_controller(&installer));
+        let tokens: Vec<_> = state::list_token_ids().into_iter().map(|t| (t, state::get_token_metadata(t).unwrap())).collect();
+        let (aaa, aaa_metadata) = tokens.iter().find(|(_, m)| m.symbol == "AAA").unwrap();
+        let (_, bbb_metadata) = tokens.iter().find(|(_, m)| m.symbol == "BBB").unwrap();
+        assert_eq!((aaa_metadata.fee, aaa_metadata.total_supply, bbb_metadata.fee), (25, 1_000, 3));
+        let holder = Account { owner: first, subaccount: None };
+        assert_eq!(state::get_balance(*aaa, holder.to_key()), 1_000);
+        assert_eq!(state::get_tx_window(), (2 * minute, minute));
+
+        // Maintenance holds user transfers until it is lifted.
+        assert!(matches!(
+            transfer_internal(*aaa, holder.clone(), test_account(1), 10, None, None, None),
+            Err(TransferError::TemporarilyUnavailable)
+        ));
+
+        // Upgrades change settings but never create tokens, and leave
+        // maintenance alone unless they name it.
+        assert!(apply_upgrade_args(args.clone()).is_err());
+        apply_upgrade_args(InitArgs { controllers: vec![second], default_token_fee: Some(Nat::from(7u64)), ..Default::default() }).unwrap();
+        assert_eq!((state::list_controllers(), state::get_controller()), (vec![second], Some(second)));
+        assert!(state::is_maintenance_mode());
+        assert_eq!(state::get_default_token_fee(), 7);
+        apply_upgrade_args(InitArgs { maintenance: Some(false), ..Default::default() }).unwrap();
+        assert!(!state::is_maintenance_mode());
+        assert_eq!(state::get_tx_window(), (2 * minute, minute));
+        assert_eq!(state::get_token_count(), 2);
+        transfer_internal(*aaa, holder, test_account(1), 10, None, None, None).unwrap();
+        assert_eq!(get_ledger_config().default_token_fee, 7);
+        runtime::set_caller(second);
+        crate::operations::set_maintenance_mode(true).unwrap();
+        assert!(crate::operations::get_maintenance_mode());
+    }
+}
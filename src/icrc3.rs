@@ -0,0 +1,211 @@
+use crate::queries::{transaction_memo, TransactionMemo};
+use crate::state;
+use crate::transaction::StoredTxV1;
+use crate::types::AdminAuditEntry;
+use candid::{CandidType, Nat};
+use serde::{Deserialize, Serialize};
+
+
+pub const MAX_VALUE_BLOCKS: u64 = 500;
+
+
+// The ICRC-3 generic value.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockValue {
+    pub id: Nat,
+    pub block: Value,
+}
+
+
+fn btype(op: u8) -> String {
+    match op {
+        0 => "1xfer".to_string(),
+        1 => "1mint".to_string(),
+        2 => "1burn".to_string(),
+        3 => "2approve".to_string(),
+        4 => "2xfer".to_string(),
+        other => format!("151op{}", other),
+    }
+}
+
+
+// Flat map with keys in sorted order, so the encoding of a block never changes.
+// The one exception is a private memo: its parties and controllers get `memo`,
+// everyone else `memo_private` with the commitment.
+pub fn tx_to_value(tx_index: u64, tx: &StoredTxV1) -> Value {
+    let mut fields = vec![
+        ("amt".to_string(), Value::Nat(Nat::from(tx.get_amount()))),
+        ("btype".to_string(), Value::Text(btype(tx.op))),
+        ("tid".to_string(), Value::Blob(tx.token_id.to_vec())),
+        ("ts".to_string(), Value::Nat(Nat::from(tx.get_timestamp()))),
+    ];
+
+    if tx.has_fee() {
+        fields.push(("fee".to_string(), Value::Nat(Nat::from(tx.get_fee()))));
+    }
+    if let Some(fee_token) = tx.get_fee_token() {
+        fields.push(("fee_tid".to_string(), Value::Blob(fee_token.to_vec())));
+    }
+    if tx.op != 1 {
+        fields.push(("from".to_string(), Value::Blob(tx.from_key.to_vec())));
+    }
+    if tx.op == 0 || tx.op == 1 || tx.op == 4 {
+        fields.push(("to".to_string(), Value::Blob(tx.to_key.to_vec())));
+    }
+    if let Some(target) = tx.get_burn_target() {
+        fields.push(("burn_to".to_string(), Value::Blob(target.to_vec())));
+    }
+    if tx.has_spender() {
+        fields.push(("spender".to_string(), Value::Blob(tx.spender_key.to_vec())));
+    }
+    if let Some(sponsor) = tx.get_fee_sponsor() {
+        fields.push(("fee_payer".to_string(), Value::Blob(sponsor.to_vec())));
+    }
+    match transaction_memo(tx_index, tx) {
+        TransactionMemo::NoMemo => {}
+        TransactionMemo::Memo(memo) | TransactionMemo::Structured { raw: memo, .. } => fields.push(("memo".to_string(), Value::Blob(memo))),
+        TransactionMemo::MemoPrivate { commitment } => {
+            fields.push(("memo_private".to_string(), Value::Blob(commitment.to_vec())));
+        }
+    }
+
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    Value::Map(fields)
+}
+
+
+pub fn admin_event_to_value(entry: &AdminAuditEntry) -> Value {
+    Value::Map(vec![
+        ("action".to_string(), Value::Text(entry.action.clone())),
+        ("btype".to_string(), Value::Text("151admin".to_string())),
+        ("caller".to_string(), Value::Blob(entry.caller.as_slice().to_vec())),
+        ("detail".to_string(), Value::Text(entry.detail.clone())),
+        ("ts".to_string(), Value::Nat(Nat::from(entry.timestamp))),
+    ])
+}
+
+
+#[ic_cdk::query]
+// Empty for callers without access while privacy mode is on.
+pub fn get_blocks_as_values(start: u64, length: u64) -> Vec<BlockValue> {
+    if crate::privacy::require_privileged_reader().is_err() {
+        return Vec::new();
+    }
+    let total = state::get_transaction_count();
+    let end = start.saturating_add(length.min(MAX_VALUE_BLOCKS)).min(total);
+
+    (start..end)
+        .filter_map(|index| {
+            state::get_transaction(index).map(|tx| BlockValue {
+                id: Nat::from(index),
+                block: tx_to_value(index, &tx),
+            })
+        })
+        .collect()
+}
+
+
+// Admin events have their own index space, separate from transaction ids.
+#[ic_cdk::query]
+pub fn get_admin_events_as_values(start: u64, length: u64) -> Vec<BlockValue> {
+    state::list_admin_actions(start, length.min(MAX_VALUE_BLOCKS) as usize)
+        .iter()
+        .map(|entry| BlockValue {
+            id: Nat::from(entry.index),
+            block: admin_event_to_value(entry),
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    const TS: u64 = 1_700_000_000_000_000_000;
+
+    fn render(value: &Value) -> String {
+        match value {
+            Value::Blob(bytes) => format!("blob {}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+            Value::Text(t) => format!("text {:?}", t),
+            Value::Nat(n) => format!("nat {}", n.0),
+            Value::Int(i) => format!("int {}", i.0),
+            Value::Array(items) => {
+                let inner: Vec<String> = items.iter().map(render).collect();
+                format!("array [{}]", inner.join(", "))
+            }
+            Value::Map(fields) => fields
+                .iter()
+                .map(|(k, v)| format!("{} = {}\n", k, render(v)))
+                .collect(),
+        }
+    }
+
+    // Run with UPDATE_GOLDEN=1 to rewrite the files after an intended change.
+    fn assert_golden(name: &str, value: &Value) {
+        let path = format!("{}/testdata/icrc3_values/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        let actual = render(value);
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            std::fs::write(&path, &actual).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap_or_else(|_| panic!("missing golden file {}", path));
+        assert_eq!(actual, expected, "Value encoding for {} changed", name);
+    }
+
+    #[test]
+    fn test_golden_values_per_op() {
+        let token = [0x11; 32];
+        let (from, to, spender) = ([0x22; 32], [0x33; 32], [0x44; 32]);
+
+        let transfer = StoredTxV1::new_transfer(token, from, to, 1_000, 10, TS, Some(b"invoice 42"));
+        assert_golden("transfer", &tx_to_value(0, &transfer));
+
+        let fee_token_transfer = StoredTxV1::new_transfer(token, from, to, 1_000, 5, TS, None).with_fee_token([0x55; 32]);
+        assert_golden("transfer_fee_token", &tx_to_value(1, &fee_token_transfer));
+
+        assert_golden("mint", &tx_to_value(2, &StoredTxV1::new_mint(token, to, 500, TS, None)));
+        assert_golden("burn", &tx_to_value(3, &StoredTxV1::new_burn(token, from, 200, TS, Some(b"withdraw"))));
+        assert_golden("approve", &tx_to_value(4, &StoredTxV1::new_approve(token, from, spender, 700, 10, TS, None)));
+        assert_golden("transfer_from", &tx_to_value(5, &StoredTxV1::new_transfer_from(token, from, to, spender, 300, 10, TS, None)));
+
+        let admin = AdminAuditEntry {
+            index: 0,
+            caller: Principal::from_slice(&[0xFE; 10]),
+            timestamp: TS,
+            action: "set_max_tokens".to_string(),
+            detail: "100000 -> 200000".to_string(),
+        };
+        assert_golden("admin", &admin_event_to_value(&admin));
+    }
+
+    #[test]
+    fn test_blocks_as_values_range_and_extended_memo() {
+        let long_memo = [7u8; 40];
+        state::add_transaction_with_memo(StoredTxV1::new_transfer([1; 32], [2; 32], [3; 32], 1, 0, TS, Some(&long_memo)), Some(&long_memo));
+        for _ in 0..3 {
+            state::add_transaction(StoredTxV1::new_mint([1; 32], [2; 32], 1, TS, None));
+        }
+
+        let blocks = get_blocks_as_values(0, 10);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[3].id, Nat::from(3u64));
+        let Value::Map(fields) = &blocks[0].block else { panic!("expected a map") };
+        assert!(fields.contains(&("memo".to_string(), Value::Blob(long_memo.to_vec()))));
+
+        assert_eq!(get_blocks_as_values(2, 10).len(), 2);
+        assert!(get_blocks_as_values(10, 10).is_empty());
+        assert_eq!(get_blocks_as_values(0, u64::MAX).len(), 4);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::allowances::hex_encode;
+use crate::operations::{check_token_capacity, validate_token_names, CreateTokenError};
+use crate::runtime;
+use crate::state;
+use crate::types::{Account, StoredTokenMetadata, TokenId, TokenImport};
+use crate::validation::{validate_account, validate_token_economics, validate_token_id};
+use candid::CandidType;
+use num_traits::cast::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+
+// Moving tokens from another ledger with their history intact: the token is
+// registered under its existing id, creation time and supply, holders'
+// balances are written in chunks without minting, and finalizing checks they
+// add up to the declared supply. Until then the token refuses every
+// operation, so nothing moves on a half-imported token.
+
+
+pub const MAX_IMPORT_BATCH: usize = 500;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ImportTokenArgs {
+    // As derived by the ledger the token comes from; must not be in use here.
+    pub token_id: TokenId,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: candid::Nat,
+    pub total_supply: candid::Nat,
+    pub created_at: u64,
+    pub logo: Option<String>,
+    pub description: Option<String>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImportStatus {
+    pub declared_supply: candid::Nat,
+    pub imported_supply: candid::Nat,
+    pub accounts: u64,
+    pub opened_at: u64,
+}
+
+impl From<TokenImport> for ImportStatus {
+    fn from(import: TokenImport) -> Self {
+        ImportStatus {
+            declared_supply: candid::Nat::from(import.declared_supply),
+            imported_supply: candid::Nat::from(import.imported_supply),
+            accounts: import.accounts,
+            opened_at: import.opened_at,
+        }
+    }
+}
+
+
+fn open_import(token_id: TokenId) -> Result<TokenImport, String> {
+    state::get_token_import(token_id).ok_or_else(|| "No open import for this token".to_string())
+}
+
+
+// Registers the token and opens its import. Fees go to the primary
+// controller, as for tokens created here.
+#[ic_cdk::update]
+pub fn import_token(args: ImportTokenArgs) -> Result<TokenId, CreateTokenError> {
+    state::require_controller()?;
+    validate_token_id(&args.token_id).map_err(|e| e.to_string())?;
+    validate_token_names(&args.name, &args.symbol)?;
+    let fee = args.fee.0.to_u128().ok_or("Fee exceeds maximum value (u128::MAX)".to_string())?;
+    let total_supply = args.total_supply.0.to_u128()
+        .ok_or("Total supply exceeds maximum value (u128::MAX)".to_string())?;
+    validate_token_economics(args.decimals, fee, total_supply, None)?;
+    if args.created_at > runtime::time() {
+        return Err("created_at lies in the future".to_string().into());
+    }
+    if state::token_exists(args.token_id) {
+        return Err("Token id already exists".to_string().into());
+    }
+    check_token_capacity()?;
+
+    let controller = state::get_controller().ok_or("No controller set".to_string())?;
+    let token_id = args.token_id;
+    state::register_token(token_id, StoredTokenMetadata {
+        name: args.name,
+        symbol: args.symbol,
+        decimals: args.decimals,
+        total_supply,
+        fee,
+        fee_recipient: Account { owner: controller, subaccount: None },
+        logo: args.logo,
+        description: args.description,
+        created_at: args.created_at,
+        controller,
+        fee_token: None,
+        require_explicit_fee: None,
+        fee_free_consolidation: None,
+        immutable: None,
+        scheduled_fee: None,
+        burn_addresses: None,
+        non_circulating_accounts: None,
+        rounding_mode: None,
+        structured_memos_required: None,
+        max_holders: None,
+    });
+    state::set_token_import(token_id, TokenImport {
+        declared_supply: total_supply,
+        imported_supply: 0,
+        accounts: 0,
+        opened_at: runtime::time(),
+    });
+    state::record_admin_action(
+        "import_token",
+        format!("{}: supply {}, created_at {}", hex_encode(&token_id), total_supply, args.created_at),
+    );
+    Ok(token_id)
+}
+
+
+// Sets each account's balance to the given amount, replacing what an earlier
+// chunk set, so a chunk can be sent again after a failed call. 0 removes the
+// account. Writes no blocks. A chunk that would take the imported total past
+// the declared supply changes nothing.
+#[ic_cdk::update]
+pub fn import_balances(token_id: TokenId, balances: Vec<(Account, candid::Nat)>) -> Result<ImportStatus, String> {
+    state::require_controller()?;
+    let mut import = open_import(token_id)?;
+    if balances.len() > MAX_IMPORT_BATCH {
+        return Err(format!("Too many balances (max {})", MAX_IMPORT_BATCH));
+    }
+
+    let mut entries = Vec::with_capacity(balances.len());
+    let mut imported = import.imported_supply;
+    let mut accounts = import.accounts;
+    for (account, amount) in &balances {
+        validate_account(account).map_err(|e| e.to_string())?;
+        let amount = amount.0.to_u128().ok_or("Balance exceeds maximum value (u128::MAX)".to_string())?;
+        let key = account.to_key();
+        // A later entry for the same account replaces an earlier one.
+        let previous = entries.iter().rev()
+            .find(|(_, k, _)| *k == key)
+            .map_or_else(|| state::get_balance(token_id, key), |(_, _, a)| *a);
+        imported = (imported - previous).checked_add(amount)
+            .filter(|total| *total <= import.declared_supply)
+            .ok_or_else(|| format!("Imported balances would exceed the declared supply of {}", import.declared_supply))?;
+        accounts = accounts + (amount > 0) as u64 - (previous > 0) as u64;
+        entries.push((account, key, amount));
+    }
+
+    for (account, key, amount) in entries {
+        state::set_imported_balance(token_id, key, amount)?;
+        state::register_account(account)?;
+    }
+    import.imported_supply = imported;
+    import.accounts = accounts;
+    state::set_token_import(token_id, import.clone());
+    state::record_admin_action(
+        "import_balances",
+        format!("{}: {} balances, {} of {} imported", hex_encode(&token_id), balances.len(), imported, import.declared_supply),
+    );
+    Ok(import.into())
+}
+
+
+// Closes the import once the balances add up to the declared supply. From
+// then on the token works like any other and nothing more can be imported.
+#[ic_cdk::update]
+pub fn finalize_import(token_id: TokenId) -> Result<(), String> {
+    state::require_controller()?;
+    let import = open_import(token_id)?;
+    if import.imported_supply != import.declared_supply {
+        return Err(format!(
+            "Imported balances total {}, the declared supply is {}",
+            import.imported_supply, import.declared_supply
+        ));
+    }
+    state::remove_token_import(token_id);
+    state::record_admin_action(
+        "finalize_import",
+        format!("{}: {} accounts, supply {}", hex_encode(&token_id), import.accounts, import.declared_supply),
+    );
+    Ok(())
+}
+
+
+// None once finalized, or for tokens that were never imported.
+#[ic_cdk::query]
+pub fn get_import_status(token_id: TokenId) -> Option<ImportStatus> {
+    state::get_token_import(token_id).map(ImportStatus::from)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{transfer_internal, TransferError};
+    use crate::queries::{get_holder_count, get_token_metadata};
+    use crate::test_utils::{register_test_token, test_account, test_subaccount};
+
+    fn setup() -> candid::Principal {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        controller
+    }
+
+    fn import_args(token_id: TokenId, total_supply: u64) -> ImportTokenArgs {
+        ImportTokenArgs {
+            token_id,
+            name: "Legacy".to_string(),
+            symbol: "LGC".to_string(),
+            decimals: 8,
+            fee: candid::Nat::from(10u64),
+            total_supply: candid::Nat::from(total_supply),
+            created_at: 1_000,
+            logo: None,
+            description: None,
+        }
+    }
+
+    fn nat(n: u64) -> candid::Nat {
+        candid::Nat::from(n)
+    }
+
+    #[test]
+    fn test_import_balances_and_finalize() {
+        setup();
+        runtime::advance_time(1_000_000);
+        let token = import_token(import_args([7u8; 32], 1_000)).unwrap();
+        let stored = state::get_token_metadata(token).unwrap();
+        assert_eq!((stored.created_at, stored.total_supply), (1_000, 1_000));
+        assert_eq!(get_token_metadata(token).unwrap().total_supply, nat(1_000));
+
+        let (alice, bob, carol) = (test_account(1), test_subaccount(2, 3), test_account(4));
+        // Nothing runs on the token while the import is open.
+        assert!(matches!(
+            transfer_internal(token, alice.clone(), bob.clone(), 1, None, None, None),
+            Err(TransferError::GenericError { error_code, .. }) if error_code == 409u64
+        ));
+
+        let status = import_balances(token, vec![(alice.clone(), nat(600)), (bob.clone(), nat(300))]).unwrap();
+        assert_eq!((status.imported_supply, status.accounts), (nat(900), 2));
+        // Resending a chunk replaces, and a later entry for an account wins.
+        let status = import_balances(token, vec![(bob.clone(), nat(300)), (carol.clone(), nat(50)), (carol.clone(), nat(100))]).unwrap();
+        assert_eq!((status.imported_supply, status.accounts), (nat(1_000), 3));
+        assert!(import_balances(token, vec![(alice.clone(), nat(601))]).is_err());
+        assert_eq!(state::get_balance(token, alice.to_key()), 600);
+        assert_eq!(get_holder_count(token).unwrap(), 3);
+        assert_eq!(state::get_registered_account(bob.to_key()), Some(bob.clone()));
+
+        // Balances must match the declared supply before the import closes.
+        import_balances(token, vec![(carol.clone(), nat(0))]).unwrap();
+        assert!(finalize_import(token).unwrap_err().contains("900"));
+        import_balances(token, vec![(carol.clone(), nat(100))]).unwrap();
+        finalize_import(token).unwrap();
+        assert_eq!(get_import_status(token), None);
+
+        assert!(import_balances(token, vec![(alice.clone(), nat(1))]).is_err());
+        assert!(finalize_import(token).is_err());
+        transfer_internal(token, alice.clone(), bob.clone(), 100, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, alice.to_key()), 490);
+        // The fee recipient joins the holders.
+        assert_eq!(get_holder_count(token).unwrap(), 4);
+
+        let actions: Vec<String> = state::list_admin_actions(0, 100).into_iter().map(|e| e.action).collect();
+        assert_eq!(actions.iter().filter(|a| a.contains("import")).count(), 6);
+    }
+
+    #[test]
+    fn test_import_token_rules() {
+        let controller = setup();
+        let existing = register_test_token(1, 0);
+        assert!(import_token(import_args(existing, 10)).is_err());
+        assert!(import_token(import_args([0u8; 32], 10)).is_err());
+        let mut future = import_args([2u8; 32], 10);
+        future.created_at = runtime::time() + 1;
+        assert!(import_token(future).is_err());
+        assert!(import_balances([3u8; 32], vec![]).is_err());
+        assert!(import_balances(existing, vec![(test_account(1), nat(1))]).is_err());
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(import_token(import_args([2u8; 32], 10)).is_err());
+        runtime::set_caller(controller);
+        let token = import_token(import_args([2u8; 32], 10)).unwrap();
+        assert!(import_token(import_args([2u8; 32], 10)).is_err());
+        let too_many = (0..=MAX_IMPORT_BATCH).map(|_| (test_account(1), nat(0))).collect();
+        assert!(import_balances(token, too_many).is_err());
+        runtime::set_caller(test_account(1).owner);
+        assert!(import_balances(token, vec![(test_account(1), nat(10))]).is_err());
+        assert!(finalize_import(token).is_err());
+        assert_eq!(get_import_status(token).unwrap().imported_supply, nat(0));
+    }
+}
@@ -1,25 +1,216 @@
 pub mod types;
 pub mod transaction;
+pub mod rounding;
+pub mod proof;
+pub mod verification;
+
+// The ledger canister. Everything below needs the IC runtime.
+#[cfg(feature = "canister")]
 pub mod state;
+#[cfg(feature = "canister")]
 pub mod validation;
+#[cfg(feature = "canister")]
 pub mod queries;
+#[cfg(feature = "canister")]
 pub mod operations;
+#[cfg(feature = "canister")]
 pub mod allowances;
+#[cfg(feature = "canister")]
+pub mod runtime;
+#[cfg(feature = "canister")]
+pub mod fees;
+#[cfg(feature = "canister")]
+pub mod accounts;
+#[cfg(feature = "canister")]
+pub mod recovery;
+#[cfg(feature = "canister")]
+pub mod netting;
+#[cfg(feature = "canister")]
+pub mod migrations;
+#[cfg(feature = "canister")]
+pub mod privacy;
+#[cfg(feature = "canister")]
+pub mod fee_governance;
+#[cfg(feature = "canister")]
+pub mod trace;
+#[cfg(feature = "canister")]
+pub mod reconciliation;
+#[cfg(feature = "canister")]
+pub mod genesis;
+#[cfg(feature = "canister")]
+pub mod api_access;
+#[cfg(feature = "canister")]
+pub mod finality;
+#[cfg(feature = "canister")]
+pub mod context;
+#[cfg(feature = "canister")]
+pub mod maintenance;
+#[cfg(feature = "canister")]
+pub mod costs;
+#[cfg(feature = "canister")]
+pub mod redemption;
+#[cfg(feature = "canister")]
+pub mod memo;
+#[cfg(feature = "canister")]
+pub mod failures;
+#[cfg(feature = "canister")]
+pub mod localization;
+#[cfg(feature = "canister")]
+pub mod reservations;
+#[cfg(feature = "canister")]
+pub mod activity;
+#[cfg(feature = "canister")]
+pub mod import;
+#[cfg(feature = "canister")]
+pub mod permissions;
+#[cfg(feature = "canister")]
+pub mod snapshots;
+#[cfg(feature = "canister")]
+pub mod airdrops;
+#[cfg(feature = "canister")]
+pub mod script;
+#[cfg(feature = "canister")]
+pub mod sponsorship;
+#[cfg(feature = "canister")]
+pub mod amounts;
+#[cfg(feature = "canister")]
+pub mod compaction;
+#[cfg(feature = "canister")]
+pub mod statements;
+#[cfg(feature = "notifications")]
+pub mod notifications;
+#[cfg(feature = "icrc3")]
+pub mod icrc3;
+#[cfg(feature = "relay")]
+pub mod relay;
+#[cfg(feature = "dev")]
+pub mod dev;
+
+#[cfg(all(test, feature = "canister"))]
+mod test_utils;
+
 
-use ic_cdk;
+pub use types::{Account, AccountKey, AdminAuditEntry, Airdrop, BalanceSnapshot, ApiAccessGrant, ApiPermissions, AdminExpiredAllowance, DeprecationMode, IncomingPolicy, IncomingPolicyConfig, Memo, OfficialAccount, OpKind, PendingFeeChange, SettlementRecord, StoredTokenMetadata, Reservation, Sponsorship, StructureId, Subaccount, TokenId, TokenImport, TokenLocalization};
+pub use rounding::RoundingMode;
+pub use proof::*;
+pub use verification::*;
 
-pub use types::{Account, TokenId};
+#[cfg(feature = "canister")]
 pub use queries::*;
+#[cfg(feature = "canister")]
 pub use operations::*;
+#[cfg(feature = "canister")]
 pub use allowances::*;
+#[cfg(feature = "canister")]
+pub use accounts::*;
+#[cfg(feature = "canister")]
+pub use recovery::*;
+#[cfg(feature = "canister")]
+pub use netting::*;
+#[cfg(feature = "canister")]
+pub use migrations::*;
+#[cfg(feature = "canister")]
+pub use privacy::*;
+#[cfg(feature = "canister")]
+pub use fee_governance::*;
+#[cfg(feature = "canister")]
+pub use trace::*;
+#[cfg(feature = "canister")]
+pub use reconciliation::*;
+#[cfg(feature = "canister")]
+pub use genesis::*;
+#[cfg(feature = "canister")]
+pub use api_access::*;
+#[cfg(feature = "canister")]
+pub use finality::*;
+#[cfg(feature = "canister")]
+pub use maintenance::*;
+#[cfg(feature = "canister")]
+pub use costs::*;
+#[cfg(feature = "canister")]
+pub use redemption::*;
+#[cfg(feature = "canister")]
+pub use memo::*;
+#[cfg(feature = "canister")]
+pub use failures::*;
+#[cfg(feature = "canister")]
+pub use localization::*;
+#[cfg(feature = "canister")]
+pub use reservations::*;
+#[cfg(feature = "canister")]
+pub use activity::*;
+#[cfg(feature = "canister")]
+pub use import::*;
+#[cfg(feature = "canister")]
+pub use permissions::*;
+#[cfg(feature = "canister")]
+pub use snapshots::*;
+#[cfg(feature = "canister")]
+pub use airdrops::*;
+#[cfg(feature = "canister")]
+pub use script::*;
+#[cfg(feature = "canister")]
+pub use sponsorship::*;
+#[cfg(feature = "canister")]
+pub use amounts::*;
+#[cfg(feature = "canister")]
+pub use compaction::*;
+#[cfg(feature = "canister")]
+pub use statements::*;
+#[cfg(feature = "notifications")]
+pub use notifications::*;
+#[cfg(feature = "icrc3")]
+pub use icrc3::*;
+#[cfg(feature = "relay")]
+pub use relay::*;
+#[cfg(feature = "dev")]
+pub use dev::*;
+
+// Traps on invalid arguments, so a failed setup leaves nothing installed.
+#[cfg(feature = "canister")]
+fn init_ledger(args: Option<InitArgs>) {
+    state::warm_up();
+    state::start_certified_balances();
+    genesis::apply_init_args(runtime::caller(), args).unwrap_or_else(|e| ic_cdk::trap(&e));
+    migrations::mark_all_complete();
+    start_timers();
+    if let Some(controller) = state::get_controller() {
+        ic_cdk::println!("ICRC-151 canister initialized with controller: {}", controller);
+    }
+}
+
+// Timers do not survive upgrades, so post_upgrade calls this again.
+#[cfg(feature = "canister")]
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(
+        std::time::Duration::from_secs(maintenance::MAINTENANCE_TICK_INTERVAL_SECS),
+        maintenance::maintenance_tick,
+    );
+    #[cfg(feature = "notifications")]
+    ic_cdk_timers::set_timer_interval(
+        std::time::Duration::from_secs(notifications::EXPIRY_SWEEP_INTERVAL_SECS),
+        notifications::sweep_allowance_expiry,
+    );
+}
+
+#[cfg(all(feature = "canister", not(feature = "dev")))]
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    init_ledger(args);
+}
 
+// Dev builds wrap the ledger arguments; the faucet only works when they say non_production.
+#[cfg(feature = "dev")]
 #[ic_cdk::init]
-fn init() {
-    let controller = ic_cdk::caller();
-    state::init_state(controller);
-    ic_cdk::println!("ICRC-151 canister initialized with controller: {}", controller);
+fn init(args: Option<DevInitArgs>) {
+    init_ledger(args.as_ref().and_then(|a| a.ledger.clone()));
+    if args.is_some_and(|a| a.non_production) {
+        state::set_non_production();
+        ic_cdk::println!("Ledger flagged as non-production; dev_faucet is enabled");
+    }
 }
 
+#[cfg(feature = "canister")]
 #[ic_cdk::pre_upgrade]
 fn pre_upgrade() {
     let tx_count = state::get_transaction_count();
@@ -31,8 +222,29 @@ fn pre_upgrade() {
     }
 }
 
+#[cfg(all(feature = "canister", not(feature = "dev")))]
 #[ic_cdk::post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<InitArgs>) {
+    upgrade_ledger(args);
+}
+
+#[cfg(feature = "dev")]
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<DevInitArgs>) {
+    upgrade_ledger(args.and_then(|a| a.ledger));
+}
+
+// Traps on invalid arguments, which rejects the upgrade.
+#[cfg(feature = "canister")]
+fn upgrade_ledger(args: Option<InitArgs>) {
+    state::warm_up();
+    // The certified balance tree lives on the heap and is refilled by the
+    // certified_balances maintenance task. Until then only the tip is certified.
+    state::certify();
+    if let Some(args) = args {
+        genesis::apply_upgrade_args(args).unwrap_or_else(|e| ic_cdk::trap(&e));
+    }
+    start_timers();
     let tx_count = state::get_transaction_count();
     let controller = state::get_controller();
 
@@ -42,4 +254,5 @@ fn post_upgrade() {
     }
 }
 
+#[cfg(feature = "canister")]
 ic_cdk::export_candid!();
\ No newline at end of file
@@ -0,0 +1,211 @@
+use crate::allowances::hex_encode;
+use crate::queries::{get_token_metadata, QueryError, TokenMetadata};
+use crate::state;
+use crate::types::{TokenId, TokenLocalization};
+#[cfg(feature = "notifications")]
+use crate::notifications::{publish_metadata_change, MetadataChangeKind};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// Translated names and descriptions for wallets that show tokens in the
+// user's language. Locales are a language (`fr`) or a language and region
+// (`fr-CA`); anything richer is not a locale this ledger stores.
+
+
+pub const MAX_LOCALIZED_NAME_LEN: usize = 255;
+pub const MAX_LOCALIZED_DESCRIPTION_LEN: usize = 1024;
+// Further preferences are ignored.
+pub const MAX_REQUESTED_LOCALES: usize = 10;
+
+
+// The token's metadata with name and description taken from the best
+// matching locale. `locale` is None when none matched and the defaults show.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LocalizedTokenMetadata {
+    pub locale: Option<String>,
+    pub metadata: TokenMetadata,
+}
+
+
+// "fr" or "fr-CA", case-insensitively; returned as lowercase language and
+// uppercase region.
+fn normalize_locale(locale: &str) -> Option<String> {
+    let (language, region) = match locale.split_once(['-', '_']) {
+        Some((language, region)) => (language, Some(region)),
+        None => (locale, None),
+    };
+    let is_code = |part: &str| part.len() == 2 && part.bytes().all(|b| b.is_ascii_alphabetic());
+    if !is_code(language) || !region.is_none_or(is_code) {
+        return None;
+    }
+    let language = language.to_ascii_lowercase();
+    Some(match region {
+        Some(region) => format!("{}-{}", language, region.to_ascii_uppercase()),
+        None => language,
+    })
+}
+
+
+fn validate_localized_text(name: &str, description: Option<&str>) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_LOCALIZED_NAME_LEN {
+        return Err(format!("Localized name must be 1-{} bytes", MAX_LOCALIZED_NAME_LEN));
+    }
+    if description.is_some_and(|d| d.len() > MAX_LOCALIZED_DESCRIPTION_LEN) {
+        return Err(format!("Localized description exceeds {} bytes", MAX_LOCALIZED_DESCRIPTION_LEN));
+    }
+    Ok(())
+}
+
+
+// Setting a locale the token already has replaces it. Frozen tokens refuse
+// changes, as they do for the metadata the translations stand in for.
+#[ic_cdk::update]
+pub fn set_token_localization(
+    token_id: TokenId,
+    locale: String,
+    name: String,
+    description: Option<String>,
+) -> Result<(), String> {
+    state::require_controller()?;
+    if state::get_token_metadata(token_id).map_err(|e| e.to_string())?.is_immutable() {
+        return Err(state::MetadataError::TokenImmutable.to_string());
+    }
+    let locale = normalize_locale(&locale).ok_or("Locale must look like \"xx\" or \"xx-XX\"")?;
+    validate_localized_text(&name, description.as_deref())?;
+
+    state::set_token_localization(token_id, TokenLocalization { locale: locale.clone(), name, description })?;
+    state::record_admin_action("set_token_localization", format!("{}: {}", hex_encode(&token_id), locale));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::LocalizationChanged);
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn remove_token_localization(token_id: TokenId, locale: String) -> Result<(), String> {
+    state::require_controller()?;
+    if state::get_token_metadata(token_id).map_err(|e| e.to_string())?.is_immutable() {
+        return Err(state::MetadataError::TokenImmutable.to_string());
+    }
+    let locale = normalize_locale(&locale).ok_or("Locale must look like \"xx\" or \"xx-XX\"")?;
+    state::remove_token_localization(token_id, &locale).ok_or("No localization for this locale")?;
+    state::record_admin_action("remove_token_localization", format!("{}: {}", hex_encode(&token_id), locale));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::LocalizationChanged);
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn list_token_localizations(token_id: TokenId) -> Vec<TokenLocalization> {
+    state::list_token_localizations(token_id)
+}
+
+
+// Preferences are tried in order, each exactly and then by its language alone,
+// so ["fr-CA", "en"] prefers `fr-CA`, then `fr`, then `en`. Malformed entries
+// are skipped rather than failing the lookup.
+#[ic_cdk::query]
+pub fn get_token_metadata_localized(token_id: TokenId, locales: Vec<String>) -> Result<LocalizedTokenMetadata, QueryError> {
+    let mut metadata = get_token_metadata(token_id)?;
+    let found = locales.iter()
+        .take(MAX_REQUESTED_LOCALES)
+        .filter_map(|locale| normalize_locale(locale))
+        .find_map(|locale| {
+            let language = locale.split('-').next().unwrap_or(&locale);
+            state::get_token_localization(token_id, &locale)
+                .or_else(|| state::get_token_localization(token_id, language))
+        });
+
+    let Some(localization) = found else {
+        return Ok(LocalizedTokenMetadata { locale: None, metadata });
+    };
+    metadata.name = localization.name;
+    if localization.description.is_some() {
+        metadata.description = localization.description;
+    }
+    Ok(LocalizedTokenMetadata { locale: Some(localization.locale), metadata })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::freeze_token_config;
+    use crate::runtime;
+    use crate::test_utils::{register_test_token, test_account};
+    use crate::types::constants;
+
+    fn setup() -> TokenId {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        register_test_token(1, 0)
+    }
+
+    fn localized(token: TokenId, locales: &[&str]) -> (Option<String>, String) {
+        let result = get_token_metadata_localized(token, locales.iter().map(|l| l.to_string()).collect()).unwrap();
+        (result.locale, result.metadata.name)
+    }
+
+    #[test]
+    fn test_localized_metadata_fallback_order() {
+        let token = setup();
+        let default_name = get_token_metadata(token).unwrap().name;
+        set_token_localization(token, "fr".to_string(), "Jeton".to_string(), Some("Un jeton".to_string())).unwrap();
+        set_token_localization(token, "fr-ca".to_string(), "Jeton QC".to_string(), None).unwrap();
+        set_token_localization(token, "de".to_string(), "Münze".to_string(), None).unwrap();
+
+        assert_eq!(localized(token, &["fr-CA", "de"]), (Some("fr-CA".to_string()), "Jeton QC".to_string()));
+        assert_eq!(localized(token, &["fr-BE", "de"]), (Some("fr".to_string()), "Jeton".to_string()));
+        assert_eq!(localized(token, &["es", "not a locale", "de-AT"]), (Some("de".to_string()), "Münze".to_string()));
+        assert_eq!(localized(token, &["es", "it"]), (None, default_name.clone()));
+        assert_eq!(localized(token, &[]), (None, default_name));
+
+        // A translation without a description keeps the default one.
+        let default_description = get_token_metadata(token).unwrap().description;
+        let fr_ca = get_token_metadata_localized(token, vec!["fr_CA".to_string()]).unwrap();
+        assert_eq!(fr_ca.metadata.description, default_description);
+        let fr = get_token_metadata_localized(token, vec!["fr".to_string()]).unwrap();
+        assert_eq!(fr.metadata.description.as_deref(), Some("Un jeton"));
+
+        remove_token_localization(token, "fr-CA".to_string()).unwrap();
+        assert_eq!(localized(token, &["fr-CA"]).0.as_deref(), Some("fr"));
+        assert!(remove_token_localization(token, "fr-CA".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_localization_limits() {
+        let token = setup();
+        for bad in ["", "f", "fra", "fr-", "fr-CAN", "12", "fr-C1", "fr-CA-x"] {
+            assert!(set_token_localization(token, bad.to_string(), "x".to_string(), None).is_err(), "{}", bad);
+        }
+        assert!(set_token_localization(token, "en".to_string(), String::new(), None).is_err());
+        assert!(set_token_localization(token, "en".to_string(), "x".repeat(MAX_LOCALIZED_NAME_LEN + 1), None).is_err());
+        assert!(set_token_localization(token, "en".to_string(), "x".to_string(), Some("x".repeat(MAX_LOCALIZED_DESCRIPTION_LEN + 1))).is_err());
+
+        let locales: Vec<String> = (0..constants::MAX_TOKEN_LOCALES).map(|i| format!("a{}", (b'a' + i as u8) as char)).collect();
+        for locale in &locales {
+            set_token_localization(token, locale.clone(), "x".to_string(), None).unwrap();
+        }
+        assert!(set_token_localization(token, "zz".to_string(), "x".to_string(), None).is_err());
+        // Replacing an existing locale is not a new one.
+        set_token_localization(token, locales[0].clone(), "y".to_string(), None).unwrap();
+        assert_eq!(list_token_localizations(token).len(), constants::MAX_TOKEN_LOCALES);
+        // Other tokens have caps of their own.
+        let other = register_test_token(2, 0);
+        set_token_localization(other, "zz".to_string(), "x".to_string(), None).unwrap();
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(set_token_localization(other, "en".to_string(), "x".to_string(), None).is_err());
+        assert!(remove_token_localization(other, "zz".to_string()).is_err());
+
+        runtime::set_caller(test_account(0xFE).owner);
+        freeze_token_config(other).unwrap();
+        assert!(set_token_localization(other, "en".to_string(), "x".to_string(), None).is_err());
+        assert!(remove_token_localization(other, "zz".to_string()).is_err());
+    }
+}
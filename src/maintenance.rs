@@ -0,0 +1,447 @@
+use crate::types::StoredMaintenanceTask;
+use crate::state;
+use crate::runtime;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// Background cleanup runs on one timer. Each tick hands the registered tasks
+// one item at a time, in turn, until the shared instruction budget is spent,
+// so a long backlog is worked down over several ticks without any task
+// starving the others. Cursors and counters live in SYSTEM_STATE and survive
+// upgrades; the timer itself is restarted by post_upgrade.
+
+
+pub const MAINTENANCE_TICK_INTERVAL_SECS: u64 = 60;
+pub const MAINTENANCE_TICK_BUDGET: u64 = 2_000_000_000;
+
+pub const DEDUP_PRUNING: &str = "dedup_pruning";
+pub const ALLOWANCE_SWEEP: &str = "allowance_sweep";
+pub const RESERVATION_SWEEP: &str = "reservation_sweep";
+pub const SPONSORSHIP_SWEEP: &str = "sponsorship_sweep";
+pub const CERTIFIED_BALANCES: &str = "certified_balances";
+
+// Dedup entries outlive the widest transaction window by this much, so
+// widening the window again does not readmit a just-pruned transaction.
+pub const DEDUP_RETENTION_MARGIN_NS: u64 = 24 * 3_600 * 1_000_000_000;
+// How long an expired allowance stays readable, and reported as expired by
+// transfer_from, before the sweep clears it.
+pub const ALLOWANCE_SWEEP_GRACE_NS: u64 = 24 * 3_600 * 1_000_000_000;
+
+// Backlog estimates stop counting here.
+const BACKLOG_COUNT_LIMIT: usize = 10_000;
+
+
+enum Step {
+    // Nothing left; the cursor starts over next time.
+    CaughtUp,
+    // One item handled; the cursor to resume after.
+    Item(Option<Vec<u8>>),
+}
+
+
+struct Task {
+    // At most 21 bytes, the room left in a SYSTEM_STATE key.
+    name: &'static str,
+    // Estimated instructions per item, charged against the tick budget.
+    item_cost: u64,
+    step: fn(Option<&[u8]>, u64) -> Step,
+    // Items waiting, as far as it is cheap to tell.
+    backlog: fn(u64) -> u64,
+}
+
+
+const TASKS: &[Task] = &[
+    Task {
+        name: DEDUP_PRUNING,
+        item_cost: 200_000,
+        step: prune_dedup,
+        backlog: dedup_backlog,
+    },
+    Task {
+        name: ALLOWANCE_SWEEP,
+        item_cost: 1_000_000,
+        step: sweep_allowances,
+        backlog: allowance_backlog,
+    },
+    Task {
+        name: RESERVATION_SWEEP,
+        item_cost: 1_000_000,
+        step: sweep_reservations,
+        backlog: reservation_backlog,
+    },
+    Task {
+        name: SPONSORSHIP_SWEEP,
+        item_cost: 1_000_000,
+        step: sweep_sponsorships,
+        backlog: sponsorship_backlog,
+    },
+    Task {
+        name: CERTIFIED_BALANCES,
+        item_cost: 300_000,
+        step: refill_certified_balances,
+        backlog: certified_balances_backlog,
+    },
+];
+
+
+// Walks the dedup map in key order. An entry is stale once its transaction is
+// too old to be resubmitted under any window the ledger has used.
+fn prune_dedup(cursor: Option<&[u8]>, now: u64) -> Step {
+    let start_after = cursor.and_then(|c| <[u8; 32]>::try_from(c).ok());
+    let stale_before = now
+        .saturating_sub(state::widest_tx_window())
+        .saturating_sub(DEDUP_RETENTION_MARGIN_NS);
+    match state::prune_dedup_entry(start_after, stale_before) {
+        Some(key) => Step::Item(Some(key.to_vec())),
+        None => Step::CaughtUp,
+    }
+}
+
+
+// A full pass looks at every entry.
+fn dedup_backlog(_now: u64) -> u64 {
+    state::get_dedup_map_size()
+}
+
+
+// The expiry queue is ordered by time, so the oldest expiry is always first
+// and no cursor is needed.
+fn sweep_allowances(_cursor: Option<&[u8]>, now: u64) -> Step {
+    match state::sweep_expired_allowance(now.saturating_sub(ALLOWANCE_SWEEP_GRACE_NS)) {
+        true => Step::Item(None),
+        false => Step::CaughtUp,
+    }
+}
+
+
+fn allowance_backlog(now: u64) -> u64 {
+    state::count_expired_allowances(now.saturating_sub(ALLOWANCE_SWEEP_GRACE_NS), BACKLOG_COUNT_LIMIT)
+}
+
+
+// Expired holds already count for nothing; this only frees their storage.
+fn sweep_reservations(_cursor: Option<&[u8]>, now: u64) -> Step {
+    match state::sweep_expired_reservation(now) {
+        true => Step::Item(None),
+        false => Step::CaughtUp,
+    }
+}
+
+
+fn reservation_backlog(now: u64) -> u64 {
+    state::count_expired_reservations(now, BACKLOG_COUNT_LIMIT)
+}
+
+
+// Expired sponsorships can no longer pay fees, but their unspent budget stays
+// held from the sponsor until this returns it.
+fn sweep_sponsorships(_cursor: Option<&[u8]>, now: u64) -> Step {
+    match state::sweep_expired_sponsorship(now) {
+        true => Step::Item(None),
+        false => Step::CaughtUp,
+    }
+}
+
+
+fn sponsorship_backlog(now: u64) -> u64 {
+    state::count_expired_sponsorships(now, BACKLOG_COUNT_LIMIT)
+}
+
+
+// Refills the heap tree behind the certified data after an upgrade. The tree
+// keeps its own position: a stored cursor would outlive the upgrade that
+// emptied it.
+fn refill_certified_balances(_cursor: Option<&[u8]>, _now: u64) -> Step {
+    match state::refill_certified_balances() {
+        true => Step::Item(None),
+        false => Step::CaughtUp,
+    }
+}
+
+
+fn certified_balances_backlog(_now: u64) -> u64 {
+    state::count_certified_balances_pending(BACKLOG_COUNT_LIMIT)
+}
+
+
+struct Budget {
+    limit: u64,
+    start: u64,
+    charged: u64,
+}
+
+impl Budget {
+    fn new(limit: u64) -> Self {
+        Budget { limit, start: runtime::instruction_counter(), charged: 0 }
+    }
+
+    // The larger of what was measured and what was estimated.
+    fn used(&self) -> u64 {
+        self.charged.max(runtime::instruction_counter().saturating_sub(self.start))
+    }
+
+    fn try_charge(&mut self, cost: u64) -> bool {
+        let charged = self.used().saturating_add(cost);
+        if charged > self.limit {
+            return false;
+        }
+        self.charged = charged;
+        true
+    }
+}
+
+
+struct Running {
+    task: &'static Task,
+    state: StoredMaintenanceTask,
+    items: u64,
+}
+
+
+// Gives each task one item per round until it catches up or its next item no
+// longer fits the budget.
+fn work(tasks: Vec<&'static Task>, budget: &mut Budget, now: u64) {
+    let mut running: Vec<Running> = tasks
+        .into_iter()
+        .map(|task| Running { task, state: state::get_maintenance_task(task.name), items: 0 })
+        .collect();
+
+    while !running.is_empty() {
+        running.retain_mut(|r| {
+            let more = budget.try_charge(r.task.item_cost) && match (r.task.step)(r.state.cursor.as_deref(), now) {
+                Step::CaughtUp => {
+                    r.state.cursor = None;
+                    false
+                }
+                Step::Item(cursor) => {
+                    r.state.cursor = cursor;
+                    r.items += 1;
+                    true
+                }
+            };
+            if !more {
+                r.state.last_run = Some(now);
+                r.state.last_run_items = r.items;
+                r.state.items_processed = r.state.items_processed.saturating_add(r.items);
+                state::set_maintenance_task(r.task.name, &r.state);
+            }
+            more
+        });
+    }
+}
+
+
+// Paused tasks sit out. The task that goes first rotates from tick to tick.
+pub(crate) fn run_tick(limit: u64) {
+    let now = runtime::time();
+    let mut tick = state::get_maintenance_tick();
+    let first = tick.next_task as usize % TASKS.len();
+    let tasks = (0..TASKS.len())
+        .map(|offset| &TASKS[(first + offset) % TASKS.len()])
+        .filter(|task| !state::get_maintenance_task(task.name).paused)
+        .collect();
+
+    let mut budget = Budget::new(limit);
+    work(tasks, &mut budget, now);
+
+    tick.last_tick_at = Some(now);
+    tick.last_tick_instructions = budget.used();
+    tick.next_task = ((first + 1) % TASKS.len()) as u32;
+    state::set_maintenance_tick(&tick);
+}
+
+
+// Timer job.
+pub fn maintenance_tick() {
+    run_tick(MAINTENANCE_TICK_BUDGET);
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceTaskStatus {
+    pub name: String,
+    pub paused: bool,
+    pub last_run: Option<u64>,
+    pub last_run_items: u64,
+    pub items_processed: u64,
+    pub backlog: u64,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceStatus {
+    pub tick_interval_secs: u64,
+    pub instruction_budget: u64,
+    pub last_tick_at: Option<u64>,
+    pub last_tick_instructions: u64,
+    pub tasks: Vec<MaintenanceTaskStatus>,
+}
+
+
+fn task_status(task: &Task, now: u64) -> MaintenanceTaskStatus {
+    let stored = state::get_maintenance_task(task.name);
+    MaintenanceTaskStatus {
+        name: task.name.to_string(),
+        paused: stored.paused,
+        last_run: stored.last_run,
+        last_run_items: stored.last_run_items,
+        items_processed: stored.items_processed,
+        backlog: (task.backlog)(now),
+    }
+}
+
+
+fn find_task(name: &str) -> Result<&'static Task, String> {
+    TASKS.iter().find(|t| t.name == name).ok_or_else(|| format!("Unknown maintenance task: {}", name))
+}
+
+
+#[ic_cdk::query]
+pub fn get_maintenance_status() -> MaintenanceStatus {
+    let now = runtime::time();
+    let tick = state::get_maintenance_tick();
+    MaintenanceStatus {
+        tick_interval_secs: MAINTENANCE_TICK_INTERVAL_SECS,
+        instruction_budget: MAINTENANCE_TICK_BUDGET,
+        last_tick_at: tick.last_tick_at,
+        last_tick_instructions: tick.last_tick_instructions,
+        tasks: TASKS.iter().map(|task| task_status(task, now)).collect(),
+    }
+}
+
+
+// A paused task keeps its cursor and resumes where it stopped.
+#[ic_cdk::update]
+pub fn set_maintenance_task_paused(name: String, paused: bool) -> Result<(), String> {
+    state::require_controller()?;
+
+    let task = find_task(&name)?;
+    let mut stored = state::get_maintenance_task(task.name);
+    let previous = stored.paused;
+    stored.paused = paused;
+    state::set_maintenance_task(task.name, &stored);
+    state::record_admin_action("set_maintenance_task_paused", format!("{}: {} -> {}", task.name, previous, paused));
+    Ok(())
+}
+
+
+// Runs one task now with a whole tick's budget, paused or not. The regular
+// tick is unaffected.
+#[ic_cdk::update]
+pub fn run_maintenance_task(name: String) -> Result<MaintenanceTaskStatus, String> {
+    state::require_controller()?;
+
+    let task = find_task(&name)?;
+    let now = runtime::time();
+    work(vec![task], &mut Budget::new(MAINTENANCE_TICK_BUDGET), now);
+    Ok(task_status(task, now))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{mint_internal, transfer_internal, TransferError};
+    use crate::test_utils::{register_test_token, test_account};
+
+    const HOUR: u64 = 3_600 * 1_000_000_000;
+
+    fn last_tick_instructions() -> u64 {
+        state::get_maintenance_tick().last_tick_instructions
+    }
+
+    #[test]
+    fn test_backlog_is_worked_down_within_budget() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        let (owner, spender) = (test_account(1), test_account(2));
+        assert!(TASKS.iter().all(|t| t.name.len() <= 21));
+
+        // 30 dedup entries and 12 allowances that expire in an hour.
+        mint_internal(token, owner.clone(), 1_000, None, None).unwrap();
+        for _ in 0..29 {
+            runtime::advance_time(1);
+            transfer_internal(token, owner.clone(), test_account(3), 1, None, None, None).unwrap();
+        }
+        let expires_at = runtime::time() + HOUR;
+        for n in 0..12u8 {
+            let spender_key = test_account(10 + n).to_key();
+            state::set_allowance(token, owner.to_key(), spender_key, 100);
+            state::set_allowance_expiry(token, owner.to_key(), spender_key, expires_at);
+        }
+        state::set_allowance(token, owner.to_key(), spender.to_key(), 100);
+
+        // Nothing is stale yet: a tick walks the dedup map and removes nothing.
+        run_tick(MAINTENANCE_TICK_BUDGET);
+        assert_eq!(state::get_dedup_map_size(), 30);
+        let status = get_maintenance_status();
+        assert_eq!((status.tasks[0].last_run_items, status.tasks[0].backlog), (30, 30));
+        assert_eq!((status.tasks[1].last_run_items, status.tasks[1].backlog), (0, 0));
+
+        // A day past both cutoffs everything is due. Three dedup items and one
+        // allowance fit a tick, so the backlog takes several.
+        runtime::advance_time(HOUR + ALLOWANCE_SWEEP_GRACE_NS + DEDUP_RETENTION_MARGIN_NS);
+        assert_eq!(get_maintenance_status().tasks[1].backlog, 12);
+        let budget = 3 * TASKS[0].item_cost + TASKS[1].item_cost;
+        let mut ticks = 0;
+        while state::get_dedup_map_size() > 0 || get_maintenance_status().tasks[1].backlog > 0 {
+            run_tick(budget);
+            ticks += 1;
+            assert!(last_tick_instructions() <= budget);
+            assert!(ticks < 100);
+        }
+        assert!(ticks >= 10);
+        let status = get_maintenance_status();
+        assert!(status.tasks[0].items_processed >= 60);
+        assert_eq!(status.tasks[1].items_processed, 12);
+        assert_eq!(state::get_allowance(token, owner.to_key(), test_account(10).to_key()), 0);
+        assert_eq!(state::get_allowance_expiry(token, owner.to_key(), test_account(10).to_key()), None);
+        // Allowances without an expiry are left alone.
+        assert_eq!(state::get_allowance(token, owner.to_key(), spender.to_key()), 100);
+
+        // A live dedup entry survives and still blocks its duplicate.
+        let now = runtime::time();
+        let tx_index = transfer_internal(token, owner.clone(), test_account(3), 1, None, None, Some(now)).unwrap();
+        run_tick(MAINTENANCE_TICK_BUDGET);
+        assert!(matches!(
+            transfer_internal(token, owner, test_account(3), 1, None, None, Some(now)),
+            Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == tx_index
+        ));
+        assert!(last_tick_instructions() <= MAINTENANCE_TICK_BUDGET);
+    }
+
+    #[test]
+    fn test_paused_tasks_and_manual_runs() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        runtime::set_caller(controller);
+        mint_internal(token, test_account(1), 10, None, None).unwrap();
+        runtime::advance_time(state::widest_tx_window() + DEDUP_RETENTION_MARGIN_NS + 1);
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(set_maintenance_task_paused(DEDUP_PRUNING.to_string(), true).is_err());
+        assert!(run_maintenance_task(DEDUP_PRUNING.to_string()).is_err());
+
+        runtime::set_caller(controller);
+        assert!(set_maintenance_task_paused("unknown".to_string(), true).is_err());
+        set_maintenance_task_paused(DEDUP_PRUNING.to_string(), true).unwrap();
+        run_tick(MAINTENANCE_TICK_BUDGET);
+        let status = get_maintenance_status();
+        assert!(status.tasks[0].paused && status.tasks[0].last_run.is_none());
+        assert_eq!(status.tasks[1].last_run, Some(runtime::time()));
+        assert_eq!(state::get_dedup_map_size(), 1);
+
+        // A manual run goes through even while the task is paused.
+        let ran = run_maintenance_task(DEDUP_PRUNING.to_string()).unwrap();
+        assert_eq!((ran.paused, ran.last_run_items, ran.items_processed, ran.backlog), (true, 1, 1, 0));
+        assert_eq!(state::get_dedup_map_size(), 0);
+
+        set_maintenance_task_paused(DEDUP_PRUNING.to_string(), false).unwrap();
+        let actions: Vec<_> = crate::queries::get_admin_audit_log(0, 10).into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, ["set_maintenance_task_paused"; 2]);
+    }
+}
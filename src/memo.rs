@@ -0,0 +1,231 @@
+use crate::types::StoredTokenMetadata;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// An optional convention for memos that carry an order or invoice reference,
+// so merchants stop packing `order_id|customer|amount` into free text. The
+// layout is
+//
+//   magic (4) ++ reference length (1) ++ reference ++ note (UTF-8) ++ kind (1)
+//
+// The magic starts with 0xFF, which no UTF-8 text does, and the kind code is
+// last and never zero, so the zero-padding trimmed from inline memos cannot
+// eat into the encoding. Any memo without the magic is an ordinary memo.
+
+
+pub const STRUCTURED_MEMO_MAGIC: [u8; 4] = [0xFF, b'S', b'M', 0x01];
+pub const MAX_MEMO_REFERENCE_LEN: usize = 64;
+pub const MAX_MEMO_NOTE_LEN: usize = 256;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoKind {
+    Order,
+    Invoice,
+    Refund,
+    Payout,
+    Other,
+}
+
+
+impl MemoKind {
+    // Wire codes; append new kinds, never renumber.
+    fn code(self) -> u8 {
+        match self {
+            MemoKind::Order => 1,
+            MemoKind::Invoice => 2,
+            MemoKind::Refund => 3,
+            MemoKind::Payout => 4,
+            MemoKind::Other => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(MemoKind::Order),
+            2 => Some(MemoKind::Invoice),
+            3 => Some(MemoKind::Refund),
+            4 => Some(MemoKind::Payout),
+            5 => Some(MemoKind::Other),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StructuredMemo {
+    pub kind: MemoKind,
+    pub reference: Vec<u8>,
+    pub note: String,
+}
+
+
+#[ic_cdk::query]
+pub fn encode_structured_memo(memo: StructuredMemo) -> Result<Vec<u8>, String> {
+    if memo.reference.len() > MAX_MEMO_REFERENCE_LEN {
+        return Err(format!("Memo reference too long (max {} bytes)", MAX_MEMO_REFERENCE_LEN));
+    }
+    if memo.note.len() > MAX_MEMO_NOTE_LEN {
+        return Err(format!("Memo note too long (max {} bytes)", MAX_MEMO_NOTE_LEN));
+    }
+    if memo.note.contains('\0') {
+        return Err("Memo note must not contain null bytes".to_string());
+    }
+
+    let mut bytes = Vec::with_capacity(STRUCTURED_MEMO_MAGIC.len() + 2 + memo.reference.len() + memo.note.len());
+    bytes.extend_from_slice(&STRUCTURED_MEMO_MAGIC);
+    bytes.push(memo.reference.len() as u8);
+    bytes.extend_from_slice(&memo.reference);
+    bytes.extend_from_slice(memo.note.as_bytes());
+    bytes.push(memo.kind.code());
+    Ok(bytes)
+}
+
+
+// None unless the bytes are exactly what encode_structured_memo produces.
+#[ic_cdk::query]
+pub fn decode_structured_memo(bytes: Vec<u8>) -> Option<StructuredMemo> {
+    parse_structured_memo(&bytes)
+}
+
+
+pub(crate) fn parse_structured_memo(bytes: &[u8]) -> Option<StructuredMemo> {
+    let body = bytes.strip_prefix(&STRUCTURED_MEMO_MAGIC[..])?;
+    let (&reference_len, body) = body.split_first()?;
+    let (&code, body) = body.split_last()?;
+    let reference_len = reference_len as usize;
+    if reference_len > MAX_MEMO_REFERENCE_LEN || body.len() < reference_len {
+        return None;
+    }
+    let (reference, note) = body.split_at(reference_len);
+    let note = std::str::from_utf8(note).ok()?;
+    if note.len() > MAX_MEMO_NOTE_LEN || note.contains('\0') {
+        return None;
+    }
+    Some(StructuredMemo { kind: MemoKind::from_code(code)?, reference: reference.to_vec(), note: note.to_string() })
+}
+
+
+// Tokens that require structured memos refuse movements whose memo is
+// missing or does not decode.
+pub(crate) fn validate_structured_memo(metadata: &StoredTokenMetadata, memo: Option<&[u8]>) -> Result<(), String> {
+    if !metadata.structured_memos_required.unwrap_or(false) {
+        return Ok(());
+    }
+    match memo {
+        Some(memo) if parse_structured_memo(memo).is_some() => Ok(()),
+        Some(_) => Err("This token requires a structured memo".to_string()),
+        None => Err("This token requires a structured memo; none was given".to_string()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{mint_tokens, set_token_structured_memos_required, transfer, Icrc151TransferArgs, TransferResult};
+    use crate::queries::{get_transaction_memo, TransactionMemo};
+    use crate::runtime;
+    use crate::state;
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Nat;
+
+    fn order(reference: &[u8], note: &str) -> StructuredMemo {
+        StructuredMemo { kind: MemoKind::Order, reference: reference.to_vec(), note: note.to_string() }
+    }
+
+    #[test]
+    fn test_structured_memo_round_trip() {
+        let kinds = [MemoKind::Order, MemoKind::Invoice, MemoKind::Refund, MemoKind::Payout, MemoKind::Other];
+        for kind in kinds {
+            for (reference, note) in [(&b""[..], ""), (&b"order-42"[..], "customer 7, 3 items"), (&[0u8; 64][..], "é")] {
+                let memo = StructuredMemo { kind, reference: reference.to_vec(), note: note.to_string() };
+                let bytes = encode_structured_memo(memo.clone()).unwrap();
+                assert_eq!(decode_structured_memo(bytes), Some(memo));
+            }
+        }
+
+        // The layout is fixed; this pins it.
+        let bytes = encode_structured_memo(order(b"A1", "hi")).unwrap();
+        assert_eq!(bytes, [0xFF, b'S', b'M', 0x01, 2, b'A', b'1', b'h', b'i', 1]);
+
+        assert!(encode_structured_memo(order(&[1u8; MAX_MEMO_REFERENCE_LEN + 1], "")).is_err());
+        assert!(encode_structured_memo(order(b"", &"x".repeat(MAX_MEMO_NOTE_LEN + 1))).is_err());
+        assert!(encode_structured_memo(order(b"", "a\0b")).is_err());
+        assert_eq!(decode_structured_memo(b"order-42|customer|100".to_vec()), None);
+    }
+
+    // Decoding arbitrary bytes never panics, and whatever decodes encodes back
+    // to the same bytes, so one memo has one reading.
+    #[test]
+    fn test_structured_memo_decode_fuzz() {
+        let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut decoded = 0;
+        for _ in 0..20_000 {
+            let len = (next() % 80) as usize;
+            let mut bytes: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Most inputs should get past the magic, or the fuzz tests little.
+            if next() % 4 != 0 {
+                bytes.splice(0..0, STRUCTURED_MEMO_MAGIC);
+                if bytes.len() > 4 {
+                    bytes[4] %= 16;
+                }
+                if let Some(last) = bytes.last_mut() {
+                    *last %= 7;
+                }
+            }
+            if let Some(memo) = decode_structured_memo(bytes.clone()) {
+                decoded += 1;
+                assert_eq!(encode_structured_memo(memo).unwrap(), bytes);
+            }
+        }
+        assert!(decoded > 100, "only {} inputs decoded", decoded);
+    }
+
+    #[test]
+    fn test_structured_memos_required() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        mint_tokens(token, test_account(1), Nat::from(1_000u64), None).unwrap();
+
+        let send = |memo: Option<Vec<u8>>| {
+            runtime::advance_time(1);
+            transfer(Icrc151TransferArgs {
+                token_id: token, from_subaccount: None, to: test_account(2), amount: Nat::from(1u64),
+                fee: None, memo: memo.map(crate::types::Memo), created_at_time: None, private_memo: None,
+                amount_mode: None, use_sponsorship: None, args_version: None,
+            })
+        };
+        let structured = encode_structured_memo(order(b"order-42", "")).unwrap();
+
+        runtime::set_caller(test_account(1).owner);
+        let TransferResult::Ok(plain) = send(Some(b"order-42|7|100".to_vec())) else { panic!("plain memo refused") };
+        assert!(set_token_structured_memos_required(token, true).is_err());
+
+        runtime::set_caller(controller);
+        set_token_structured_memos_required(token, true).unwrap();
+        assert!(crate::queries::get_token_metadata(token).unwrap().structured_memos_required);
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(matches!(send(Some(b"order-42|7|100".to_vec())), TransferResult::Err(_)));
+        assert!(matches!(send(None), TransferResult::Err(_)));
+        let TransferResult::Ok(parsed) = send(Some(structured.clone())) else { panic!("structured memo refused") };
+
+        runtime::set_caller(controller);
+        assert_eq!(get_transaction_memo(plain).unwrap(), TransactionMemo::Memo(b"order-42|7|100".to_vec()));
+        assert_eq!(
+            get_transaction_memo(parsed).unwrap(),
+            TransactionMemo::Structured { raw: structured, memo: order(b"order-42", "") },
+        );
+    }
+}
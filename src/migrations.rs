@@ -0,0 +1,220 @@
+use crate::types::StoredMigrationProgress;
+use crate::state;
+use crate::runtime;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+pub const MAX_MIGRATION_BATCH: u64 = 10_000;
+
+pub const ACCOUNT_TOKENS_INDEX: &str = "account_tokens_index";
+pub const BLOCK_HASHES: &str = "block_hashes";
+pub const ALLOWANCE_EXPIRY_QUEUE: &str = "expiry_queue";
+
+
+// (items processed, cursor after the last one)
+type StepResult = Result<(u64, Option<Vec<u8>>), String>;
+
+
+struct Migration {
+    // At most 20 bytes, the room left in a SYSTEM_STATE key.
+    name: &'static str,
+    // Endpoints that refuse to run until the migration completes.
+    blocks: &'static [&'static str],
+    // Processes up to `max_items` after the cursor; returns the count and the new cursor.
+    step: fn(Option<&[u8]>, u64) -> StepResult,
+}
+
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: ACCOUNT_TOKENS_INDEX,
+        blocks: &["get_account_tokens"],
+        step: backfill_account_tokens,
+    },
+    Migration {
+        name: BLOCK_HASHES,
+        blocks: &["get_range_commitment"],
+        step: backfill_block_hashes,
+    },
+    Migration {
+        name: ALLOWANCE_EXPIRY_QUEUE,
+        blocks: &["subscribe_allowance_expiry"],
+        step: backfill_allowance_expiry_queue,
+    },
+];
+
+
+fn backfill_account_tokens(cursor: Option<&[u8]>, max_items: u64) -> StepResult {
+    let start_after = cursor
+        .map(|c| <[u8; 64]>::try_from(c).map_err(|_| "Corrupted migration cursor".to_string()))
+        .transpose()?;
+    let (processed, last) = state::backfill_account_tokens(start_after, max_items as usize);
+    Ok((processed, last.map(|key| key.to_vec())))
+}
+
+
+fn backfill_allowance_expiry_queue(cursor: Option<&[u8]>, max_items: u64) -> StepResult {
+    let start_after = cursor
+        .map(|c| <[u8; 96]>::try_from(c).map_err(|_| "Corrupted migration cursor".to_string()))
+        .transpose()?;
+    let (processed, last) = state::backfill_allowance_expiry_queue(start_after, max_items as usize);
+    Ok((processed, last.map(|key| key.to_vec())))
+}
+
+
+// The cursor is the next block to hash. New blocks chain themselves once their
+// parent is hashed, so the migration ends when it reaches the tip.
+fn backfill_block_hashes(cursor: Option<&[u8]>, max_items: u64) -> StepResult {
+    let start = cursor
+        .map(|c| <[u8; 8]>::try_from(c).map(u64::from_be_bytes).map_err(|_| "Corrupted migration cursor".to_string()))
+        .transpose()?
+        .unwrap_or(0);
+    let processed = state::backfill_block_hashes(start, max_items)?;
+    Ok((processed, Some((start + processed).to_be_bytes().to_vec())))
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MigrationStatus {
+    pub name: String,
+    pub blocks: Vec<String>,
+    pub processed: u64,
+    pub complete: bool,
+    pub started_at: Option<u64>,
+    pub completed_at: Option<u64>,
+}
+
+
+fn status(migration: &Migration) -> MigrationStatus {
+    let progress = state::get_migration_progress(migration.name).unwrap_or_default();
+    MigrationStatus {
+        name: migration.name.to_string(),
+        blocks: migration.blocks.iter().map(|e| e.to_string()).collect(),
+        processed: progress.processed,
+        complete: progress.completed_at.is_some(),
+        started_at: progress.started_at,
+        completed_at: progress.completed_at,
+    }
+}
+
+
+pub fn is_complete(name: &str) -> bool {
+    state::get_migration_progress(name).is_some_and(|p| p.completed_at.is_some())
+}
+
+
+// The incomplete migration that blocks `endpoint`, if any.
+pub fn blocking_migration(endpoint: &str) -> Option<&'static str> {
+    MIGRATIONS
+        .iter()
+        .find(|m| m.blocks.contains(&endpoint) && !is_complete(m.name))
+        .map(|m| m.name)
+}
+
+
+// A fresh ledger builds every index as it goes, so there is nothing to migrate.
+pub(crate) fn mark_all_complete() {
+    let now = runtime::time();
+    for migration in MIGRATIONS {
+        state::set_migration_progress(migration.name, &StoredMigrationProgress {
+            cursor: None,
+            processed: 0,
+            started_at: Some(now),
+            completed_at: Some(now),
+        });
+    }
+}
+
+
+// Runs one bounded chunk. Call repeatedly until `complete` is true; progress
+// survives upgrades, so an interrupted migration resumes where it stopped.
+#[ic_cdk::update]
+pub fn run_migration(name: String, max_items: u64) -> Result<MigrationStatus, String> {
+    state::require_controller()?;
+
+    let migration = MIGRATIONS
+        .iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| format!("Unknown migration: {}", name))?;
+    if max_items == 0 || max_items > MAX_MIGRATION_BATCH {
+        return Err(format!("max_items must be between 1 and {}", MAX_MIGRATION_BATCH));
+    }
+
+    let mut progress = state::get_migration_progress(migration.name).unwrap_or_default();
+    if progress.completed_at.is_some() {
+        return Ok(status(migration));
+    }
+
+    let now = runtime::time();
+    progress.started_at.get_or_insert(now);
+    let (processed, cursor) = (migration.step)(progress.cursor.as_deref(), max_items)?;
+    progress.processed = progress.processed.saturating_add(processed);
+    if processed < max_items {
+        progress.cursor = None;
+        progress.completed_at = Some(now);
+        state::record_admin_action("run_migration", format!("{} completed after {} items", migration.name, progress.processed));
+    } else {
+        progress.cursor = cursor;
+    }
+    state::set_migration_progress(migration.name, &progress);
+
+    Ok(status(migration))
+}
+
+
+#[ic_cdk::query]
+pub fn migration_status() -> Vec<MigrationStatus> {
+    MIGRATIONS.iter().map(status).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::{get_account_tokens, get_balances_for, QueryError};
+    use crate::test_utils::{register_test_token, test_account};
+
+    #[test]
+    fn test_account_tokens_migration_resumes() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let tokens: Vec<_> = (1..=3).map(|seed| register_test_token(seed, 0)).collect();
+        let (alice, bob, carol) = (test_account(2), test_account(3), test_account(4));
+        for token in &tokens {
+            state::seed_balance(*token, alice.to_key(), 10);
+            state::seed_balance(*token, bob.to_key(), 10);
+        }
+        assert!(MIGRATIONS.iter().all(|m| m.name.len() <= 20));
+
+        // Balances scan every token until the index is complete; the index itself is blocked.
+        assert!(matches!(get_account_tokens(alice.clone()), Err(QueryError::MigrationPending { .. })));
+        assert_eq!(get_balances_for(alice.owner, None, None).unwrap().len(), 3);
+        assert!(run_migration(ACCOUNT_TOKENS_INDEX.to_string(), 0).is_err());
+        assert!(run_migration("unknown".to_string(), 1).is_err());
+
+        let status = run_migration(ACCOUNT_TOKENS_INDEX.to_string(), 4).unwrap();
+        assert_eq!((status.processed, status.complete), (4, false));
+
+        // Writes between chunks stay consistent on both sides of the cursor.
+        state::seed_balance(tokens[0], alice.to_key(), 0);
+        state::seed_balance(tokens[2], bob.to_key(), 0);
+        state::seed_balance(tokens[1], carol.to_key(), 5);
+        assert!(get_account_tokens(carol.clone()).is_err());
+
+        let status = run_migration(ACCOUNT_TOKENS_INDEX.to_string(), 4).unwrap();
+        assert!(status.complete && status.processed > 4);
+        assert!(migration_status().contains(&status));
+        assert_eq!(run_migration(ACCOUNT_TOKENS_INDEX.to_string(), 4).unwrap(), status);
+
+        assert_eq!(get_account_tokens(alice.clone()).unwrap(), vec![tokens[1], tokens[2]]);
+        assert_eq!(get_account_tokens(bob.clone()).unwrap(), vec![tokens[0], tokens[1]]);
+        assert_eq!(get_account_tokens(carol.clone()).unwrap(), vec![tokens[1]]);
+        let balances = get_balances_for(carol.owner, None, None).unwrap();
+        assert_eq!((balances.len(), balances[0].token_id, balances[0].balance), (1, tokens[1], 5));
+
+        runtime::set_caller(alice.owner);
+        assert!(run_migration(ACCOUNT_TOKENS_INDEX.to_string(), 4).is_err());
+    }
+}
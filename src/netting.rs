@@ -0,0 +1,294 @@
+use crate::types::{constants, Account, SettlementRecord, TokenId};
+use crate::state;
+use crate::runtime;
+use crate::operations::{transfer_internal, TransferError};
+use crate::validation::{validate_account, validate_token_id};
+use candid::{CandidType, Nat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use num_traits::cast::ToPrimitive;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum NettingDirection {
+    ToMe,
+    FromMe,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NettingEntry {
+    pub direction: NettingDirection,
+    pub amount: Nat,
+    #[serde(rename = "ref")]
+    pub reference: Vec<u8>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SettleNetResult {
+    pub settlement_index: u64,
+    // None when the entries cancel out exactly.
+    pub tx_index: Option<u64>,
+    pub net_direction: Option<NettingDirection>,
+    pub net_amount: Nat,
+    pub gross_to_me: Nat,
+    pub gross_from_me: Nat,
+    pub entries_hash: Vec<u8>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum SettleNetError {
+    NotAuthorized { caller_authorized: bool, counterparty_authorized: bool },
+    TransferFailed(TransferError),
+    GenericError { message: String },
+}
+
+impl From<String> for SettleNetError {
+    fn from(message: String) -> Self {
+        SettleNetError::GenericError { message }
+    }
+}
+
+
+fn caller_account() -> Result<Account, String> {
+    let account = Account { owner: runtime::caller(), subaccount: None };
+    validate_account(&account).map_err(|e| e.to_string())?;
+    Ok(account)
+}
+
+
+// Netting between two default accounts needs this from both sides.
+#[ic_cdk::update]
+pub fn authorize_netting(counterparty: Account) -> Result<(), String> {
+    let account = caller_account()?;
+    validate_account(&counterparty).map_err(|e| e.to_string())?;
+    if counterparty.canonical_eq(&account) {
+        return Err("Cannot authorize netting with yourself".to_string());
+    }
+
+    state::set_netting_authorization(account.to_key(), counterparty.to_key(), Some(runtime::time()));
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn revoke_netting(counterparty: Account) -> Result<(), String> {
+    let account = caller_account()?;
+    validate_account(&counterparty).map_err(|e| e.to_string())?;
+
+    state::set_netting_authorization(account.to_key(), counterparty.to_key(), None);
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn is_netting_authorized(account: Account, counterparty: Account) -> bool {
+    state::get_netting_authorization(account.to_key(), counterparty.to_key()).is_some()
+}
+
+
+// Settles the entries as a single transfer of the net amount, paid by whichever
+// side owes it. The transfer's memo is the hash of all entries.
+#[ic_cdk::update]
+pub fn settle_net(
+    token_id: TokenId,
+    counterparty: Account,
+    entries: Vec<NettingEntry>,
+) -> Result<SettleNetResult, SettleNetError> {
+    let account = caller_account()?;
+    validate_token_id(&token_id).map_err(|e| e.to_string())?;
+    validate_account(&counterparty).map_err(|e| e.to_string())?;
+    if counterparty.canonical_eq(&account) {
+        return Err("Cannot settle with yourself".to_string().into());
+    }
+    if entries.is_empty() {
+        return Err("At least one entry is required".to_string().into());
+    }
+    if entries.len() > constants::MAX_NETTING_ENTRIES {
+        return Err(format!("Too many entries (max {})", constants::MAX_NETTING_ENTRIES).into());
+    }
+
+    let account_key = account.to_key();
+    let counterparty_key = counterparty.to_key();
+    let caller_authorized = state::get_netting_authorization(account_key, counterparty_key).is_some();
+    let counterparty_authorized = state::get_netting_authorization(counterparty_key, account_key).is_some();
+    if !caller_authorized || !counterparty_authorized {
+        return Err(SettleNetError::NotAuthorized { caller_authorized, counterparty_authorized });
+    }
+
+    let (gross_to_me, gross_from_me) = gross_totals(&entries)?;
+    let entries_hash = hash_entries(&entries);
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+
+    let (net_direction, net_amount) = if gross_from_me > gross_to_me {
+        (Some(NettingDirection::FromMe), gross_from_me - gross_to_me)
+    } else if gross_to_me > gross_from_me {
+        (Some(NettingDirection::ToMe), gross_to_me - gross_from_me)
+    } else {
+        (None, 0)
+    };
+
+    let tx_index = match net_direction {
+        Some(direction) => {
+            let (from, to) = match direction {
+                NettingDirection::FromMe => (account.clone(), counterparty.clone()),
+                NettingDirection::ToMe => (counterparty.clone(), account.clone()),
+            };
+            let tx_index = transfer_internal(token_id, from, to, net_amount, Some(metadata.fee), Some(&entries_hash), None)
+                .map_err(SettleNetError::TransferFailed)?;
+            Some(tx_index)
+        }
+        None => None,
+    };
+
+    let timestamp = runtime::time();
+    let entry_count = entries.len() as u64;
+    let settlement_index = state::record_settlement(|index| SettlementRecord {
+        index,
+        token_id,
+        initiator: account,
+        counterparty,
+        entries_hash: entries_hash.to_vec(),
+        entry_count,
+        gross_to_initiator: gross_to_me,
+        gross_from_initiator: gross_from_me,
+        tx_index,
+        timestamp,
+    });
+
+    Ok(SettleNetResult {
+        settlement_index,
+        tx_index,
+        net_direction,
+        net_amount: Nat::from(net_amount),
+        gross_to_me: Nat::from(gross_to_me),
+        gross_from_me: Nat::from(gross_from_me),
+        entries_hash: entries_hash.to_vec(),
+    })
+}
+
+
+fn gross_totals(entries: &[NettingEntry]) -> Result<(u128, u128), String> {
+    let mut to_me = 0u128;
+    let mut from_me = 0u128;
+
+    for entry in entries {
+        if entry.reference.len() > constants::MAX_NETTING_REF_LEN {
+            return Err(format!("Entry ref too long (max {} bytes)", constants::MAX_NETTING_REF_LEN));
+        }
+        let amount = entry.amount.0.to_u128().ok_or("Entry amount exceeds maximum value (u128::MAX)".to_string())?;
+        let total = match entry.direction {
+            NettingDirection::ToMe => &mut to_me,
+            NettingDirection::FromMe => &mut from_me,
+        };
+        *total = total.checked_add(amount).ok_or("Gross total overflows".to_string())?;
+    }
+
+    Ok((to_me, from_me))
+}
+
+
+// Order-sensitive, and each ref is length-prefixed so entries cannot be re-split.
+fn hash_entries(entries: &[NettingEntry]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"icrc151:settle_net:v1");
+    for entry in entries {
+        hasher.update([match entry.direction {
+            NettingDirection::ToMe => 0u8,
+            NettingDirection::FromMe => 1u8,
+        }]);
+        hasher.update(entry.amount.0.to_u128().unwrap_or(u128::MAX).to_be_bytes());
+        hasher.update((entry.reference.len() as u32).to_be_bytes());
+        hasher.update(&entry.reference);
+    }
+    hasher.finalize().into()
+}
+
+
+#[ic_cdk::query]
+// Empty for callers without access while privacy mode is on.
+pub fn get_settlements(start: u64, length: u64) -> Vec<SettlementRecord> {
+    if crate::privacy::require_privileged_reader().is_err() {
+        return Vec::new();
+    }
+    let length = length.min(constants::MAX_AUDIT_LOG_PAGE);
+    state::list_settlements(start, length as usize)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{register_test_token, test_account};
+
+    fn entry(direction: NettingDirection, amount: u64, reference: &[u8]) -> NettingEntry {
+        NettingEntry { direction, amount: Nat::from(amount), reference: reference.to_vec() }
+    }
+
+    #[test]
+    fn test_settle_net() {
+        let token = register_test_token(1, 5);
+        let (alice, bob) = (test_account(2), test_account(3));
+        state::seed_balance(token, alice.to_key(), 1_000);
+        state::seed_balance(token, bob.to_key(), 1_000);
+        let entries = vec![
+            entry(NettingDirection::FromMe, 300, b"a"),
+            entry(NettingDirection::ToMe, 120, b"b"),
+            entry(NettingDirection::FromMe, 20, b"c"),
+        ];
+
+        // Both sides must opt in.
+        runtime::set_caller(alice.owner);
+        authorize_netting(bob.clone()).unwrap();
+        assert!(matches!(
+            settle_net(token, bob.clone(), entries.clone()),
+            Err(SettleNetError::NotAuthorized { caller_authorized: true, counterparty_authorized: false })
+        ));
+        runtime::set_caller(bob.owner);
+        authorize_netting(alice.clone()).unwrap();
+
+        runtime::set_caller(alice.owner);
+        let result = settle_net(token, bob.clone(), entries.clone()).unwrap();
+        assert_eq!(result.net_direction, Some(NettingDirection::FromMe));
+        assert_eq!(result.net_amount, 200u64);
+        assert_eq!((result.gross_to_me.clone(), result.gross_from_me.clone()), (Nat::from(120u64), Nat::from(320u64)));
+        assert_eq!(state::get_balance(token, alice.to_key()), 795);
+        assert_eq!(state::get_balance(token, bob.to_key()), 1_200);
+        let tx = state::get_transaction(result.tx_index.unwrap()).unwrap();
+        assert_eq!(tx.memo.to_vec(), result.entries_hash);
+        assert_eq!(tx.from_key, alice.to_key());
+
+        // From the other side the counterparty pays; balanced entries move nothing.
+        runtime::advance_time(1);
+        let result = settle_net(token, bob.clone(), vec![entry(NettingDirection::ToMe, 50, b"d")]).unwrap();
+        assert_eq!(state::get_transaction(result.tx_index.unwrap()).unwrap().from_key, bob.to_key());
+        let tx_count = state::get_transaction_count();
+        let even = settle_net(token, bob.clone(), vec![entry(NettingDirection::ToMe, 5, b"e"), entry(NettingDirection::FromMe, 5, b"f")]).unwrap();
+        assert_eq!((even.tx_index, even.net_direction), (None, None));
+        assert_eq!(state::get_transaction_count(), tx_count);
+
+        let settlements = get_settlements(0, 10);
+        assert_eq!(settlements.len(), 3);
+        assert_eq!(settlements[0].tx_index, Some(0));
+        assert_eq!(settlements[0].entry_count, 3);
+        assert_eq!(settlements[2].entries_hash, even.entries_hash);
+
+        // Revoking from either side stops netting; a failed transfer records nothing.
+        assert!(matches!(
+            settle_net(token, bob.clone(), vec![entry(NettingDirection::FromMe, 10_000, b"g")]),
+            Err(SettleNetError::TransferFailed(TransferError::InsufficientFunds { .. }))
+        ));
+        assert_eq!(get_settlements(0, 10).len(), 3);
+        runtime::set_caller(bob.owner);
+        revoke_netting(alice.clone()).unwrap();
+        runtime::set_caller(alice.owner);
+        assert!(settle_net(token, bob, entries.clone()).is_err());
+
+        // The zero subaccount is the caller's own default account.
+        let alice_zero = Account { owner: alice.owner, subaccount: Some(vec![0u8; 32]) };
+        assert!(authorize_netting(alice_zero.clone()).unwrap_err().contains("yourself"));
+        assert!(matches!(settle_net(token, alice_zero, entries), Err(SettleNetError::GenericError { message }) if message.contains("yourself")));
+    }
+}
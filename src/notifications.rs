@@ -0,0 +1,851 @@
+use crate::queries::{self, TokenMetadata};
+use crate::runtime;
+use crate::state;
+use crate::types::{decode_allowance_expiry_queue_key, memory_ids, Account, AccountKey, StoredPrincipal, TokenId};
+use candid::CandidType;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+
+pub const MAX_METADATA_SUBSCRIBERS: u64 = 100;
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+pub const METADATA_CHANGED_METHOD: &str = "icrc151_metadata_changed";
+pub const RECOVERY_INITIATED_METHOD: &str = "icrc151_recovery_initiated";
+pub const ALLOWANCE_EXPIRING_METHOD: &str = "icrc151_allowance_expiring";
+pub const BALANCE_ALERT_METHOD: &str = "icrc151_balance_alert";
+pub const HOLDER_SOFT_CAP_METHOD: &str = "icrc151_holder_soft_cap";
+pub const MAX_BALANCE_ALERTS: u64 = 100;
+pub const MAX_EXPIRY_SUBSCRIBERS: u64 = 100;
+pub const MAX_EXPIRY_NOTICE_HOURS: u32 = 720;
+pub const MAX_EXPIRY_NOTICES_PER_HOUR: u32 = 100;
+pub const EXPIRY_SWEEP_INTERVAL_SECS: u64 = 900;
+// Queue entries examined per subscriber per sweep.
+const EXPIRY_SCAN_LIMIT: usize = 1000;
+const NANOS_PER_HOUR: u64 = 3_600_000_000_000;
+
+
+thread_local! {
+    // Value: has_filter (1) ++ token_id (32) ++ consecutive_failures (u32 BE)
+    static METADATA_SUBSCRIBERS: RefCell<StableBTreeMap<StoredPrincipal, [u8; 37], state::Memory>> = RefCell::new(
+        StableBTreeMap::init(state::memory(memory_ids::METADATA_SUBSCRIBERS))
+    );
+
+    // Value: notice_hours (u32 BE) ++ consecutive_failures (u32 BE) ++ window_start (u64 BE)
+    //        ++ sent_in_window (u32 BE) ++ has_cursor (1) ++ cursor (104)
+    static EXPIRY_SUBSCRIBERS: RefCell<StableBTreeMap<StoredPrincipal, [u8; 125], state::Memory>> = RefCell::new(
+        StableBTreeMap::init(state::memory(memory_ids::EXPIRY_SUBSCRIBERS))
+    );
+
+    // Key: account_key (32) ++ token_id (32) ++ alert id (u64 BE), so a balance
+    // change finds its account's alerts with one range lookup.
+    static BALANCE_ALERTS: RefCell<StableBTreeMap<[u8; 72], BalanceAlert, state::Memory>> = RefCell::new(
+        StableBTreeMap::init(state::memory(memory_ids::BALANCE_ALERTS))
+    );
+}
+
+
+// See state::warm_up.
+pub(crate) fn warm_up() {
+    if state::holds_data(memory_ids::METADATA_SUBSCRIBERS) {
+        METADATA_SUBSCRIBERS.with(|_| ());
+    }
+    if state::holds_data(memory_ids::EXPIRY_SUBSCRIBERS) {
+        EXPIRY_SUBSCRIBERS.with(|_| ());
+    }
+    if state::holds_data(memory_ids::BALANCE_ALERTS) {
+        BALANCE_ALERTS.with(|_| ());
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MetadataChangeKind {
+    FeeChanged,
+    FeeTokenChanged,
+    RequireExplicitFeeChanged,
+    FeeFreeConsolidationChanged,
+    MetadataRepaired,
+    BurnAddressesChanged,
+    NonCirculatingAccountsChanged,
+    RoundingModeChanged,
+    StructuredMemosRequiredChanged,
+    LocalizationChanged,
+    MaxHoldersChanged,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HolderSoftCapWarning {
+    pub token_id: TokenId,
+    pub holders: u64,
+    pub soft_cap: u64,
+    pub at: u64,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MetadataChange {
+    pub token_id: TokenId,
+    pub kind: MetadataChangeKind,
+    pub metadata: TokenMetadata,
+    pub changed_at: u64,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MetadataSubscription {
+    pub filter: Option<TokenId>,
+    pub consecutive_failures: u32,
+}
+
+
+fn encode_subscription(sub: &MetadataSubscription) -> [u8; 37] {
+    let mut value = [0u8; 37];
+    if let Some(token_id) = sub.filter {
+        value[0] = 1;
+        value[1..33].copy_from_slice(&token_id);
+    }
+    value[33..37].copy_from_slice(&sub.consecutive_failures.to_be_bytes());
+    value
+}
+
+fn decode_subscription(value: &[u8; 37]) -> MetadataSubscription {
+    let filter = (value[0] == 1).then(|| {
+        let mut token_id = [0u8; 32];
+        token_id.copy_from_slice(&value[1..33]);
+        token_id
+    });
+    let mut failures = [0u8; 4];
+    failures.copy_from_slice(&value[33..37]);
+    MetadataSubscription {
+        filter,
+        consecutive_failures: u32::from_be_bytes(failures),
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AllowanceExpirySubscription {
+    pub notice_hours: u32,
+    pub consecutive_failures: u32,
+    pub sent_in_window: u32,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AllowanceExpiring {
+    pub token_id: TokenId,
+    pub owner: Account,
+    pub spender: Account,
+    pub amount: candid::Nat,
+    pub expires_at: u64,
+}
+
+
+// The cursor is the last queue entry this subscriber is done with.
+struct ExpiryState {
+    notice_hours: u32,
+    consecutive_failures: u32,
+    window_start: u64,
+    sent_in_window: u32,
+    cursor: Option<[u8; 104]>,
+}
+
+
+fn encode_expiry_state(sub: &ExpiryState) -> [u8; 125] {
+    let mut value = [0u8; 125];
+    value[0..4].copy_from_slice(&sub.notice_hours.to_be_bytes());
+    value[4..8].copy_from_slice(&sub.consecutive_failures.to_be_bytes());
+    value[8..16].copy_from_slice(&sub.window_start.to_be_bytes());
+    value[16..20].copy_from_slice(&sub.sent_in_window.to_be_bytes());
+    if let Some(cursor) = sub.cursor {
+        value[20] = 1;
+        value[21..125].copy_from_slice(&cursor);
+    }
+    value
+}
+
+fn decode_expiry_state(value: &[u8; 125]) -> ExpiryState {
+    let u32_at = |at: usize| u32::from_be_bytes(value[at..at + 4].try_into().unwrap());
+    let cursor = (value[20] == 1).then(|| {
+        let mut cursor = [0u8; 104];
+        cursor.copy_from_slice(&value[21..125]);
+        cursor
+    });
+    ExpiryState {
+        notice_hours: u32_at(0),
+        consecutive_failures: u32_at(4),
+        window_start: u64::from_be_bytes(value[8..16].try_into().unwrap()),
+        sent_in_window: u32_at(16),
+        cursor,
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AlertDirection {
+    Above,
+    Below,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceAlertRule {
+    pub account: Account,
+    pub token_id: TokenId,
+    pub direction: AlertDirection,
+    pub threshold: u128,
+    pub notify: candid::Principal,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceAlert {
+    pub id: u64,
+    pub rule: BalanceAlertRule,
+    pub created_at: u64,
+}
+
+impl Storable for BalanceAlert {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Balance alerts are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceAlertEvent {
+    pub alert_id: u64,
+    pub account: Account,
+    pub token_id: TokenId,
+    pub direction: AlertDirection,
+    pub threshold: u128,
+    pub previous_balance: u128,
+    pub balance: u128,
+    pub timestamp: u64,
+}
+
+
+fn balance_alert_key(account_key: AccountKey, token_id: TokenId, id: u64) -> [u8; 72] {
+    let mut key = [0u8; 72];
+    key[0..32].copy_from_slice(&account_key);
+    key[32..64].copy_from_slice(&token_id);
+    key[64..72].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+
+// Opaque principals (canister ids) end in 0x01; users cannot receive notifications.
+fn is_canister(principal: &candid::Principal) -> bool {
+    principal.as_slice().last() == Some(&0x01)
+}
+
+
+#[ic_cdk::update]
+pub fn subscribe_metadata_changes(filter: Option<TokenId>) -> Result<(), String> {
+    let caller = runtime::caller();
+    if !is_canister(&caller) {
+        return Err("Only canisters can subscribe to metadata changes".to_string());
+    }
+
+    if let Some(token_id) = filter {
+        if !state::token_exists(token_id) {
+            return Err("Token not found".to_string());
+        }
+    }
+
+    let key = StoredPrincipal::from_principal(&caller)?;
+    METADATA_SUBSCRIBERS.with(|s| {
+        let mut subscribers = s.borrow_mut();
+        if !subscribers.contains_key(&key) && subscribers.len() >= MAX_METADATA_SUBSCRIBERS {
+            return Err(format!("Subscriber limit of {} reached", MAX_METADATA_SUBSCRIBERS));
+        }
+        let subscription = MetadataSubscription { filter, consecutive_failures: 0 };
+        subscribers.insert(key, encode_subscription(&subscription));
+        Ok(())
+    })
+}
+
+
+#[ic_cdk::update]
+pub fn unsubscribe_metadata_changes() -> Result<(), String> {
+    let key = StoredPrincipal::from_principal(&runtime::caller())?;
+    METADATA_SUBSCRIBERS.with(|s| s.borrow_mut().remove(&key))
+        .map(|_| ())
+        .ok_or("Not subscribed".to_string())
+}
+
+
+#[ic_cdk::query]
+pub fn get_metadata_subscription() -> Option<MetadataSubscription> {
+    let key = StoredPrincipal::from_principal(&runtime::caller()).ok()?;
+    METADATA_SUBSCRIBERS.with(|s| s.borrow().get(&key)).map(|v| decode_subscription(&v))
+}
+
+
+// The caller is notified `notice_hours` before each of its allowances expires,
+// for allowances whose spender account is owned by the caller.
+#[ic_cdk::update]
+pub fn subscribe_allowance_expiry(notice_hours: u32) -> Result<(), String> {
+    if let Some(migration) = crate::migrations::blocking_migration("subscribe_allowance_expiry") {
+        return Err(format!("Migration {} is pending", migration));
+    }
+    let caller = runtime::caller();
+    if !is_canister(&caller) {
+        return Err("Only canisters can subscribe to allowance expiry".to_string());
+    }
+    if notice_hours == 0 || notice_hours > MAX_EXPIRY_NOTICE_HOURS {
+        return Err(format!("notice_hours must be between 1 and {}", MAX_EXPIRY_NOTICE_HOURS));
+    }
+
+    let key = StoredPrincipal::from_principal(&caller)?;
+    EXPIRY_SUBSCRIBERS.with(|s| {
+        let mut subscribers = s.borrow_mut();
+        // Re-subscribing changes the notice period but keeps delivery progress.
+        let subscription = match subscribers.get(&key) {
+            Some(value) => ExpiryState { notice_hours, ..decode_expiry_state(&value) },
+            None => {
+                if subscribers.len() >= MAX_EXPIRY_SUBSCRIBERS {
+                    return Err(format!("Subscriber limit of {} reached", MAX_EXPIRY_SUBSCRIBERS));
+                }
+                ExpiryState { notice_hours, consecutive_failures: 0, window_start: 0, sent_in_window: 0, cursor: None }
+            }
+        };
+        subscribers.insert(key, encode_expiry_state(&subscription));
+        Ok(())
+    })
+}
+
+
+#[ic_cdk::update]
+pub fn unsubscribe_allowance_expiry() -> Result<(), String> {
+    let key = StoredPrincipal::from_principal(&runtime::caller())?;
+    EXPIRY_SUBSCRIBERS.with(|s| s.borrow_mut().remove(&key))
+        .map(|_| ())
+        .ok_or("Not subscribed".to_string())
+}
+
+
+#[ic_cdk::query]
+pub fn get_allowance_expiry_subscription() -> Option<AllowanceExpirySubscription> {
+    let key = StoredPrincipal::from_principal(&runtime::caller()).ok()?;
+    EXPIRY_SUBSCRIBERS.with(|s| s.borrow().get(&key)).map(|v| {
+        let sub = decode_expiry_state(&v);
+        AllowanceExpirySubscription {
+            notice_hours: sub.notice_hours,
+            consecutive_failures: sub.consecutive_failures,
+            sent_in_window: sub.sent_in_window,
+        }
+    })
+}
+
+
+// Timer job. Sends one notice per (allowance, expiry) to the spender's owner
+// once the expiry is within its notice period. Revoked, changed and already
+// expired allowances are skipped; nothing here touches ledger state. The
+// per-subscriber cursor only moves forward, so an expiry moved earlier than
+// one already announced is not announced again.
+pub fn sweep_allowance_expiry() {
+    let now = runtime::time();
+    let subscribers: Vec<(StoredPrincipal, ExpiryState)> = EXPIRY_SUBSCRIBERS.with(|s| {
+        s.borrow().iter().map(|(key, value)| (key, decode_expiry_state(&value))).collect()
+    });
+
+    for (key, mut sub) in subscribers {
+        let Ok(target) = key.to_principal() else { continue };
+        if now.saturating_sub(sub.window_start) >= NANOS_PER_HOUR {
+            sub.window_start = now;
+            sub.sent_in_window = 0;
+        }
+
+        let until = now.saturating_add(sub.notice_hours as u64 * NANOS_PER_HOUR);
+        let mut evicted = false;
+        for queue_key in state::list_expiring_allowances(sub.cursor, now, until, EXPIRY_SCAN_LIMIT) {
+            let (expires_at, token_id, owner_key, spender_key) = decode_allowance_expiry_queue_key(&queue_key);
+            let notice = match expiring_allowance(target, expires_at, token_id, owner_key, spender_key) {
+                Some(notice) => notice,
+                None => {
+                    sub.cursor = Some(queue_key);
+                    continue;
+                }
+            };
+            if sub.sent_in_window >= MAX_EXPIRY_NOTICES_PER_HOUR {
+                break;
+            }
+            let Ok(payload) = candid::encode_one(&notice) else { continue };
+            match runtime::notify(target, ALLOWANCE_EXPIRING_METHOD, payload) {
+                Ok(()) => {
+                    sub.consecutive_failures = 0;
+                    sub.sent_in_window += 1;
+                }
+                Err(_) => {
+                    sub.consecutive_failures = sub.consecutive_failures.saturating_add(1);
+                    if sub.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                        evicted = true;
+                        break;
+                    }
+                }
+            }
+            sub.cursor = Some(queue_key);
+        }
+
+        EXPIRY_SUBSCRIBERS.with(|s| {
+            let mut subscribers = s.borrow_mut();
+            if evicted {
+                subscribers.remove(&key);
+            } else {
+                subscribers.insert(key, encode_expiry_state(&sub));
+            }
+        });
+    }
+}
+
+
+fn expiring_allowance(
+    subscriber: candid::Principal,
+    expires_at: u64,
+    token_id: TokenId,
+    owner_key: crate::types::AccountKey,
+    spender_key: crate::types::AccountKey,
+) -> Option<AllowanceExpiring> {
+    let spender = state::get_registered_account(spender_key).filter(|s| s.owner == subscriber)?;
+    if state::get_allowance_expiry(token_id, owner_key, spender_key) != Some(expires_at) {
+        return None;
+    }
+    let amount = state::get_allowance(token_id, owner_key, spender_key);
+    if amount == 0 {
+        return None;
+    }
+    let owner = state::get_registered_account(owner_key)?;
+    Some(AllowanceExpiring { token_id, owner, spender, amount: candid::Nat::from(amount), expires_at })
+}
+
+
+#[ic_cdk::update]
+pub fn add_balance_alert(rule: BalanceAlertRule) -> Result<u64, String> {
+    state::require_controller()?;
+    crate::validation::validate_account(&rule.account).map_err(|e| e.to_string())?;
+    if !state::token_exists(rule.token_id) {
+        return Err("Token not found".to_string());
+    }
+    if !is_canister(&rule.notify) {
+        return Err("Alerts can only notify canisters".to_string());
+    }
+    if BALANCE_ALERTS.with(|a| a.borrow().len()) >= MAX_BALANCE_ALERTS {
+        return Err(format!("Balance alert limit of {} reached", MAX_BALANCE_ALERTS));
+    }
+
+    let id = state::next_balance_alert_id();
+    let key = balance_alert_key(rule.account.to_key(), rule.token_id, id);
+    let detail = format!("#{}: {:?} {} -> {}", id, rule.direction, rule.threshold, rule.notify);
+    BALANCE_ALERTS.with(|a| a.borrow_mut().insert(key, BalanceAlert { id, rule, created_at: runtime::time() }));
+    state::record_admin_action("add_balance_alert", detail);
+    Ok(id)
+}
+
+
+#[ic_cdk::update]
+pub fn remove_balance_alert(id: u64) -> Result<(), String> {
+    state::require_controller()?;
+
+    let key = BALANCE_ALERTS.with(|a| a.borrow().iter().find(|(_, alert)| alert.id == id).map(|(key, _)| key))
+        .ok_or("Balance alert not found".to_string())?;
+    BALANCE_ALERTS.with(|a| a.borrow_mut().remove(&key));
+    state::record_admin_action("remove_balance_alert", format!("#{}", id));
+    Ok(())
+}
+
+
+// Rules name treasury accounts, so privacy mode limits them like other per-account data.
+#[ic_cdk::query]
+pub fn list_balance_alerts() -> Result<Vec<BalanceAlert>, queries::QueryError> {
+    crate::privacy::require_privileged_reader()?;
+    Ok(BALANCE_ALERTS.with(|a| a.borrow().iter().map(|(_, alert)| alert).collect()))
+}
+
+
+// Called by state::set_balance for every balance that changes. Edge-triggered:
+// an alert fires when the balance moves across its threshold, not while it
+// stays on the far side. Without any alerts this is a single length check.
+pub fn check_balance_alerts(token_id: TokenId, account_key: AccountKey, previous: u128, balance: u128) {
+    let alerts: Vec<BalanceAlert> = BALANCE_ALERTS.with(|a| {
+        let map = a.borrow();
+        if map.is_empty() {
+            return Vec::new();
+        }
+        let start = balance_alert_key(account_key, token_id, 0);
+        let end = balance_alert_key(account_key, token_id, u64::MAX);
+        map.range(start..=end).map(|(_, alert)| alert).collect()
+    });
+
+    for alert in alerts {
+        let threshold = alert.rule.threshold;
+        let crossed = match alert.rule.direction {
+            AlertDirection::Above => previous <= threshold && balance > threshold,
+            AlertDirection::Below => previous >= threshold && balance < threshold,
+        };
+        if !crossed {
+            continue;
+        }
+        let event = BalanceAlertEvent {
+            alert_id: alert.id,
+            account: alert.rule.account,
+            token_id,
+            direction: alert.rule.direction,
+            threshold,
+            previous_balance: previous,
+            balance,
+            timestamp: runtime::time(),
+        };
+        if let Ok(payload) = candid::encode_one(&event) {
+            let _ = runtime::notify(alert.rule.notify, BALANCE_ALERT_METHOD, payload);
+        }
+    }
+}
+
+
+// Best effort: a failed enqueue only bumps the subscriber's failure count and
+// never surfaces to the admin call that triggered it.
+pub fn publish_metadata_change(token_id: TokenId, kind: MetadataChangeKind) {
+    let metadata = match queries::get_token_metadata(token_id) {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+    let change = MetadataChange {
+        token_id,
+        kind,
+        metadata,
+        changed_at: runtime::time(),
+    };
+    let payload = match candid::encode_one(&change) {
+        Ok(bytes) => bytes,
+        Err(_) => return,
+    };
+
+    broadcast(METADATA_CHANGED_METHOD, payload, |sub| sub.filter.is_none_or(|t| t == token_id));
+}
+
+
+// Sent to every subscriber regardless of its token filter.
+pub fn publish_recovery_initiated(status: &crate::recovery::RecoveryStatus) {
+    if let Ok(payload) = candid::encode_one(status) {
+        broadcast(RECOVERY_INITIATED_METHOD, payload, |_| true);
+    }
+}
+
+
+// Sent to the subscribers that follow the token, as metadata changes are.
+pub fn publish_holder_soft_cap_warning(token_id: TokenId, holders: u64, soft_cap: u64) {
+    let warning = HolderSoftCapWarning { token_id, holders, soft_cap, at: runtime::time() };
+    if let Ok(payload) = candid::encode_one(&warning) {
+        broadcast(HOLDER_SOFT_CAP_METHOD, payload, |sub| sub.filter.is_none_or(|t| t == token_id));
+    }
+}
+
+
+fn broadcast(method: &str, payload: Vec<u8>, wants: impl Fn(&MetadataSubscription) -> bool) {
+    let subscribers: Vec<(StoredPrincipal, MetadataSubscription)> = METADATA_SUBSCRIBERS.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(key, value)| (key, decode_subscription(&value)))
+            .filter(|(_, sub)| wants(sub))
+            .collect()
+    });
+
+    for (key, mut subscription) in subscribers {
+        let Ok(target) = key.to_principal() else { continue };
+        let failures = match runtime::notify(target, method, payload.clone()) {
+            Ok(()) => 0,
+            Err(_) => subscription.consecutive_failures.saturating_add(1),
+        };
+        if failures == subscription.consecutive_failures {
+            continue;
+        }
+
+        METADATA_SUBSCRIBERS.with(|s| {
+            let mut subscribers = s.borrow_mut();
+            if failures >= MAX_CONSECUTIVE_FAILURES {
+                subscribers.remove(&key);
+            } else {
+                subscription.consecutive_failures = failures;
+                subscribers.insert(key, encode_subscription(&subscription));
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Principal;
+
+    fn canister(id: u8) -> Principal {
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, id, 1, 1])
+    }
+
+    fn subscribe_as(subscriber: Principal, filter: Option<TokenId>) -> Result<(), String> {
+        runtime::set_caller(subscriber);
+        subscribe_metadata_changes(filter)
+    }
+
+    #[test]
+    fn test_metadata_change_delivery_and_filter() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 10);
+        let other = register_test_token(2, 10);
+
+        assert!(subscribe_as(test_account(2).owner, None).is_err());
+        subscribe_as(canister(1), None).unwrap();
+        subscribe_as(canister(2), Some(other)).unwrap();
+
+        runtime::set_caller(controller);
+        crate::operations::set_token_fee(token, candid::Nat::from(25u64)).unwrap();
+
+        let sent = runtime::take_notifications();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, canister(1));
+        assert_eq!(sent[0].1, METADATA_CHANGED_METHOD);
+        let change: MetadataChange = candid::decode_one(&sent[0].2).unwrap();
+        assert_eq!(change.token_id, token);
+        assert_eq!(change.kind, MetadataChangeKind::FeeChanged);
+        assert_eq!(change.metadata.fee, 25);
+
+        crate::operations::set_token_require_explicit_fee(other, true).unwrap();
+        assert_eq!(runtime::take_notifications().len(), 2);
+
+        runtime::set_caller(canister(1));
+        unsubscribe_metadata_changes().unwrap();
+        assert!(unsubscribe_metadata_changes().is_err());
+    }
+
+    #[test]
+    fn test_holder_soft_cap_warns_once_per_crossing() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let from = test_account(1);
+        state::seed_balance(token, from.to_key(), 1_000);
+        subscribe_as(canister(1), Some(token)).unwrap();
+        runtime::set_caller(controller);
+        crate::operations::set_holder_soft_cap(10).unwrap();
+        assert_eq!(crate::queries::get_ledger_config().holder_soft_cap, 10);
+        runtime::take_notifications();
+
+        // 90% of 10: the ninth holder triggers the warning, the tenth does not.
+        let pay = |to: u8| crate::operations::transfer_internal(token, from.clone(), test_account(to), 1, None, None, None).unwrap();
+        for to in 2..=8 {
+            pay(to);
+        }
+        assert!(runtime::take_notifications().is_empty());
+        pay(9);
+        let sent = runtime::take_notifications();
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0, sent[0].1.as_str()), (canister(1), HOLDER_SOFT_CAP_METHOD));
+        let warning: HolderSoftCapWarning = candid::decode_one(&sent[0].2).unwrap();
+        assert_eq!((warning.token_id, warning.holders, warning.soft_cap), (token, 9, 10));
+        pay(10);
+        pay(11);
+        assert!(runtime::take_notifications().is_empty());
+    }
+
+    #[test]
+    fn test_failing_subscriber_is_evicted() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 10);
+        subscribe_as(canister(1), None).unwrap();
+        runtime::set_unreachable(canister(1), true);
+
+        runtime::set_caller(controller);
+        for fee in 1..MAX_CONSECUTIVE_FAILURES as u64 {
+            crate::operations::set_token_fee(token, candid::Nat::from(fee)).unwrap();
+        }
+        runtime::set_caller(canister(1));
+        assert_eq!(
+            get_metadata_subscription().map(|s| s.consecutive_failures),
+            Some(MAX_CONSECUTIVE_FAILURES - 1)
+        );
+
+        runtime::set_caller(controller);
+        crate::operations::set_token_fee(token, candid::Nat::from(100u64)).unwrap();
+        runtime::set_caller(canister(1));
+        assert_eq!(get_metadata_subscription(), None);
+    }
+
+    #[test]
+    fn test_subscriber_cap() {
+        for id in 0..MAX_METADATA_SUBSCRIBERS as u8 {
+            subscribe_as(canister(id), None).unwrap();
+        }
+        assert!(subscribe_as(canister(200), None).is_err());
+        // Existing subscribers can still change their filter.
+        subscribe_as(canister(0), None).unwrap();
+    }
+
+    fn expiring_approval(token: TokenId, owner: &Account, spender: &Account, amount: u128, expires_at: u64) {
+        state::register_account(owner).unwrap();
+        state::register_account(spender).unwrap();
+        state::set_allowance(token, owner.to_key(), spender.to_key(), amount);
+        state::set_allowance_expiry(token, owner.to_key(), spender.to_key(), expires_at);
+    }
+
+    fn expiry_notices() -> Vec<AllowanceExpiring> {
+        runtime::take_notifications()
+            .into_iter()
+            .inspect(|(_, method, _)| assert_eq!(method, ALLOWANCE_EXPIRING_METHOD))
+            .map(|(_, _, payload)| candid::decode_one(&payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_allowance_expiry_notices() {
+        runtime::set_caller(canister(1));
+        assert!(subscribe_allowance_expiry(24).unwrap_err().contains(crate::migrations::ALLOWANCE_EXPIRY_QUEUE));
+        crate::migrations::mark_all_complete();
+        let token = register_test_token(1, 10);
+        let hour = NANOS_PER_HOUR;
+        let now = runtime::time();
+        let owner = test_account(1);
+        let spender = Account { owner: canister(1), subaccount: None };
+        let other_spender = Account { owner: canister(2), subaccount: None };
+
+        assert!(subscribe_as(test_account(2).owner, None).is_err());
+        runtime::set_caller(test_account(2).owner);
+        assert!(subscribe_allowance_expiry(24).is_err());
+        runtime::set_caller(canister(1));
+        assert!(subscribe_allowance_expiry(0).is_err());
+        assert!(subscribe_allowance_expiry(MAX_EXPIRY_NOTICE_HOURS + 1).is_err());
+        subscribe_allowance_expiry(24).unwrap();
+
+        let revoked = test_account(3);
+        let later = test_account(4);
+        expiring_approval(token, &owner, &spender, 50, now + 2 * hour);
+        expiring_approval(token, &owner, &other_spender, 50, now + 3 * hour);
+        expiring_approval(token, &revoked, &spender, 50, now + 4 * hour);
+        state::set_allowance(token, revoked.to_key(), spender.to_key(), 0);
+        expiring_approval(token, &later, &spender, 70, now + 30 * hour);
+
+        sweep_allowance_expiry();
+        let notices = expiry_notices();
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].owner, owner);
+        assert_eq!(notices[0].spender, spender);
+        assert_eq!(notices[0].amount, 50u64);
+        assert_eq!(notices[0].expires_at, now + 2 * hour);
+
+        // Each expiry is announced once; a renewal is a new expiry.
+        sweep_allowance_expiry();
+        assert!(expiry_notices().is_empty());
+        runtime::advance_time(8 * hour);
+        sweep_allowance_expiry();
+        assert_eq!(expiry_notices()[0].owner, later);
+        state::set_allowance_expiry(token, owner.to_key(), spender.to_key(), now + 31 * hour);
+        sweep_allowance_expiry();
+        assert_eq!(expiry_notices()[0].expires_at, now + 31 * hour);
+
+        // Rate limit: the rest goes out in the next hourly window.
+        runtime::advance_time(8 * hour);
+        for id in 0..=MAX_EXPIRY_NOTICES_PER_HOUR as u8 {
+            expiring_approval(token, &test_account(100 + id), &spender, 1, now + 36 * hour);
+        }
+        sweep_allowance_expiry();
+        assert_eq!(expiry_notices().len(), MAX_EXPIRY_NOTICES_PER_HOUR as usize);
+        runtime::set_caller(canister(1));
+        assert_eq!(get_allowance_expiry_subscription().unwrap().sent_in_window, MAX_EXPIRY_NOTICES_PER_HOUR);
+        runtime::advance_time(hour);
+        sweep_allowance_expiry();
+        assert_eq!(expiry_notices().len(), 1);
+
+        unsubscribe_allowance_expiry().unwrap();
+        assert_eq!(get_allowance_expiry_subscription(), None);
+    }
+
+    #[test]
+    fn test_balance_alerts_are_edge_triggered() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let (treasury, ops) = (test_account(1), test_account(2));
+        let rule = |account: &Account, direction, threshold| BalanceAlertRule {
+            account: account.clone(),
+            token_id: token,
+            direction,
+            threshold,
+            notify: canister(9),
+        };
+
+        runtime::set_caller(controller);
+        assert!(add_balance_alert(BalanceAlertRule { notify: test_account(3).owner, ..rule(&treasury, AlertDirection::Above, 100) }).is_err());
+        assert!(add_balance_alert(BalanceAlertRule { token_id: [9; 32], ..rule(&treasury, AlertDirection::Above, 100) }).is_err());
+        let above = add_balance_alert(rule(&treasury, AlertDirection::Above, 100)).unwrap();
+        let below = add_balance_alert(rule(&ops, AlertDirection::Below, 50)).unwrap();
+        assert_eq!(list_balance_alerts().unwrap().len(), 2);
+        runtime::set_caller(test_account(3).owner);
+        assert!(add_balance_alert(rule(&ops, AlertDirection::Above, 1)).is_err());
+
+        state::seed_balance(token, treasury.to_key(), 100);
+        state::seed_balance(token, ops.to_key(), 80);
+        assert!(runtime::take_notifications().is_empty());
+
+        state::seed_balance(token, treasury.to_key(), 150);
+        state::seed_balance(token, treasury.to_key(), 200);
+        state::seed_balance(token, ops.to_key(), 40);
+        state::seed_balance(token, ops.to_key(), 0);
+        let sent = runtime::take_notifications();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|(target, method, _)| *target == canister(9) && method == BALANCE_ALERT_METHOD));
+        let event: BalanceAlertEvent = candid::decode_one(&sent[0].2).unwrap();
+        assert_eq!((event.alert_id, event.account, event.previous_balance, event.balance), (above, treasury.clone(), 100, 150));
+        let event: BalanceAlertEvent = candid::decode_one(&sent[1].2).unwrap();
+        assert_eq!((event.alert_id, event.direction, event.balance), (below, AlertDirection::Below, 40));
+
+        // Re-arms once the balance is back on the near side.
+        state::seed_balance(token, treasury.to_key(), 10);
+        state::seed_balance(token, treasury.to_key(), 101);
+        assert_eq!(runtime::take_notifications().len(), 1);
+
+        runtime::set_caller(controller);
+        remove_balance_alert(above).unwrap();
+        assert!(remove_balance_alert(above).is_err());
+        state::seed_balance(token, treasury.to_key(), 10);
+        state::seed_balance(token, treasury.to_key(), 500);
+        assert!(runtime::take_notifications().is_empty());
+        assert_eq!(list_balance_alerts().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_recovery_initiation_notifies_all_subscribers() {
+        use crate::recovery::{configure_recovery, initiate_recovery, RecoveryConfig, RecoveryStatus};
+
+        let controller = test_account(0xFE).owner;
+        let rescuer = test_account(7).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 10);
+        subscribe_as(canister(1), Some(token)).unwrap();
+
+        runtime::set_caller(controller);
+        configure_recovery(Some(RecoveryConfig { recovery_principal: rescuer, delay_ns: crate::types::constants::NANOS_PER_DAY })).unwrap();
+        runtime::set_caller(rescuer);
+        initiate_recovery().unwrap();
+
+        let sent = runtime::take_notifications();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, RECOVERY_INITIATED_METHOD);
+        let status: RecoveryStatus = candid::decode_one(&sent[0].2).unwrap();
+        assert_eq!(status.recovery_principal, rescuer);
+    }
+}
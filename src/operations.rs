@@ -1,7 +1,15 @@
-use crate::types::{Account, TokenId, derive_token_id};
+use crate::types::{Account, AccountKey, Memo, OpKind, ScheduledFee, Subaccount, TokenId, derive_token_id, derive_token_id_v2};
 use crate::state;
-use crate::validation::{validate_transfer_params, validate_account, validate_token_id, ValidationError};
-use crate::transaction::StoredTxV1;
+use crate::runtime;
+use crate::trace;
+use crate::failures::{self, FailedOperation};
+use crate::context::{ContextError, OperationContext};
+use crate::rounding::RoundingMode;
+use crate::validation::{validate_transfer_params, validate_account, validate_token_economics, validate_token_namespace, check_args_version, ValidationError};
+use crate::transaction::{StoredTxV1, OP_BURN, OP_MINT, OP_TRANSFER};
+use crate::fees::{self, FeeLeg, FeeLegError, Postings};
+#[cfg(feature = "notifications")]
+use crate::notifications::{publish_metadata_change, MetadataChangeKind};
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 use num_traits::cast::ToPrimitive;
@@ -14,16 +22,104 @@ pub enum TransferResult {
 }
 
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TxReceipt {
+    pub tx_index: u64,
+    pub token_seq: u64,
+}
+
+
+// A TxReceipt with the amount moved, which differs from the requested amount
+// for UpTo transfers.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransferReceipt {
+    pub tx_index: u64,
+    pub token_seq: u64,
+    pub amount: candid::Nat,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TransferResultV2 {
+    Ok(TransferReceipt),
+    Err(TransferError),
+}
+
+
+// Every transaction appended since token sequences were introduced carries one.
+pub(crate) fn tx_receipt(tx_index: u64) -> TxReceipt {
+    let token_seq = state::get_transaction(tx_index)
+        .and_then(|tx| tx.get_token_seq())
+        .expect("Appended transactions always carry a token sequence");
+    TxReceipt { tx_index, token_seq }
+}
+
+
+impl From<TransferResult> for TransferResultV2 {
+    fn from(result: TransferResult) -> Self {
+        match result {
+            TransferResult::Ok(tx_index) => {
+                let TxReceipt { tx_index, token_seq } = tx_receipt(tx_index);
+                let amount = state::get_transaction(tx_index).map_or(0, |tx| tx.get_amount());
+                TransferResultV2::Ok(TransferReceipt { tx_index, token_seq, amount: candid::Nat::from(amount) })
+            }
+            TransferResult::Err(e) => TransferResultV2::Err(e),
+        }
+    }
+}
+
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub enum TransferError {
     BadFee { expected_fee: candid::Nat },
     BadBurn { min_burn_amount: candid::Nat },
     InsufficientFunds { balance: candid::Nat },
+    InsufficientFeeFunds { fee_token: TokenId, balance: candid::Nat },
     TooOld,
     CreatedInFuture { ledger_time: u64 },
     Duplicate { duplicate_of: u64 },
     TemporarilyUnavailable,
     GenericError { error_code: candid::Nat, message: String },
+    RecipientRefused,
+    UnsupportedArgsVersion { supported: u16 },
+    OperationPaused { op: OpKind },
+    AllowanceExpired { expires_at: u64 },
+    HolderLimitReached { max_holders: u64 },
+}
+
+impl From<FeeLegError> for TransferError {
+    fn from(err: FeeLegError) -> Self {
+        match err {
+            FeeLegError::InsufficientFunds { fee_token, balance } => TransferError::InsufficientFeeFunds {
+                fee_token,
+                balance: candid::Nat::from(balance),
+            },
+            FeeLegError::Overflow => TransferError::GenericError {
+                error_code: candid::Nat::from(500u64),
+                message: "Fee recipient balance overflow".to_string(),
+            },
+            FeeLegError::HolderLimitReached { max_holders } => TransferError::HolderLimitReached { max_holders },
+        }
+    }
+}
+
+impl From<state::MetadataError> for TransferError {
+    fn from(err: state::MetadataError) -> Self {
+        match err {
+            state::MetadataError::NotFound => TransferError::GenericError {
+                error_code: candid::Nat::from(404u64),
+                message: "Token not found".to_string(),
+            },
+            state::MetadataError::Corrupted(_) => TransferError::GenericError {
+                error_code: candid::Nat::from(503u64),
+                message: "Token metadata is corrupted; the token is quarantined".to_string(),
+            },
+            state::MetadataError::TokenImmutable => TransferError::GenericError {
+                error_code: candid::Nat::from(403u64),
+                message: err.to_string(),
+            },
+        }
+    }
 }
 
 impl From<ValidationError> for TransferError {
@@ -35,33 +131,115 @@ impl From<ValidationError> for TransferError {
     }
 }
 
+impl From<ContextError> for TransferError {
+    fn from(err: ContextError) -> Self {
+        match err {
+            ContextError::Maintenance => TransferError::TemporarilyUnavailable,
+            ContextError::Paused(op) => TransferError::OperationPaused { op },
+            ContextError::ImportPending => TransferError::GenericError {
+                error_code: candid::Nat::from(409u64),
+                message: err.to_string(),
+            },
+            ContextError::InvalidToken(e) => e.into(),
+            ContextError::Metadata(e) => e.into(),
+            ContextError::TooOld => TransferError::TooOld,
+            ContextError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            ContextError::BadFee { expected_fee } => TransferError::BadFee { expected_fee: candid::Nat::from(expected_fee) },
+        }
+    }
+}
+
+impl From<state::MovementError> for TransferError {
+    fn from(err: state::MovementError) -> Self {
+        match err {
+            state::MovementError::InsufficientBalance { balance, .. } => TransferError::InsufficientFunds {
+                balance: candid::Nat::from(balance),
+            },
+            state::MovementError::HolderLimitReached { max_holders } => TransferError::HolderLimitReached { max_holders },
+            other => generic_error(500, other.to_string()),
+        }
+    }
+}
+
+
+// For failures of mints and burns without a variant of their own.
+pub(crate) fn generic_error(error_code: u64, message: impl Into<String>) -> TransferError {
+    TransferError::GenericError { error_code: candid::Nat::from(error_code), message: message.into() }
+}
+
+
+// UpTo sends what the balance allows, less the fee, when it cannot cover the
+// requested amount, so a sweep needs no balance query and cannot race a debit.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AmountMode {
+    #[default]
+    Exact,
+    UpTo,
+}
+
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Icrc151TransferArgs {
     pub token_id: TokenId,
-    pub from_subaccount: Option<Vec<u8>>,
+    pub from_subaccount: Option<Subaccount>,
     pub to: Account,
     pub amount: candid::Nat,
     pub fee: Option<candid::Nat>,
-    pub memo: Option<Vec<u8>>,
+    pub memo: Option<Memo>,
     pub created_at_time: Option<u64>,
+    // Shows the memo only to the sender, the recipient and controllers.
+    pub private_memo: Option<bool>,
+    // Exact when absent.
+    pub amount_mode: Option<AmountMode>,
+    // Has one of the sender's fee sponsorships pay the fee; see sponsorship.rs.
+    pub use_sponsorship: Option<bool>,
+    // See validation::ARGS_VERSION.
+    pub args_version: Option<u16>,
 }
 
 
 #[ic_cdk::update]
 pub fn transfer(args: Icrc151TransferArgs) -> TransferResult {
-    let caller = ic_cdk::caller();
+    state::record_legacy_call("transfer");
+    handle_transfer(args)
+}
+
+
+fn handle_transfer(args: Icrc151TransferArgs) -> TransferResult {
+    let watch = failures::watch(FailedOperation::Transfer, args.token_id, &args, Some(&args.amount));
+    let result = transfer_with_args(args);
+    if let TransferResult::Err(error) = &result {
+        failures::record(watch, error);
+    }
+    result
+}
+
+
+fn transfer_with_args(args: Icrc151TransferArgs) -> TransferResult {
+    match transfer_checked(args, None) {
+        Ok(tx_index) => TransferResult::Ok(tx_index),
+        Err(err) => TransferResult::Err(err),
+    }
+}
+
+
+// `script_position` is set for the operations of execute_script.
+pub(crate) fn transfer_checked(args: Icrc151TransferArgs, script_position: Option<u32>) -> Result<u64, TransferError> {
+    if let Err(supported) = check_args_version(args.args_version) {
+        return Err(TransferError::UnsupportedArgsVersion { supported });
+    }
+    let caller = runtime::caller();
     
 
     let from_account = Account {
         owner: caller,
-        subaccount: args.from_subaccount.clone(),
+        subaccount: args.from_subaccount.map(|s| s.to_vec()),
     };
     
 
     let amount = match args.amount.0.to_u128() {
         Some(a) => a,
-        None => return TransferResult::Err(TransferError::GenericError {
+        None => return Err(TransferError::GenericError {
             error_code: candid::Nat::from(400u64),
             message: "Amount exceeds maximum value (u128::MAX)".to_string(),
         }),
@@ -70,7 +248,7 @@ pub fn transfer(args: Icrc151TransferArgs) -> TransferResult {
     let fee = match args.fee.as_ref() {
         Some(f) => match f.0.to_u128() {
             Some(val) => Some(val),
-            None => return TransferResult::Err(TransferError::GenericError {
+            None => return Err(TransferError::GenericError {
                 error_code: candid::Nat::from(400u64),
                 message: "Fee exceeds maximum value (u128::MAX)".to_string(),
             }),
@@ -78,22 +256,24 @@ pub fn transfer(args: Icrc151TransferArgs) -> TransferResult {
         None => None,
     };
 
-    match transfer_internal(
-        args.token_id,
+    let ctx = OperationContext::new(OpKind::Transfer, args.token_id, caller, args.created_at_time)?
+        .in_script(script_position)
+        .with_sponsorship(args.use_sponsorship.unwrap_or(false));
+
+    execute_transfer(
+        &ctx,
         from_account,
         args.to,
         amount,
         fee,
         args.memo.as_deref(),
-        args.created_at_time,
-    ) {
-        Ok(tx_index) => TransferResult::Ok(tx_index),
-        Err(err) => TransferResult::Err(err),
-    }
+        TransferMode::User(args.amount_mode.unwrap_or_default()),
+        args.private_memo.unwrap_or(false),
+    )
 }
 
 
-fn transfer_internal(
+pub(crate) fn transfer_internal(
     token_id: TokenId,
     from: Account,
     to: Account,
@@ -102,51 +282,111 @@ fn transfer_internal(
     memo: Option<&[u8]>,
     created_at_time: Option<u64>,
 ) -> Result<u64, TransferError> {
-
-    validate_token_id(&token_id)?;
+    let ctx = OperationContext::new(OpKind::Transfer, token_id, from.owner, created_at_time)?;
+    execute_transfer(&ctx, from, to, amount, fee, memo, TransferMode::User(AmountMode::Exact), false)
+}
 
 
-    let metadata = state::get_token_metadata(token_id)
-        .ok_or(TransferError::GenericError {
-            error_code: candid::Nat::from(404u64),
-            message: "Token not found".to_string(),
-        })?;
+#[derive(Clone, Copy, PartialEq)]
+enum TransferMode {
+    User(AmountMode),
+    // Ledger-driven sweep between the caller's own subaccounts: no deduplication,
+    // and the fee is waived when the token allows fee-free consolidation.
+    Consolidation,
+}
 
-    let expected_fee = metadata.fee;
-    let fee_amount = fee.unwrap_or(expected_fee);
 
+impl TransferMode {
+    fn is_user(self) -> bool {
+        matches!(self, TransferMode::User(_))
+    }
 
-    if let Some(provided_fee) = fee {
-        if provided_fee != expected_fee {
-            return Err(TransferError::BadFee {
-                expected_fee: candid::Nat::from(expected_fee),
-            });
+    // The amount to move given the sender's balance. UpTo falls back to
+    // everything the balance covers after `fee`; when that is nothing the
+    // requested amount stands and the balance check refuses it.
+    fn effective_amount(self, requested: u128, balance: u128, fee: u128) -> u128 {
+        let covered = requested.checked_add(fee).is_some_and(|total| total <= balance);
+        match self {
+            TransferMode::User(AmountMode::UpTo) if !covered && balance > fee => balance - fee,
+            _ => requested,
         }
     }
+}
 
-    validate_transfer_params(&from, &to, amount, Some(fee_amount), memo)?;
-    
 
-    let timestamp = created_at_time.unwrap_or_else(|| ic_cdk::api::time());
-    if let Some(provided_time) = created_at_time {
-        let current_time = ic_cdk::api::time();
+#[allow(clippy::too_many_arguments)]
+fn execute_transfer(
+    ctx: &OperationContext,
+    from: Account,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<&[u8]>,
+    mode: TransferMode,
+    private_memo: bool,
+) -> Result<u64, TransferError> {
+    if !trace::is_enabled() {
+        return apply_transfer(ctx, from, to, amount, fee, memo, mode, private_memo);
+    }
+    let accounts = [from.to_key(), to.to_key()];
+    let result = apply_transfer(ctx, from, to, amount, fee, memo, mode, private_memo);
+    let op = if mode == TransferMode::Consolidation { "consolidate" } else { "transfer" };
+    trace::record(op, ctx.token_id, &accounts, &[amount], &result);
+    result
+}
 
-        if provided_time > current_time + crate::types::constants::MAX_FUTURE_DRIFT {
-            return Err(TransferError::CreatedInFuture { ledger_time: current_time });
-        }
 
-        if provided_time < current_time.saturating_sub(crate::types::constants::MAX_PAST_DRIFT) {
-            return Err(TransferError::TooOld);
-        }
+#[allow(clippy::too_many_arguments)]
+fn apply_transfer(
+    ctx: &OperationContext,
+    from: Account,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<&[u8]>,
+    mode: TransferMode,
+    private_memo: bool,
+) -> Result<u64, TransferError> {
+    let token_id = ctx.token_id;
+    let fee_amount = ctx.resolve_fee(consolidation_fee(&ctx.metadata, mode), fee)?;
+    validate_transfer_params(&from, &to, amount, Some(fee_amount), memo)?;
+    if mode.is_user() {
+        crate::memo::validate_structured_memo(&ctx.metadata, memo)
+            .map_err(|message| TransferError::GenericError { error_code: candid::Nat::from(400u64), message })?;
     }
-    
+    let timestamp = ctx.timestamp;
+
 
     let from_key = from.to_key();
     let to_key = to.to_key();
-    
+    let fee_recipient_key = ctx.fee_recipient_key();
+
+    // Keyed on the amount as submitted, before any UpTo adjustment.
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(
+        OP_TRANSFER,
+        from.owner,
+        token_id,
+        timestamp,
+        memo,
+        Some(to_key),
+        amount,
+    ));
+
+    if mode.is_user() && ctx.metadata.is_burn_address(&to_key) {
+        return burn_to_address(token_id, &from, &to, amount, mode, timestamp, memo, private_memo, dedup_key);
+    }
+
+    let fee_token = ctx.fee_token();
+    let sponsorship = match ctx.use_sponsorship && mode.is_user() {
+        true => crate::sponsorship::find_cover(ctx, from_key, fee_amount)?,
+        false => None,
+    };
+    let primary_fee = if fee_token == token_id && sponsorship.is_none() { fee_amount } else { 0 };
+
 
-    let from_balance = state::get_balance(token_id, from_key);
-    let total_amount = amount.checked_add(fee_amount)
+    let from_balance = state::get_spendable_balance(token_id, from_key);
+    let amount = mode.effective_amount(amount, from_balance, primary_fee);
+    let total_amount = amount.checked_add(primary_fee)
         .ok_or(TransferError::GenericError {
             error_code: candid::Nat::from(400u64),
             message: "Amount + fee overflow".to_string(),
@@ -158,46 +398,56 @@ fn transfer_internal(
         });
     }
 
-    let dedup_key = state::compute_dedup_key(
-        from.owner,
-        token_id,
-        timestamp,
-        memo,
-    );
+    let fee_leg = if fee_token != token_id && fee_amount > 0 {
+        Some(FeeLeg::prepare(fee_token, from_key, fee_recipient_key, fee_amount)?)
+    } else {
+        None
+    };
 
-    if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
-        return Err(TransferError::Duplicate {
-            duplicate_of: duplicate_tx_index,
-        });
+
+    if mode.is_user() {
+        if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
+            return Err(TransferError::Duplicate {
+                duplicate_of: duplicate_tx_index,
+            });
+        }
     }
 
-    let to_balance = state::get_balance(token_id, to_key);
-    let new_to_balance = to_balance.checked_add(amount)
-        .ok_or(TransferError::GenericError {
-            error_code: candid::Nat::from(500u64),
-            message: "Recipient balance overflow".to_string(),
-        })?;
+    // Consolidation only moves funds between the caller's own accounts, and a
+    // self-transfer brings the account nothing new.
+    let self_transfer = from_key == to_key;
+    if mode.is_user() && !self_transfer && !crate::accounts::accepts_incoming(token_id, to_key, false) {
+        return Err(TransferError::RecipientRefused);
+    }
 
-    let fee_recipient_key = metadata.fee_recipient.to_key();
-    let fee_balance = state::get_balance(token_id, fee_recipient_key);
-    let new_fee_balance = if fee_amount > 0 {
-        fee_balance.checked_add(fee_amount)
+    if !self_transfer {
+        state::get_balance(token_id, to_key).checked_add(amount)
             .ok_or(TransferError::GenericError {
                 error_code: candid::Nat::from(500u64),
-                message: "Fee recipient balance overflow".to_string(),
-            })?
-    } else {
-        fee_balance
-    };
+                message: "Recipient balance overflow".to_string(),
+            })?;
+    }
+
+    // Netted, so a fee recipient sending or receiving is written once.
+    let mut postings = Postings::new(token_id);
+    postings.debit(from_key, amount);
+    postings.credit(to_key, amount);
+    postings.post_fee(from_key, fee_recipient_key, primary_fee);
+    if let Some(sponsorship) = &sponsorship {
+        postings.post_fee(sponsorship.sponsor.to_key(), fee_recipient_key, fee_amount);
+    }
+    postings.check_credits()?;
 
-    state::set_balance(token_id, from_key, from_balance - total_amount);
-    state::set_balance(token_id, to_key, new_to_balance);
-    if fee_amount > 0 {
-        state::set_balance(token_id, fee_recipient_key, new_fee_balance);
+    if let Some(sponsorship) = &sponsorship {
+        state::draw_sponsorship(sponsorship.id, fee_amount);
+    }
+    postings.apply();
+    if let Some(leg) = fee_leg {
+        leg.apply();
     }
 
 
-    let tx = StoredTxV1::new_transfer(
+    let mut tx = StoredTxV1::new_transfer(
         token_id,
         from_key,
         to_key,
@@ -206,183 +456,818 @@ fn transfer_internal(
         timestamp,
         memo,
     );
+    if fee_token != token_id {
+        tx = tx.with_fee_token(fee_token);
+    }
+    if let Some(sponsorship) = &sponsorship {
+        tx = tx.with_fee_sponsor(sponsorship.sponsor.to_key());
+    }
 
-    let tx_index = state::add_transaction(tx);
+    let tx_index = match memo {
+        Some(memo) if private_memo => state::add_transaction_with_private_memo(tx, memo, &[from.owner, to.owner]),
+        _ => state::add_transaction_with_memo(tx, memo),
+    };
     state::increment_tx_count();
 
 
-    if let Some(memo_bytes) = memo {
-        if memo_bytes.len() > 32 {
-            state::store_extended_memo(tx_index, memo_bytes.to_vec());
-        }
+    if mode.is_user() {
+        state::record_transaction_dedup(dedup_key, tx_index);
     }
 
-
-    state::record_transaction_dedup(dedup_key, tx_index);
-
     Ok(tx_index)
 }
 
 
-#[ic_cdk::update]
-pub fn create_token(
-    name: String,
-    symbol: String,
-    decimals: u8,
-    initial_supply: Option<candid::Nat>,
-    fee: Option<candid::Nat>,
-    logo: Option<String>,
-    description: Option<String>,
-) -> Result<TokenId, String> {
-
-    state::require_controller()?;
-
-
-    if name.is_empty() || name.len() > 255 {
-        return Err("Invalid token name length".to_string());
+// A transfer to one of the token's burn addresses burns the amount instead of
+// crediting an account nobody can spend from. Like other burns it pays no fee.
+// A user transfer is deduplicated as the transfer it was submitted as.
+#[allow(clippy::too_many_arguments)]
+fn burn_to_address(
+    token_id: TokenId,
+    from: &Account,
+    to: &Account,
+    amount: u128,
+    mode: TransferMode,
+    timestamp: u64,
+    memo: Option<&[u8]>,
+    private_memo: bool,
+    dedup_key: state::DedupKey,
+) -> Result<u64, TransferError> {
+    let from_key = from.to_key();
+    if mode.is_user() {
+        if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
+            return Err(TransferError::Duplicate { duplicate_of: duplicate_tx_index });
+        }
     }
-    if symbol.is_empty() || symbol.len() > 32 {
-        return Err("Invalid token symbol length".to_string());
+
+    let balance = state::get_spendable_balance(token_id, from_key);
+    let amount = mode.effective_amount(amount, balance, 0);
+    if balance < amount {
+        return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(balance) });
     }
-    if decimals > 18 {
-        return Err("Decimals cannot exceed 18".to_string());
+    burn_movement(token_id, from_key, amount)?;
+
+    let tx = StoredTxV1::new_burn(token_id, from_key, amount, timestamp, memo).with_burn_target(to.to_key());
+    let tx_index = match memo {
+        Some(memo) if private_memo => state::add_transaction_with_private_memo(tx, memo, &[from.owner, to.owner]),
+        _ => state::add_transaction_with_memo(tx, memo),
+    };
+    state::increment_tx_count();
+    if mode.is_user() {
+        state::record_transaction_dedup(dedup_key, tx_index);
     }
 
+    Ok(tx_index)
+}
 
-    let nonce = state::next_token_nonce();
-    let ledger_principal = ic_cdk::id();
-    let token_id = derive_token_id(ledger_principal, nonce);
 
+pub(crate) fn burn_movement(token_id: TokenId, from_key: crate::types::AccountKey, amount: u128) -> Result<(), TransferError> {
+    let supply_delta = i128::try_from(amount).map_err(|_| generic_error(500, "Total supply underflow"))?;
+    Ok(state::apply_movements(token_id, &[(from_key, amount)], &[], -supply_delta)?)
+}
 
-    let fee_amount = match fee {
-        Some(f) => f.0.to_u128().ok_or("Fee exceeds maximum value (u128::MAX)".to_string())?,
-        None => 10_000,
-    };
 
+fn consolidation_fee(metadata: &crate::types::StoredTokenMetadata, mode: TransferMode) -> u128 {
+    if mode == TransferMode::Consolidation && metadata.fee_free_consolidation.unwrap_or(false) {
+        0
+    } else {
+        metadata.fee
+    }
+}
 
-    let controller = state::get_controller().ok_or("No controller set")?;
-    let fee_recipient = Account {
-        owner: controller,
-        subaccount: None,
-    };
 
-    let metadata = crate::types::StoredTokenMetadata {
-        name,
-        symbol,
-        decimals,
-        total_supply: 0,
-        fee: fee_amount,
-        fee_recipient,
-        logo,
-        description,
-        created_at: ic_cdk::api::time(),
-        controller,
-    };
+pub const MAX_CONSOLIDATION_SUBACCOUNTS: usize = 50;
 
-    state::register_token(token_id, metadata);
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ConsolidationOutcome {
+    Moved { tx_index: u64, amount: candid::Nat, fee: candid::Nat },
+    Empty,
+    BelowFee { balance: candid::Nat, fee: candid::Nat },
+    SameAsDestination,
+    Failed(TransferError),
+}
 
-    if let Some(supply) = initial_supply {
-        let supply_amount = supply.0.to_u128()
-            .ok_or("Initial supply exceeds maximum value (u128::MAX)".to_string())?;
-        if supply_amount > 0 {
-            let controller = state::get_controller().ok_or("No controller set")?;
-            let controller_account = Account {
-                owner: controller,
-                subaccount: None,
-            };
-            
-            mint_internal(token_id, controller_account, supply_amount, None, None)?;
-        }
-    }
-    
-    Ok(token_id)
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConsolidationEntry {
+    pub from_subaccount: Vec<u8>,
+    pub outcome: ConsolidationOutcome,
 }
 
 
+// Sweeps the caller's subaccounts into one, as individual transfer transactions.
+// Each entry pays at most one fee, taken out of the moved amount.
 #[ic_cdk::update]
-pub fn mint_tokens(
+pub fn consolidate_subaccounts(
     token_id: TokenId,
-    to: Account,
-    amount: candid::Nat,
-    memo: Option<Vec<u8>>,
-) -> Result<u64, String> {
-
-    state::require_controller()?;
-
-    let amount_u128 = amount.0.to_u128()
-        .ok_or("Amount exceeds maximum value (u128::MAX)".to_string())?;
-    mint_internal(token_id, to, amount_u128, memo.as_deref(), None)
+    from_subaccounts: Vec<Subaccount>,
+    to_subaccount: Option<Subaccount>,
+) -> Result<Vec<ConsolidationEntry>, String> {
+    let watch = failures::watch(FailedOperation::Consolidate, token_id, &(token_id, &from_subaccounts, &to_subaccount), None);
+    failures::track(watch, consolidate_checked(token_id, from_subaccounts, to_subaccount))
 }
 
 
-fn mint_internal(
+fn consolidate_checked(
     token_id: TokenId,
-    to: Account,
-    amount: u128,
-    memo: Option<&[u8]>,
-    created_at_time: Option<u64>,
-) -> Result<u64, String> {
+    from_subaccounts: Vec<Subaccount>,
+    to_subaccount: Option<Subaccount>,
+) -> Result<Vec<ConsolidationEntry>, String> {
+    if from_subaccounts.is_empty() {
+        return Err("At least one source subaccount is required".to_string());
+    }
+    if from_subaccounts.len() > MAX_CONSOLIDATION_SUBACCOUNTS {
+        return Err(format!("Too many subaccounts (max {})", MAX_CONSOLIDATION_SUBACCOUNTS));
+    }
 
-    validate_token_id(&token_id).map_err(|e| e.to_string())?;
+    let ctx = OperationContext::new(OpKind::Transfer, token_id, runtime::caller(), None)?;
+    let to = Account { owner: ctx.caller, subaccount: to_subaccount.map(|s| s.to_vec()) };
     validate_account(&to).map_err(|e| e.to_string())?;
-    
-    if amount == 0 {
-        return Err("Amount must be greater than 0".to_string());
-    }
-    
-    let timestamp = created_at_time.unwrap_or_else(|| ic_cdk::api::time());
+    let fee = consolidation_fee(&ctx.metadata, TransferMode::Consolidation);
     let to_key = to.to_key();
 
+    Ok(from_subaccounts
+        .into_iter()
+        .map(|subaccount| {
+            let from = Account { owner: ctx.caller, subaccount: Some(subaccount.to_vec()) };
+            let outcome = consolidate_one(&ctx, from, &to, to_key, fee);
+            ConsolidationEntry { from_subaccount: subaccount.to_vec(), outcome }
+        })
+        .collect())
+}
 
-    let dedup_key = state::compute_dedup_key(
-        to.owner,
-        token_id,
-        timestamp,
-        memo,
-    );
 
-    if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
-        return Err(format!("Duplicate mint transaction, original tx_index: {}", duplicate_tx_index));
+fn consolidate_one(
+    ctx: &OperationContext,
+    from: Account,
+    to: &Account,
+    to_key: crate::types::AccountKey,
+    fee: u128,
+) -> ConsolidationOutcome {
+    if let Err(e) = validate_account(&from) {
+        return ConsolidationOutcome::Failed(e.into());
+    }
+    if from.to_key() == to_key {
+        return ConsolidationOutcome::SameAsDestination;
     }
 
+    let balance = state::get_spendable_balance(ctx.token_id, from.to_key());
+    if balance == 0 {
+        return ConsolidationOutcome::Empty;
+    }
 
-    let current_balance = state::get_balance(token_id, to_key);
-    let new_balance = current_balance.checked_add(amount)
-        .ok_or("Balance overflow")?;
+    let primary_fee = if ctx.fee_in_token() { fee } else { 0 };
+    if balance <= primary_fee {
+        return ConsolidationOutcome::BelowFee {
+            balance: candid::Nat::from(balance),
+            fee: candid::Nat::from(fee),
+        };
+    }
 
-    state::set_balance(token_id, to_key, new_balance);
+    let amount = balance - primary_fee;
+    match execute_transfer(ctx, from, to.clone(), amount, Some(fee), None, TransferMode::Consolidation, false) {
+        Ok(tx_index) => ConsolidationOutcome::Moved {
+            tx_index,
+            amount: candid::Nat::from(amount),
+            fee: candid::Nat::from(fee),
+        },
+        Err(e) => ConsolidationOutcome::Failed(e),
+    }
+}
 
 
-    if let Some(metadata) = state::get_token_metadata(token_id) {
-        let new_supply = metadata.total_supply.checked_add(amount)
-            .ok_or("Total supply overflow")?;
-        state::update_total_supply(token_id, new_supply)?;
-    }
+pub const MAX_DISBURSE_PAYOUTS: usize = 100;
 
 
-    let tx = StoredTxV1::new_mint(
-        token_id,
-        to_key,
-        amount,
-        timestamp,
-        memo,
-    );
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Payout {
+    pub to: Account,
+    pub amount: candid::Nat,
+    pub memo: Option<Memo>,
+}
 
-    let tx_index = state::add_transaction(tx);
-    state::increment_tx_count();
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum DisburseError {
+    // Nothing was applied because of this payout.
+    InvalidPayout { payout_index: u32, error: TransferError },
+    // Nothing was applied because of the disbursement as a whole.
+    Rejected(TransferError),
+}
 
-    if let Some(memo_bytes) = memo {
-        if memo_bytes.len() > 32 {
-            state::store_extended_memo(tx_index, memo_bytes.to_vec());
-        }
+impl From<TransferError> for DisburseError {
+    fn from(error: TransferError) -> Self {
+        DisburseError::Rejected(error)
     }
+}
 
 
-    state::record_transaction_dedup(dedup_key, tx_index);
-
-    Ok(tx_index)
+struct CheckedPayout {
+    to_key: crate::types::AccountKey,
+    amount: u128,
+    memo: Option<Vec<u8>>,
+    dedup_key: state::DedupKey,
+}
+
+
+// Pays many recipients from one account, all or nothing. The sender's balance
+// must cover every amount plus one fee per payout before anything is written;
+// each payout is then its own transfer transaction, sharing one timestamp.
+#[ic_cdk::update]
+pub fn disburse(
+    token_id: TokenId,
+    from_subaccount: Option<Subaccount>,
+    payouts: Vec<Payout>,
+    fee: Option<candid::Nat>,
+    created_at_time: Option<u64>,
+) -> Result<Vec<u64>, DisburseError> {
+    let watch = failures::watch(
+        FailedOperation::Disburse, token_id, &(token_id, &from_subaccount, &payouts, &fee, created_at_time), None,
+    );
+    let result = OperationContext::new(OpKind::Disburse, token_id, runtime::caller(), created_at_time)
+        .map_err(|e| DisburseError::from(TransferError::from(e)))
+        .and_then(|ctx| {
+            let from = Account { owner: ctx.caller, subaccount: from_subaccount.map(|s| s.to_vec()) };
+            disburse_account(&ctx, from, payouts, fee)
+        });
+    failures::track(watch, result)
+}
+
+
+// As disburse, from an account of the principal that granted the caller
+// can_disburse for the token. The owner itself may call it too.
+#[ic_cdk::update]
+pub fn disburse_from(
+    token_id: TokenId,
+    from: Account,
+    payouts: Vec<Payout>,
+    fee: Option<candid::Nat>,
+    created_at_time: Option<u64>,
+) -> Result<Vec<u64>, DisburseError> {
+    let ctx = OperationContext::new(OpKind::Disburse, token_id, runtime::caller(), created_at_time).map_err(TransferError::from)?;
+    if ctx.caller != from.owner && crate::api_access::disburses_for(token_id, &ctx.caller) != Some(from.owner) {
+        return Err(TransferError::GenericError {
+            error_code: candid::Nat::from(403u64),
+            message: "Caller may not disburse from this account".to_string(),
+        }.into());
+    }
+    disburse_account(&ctx, from, payouts, fee)
+}
+
+
+fn disburse_account(
+    ctx: &OperationContext,
+    from: Account,
+    payouts: Vec<Payout>,
+    fee: Option<candid::Nat>,
+) -> Result<Vec<u64>, DisburseError> {
+    if !trace::is_enabled() {
+        return apply_disbursement(ctx, &from, payouts, fee);
+    }
+    let mut accounts = vec![from.to_key()];
+    accounts.extend(payouts.iter().map(|p| p.to.to_key()));
+    let amounts: Vec<u128> = payouts.iter().map(|p| p.amount.0.to_u128().unwrap_or(u128::MAX)).collect();
+    let result = apply_disbursement(ctx, &from, payouts, fee);
+    let first_tx = match &result {
+        Ok(tx_indices) => Ok(tx_indices[0]),
+        Err(e) => Err(e),
+    };
+    trace::record("disburse", ctx.token_id, &accounts, &amounts, &first_tx);
+    result
+}
+
+
+fn apply_disbursement(
+    ctx: &OperationContext,
+    from: &Account,
+    payouts: Vec<Payout>,
+    fee: Option<candid::Nat>,
+) -> Result<Vec<u64>, DisburseError> {
+    let token_id = ctx.token_id;
+    let rejected = |message: String| TransferError::GenericError { error_code: candid::Nat::from(400u64), message };
+    if payouts.is_empty() {
+        return Err(rejected("At least one payout is required".to_string()).into());
+    }
+    if payouts.len() > MAX_DISBURSE_PAYOUTS {
+        return Err(rejected(format!("Too many payouts (max {})", MAX_DISBURSE_PAYOUTS)).into());
+    }
+
+    validate_account(from).map_err(TransferError::from)?;
+    let fee = match fee {
+        Some(fee) => Some(fee.0.to_u128().ok_or_else(|| rejected("Fee exceeds maximum value (u128::MAX)".to_string()))?),
+        None => None,
+    };
+    let expected_fee = ctx.standard_fee(fee).map_err(TransferError::from)?;
+    let timestamp = ctx.timestamp;
+
+    let from_key = from.to_key();
+    let mut checked = Vec::with_capacity(payouts.len());
+    let mut total_amount: u128 = 0;
+    for (payout_index, payout) in payouts.into_iter().enumerate() {
+        let payout_index = payout_index as u32;
+        let invalid = |error: TransferError| DisburseError::InvalidPayout { payout_index, error };
+        let amount = payout.amount.0.to_u128()
+            .ok_or_else(|| invalid(rejected("Amount exceeds maximum value (u128::MAX)".to_string())))?;
+        let memo = payout.memo.map(|m| m.0);
+        validate_transfer_params(from, &payout.to, amount, Some(expected_fee), memo.as_deref())
+            .map_err(|e| invalid(e.into()))?;
+        // A payout back to the sender only burns a fee; unlike a transfer it is
+        // taken for a mistake.
+        if payout.to.canonical_eq(from) {
+            return Err(invalid(rejected("Payouts cannot go to the sending account".to_string())));
+        }
+        crate::memo::validate_structured_memo(&ctx.metadata, memo.as_deref())
+            .map_err(|message| invalid(rejected(message)))?;
+
+        let to_key = payout.to.to_key();
+        if ctx.metadata.is_burn_address(&to_key) {
+            return Err(invalid(rejected("Payouts cannot go to a burn address".to_string())));
+        }
+        if !crate::accounts::accepts_incoming(token_id, to_key, false) {
+            return Err(invalid(TransferError::RecipientRefused));
+        }
+        let dedup_key = state::compute_payout_dedup_key(from.owner, token_id, timestamp, payout_index, memo.as_deref());
+        if let Some(duplicate_of) = state::check_duplicate(dedup_key) {
+            return Err(invalid(TransferError::Duplicate { duplicate_of }));
+        }
+
+        total_amount = total_amount.checked_add(amount)
+            .ok_or_else(|| rejected("Total amount overflow".to_string()))?;
+        checked.push(CheckedPayout { to_key, amount, memo, dedup_key });
+    }
+
+    let fee_token = ctx.fee_token();
+    let total_fee = expected_fee.checked_mul(checked.len() as u128)
+        .ok_or_else(|| rejected("Total fee overflow".to_string()))?;
+    let primary_fee = if fee_token == token_id { expected_fee } else { 0 };
+    let total_debit = total_amount.checked_add(if fee_token == token_id { total_fee } else { 0 })
+        .ok_or_else(|| rejected("Amount + fee overflow".to_string()))?;
+    let from_balance = state::get_spendable_balance(token_id, from_key);
+    if from_balance < total_debit {
+        return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(from_balance) }.into());
+    }
+
+    let fee_recipient_key = ctx.fee_recipient_key();
+    let fee_leg = if fee_token != token_id && total_fee > 0 {
+        Some(FeeLeg::prepare_each(fee_token, from_key, fee_recipient_key, expected_fee, checked.len() as u64).map_err(TransferError::from)?)
+    } else {
+        None
+    };
+
+    let mut postings = Postings::new(token_id);
+    for payout in &checked {
+        postings.debit(from_key, payout.amount);
+        postings.credit(payout.to_key, payout.amount);
+        postings.post_fee(from_key, fee_recipient_key, primary_fee);
+    }
+    postings.check_credits().map_err(|e| match e {
+        state::MovementError::Overflow => TransferError::GenericError {
+            error_code: candid::Nat::from(500u64),
+            message: "Recipient balance overflow".to_string(),
+        },
+        other => other.into(),
+    })?;
+
+    postings.apply();
+    if let Some(leg) = fee_leg {
+        leg.apply();
+    }
+
+    Ok(checked
+        .into_iter()
+        .map(|payout| {
+            let mut tx = StoredTxV1::new_transfer(
+                token_id,
+                from_key,
+                payout.to_key,
+                payout.amount,
+                expected_fee,
+                timestamp,
+                payout.memo.as_deref(),
+            );
+            if fee_token != token_id {
+                tx = tx.with_fee_token(fee_token);
+            }
+            let tx_index = state::add_transaction_with_memo(tx, payout.memo.as_deref());
+            state::increment_tx_count();
+            state::record_transaction_dedup(payout.dedup_key, tx_index);
+            tx_index
+        })
+        .collect())
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CreateTokenArgs {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub initial_supply: Option<candid::Nat>,
+    pub fee: Option<candid::Nat>,
+    pub logo: Option<String>,
+    pub description: Option<String>,
+    pub namespace: Option<Vec<u8>>,
+    // See validation::ARGS_VERSION.
+    pub args_version: Option<u16>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum CreateTokenError {
+    TokenLimitReached { max_tokens: u64 },
+    StableMemoryLimitReached { stable_pages_used: u64, stable_pages_soft_limit: u64 },
+    GenericError { message: String },
+    ExceedsEconomicLimit { field: String, limit: candid::Nat },
+    UnsupportedArgsVersion { supported: u16 },
+    DeprecatedEndpoint { use_instead: String },
+}
+
+impl From<String> for CreateTokenError {
+    fn from(message: String) -> Self {
+        CreateTokenError::GenericError { message }
+    }
+}
+
+impl From<ValidationError> for CreateTokenError {
+    fn from(err: ValidationError) -> Self {
+        match err {
+            ValidationError::ExceedsEconomicLimit { field, limit } => CreateTokenError::ExceedsEconomicLimit {
+                field: field.to_string(),
+                limit: candid::Nat::from(limit),
+            },
+            other => CreateTokenError::GenericError { message: other.to_string() },
+        }
+    }
+}
+
+
+#[ic_cdk::update]
+pub fn create_token(
+    name: String,
+    symbol: String,
+    decimals: u8,
+    initial_supply: Option<candid::Nat>,
+    fee: Option<candid::Nat>,
+    logo: Option<String>,
+    description: Option<String>,
+) -> Result<TokenId, CreateTokenError> {
+    state::record_legacy_call("create_token");
+    state::require_controller()?;
+    crate::queries::check_deprecation("create_token")
+        .map_err(|use_instead| CreateTokenError::DeprecatedEndpoint { use_instead })?;
+
+    // The same path as create_token_with_args, so the two cannot drift apart.
+    create_token_with_args(CreateTokenArgs {
+        name,
+        symbol,
+        decimals,
+        initial_supply,
+        fee,
+        logo,
+        description,
+        namespace: None,
+        args_version: None,
+    })
+}
+
+
+#[ic_cdk::update]
+pub fn create_token_with_args(args: CreateTokenArgs) -> Result<TokenId, CreateTokenError> {
+    state::require_controller()?;
+    check_args_version(args.args_version).map_err(|supported| CreateTokenError::UnsupportedArgsVersion { supported })?;
+    create_token_internal(runtime::caller(), args)
+}
+
+
+pub(crate) fn create_token_internal(creator: candid::Principal, args: CreateTokenArgs) -> Result<TokenId, CreateTokenError> {
+    validate_token_names(&args.name, &args.symbol)?;
+
+    let fee_amount = match args.fee {
+        Some(f) => f.0.to_u128().ok_or("Fee exceeds maximum value (u128::MAX)".to_string())?,
+        None => state::get_default_token_fee(),
+    };
+
+    let supply_amount = match args.initial_supply {
+        Some(supply) => supply.0.to_u128()
+            .ok_or("Initial supply exceeds maximum value (u128::MAX)".to_string())?,
+        None => 0,
+    };
+
+    validate_token_economics(args.decimals, fee_amount, supply_amount, None)?;
+
+    check_token_capacity()?;
+
+
+    let ledger_principal = runtime::canister_id();
+    let token_id = match args.namespace.as_deref() {
+        Some(namespace) => {
+            validate_token_namespace(namespace).map_err(|e| e.to_string())?;
+            derive_token_id_v2(ledger_principal, creator, namespace)
+        }
+        None => derive_token_id(ledger_principal, state::next_token_nonce()),
+    };
+
+    if state::token_exists(token_id) {
+        return Err("Token id already exists".to_string().into());
+    }
+
+
+    let controller = state::get_controller().ok_or("No controller set".to_string())?;
+    let fee_recipient = Account {
+        owner: controller,
+        subaccount: None,
+    };
+
+    let metadata = crate::types::StoredTokenMetadata {
+        name: args.name,
+        symbol: args.symbol,
+        decimals: args.decimals,
+        total_supply: 0,
+        fee: fee_amount,
+        fee_recipient,
+        logo: args.logo,
+        description: args.description,
+        created_at: runtime::time(),
+        controller,
+        fee_token: None,
+        require_explicit_fee: None,
+        fee_free_consolidation: None,
+        immutable: None,
+        scheduled_fee: None,
+        burn_addresses: None,
+        non_circulating_accounts: None,
+        rounding_mode: None,
+        structured_memos_required: None,
+        max_holders: None,
+    };
+
+    state::register_token(token_id, metadata);
+
+
+    if supply_amount > 0 {
+        let controller_account = Account {
+            owner: controller,
+            subaccount: None,
+        };
+
+        mint_internal(token_id, controller_account, supply_amount, None, None)
+            .map_err(|e| CreateTokenError::GenericError { message: format!("Initial mint failed: {:?}", e) })?;
+    }
+
+    Ok(token_id)
+}
+
+
+pub(crate) fn validate_token_names(name: &str, symbol: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 255 {
+        return Err("Invalid token name length".to_string());
+    }
+    if symbol.is_empty() || symbol.len() > 32 {
+        return Err("Invalid token symbol length".to_string());
+    }
+    Ok(())
+}
+
+
+// Creation stops at the soft stable-memory limit; transfers keep working.
+pub(crate) fn check_token_capacity() -> Result<(), CreateTokenError> {
+    let max_tokens = state::get_max_tokens();
+    if state::get_token_count() >= max_tokens {
+        return Err(CreateTokenError::TokenLimitReached { max_tokens });
+    }
+
+    let stable_pages_used = runtime::stable_pages();
+    let stable_pages_soft_limit = state::get_stable_pages_soft_limit();
+    if stable_pages_used >= stable_pages_soft_limit {
+        return Err(CreateTokenError::StableMemoryLimitReached { stable_pages_used, stable_pages_soft_limit });
+    }
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn mint_tokens(
+    token_id: TokenId,
+    to: Account,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+) -> Result<u64, TransferError> {
+    state::record_legacy_call("mint_tokens");
+    handle_mint_tokens(token_id, to, amount, memo)
+}
+
+
+fn handle_mint_tokens(
+    token_id: TokenId,
+    to: Account,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+) -> Result<u64, TransferError> {
+    let watch = failures::watch(FailedOperation::Mint, token_id, &(token_id, &to, &amount, &memo), Some(&amount));
+    failures::track(watch, mint_with_args(token_id, to, amount, memo, None))
+}
+
+
+// `script_position` is set for the operations of execute_script.
+pub(crate) fn mint_with_args(
+    token_id: TokenId,
+    to: Account,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+    script_position: Option<u32>,
+) -> Result<u64, TransferError> {
+
+    crate::api_access::require_minter(token_id).map_err(|message| generic_error(403, message))?;
+
+    let amount_u128 = amount.0.to_u128()
+        .ok_or_else(|| generic_error(400, "Amount exceeds maximum value (u128::MAX)"))?;
+    let ctx = OperationContext::privileged(OpKind::Mint, token_id, runtime::caller(), None)?.in_script(script_position);
+    execute_mint(&ctx, to, amount_u128, memo.as_deref())
+}
+
+
+pub(crate) fn mint_internal(
+    token_id: TokenId,
+    to: Account,
+    amount: u128,
+    memo: Option<&[u8]>,
+    created_at_time: Option<u64>,
+) -> Result<u64, TransferError> {
+    let ctx = OperationContext::privileged(OpKind::Mint, token_id, runtime::caller(), created_at_time)?;
+    execute_mint(&ctx, to, amount, memo)
+}
+
+
+fn execute_mint(ctx: &OperationContext, to: Account, amount: u128, memo: Option<&[u8]>) -> Result<u64, TransferError> {
+    if !trace::is_enabled() {
+        return apply_mint(ctx, to, amount, memo);
+    }
+    let accounts = [to.to_key()];
+    let result = apply_mint(ctx, to, amount, memo);
+    trace::record("mint", ctx.token_id, &accounts, &[amount], &result);
+    result
+}
+
+
+fn apply_mint(ctx: &OperationContext, to: Account, amount: u128, memo: Option<&[u8]>) -> Result<u64, TransferError> {
+    let token_id = ctx.token_id;
+    validate_account(&to)?;
+    
+    if amount == 0 {
+        return Err(generic_error(400, "Amount must be greater than 0"));
+    }
+
+    // A frozen token's supply can only shrink.
+    if ctx.metadata.is_immutable() {
+        return Err(state::MetadataError::TokenImmutable.into());
+    }
+    
+    let timestamp = ctx.timestamp;
+    let to_key = to.to_key();
+
+
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(
+        OP_MINT,
+        to.owner,
+        token_id,
+        timestamp,
+        memo,
+        Some(to_key),
+        amount,
+    ));
+
+    if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
+        return Err(TransferError::Duplicate { duplicate_of: duplicate_tx_index });
+    }
+
+    if !crate::accounts::accepts_incoming(token_id, to_key, true) {
+        return Err(TransferError::RecipientRefused);
+    }
+
+
+    let supply_delta = i128::try_from(amount).map_err(|_| generic_error(500, "Total supply overflow"))?;
+    state::apply_movements(token_id, &[], &[(to_key, amount)], supply_delta)?;
+
+
+    let tx = StoredTxV1::new_mint(
+        token_id,
+        to_key,
+        amount,
+        timestamp,
+        memo,
+    );
+
+    let tx_index = state::add_transaction_with_memo(tx, memo);
+    state::increment_tx_count();
+
+
+    state::record_transaction_dedup(dedup_key, tx_index);
+
+    Ok(tx_index)
+}
+
+
+#[ic_cdk::update]
+pub fn transfer_v2(args: Icrc151TransferArgs) -> TransferResultV2 {
+    handle_transfer(args).into()
+}
+
+
+pub const MAX_BATCH_TRANSFERS: usize = 100;
+
+
+// Each entry is a transfer of its own, in input order: its own transaction,
+// fee and deduplication, and a failing entry changes nothing. Unlike disburse,
+// the others still go through. A batch over the cap runs no entry, and every
+// result is the same error.
+#[ic_cdk::update]
+pub fn batch_transfer(args: Vec<Icrc151TransferArgs>) -> Vec<TransferResult> {
+    if args.len() > MAX_BATCH_TRANSFERS {
+        let error = TransferError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: format!("Too many transfers (max {})", MAX_BATCH_TRANSFERS),
+        };
+        return args.iter().map(|_| TransferResult::Err(error.clone())).collect();
+    }
+    args.into_iter().map(handle_transfer).collect()
+}
+
+
+// Empties an account without the caller working out balance minus fee. When
+// fees are paid in another token the whole balance moves.
+#[ic_cdk::update]
+pub fn transfer_all(
+    token_id: TokenId,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    memo: Option<Memo>,
+) -> TransferResultV2 {
+    let watch = failures::watch(FailedOperation::Transfer, token_id, &(token_id, &from_subaccount, &to, &memo), None);
+    match transfer_all_checked(token_id, from_subaccount, to, memo) {
+        Ok(tx_index) => TransferResult::Ok(tx_index).into(),
+        Err(error) => {
+            failures::record(watch, &error);
+            TransferResultV2::Err(error)
+        }
+    }
+}
+
+
+fn transfer_all_checked(
+    token_id: TokenId,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    memo: Option<Memo>,
+) -> Result<u64, TransferError> {
+    let ctx = OperationContext::new(OpKind::Transfer, token_id, runtime::caller(), None)?;
+    let from = Account { owner: ctx.caller, subaccount: from_subaccount.map(|s| s.to_vec()) };
+    let fee = ctx.metadata.fee;
+    let primary_fee = if ctx.fee_in_token() { fee } else { 0 };
+
+    let balance = state::get_spendable_balance(token_id, from.to_key());
+    if balance <= primary_fee {
+        return Err(TransferError::GenericError {
+            error_code: candid::Nat::from(400u64),
+            message: format!("Nothing to transfer: balance {} does not exceed the fee {}", balance, primary_fee),
+        });
+    }
+
+    // The fee is passed explicitly so tokens that require one still accept it.
+    execute_transfer(
+        &ctx,
+        from,
+        to,
+        balance - primary_fee,
+        Some(fee),
+        memo.as_deref(),
+        TransferMode::User(AmountMode::Exact),
+        false,
+    )
+}
+
+
+#[ic_cdk::update]
+pub fn mint_tokens_v2(
+    token_id: TokenId,
+    to: Account,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+) -> Result<TxReceipt, TransferError> {
+    handle_mint_tokens(token_id, to, amount, memo).map(tx_receipt)
+}
+
+
+#[ic_cdk::update]
+pub fn burn_tokens_v2(
+    token_id: TokenId,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+    external_ref: Option<Vec<u8>>,
+    from_subaccount: Option<Subaccount>,
+    created_at_time: Option<u64>,
+) -> Result<TxReceipt, TransferError> {
+    handle_burn_tokens(token_id, amount, memo, external_ref, from_subaccount, created_at_time).map(tx_receipt)
 }
 
 
@@ -390,17 +1275,51 @@ fn mint_internal(
 pub fn burn_tokens(
     token_id: TokenId,
     amount: candid::Nat,
-    memo: Option<Vec<u8>>,
-) -> Result<u64, String> {
-    let caller = ic_cdk::caller();
+    memo: Option<Memo>,
+    external_ref: Option<Vec<u8>>,
+    from_subaccount: Option<Subaccount>,
+    created_at_time: Option<u64>,
+) -> Result<u64, TransferError> {
+    state::record_legacy_call("burn_tokens");
+    handle_burn_tokens(token_id, amount, memo, external_ref, from_subaccount, created_at_time)
+}
+
+
+fn handle_burn_tokens(
+    token_id: TokenId,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+    external_ref: Option<Vec<u8>>,
+    from_subaccount: Option<Subaccount>,
+    created_at_time: Option<u64>,
+) -> Result<u64, TransferError> {
+    let watch = failures::watch(
+        FailedOperation::Burn, token_id, &(token_id, &amount, &memo, &external_ref, &from_subaccount, created_at_time), Some(&amount),
+    );
+    let result = burn_with_args(token_id, amount, memo, external_ref, from_subaccount, created_at_time, None);
+    failures::track(watch, result)
+}
+
+
+// `script_position` is set for the operations of execute_script.
+pub(crate) fn burn_with_args(
+    token_id: TokenId,
+    amount: candid::Nat,
+    memo: Option<Memo>,
+    external_ref: Option<Vec<u8>>,
+    from_subaccount: Option<Subaccount>,
+    created_at_time: Option<u64>,
+    script_position: Option<u32>,
+) -> Result<u64, TransferError> {
+    let ctx = OperationContext::new(OpKind::Burn, token_id, runtime::caller(), created_at_time)?.in_script(script_position);
     let from_account = Account {
-        owner: caller,
-        subaccount: None,
+        owner: ctx.caller,
+        subaccount: from_subaccount.map(|s| s.to_vec()),
     };
 
     let amount_u128 = amount.0.to_u128()
-        .ok_or("Amount exceeds maximum value (u128::MAX)".to_string())?;
-    burn_internal(token_id, from_account, amount_u128, memo.as_deref(), None)
+        .ok_or_else(|| generic_error(400, "Amount exceeds maximum value (u128::MAX)"))?;
+    execute_burn(&ctx, from_account, amount_u128, memo.as_deref(), external_ref.as_deref())
 }
 
 #[ic_cdk::update]
@@ -408,13 +1327,18 @@ pub fn burn_tokens_from(
     token_id: TokenId,
     from: Account,
     amount: candid::Nat,
-    memo: Option<Vec<u8>>,
-) -> Result<u64, String> {
-    state::only_controller()?;
-
-    let amount_u128 = amount.0.to_u128()
-        .ok_or("Amount exceeds maximum value (u128::MAX)".to_string())?;
-    burn_internal(token_id, from, amount_u128, memo.as_deref(), None)
+    memo: Option<Memo>,
+    external_ref: Option<Vec<u8>>,
+) -> Result<u64, TransferError> {
+    let watch = failures::watch(
+        FailedOperation::BurnFrom, token_id, &(token_id, &from, &amount, &memo, &external_ref), Some(&amount),
+    );
+    let result = state::only_controller().map_err(|message| generic_error(403, message)).and_then(|()| {
+        let amount_u128 = amount.0.to_u128()
+            .ok_or_else(|| generic_error(400, "Amount exceeds maximum value (u128::MAX)"))?;
+        burn_internal(token_id, from, amount_u128, memo.as_deref(), external_ref.as_deref(), None)
+    });
+    failures::track(watch, result)
 }
 
 
@@ -423,49 +1347,78 @@ fn burn_internal(
     from: Account,
     amount: u128,
     memo: Option<&[u8]>,
+    external_ref: Option<&[u8]>,
     created_at_time: Option<u64>,
-) -> Result<u64, String> {
+) -> Result<u64, TransferError> {
+    let ctx = OperationContext::privileged(OpKind::Burn, token_id, runtime::caller(), created_at_time)?;
+    execute_burn(&ctx, from, amount, memo, external_ref)
+}
+
+
+fn execute_burn(
+    ctx: &OperationContext,
+    from: Account,
+    amount: u128,
+    memo: Option<&[u8]>,
+    external_ref: Option<&[u8]>,
+) -> Result<u64, TransferError> {
+    if !trace::is_enabled() {
+        return apply_burn(ctx, from, amount, memo, external_ref);
+    }
+    let accounts = [from.to_key()];
+    let result = apply_burn(ctx, from, amount, memo, external_ref);
+    trace::record("burn", ctx.token_id, &accounts, &[amount], &result);
+    result
+}
+
+
+// Burns from a subaccount are told apart by the account in their dedup key.
+// Default-account burns leave it out, so their keys are as before.
+fn burn_source(from: &Account) -> Option<AccountKey> {
+    from.subaccount.as_ref().map(|_| from.to_key())
+}
 
-    validate_token_id(&token_id).map_err(|e| e.to_string())?;
-    validate_account(&from).map_err(|e| e.to_string())?;
+
+fn apply_burn(
+    ctx: &OperationContext,
+    from: Account,
+    amount: u128,
+    memo: Option<&[u8]>,
+    external_ref: Option<&[u8]>,
+) -> Result<u64, TransferError> {
+    let token_id = ctx.token_id;
+    validate_account(&from)?;
+    crate::redemption::validate_external_ref(external_ref).map_err(|message| generic_error(400, message))?;
     
     if amount == 0 {
-        return Err("Amount must be greater than 0".to_string());
+        return Err(TransferError::BadBurn { min_burn_amount: candid::Nat::from(1u64) });
     }
     
-    let timestamp = created_at_time.unwrap_or_else(|| ic_cdk::api::time());
+    let timestamp = ctx.timestamp;
     let from_key = from.to_key();
 
 
-    let dedup_key = state::compute_dedup_key(
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(
+        OP_BURN,
         from.owner,
         token_id,
         timestamp,
         memo,
-    );
+        burn_source(&from),
+        amount,
+    ));
 
     if let Some(duplicate_tx_index) = state::check_duplicate(dedup_key) {
-        return Err(format!("Duplicate burn transaction, original tx_index: {}", duplicate_tx_index));
+        return Err(TransferError::Duplicate { duplicate_of: duplicate_tx_index });
     }
 
 
-    let current_balance = state::get_balance(token_id, from_key);
-    if current_balance < amount {
-        return Err(format!(
-            "Insufficient balance: {} < {}",
-            current_balance, amount
-        ));
+    let spendable = state::get_spendable_balance(token_id, from_key);
+    if spendable < amount {
+        return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(spendable) });
     }
 
-
-    state::set_balance(token_id, from_key, current_balance - amount);
-
-
-    if let Some(metadata) = state::get_token_metadata(token_id) {
-        let new_supply = metadata.total_supply.checked_sub(amount)
-            .ok_or("Total supply underflow")?;
-        state::update_total_supply(token_id, new_supply)?;
-    }
+    burn_movement(token_id, from_key, amount)?;
 
 
     let tx = StoredTxV1::new_burn(
@@ -476,85 +1429,1377 @@ fn burn_internal(
         memo,
     );
 
-    let tx_index = state::add_transaction(tx);
-    state::increment_tx_count();
+    let tx_index = state::add_transaction_with_memo(tx, memo);
+    state::increment_tx_count();
+    crate::redemption::record_burn(tx_index, ctx.caller, external_ref);
+
+
+    state::record_transaction_dedup(dedup_key, tx_index);
+
+    Ok(tx_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_account;
+    use candid::Principal;
+
+    #[test]
+    fn test_transfer_validation() {
+
+        let principal_bytes1 = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xD2];
+        let principal_bytes2 = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xD3];
+        
+        let from = Account {
+            owner: candid::Principal::from_slice(&principal_bytes1),
+            subaccount: None,
+        };
+        let to = Account {
+            owner: candid::Principal::from_slice(&principal_bytes2),
+            subaccount: None,
+        };
+        let token_id = [1u8; 32];
+        
+
+
+        assert!(crate::validation::validate_token_id(&token_id).is_ok());
+        assert!(crate::validation::validate_transfer_params(&from, &to, 1000, Some(10), None).is_ok());
+    }
+
+    #[test]
+    fn test_transfer_args_conversion() {
+        let args = Icrc151TransferArgs {
+            token_id: [1u8; 32],
+            from_subaccount: None,
+            to: Account {
+                owner: Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xD2]),
+                subaccount: None,
+            },
+            amount: candid::Nat::from(1000u64),
+            fee: Some(candid::Nat::from(10u64)),
+            memo: Some(Memo(b"test".to_vec())),
+            created_at_time: None,
+            private_memo: None,
+            amount_mode: None, use_sponsorship: None, args_version: None,
+        };
+        
+
+        let amount = args.amount.0.to_u128().unwrap_or(0);
+        let fee = args.fee.as_ref().map(|f| f.0.to_u128().unwrap_or(0));
+        
+        assert_eq!(amount, 1000);
+        assert_eq!(fee, Some(10));
+    }
+
+    #[test]
+    fn test_transfer_args_decoding_limits() {
+        use candid::{Decode, Encode};
+        use crate::types::constants;
+
+        // The wire shape of the args before the newtypes.
+        #[derive(CandidType)]
+        struct RawAccount {
+            owner: Principal,
+            subaccount: Option<Vec<u8>>,
+        }
+        #[derive(CandidType)]
+        struct RawTransferArgs {
+            token_id: TokenId,
+            from_subaccount: Option<Vec<u8>>,
+            to: RawAccount,
+            amount: candid::Nat,
+            fee: Option<candid::Nat>,
+            memo: Option<Vec<u8>>,
+            created_at_time: Option<u64>,
+            private_memo: Option<bool>,
+            amount_mode: Option<AmountMode>,
+            use_sponsorship: Option<bool>,
+            args_version: Option<u16>,
+        }
+        let raw = |from_subaccount: Option<Vec<u8>>, to_subaccount: Option<Vec<u8>>, memo: Option<Vec<u8>>| {
+            Encode!(&RawTransferArgs {
+                token_id: [1u8; 32],
+                from_subaccount,
+                to: RawAccount { owner: test_account(2).owner, subaccount: to_subaccount },
+                amount: candid::Nat::from(5u64),
+                fee: None,
+                memo,
+                created_at_time: None,
+                private_memo: None,
+                amount_mode: None,
+                use_sponsorship: None,
+                args_version: None,
+            }).unwrap()
+        };
+
+        let bytes = raw(Some(vec![7u8; 32]), Some(vec![8u8; 31]), Some(b"hello".to_vec()));
+        let args = Decode!(&bytes, Icrc151TransferArgs).unwrap();
+        assert_eq!(args.from_subaccount, Some(Subaccount([7u8; 32])));
+        assert_eq!(args.to.subaccount, Some(vec![8u8; 31]));
+        assert_eq!(args.memo.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(Encode!(&args).unwrap(), bytes);
+        let max_memo = raw(None, None, Some(vec![1u8; constants::MAX_MEMO_LEN]));
+        assert!(Decode!(&max_memo, Icrc151TransferArgs).is_ok());
+
+        for oversized in [
+            raw(Some(vec![0u8; 1_900_000]), None, None),
+            raw(Some(vec![0u8; 31]), None, None),
+            raw(None, Some(vec![0u8; 33]), None),
+            raw(None, None, Some(vec![0u8; constants::MAX_MEMO_LEN + 1])),
+        ] {
+            assert!(Decode!(&oversized, Icrc151TransferArgs).is_err());
+        }
+    }
+
+    #[test]
+    fn test_token_creation_validation() {
+
+        assert!(validate_token_name("").is_err());
+        assert!(validate_token_name(&"a".repeat(256)).is_err());
+        assert!(validate_token_name("Valid Token").is_ok());
+        
+
+        assert!(validate_token_symbol("").is_err());
+        assert!(validate_token_symbol(&"A".repeat(33)).is_err());
+        assert!(validate_token_symbol("VALID").is_ok());
+    }
+    
+    fn register_test_token(seed: u8, fee: u128, fee_token: Option<TokenId>) -> TokenId {
+        let token_id = crate::test_utils::register_test_token(seed, fee);
+        state::update_token_fee_token(token_id, fee_token).unwrap();
+        token_id
+    }
+
+    #[test]
+    fn test_transfer_with_fee_token() {
+        let gas = register_test_token(2, 0, None);
+        let token = register_test_token(1, 10, Some(gas));
+        let from = test_account(1);
+        let to = test_account(2);
+        state::seed_balance(token, from.to_key(), 1000);
+        state::seed_balance(gas, from.to_key(), 50);
+
+        let tx_index = transfer_internal(token, from.clone(), to.clone(), 100, None, None, None).unwrap();
+
+        assert_eq!(state::get_balance(token, from.to_key()), 900);
+        assert_eq!(state::get_balance(token, to.to_key()), 100);
+        assert_eq!(state::get_balance(gas, from.to_key()), 40);
+        assert_eq!(state::get_balance(gas, test_account(0xFE).to_key()), 10);
+        assert_eq!(state::get_balance(token, test_account(0xFE).to_key()), 0);
+
+        let tx = state::get_transaction(tx_index).unwrap();
+        assert_eq!(tx.get_fee(), 10);
+        assert_eq!(tx.get_fee_token(), Some(gas));
+    }
+
+    #[test]
+    fn test_transfer_bumps_only_involved_token_versions() {
+        use crate::queries::{get_balance, get_token_versions, QueryError};
+
+        let gas = register_test_token(2, 0, None);
+        let token = register_test_token(1, 10, Some(gas));
+        let bystander = register_test_token(3, 0, None);
+        let from = test_account(2);
+        state::seed_balance(token, from.to_key(), 1000);
+        state::seed_balance(gas, from.to_key(), 50);
+        state::seed_balance(bystander, from.to_key(), 5);
+
+        let before = get_token_versions(vec![token, gas, bystander]);
+        transfer_internal(token, from.clone(), test_account(3), 100, None, None, None).unwrap();
+        let after = get_token_versions(vec![token, gas, bystander]);
+        assert!(after[0] > before[0]);
+        assert!(after[1] > before[1]);
+        assert_eq!(after[2], before[2]);
+
+        assert!(matches!(get_balance(bystander, from.clone(), Some(before[2])), Err(QueryError::NotModified { version }) if version == before[2]));
+        assert_eq!(get_balance(token, from.clone(), Some(before[0])).unwrap(), 900);
+        assert!(matches!(get_balance(token, from, Some(after[0])), Err(QueryError::NotModified { .. })));
+        assert_eq!(get_token_versions(vec![[9u8; 32]]), vec![0]);
+    }
+
+    #[test]
+    fn test_balances_for_only_change_with_the_account() {
+        use crate::queries::{get_account_version, get_balances_for, QueryError};
+
+        let token = register_test_token(1, 10, None);
+        let other = register_test_token(2, 0, None);
+        let (alice, bob, carol) = (test_account(2), test_account(3), test_account(4));
+        state::seed_balance(token, alice.to_key(), 1000);
+        state::seed_balance(token, bob.to_key(), 1000);
+        state::seed_balance(other, bob.to_key(), 1000);
+
+        let version = get_account_version(alice.clone()).unwrap();
+        let not_modified = |since| matches!(get_balances_for(alice.owner, None, Some(since)), Err(QueryError::NotModified { .. }));
+        assert!(not_modified(version));
+
+        // Other accounts' transfers, in a token alice holds or not, leave her
+        // version alone.
+        transfer_internal(other, bob.clone(), carol.clone(), 100, None, None, None).unwrap();
+        transfer_internal(token, bob.clone(), carol.clone(), 100, None, None, None).unwrap();
+        assert_eq!(get_account_version(alice.clone()).unwrap(), version);
+        assert!(not_modified(version));
+
+        transfer_internal(other, bob, alice.clone(), 100, None, None, None).unwrap();
+        assert!(get_account_version(alice.clone()).unwrap() > version);
+        assert_eq!(get_balances_for(alice.owner, None, Some(version)).unwrap().len(), 2);
+        assert_eq!(get_account_version(carol).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_mint_then_transfer_with_same_dedup_inputs() {
+        let token = register_test_token(1, 0, None);
+        let (owner, other) = (test_account(2), test_account(3));
+        let now = runtime::time();
+
+        mint_internal(token, owner.clone(), 500, Some(b"same"), Some(now)).unwrap();
+        transfer_internal(token, owner.clone(), other.clone(), 100, None, Some(b"same"), Some(now)).unwrap();
+        assert_eq!(state::get_balance(token, other.to_key()), 100);
+
+        assert!(mint_internal(token, owner.clone(), 500, Some(b"same"), Some(now)).is_err());
+        assert!(matches!(
+            transfer_internal(token, owner, other, 100, None, Some(b"same"), Some(now)),
+            Err(TransferError::Duplicate { duplicate_of: 1 })
+        ));
+    }
+
+    // Mint and burn used to take any created_at_time and to write to a token id
+    // nobody registered; they now share the checks transfers make.
+    #[test]
+    fn test_every_mutation_checks_the_same_context() {
+        let token = register_test_token(1, 0, None);
+        let (owner, other) = (test_account(2), test_account(3));
+        let now = runtime::time();
+        let too_old = Some(now - crate::types::constants::MAX_PAST_DRIFT - 1);
+        let in_future = Some(now + crate::types::constants::MAX_FUTURE_DRIFT + 1);
+        mint_internal(token, owner.clone(), 500, None, None).unwrap();
+
+        assert!(matches!(transfer_internal(token, owner.clone(), other.clone(), 1, None, None, too_old), Err(TransferError::TooOld)));
+        assert!(matches!(
+            transfer_internal(token, owner.clone(), other.clone(), 1, None, None, in_future),
+            Err(TransferError::CreatedInFuture { ledger_time }) if ledger_time == now
+        ));
+        assert!(matches!(mint_internal(token, owner.clone(), 1, None, too_old), Err(TransferError::TooOld)));
+        assert!(matches!(mint_internal(token, owner.clone(), 1, None, in_future), Err(TransferError::CreatedInFuture { .. })));
+        assert!(matches!(burn_internal(token, owner.clone(), 1, None, None, too_old), Err(TransferError::TooOld)));
+        assert!(matches!(burn_internal(token, owner.clone(), 1, None, None, in_future), Err(TransferError::CreatedInFuture { .. })));
+
+        let unknown = [9u8; 32];
+        let not_found = |result: Result<u64, TransferError>| matches!(
+            result, Err(TransferError::GenericError { error_code, message }) if error_code == 404u64 && message == "Token not found"
+        );
+        assert!(not_found(mint_internal(unknown, owner.clone(), 1, None, None)));
+        assert!(not_found(burn_internal(unknown, owner.clone(), 1, None, None, None)));
+        assert_eq!(state::get_balance(unknown, owner.to_key()), 0);
+        assert_eq!(state::get_balance(token, owner.to_key()), 500);
+        assert_eq!(state::get_token_metadata(token).unwrap().total_supply, 500);
+
+        // Maintenance holds the whole consolidation, not each entry.
+        state::set_maintenance_mode(true);
+        runtime::set_caller(owner.owner);
+        assert_eq!(consolidate_subaccounts(token, vec![Subaccount([1u8; 32])], None).unwrap_err(), MAINTENANCE_MODE_MESSAGE);
+        assert!(matches!(burn_tokens(token, candid::Nat::from(1u64), None, None, None, None), Err(TransferError::TemporarilyUnavailable)));
+        runtime::advance_time(1);
+        assert!(mint_internal(token, owner.clone(), 1, None, None).is_ok());
+    }
+
+    #[test]
+    fn test_newer_args_versions_are_refused() {
+        use crate::allowances::{approve, ApproveError, ApproveResult, Icrc151ApproveArgs};
+        use crate::validation::ARGS_VERSION;
+        use candid::{Decode, Encode};
+
+        // What a newer client sends: a field this ledger does not know, and the
+        // version that introduced it. Decoding drops the field without complaint.
+        #[derive(CandidType)]
+        struct NewerTransferArgs {
+            token_id: TokenId,
+            to: Account,
+            amount: candid::Nat,
+            valid_until: Option<u64>,
+            args_version: Option<u16>,
+        }
+
+        let token = register_test_token(1, 0, None);
+        let (from, to) = (test_account(1), test_account(2));
+        state::seed_balance(token, from.to_key(), 1000);
+        runtime::set_caller(from.owner);
+
+        let encode = |args_version: Option<u16>| Encode!(&NewerTransferArgs {
+            token_id: token,
+            to: to.clone(),
+            amount: candid::Nat::from(10u64),
+            valid_until: Some(runtime::time() + 1),
+            args_version,
+        }).unwrap();
+        let newer = Decode!(&encode(Some(ARGS_VERSION + 1)), Icrc151TransferArgs).unwrap();
+        assert!(matches!(
+            transfer(newer),
+            TransferResult::Err(TransferError::UnsupportedArgsVersion { supported }) if supported == ARGS_VERSION
+        ));
+        assert_eq!(state::get_balance(token, from.to_key()), 1000);
+
+        // Clients that predate versioning, or name a version this ledger has, go through.
+        for args_version in [None, Some(ARGS_VERSION)] {
+            runtime::advance_time(1);
+            let args = Decode!(&encode(args_version), Icrc151TransferArgs).unwrap();
+            assert!(matches!(transfer(args), TransferResult::Ok(_)));
+        }
+
+        let approve_args = Icrc151ApproveArgs {
+            token_id: token,
+            spender: to.clone(),
+            amount: candid::Nat::from(10u64),
+            expires_at: None,
+            expected_allowance: None,
+            memo: None,
+            fee: None,
+            from_subaccount: None,
+            created_at_time: None,
+            private_memo: None,
+            args_version: Some(ARGS_VERSION + 1),
+        };
+        assert!(matches!(
+            approve(approve_args),
+            ApproveResult::Err(ApproveError::UnsupportedArgsVersion { supported }) if supported == ARGS_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_batch_transfer_results_follow_input_order() {
+        let token = register_test_token(1, 10, None);
+        let (from, to) = (test_account(1), test_account(2));
+        state::seed_balance(token, from.to_key(), 1000);
+        runtime::set_caller(from.owner);
+        let args = |amount: u64, memo: &[u8]| Icrc151TransferArgs {
+            token_id: token, from_subaccount: None, to: to.clone(), amount: candid::Nat::from(amount),
+            fee: None, memo: Some(Memo(memo.to_vec())), created_at_time: None, private_memo: None,
+            amount_mode: None, use_sponsorship: None, args_version: None,
+        };
+
+        let results = batch_transfer(vec![args(100, b"a"), args(5_000, b"b"), args(200, b"c"), args(100, b"a")]);
+        let [TransferResult::Ok(first), TransferResult::Err(TransferError::InsufficientFunds { .. }), TransferResult::Ok(third),
+            TransferResult::Err(TransferError::Duplicate { duplicate_of })] = results.as_slice() else {
+            panic!("unexpected results: {:?}", results)
+        };
+        assert_eq!(*duplicate_of, *first);
+        assert!(third > first);
+        assert_eq!(state::get_transaction(*third).unwrap().get_amount(), 200);
+        // Two transfers and two fees; the refused entries moved nothing.
+        assert_eq!(state::get_balance(token, from.to_key()), 1000 - 300 - 20);
+        assert_eq!(state::get_balance(token, to.to_key()), 300);
+
+        runtime::advance_time(1);
+        let too_many: Vec<_> = (0..=MAX_BATCH_TRANSFERS).map(|i| args(1, &[b'x', i as u8])).collect();
+        let results = batch_transfer(too_many);
+        assert_eq!(results.len(), MAX_BATCH_TRANSFERS + 1);
+        assert!(results.iter().all(|r| matches!(r, TransferResult::Err(TransferError::GenericError { .. }))));
+        assert_eq!(state::get_balance(token, to.to_key()), 300);
+        assert!(batch_transfer(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_up_to_transfer_sweeps_account_to_zero() {
+        let token = register_test_token(1, 10, None);
+        let (from, to) = (test_account(1), test_account(2));
+        state::seed_balance(token, from.to_key(), 1000);
+        runtime::set_caller(from.owner);
+        let args = |amount: u64, amount_mode: Option<AmountMode>, fee: Option<u64>| Icrc151TransferArgs {
+            token_id: token, from_subaccount: None, to: to.clone(), amount: candid::Nat::from(amount),
+            fee: fee.map(candid::Nat::from), memo: None, created_at_time: Some(runtime::time()), private_memo: None,
+            amount_mode, use_sponsorship: None, args_version: Some(2),
+        };
+
+        // A covered UpTo transfer moves what was asked.
+        let TransferResultV2::Ok(receipt) = transfer_v2(args(100, Some(AmountMode::UpTo), None)) else { panic!("transfer failed") };
+        assert_eq!(receipt.amount, candid::Nat::from(100u64));
+        assert_eq!(state::get_balance(token, from.to_key()), 890);
+
+        // The sweeper read 890, then a concurrent debit took 300. Exact fails; UpTo
+        // sends the rest less the fee and leaves exactly zero.
+        runtime::advance_time(1);
+        state::seed_balance(token, from.to_key(), 590);
+        assert!(matches!(transfer(args(880, None, None)), TransferResult::Err(TransferError::InsufficientFunds { .. })));
+        assert!(matches!(transfer_v2(args(880, Some(AmountMode::UpTo), Some(9))), TransferResultV2::Err(TransferError::BadFee { .. })));
+        let TransferResultV2::Ok(receipt) = transfer_v2(args(880, Some(AmountMode::UpTo), None)) else { panic!("sweep failed") };
+        assert_eq!(receipt.amount, candid::Nat::from(580u64));
+        assert_eq!(state::get_transaction(receipt.tx_index).unwrap().get_amount(), 580);
+        assert_eq!(state::get_balance(token, from.to_key()), 0);
+        assert_eq!(state::get_balance(token, to.to_key()), 680);
+
+        // A retry with the same inputs is the same request, whatever amount it moved.
+        state::seed_balance(token, from.to_key(), 1000);
+        assert!(matches!(
+            transfer(args(880, Some(AmountMode::UpTo), None)),
+            TransferResult::Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == receipt.tx_index
+        ));
+
+        // Nothing left after the fee is still insufficient.
+        runtime::advance_time(1);
+        state::seed_balance(token, from.to_key(), 10);
+        assert!(matches!(
+            transfer(args(880, Some(AmountMode::UpTo), None)),
+            TransferResult::Err(TransferError::InsufficientFunds { balance }) if balance == 10u64
+        ));
+    }
+
+    #[test]
+    fn test_transfer_all_leaves_nothing_behind() {
+        let gas = register_test_token(3, 0, None);
+        let token = register_test_token(1, 10, None);
+        let gas_fee_token = register_test_token(2, 5, Some(gas));
+        let (from, to) = (test_account(1), test_account(2));
+        runtime::set_caller(from.owner);
+
+        state::seed_balance(token, from.to_key(), 1000);
+        let TransferResultV2::Ok(receipt) = transfer_all(token, None, to.clone(), None) else { panic!("transfer_all failed") };
+        assert_eq!(receipt.amount, candid::Nat::from(990u64));
+        assert_eq!(state::get_balance(token, from.to_key()), 0);
+        assert_eq!(state::get_balance(token, to.to_key()), 990);
+
+        // A balance that only pays the fee has nothing to send.
+        runtime::advance_time(1);
+        state::seed_balance(token, from.to_key(), 10);
+        let TransferResultV2::Err(TransferError::GenericError { message, .. }) = transfer_all(token, None, to.clone(), None) else {
+            panic!("balance equal to the fee was accepted")
+        };
+        assert!(message.contains("does not exceed the fee"), "{}", message);
+        assert_eq!(state::get_balance(token, from.to_key()), 10);
+
+        // Fees paid in another token leave the whole balance to move.
+        state::seed_balance(gas_fee_token, from.to_key(), 400);
+        state::seed_balance(gas, from.to_key(), 5);
+        let TransferResultV2::Ok(receipt) = transfer_all(gas_fee_token, None, to.clone(), None) else { panic!("transfer_all failed") };
+        assert_eq!(receipt.amount, candid::Nat::from(400u64));
+        assert_eq!(state::get_balance(gas_fee_token, from.to_key()), 0);
+        assert_eq!(state::get_balance(gas, from.to_key()), 0);
+
+        // So does a zero fee, and an empty account is refused.
+        runtime::advance_time(1);
+        state::seed_balance(gas, from.to_key(), 77);
+        let TransferResultV2::Ok(receipt) = transfer_all(gas, None, to.clone(), None) else { panic!("transfer_all failed") };
+        assert_eq!(receipt.amount, candid::Nat::from(77u64));
+        runtime::advance_time(1);
+        assert!(matches!(transfer_all(gas, None, to, None), TransferResultV2::Err(TransferError::GenericError { .. })));
+    }
+
+    #[test]
+    fn test_paused_ops_stop_only_their_endpoints() {
+        use crate::allowances::{approve, transfer_from, ApproveResult, Icrc151ApproveArgs, Icrc151TransferFromArgs};
+        use crate::test_utils::fee_recipient;
+
+        let controller = fee_recipient().owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0, None);
+        let (owner, spender, to) = (test_account(1), test_account(2), test_account(3));
+        state::apply_movements(token, &[], &[(owner.to_key(), 1_000_000)], 1_000_000).unwrap();
+        runtime::set_caller(owner.owner);
+        assert!(matches!(approve(Icrc151ApproveArgs {
+            token_id: token, spender: spender.clone(), amount: candid::Nat::from(1_000u64), expires_at: None,
+            expected_allowance: None, memo: None, fee: None, from_subaccount: None, created_at_time: None,
+            private_memo: None, args_version: None,
+        }), ApproveResult::Ok(_)));
+
+        // One endpoint of each kind; Err carries the error as text.
+        let attempt = |op: OpKind| -> Result<(), String> {
+            runtime::advance_time(1);
+            let one = candid::Nat::from(1u64);
+            runtime::set_caller(owner.owner);
+            match op {
+                OpKind::Transfer => match transfer(Icrc151TransferArgs {
+                    token_id: token, from_subaccount: None, to: to.clone(), amount: one, fee: None, memo: None,
+                    created_at_time: None, private_memo: None, amount_mode: None, use_sponsorship: None, args_version: None,
+                }) {
+                    TransferResult::Ok(_) => Ok(()),
+                    TransferResult::Err(e) => Err(format!("{:?}", e)),
+                },
+                OpKind::Approve => match approve(Icrc151ApproveArgs {
+                    token_id: token, spender: spender.clone(), amount: candid::Nat::from(1_000u64), expires_at: None,
+                    expected_allowance: None, memo: None, fee: None, from_subaccount: None, created_at_time: None,
+                    private_memo: None, args_version: None,
+                }) {
+                    ApproveResult::Ok(_) => Ok(()),
+                    ApproveResult::Err(e) => Err(format!("{:?}", e)),
+                },
+                OpKind::TransferFrom => {
+                    runtime::set_caller(spender.owner);
+                    match transfer_from(Icrc151TransferFromArgs {
+                        token_id: token, spender_subaccount: None, from: owner.clone(), to: to.clone(), amount: one,
+                        fee: None, memo: None, created_at_time: None, private_memo: None, args_version: None,
+                    }) {
+                        TransferResult::Ok(_) => Ok(()),
+                        TransferResult::Err(e) => Err(format!("{:?}", e)),
+                    }
+                }
+                OpKind::Burn => burn_tokens(token, one, None, None, None, None).map(|_| ()).map_err(|e| format!("{:?}", e)),
+                OpKind::Mint => {
+                    runtime::set_caller(controller);
+                    mint_tokens(token, to.clone(), one, None).map(|_| ()).map_err(|e| format!("{:?}", e))
+                }
+                OpKind::Disburse => disburse(token, None, vec![Payout { to: to.clone(), amount: one, memo: None }], None, None)
+                    .map(|_| ())
+                    .map_err(|e| format!("{:?}", e)),
+            }
+        };
+
+        for paused in OpKind::ALL {
+            runtime::set_caller(controller);
+            pause_ops(vec![paused]).unwrap();
+            assert_eq!(get_paused_ops(), vec![paused]);
+            for op in OpKind::ALL {
+                let result = attempt(op);
+                if op != paused {
+                    assert_eq!(result, Ok(()), "{:?} with {:?} paused", op, paused);
+                    continue;
+                }
+                let error = result.unwrap_err();
+                assert!(error.contains(&format!("OperationPaused {{ op: {:?} }}", op)), "{:?}: {}", op, error);
+            }
+            runtime::set_caller(controller);
+            resume_ops(vec![paused]).unwrap();
+        }
+        for op in OpKind::ALL {
+            assert_eq!(attempt(op), Ok(()), "{:?} after resuming", op);
+        }
+
+        // Maintenance mode still wins over an unpaused kind, and is reported
+        // first when both apply.
+        runtime::set_caller(controller);
+        pause_ops(vec![OpKind::Approve, OpKind::Mint]).unwrap();
+        assert_eq!(get_paused_ops(), vec![OpKind::Approve, OpKind::Mint]);
+        state::set_maintenance_mode(true);
+        assert_eq!(attempt(OpKind::Transfer), Err("TemporarilyUnavailable".to_string()));
+        assert_eq!(attempt(OpKind::Approve), Err("TemporarilyUnavailable".to_string()));
+        assert_eq!(attempt(OpKind::Burn), Err("TemporarilyUnavailable".to_string()));
+        assert_eq!(attempt(OpKind::Mint), Err("OperationPaused { op: Mint }".to_string()));
+        state::set_maintenance_mode(false);
+
+        runtime::set_caller(owner.owner);
+        assert!(pause_ops(vec![OpKind::Transfer]).is_err());
+        assert!(resume_ops(vec![OpKind::Mint]).is_err());
+        runtime::set_caller(controller);
+        assert!(pause_ops(vec![]).is_err());
+        resume_ops(vec![OpKind::Approve, OpKind::Mint, OpKind::Burn]).unwrap();
+        assert!(get_paused_ops().is_empty());
+        let actions: Vec<String> = state::list_admin_actions(0, 100).into_iter()
+            .map(|entry| entry.action)
+            .filter(|action| action.ends_with("_ops"))
+            .collect();
+        assert_eq!(actions.len(), 2 * OpKind::ALL.len() + 2);
+    }
+
+    #[test]
+    fn test_self_transfer_pays_only_the_fee() {
+        use crate::test_utils::{fee_recipient, test_subaccount};
+
+        let token = register_test_token(1, 10, None);
+        let owner = test_account(1);
+        let owner_zero = Account { owner: owner.owner, subaccount: Some(vec![0u8; 32]) };
+        state::seed_balance(token, owner.to_key(), 100);
+        state::seed_balance(token, fee_recipient().to_key(), 1);
+        let holders = state::get_holder_count(token);
+        let now = runtime::time();
+
+        let tx_index = transfer_internal(token, owner.clone(), owner_zero.clone(), 80, None, Some(b"self"), Some(now)).unwrap();
+        assert_eq!(state::get_balance(token, owner.to_key()), 90);
+        assert_eq!(state::get_balance(token, fee_recipient().to_key()), 11);
+        assert_eq!(state::get_holder_count(token), holders);
+        let tx = state::get_transaction(tx_index).unwrap();
+        assert_eq!((tx.get_amount(), tx.get_fee()), (80, 10));
+        assert_eq!(tx.balance_accounts(&[]), vec![owner.to_key()]);
+
+        assert!(matches!(
+            transfer_internal(token, owner.clone(), owner.clone(), 80, None, Some(b"self"), Some(now)),
+            Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == tx_index
+        ));
+        // The amount must still be covered along with the fee.
+        assert!(matches!(
+            transfer_internal(token, owner.clone(), owner.clone(), 81, None, None, None),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
+
+        state::seed_balance(token, owner.to_key(), 10);
+        assert!(transfer_internal(token, owner.clone(), owner.clone(), 1, None, None, None).is_err());
+
+        // Other subaccounts of the same principal are other accounts.
+        let savings = test_subaccount(1, 7);
+        state::seed_balance(token, owner.to_key(), 100);
+        transfer_internal(token, owner.clone(), savings.clone(), 50, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, owner.to_key()), 40);
+        assert_eq!(state::get_balance(token, savings.to_key()), 50);
+    }
+
+    #[test]
+    fn test_failed_operations_leave_dedup_inputs_free() {
+        use crate::queries::{can_retry, DedupOperation, RetryCheckArgs, RetryStatus};
+
+        let token = register_test_token(1, 10, None);
+        let (from, to) = (test_account(2), test_account(3));
+        state::seed_balance(token, from.to_key(), 1000);
+        let now = runtime::time();
+        let memo = Some(&b"pay"[..]);
+        let check = |operation| can_retry(RetryCheckArgs {
+            operation,
+            principal: Some(from.owner),
+            token_id: token,
+            created_at_time: now,
+            memo: memo.map(|m| m.to_vec()),
+            destination: Some(to.clone()),
+            amount: candid::Nat::from(100u64),
+        }).unwrap();
+        let available = RetryStatus::Available { valid_until: now + crate::types::constants::MAX_PAST_DRIFT };
+
+        // The fee is raised after the wallet pinned it.
+        state::update_token_fee(token, 20).unwrap();
+        assert!(matches!(transfer_internal(token, from.clone(), to.clone(), 100, Some(10), memo, Some(now)), Err(TransferError::BadFee { .. })));
+        assert!(matches!(transfer_internal(token, from.clone(), to.clone(), 5000, Some(20), memo, Some(now)), Err(TransferError::InsufficientFunds { .. })));
+        assert!(burn_internal(token, from.clone(), 5000, memo, None, Some(now)).is_err());
+        assert_eq!(check(DedupOperation::Transfer), available);
+        assert_eq!(check(DedupOperation::Burn), available);
+
+        let tx_index = transfer_internal(token, from.clone(), to.clone(), 100, Some(20), memo, Some(now)).unwrap();
+        assert_eq!(check(DedupOperation::Transfer), RetryStatus::Consumed { tx_index });
+        assert_eq!(check(DedupOperation::TransferFrom), available);
+        assert!(matches!(
+            transfer_internal(token, from.clone(), to.clone(), 100, Some(20), memo, Some(now)),
+            Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == tx_index
+        ));
+        assert_eq!(state::get_balance(token, from.to_key()), 880);
+
+        runtime::advance_time(crate::types::constants::MAX_PAST_DRIFT + 1);
+        assert_eq!(check(DedupOperation::Burn), RetryStatus::TooOld);
+        assert_eq!(check(DedupOperation::Transfer), RetryStatus::Consumed { tx_index });
+    }
+
+    #[test]
+    fn test_dedup_key_covers_recipient_and_amount() {
+        let token = register_test_token(1, 10, None);
+        let (from, alice, bob) = (test_account(1), test_account(2), test_account(3));
+        state::seed_balance(token, from.to_key(), 1000);
+        let now = runtime::time();
+        let memo = Some(&b"payroll"[..]);
+
+        // Same created_at_time and memo, different recipient or amount: all new.
+        let to_alice = transfer_internal(token, from.clone(), alice.clone(), 100, None, memo, Some(now)).unwrap();
+        let to_bob = transfer_internal(token, from.clone(), bob.clone(), 100, None, memo, Some(now)).unwrap();
+        transfer_internal(token, from.clone(), bob.clone(), 200, None, memo, Some(now)).unwrap();
+        assert_eq!(state::get_balance(token, bob.to_key()), 300);
+
+        // A true retry is still caught.
+        assert!(matches!(
+            transfer_internal(token, from.clone(), alice.clone(), 100, None, memo, Some(now)),
+            Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == to_alice
+        ));
+        assert!(matches!(
+            transfer_internal(token, from.clone(), bob, 100, None, memo, Some(now)),
+            Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == to_bob
+        ));
+        assert_eq!(state::get_balance(token, from.to_key()), 1000 - 400 - 30);
+    }
+
+    #[test]
+    fn test_max_holders_stops_new_holders_only() {
+        use crate::allowances::{approve, transfer_from, ApproveResult, Icrc151ApproveArgs, Icrc151TransferFromArgs};
+        use crate::test_utils::fee_recipient;
+
+        let controller = fee_recipient().owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0, None);
+        let (alice, bob, carol) = (test_account(1), test_account(2), test_account(3));
+        mint_internal(token, alice.clone(), 1_000, None, None).unwrap();
+        mint_internal(token, bob.clone(), 1_000, None, None).unwrap();
+        set_token_max_holders(token, Some(2)).unwrap();
+        assert_eq!(crate::queries::get_token_metadata(token).unwrap().max_holders, Some(2));
+        let at_cap = |e: &TransferError| matches!(e, TransferError::HolderLimitReached { max_holders: 2 });
+
+        // Transfer, mint and transfer_from to a third account are all refused.
+        assert!(at_cap(&transfer_internal(token, alice.clone(), carol.clone(), 10, None, None, None).unwrap_err()));
+        assert!(at_cap(&mint_internal(token, carol.clone(), 10, None, None).unwrap_err()));
+        runtime::set_caller(alice.owner);
+        assert!(matches!(approve(Icrc151ApproveArgs {
+            token_id: token, spender: bob.clone(), amount: candid::Nat::from(100u64), expires_at: None,
+            expected_allowance: None, memo: None, fee: None, from_subaccount: None, created_at_time: None,
+            private_memo: None, args_version: None,
+        }), ApproveResult::Ok(_)));
+        runtime::set_caller(bob.owner);
+        let TransferResult::Err(e) = transfer_from(Icrc151TransferFromArgs {
+            token_id: token, spender_subaccount: None, from: alice.clone(), to: carol.clone(), amount: candid::Nat::from(10u64),
+            fee: None, memo: None, created_at_time: None, private_memo: None, args_version: None,
+        }) else { panic!("transfer_from went past the holder limit") };
+        assert!(at_cap(&e));
+        assert_eq!((state::get_balance(token, carol.to_key()), state::get_holder_count(token)), (0, 2));
+
+        // Existing holders still transact, burn and empty their accounts, and
+        // a holder leaving makes room for a new one.
+        transfer_internal(token, alice.clone(), bob.clone(), 500, None, None, None).unwrap();
+        runtime::set_caller(controller);
+        burn_internal(token, bob.clone(), 100, None, None, None).unwrap();
+        runtime::advance_time(1);
+        transfer_internal(token, alice.clone(), bob.clone(), 500, None, None, None).unwrap();
+        assert_eq!(state::get_holder_count(token), 1);
+        transfer_internal(token, bob.clone(), carol.clone(), 10, None, None, None).unwrap();
+        assert!(at_cap(&transfer_internal(token, bob.clone(), alice.clone(), 10, None, None, None).unwrap_err()));
+
+        set_token_max_holders(token, None).unwrap();
+        transfer_internal(token, bob.clone(), alice, 10, None, None, None).unwrap();
+        assert_eq!(state::get_holder_count(token), 3);
+    }
+
+    #[test]
+    fn test_transfer_insufficient_fee_token_funds() {
+        let gas = register_test_token(2, 0, None);
+        let token = register_test_token(1, 10, Some(gas));
+        let from = test_account(1);
+        let to = test_account(2);
+        state::seed_balance(token, from.to_key(), 1000);
+        state::seed_balance(gas, from.to_key(), 5);
+
+        match transfer_internal(token, from.clone(), to.clone(), 100, None, None, None) {
+            Err(TransferError::InsufficientFeeFunds { fee_token, balance }) => {
+                assert_eq!(fee_token, gas);
+                assert_eq!(balance, candid::Nat::from(5u64));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert_eq!(state::get_balance(token, from.to_key()), 1000);
+        assert_eq!(state::get_balance(token, to.to_key()), 0);
+        assert_eq!(state::get_balance(gas, from.to_key()), 5);
+    }
+
+    #[test]
+    fn test_same_token_shortfall_is_not_a_fee_shortfall() {
+        let token = register_test_token(1, 10, None);
+        let from = test_account(1);
+        state::seed_balance(token, from.to_key(), 105);
+
+        // The amount alone is more than the account holds, and so is amount
+        // plus fee; both are a shortfall of funds, not of fee funds.
+        for amount in [100, 200] {
+            let result = transfer_internal(token, from.clone(), test_account(2), amount, None, None, None);
+            assert!(matches!(result, Err(TransferError::InsufficientFunds { ref balance }) if *balance == 105u64), "{:?}", result);
+        }
+
+        // Postings over the token itself report the account's shortfall.
+        let mut postings = Postings::new(token);
+        postings.debit(from.to_key(), 100);
+        postings.credit(test_account(2).to_key(), 100);
+        postings.post_fee(from.to_key(), crate::test_utils::fee_recipient().to_key(), 10);
+        let error = TransferError::from(postings.check_credits().unwrap_err());
+        assert!(matches!(error, TransferError::InsufficientFunds { ref balance } if *balance == 105u64), "{:?}", error);
+    }
+
+    #[test]
+    fn test_fee_token_config_rejects_cycles() {
+        let gas = register_test_token(2, 0, None);
+        let token = register_test_token(1, 10, None);
+
+        assert!(fees::validate_fee_token_config(token, Some(token)).is_err());
+        assert!(fees::validate_fee_token_config(token, Some([7u8; 32])).is_err());
+        assert!(fees::validate_fee_token_config(token, Some(gas)).is_ok());
+        assert!(fees::validate_fee_token_config(token, None).is_ok());
+
+        state::update_token_fee_token(token, Some(gas)).unwrap();
+        assert!(fees::validate_fee_token_config(gas, Some(token)).is_err());
+
+        let other = register_test_token(3, 10, None);
+        assert!(fees::validate_fee_token_config(other, Some(token)).is_err());
+    }
+
+    #[test]
+    fn test_transfer_require_explicit_fee() {
+        let token = register_test_token(1, 10, None);
+        let from = test_account(1);
+        let to = test_account(2);
+        state::seed_balance(token, from.to_key(), 1000);
+        state::update_token_require_explicit_fee(token, true).unwrap();
+
+        match transfer_internal(token, from.clone(), to.clone(), 100, None, None, None) {
+            Err(TransferError::BadFee { expected_fee }) => assert_eq!(expected_fee, candid::Nat::from(10u64)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(state::get_balance(token, from.to_key()), 1000);
+
+        transfer_internal(token, from.clone(), to.clone(), 100, Some(10), None, None).unwrap();
+        assert_eq!(state::get_balance(token, from.to_key()), 890);
+    }
+
+    #[test]
+    fn test_corrupted_token_is_quarantined() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+
+        let healthy = register_test_token(1, 0, None);
+        let broken = register_test_token(2, 0, None);
+        let from = test_account(1);
+        state::seed_balance(healthy, from.to_key(), 1000);
+        state::seed_balance(broken, from.to_key(), 1000);
+        state::insert_raw_token_metadata(broken, b"not candid".to_vec());
+
+        match transfer_internal(broken, from.clone(), test_account(2), 10, None, None, None) {
+            Err(TransferError::GenericError { error_code, .. }) => assert_eq!(error_code, candid::Nat::from(503u64)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(
+            crate::queries::get_token_metadata(broken),
+            Err(crate::queries::QueryError::TokenCorrupted)
+        ));
+        assert!(mint_internal(broken, from.clone(), 10, None, None).is_err());
+        assert_eq!(state::get_balance(broken, from.to_key()), 1000);
+
+        // Everything else keeps working, including calls that iterate the registry.
+        transfer_internal(healthy, from.clone(), test_account(2), 10, None, None, None).unwrap();
+        assert!(crate::queries::get_token_metadata(healthy).is_ok());
+        assert_eq!(crate::queries::get_balances_for(from.owner, None, None).unwrap().len(), 2);
+        assert!(fees::validate_fee_token_config(healthy, None).is_ok());
+
+        let quarantined = list_quarantined_tokens().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].token_id, broken);
+
+        assert!(repair_token_metadata(healthy, crate::test_utils::test_metadata(0)).is_err());
+        repair_token_metadata(broken, crate::test_utils::test_metadata(0)).unwrap();
+        assert!(list_quarantined_tokens().unwrap().is_empty());
+        transfer_internal(broken, from.clone(), test_account(2), 10, None, None, None).unwrap();
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(list_quarantined_tokens().is_err());
+    }
+
+    fn run_fee_scenario(commission: Option<u16>) -> (Vec<u128>, Vec<u8>, (u128, u128)) {
+        std::thread::spawn(move || {
+            let token = register_test_token(1, 10, None);
+            let from = test_account(1);
+            let to = test_account(2);
+            let treasury = test_account(3);
+            state::seed_balance(token, from.to_key(), 1000);
+            if let Some(bps) = commission {
+                state::set_protocol_commission(bps, &treasury).unwrap();
+            }
+
+            let tx_index = transfer_internal(token, from.clone(), to.clone(), 100, None, None, None).unwrap();
+
+            let balances = [&from, &to, &treasury, &crate::test_utils::fee_recipient()]
+                .iter()
+                .map(|a| state::get_balance(token, a.to_key()))
+                .collect();
+            let tx_bytes = ic_stable_structures::Storable::to_bytes(&state::get_transaction(tx_index).unwrap()).to_vec();
+            (balances, tx_bytes, state::get_fee_stats(token))
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_zero_commission_matches_no_commission() {
+        assert_eq!(run_fee_scenario(None), run_fee_scenario(Some(0)));
+    }
+
+    #[test]
+    fn test_protocol_commission_split() {
+        // 10% of a fee of 10 is exactly 1
+        let (balances, _, stats) = run_fee_scenario(Some(1_000));
+        assert_eq!(balances, vec![890, 100, 1, 9]);
+        assert_eq!(stats, (9, 1));
+
+        // 15% of 10 is 1.5, floored to 1 for the treasury
+        let (balances, tx_bytes, stats) = run_fee_scenario(Some(1_500));
+        assert_eq!(balances, vec![890, 100, 1, 9]);
+        assert_eq!(stats, (9, 1));
+        assert_eq!(tx_bytes, run_fee_scenario(None).1);
+
+        assert_eq!(fees::split_fee(9_999, 1, RoundingMode::Floor), (9_999, 0));
+        assert_eq!(fees::split_fee(9_999, 1, RoundingMode::Ceil), (9_998, 1));
+        assert_eq!(fees::split_fee(u128::MAX, 10_000, RoundingMode::Ceil), (0, u128::MAX));
+        assert_eq!(fees::split_fee(u128::MAX, 5_000, RoundingMode::HalfEven), (u128::MAX / 2, u128::MAX / 2 + 1));
+    }
+
+    #[test]
+    fn test_commission_follows_token_rounding_mode() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 10, None);
+        let (from, treasury) = (test_account(1), test_account(3));
+        state::seed_balance(token, from.to_key(), 1_000);
+        state::set_protocol_commission(1_500, &treasury).unwrap();
+        assert_eq!(crate::queries::get_token_metadata(token).unwrap().rounding_mode, RoundingMode::Floor);
+
+        runtime::set_caller(from.owner);
+        assert!(set_token_rounding_mode(token, RoundingMode::Ceil).is_err());
+        runtime::set_caller(controller);
+        set_token_rounding_mode(token, RoundingMode::Ceil).unwrap();
+        assert_eq!(crate::queries::get_token_metadata(token).unwrap().rounding_mode, RoundingMode::Ceil);
+
+        // 15% of 10 is 1.5, rounded up to 2 for the treasury.
+        transfer_internal(token, from.clone(), test_account(2), 100, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, treasury.to_key()), 2);
+        assert_eq!(state::get_fee_stats(token), (8, 2));
+
+        // Half to even: 1.5 goes to 2, 0.5 (5% of 10) goes to 0.
+        set_token_rounding_mode(token, RoundingMode::HalfEven).unwrap();
+        runtime::advance_time(1);
+        transfer_internal(token, from.clone(), test_account(2), 100, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, treasury.to_key()), 4);
+        state::set_protocol_commission(500, &treasury).unwrap();
+        runtime::advance_time(1);
+        transfer_internal(token, from.clone(), test_account(2), 100, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, treasury.to_key()), 4);
+        assert_eq!(state::get_fee_stats(token), (26, 4));
+    }
+
+    #[test]
+    fn test_fee_recipient_as_party() {
+        let token = register_test_token(1, 10, None);
+        let recipient = crate::test_utils::fee_recipient();
+        let other = test_account(2);
+        let treasury = test_account(3);
+        state::set_protocol_commission(1_000, &treasury).unwrap();
+
+        // Receiving from zero still counts as a new holder; the fee is collected.
+        state::seed_balance(token, other.to_key(), 1_000);
+        let holders = state::get_holder_count(token);
+        transfer_internal(token, other.clone(), recipient.clone(), 100, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, other.to_key()), 890);
+        assert_eq!(state::get_balance(token, recipient.to_key()), 109);
+        assert_eq!(state::get_holder_count(token), holders + 2);
+        assert_eq!(state::get_fee_stats(token), (9, 1));
+
+        // Sending: the recipient's own share nets out and is not counted as collected.
+        runtime::advance_time(1);
+        let tx_index = transfer_internal(token, recipient.clone(), other.clone(), 50, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, recipient.to_key()), 109 - 60 + 9);
+        assert_eq!(state::get_balance(token, other.to_key()), 940);
+        assert_eq!(state::get_balance(token, treasury.to_key()), 2);
+        assert_eq!(state::get_transaction(tx_index).unwrap().get_fee(), 10);
+        assert_eq!(state::get_fee_stats(token), (9, 2));
+    }
+
+    #[test]
+    fn test_frozen_token_rejects_configuration_changes() {
+        use candid::Nat;
+
+        let (controller, second) = (test_account(0xFE).owner, test_account(0xFD).owner);
+        state::init_state(controller);
+        state::add_controller_internal(second).unwrap();
+        runtime::set_caller(controller);
+        let gas = register_test_token(2, 0, None);
+        let token = register_test_token(1, 10, None);
+        let holder = test_account(3);
+        mint_internal(token, holder.clone(), 1_000, None, None).unwrap();
+
+        crate::fee_governance::set_fee_change_threshold(Some(100)).unwrap();
+        assert!(set_token_fee(token, Nat::from(100u64)).is_err());
+        let pending_id = crate::fee_governance::list_pending_fee_changes()[0].id;
+
+        runtime::set_caller(test_account(4).owner);
+        assert!(freeze_token_config(token).is_err());
+        runtime::set_caller(controller);
+        freeze_token_config(token).unwrap();
+        assert!(crate::queries::get_token_metadata(token).unwrap().immutable);
+
+        let frozen = |result: Result<(), String>| result.is_err_and(|e| e.starts_with("TokenImmutable"));
+        assert!(frozen(freeze_token_config(token)));
+        assert!(frozen(set_token_fee(token, Nat::from(5u64))));
+        assert!(frozen(set_token_fee_token(token, Some(gas))));
+        assert!(frozen(set_token_require_explicit_fee(token, true)));
+        assert!(frozen(set_token_fee_free_consolidation(token, true)));
+        assert!(frozen(set_token_rounding_mode(token, RoundingMode::Ceil)));
+        assert!(frozen(mint_internal(token, holder.clone(), 1, None, None).map(|_| ()).map_err(|e| match e {
+            TransferError::GenericError { message, .. } => message,
+            other => format!("{:?}", other),
+        })));
+        runtime::set_caller(second);
+        assert!(frozen(crate::fee_governance::confirm_fee_change(token, pending_id)));
+
+        // Attempts to unset the flag are rejected and audited.
+        runtime::set_caller(controller);
+        let unfrozen = crate::types::StoredTokenMetadata { immutable: Some(false), ..state::get_token_metadata(token).unwrap() };
+        assert!(frozen(repair_token_metadata(token, unfrozen)));
+        let last = state::list_admin_actions(0, 100).pop().unwrap();
+        assert_eq!(last.action, "rejected_token_unfreeze");
+        assert!(state::get_token_metadata(token).unwrap().is_immutable());
+
+        let metadata = state::get_token_metadata(token).unwrap();
+        assert_eq!((metadata.fee, metadata.fee_token, metadata.require_explicit_fee), (10, None, None));
+        transfer_internal(token, holder.clone(), test_account(5), 100, None, None, None).unwrap();
+        burn_internal(token, holder.clone(), 50, None, None, None).unwrap();
+        assert_eq!(state::get_token_metadata(token).unwrap().total_supply, 950);
+        // Other tokens are unaffected.
+        set_token_fee(gas, Nat::from(0u64)).unwrap();
+    }
+
+    #[test]
+    fn test_scheduled_fee_switches_at_effective_at() {
+        use crate::queries::{get_token_metadata, get_transfer_fee};
+        use candid::Nat;
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 10, None);
+        let from = test_account(2);
+        state::seed_balance(token, from.to_key(), 10_000);
+        let effective_at = runtime::time() + 1_000;
+
+        assert!(schedule_token_fee(token, Nat::from(20u64), runtime::time()).is_err());
+        schedule_token_fee(token, Nat::from(15u64), effective_at).unwrap();
+        schedule_token_fee(token, Nat::from(20u64), effective_at).unwrap();
+        let scheduled = Some(ScheduledFee { fee: 20, effective_at });
+        assert_eq!(get_token_metadata(token).unwrap().scheduled_fee, scheduled);
+        let fee = get_transfer_fee(token).unwrap();
+        assert_eq!((fee.fee, fee.fee_token, fee.scheduled_fee), (10, token, scheduled));
+        assert!(freeze_token_config(token).is_err());
+
+        // One nanosecond before the switch the old fee still applies.
+        runtime::set_time(effective_at - 1);
+        transfer_internal(token, from.clone(), test_account(3), 100, Some(10), None, None).unwrap();
+
+        runtime::set_time(effective_at);
+        let fee = get_transfer_fee(token).unwrap();
+        assert_eq!((fee.fee, fee.scheduled_fee), (20, None));
+        assert!(matches!(
+            transfer_internal(token, from.clone(), test_account(3), 100, Some(10), None, None),
+            Err(TransferError::BadFee { expected_fee }) if expected_fee == 20u64
+        ));
+        transfer_internal(token, from.clone(), test_account(3), 100, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, from.to_key()), 10_000 - 110 - 120);
+        assert!(cancel_scheduled_token_fee(token).is_err());
+
+        // Cancelled before it takes effect.
+        schedule_token_fee(token, Nat::from(30u64), effective_at + 1_000).unwrap();
+        cancel_scheduled_token_fee(token).unwrap();
+        runtime::set_time(effective_at + 2_000);
+        assert_eq!(get_token_metadata(token).unwrap().fee, 20);
+        let actions: Vec<String> = state::list_admin_actions(0, 100).into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, ["schedule_token_fee", "replace_scheduled_token_fee", "schedule_token_fee", "cancel_scheduled_token_fee"]);
+
+        runtime::set_caller(test_account(4).owner);
+        assert!(schedule_token_fee(token, Nat::from(1u64), effective_at + 5_000).is_err());
+    }
+
+    #[test]
+    fn test_burn_tokens_from_a_subaccount() {
+        let controller = test_account(0xFE);
+        state::init_state(controller.owner);
+        runtime::set_caller(controller.owner);
+        let token = register_test_token(1, 10, None);
+        let (sub1, sub2) = (Subaccount([1; 32]), Subaccount([2; 32]));
+        let account = |sub: &Subaccount| Account { owner: controller.owner, subaccount: Some(sub.to_vec()) };
+        mint_internal(token, account(&sub1), 500, None, None).unwrap();
+        mint_internal(token, account(&sub2), 500, None, None).unwrap();
+        mint_internal(token, controller.clone(), 500, None, None).unwrap();
+
+        let now = runtime::time();
+        let burn = |sub: Option<Subaccount>| burn_tokens(token, candid::Nat::from(100u64), Some(Memo(b"b".to_vec())), None, sub, Some(now));
+        let tx_index = burn(Some(sub1)).unwrap();
+        assert_eq!(state::get_transaction(tx_index).unwrap().from_key, account(&sub1).to_key());
+        assert_eq!(state::get_balance(token, account(&sub1).to_key()), 400);
+        assert!(matches!(burn(Some(sub1)), Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == tx_index));
+
+        // The same burn from another subaccount or the default account is not a duplicate.
+        burn(Some(sub2)).unwrap();
+        burn(None).unwrap();
+        assert_eq!(state::get_balance(token, account(&sub2).to_key()), 400);
+        assert_eq!(state::get_balance(token, controller.to_key()), 400);
+        assert_eq!(state::get_balance(token, account(&sub1).to_key()), 400);
+
+        assert!(matches!(
+            burn_tokens(token, candid::Nat::from(1_000u64), None, None, Some(sub1), None),
+            Err(TransferError::InsufficientFunds { balance }) if balance == 400u64
+        ));
+        let too_old = Some(now - 2 * crate::types::constants::NANOS_PER_DAY);
+        assert!(burn_tokens(token, candid::Nat::from(1u64), None, None, Some(sub1), too_old).is_err());
+    }
+
+    #[test]
+    fn test_transfer_to_burn_address_burns() {
+        use crate::queries::{get_circulating_supply, get_token_metadata};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 10, None);
+        let (alice, dead, treasury) = (test_account(1), test_account(0xDE), test_account(3));
+        mint_internal(token, alice.clone(), 1_000, None, None).unwrap();
+        mint_internal(token, treasury.clone(), 200, None, None).unwrap();
+
+        // Sent before the address was designated: an ordinary transfer.
+        transfer_internal(token, alice.clone(), dead.clone(), 50, None, None, None).unwrap();
+        assert_eq!(state::get_balance(token, dead.to_key()), 50);
+
+        assert!(set_token_burn_addresses(token, vec![dead.clone(), dead.clone()]).is_err());
+        assert!(set_token_burn_addresses(token, vec![test_account(0xFE)]).is_err());
+        runtime::set_caller(alice.owner);
+        assert!(set_token_burn_addresses(token, vec![dead.clone()]).is_err());
+        runtime::set_caller(controller);
+        set_token_burn_addresses(token, vec![dead.clone()]).unwrap();
+        set_token_non_circulating_accounts(token, vec![treasury.clone()]).unwrap();
+        assert_eq!(get_token_metadata(token).unwrap().burn_addresses, vec![dead.clone()]);
+
+        runtime::advance_time(1);
+        let tx_index = transfer_internal(token, alice.clone(), dead.clone(), 100, Some(10), Some(b"gone"), None).unwrap();
+        assert_eq!(state::get_balance(token, alice.to_key()), 1_000 - 60 - 100);
+        assert_eq!(state::get_balance(token, dead.to_key()), 50);
+        assert_eq!(state::get_token_metadata(token).unwrap().total_supply, 1_100);
+        let tx = state::get_transaction(tx_index).unwrap();
+        assert_eq!((tx.op, tx.get_fee(), tx.get_burn_target()), (OP_BURN, 0, Some(dead.to_key())));
+        assert!(matches!(
+            transfer_internal(token, alice.clone(), dead.clone(), 100, Some(10), Some(b"gone"), None),
+            Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == tx_index
+        ));
+        runtime::advance_time(1);
+        assert!(matches!(
+            transfer_internal(token, alice.clone(), dead.clone(), 10_000, None, None, None),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
 
+        // 1,100 minted and not burned, less the treasury's 200 and the 50 stuck at the burn address.
+        assert_eq!(get_circulating_supply(token).unwrap(), 850);
+        set_token_burn_addresses(token, vec![]).unwrap();
+        assert_eq!(get_circulating_supply(token).unwrap(), 900);
 
-    if let Some(memo_bytes) = memo {
-        if memo_bytes.len() > 32 {
-            state::store_extended_memo(tx_index, memo_bytes.to_vec());
-        }
+        freeze_token_config(token).unwrap();
+        assert!(set_token_burn_addresses(token, vec![dead]).unwrap_err().starts_with("TokenImmutable"));
     }
 
+    #[test]
+    fn test_set_protocol_commission_validation() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+
+        assert!(set_protocol_commission(10_001, test_account(3)).is_err());
+        assert!(set_protocol_commission(10_000, test_account(3)).is_ok());
+        assert_eq!(state::get_protocol_commission_bps(), 10_000);
+        assert_eq!(state::get_protocol_treasury(), Some(test_account(3)));
+
+        runtime::set_caller(test_account(1).owner);
+        assert!(set_protocol_commission(0, test_account(3)).is_err());
+        assert_eq!(state::get_protocol_commission_bps(), 10_000);
+    }
 
-    state::record_transaction_dedup(dedup_key, tx_index);
-
-    Ok(tx_index)
-}
+    fn namespaced_args(namespace: &[u8]) -> CreateTokenArgs {
+        CreateTokenArgs {
+            name: "Bridged USDC".to_string(),
+            symbol: "bUSDC".to_string(),
+            decimals: 6,
+            initial_supply: None,
+            fee: None,
+            logo: None,
+            description: None,
+            namespace: Some(namespace.to_vec()),
+            args_version: None,
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use candid::Principal;
+    #[test]
+    fn test_create_token_with_namespace() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+
+        let token_id = create_token_internal(controller, namespaced_args(b"sol/usdc")).unwrap();
+        assert_eq!(token_id, derive_token_id_v2(runtime::canister_id(), controller, b"sol/usdc"));
+        assert!(state::token_exists(token_id));
+
+        assert!(create_token_internal(controller, namespaced_args(b"sol/usdc")).is_err());
+        assert!(create_token_internal(controller, namespaced_args(b"sol/usdt")).is_ok());
+        assert!(create_token_internal(controller, namespaced_args(&[1u8; 65])).is_err());
+
+        let limit = crate::validation::HIGH_PRECISION_SUPPLY_LIMIT;
+        let high_precision = |supply: u128| CreateTokenArgs {
+            decimals: 18,
+            initial_supply: Some(candid::Nat::from(supply)),
+            ..namespaced_args(b"sol/wif")
+        };
+        assert_eq!(
+            create_token_internal(controller, high_precision(limit + 1)),
+            Err(CreateTokenError::ExceedsEconomicLimit { field: "initial_supply".to_string(), limit: candid::Nat::from(limit) })
+        );
+        let token_id = create_token_internal(controller, high_precision(limit)).unwrap();
+        assert_eq!(state::get_token_metadata(token_id).unwrap().total_supply, limit);
+    }
 
     #[test]
-    fn test_transfer_validation() {
+    fn test_consolidate_subaccounts() {
+        use crate::test_utils::{fee_recipient, register_test_token, subaccount_of, test_subaccount};
+
+        let token = register_test_token(1, 10);
+        let owner = test_account(2);
+        runtime::set_caller(owner.owner);
+        let subs: Vec<Account> = (1..=4).map(|i| test_subaccount(2, i)).collect();
+        state::seed_balance(token, subs[0].to_key(), 100);
+        state::seed_balance(token, subs[1].to_key(), 10);
+        state::seed_balance(token, subs[3].to_key(), 50);
+
+        let sources = subs.iter().map(|a| subaccount_of(a).unwrap()).collect();
+        let entries = consolidate_subaccounts(token, sources, subaccount_of(&subs[3])).unwrap();
+        assert!(matches!(&entries[0].outcome, ConsolidationOutcome::Moved { amount, .. } if *amount == 90u64));
+        assert!(matches!(entries[1].outcome, ConsolidationOutcome::BelowFee { .. }));
+        assert!(matches!(entries[2].outcome, ConsolidationOutcome::Empty));
+        assert!(matches!(entries[3].outcome, ConsolidationOutcome::SameAsDestination));
+        assert_eq!(state::get_balance(token, subs[0].to_key()), 0);
+        assert_eq!(state::get_balance(token, subs[1].to_key()), 10);
+        assert_eq!(state::get_balance(token, subs[3].to_key()), 140);
+        assert_eq!(state::get_balance(token, fee_recipient().to_key()), 10);
+
+        // Fee-free tokens sweep everything, including would-be dust, in the same instant.
+        state::update_token_fee_free_consolidation(token, true).unwrap();
+        state::seed_balance(token, subs[2].to_key(), 3);
+        let sources = vec![subaccount_of(&subs[1]).unwrap(), subaccount_of(&subs[2]).unwrap()];
+        let entries = consolidate_subaccounts(token, sources, None).unwrap();
+        assert!(entries.iter().all(|e| matches!(e.outcome, ConsolidationOutcome::Moved { .. })));
+        assert_eq!(state::get_balance(token, owner.to_key()), 13);
+        assert_eq!(state::get_balance(token, fee_recipient().to_key()), 10);
+
+        assert!(consolidate_subaccounts(token, vec![Subaccount([1u8; 32]); MAX_CONSOLIDATION_SUBACCOUNTS + 1], None).is_err());
+    }
 
-        let principal_bytes1 = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xD2];
-        let principal_bytes2 = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xD3];
-        
-        let from = Account {
-            owner: candid::Principal::from_slice(&principal_bytes1),
-            subaccount: None,
-        };
-        let to = Account {
-            owner: candid::Principal::from_slice(&principal_bytes2),
-            subaccount: None,
+    #[test]
+    fn test_disburse_is_all_or_nothing() {
+        use crate::test_utils::fee_recipient;
+
+        let token = register_test_token(1, 10, None);
+        let sender = test_account(1);
+        let (alice, bob) = (test_account(2), test_account(3));
+        state::seed_balance(token, sender.to_key(), 1_000);
+        runtime::set_caller(sender.owner);
+        let payout = |to: &Account, amount: u64, memo: &[u8]| Payout {
+            to: to.clone(),
+            amount: candid::Nat::from(amount),
+            memo: Some(Memo(memo.to_vec())),
         };
-        let token_id = [1u8; 32];
-        
 
+        // 2 x 500 + 2 fees is 20 more than the sender has; nothing moves.
+        let too_much = vec![payout(&alice, 500, b"june"), payout(&bob, 500, b"june")];
+        assert!(matches!(
+            disburse(token, None, too_much, None, None),
+            Err(DisburseError::Rejected(TransferError::InsufficientFunds { .. }))
+        ));
+        let with_bad_payout = vec![payout(&alice, 100, b"june"), payout(&sender, 100, b"june")];
+        assert!(matches!(
+            disburse(token, None, with_bad_payout, None, None),
+            Err(DisburseError::InvalidPayout { payout_index: 1, .. })
+        ));
+        assert_eq!(state::get_balance(token, sender.to_key()), 1_000);
+        assert_eq!(state::get_transaction_count(), 0);
+
+        // Same memo twice, even to the same recipient: the payout index keeps them apart.
+        let now = runtime::time();
+        let payouts = vec![payout(&alice, 300, b"june"), payout(&bob, 200, b"bonus"), payout(&alice, 300, b"june")];
+        let tx_indices = disburse(token, None, payouts.clone(), Some(candid::Nat::from(10u64)), Some(now)).unwrap();
+        assert_eq!(tx_indices.len(), 3);
+        assert_eq!(state::get_balance(token, sender.to_key()), 1_000 - 800 - 30);
+        assert_eq!(state::get_balance(token, alice.to_key()), 600);
+        assert_eq!(state::get_balance(token, bob.to_key()), 200);
+        assert_eq!(state::get_balance(token, fee_recipient().to_key()), 30);
+        let bonus = state::get_transaction(tx_indices[1]).unwrap();
+        assert_eq!((bonus.to_key, bonus.get_amount(), bonus.get_fee(), bonus.get_timestamp()), (bob.to_key(), 200, 10, now));
+        assert_eq!(&bonus.memo[..5], b"bonus");
+
+        assert!(matches!(
+            disburse(token, None, payouts, None, Some(now)),
+            Err(DisburseError::InvalidPayout { payout_index: 0, error: TransferError::Duplicate { duplicate_of } }) if duplicate_of == tx_indices[0]
+        ));
+        assert!(matches!(disburse(token, None, vec![], None, None), Err(DisburseError::Rejected(_))));
+        let too_many = vec![payout(&alice, 1, b""); MAX_DISBURSE_PAYOUTS + 1];
+        assert!(matches!(disburse(token, None, too_many, None, None), Err(DisburseError::Rejected(_))));
+        assert!(matches!(
+            disburse(token, None, vec![payout(&alice, 1, b"")], Some(candid::Nat::from(1u64)), None),
+            Err(DisburseError::Rejected(TransferError::BadFee { .. }))
+        ));
+    }
 
-        assert!(crate::validation::validate_token_id(&token_id).is_ok());
-        assert!(crate::validation::validate_transfer_params(&from, &to, 1000, Some(10), None).is_ok());
+    #[test]
+    fn test_ledger_config_matches_enforcement() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+
+        set_max_tokens(state::get_token_count() + 1).unwrap();
+        let config = crate::queries::get_ledger_config();
+        assert_eq!(config.max_tokens, state::get_token_count() + 1);
+        create_token_internal(controller, namespaced_args(b"config/1")).unwrap();
+        assert!(create_token_internal(controller, namespaced_args(b"config/2")).is_err());
+
+        set_max_tokens(config.max_tokens + 1).unwrap();
+        assert_eq!(crate::queries::get_ledger_config().max_tokens, config.max_tokens + 1);
+        assert!(create_token_internal(controller, namespaced_args(b"config/2")).is_ok());
+
+        let token = register_test_token(1, 0, None);
+        state::seed_balance(token, test_account(1).to_key(), 100);
+        let memo = vec![1u8; config.max_memo_len as usize];
+        assert!(transfer_internal(token, test_account(1), test_account(2), 1, None, Some(&memo), None).is_ok());
+        let memo = vec![1u8; config.max_memo_len as usize + 1];
+        assert!(transfer_internal(token, test_account(1), test_account(2), 1, None, Some(&memo), None).is_err());
     }
 
     #[test]
-    fn test_transfer_args_conversion() {
+    fn test_max_tokens_limit() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+
+        let existing = state::get_token_count();
+        set_max_tokens(existing + 2).unwrap();
+        create_token_internal(controller, namespaced_args(b"cap/1")).unwrap();
+        create_token_internal(controller, namespaced_args(b"cap/2")).unwrap();
+        assert_eq!(
+            create_token_internal(controller, namespaced_args(b"cap/3")),
+            Err(CreateTokenError::TokenLimitReached { max_tokens: existing + 2 })
+        );
+        assert_eq!(state::get_token_count(), existing + 2);
+
+        set_max_tokens(existing + 3).unwrap();
+        assert!(create_token_internal(controller, namespaced_args(b"cap/3")).is_ok());
+
+        let audit = state::list_admin_actions(0, 10);
+        assert_eq!(audit.len(), 2);
+        assert_eq!(audit[1].action, "set_max_tokens");
+        assert_eq!(audit[1].detail, format!("{} -> {}", existing + 2, existing + 3));
+        assert_eq!(audit[1].caller, controller);
+
+        // Past the soft stable-memory limit creation stops, transfers do not.
+        set_max_tokens(existing + 10).unwrap();
+        runtime::set_stable_pages(state::get_stable_pages_soft_limit());
+        assert!(matches!(
+            create_token_internal(controller, namespaced_args(b"cap/4")),
+            Err(CreateTokenError::StableMemoryLimitReached { .. })
+        ));
+        let token = crate::test_utils::register_test_token(9, 0);
+        state::seed_balance(token, test_account(2).to_key(), 10);
+        runtime::set_caller(test_account(2).owner);
         let args = Icrc151TransferArgs {
-            token_id: [1u8; 32],
+            token_id: token,
             from_subaccount: None,
-            to: Account {
-                owner: Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xD2]),
-                subaccount: None,
-            },
-            amount: candid::Nat::from(1000u64),
-            fee: Some(candid::Nat::from(10u64)),
-            memo: Some(b"test".to_vec()),
+            to: test_account(3),
+            amount: candid::Nat::from(5u64),
+            fee: None,
+            memo: None,
             created_at_time: None,
+            private_memo: None,
+            amount_mode: None, use_sponsorship: None, args_version: None,
         };
-        
-
-        let amount = args.amount.0.to_u128().unwrap_or(0);
-        let fee = args.fee.as_ref().map(|f| f.0.to_u128().unwrap_or(0));
-        
-        assert_eq!(amount, 1000);
-        assert_eq!(fee, Some(10));
+        assert!(matches!(transfer(args), TransferResult::Ok(_)));
+        runtime::set_stable_pages(0);
     }
 
     #[test]
-    fn test_token_creation_validation() {
-
-        assert!(validate_token_name("").is_err());
-        assert!(validate_token_name(&"a".repeat(256)).is_err());
-        assert!(validate_token_name("Valid Token").is_ok());
-        
-
-        assert!(validate_token_symbol("").is_err());
-        assert!(validate_token_symbol(&"A".repeat(33)).is_err());
-        assert!(validate_token_symbol("VALID").is_ok());
+    fn test_derive_token_id_v2_uniqueness() {
+        let ledger_a = Principal::from_slice(&[0xAA; 10]);
+        let ledger_b = Principal::from_slice(&[0xBB; 10]);
+        let creator = test_account(1).owner;
+        let other_creator = test_account(2).owner;
+
+        let id = derive_token_id_v2(ledger_a, creator, b"ns");
+        assert_eq!(id, derive_token_id_v2(ledger_a, creator, b"ns"));
+        assert_ne!(id, derive_token_id_v2(ledger_b, creator, b"ns"));
+        assert_ne!(id, derive_token_id_v2(ledger_a, other_creator, b"ns"));
+        assert_ne!(id, derive_token_id_v2(ledger_a, creator, b"ns2"));
+        assert_ne!(id, derive_token_id(ledger_a, 1));
     }
-    
+
     fn validate_token_name(name: &str) -> Result<(), &'static str> {
         if name.is_empty() || name.len() > 255 {
             Err("Invalid token name length")
@@ -608,6 +2853,412 @@ pub fn set_token_fee(token_id: TokenId, new_fee: candid::Nat) -> Result<(), Stri
 
     let fee_amount = new_fee.0.to_u128()
         .ok_or("Fee exceeds maximum value (u128::MAX)".to_string())?;
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    if metadata.is_immutable() {
+        return Err(state::MetadataError::TokenImmutable.to_string());
+    }
+    validate_token_economics(metadata.decimals, fee_amount, 0, None).map_err(|e| e.to_string())?;
+    crate::fee_governance::gate_fee_change(token_id, metadata.fee, fee_amount)?;
+
+    state::update_token_fee(token_id, fee_amount)?;
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::FeeChanged);
+    Ok(())
+}
+
+
+// Announces a fee change ahead of time. Until `effective_at` transfers pay the
+// current fee; from that nanosecond on, the new one. Scheduling again replaces
+// the pending change.
+#[ic_cdk::update]
+pub fn schedule_token_fee(token_id: TokenId, new_fee: candid::Nat, effective_at: u64) -> Result<(), String> {
+    state::require_controller()?;
+
+    let fee_amount = new_fee.0.to_u128()
+        .ok_or("Fee exceeds maximum value (u128::MAX)".to_string())?;
+    if effective_at <= runtime::time() {
+        return Err("effective_at must be in the future; use set_token_fee for immediate changes".to_string());
+    }
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    if metadata.is_immutable() {
+        return Err(state::MetadataError::TokenImmutable.to_string());
+    }
+    validate_token_economics(metadata.decimals, fee_amount, 0, None).map_err(|e| e.to_string())?;
+    if crate::fee_governance::requires_confirmation(metadata.fee, fee_amount) {
+        return Err("Fee increase exceeds the fee change threshold; use set_token_fee and confirm_fee_change".to_string());
+    }
+
+    state::update_token_scheduled_fee(token_id, Some(ScheduledFee { fee: fee_amount, effective_at }))?;
+    let action = if metadata.scheduled_fee.is_some() { "replace_scheduled_token_fee" } else { "schedule_token_fee" };
+    state::record_admin_action(action, format!(
+        "{}: {} -> {} at {}",
+        crate::allowances::hex_encode(&token_id), metadata.fee, fee_amount, effective_at
+    ));
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn cancel_scheduled_token_fee(token_id: TokenId) -> Result<(), String> {
+    state::require_controller()?;
+
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    let scheduled = metadata.scheduled_fee.ok_or("No fee change is scheduled".to_string())?;
+    state::update_token_scheduled_fee(token_id, None)?;
+    state::record_admin_action("cancel_scheduled_token_fee", format!(
+        "{}: {} at {}",
+        crate::allowances::hex_encode(&token_id), scheduled.fee, scheduled.effective_at
+    ));
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn set_token_fee_token(token_id: TokenId, fee_token: Option<TokenId>) -> Result<(), String> {
+    state::require_controller()?;
+
+    if !state::token_exists(token_id) {
+        return Err("Token not found".to_string());
+    }
+
+    fees::validate_fee_token_config(token_id, fee_token)?;
+    state::update_token_fee_token(token_id, fee_token)?;
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::FeeTokenChanged);
+    Ok(())
+}
+
+#[ic_cdk::update]
+pub fn set_protocol_commission(commission_bps: u16, treasury: Account) -> Result<(), String> {
+    state::require_controller()?;
+
+    if commission_bps > fees::MAX_COMMISSION_BPS {
+        return Err(format!("Commission cannot exceed {} bps", fees::MAX_COMMISSION_BPS));
+    }
+
+    validate_account(&treasury).map_err(|e| e.to_string())?;
+    state::set_protocol_commission(commission_bps, &treasury)
+}
+
+
+#[ic_cdk::update]
+pub fn set_holder_history_retention(days: u32) -> Result<(), String> {
+    state::require_controller()?;
+
+    if days == 0 {
+        return Err("Retention must be at least one day".to_string());
+    }
+
+    state::set_holder_history_retention_days(days);
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn set_max_tokens(max_tokens: u64) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = state::get_max_tokens();
+    state::set_max_tokens(max_tokens);
+    state::record_admin_action("set_max_tokens", format!("{} -> {}", previous, max_tokens));
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn set_stable_pages_soft_limit(pages: u64) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = state::get_stable_pages_soft_limit();
+    state::set_stable_pages_soft_limit(pages);
+    state::record_admin_action("set_stable_pages_soft_limit", format!("{} -> {}", previous, pages));
+    Ok(())
+}
+
+
+// 0 turns the warning off.
+#[ic_cdk::update]
+pub fn set_holder_soft_cap(holders: u64) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = state::get_holder_soft_cap();
+    state::set_holder_soft_cap(holders);
+    state::record_admin_action("set_holder_soft_cap", format!("{} -> {}", previous, holders));
+    Ok(())
+}
+
+
+pub(crate) const MAINTENANCE_MODE_MESSAGE: &str = "The ledger is in maintenance mode";
+
+
+// While on, transfers, approvals, transfer_from, disbursements and user burns
+// fail with TemporarilyUnavailable. Controller mints and burns, admin calls and
+// queries keep working.
+#[ic_cdk::update]
+pub fn set_maintenance_mode(enabled: bool) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = state::is_maintenance_mode();
+    state::set_maintenance_mode(enabled);
+    state::record_admin_action("set_maintenance_mode", format!("{} -> {}", previous, enabled));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_maintenance_mode() -> bool {
+    state::is_maintenance_mode()
+}
+
+
+// Stops single kinds of operation while the rest of the ledger keeps running,
+// e.g. approvals and transfer_from during an allowance incident:
+// - Transfer: transfer, transfer_v2, batch_transfer, transfer_all,
+//   consolidate_subaccounts, settle_net, reserve, capture, transfer_signed
+// - Approve: approve, approve_v2, increase_allowance, decrease_allowance,
+//   revoke_allowance
+// - TransferFrom: transfer_from, transfer_from_v2
+// - Burn: burn_tokens, burn_tokens_v2, burn_tokens_from
+// - Mint: mint_tokens, mint_tokens_v2, dev_faucet
+// - Disburse: disburse, disburse_from
+// Unlike maintenance mode, a paused kind stops controllers too. An operation
+// runs only if neither maintenance mode, its kind nor its token's quarantine
+// refuses it; maintenance is reported first, as TemporarilyUnavailable.
+#[ic_cdk::update]
+pub fn pause_ops(ops: Vec<OpKind>) -> Result<(), String> {
+    update_paused_ops("pause_ops", ops, true)
+}
+
+
+#[ic_cdk::update]
+pub fn resume_ops(ops: Vec<OpKind>) -> Result<(), String> {
+    update_paused_ops("resume_ops", ops, false)
+}
+
+
+#[ic_cdk::query]
+pub fn get_paused_ops() -> Vec<OpKind> {
+    paused_ops_in(state::get_paused_ops_mask())
+}
+
+
+fn paused_ops_in(mask: u64) -> Vec<OpKind> {
+    OpKind::ALL.into_iter().filter(|op| mask & op.bit() != 0).collect()
+}
+
+
+fn update_paused_ops(action: &str, ops: Vec<OpKind>, pause: bool) -> Result<(), String> {
+    state::require_controller()?;
+    if ops.is_empty() {
+        return Err("At least one operation kind is required".to_string());
+    }
+
+    let previous = state::get_paused_ops_mask();
+    let bits = ops.iter().fold(0, |mask, op| mask | op.bit());
+    let mask = if pause { previous | bits } else { previous & !bits };
+    state::set_paused_ops_mask(mask);
+    state::record_admin_action(action, format!("{:?} -> {:?}", paused_ops_in(previous), paused_ops_in(mask)));
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn set_token_require_explicit_fee(token_id: TokenId, required: bool) -> Result<(), String> {
+    state::require_controller()?;
+    state::update_token_require_explicit_fee(token_id, required)?;
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::RequireExplicitFeeChanged);
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn set_token_fee_free_consolidation(token_id: TokenId, fee_free: bool) -> Result<(), String> {
+    state::require_controller()?;
+    state::update_token_fee_free_consolidation(token_id, fee_free)?;
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::FeeFreeConsolidationChanged);
+    Ok(())
+}
+
+
+// Applies to proportional amounts computed after the change; amounts already
+// posted stay as they were rounded.
+#[ic_cdk::update]
+pub fn set_token_rounding_mode(token_id: TokenId, mode: RoundingMode) -> Result<(), String> {
+    state::require_controller()?;
+    let previous = state::get_token_metadata(token_id).map_err(|e| e.to_string())?.rounding_mode.unwrap_or_default();
+
+    state::update_token_rounding_mode(token_id, mode)?;
+    state::record_admin_action("set_token_rounding_mode", format!(
+        "{}: {:?} -> {:?}", crate::allowances::hex_encode(&token_id), previous, mode
+    ));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::RoundingModeChanged);
+    Ok(())
+}
+
+
+// Applies to movements made after the change; mints and burns are not checked.
+#[ic_cdk::update]
+pub fn set_token_structured_memos_required(token_id: TokenId, required: bool) -> Result<(), String> {
+    state::require_controller()?;
+    let previous = state::get_token_metadata(token_id).map_err(|e| e.to_string())?.structured_memos_required.unwrap_or(false);
+
+    state::update_token_structured_memos_required(token_id, required)?;
+    state::record_admin_action("set_token_structured_memos_required", format!(
+        "{}: {} -> {}", crate::allowances::hex_encode(&token_id), previous, required
+    ));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::StructuredMemosRequiredChanged);
+    Ok(())
+}
+
+
+// None lifts the limit. A limit below the current holder count stops growth
+// without touching existing holders: they can still send, burn and empty
+// their accounts.
+#[ic_cdk::update]
+pub fn set_token_max_holders(token_id: TokenId, max_holders: Option<u64>) -> Result<(), String> {
+    state::require_controller()?;
+    let previous = state::get_token_metadata(token_id).map_err(|e| e.to_string())?.max_holders;
+
+    state::update_token_max_holders(token_id, max_holders)?;
+    state::record_admin_action("set_token_max_holders", format!(
+        "{}: {:?} -> {:?}", crate::allowances::hex_encode(&token_id), previous, max_holders
+    ));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::MaxHoldersChanged);
+    Ok(())
+}
+
+
+pub const MAX_DESIGNATED_ACCOUNTS: usize = 10;
+
+
+fn validate_designated_accounts(accounts: &[Account]) -> Result<(), String> {
+    if accounts.len() > MAX_DESIGNATED_ACCOUNTS {
+        return Err(format!("At most {} accounts", MAX_DESIGNATED_ACCOUNTS));
+    }
+    for (i, account) in accounts.iter().enumerate() {
+        validate_account(account).map_err(|e| e.to_string())?;
+        if accounts[..i].iter().any(|other| other.to_key() == account.to_key()) {
+            return Err("Duplicate account".to_string());
+        }
+    }
+    Ok(())
+}
+
+
+// An empty list clears them. Balances already held by a burn address stay
+// where they are; they are left out of the circulating supply.
+#[ic_cdk::update]
+pub fn set_token_burn_addresses(token_id: TokenId, accounts: Vec<Account>) -> Result<(), String> {
+    state::require_controller()?;
+    validate_designated_accounts(&accounts)?;
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    if accounts.iter().any(|a| a.to_key() == metadata.fee_recipient.to_key()) {
+        return Err("The fee recipient cannot be a burn address".to_string());
+    }
+
+    let count = accounts.len();
+    state::update_token_burn_addresses(token_id, accounts)?;
+    state::record_admin_action("set_token_burn_addresses", format!(
+        "{}: {} accounts", crate::allowances::hex_encode(&token_id), count
+    ));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::BurnAddressesChanged);
+    Ok(())
+}
+
+
+// Accounts such as a treasury whose balances get_circulating_supply leaves out.
+#[ic_cdk::update]
+pub fn set_token_non_circulating_accounts(token_id: TokenId, accounts: Vec<Account>) -> Result<(), String> {
+    state::require_controller()?;
+    validate_designated_accounts(&accounts)?;
 
-    state::update_token_fee(token_id, fee_amount)
-}
\ No newline at end of file
+    let count = accounts.len();
+    state::update_token_non_circulating_accounts(token_id, accounts)?;
+    state::record_admin_action("set_token_non_circulating_accounts", format!(
+        "{}: {} accounts", crate::allowances::hex_encode(&token_id), count
+    ));
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::NonCirculatingAccountsChanged);
+    Ok(())
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct QuarantinedToken {
+    pub token_id: TokenId,
+    pub error: String,
+}
+
+
+#[ic_cdk::query]
+pub fn list_quarantined_tokens() -> Result<Vec<QuarantinedToken>, String> {
+    state::require_controller()?;
+
+    Ok(state::list_quarantined_tokens()
+        .into_iter()
+        .map(|(token_id, error)| QuarantinedToken { token_id, error })
+        .collect())
+}
+
+
+// One way: nothing can unset the flag. Transfers, burns and queries keep
+// working; fee and metadata setters, fee changes awaiting confirmation and
+// mints are rejected from then on.
+#[ic_cdk::update]
+pub fn freeze_token_config(token_id: TokenId) -> Result<(), String> {
+    state::require_controller()?;
+
+    let metadata = state::get_token_metadata(token_id).map_err(|e| e.to_string())?;
+    if metadata.scheduled_fee.is_some() && !metadata.is_immutable() {
+        return Err("Cancel the scheduled fee change before freezing the token".to_string());
+    }
+    state::freeze_token(token_id).map_err(|e| e.to_string())?;
+    state::record_admin_action("freeze_token_config", crate::allowances::hex_encode(&token_id));
+    Ok(())
+}
+
+
+// Only quarantined entries can be rewritten; healthy tokens go through the
+// regular setters. Balances are untouched, so total_supply must be restored
+// to the value the balances add up to.
+#[ic_cdk::update]
+pub fn repair_token_metadata(token_id: TokenId, metadata: crate::types::StoredTokenMetadata) -> Result<(), String> {
+    state::require_controller()?;
+
+    match state::get_token_metadata(token_id) {
+        Err(state::MetadataError::Corrupted(_)) => {}
+        Err(state::MetadataError::NotFound) => return Err("Token not found".to_string()),
+        Err(e) => return Err(e.to_string()),
+        Ok(current) if current.is_immutable() => {
+            if !metadata.is_immutable() {
+                state::record_admin_action("rejected_token_unfreeze", crate::allowances::hex_encode(&token_id));
+            }
+            return Err(state::MetadataError::TokenImmutable.to_string());
+        }
+        Ok(_) => return Err("Token metadata is not corrupted".to_string()),
+    }
+
+    validate_account(&metadata.fee_recipient).map_err(|e| e.to_string())?;
+    fees::validate_fee_token_config(token_id, metadata.fee_token)?;
+
+    state::register_token(token_id, metadata);
+
+    #[cfg(feature = "notifications")]
+    publish_metadata_change(token_id, MetadataChangeKind::MetadataRepaired);
+    Ok(())
+}
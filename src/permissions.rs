@@ -0,0 +1,245 @@
+use crate::api_access;
+use crate::context::check_gates;
+use crate::privacy;
+use crate::queries::QueryError;
+use crate::runtime;
+use crate::state;
+use crate::types::{ApiAccessGrant, OpKind, TokenId};
+use crate::validation::validate_token_id;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+
+// What a principal may do right now, in one read. Every field comes from the
+// predicate the enforcing path itself calls, so the report cannot drift from
+// what the ledger actually lets through.
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PermissionsReport {
+    pub principal: Principal,
+    pub is_controller: bool,
+    pub is_auditor: bool,
+    pub maintenance_mode: bool,
+    pub paused_ops: Vec<OpKind>,
+    pub privacy_mode: bool,
+    // Holder listings and the transaction log of every token.
+    pub reads_all_accounts: bool,
+    pub token: Option<TokenPermissions>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenPermissions {
+    pub token_id: TokenId,
+    pub is_token_controller: bool,
+    // As stored, even when expired or beyond what its granter still holds.
+    pub api_grant: Option<ApiAccessGrant>,
+    // Holds the right to mint, whether or not minting is open right now.
+    pub can_mint: bool,
+    // burn_tokens_from, which controllers run past maintenance mode.
+    pub can_burn_from: bool,
+    pub can_manage_api_access: bool,
+    pub reads_all_accounts: bool,
+    // The principal whose accounts this one may disburse from, besides its own.
+    pub disburses_for: Option<Principal>,
+    pub can_change_config: bool,
+    pub immutable: bool,
+    pub quarantined: bool,
+    pub import_pending: bool,
+    // One entry per operation kind, started from the principal's own accounts.
+    pub operations: Vec<OperationAccess>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OperationAccess {
+    pub op: OpKind,
+    pub allowed: bool,
+    // The error the operation would fail with; None when allowed.
+    pub refused_because: Option<String>,
+}
+
+
+// Callers may read their own report; controllers and auditors anyone's.
+#[ic_cdk::query]
+pub fn get_effective_permissions(principal: candid::Principal, token_id: Option<TokenId>) -> Result<PermissionsReport, QueryError> {
+    let caller = runtime::caller();
+    if caller != principal && !privacy::is_privileged(&caller) {
+        return Err(QueryError::Unauthorized);
+    }
+    let token = match token_id {
+        Some(token_id) => Some(token_permissions(token_id, &principal)?),
+        None => None,
+    };
+    Ok(PermissionsReport {
+        principal,
+        is_controller: state::is_controller(&principal),
+        is_auditor: state::is_auditor(&principal),
+        maintenance_mode: state::is_maintenance_mode(),
+        paused_ops: OpKind::ALL.into_iter().filter(|op| state::is_op_paused(*op)).collect(),
+        privacy_mode: state::is_privacy_mode(),
+        reads_all_accounts: privacy::reads_all_accounts(None, &principal),
+        token,
+    })
+}
+
+
+fn token_permissions(token_id: TokenId, principal: &Principal) -> Result<TokenPermissions, QueryError> {
+    validate_token_id(&token_id)?;
+    let metadata = match state::get_token_metadata(token_id) {
+        Ok(metadata) => Some(metadata),
+        Err(state::MetadataError::Corrupted(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let is_controller = state::is_controller(principal);
+    let immutable = metadata.as_ref().is_some_and(|m| m.is_immutable());
+    let minter = api_access::check_minter(token_id, principal);
+
+    let operations = OpKind::ALL.into_iter().map(|op| {
+        // Mints take the privileged path; everything else the one users take.
+        let refusal = match (op, &minter) {
+            (OpKind::Mint, Err(e)) => Some(e.clone()),
+            _ => check_gates(op, token_id, op == OpKind::Mint).err().map(String::from),
+        };
+        OperationAccess { op, allowed: refusal.is_none(), refused_because: refusal }
+    }).collect();
+
+    Ok(TokenPermissions {
+        token_id,
+        is_token_controller: metadata.as_ref().is_some_and(|m| m.controller == *principal),
+        api_grant: state::get_api_grant(token_id, principal),
+        can_mint: minter.is_ok(),
+        can_burn_from: is_controller && check_gates(OpKind::Burn, token_id, true).is_ok(),
+        can_manage_api_access: metadata.as_ref().is_some_and(|m| api_access::can_manage(m, principal)),
+        reads_all_accounts: privacy::reads_all_accounts(Some(token_id), principal),
+        disburses_for: api_access::disburses_for(token_id, principal),
+        can_change_config: is_controller && metadata.is_some() && !immutable,
+        immutable,
+        quarantined: metadata.is_none(),
+        import_pending: state::is_import_pending(token_id),
+        operations,
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowances::{approve, transfer_from, ApproveResult, Icrc151ApproveArgs, Icrc151TransferFromArgs};
+    use crate::api_access::grant_token_api_access;
+    use crate::operations::{
+        burn_tokens, burn_tokens_from, disburse, disburse_from, mint_tokens, pause_ops, set_maintenance_mode, transfer,
+        Icrc151TransferArgs, Payout, TransferResult,
+    };
+    use crate::queries::{get_transaction_certified, list_holders};
+    use crate::test_utils::{fee_recipient, register_test_token, test_account};
+    use crate::types::{Account, ApiPermissions};
+    use candid::Nat;
+
+    fn attempt(op: OpKind, token: TokenId, owner: &Account, sink: &Account) -> bool {
+        runtime::advance_time(1);
+        let one = || Nat::from(1u64);
+        match op {
+            OpKind::Transfer => matches!(transfer(Icrc151TransferArgs {
+                token_id: token, from_subaccount: None, to: sink.clone(), amount: one(), fee: None, memo: None,
+                created_at_time: None, private_memo: None, amount_mode: None, use_sponsorship: None, args_version: None,
+            }), TransferResult::Ok(_)),
+            OpKind::Approve => matches!(approve(Icrc151ApproveArgs {
+                token_id: token, spender: sink.clone(), amount: one(), expires_at: None, expected_allowance: None, memo: None,
+                fee: None, from_subaccount: None, created_at_time: None, private_memo: None, args_version: None,
+            }), ApproveResult::Ok(_)),
+            OpKind::TransferFrom => matches!(transfer_from(Icrc151TransferFromArgs {
+                token_id: token, spender_subaccount: None, from: owner.clone(), to: sink.clone(), amount: one(), fee: None,
+                memo: None, created_at_time: None, private_memo: None, args_version: None,
+            }), TransferResult::Ok(_)),
+            OpKind::Burn => burn_tokens(token, one(), None, None, None, None).is_ok(),
+            OpKind::Mint => mint_tokens(token, sink.clone(), one(), None).is_ok(),
+            OpKind::Disburse => disburse(token, None, vec![Payout { to: sink.clone(), amount: one(), memo: None }], None, None).is_ok(),
+        }
+    }
+
+    #[test]
+    fn test_report_matches_enforcement() {
+        let controller = test_account(0xF0).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let token_controller = fee_recipient().owner;
+        let (owner, sink) = (test_account(2), test_account(3));
+        let principals = [
+            controller,
+            token_controller,
+            test_account(0xF1).owner,
+            test_account(7).owner,
+            test_account(8).owner,
+            test_account(9).owner,
+            test_account(1).owner,
+        ];
+
+        runtime::set_caller(controller);
+        crate::privacy::add_auditor(principals[2]).unwrap();
+        let read = ApiPermissions { can_query_private: true, ..Default::default() };
+        grant_token_api_access(token, principals[3], read, None).unwrap();
+        grant_token_api_access(token, principals[5], ApiPermissions { can_mint: true, ..Default::default() }, None).unwrap();
+        runtime::set_caller(token_controller);
+        grant_token_api_access(token, principals[4], ApiPermissions { can_disburse: true, ..Default::default() }, None).unwrap();
+
+        // Minted rather than seeded, so burns have supply to take from.
+        runtime::set_caller(controller);
+        crate::operations::mint_internal(token, owner.clone(), 1_000_000, None, None).unwrap();
+        for principal in principals {
+            runtime::set_caller(controller);
+            crate::operations::mint_internal(token, Account { owner: principal, subaccount: None }, 1_000_000, None, None).unwrap();
+            runtime::set_caller(owner.owner);
+            runtime::advance_time(1);
+            let spender = Account { owner: principal, subaccount: None };
+            crate::allowances::approve(Icrc151ApproveArgs {
+                token_id: token, spender, amount: Nat::from(1_000u64), expires_at: None, expected_allowance: None, memo: None,
+                fee: None, from_subaccount: None, created_at_time: None, private_memo: None, args_version: None,
+            });
+        }
+
+        // Each scenario is applied on top of the ones before it.
+        let scenarios: [fn(); 4] = [
+            || {},
+            || crate::privacy::set_privacy_mode(true).unwrap(),
+            || pause_ops(vec![OpKind::Mint, OpKind::Transfer]).unwrap(),
+            || set_maintenance_mode(true).unwrap(),
+        ];
+        for scenario in scenarios {
+            runtime::set_caller(controller);
+            scenario();
+            for principal in principals {
+                runtime::set_caller(controller);
+                let report = get_effective_permissions(principal, Some(token)).unwrap();
+                let permissions = report.token.clone().unwrap();
+                runtime::set_caller(principal);
+                assert_eq!(get_effective_permissions(principal, None).unwrap().reads_all_accounts, report.reads_all_accounts);
+
+                for access in &permissions.operations {
+                    assert_eq!(access.allowed, attempt(access.op, token, &owner, &sink), "{:?} for {}", access.op, principal);
+                }
+                let disburse_allowed = permissions.operations.iter().any(|a| a.op == OpKind::Disburse && a.allowed);
+                let from = Account { owner: permissions.disburses_for.unwrap_or(token_controller), subaccount: None };
+                let payouts = vec![Payout { to: sink.clone(), amount: Nat::from(1u64), memo: None }];
+                runtime::advance_time(1);
+                assert_eq!(
+                    disburse_from(token, from.clone(), payouts, None, None).is_ok(),
+                    disburse_allowed && (permissions.disburses_for.is_some() || from.owner == principal),
+                );
+                runtime::advance_time(1);
+                assert_eq!(burn_tokens_from(token, owner.clone(), Nat::from(1u64), None, None).is_ok(), permissions.can_burn_from);
+                assert_eq!(list_holders(token, None, None, None).is_ok(), permissions.reads_all_accounts);
+                assert_eq!(get_transaction_certified(0).is_ok(), report.reads_all_accounts);
+            }
+        }
+
+        // Only controllers and auditors read someone else's report.
+        runtime::set_caller(principals[6]);
+        assert!(matches!(get_effective_permissions(controller, None), Err(QueryError::Unauthorized)));
+        runtime::set_caller(principals[2]);
+        let report = get_effective_permissions(principals[4], Some(token)).unwrap();
+        assert_eq!(report.token.unwrap().disburses_for, Some(token_controller));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::queries::QueryError;
+use crate::runtime;
+use crate::state;
+use crate::types::{Account, TokenId};
+use crate::validation::validate_admin_principal;
+use candid::Principal;
+
+
+// Privacy mode restricts per-account reads to the account's owner, controllers
+// and auditors. Aggregates (supply, holder counts, tx counts) stay public.
+// Anonymous callers are never an owner, controller or auditor, so they only
+// get aggregates while the mode is on.
+
+
+pub(crate) fn is_privileged(caller: &Principal) -> bool {
+    state::is_controller(caller) || state::is_auditor(caller)
+}
+
+
+pub(crate) fn require_account_reader(account: &Account) -> Result<(), QueryError> {
+    if !state::is_privacy_mode() {
+        return Ok(());
+    }
+    let caller = runtime::caller();
+    if caller == account.owner || is_privileged(&caller) {
+        return Ok(());
+    }
+    Err(QueryError::Unauthorized)
+}
+
+
+// Either side of an allowance may read it.
+pub(crate) fn require_allowance_reader(owner: &Account, spender: &Account) -> Result<(), QueryError> {
+    if require_account_reader(owner).is_ok() {
+        return Ok(());
+    }
+    require_account_reader(spender)
+}
+
+
+// For data spanning many accounts: holder listings and the raw transaction log.
+pub(crate) fn require_privileged_reader() -> Result<(), QueryError> {
+    require_reader_of_all_accounts(None)
+}
+
+
+// Token-scoped reads also admit API principals holding can_query_private for
+// the token.
+pub(crate) fn is_token_reader(token_id: TokenId, who: &Principal) -> bool {
+    crate::api_access::permits(token_id, who, |p| p.can_query_private).is_some()
+}
+
+
+// Whether `who` may read every account, ledger-wide or of one token.
+pub(crate) fn reads_all_accounts(token_id: Option<TokenId>, who: &Principal) -> bool {
+    !state::is_privacy_mode() || is_privileged(who) || token_id.is_some_and(|token_id| is_token_reader(token_id, who))
+}
+
+
+fn require_reader_of_all_accounts(token_id: Option<TokenId>) -> Result<(), QueryError> {
+    if reads_all_accounts(token_id, &runtime::caller()) {
+        return Ok(());
+    }
+    Err(QueryError::Unauthorized)
+}
+
+
+pub(crate) fn require_token_account_reader(token_id: TokenId, account: &Account) -> Result<(), QueryError> {
+    let result = require_account_reader(account);
+    if result.is_err() && is_token_reader(token_id, &runtime::caller()) {
+        return Ok(());
+    }
+    result
+}
+
+
+pub(crate) fn require_token_allowance_reader(token_id: TokenId, owner: &Account, spender: &Account) -> Result<(), QueryError> {
+    let result = require_allowance_reader(owner, spender);
+    if result.is_err() && is_token_reader(token_id, &runtime::caller()) {
+        return Ok(());
+    }
+    result
+}
+
+
+pub(crate) fn require_token_privileged_reader(token_id: TokenId) -> Result<(), QueryError> {
+    require_reader_of_all_accounts(Some(token_id))
+}
+
+
+// Private memos are readable by the parties recorded with the transaction and
+// by controllers, whether or not privacy mode is on.
+pub(crate) fn can_read_private_memo(tx_index: u64) -> bool {
+    let caller = runtime::caller();
+    state::is_controller(&caller) || state::is_private_memo_reader(tx_index, &caller)
+}
+
+
+#[ic_cdk::update]
+pub fn set_privacy_mode(enabled: bool) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = state::is_privacy_mode();
+    state::set_privacy_mode(enabled);
+    state::record_admin_action("set_privacy_mode", format!("{} -> {}", previous, enabled));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_privacy_mode() -> bool {
+    state::is_privacy_mode()
+}
+
+
+#[ic_cdk::update]
+pub fn add_auditor(auditor: candid::Principal) -> Result<(), String> {
+    state::require_controller()?;
+    validate_admin_principal(&auditor).map_err(|e| e.to_string())?;
+
+    state::add_auditor(auditor)?;
+    state::record_admin_action("add_auditor", auditor.to_text());
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn remove_auditor(auditor: candid::Principal) -> Result<(), String> {
+    state::require_controller()?;
+
+    state::remove_auditor(auditor)?;
+    state::record_admin_action("remove_auditor", auditor.to_text());
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn list_auditors() -> Vec<candid::Principal> {
+    state::list_auditors()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::{get_allowance, get_balance, get_balance_changes, get_balances_for, get_holder_count, get_total_supply, get_transactions, list_holders};
+    use crate::test_utils::{register_test_token, test_account};
+
+    #[test]
+    fn test_privacy_mode() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let (alice, bob, auditor) = (test_account(1), test_account(2), test_account(3));
+        state::seed_balance(token, alice.to_key(), 100);
+        state::set_allowance(token, alice.to_key(), bob.to_key(), 10);
+
+        // Off by default: anyone reads anything.
+        runtime::set_caller(Principal::anonymous());
+        assert_eq!(get_balance(token, alice.clone(), None).unwrap(), 100);
+        assert!(list_holders(token, None, None, None).is_ok());
+
+        runtime::set_caller(controller);
+        set_privacy_mode(true).unwrap();
+        add_auditor(auditor.owner).unwrap();
+        assert!(add_auditor(Principal::anonymous()).is_err());
+
+        for caller in [Principal::anonymous(), test_account(4).owner] {
+            runtime::set_caller(caller);
+            assert!(matches!(get_balance(token, alice.clone(), None), Err(QueryError::Unauthorized)));
+            assert!(matches!(get_balances_for(alice.owner, None, None), Err(QueryError::Unauthorized)));
+            assert!(matches!(get_allowance(token, alice.clone(), bob.clone()), Err(QueryError::Unauthorized)));
+            assert!(matches!(get_balance_changes(alice.clone(), 0, None), Err(QueryError::Unauthorized)));
+            assert!(matches!(list_holders(token, None, None, None), Err(QueryError::Unauthorized)));
+            assert!(matches!(get_transactions(None, None, None, None, None), Err(QueryError::Unauthorized)));
+            // Aggregates stay public.
+            assert_eq!(get_total_supply(token).unwrap(), 0);
+            assert!(get_holder_count(token).is_ok());
+        }
+
+        // The owner, the spender (for the allowance only), controllers and auditors.
+        runtime::set_caller(alice.owner);
+        assert_eq!(get_balance(token, alice.clone(), None).unwrap(), 100);
+        assert!(list_holders(token, None, None, None).is_err());
+        runtime::set_caller(bob.owner);
+        assert_eq!(get_allowance(token, alice.clone(), bob.clone()).unwrap(), 10);
+        assert!(get_balance(token, alice.clone(), None).is_err());
+        for caller in [controller, auditor.owner] {
+            runtime::set_caller(caller);
+            assert_eq!(get_balance(token, alice.clone(), None).unwrap(), 100);
+            assert!(list_holders(token, None, None, None).is_ok());
+        }
+
+        runtime::set_caller(controller);
+        remove_auditor(auditor.owner).unwrap();
+        runtime::set_caller(auditor.owner);
+        assert!(get_balance(token, alice.clone(), None).is_err());
+        assert!(set_privacy_mode(false).is_err());
+    }
+
+    #[test]
+    fn test_private_memo_visibility() {
+        use crate::allowances::{approve, transfer_from, ApproveResult, Icrc151ApproveArgs, Icrc151TransferFromArgs};
+        use crate::operations::{transfer, Icrc151TransferArgs, TransferResult};
+        use crate::queries::{get_transaction_memo, get_transaction_range, TransactionMemo};
+        use crate::types::Memo;
+        use candid::Nat;
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let (alice, bob, carol, dave, outsider) = (test_account(1), test_account(2), test_account(3), test_account(4), test_account(5));
+        state::seed_balance(token, alice.to_key(), 1_000);
+
+        let send = |to: &Account, memo: &[u8], private_memo: Option<bool>| {
+            runtime::advance_time(1);
+            match transfer(Icrc151TransferArgs {
+                token_id: token,
+                from_subaccount: None,
+                to: to.clone(),
+                amount: Nat::from(10u64),
+                fee: None,
+                memo: Some(Memo(memo.to_vec())),
+                created_at_time: None,
+                private_memo,
+                amount_mode: None, use_sponsorship: None, args_version: None,
+            }) {
+                TransferResult::Ok(tx_index) => tx_index,
+                TransferResult::Err(e) => panic!("{:?}", e),
+            }
+        };
+        runtime::set_caller(alice.owner);
+        let private_transfer = send(&bob, b"invoice 42", Some(true));
+        let public_transfer = send(&bob, b"hello", None);
+        let approval = match approve(Icrc151ApproveArgs {
+            token_id: token,
+            spender: carol.clone(),
+            amount: Nat::from(100u64),
+            expires_at: None,
+            expected_allowance: None,
+            memo: Some(Memo(b"standing order".to_vec())),
+            fee: None,
+            from_subaccount: None,
+            created_at_time: None,
+            private_memo: Some(true),
+            args_version: None,
+        }) {
+            ApproveResult::Ok(tx_index) => tx_index,
+            ApproveResult::Err(e) => panic!("{:?}", e),
+        };
+        runtime::set_caller(carol.owner);
+        let pull = match transfer_from(Icrc151TransferFromArgs {
+            token_id: token,
+            spender_subaccount: None,
+            from: alice.clone(),
+            to: dave.clone(),
+            amount: Nat::from(10u64),
+            fee: None,
+            memo: Some(Memo(vec![7u8; 40])),
+            created_at_time: None,
+            private_memo: Some(true),
+            args_version: None,
+        }) {
+            TransferResult::Ok(tx_index) => tx_index,
+            TransferResult::Err(e) => panic!("{:?}", e),
+        };
+
+        // The block keeps only the commitment, and the hash chain covers it.
+        let tx = state::get_transaction(private_transfer).unwrap();
+        let commitment = crate::proof::private_memo_commitment(private_transfer, &tx, b"invoice 42");
+        assert_eq!((tx.memo, tx.has_private_memo(), tx.has_extended_memo()), (commitment, true, false));
+        let parent = private_transfer.checked_sub(1).and_then(state::get_block_hash).unwrap_or(crate::proof::GENESIS_HASH);
+        assert_eq!(state::get_block_hash(private_transfer), Some(crate::proof::block_hash(private_transfer, &parent, &tx, None)));
+        runtime::set_caller(outsider.owner);
+        assert_eq!(get_transaction_range(pull, 1, None, None).unwrap()[0].extended_memo, None);
+
+        let readers = [
+            (private_transfer, b"invoice 42".to_vec(), vec![alice.owner, bob.owner, controller]),
+            (approval, b"standing order".to_vec(), vec![alice.owner, carol.owner, controller]),
+            (pull, vec![7u8; 40], vec![alice.owner, carol.owner, dave.owner, controller]),
+        ];
+        for (tx_index, memo, allowed) in readers {
+            let commitment = state::get_transaction(tx_index).unwrap().memo;
+            for caller in [alice.owner, bob.owner, carol.owner, dave.owner, controller, Principal::anonymous()] {
+                runtime::set_caller(caller);
+                let readable = allowed.contains(&caller);
+                let expected = match readable {
+                    true => TransactionMemo::Memo(memo.clone()),
+                    false => TransactionMemo::MemoPrivate { commitment },
+                };
+                assert_eq!(get_transaction_memo(tx_index).unwrap(), expected);
+                #[cfg(feature = "icrc3")]
+                {
+                    use crate::icrc3::{tx_to_value, Value};
+                    let Value::Map(fields) = tx_to_value(tx_index, &state::get_transaction(tx_index).unwrap()) else {
+                        panic!("blocks are maps");
+                    };
+                    let shown = match readable {
+                        true => ("memo".to_string(), Value::Blob(memo.clone())),
+                        false => ("memo_private".to_string(), Value::Blob(commitment.to_vec())),
+                    };
+                    let memo_fields: Vec<_> = fields.into_iter().filter(|(key, _)| key.starts_with("memo")).collect();
+                    assert_eq!(memo_fields, vec![shown]);
+                }
+            }
+        }
+        assert_eq!(get_transaction_memo(public_transfer).unwrap(), TransactionMemo::Memo(b"hello".to_vec()));
+        assert!(matches!(get_transaction_memo(pull + 1), Err(QueryError::InvalidInput(_))));
+
+        // In privacy mode the parties still read their memos; outsiders get nothing.
+        runtime::set_caller(controller);
+        set_privacy_mode(true).unwrap();
+        runtime::set_caller(bob.owner);
+        assert_eq!(get_transaction_memo(private_transfer).unwrap(), TransactionMemo::Memo(b"invoice 42".to_vec()));
+        assert!(matches!(get_transaction_memo(approval), Err(QueryError::Unauthorized)));
+        runtime::set_caller(outsider.owner);
+        assert!(matches!(get_transaction_memo(public_transfer), Err(QueryError::Unauthorized)));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::transaction::StoredTxV1;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Hash chaining over the transaction log. Everything here is plain Rust so
+// auditors can link the crate and verify exported ranges off-chain.
+
+
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+
+// Commits to the position, the parent, all 256 stored bytes and the full
+// extended memo, so none of them can change without changing every later hash.
+pub fn block_hash(tx_index: u64, parent: &[u8; 32], tx: &StoredTxV1, extended_memo: Option<&[u8]>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"icrc151:block:v1");
+    hasher.update(tx_index.to_be_bytes());
+    hasher.update(parent);
+    hasher.update(tx.to_bytes());
+    match extended_memo {
+        Some(memo) => {
+            hasher.update([1u8]);
+            hasher.update((memo.len() as u64).to_be_bytes());
+            hasher.update(memo);
+        }
+        None => hasher.update([0u8]),
+    }
+    hasher.finalize().into()
+}
+
+
+// Stored inline for private memos. Salted with the block's position, time and
+// payer so equal memos commit differently; a party holding the memo can show
+// it matches. Not encryption: a guessable memo can be confirmed by guessing.
+pub fn private_memo_commitment(tx_index: u64, tx: &StoredTxV1, memo: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"icrc151:private_memo:v1");
+    hasher.update(tx_index.to_be_bytes());
+    hasher.update(tx.timestamp);
+    hasher.update(tx.from_key);
+    hasher.update(memo);
+    hasher.finalize().into()
+}
+
+
+// Covers [start, end). start_hash is the hash of block start - 1 (GENESIS_HASH
+// for 0) and end_hash the hash of block end - 1.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RangeCommitment {
+    pub start: u64,
+    pub end: u64,
+    pub start_hash: [u8; 32],
+    pub end_hash: [u8; 32],
+    pub count: u64,
+    // Only when end is the log length and the call was a query.
+    pub certificate: Option<Vec<u8>>,
+    // When end is the log length: the certified tree pruned to its tip, which
+    // verification::certified_tip checks against the certificate.
+    pub witness: Option<Vec<u8>>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RangeTransaction {
+    pub tx_index: u64,
+    pub transaction: StoredTxV1,
+    pub extended_memo: Option<Vec<u8>>,
+    pub hash: Option<[u8; 32]>,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProofError {
+    CountMismatch { expected: u64, actual: u64 },
+    UnexpectedIndex { expected: u64, actual: u64 },
+    HashMismatch { tx_index: u64 },
+    EndHashMismatch,
+}
+
+
+// Recomputes the chain from start_hash over `range` and checks it ends at end_hash.
+// Per-block hashes, when present, must match along the way.
+pub fn verify_range(commitment: &RangeCommitment, range: &[RangeTransaction]) -> Result<(), ProofError> {
+    if range.len() as u64 != commitment.count {
+        return Err(ProofError::CountMismatch { expected: commitment.count, actual: range.len() as u64 });
+    }
+
+    let mut hash = commitment.start_hash;
+    for (expected, entry) in (commitment.start..).zip(range) {
+        if entry.tx_index != expected {
+            return Err(ProofError::UnexpectedIndex { expected, actual: entry.tx_index });
+        }
+        hash = block_hash(entry.tx_index, &hash, &entry.transaction, entry.extended_memo.as_deref());
+        if entry.hash.is_some_and(|h| h != hash) {
+            return Err(ProofError::HashMismatch { tx_index: entry.tx_index });
+        }
+    }
+
+    if hash != commitment.end_hash {
+        return Err(ProofError::EndHashMismatch);
+    }
+    Ok(())
+}
+
+
+#[cfg(all(test, feature = "canister"))]
+mod tests {
+    use super::*;
+    use crate::queries::{get_range_commitment, get_transaction_range};
+    use crate::verification::certified_tip;
+    use crate::verification::tests::{der_key, sign_certificate, test_key};
+    use crate::{migrations, runtime, state};
+    use candid::{Decode, Encode};
+
+    fn exported_range(start: u64, end: u64) -> (RangeCommitment, Vec<RangeTransaction>) {
+        let commitment = get_range_commitment(start, end).unwrap();
+        // Round-trip through candid, as an auditor would receive it.
+        let range = get_transaction_range(start, end - start, Some(true), None).unwrap();
+        let range = Decode!(&Encode!(&range).unwrap(), Vec<RangeTransaction>).unwrap();
+        (commitment, range)
+    }
+
+    #[test]
+    fn test_verify_range() {
+        migrations::mark_all_complete();
+        let long_memo = [9u8; 40];
+        for i in 0..6u8 {
+            let memo = if i == 2 { Some(&long_memo[..]) } else { None };
+            state::add_transaction_with_memo(StoredTxV1::new_transfer([1; 32], [i; 32], [3; 32], 10, 0, 5, memo), memo);
+        }
+        let root_key = test_key(1);
+        runtime::set_data_certificate(Some(sign_certificate(&root_key, runtime::canister_id(), &runtime::certified_data(), None)));
+
+        let (commitment, range) = exported_range(1, 5);
+        assert_eq!(range[1].extended_memo.as_deref(), Some(&long_memo[..]));
+        assert_eq!(verify_range(&commitment, &range), Ok(()));
+        assert!(commitment.certificate.is_none() && commitment.witness.is_none());
+        let (tip, tip_range) = exported_range(0, 6);
+        let certified = certified_tip(tip.certificate.as_deref().unwrap(), tip.witness.as_deref().unwrap(), &der_key(&root_key), runtime::canister_id());
+        assert_eq!(certified, Ok(state::get_block_hash(5).unwrap()));
+        assert_eq!(certified, Ok(tip.end_hash));
+        assert_eq!(verify_range(&tip, &tip_range), Ok(()));
+
+        let mut tampered = range.clone();
+        tampered[2].transaction.amount = 11u128.to_le_bytes();
+        tampered[2].hash = None;
+        assert_eq!(verify_range(&commitment, &tampered), Err(ProofError::HashMismatch { tx_index: 4 }));
+        tampered[3].hash = None;
+        assert_eq!(verify_range(&commitment, &tampered), Err(ProofError::EndHashMismatch));
+        let mut tampered = range.clone();
+        tampered[1].extended_memo = Some(vec![9u8; 39]);
+        assert_eq!(verify_range(&commitment, &tampered), Err(ProofError::HashMismatch { tx_index: 2 }));
+
+        let mut dropped = range.clone();
+        dropped.remove(3);
+        assert_eq!(verify_range(&commitment, &dropped), Err(ProofError::CountMismatch { expected: 4, actual: 3 }));
+        let dropped_commitment = RangeCommitment { count: 3, ..commitment.clone() };
+        assert_eq!(verify_range(&dropped_commitment, &dropped), Err(ProofError::EndHashMismatch));
+
+        let mut reordered = range.clone();
+        reordered.swap(0, 1);
+        assert_eq!(verify_range(&commitment, &reordered), Err(ProofError::UnexpectedIndex { expected: 1, actual: 2 }));
+        for entry in reordered.iter_mut() {
+            entry.hash = None;
+        }
+        reordered[0].tx_index = 1;
+        reordered[1].tx_index = 2;
+        assert_eq!(verify_range(&commitment, &reordered), Err(ProofError::EndHashMismatch));
+
+        assert!(get_range_commitment(4, 7).is_err());
+        assert!(get_range_commitment(4, 3).is_err());
+    }
+}
@@ -1,5 +1,7 @@
-use crate::types::{Account, TokenId};
+use crate::types::{Account, AdminAuditEntry, AdminExpiredAllowance, DeprecationMode, ScheduledFee, TokenId};
+use crate::rounding::RoundingMode;
 use crate::state;
+use crate::privacy::{can_read_private_memo, require_account_reader, require_privileged_reader, require_token_account_reader, require_token_allowance_reader, require_token_privileged_reader};
 use crate::validation::{validate_account, validate_token_id, ValidationError};
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
@@ -14,6 +16,16 @@ pub struct TokenMetadata {
     pub fee: u128,
     pub logo: Option<String>,
     pub description: Option<String>,
+    pub fee_token: Option<TokenId>,
+    pub require_explicit_fee: bool,
+    pub fee_free_consolidation: bool,
+    pub immutable: bool,
+    pub scheduled_fee: Option<ScheduledFee>,
+    pub burn_addresses: Vec<Account>,
+    pub non_circulating_accounts: Vec<Account>,
+    pub rounding_mode: RoundingMode,
+    pub structured_memos_required: bool,
+    pub max_holders: Option<u64>,
 }
 
 
@@ -26,6 +38,7 @@ pub struct Balance {
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Allowance {
+    pub token_id: TokenId,
     pub owner: Account,
     pub spender: Account,
     pub allowance: u128,
@@ -47,213 +60,1433 @@ pub enum QueryError {
     TokenNotFound,
     InvalidInput(String),
     InternalError(String),
+    StaleReplica { log_length: u64 },
+    TokenCorrupted,
+    NotModified { version: u64 },
+    MigrationPending { migration: String },
+    Unauthorized,
+}
+
+impl From<state::MetadataError> for QueryError {
+    fn from(err: state::MetadataError) -> Self {
+        match err {
+            state::MetadataError::NotFound => QueryError::TokenNotFound,
+            state::MetadataError::Corrupted(_) => QueryError::TokenCorrupted,
+            state::MetadataError::TokenImmutable => QueryError::InvalidInput(err.to_string()),
+        }
+    }
+}
+
+impl From<ValidationError> for QueryError {
+    fn from(err: ValidationError) -> Self {
+        QueryError::InvalidInput(err.to_string())
+    }
+}
+
+
+// Errors with NotModified when `if_changed_since` is given and the version has
+// not moved past it.
+fn check_modified(version: u64, if_changed_since: Option<u64>) -> Result<(), QueryError> {
+    match if_changed_since {
+        Some(since) if version <= since => Err(QueryError::NotModified { version }),
+        _ => Ok(()),
+    }
+}
+
+
+#[ic_cdk::query]
+pub fn get_token_versions(token_ids: Vec<TokenId>) -> Vec<u64> {
+    token_ids.iter().map(|token_id| state::get_mutation_seq(*token_id)).collect()
+}
+
+
+// The `_certified` update methods below run the same read functions as their
+// queries. Going through consensus, their answers can't come from a stale or
+// faulty replica; exchanges crediting deposits should check balances this way.
+
+
+#[ic_cdk::query]
+pub fn get_balance(token_id: TokenId, account: Account, if_changed_since: Option<u64>) -> Result<u128, QueryError> {
+    read_balance(token_id, account, if_changed_since)
+}
+
+
+#[ic_cdk::update]
+pub fn get_balance_certified(token_id: TokenId, account: Account) -> Result<u128, QueryError> {
+    read_balance(token_id, account, None)
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedBalance {
+    pub balance: candid::Nat,
+    // A CBOR hash tree keeping the balance's path and the tip. Check it with
+    // the certificate using verification::verify_balance_certificate.
+    pub witness: Vec<u8>,
+    pub certificate: Vec<u8>,
+}
+
+
+// Lets a holder prove a balance to a third party without trusting this
+// replica: the certificate is signed by the subnet, not by the ledger.
+#[ic_cdk::query]
+pub fn get_balance_with_certificate(token_id: TokenId, account: Account) -> Result<CertifiedBalance, QueryError> {
+    let balance = read_balance(token_id, account.clone(), None)?;
+    let certificate = crate::runtime::data_certificate()
+        .ok_or_else(|| QueryError::InvalidInput("Certificates are only available in query calls".to_string()))?;
+    let witness = state::balance_witness(token_id, account.to_key())
+        .ok_or_else(|| QueryError::MigrationPending { migration: crate::maintenance::CERTIFIED_BALANCES.to_string() })?;
+    Ok(CertifiedBalance {
+        balance: candid::Nat::from(balance),
+        witness,
+        certificate,
+    })
+}
+
+
+fn read_balance(token_id: TokenId, account: Account, if_changed_since: Option<u64>) -> Result<u128, QueryError> {
+    validate_token_id(&token_id)?;
+    validate_account(&account)?;
+    require_token_account_reader(token_id, &account)?;
+    check_modified(state::get_mutation_seq(token_id), if_changed_since)?;
+
+    let account_key = account.to_key();
+    Ok(state::get_balance(token_id, account_key))
+}
+
+
+#[ic_cdk::query]
+pub fn get_allowance(token_id: TokenId, owner: Account, spender: Account) -> Result<u128, QueryError> {
+    read_allowance(token_id, owner, spender)
+}
+
+
+#[ic_cdk::update]
+pub fn get_allowance_certified(token_id: TokenId, owner: Account, spender: Account) -> Result<u128, QueryError> {
+    read_allowance(token_id, owner, spender)
+}
+
+
+fn read_allowance(token_id: TokenId, owner: Account, spender: Account) -> Result<u128, QueryError> {
+    validate_token_id(&token_id)?;
+    validate_account(&owner)?;
+    validate_account(&spender)?;
+    require_token_allowance_reader(token_id, &owner, &spender)?;
+
+    let owner_key = owner.to_key();
+    let spender_key = spender.to_key();
+
+    Ok(state::get_live_allowance(token_id, owner_key, spender_key))
+}
+
+
+#[ic_cdk::query]
+pub fn get_allowance_details(token_id: TokenId, owner: Account, spender: Account) -> Result<Allowance, QueryError> {
+    validate_token_id(&token_id)?;
+    validate_account(&owner)?;
+    validate_account(&spender)?;
+    require_token_allowance_reader(token_id, &owner, &spender)?;
+
+    let owner_key = owner.to_key();
+    let spender_key = spender.to_key();
+
+    // An expired allowance reads as zero; expires_at says why.
+    let allowance_amount = state::get_live_allowance(token_id, owner_key, spender_key);
+    let expires_at = state::get_allowance_expiry(token_id, owner_key, spender_key);
+
+    Ok(Allowance {
+        token_id,
+        owner,
+        spender,
+        allowance: allowance_amount,
+        expires_at,
+    })
+}
+
+
+pub const DEFAULT_ALLOWANCE_PAGE: u64 = 100;
+pub const MAX_ALLOWANCE_PAGE: u64 = 500;
+
+
+// Every live allowance `owner` has granted, on any token, from the owner-side
+// allowance index. `start` counts live allowances only, so a short page is
+// the last one.
+#[ic_cdk::query]
+pub fn list_allowances_of(owner: Account, start: Option<u64>, limit: Option<u64>) -> Result<Vec<Allowance>, QueryError> {
+    validate_account(&owner)?;
+    require_account_reader(&owner)?;
+
+    let start = start.unwrap_or(0) as usize;
+    let limit = limit.unwrap_or(DEFAULT_ALLOWANCE_PAGE).clamp(1, MAX_ALLOWANCE_PAGE) as usize;
+    Ok(state::list_live_owner_allowances(owner.to_key(), start, limit)
+        .into_iter()
+        .filter_map(|(token_id, spender_key, allowance, expires_at)| {
+            let spender = state::get_registered_account(spender_key)?;
+            Some(Allowance { token_id, owner: owner.clone(), spender, allowance, expires_at })
+        })
+        .collect())
+}
+
+
+// Every live allowance granted to `spender`, optionally on one token only,
+// from the spender-side allowance index. Paged as list_allowances_of.
+#[ic_cdk::query]
+pub fn list_allowances_for_spender(
+    spender: Account,
+    token_id: Option<TokenId>,
+    start: Option<u64>,
+    limit: Option<u64>,
+) -> Result<Vec<Allowance>, QueryError> {
+    validate_account(&spender)?;
+    require_account_reader(&spender)?;
+    if let Some(tid) = token_id {
+        validate_token_id(&tid)?;
+    }
+
+    let start = start.unwrap_or(0) as usize;
+    let limit = limit.unwrap_or(DEFAULT_ALLOWANCE_PAGE).clamp(1, MAX_ALLOWANCE_PAGE) as usize;
+    Ok(state::list_live_spender_allowances(spender.to_key(), token_id, start, limit)
+        .into_iter()
+        .filter_map(|(token_id, owner_key, allowance, expires_at)| {
+            let owner = state::get_registered_account(owner_key)?;
+            Some(Allowance { token_id, owner, spender: spender.clone(), allowance, expires_at })
+        })
+        .collect())
+}
+
+
+// Transactions get_allowance_at reads per call before handing back a cursor.
+pub const MAX_ALLOWANCE_REPLAY_SCAN: usize = 5_000;
+
+
+// Where an unfinished replay stops: the token seq to continue from, downwards,
+// and what the pulls seen so far took out of the allowance.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AllowanceReplayCursor {
+    pub token_seq: u64,
+    pub pulled: u128,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AllowanceAt {
+    // `approved_at` is the approval the value derives from; None when the pair
+    // had none by then.
+    Found { allowance: u128, approved_at: Option<u64> },
+    // Call again with `resume` to keep scanning.
+    Incomplete { resume: AllowanceReplayCursor },
+}
+
+
+// The allowance as the log has it right after transaction `at_tx_index`: the
+// latest approval for the pair at or before it (approvals record the
+// resulting allowance) less what transfer_from pulled since, amount and fee.
+// Nothing indexes approvals by pair, and zero-fee approvals are not in the
+// owner's account index, so this walks the token's transactions backwards
+// through the per-token seq index, at most MAX_ALLOWANCE_REPLAY_SCAN per call.
+// Expiries and sweeps are not in the log and are not applied.
+#[ic_cdk::query]
+pub fn get_allowance_at(
+    token_id: TokenId,
+    owner: Account,
+    spender: Account,
+    at_tx_index: u64,
+    resume: Option<AllowanceReplayCursor>,
+) -> Result<AllowanceAt, QueryError> {
+    validate_token_id(&token_id)?;
+    validate_account(&owner)?;
+    validate_account(&spender)?;
+    require_token_allowance_reader(token_id, &owner, &spender)?;
+    state::get_token_metadata(token_id)?;
+    if at_tx_index >= state::get_transaction_count() {
+        return Err(QueryError::InvalidInput(format!("Transaction {} does not exist yet", at_tx_index)));
+    }
+
+    replay_allowance(token_id, owner.to_key(), spender.to_key(), at_tx_index, resume, MAX_ALLOWANCE_REPLAY_SCAN)
+}
+
+
+fn replay_allowance(
+    token_id: TokenId,
+    owner_key: crate::types::AccountKey,
+    spender_key: crate::types::AccountKey,
+    at_tx_index: u64,
+    resume: Option<AllowanceReplayCursor>,
+    scan_limit: usize,
+) -> Result<AllowanceAt, QueryError> {
+    use crate::transaction::{OP_APPROVE, OP_BURN, OP_TRANSFER_FROM};
+
+    let (from_seq, mut pulled) = match resume {
+        Some(cursor) => (Some(cursor.token_seq), cursor.pulled),
+        None => (state::token_seq_at_or_before(token_id, at_tx_index), 0),
+    };
+    let Some(from_seq) = from_seq else {
+        return Ok(AllowanceAt::Found { allowance: 0, approved_at: None });
+    };
+
+    let scanned = state::list_token_transactions_back(token_id, from_seq, scan_limit);
+    for (_, tx_index) in &scanned {
+        let tx = state::get_transaction(*tx_index)
+            .ok_or_else(|| QueryError::InternalError(format!("Transaction {} is missing", tx_index)))?;
+        if tx.from_key != owner_key || tx.spender_key != spender_key {
+            continue;
+        }
+        match tx.op {
+            OP_APPROVE => {
+                let allowance = match tx.get_amount() {
+                    crate::types::constants::UNLIMITED_ALLOWANCE => crate::types::constants::UNLIMITED_ALLOWANCE,
+                    approved => approved.saturating_sub(pulled),
+                };
+                return Ok(AllowanceAt::Found { allowance, approved_at: Some(*tx_index) });
+            }
+            OP_TRANSFER_FROM => {
+                let fee = if tx.get_fee_token().is_none() { tx.get_fee() } else { 0 };
+                pulled = pulled.saturating_add(tx.get_amount()).saturating_add(fee);
+            }
+            OP_BURN if tx.has_spender() => pulled = pulled.saturating_add(tx.get_amount()),
+            _ => {}
+        }
+    }
+
+    match scanned.last() {
+        Some((0, _)) | None => Ok(AllowanceAt::Found { allowance: 0, approved_at: None }),
+        Some((seq, _)) => Ok(AllowanceAt::Incomplete { resume: AllowanceReplayCursor { token_seq: seq - 1, pulled } }),
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SpenderAllowance {
+    pub spender: Account,
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+}
+
+
+// Which of the caller's accounts `owner` has approved: the default subaccount
+// and every named subaccount the caller has been approved on before.
+#[ic_cdk::query]
+pub fn find_my_allowance(token_id: TokenId, owner: Account) -> Result<Vec<SpenderAllowance>, QueryError> {
+    validate_token_id(&token_id)?;
+    validate_account(&owner)?;
+
+    Ok(state::find_principal_allowances(
+        token_id,
+        owner.to_key(),
+        crate::runtime::caller(),
+        crate::allowances::MAX_SPENDER_SUBACCOUNTS,
+    )
+    .into_iter()
+    .map(|(spender, allowance, expires_at)| SpenderAllowance { spender, allowance, expires_at })
+    .collect())
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceScan {
+    pub token_id: TokenId,
+    pub tx_count: u64,
+    pub balance_seq: u64,
+}
+
+
+// Marks the start of a multi-page balance dump. Pass it to every page; once a
+// page reports `consistent = false`, replay the log from `tx_count` or restart.
+#[ic_cdk::query]
+pub fn begin_balance_scan(token_id: TokenId) -> Result<BalanceScan, QueryError> {
+    validate_token_id(&token_id)?;
+    state::get_token_metadata(token_id)?;
+
+    Ok(BalanceScan {
+        token_id,
+        tx_count: state::get_transaction_count(),
+        balance_seq: state::get_balance_seq(token_id),
+    })
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Holder {
+    pub account_key: Vec<u8>,
+    pub account: Option<Account>,
+    pub balance: candid::Nat,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HoldersPage {
+    pub holders: Vec<Holder>,
+    pub next_cursor: Option<Vec<u8>>,
+    pub consistent: bool,
+}
+
+
+#[ic_cdk::query]
+pub fn list_holders(
+    token_id: TokenId,
+    start_after: Option<Vec<u8>>,
+    limit: Option<u64>,
+    scan: Option<BalanceScan>,
+) -> Result<HoldersPage, QueryError> {
+    validate_token_id(&token_id)?;
+    require_token_privileged_reader(token_id)?;
+    state::get_token_metadata(token_id)?;
+    if scan.as_ref().is_some_and(|s| s.token_id != token_id) {
+        return Err(QueryError::InvalidInput("Scan was started for another token".to_string()));
+    }
+    let start_after = match start_after {
+        Some(bytes) => Some(<[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| QueryError::InvalidInput("Cursor must be 32 bytes".to_string()))?),
+        None => None,
+    };
+
+    const DEFAULT_LIMIT: u64 = 100;
+    const MAX_LIMIT: u64 = 1000;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+
+    let entries = state::list_token_holders(token_id, start_after, limit);
+    let next_cursor = if entries.len() == limit {
+        entries.last().map(|(key, _)| key.to_vec())
+    } else {
+        None
+    };
+
+    Ok(HoldersPage {
+        holders: entries
+            .into_iter()
+            .map(|(key, balance)| Holder {
+                account_key: key.to_vec(),
+                account: state::get_registered_account(key),
+                balance: candid::Nat::from(balance),
+            })
+            .collect(),
+        next_cursor,
+        consistent: scan.is_none_or(|s| s.balance_seq == state::get_balance_seq(token_id)),
+    })
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceChange {
+    pub token_id: TokenId,
+    pub delta: candid::Int,
+    pub tx_index: u64,
+    // The block's timestamp. Shared by every transaction of a round and taken
+    // from created_at_time when given, so neither unique nor increasing.
+    pub timestamp: u64,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceChangesPage {
+    pub changes: Vec<BalanceChange>,
+    // Current balances of every token in `changes`.
+    pub balances: Vec<TokenBalance>,
+    pub next_since_tx_index: u64,
+    pub complete: bool,
+}
+
+
+// Walks the account's transactions from `since_tx_index` on, in tx_index
+// order. `limit` counts transactions and never splits one: a transaction may
+// yield two changes when its fee is paid in another token, amount first.
+// Passing `next_since_tx_index` back pages without gaps or repeats, however
+// many transactions share a timestamp.
+#[ic_cdk::query]
+pub fn get_balance_changes(account: Account, since_tx_index: u64, limit: Option<u64>) -> Result<BalanceChangesPage, QueryError> {
+    validate_account(&account)?;
+    require_account_reader(&account)?;
+
+    const DEFAULT_LIMIT: u64 = 100;
+    const MAX_LIMIT: u64 = 1000;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+
+    let account_key = account.to_key();
+    let tx_indices = state::list_account_transactions(account_key, since_tx_index, limit);
+    let complete = tx_indices.len() < limit;
+
+    let mut changes = Vec::new();
+    for tx_index in &tx_indices {
+        let tx = state::get_transaction(*tx_index)
+            .ok_or_else(|| QueryError::InternalError(format!("Transaction {} is missing", tx_index)))?;
+        for (token_id, delta) in account_deltas(*tx_index, &tx, account_key) {
+            changes.push(BalanceChange { token_id, delta, tx_index: *tx_index, timestamp: tx.get_timestamp() });
+        }
+    }
+
+    let mut token_ids: Vec<TokenId> = Vec::new();
+    for change in &changes {
+        if !token_ids.contains(&change.token_id) {
+            token_ids.push(change.token_id);
+        }
+    }
+
+    let next_since_tx_index = if complete {
+        state::get_transaction_count().max(since_tx_index)
+    } else {
+        tx_indices.last().map_or(since_tx_index, |last| last + 1)
+    };
+
+    Ok(BalanceChangesPage {
+        changes,
+        balances: token_ids
+            .into_iter()
+            .map(|token_id| TokenBalance { token_id, balance: state::get_balance(token_id, account_key) })
+            .collect(),
+        next_since_tx_index,
+        complete,
+    })
+}
+
+
+// Net effect of one transaction on an account, per token, skipping zero deltas.
+// Fee income counts: a fee recipient paying a fee gets its share back.
+pub(crate) fn account_deltas(tx_index: u64, tx: &crate::transaction::StoredTxV1, account_key: crate::types::AccountKey) -> Vec<(TokenId, candid::Int)> {
+    let amount = candid::Int::from(tx.get_amount());
+    let mut deltas: Vec<(TokenId, candid::Int)> = vec![(tx.token_id, candid::Int::from(0))];
+
+    let moves_amount = matches!(tx.op, 0 | 1 | 2 | 4);
+    if moves_amount && tx.op != 1 && tx.from_key == account_key {
+        deltas[0].1 -= amount.clone();
+    }
+    if moves_amount && tx.op != 2 && tx.to_key == account_key {
+        deltas[0].1 += amount;
+    }
+
+    if tx.has_fee() && tx.op != 1 {
+        let mut fee_delta = candid::Int::from(0);
+        if tx.fee_payer() == account_key {
+            fee_delta -= candid::Int::from(tx.get_fee());
+        }
+        for (credited_key, credit) in crate::fees::fee_credits(tx_index, tx) {
+            if credited_key == account_key {
+                fee_delta += candid::Int::from(credit);
+            }
+        }
+        match tx.get_fee_token() {
+            Some(fee_token) if fee_token != tx.token_id => deltas.push((fee_token, fee_delta)),
+            _ => deltas[0].1 += fee_delta,
+        }
+    }
+
+    deltas.retain(|(_, delta)| delta.0 != 0.into());
+    deltas
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AllowanceCursor {
+    pub token_id: TokenId,
+    pub owner: Account,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReceivedAllowance {
+    pub token_id: TokenId,
+    pub owner: Account,
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReceivedAllowancesPage {
+    pub allowances: Vec<ReceivedAllowance>,
+    pub next_cursor: Option<AllowanceCursor>,
+}
+
+
+#[ic_cdk::query]
+pub fn get_allowances_received(
+    spender: Account,
+    token_id: Option<TokenId>,
+    start_after: Option<AllowanceCursor>,
+    limit: Option<u64>,
+) -> Result<ReceivedAllowancesPage, QueryError> {
+    validate_account(&spender)?;
+    require_account_reader(&spender)?;
+    if let Some(tid) = token_id {
+        validate_token_id(&tid)?;
+    }
+    if let Some(cursor) = &start_after {
+        validate_token_id(&cursor.token_id)?;
+        validate_account(&cursor.owner)?;
+    }
+
+    const DEFAULT_LIMIT: u64 = 100;
+    const MAX_LIMIT: u64 = 500;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+
+    let spender_key = spender.to_key();
+    let entries = state::list_spender_allowances(
+        spender_key,
+        token_id,
+        start_after.map(|c| (c.token_id, c.owner.to_key())),
+        limit,
+    );
+
+    let next_cursor = if entries.len() == limit {
+        entries.last().and_then(|(tid, owner_key)| {
+            state::get_registered_account(*owner_key).map(|owner| AllowanceCursor { token_id: *tid, owner })
+        })
+    } else {
+        None
+    };
+
+    let mut allowances = Vec::with_capacity(entries.len());
+    for (tid, owner_key) in entries {
+        let expires_at = state::get_allowance_expiry(tid, owner_key, spender_key);
+        if state::is_allowance_expired(expires_at) {
+            continue;
+        }
+        let allowance = state::get_allowance(tid, owner_key, spender_key);
+        if allowance == 0 {
+            continue;
+        }
+        if let Some(owner) = state::get_registered_account(owner_key) {
+            allowances.push(ReceivedAllowance {
+                token_id: tid,
+                owner,
+                allowance,
+                expires_at,
+            });
+        }
+    }
+
+    Ok(ReceivedAllowancesPage { allowances, next_cursor })
+}
+
+
+#[ic_cdk::query]
+pub fn get_total_supply(token_id: TokenId) -> Result<u128, QueryError> {
+    validate_token_id(&token_id)?;
+
+    Ok(state::get_token_metadata(token_id)?.total_supply)
+}
+
+
+// Total supply less the balances of the token's burn addresses and
+// non-circulating accounts (each counted once).
+#[ic_cdk::query]
+pub fn get_circulating_supply(token_id: TokenId) -> Result<u128, QueryError> {
+    validate_token_id(&token_id)?;
+
+    let stored = state::get_token_metadata(token_id)?;
+    let mut excluded: Vec<crate::types::AccountKey> = Vec::new();
+    for account in stored.burn_addresses.iter().chain(stored.non_circulating_accounts.iter()).flatten() {
+        let key = account.to_key();
+        if !excluded.contains(&key) {
+            excluded.push(key);
+        }
+    }
+    let held = excluded.iter().fold(0u128, |sum, key| sum.saturating_add(state::get_balance(token_id, *key)));
+    Ok(stored.total_supply.saturating_sub(held))
+}
+
+
+#[ic_cdk::query]
+pub fn get_holder_count(token_id: TokenId) -> Result<u64, QueryError> {
+    validate_token_id(&token_id)?;
+
+    if !state::token_exists(token_id) {
+        return Err(QueryError::TokenNotFound);
+    }
+
+    Ok(state::get_holder_count(token_id))
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HolderCountPoint {
+    pub day: u64,
+    pub holder_count: u64,
+}
+
+
+// Days are UTC day indexes (ledger time / 86_400s), one point per day in day
+// order, so adjacent ranges never overlap. Days before the first recorded
+// change, or outside the retention window, are omitted.
+#[ic_cdk::query]
+pub fn get_holder_count_history(token_id: TokenId, from_day: u64, to_day: u64) -> Result<Vec<HolderCountPoint>, QueryError> {
+    validate_token_id(&token_id)?;
+
+    if !state::token_exists(token_id) {
+        return Err(QueryError::TokenNotFound);
+    }
+
+    if from_day > to_day {
+        return Err(QueryError::InvalidInput("from_day must not be after to_day".to_string()));
+    }
+
+    if to_day - from_day >= crate::types::constants::MAX_HOLDER_HISTORY_QUERY_DAYS {
+        return Err(QueryError::InvalidInput(format!(
+            "Range cannot exceed {} days",
+            crate::types::constants::MAX_HOLDER_HISTORY_QUERY_DAYS
+        )));
+    }
+
+    Ok(state::get_holder_count_history(token_id, from_day, to_day)
+        .into_iter()
+        .map(|(day, holder_count)| HolderCountPoint { day, holder_count })
+        .collect())
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ProtocolCommission {
+    pub commission_bps: u16,
+    pub treasury: Option<Account>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FeeStats {
+    pub collected_by_recipient: u128,
+    pub collected_by_treasury: u128,
+}
+
+
+#[ic_cdk::query]
+pub fn get_protocol_commission() -> ProtocolCommission {
+    ProtocolCommission {
+        commission_bps: state::get_protocol_commission_bps(),
+        treasury: state::get_protocol_treasury(),
+    }
+}
+
+
+// Keyed by the token the fees were paid in, which differs from the charged
+// token when a fee token is configured.
+#[ic_cdk::query]
+pub fn get_fee_stats(token_id: TokenId) -> Result<FeeStats, QueryError> {
+    validate_token_id(&token_id)?;
+
+    if !state::token_exists(token_id) {
+        return Err(QueryError::TokenNotFound);
+    }
+
+    let (collected_by_recipient, collected_by_treasury) = state::get_fee_stats(token_id);
+    Ok(FeeStats {
+        collected_by_recipient,
+        collected_by_treasury,
+    })
+}
+
+
+#[ic_cdk::query]
+pub fn get_token_metadata(token_id: TokenId) -> Result<TokenMetadata, QueryError> {
+    validate_token_id(&token_id)?;
+
+    let stored = state::get_token_metadata(token_id)?;
+    Ok(TokenMetadata {
+        name: stored.name,
+        symbol: stored.symbol,
+        decimals: stored.decimals,
+        total_supply: stored.total_supply,
+        fee: stored.fee,
+        logo: stored.logo,
+        description: stored.description,
+        fee_token: stored.fee_token,
+        require_explicit_fee: stored.require_explicit_fee.unwrap_or(false),
+        fee_free_consolidation: stored.fee_free_consolidation.unwrap_or(false),
+        immutable: stored.immutable.unwrap_or(false),
+        scheduled_fee: stored.scheduled_fee,
+        burn_addresses: stored.burn_addresses.unwrap_or_default(),
+        non_circulating_accounts: stored.non_circulating_accounts.unwrap_or_default(),
+        rounding_mode: stored.rounding_mode.unwrap_or_default(),
+        structured_memos_required: stored.structured_memos_required.unwrap_or(false),
+        max_holders: stored.max_holders,
+    })
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransferFee {
+    pub fee: u128,
+    pub fee_token: TokenId,
+    pub scheduled_fee: Option<ScheduledFee>,
+}
+
+
+// The fee a transfer submitted now must pay, and the next one if a change is scheduled.
+#[ic_cdk::query]
+pub fn get_transfer_fee(token_id: TokenId) -> Result<TransferFee, QueryError> {
+    validate_token_id(&token_id)?;
+
+    let stored = state::get_token_metadata(token_id)?;
+    Ok(TransferFee {
+        fee: stored.fee,
+        fee_token: crate::fees::resolve_fee_token(token_id, &stored),
+        scheduled_fee: stored.scheduled_fee,
+    })
+}
+
+
+#[ic_cdk::query]
+pub fn get_transaction_count() -> u64 {
+    state::get_transaction_count()
+}
+
+
+// Blocks in tx_index order. A page covers the indexes [start, start + length),
+// with length capped at MAX_TRANSACTIONS_PAGE, whatever the token filter
+// leaves of it; the next page starts there.
+#[ic_cdk::query]
+pub fn get_transactions(
+    token_id: Option<TokenId>,
+    start: Option<u64>,
+    length: Option<u64>,
+    min_log_length: Option<u64>,
+    only_finalized: Option<bool>,
+) -> Result<Vec<crate::transaction::StoredTxV1>, QueryError> {
+    read_transactions(token_id, start, length, min_log_length, only_finalized.unwrap_or(false))
+}
+
+
+// None past the end of the log.
+#[ic_cdk::update]
+pub fn get_transaction_certified(tx_index: u64) -> Result<Option<crate::transaction::StoredTxV1>, QueryError> {
+    Ok(read_transactions(None, Some(tx_index), Some(1), None, false)?.into_iter().next())
+}
+
+
+fn read_transactions(
+    token_id: Option<TokenId>,
+    start: Option<u64>,
+    length: Option<u64>,
+    min_log_length: Option<u64>,
+    only_finalized: bool,
+) -> Result<Vec<crate::transaction::StoredTxV1>, QueryError> {
+    if let Some(tid) = token_id {
+        validate_token_id(&tid)?;
+    }
+    require_privileged_reader()?;
+
+    let total_count = state::get_transaction_count();
+    if let Some(min_length) = min_log_length {
+        if total_count < min_length {
+            return Err(QueryError::StaleReplica { log_length: total_count });
+        }
+    }
+    let start_idx = start.unwrap_or(0);
+    let requested_length = length.unwrap_or(100).min(crate::types::constants::MAX_TRANSACTIONS_PAGE);
+
+
+    if start_idx >= total_count {
+        return Ok(vec![]);
+    }
+
+
+    let end_idx = (start_idx + requested_length).min(readable_end(total_count, only_finalized));
+
+    let mut results = Vec::new();
+
+    for idx in start_idx..end_idx {
+        if let Some(tx) = state::get_transaction(idx) {
+
+            if let Some(filter_token_id) = token_id {
+                if tx.token_id == filter_token_id {
+                    results.push(tx);
+                }
+            } else {
+
+                results.push(tx);
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+
+// With only_finalized, blocks past the finalized height are left out as if the
+// log ended there.
+fn readable_end(total_count: u64, only_finalized: bool) -> u64 {
+    match only_finalized {
+        true => crate::finality::finalized_end().min(total_count),
+        false => total_count,
+    }
+}
+
+
+pub const MAX_RANGE_TRANSACTIONS: u64 = 1000;
+
+
+// Blocks in [start, start + length) with extended memos, and optionally their
+// hashes, in the form `proof::verify_range` checks against a commitment.
+#[ic_cdk::query]
+pub fn get_transaction_range(
+    start: u64,
+    length: u64,
+    include_hashes: Option<bool>,
+    only_finalized: Option<bool>,
+) -> Result<Vec<crate::proof::RangeTransaction>, QueryError> {
+    require_privileged_reader()?;
+    let include_hashes = include_hashes.unwrap_or(false);
+    let end = start.saturating_add(length.min(MAX_RANGE_TRANSACTIONS))
+        .min(readable_end(state::get_transaction_count(), only_finalized.unwrap_or(false)));
+
+    (start..end)
+        .map(|tx_index| {
+            let transaction = state::get_transaction(tx_index)
+                .ok_or_else(|| QueryError::InternalError(format!("Block {} is missing", tx_index)))?;
+            Ok(crate::proof::RangeTransaction {
+                tx_index,
+                transaction,
+                extended_memo: if transaction.has_extended_memo() { state::get_extended_memo(tx_index) } else { None },
+                hash: if include_hashes { state::get_block_hash(tx_index) } else { None },
+            })
+        })
+        .collect()
+}
+
+
+#[ic_cdk::query]
+pub fn get_range_commitment(start: u64, end: u64) -> Result<crate::proof::RangeCommitment, QueryError> {
+    if let Some(migration) = crate::migrations::blocking_migration("get_range_commitment") {
+        return Err(QueryError::MigrationPending { migration: migration.to_string() });
+    }
+    let total = state::get_transaction_count();
+    if start > end || end > total {
+        return Err(QueryError::InvalidInput(format!("Range must satisfy start <= end <= {}", total)));
+    }
+
+    let hash_before = |index: u64| match index {
+        0 => Ok(crate::proof::GENESIS_HASH),
+        _ => state::get_block_hash(index - 1)
+            .ok_or_else(|| QueryError::InternalError(format!("Block {} has no hash", index - 1))),
+    };
+
+    Ok(crate::proof::RangeCommitment {
+        start,
+        end,
+        start_hash: hash_before(start)?,
+        end_hash: hash_before(end)?,
+        count: end - start,
+        certificate: if end == total { crate::runtime::data_certificate() } else { None },
+        witness: (end == total).then(state::tip_witness),
+    })
+}
+
+
+#[derive(CandidType, Clone, Debug)]
+pub struct TokenTransaction {
+    pub tx_index: u64,
+    pub token_seq: u64,
+    pub transaction: crate::transaction::StoredTxV1,
+}
+
+
+#[ic_cdk::query]
+pub fn get_token_tx_by_seq(token_id: TokenId, token_seq: u64, only_finalized: Option<bool>) -> Result<Option<TokenTransaction>, QueryError> {
+    validate_token_id(&token_id)?;
+    require_token_privileged_reader(token_id)?;
+
+    let end = readable_end(state::get_transaction_count(), only_finalized.unwrap_or(false));
+    Ok(state::get_tx_index_by_token_seq(token_id, token_seq).filter(|tx_index| *tx_index < end).and_then(|tx_index| {
+        state::get_transaction(tx_index).map(|transaction| TokenTransaction { tx_index, token_seq, transaction })
+    }))
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransactionMemo {
+    NoMemo,
+    Memo(Vec<u8>),
+    // A private memo the caller may not read, and the commitment stored in its block.
+    MemoPrivate { commitment: [u8; 32] },
+    // A memo in the structured memo format, with its bytes.
+    Structured { raw: Vec<u8>, memo: crate::memo::StructuredMemo },
+}
+
+
+// The memo as the caller may see it. Inline memos are zero-padded to 32 bytes,
+// so trailing zeros are dropped.
+pub(crate) fn transaction_memo(tx_index: u64, tx: &crate::transaction::StoredTxV1) -> TransactionMemo {
+    if !tx.has_memo() {
+        return TransactionMemo::NoMemo;
+    }
+    if tx.has_private_memo() {
+        return match can_read_private_memo(tx_index) {
+            true => TransactionMemo::Memo(state::get_extended_memo(tx_index).unwrap_or_default()),
+            false => TransactionMemo::MemoPrivate { commitment: tx.memo },
+        };
+    }
+    if tx.has_extended_memo() {
+        return TransactionMemo::Memo(state::get_extended_memo(tx_index).unwrap_or_else(|| tx.memo.to_vec()));
+    }
+    let len = tx.memo.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    TransactionMemo::Memo(tx.memo[..len].to_vec())
+}
+
+
+// Parties to a private memo read it even in privacy mode; everyone else needs
+// the same access as get_transactions. Memos in the structured memo format
+// come back parsed.
+#[ic_cdk::query]
+pub fn get_transaction_memo(tx_index: u64) -> Result<TransactionMemo, QueryError> {
+    let tx = state::get_transaction(tx_index)
+        .ok_or_else(|| QueryError::InvalidInput(format!("Transaction {} not found", tx_index)))?;
+    if !(tx.has_private_memo() && can_read_private_memo(tx_index)) {
+        require_privileged_reader()?;
+    }
+    Ok(match transaction_memo(tx_index, &tx) {
+        TransactionMemo::Memo(raw) => match crate::memo::parse_structured_memo(&raw) {
+            Some(memo) => TransactionMemo::Structured { raw, memo },
+            None => TransactionMemo::Memo(raw),
+        },
+        memo => memo,
+    })
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DedupOperation {
+    Transfer,
+    TransferFrom,
+    Approve,
+    Mint,
+    Burn,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RetryCheckArgs {
+    pub operation: DedupOperation,
+    // The principal the operation is deduplicated on: the caller, except for
+    // mints (the recipient). Defaults to the caller.
+    pub principal: Option<Principal>,
+    pub token_id: TokenId,
+    pub created_at_time: u64,
+    pub memo: Option<Vec<u8>>,
+    // The recipient, or the spender for approvals. None for burns.
+    pub destination: Option<Account>,
+    // As submitted; the allowance for approvals.
+    pub amount: candid::Nat,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RetryStatus {
+    // Nothing executed with these inputs. A submission before `valid_until`
+    // is new, whatever its fee.
+    Available { valid_until: u64 },
+    // Executed as `tx_index`. Resubmitting returns Duplicate.
+    Consumed { tx_index: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+
+// Only successful operations record dedup entries, so a transfer rejected with
+// BadFee leaves its created_at_time and memo free for the retry.
+#[ic_cdk::query]
+pub fn can_retry(args: RetryCheckArgs) -> Result<RetryStatus, QueryError> {
+    use crate::transaction::{OP_APPROVE, OP_BURN, OP_MINT, OP_TRANSFER, OP_TRANSFER_FROM};
+
+    validate_token_id(&args.token_id)?;
+    let principal = args.principal.unwrap_or_else(crate::runtime::caller);
+    require_account_reader(&Account { owner: principal, subaccount: None })?;
+
+    let op = match args.operation {
+        DedupOperation::Transfer => OP_TRANSFER,
+        DedupOperation::TransferFrom => OP_TRANSFER_FROM,
+        DedupOperation::Approve => OP_APPROVE,
+        DedupOperation::Mint => OP_MINT,
+        DedupOperation::Burn => OP_BURN,
+    };
+    let amount = num_traits::ToPrimitive::to_u128(&args.amount.0).ok_or(QueryError::InvalidInput("Amount exceeds maximum value (u128::MAX)".to_string()))?;
+    let destination = args.destination.as_ref().map(Account::to_key);
+    let dedup_key = state::compute_dedup_key(op, principal, args.token_id, args.created_at_time, args.memo.as_deref(), destination, amount);
+    if let Some(tx_index) = state::check_duplicate(dedup_key) {
+        return Ok(RetryStatus::Consumed { tx_index });
+    }
+
+    let now = crate::runtime::time();
+    let (max_past_drift, max_future_drift) = state::get_tx_window();
+    if args.created_at_time > now.saturating_add(max_future_drift) {
+        return Ok(RetryStatus::CreatedInFuture { ledger_time: now });
+    }
+    let valid_until = args.created_at_time.saturating_add(max_past_drift);
+    if valid_until < now {
+        return Ok(RetryStatus::TooOld);
+    }
+    Ok(RetryStatus::Available { valid_until })
+}
+
+
+#[ic_cdk::query]
+pub fn health_check() -> String {
+    format!(
+        "ICRC-151 Canister v0.1.0 - Controller: {:?} - Transactions: {}",
+        state::get_controller(),
+        state::get_transaction_count()
+    )
+}
+
+
+#[ic_cdk::query]
+pub fn get_info() -> CanisterInfo {
+    CanisterInfo {
+        name: "ICRC-151 Multi-Token Ledger".to_string(),
+        version: "0.1.0".to_string(),
+        controller: state::get_controller()
+            .map(|p| p.to_text())
+            .unwrap_or("None".to_string()),
+        transaction_count: state::get_transaction_count(),
+        global_tx_count: state::get_global_tx_count(),
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CanisterInfo {
+    pub name: String,
+    pub version: String,
+    pub controller: String,
+    pub transaction_count: u64,
+    pub global_tx_count: u64,
+}
+
+
+#[ic_cdk::query]
+pub fn derive_token_id_v2(creator: candid::Principal, namespace: Vec<u8>) -> Result<TokenId, QueryError> {
+    crate::validation::validate_token_namespace(&namespace)?;
+    Ok(crate::types::derive_token_id_v2(crate::runtime::canister_id(), creator, &namespace))
+}
+
+
+#[ic_cdk::query]
+pub fn list_tokens() -> Vec<TokenId> {
+    state::list_token_ids()
 }
 
-impl From<ValidationError> for QueryError {
-    fn from(err: ValidationError) -> Self {
-        QueryError::InvalidInput(err.to_string())
-    }
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TokenBalance {
+    pub token_id: TokenId,
+    pub balance: u128,
 }
 
 
+// Counts the account's balance changes in every token; other accounts'
+// activity leaves it alone.
 #[ic_cdk::query]
-pub fn get_balance(token_id: TokenId, account: Account) -> Result<u128, QueryError> {
-    validate_token_id(&token_id)?;
+pub fn get_account_version(account: Account) -> Result<u64, QueryError> {
     validate_account(&account)?;
-    
-    let account_key = account.to_key();
-    Ok(state::get_balance(token_id, account_key))
+    require_account_reader(&account)?;
+    Ok(state::get_account_balance_seq(account.to_key()))
 }
 
 
+// Versioned by the account's own balance changes, whatever the token.
 #[ic_cdk::query]
-pub fn get_allowance(token_id: TokenId, owner: Account, spender: Account) -> Result<u128, QueryError> {
-    validate_token_id(&token_id)?;
-    validate_account(&owner)?;
-    validate_account(&spender)?;
+pub fn get_balances_for(
+    owner: candid::Principal,
+    subaccount: Option<Vec<u8>>,
+    if_changed_since: Option<u64>,
+) -> Result<Vec<TokenBalance>, QueryError> {
+    let account = Account { owner, subaccount };
+    validate_account(&account)?;
+    require_account_reader(&account)?;
+    check_modified(state::get_account_balance_seq(account.to_key()), if_changed_since)?;
 
-    let owner_key = owner.to_key();
-    let spender_key = spender.to_key();
+    Ok(non_zero_balances(account.to_key(), &held_token_ids(account.to_key())))
+}
 
-    Ok(state::get_allowance(token_id, owner_key, spender_key))
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AccountBalances {
+    pub account: Account,
+    pub balances: Vec<TokenBalance>,
 }
 
 
-#[ic_cdk::query]
-pub fn get_allowance_details(token_id: TokenId, owner: Account, spender: Account) -> Result<Allowance, QueryError> {
-    validate_token_id(&token_id)?;
-    validate_account(&owner)?;
-    validate_account(&spender)?;
+pub const MAX_BALANCE_QUERY_ACCOUNTS: usize = 20;
 
-    let owner_key = owner.to_key();
-    let spender_key = spender.to_key();
 
-    let allowance_amount = state::get_allowance(token_id, owner_key, spender_key);
-    let expires_at = state::get_allowance_expiry(token_id, owner_key, spender_key);
+#[ic_cdk::query]
+pub fn get_balances_for_accounts(accounts: Vec<Account>) -> Result<Vec<AccountBalances>, QueryError> {
+    if accounts.len() > MAX_BALANCE_QUERY_ACCOUNTS {
+        return Err(QueryError::InvalidInput(format!(
+            "At most {} accounts per call",
+            MAX_BALANCE_QUERY_ACCOUNTS
+        )));
+    }
 
-    Ok(Allowance {
-        owner,
-        spender,
-        allowance: allowance_amount,
-        expires_at,
-    })
-}
+    for account in &accounts {
+        validate_account(account)?;
+        require_account_reader(account)?;
+    }
 
+    Ok(accounts
+        .into_iter()
+        .map(|account| {
+            let balances = non_zero_balances(account.to_key(), &held_token_ids(account.to_key()));
+            AccountBalances { account, balances }
+        })
+        .collect())
+}
 
-#[ic_cdk::query]
-pub fn get_total_supply(token_id: TokenId) -> Result<u128, QueryError> {
-    validate_token_id(&token_id)?;
 
-    match state::get_token_metadata(token_id) {
-        Some(metadata) => Ok(metadata.total_supply),
-        None => Err(QueryError::TokenNotFound),
+// Every token until the account index is backfilled, after that only the held ones.
+fn held_token_ids(account_key: crate::types::AccountKey) -> Vec<TokenId> {
+    if crate::migrations::is_complete(crate::migrations::ACCOUNT_TOKENS_INDEX) {
+        state::list_account_tokens(account_key)
+    } else {
+        state::list_token_ids()
     }
 }
 
 
 #[ic_cdk::query]
-pub fn get_holder_count(token_id: TokenId) -> Result<u64, QueryError> {
-    validate_token_id(&token_id)?;
-
-    if !state::token_exists(token_id) {
-        return Err(QueryError::TokenNotFound);
+pub fn get_account_tokens(account: Account) -> Result<Vec<TokenId>, QueryError> {
+    if let Some(migration) = crate::migrations::blocking_migration("get_account_tokens") {
+        return Err(QueryError::MigrationPending { migration: migration.to_string() });
     }
+    validate_account(&account)?;
+    require_account_reader(&account)?;
 
-    Ok(state::get_holder_count(token_id))
+    Ok(state::list_account_tokens(account.to_key()))
 }
 
 
-#[ic_cdk::query]
-pub fn get_token_metadata(token_id: TokenId) -> Result<TokenMetadata, QueryError> {
-    validate_token_id(&token_id)?;
+fn non_zero_balances(account_key: crate::types::AccountKey, token_ids: &[TokenId]) -> Vec<TokenBalance> {
+    token_ids
+        .iter()
+        .filter_map(|token_id| {
+            let balance = state::get_balance(*token_id, account_key);
+            (balance > 0).then_some(TokenBalance { token_id: *token_id, balance })
+        })
+        .collect()
+}
 
-    match state::get_token_metadata(token_id) {
-        Some(stored) => Ok(TokenMetadata {
-            name: stored.name,
-            symbol: stored.symbol,
-            decimals: stored.decimals,
-            total_supply: stored.total_supply,
-            fee: stored.fee,
-            logo: stored.logo,
-            description: stored.description,
-        }),
-        None => Err(QueryError::TokenNotFound),
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct InterfaceVersion {
+    pub version: String,
+    pub features: Vec<String>,
 }
 
 
-#[ic_cdk::query]
-pub fn get_transaction_count() -> u64 {
-    state::get_transaction_count()
+pub fn enabled_features() -> Vec<String> {
+    let features = [
+        ("icrc3", cfg!(feature = "icrc3")),
+        ("notifications", cfg!(feature = "notifications")),
+        ("relay", cfg!(feature = "relay")),
+        ("dev", cfg!(feature = "dev")),
+    ];
+
+    features
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect()
 }
 
 
-#[ic_cdk::query]
-pub fn get_transactions(
-    token_id: Option<TokenId>,
-    start: Option<u64>,
-    length: Option<u64>,
-) -> Result<Vec<crate::transaction::StoredTxV1>, QueryError> {
-    if let Some(tid) = token_id {
-        validate_token_id(&tid)?;
-    }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Deprecation {
+    pub method: String,
+    pub replacement: String,
+    pub sunset_hint: Option<u64>,
+    pub mode: DeprecationMode,
+}
 
-    const MAX_RESULTS: u64 = 1000;
 
-    let total_count = state::get_transaction_count();
-    let start_idx = start.unwrap_or(0);
-    let requested_length = length.unwrap_or(100).min(MAX_RESULTS);
+// Legacy endpoints and their replacements. Every method listed here counts its
+// calls via state::record_legacy_call.
+pub const DEPRECATED_METHODS: &[(&str, &str)] = &[
+    ("transfer", "transfer_v2"),
+    ("transfer_from", "transfer_from_v2"),
+    ("approve", "approve_v2"),
+    ("mint_tokens", "mint_tokens_v2"),
+    ("burn_tokens", "burn_tokens_v2"),
+    ("create_token", "create_token_with_args"),
+];
 
 
-    if start_idx >= total_count {
-        return Ok(vec![]);
-    }
+#[ic_cdk::query]
+pub fn get_deprecations() -> Vec<Deprecation> {
+    DEPRECATED_METHODS
+        .iter()
+        .map(|(method, replacement)| Deprecation {
+            method: method.to_string(),
+            replacement: replacement.to_string(),
+            sunset_hint: None,
+            mode: state::get_deprecation_mode(method),
+        })
+        .collect()
+}
 
 
-    let end_idx = (start_idx + requested_length).min(total_count);
+// Deprecated methods that check their mode. Others are always Allowed.
+pub const GUARDED_METHODS: &[&str] = &["create_token"];
 
-    let mut results = Vec::new();
+// Suffixes of the legacy call counters kept per mode outcome.
+const WARNED_SUFFIX: &str = ":warned";
+const BLOCKED_SUFFIX: &str = ":blocked";
 
-    for idx in start_idx..end_idx {
-        if let Some(tx) = state::get_transaction(idx) {
 
-            if let Some(filter_token_id) = token_id {
-                if tx.token_id == filter_token_id {
-                    results.push(tx);
-                }
-            } else {
+// Warn and Blocked let a controller retire an endpoint in steps: first see
+// who still calls it, then refuse it, without an upgrade for either.
+#[ic_cdk::update]
+pub fn set_deprecation_mode(method: String, mode: DeprecationMode) -> Result<(), String> {
+    state::require_controller()?;
+    if !GUARDED_METHODS.contains(&method.as_str()) {
+        return Err(format!("Deprecation modes apply to: {}", GUARDED_METHODS.join(", ")));
+    }
 
-                results.push(tx);
-            }
+    let previous = state::get_deprecation_mode(&method);
+    state::set_deprecation_mode(&method, mode);
+    state::record_admin_action("set_deprecation_mode", format!("{}: {:?} -> {:?}", method, previous, mode));
+    Ok(())
+}
+
+
+// Called by a guarded endpoint after it counted the call. Err carries the
+// replacement when the endpoint is blocked.
+pub(crate) fn check_deprecation(method: &str) -> Result<(), String> {
+    let replacement = DEPRECATED_METHODS
+        .iter()
+        .find(|(m, _)| *m == method)
+        .map(|(_, replacement)| replacement.to_string())
+        .unwrap_or_default();
+
+    match state::get_deprecation_mode(method) {
+        DeprecationMode::Allowed => Ok(()),
+        DeprecationMode::Warn => {
+            state::record_legacy_call(&format!("{}{}", method, WARNED_SUFFIX));
+            state::record_admin_action(method, format!("Deprecated endpoint called; use {}", replacement));
+            Ok(())
+        }
+        DeprecationMode::Blocked => {
+            state::record_legacy_call(&format!("{}{}", method, BLOCKED_SUFFIX));
+            Err(replacement)
         }
     }
+}
 
-    Ok(results)
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LegacyCallCount {
+    pub method: String,
+    // Every call, including those refused while Blocked.
+    pub count: u64,
+    pub warned: u64,
+    pub blocked: u64,
 }
 
 
 #[ic_cdk::query]
-pub fn health_check() -> String {
-    format!(
-        "ICRC-151 Canister v0.1.0 - Controller: {:?} - Transactions: {}",
-        state::get_controller(),
-        state::get_transaction_count()
-    )
+pub fn get_legacy_call_counts() -> Result<Vec<LegacyCallCount>, String> {
+    state::require_controller()?;
+
+    let counters = state::list_legacy_call_counts();
+    let outcome = |method: &str, suffix: &str| {
+        let key = format!("{}{}", method, suffix);
+        counters.iter().find(|(m, _)| *m == key).map_or(0, |(_, count)| *count)
+    };
+    Ok(counters
+        .iter()
+        .filter(|(method, _)| !method.ends_with(WARNED_SUFFIX) && !method.ends_with(BLOCKED_SUFFIX))
+        .map(|(method, count)| LegacyCallCount {
+            method: method.clone(),
+            count: *count,
+            warned: outcome(method, WARNED_SUFFIX),
+            blocked: outcome(method, BLOCKED_SUFFIX),
+        })
+        .collect())
 }
 
 
 #[ic_cdk::query]
-pub fn get_info() -> CanisterInfo {
-    CanisterInfo {
-        name: "ICRC-151 Multi-Token Ledger".to_string(),
-        version: "0.1.0".to_string(),
-        controller: state::get_controller()
-            .map(|p| p.to_text())
-            .unwrap_or("None".to_string()),
-        transaction_count: state::get_transaction_count(),
-        global_tx_count: state::get_global_tx_count(),
+pub fn get_interface_version() -> InterfaceVersion {
+    InterfaceVersion {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: enabled_features(),
     }
 }
 
+
+// CANDID_HASH is the SHA-256 of candid/icrc151.did, generated by build.rs.
+mod build_info {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct CanisterInfo {
-    pub name: String,
-    pub version: String,
-    pub controller: String,
-    pub transaction_count: u64,
-    pub global_tx_count: u64,
+pub struct BuildInfo {
+    pub crate_version: String,
+    pub git_commit: Option<String>,
+    pub candid_hash: [u8; 32],
+    pub wasm_features: Vec<String>,
 }
 
 
+// The git commit is whatever ICRC151_GIT_COMMIT held at build time.
 #[ic_cdk::query]
-pub fn list_tokens() -> Vec<TokenId> {
-    state::list_token_ids()
+pub fn get_build_info() -> BuildInfo {
+    BuildInfo {
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("ICRC151_GIT_COMMIT").filter(|c| !c.is_empty()).map(str::to_string),
+        candid_hash: build_info::CANDID_HASH,
+        wasm_features: enabled_features(),
+    }
 }
 
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct TokenBalance {
-    pub token_id: TokenId,
-    pub balance: u128,
+// Limits clients would otherwise hardcode. Every field reads the same constant
+// or setting the enforcing code reads.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LedgerConfig {
+    pub max_future_drift_ns: u64,
+    pub max_past_drift_ns: u64,
+    pub max_memo_len: u64,
+    pub inline_memo_len: u64,
+    pub default_token_fee: u128,
+    pub max_transactions_page: u64,
+    pub max_range_transactions: u64,
+    pub max_audit_log_page: u64,
+    pub max_balance_query_accounts: u64,
+    pub max_consolidation_subaccounts: u64,
+    pub max_spender_subaccounts: u64,
+    pub max_netting_entries: u64,
+    pub max_incoming_allowlist: u64,
+    pub max_tokens: u64,
+    pub stable_pages_soft_limit: u64,
+    pub holder_soft_cap: u64,
+    pub holder_history_retention_days: u32,
+    pub protocol_commission_bps: u16,
 }
 
 
 #[ic_cdk::query]
-pub fn get_balances_for(owner: candid::Principal, subaccount: Option<Vec<u8>>) -> Vec<TokenBalance> {
-    let account = Account { owner, subaccount };
-    let account_key = account.to_key();
-    let token_ids = state::list_token_ids();
-
-    let mut results = Vec::with_capacity(token_ids.len());
-    for token_id in token_ids.into_iter() {
-        let amount = state::get_balance(token_id, account_key);
-        if amount > 0 {
-            results.push(TokenBalance { token_id, balance: amount });
-        }
+pub fn get_ledger_config() -> LedgerConfig {
+    use crate::types::constants;
+
+    LedgerConfig {
+        max_future_drift_ns: state::max_future_drift(),
+        max_past_drift_ns: state::max_past_drift(),
+        max_memo_len: constants::MAX_MEMO_LEN as u64,
+        inline_memo_len: constants::INLINE_MEMO_LEN as u64,
+        default_token_fee: state::get_default_token_fee(),
+        max_transactions_page: constants::MAX_TRANSACTIONS_PAGE,
+        max_range_transactions: MAX_RANGE_TRANSACTIONS,
+        max_audit_log_page: constants::MAX_AUDIT_LOG_PAGE,
+        max_balance_query_accounts: MAX_BALANCE_QUERY_ACCOUNTS as u64,
+        max_consolidation_subaccounts: crate::operations::MAX_CONSOLIDATION_SUBACCOUNTS as u64,
+        max_spender_subaccounts: crate::allowances::MAX_SPENDER_SUBACCOUNTS as u64,
+        max_netting_entries: constants::MAX_NETTING_ENTRIES as u64,
+        max_incoming_allowlist: constants::MAX_INCOMING_ALLOWLIST as u64,
+        max_tokens: state::get_max_tokens(),
+        stable_pages_soft_limit: state::get_stable_pages_soft_limit(),
+        holder_soft_cap: state::get_holder_soft_cap(),
+        holder_history_retention_days: state::get_holder_history_retention_days(),
+        protocol_commission_bps: state::get_protocol_commission_bps(),
     }
-    results
 }
 
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct StorageStats {
     pub transaction_log_size: u64,
@@ -261,6 +1494,7 @@ pub struct StorageStats {
     pub allowance_expiry_size: u64,
     pub extended_memos_size: u64,
     pub holder_counts_size: u64,
+    pub allowances_cleaned_up: u64,
     pub token_count: u64,
     pub estimated_memory_bytes: u64,
 }
@@ -272,7 +1506,7 @@ pub fn get_storage_stats() -> StorageStats {
     let expiry_size = state::get_allowance_expiry_size();
     let memo_size = state::get_extended_memos_size();
     let holder_counts_size = state::get_holder_counts_size();
-    let token_count = state::list_token_ids().len() as u64;
+    let token_count = state::get_token_count();
 
     let estimated_memory = (tx_count * 256)
         + (dedup_size * 40)
@@ -286,11 +1520,44 @@ pub fn get_storage_stats() -> StorageStats {
         allowance_expiry_size: expiry_size,
         extended_memos_size: memo_size,
         holder_counts_size,
+        allowances_cleaned_up: state::get_allowances_cleaned_up(),
         token_count,
         estimated_memory_bytes: estimated_memory,
     }
 }
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Capacity {
+    pub tokens_used: u64,
+    pub max_tokens: u64,
+    pub stable_pages_used: u64,
+    pub stable_pages_soft_limit: u64,
+}
+
+#[ic_cdk::query]
+pub fn get_capacity() -> Capacity {
+    Capacity {
+        tokens_used: state::get_token_count(),
+        max_tokens: state::get_max_tokens(),
+        stable_pages_used: crate::runtime::stable_pages(),
+        stable_pages_soft_limit: state::get_stable_pages_soft_limit(),
+    }
+}
+
+#[ic_cdk::query]
+pub fn get_admin_audit_log(start: u64, length: u64) -> Vec<AdminAuditEntry> {
+    let length = length.min(crate::types::constants::MAX_AUDIT_LOG_PAGE);
+    state::list_admin_actions(start, length as usize)
+}
+
+
+// Keyed by the index of the matching audit log entry.
+#[ic_cdk::query]
+pub fn list_admin_expired_allowances(start: u64, length: u64) -> Vec<AdminExpiredAllowance> {
+    let length = length.min(crate::types::constants::MAX_AUDIT_LOG_PAGE);
+    state::list_admin_expired_allowances(start, length as usize)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -306,7 +1573,7 @@ mod tests {
         
 
         let token_id = [1u8; 32];
-        assert_eq!(get_balance(token_id, account.clone()).unwrap(), 0);
+        assert_eq!(get_balance(token_id, account.clone(), None).unwrap(), 0);
     }
 
     #[test]
@@ -327,6 +1594,169 @@ mod tests {
         assert_eq!(get_allowance(token_id, owner, spender).unwrap(), 0);
     }
 
+    #[test]
+    fn test_list_allowances_of_skips_dead_allowances() {
+        use crate::allowances::{approve, ApproveResult, Icrc151ApproveArgs};
+        use crate::test_utils::{register_test_token, test_account, test_subaccount};
+
+        state::init_state(test_account(0xFE).owner);
+        let (token, other_token) = (register_test_token(1, 0), register_test_token(2, 0));
+        let owner = test_account(1);
+        let approve_for = |token_id, spender: &Account, amount: u64, expires_at| {
+            let args = Icrc151ApproveArgs {
+                token_id,
+                spender: spender.clone(),
+                amount: candid::Nat::from(amount),
+                expires_at,
+                expected_allowance: None,
+                memo: None,
+                fee: None,
+                from_subaccount: None,
+                created_at_time: None,
+                private_memo: None,
+                args_version: None,
+            };
+            assert!(matches!(approve(args), ApproveResult::Ok(_)));
+        };
+
+        crate::runtime::set_caller(owner.owner);
+        let now = crate::runtime::time();
+        approve_for(token, &test_account(2), 100, None);
+        approve_for(token, &test_subaccount(3, 7), 200, Some(now + 10));
+        approve_for(token, &test_account(4), 300, Some(now + 20));
+        approve_for(token, &test_account(4), 0, None);
+        approve_for(other_token, &test_account(2), 400, None);
+        crate::runtime::advance_time(15);
+
+        let listed = list_allowances_of(owner.clone(), None, None).unwrap();
+        let found: Vec<_> = listed.iter().map(|a| (a.token_id, a.spender.clone(), a.allowance)).collect();
+        assert_eq!(found, vec![(token, test_account(2), 100), (other_token, test_account(2), 400)]);
+        assert!(listed.iter().all(|a| a.owner == owner && a.expires_at.is_none()));
+
+        let second_page = list_allowances_of(owner.clone(), Some(1), Some(1)).unwrap();
+        assert_eq!(second_page.iter().map(|a| a.token_id).collect::<Vec<_>>(), vec![other_token]);
+        assert!(list_allowances_of(owner.clone(), Some(2), Some(1)).unwrap().is_empty());
+        assert!(list_allowances_of(test_account(2), None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_allowances_for_spender_skips_expired_allowances() {
+        use crate::test_utils::{register_test_token, test_account, test_subaccount};
+
+        state::init_state(test_account(0xFE).owner);
+        let (token, other_token) = (register_test_token(1, 0), register_test_token(2, 0));
+        let dex = test_account(9);
+        let now = crate::runtime::time();
+        let grant = |token_id, owner: &Account, amount, expires_at: Option<u64>| {
+            state::register_account(owner).unwrap();
+            state::set_allowance(token_id, owner.to_key(), dex.to_key(), amount);
+            if let Some(expires_at) = expires_at {
+                state::set_allowance_expiry(token_id, owner.to_key(), dex.to_key(), expires_at);
+            }
+        };
+        grant(token, &test_account(1), 100, None);
+        grant(token, &test_subaccount(2, 5), 200, Some(now + 10));
+        grant(token, &test_account(3), 300, Some(now - 1));
+        grant(other_token, &test_account(1), 400, None);
+
+        let listed = list_allowances_for_spender(dex.clone(), None, None, None).unwrap();
+        let found: Vec<_> = listed.iter().map(|a| (a.token_id, a.allowance)).collect();
+        assert_eq!(found, vec![(token, 100), (token, 200), (other_token, 400)]);
+        assert!(listed.iter().all(|a| a.spender == dex));
+
+        let only_token = list_allowances_for_spender(dex.clone(), Some(token), Some(1), Some(5)).unwrap();
+        assert_eq!(only_token.len(), 1);
+        assert_eq!((only_token[0].owner.clone(), only_token[0].expires_at), (test_subaccount(2, 5), Some(now + 10)));
+        assert!(list_allowances_for_spender(test_account(1), None, None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_allowance_at_replays_approvals_and_pulls() {
+        use crate::allowances::{approve, increase_allowance, transfer_from, Icrc151AllowanceChangeArgs, Icrc151ApproveArgs, Icrc151TransferFromArgs};
+        use crate::operations::{mint_tokens, transfer_internal, TransferResult};
+        use crate::runtime;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let (token, other_token) = (register_test_token(1, 10), register_test_token(2, 0));
+        let (owner, spender, shop) = (test_account(1), test_account(2), test_account(3));
+        runtime::set_caller(controller);
+        mint_tokens(token, owner.clone(), candid::Nat::from(10_000u64), None).unwrap();
+        mint_tokens(other_token, owner.clone(), candid::Nat::from(10_000u64), None).unwrap();
+
+        let set = |amount: u64| {
+            runtime::set_caller(owner.owner);
+            approve(Icrc151ApproveArgs {
+                token_id: token, spender: spender.clone(), amount: candid::Nat::from(amount), expires_at: None,
+                expected_allowance: None, memo: None, fee: None, from_subaccount: None, created_at_time: None,
+                private_memo: None, args_version: None,
+            });
+        };
+        let pull = |amount: u64| {
+            runtime::set_caller(spender.owner);
+            let result = transfer_from(Icrc151TransferFromArgs {
+                token_id: token, spender_subaccount: None, from: owner.clone(), to: shop.clone(), amount: candid::Nat::from(amount),
+                fee: None, memo: None, created_at_time: None, private_memo: None, args_version: None,
+            });
+            assert!(matches!(result, TransferResult::Ok(_)));
+        };
+
+        // After each step: the index of its block and the live allowance.
+        let mut checkpoints = Vec::new();
+        let mut step = |action: &dyn Fn()| {
+            runtime::advance_time(1);
+            action();
+            checkpoints.push((state::get_transaction_count() - 1, state::get_allowance(token, owner.to_key(), spender.to_key())));
+        };
+        step(&|| set(500));
+        step(&|| pull(100));
+        step(&|| { transfer_internal(other_token, owner.clone(), shop.clone(), 5, None, None, None).unwrap(); });
+        step(&|| {
+            runtime::set_caller(owner.owner);
+            increase_allowance(Icrc151AllowanceChangeArgs {
+                token_id: token, spender: spender.clone(), delta: candid::Nat::from(50u64), expires_at: None, memo: None,
+                fee: None, from_subaccount: None, created_at_time: None, private_memo: None, args_version: None,
+            });
+        });
+        step(&|| pull(40));
+        step(&|| set(0));
+
+        let first_approval = checkpoints[0].0;
+        runtime::set_caller(owner.owner);
+        assert_eq!(
+            get_allowance_at(token, owner.clone(), spender.clone(), first_approval - 1, None).unwrap(),
+            AllowanceAt::Found { allowance: 0, approved_at: None }
+        );
+        assert_eq!(checkpoints.iter().map(|(_, live)| *live).collect::<Vec<_>>(), vec![500, 390, 390, 440, 390, 0]);
+        for (tx_index, live) in &checkpoints {
+            let AllowanceAt::Found { allowance, approved_at } = get_allowance_at(token, owner.clone(), spender.clone(), *tx_index, None).unwrap() else {
+                panic!("replay did not finish");
+            };
+            assert_eq!(allowance, *live);
+            assert!(approved_at.is_some_and(|approved_at| approved_at <= *tx_index));
+        }
+
+        // A short scan hands back a cursor and picks up where it stopped.
+        let (after_second_pull, _) = checkpoints[4];
+        let mut resume = None;
+        let mut calls = 0;
+        let found = loop {
+            calls += 1;
+            match replay_allowance(token, owner.to_key(), spender.to_key(), after_second_pull, resume.take(), 1).unwrap() {
+                AllowanceAt::Incomplete { resume: cursor } => resume = Some(cursor),
+                found => break found,
+            }
+        };
+        assert_eq!(found, AllowanceAt::Found { allowance: 390, approved_at: Some(checkpoints[3].0) });
+        assert_eq!(calls, 2);
+
+        assert!(get_allowance_at(token, owner.clone(), spender.clone(), state::get_transaction_count(), None).is_err());
+        runtime::set_caller(shop.owner);
+        state::set_privacy_mode(true);
+        assert!(matches!(get_allowance_at(token, owner, spender, first_approval, None), Err(QueryError::Unauthorized)));
+    }
+
     #[test]
     fn test_validation_errors() {
         let zero_token = [0u8; 32];
@@ -335,6 +1765,533 @@ mod tests {
             subaccount: None,
         };
         
-        assert!(get_balance(zero_token, valid_account).is_err());
+        assert!(get_balance(zero_token, valid_account, None).is_err());
+    }
+
+    #[test]
+    fn test_token_seq_contiguous_across_interleaved_tokens() {
+        let tokens = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        let mut expected: Vec<Vec<u64>> = vec![Vec::new(); tokens.len()];
+
+        // Deterministic pseudo-random interleaving.
+        let mut rng = 0x2545_F491_4F6C_DD1Du64;
+        for _ in 0..500 {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let which = (rng % tokens.len() as u64) as usize;
+            let tx_index = state::add_transaction(crate::transaction::StoredTxV1::new_mint(tokens[which], [7u8; 32], 1, 0, None));
+            expected[which].push(tx_index);
+        }
+
+        for (token, indices) in tokens.iter().zip(&expected) {
+            for (seq, tx_index) in indices.iter().enumerate() {
+                let found = get_token_tx_by_seq(*token, seq as u64, None).unwrap().unwrap();
+                assert_eq!(found.tx_index, *tx_index);
+                assert_eq!(found.transaction.get_token_seq(), Some(seq as u64));
+                assert_eq!(found.transaction.token_id, *token);
+            }
+            assert!(get_token_tx_by_seq(*token, indices.len() as u64, None).unwrap().is_none());
+        }
+
+        // Mutation endpoints report the same sequence.
+        let token = crate::test_utils::register_test_token(4, 0);
+        let sender = crate::test_utils::test_account(2);
+        state::seed_balance(token, sender.to_key(), 100);
+        crate::runtime::set_caller(sender.owner);
+        for seq in 0..3u64 {
+            crate::runtime::advance_time(1);
+            let args = crate::operations::Icrc151TransferArgs {
+                token_id: token,
+                from_subaccount: None,
+                to: crate::test_utils::test_account(3),
+                amount: candid::Nat::from(1u64),
+                fee: None,
+                memo: None,
+                created_at_time: None,
+                private_memo: None,
+                amount_mode: None, use_sponsorship: None, args_version: None,
+            };
+            match crate::operations::transfer_v2(args) {
+                crate::operations::TransferResultV2::Ok(receipt) => {
+                    assert_eq!(receipt.token_seq, seq);
+                    assert_eq!(get_token_tx_by_seq(token, seq, None).unwrap().unwrap().tx_index, receipt.tx_index);
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_legacy_call_counters() {
+        use crate::operations::{transfer, transfer_v2, Icrc151TransferArgs};
+        use crate::test_utils::{register_test_token, test_account};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let sender = test_account(2);
+        state::seed_balance(token, sender.to_key(), 100);
+        crate::runtime::set_caller(sender.owner);
+
+        let args = |t: u64| Icrc151TransferArgs {
+            token_id: token,
+            from_subaccount: None,
+            to: test_account(3),
+            amount: candid::Nat::from(1u64),
+            fee: None,
+            memo: None,
+            created_at_time: Some(crate::runtime::time() - t),
+            private_memo: None,
+            amount_mode: None, use_sponsorship: None, args_version: None,
+        };
+        transfer(args(1));
+        transfer(args(2));
+        transfer_v2(args(3));
+
+        assert!(get_legacy_call_counts().is_err());
+        crate::runtime::set_caller(controller);
+        let counts = get_legacy_call_counts().unwrap();
+        assert_eq!(counts, vec![LegacyCallCount { method: "transfer".to_string(), count: 2, warned: 0, blocked: 0 }]);
+
+        let deprecations = get_deprecations();
+        assert_eq!(deprecations.len(), DEPRECATED_METHODS.len());
+        assert!(deprecations.iter().any(|d| d.method == "transfer" && d.replacement == "transfer_v2"));
+    }
+
+    #[test]
+    fn test_create_token_deprecation_modes() {
+        use crate::operations::{create_token, create_token_with_args, CreateTokenArgs, CreateTokenError};
+        use crate::test_utils::test_account;
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        crate::runtime::set_caller(controller);
+        let legacy = |symbol: &str| create_token(symbol.to_string(), symbol.to_string(), 8, None, None, None, None);
+        let last_audit_entry = || state::list_admin_actions(0, usize::MAX).pop().unwrap();
+        let counts = || get_legacy_call_counts().unwrap().into_iter().find(|c| c.method == "create_token").unwrap();
+        let mode = || get_deprecations().into_iter().find(|d| d.method == "create_token").unwrap().mode;
+
+        assert_eq!(mode(), DeprecationMode::Allowed);
+        legacy("AAA").unwrap();
+        assert_eq!((counts().count, counts().warned, counts().blocked), (1, 0, 0));
+
+        set_deprecation_mode("create_token".to_string(), DeprecationMode::Warn).unwrap();
+        assert_eq!(mode(), DeprecationMode::Warn);
+        legacy("BBB").unwrap();
+        assert_eq!((counts().count, counts().warned, counts().blocked), (2, 1, 0));
+        let note = last_audit_entry();
+        assert_eq!(note.action, "create_token");
+        assert!(note.detail.contains("create_token_with_args"));
+
+        set_deprecation_mode("create_token".to_string(), DeprecationMode::Blocked).unwrap();
+        assert_eq!(
+            legacy("CCC"),
+            Err(CreateTokenError::DeprecatedEndpoint { use_instead: "create_token_with_args".to_string() })
+        );
+        assert_eq!((counts().count, counts().warned, counts().blocked), (3, 1, 1));
+        assert_eq!(state::get_token_count(), 2);
+        create_token_with_args(CreateTokenArgs {
+            name: "CCC".to_string(), symbol: "CCC".to_string(), decimals: 8, initial_supply: None, fee: None,
+            logo: None, description: None, namespace: None, args_version: None,
+        }).unwrap();
+
+        // Non-controllers are refused before the mode is looked at, and cannot change it.
+        crate::runtime::set_caller(test_account(1).owner);
+        assert!(matches!(legacy("DDD"), Err(CreateTokenError::GenericError { .. })));
+        assert!(set_deprecation_mode("create_token".to_string(), DeprecationMode::Allowed).is_err());
+        crate::runtime::set_caller(controller);
+        assert_eq!(counts().blocked, 1);
+
+        assert!(set_deprecation_mode("transfer".to_string(), DeprecationMode::Blocked).is_err());
+        set_deprecation_mode("create_token".to_string(), DeprecationMode::Allowed).unwrap();
+        assert_eq!(mode(), DeprecationMode::Allowed);
+        legacy("EEE").unwrap();
+    }
+
+    #[test]
+    fn test_balance_scan_consistency() {
+        use crate::test_utils::{register_test_token, test_account};
+
+        let token = register_test_token(1, 0);
+        let other = register_test_token(2, 0);
+        for id in 2..7u8 {
+            state::seed_balance(token, test_account(id).to_key(), id as u128);
+        }
+
+        let scan = begin_balance_scan(token).unwrap();
+        let first = list_holders(token, None, Some(3), Some(scan.clone())).unwrap();
+        assert_eq!(first.holders.len(), 3);
+        assert!(first.consistent);
+
+        // Other tokens do not disturb the scan; a balance change of this one does.
+        state::seed_balance(other, test_account(2).to_key(), 50);
+        assert!(list_holders(token, first.next_cursor.clone(), Some(3), Some(scan.clone())).unwrap().consistent);
+        state::seed_balance(token, test_account(2).to_key(), 0);
+        let second = list_holders(token, first.next_cursor, Some(3), Some(scan.clone())).unwrap();
+        assert!(!second.consistent);
+        assert_eq!(second.next_cursor, None);
+
+        // A fresh scan sees the drained holder gone.
+        let rescan = begin_balance_scan(token).unwrap();
+        assert!(rescan.balance_seq > scan.balance_seq);
+        let all = list_holders(token, None, None, Some(rescan)).unwrap();
+        assert_eq!(all.holders.len(), 4);
+        assert!(all.consistent);
+        assert!(list_holders(token, None, None, Some(begin_balance_scan(other).unwrap())).is_err());
+    }
+
+    #[test]
+    fn test_get_transactions_min_log_length() {
+        for i in 0..3u8 {
+            state::add_transaction(crate::transaction::StoredTxV1::new_mint([1u8; 32], [i; 32], 100, 0, None));
+        }
+
+        assert_eq!(get_transactions(None, None, None, Some(3), None).unwrap().len(), 3);
+        assert_eq!(get_transactions(None, None, None, None, None).unwrap().len(), 3);
+
+        match get_transactions(None, None, None, Some(4), None) {
+            Err(QueryError::StaleReplica { log_length }) => assert_eq!(log_length, 3),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_certified_reads_match_queries() {
+        use crate::runtime;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let (owner, spender) = (test_account(1), test_account(2));
+        state::seed_balance(token, owner.to_key(), 100);
+        state::set_allowance(token, owner.to_key(), spender.to_key(), 10);
+        state::add_transaction(crate::transaction::StoredTxV1::new_mint(token, owner.to_key(), 100, 0, None));
+
+        let check = || {
+            for account in [owner.clone(), spender.clone()] {
+                assert_eq!(
+                    format!("{:?}", get_balance(token, account.clone(), None)),
+                    format!("{:?}", get_balance_certified(token, account)),
+                );
+            }
+            assert_eq!(
+                format!("{:?}", get_balance([0u8; 32], owner.clone(), None)),
+                format!("{:?}", get_balance_certified([0u8; 32], owner.clone())),
+            );
+            assert_eq!(
+                format!("{:?}", get_allowance(token, owner.clone(), spender.clone())),
+                format!("{:?}", get_allowance_certified(token, owner.clone(), spender.clone())),
+            );
+            for tx_index in 0..2 {
+                assert_eq!(
+                    format!("{:?}", get_transactions(None, Some(tx_index), Some(1), None, None).map(|txs| txs.first().copied())),
+                    format!("{:?}", get_transaction_certified(tx_index)),
+                );
+            }
+        };
+        runtime::set_caller(owner.owner);
+        check();
+        assert_eq!(get_balance_certified(token, owner.clone()).unwrap(), 100);
+        assert!(get_transaction_certified(0).unwrap().is_some());
+        assert!(get_transaction_certified(1).unwrap().is_none());
+
+        // The same access rules apply.
+        runtime::set_caller(controller);
+        crate::privacy::set_privacy_mode(true).unwrap();
+        runtime::set_caller(spender.owner);
+        check();
+        assert!(matches!(get_balance_certified(token, owner.clone()), Err(QueryError::Unauthorized)));
+        assert!(matches!(get_transaction_certified(0), Err(QueryError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_balance_with_certificate_verifies_offline() {
+        use crate::runtime;
+        use crate::test_utils::{register_test_token, test_account};
+        use crate::verification::tests::{der_key, sign_certificate, test_key};
+        use crate::verification::{verify_balance_certificate, VerifyError};
+
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        state::start_certified_balances();
+        runtime::set_caller(controller);
+        let (token, other) = (register_test_token(1, 0), register_test_token(2, 0));
+        let (owner, empty) = (test_account(1), test_account(9));
+        let credits: Vec<_> = (1..6).map(|i| (test_account(i).to_key(), 100 * i as u128)).collect();
+        state::apply_movements(token, &[], &credits, 1_500).unwrap();
+        state::apply_movements(other, &[], &credits, 1_500).unwrap();
+        state::apply_movements(token, &[(test_account(2).to_key(), 200)], &[(owner.to_key(), 200)], 0).unwrap();
+
+        let root_key = test_key(1);
+        let sign = || runtime::set_data_certificate(Some(sign_certificate(&root_key, runtime::canister_id(), &runtime::certified_data(), None)));
+        let verify = |proof: &CertifiedBalance, account: &Account, token_id: TokenId| {
+            let balance = num_traits::ToPrimitive::to_u128(&proof.balance.0).unwrap();
+            verify_balance_certificate(&proof.certificate, &proof.witness, &der_key(&root_key), runtime::canister_id(), account, &token_id, balance)
+        };
+        assert!(get_balance_with_certificate(token, owner.clone()).is_err());
+        sign();
+
+        runtime::set_caller(owner.owner);
+        let proof = get_balance_with_certificate(token, owner.clone()).unwrap();
+        assert_eq!(proof.balance, candid::Nat::from(300u32));
+        assert_eq!(verify(&proof, &owner, token), Ok(()));
+        assert_eq!(verify(&proof, &owner, other), Err(VerifyError::AccountNotCovered));
+        let nothing = get_balance_with_certificate(token, empty.clone()).unwrap();
+        assert_eq!(verify(&nothing, &empty, token), Ok(()));
+        runtime::set_caller(test_account(2).owner);
+        assert_eq!(verify(&get_balance_with_certificate(token, test_account(2)).unwrap(), &test_account(2), token), Ok(()));
+
+        // A later balance change needs a later certificate.
+        state::apply_movements(token, &[(owner.to_key(), 1)], &[(empty.to_key(), 1)], 0).unwrap();
+        runtime::set_caller(owner.owner);
+        let stale = get_balance_with_certificate(token, owner.clone()).unwrap();
+        assert_eq!(verify(&stale, &owner, token), Err(VerifyError::CertifiedDataMismatch));
+        sign();
+        assert_eq!(verify(&get_balance_with_certificate(token, owner.clone()).unwrap(), &owner, token), Ok(()));
+
+        // After an upgrade only the tip is certified, and balance proofs wait
+        // for the maintenance task to refill the tree.
+        let certified = runtime::certified_data();
+        state::simulate_upgrade();
+        state::certify();
+        assert_ne!(runtime::certified_data(), certified);
+        sign();
+        assert!(matches!(
+            get_balance_with_certificate(token, owner.clone()),
+            Err(QueryError::MigrationPending { migration }) if migration == crate::maintenance::CERTIFIED_BALANCES
+        ));
+        // The movements above log no blocks, so the tip is still genesis.
+        let certificate = runtime::data_certificate().unwrap();
+        let tip = crate::verification::certified_tip(&certificate, &state::tip_witness(), &der_key(&root_key), runtime::canister_id());
+        assert_eq!(tip, Ok(crate::proof::GENESIS_HASH));
+
+        // Balances the refill has passed are kept current; the others are read
+        // when it gets to them.
+        for _ in 0..4 {
+            assert!(state::refill_certified_balances());
+        }
+        state::apply_movements(token, &[(owner.to_key(), 1), (test_account(4).to_key(), 1)], &[(empty.to_key(), 2)], 0).unwrap();
+        let pending = state::count_certified_balances_pending(100);
+        runtime::set_caller(controller);
+        let status = crate::maintenance::run_maintenance_task(crate::maintenance::CERTIFIED_BALANCES.to_string()).unwrap();
+        assert_eq!((status.last_run_items, status.backlog), (pending, 0));
+        let refilled = runtime::certified_data();
+        state::simulate_upgrade();
+        while state::refill_certified_balances() {}
+        assert_eq!(runtime::certified_data(), refilled);
+        sign();
+        runtime::set_caller(owner.owner);
+        assert_eq!(verify(&get_balance_with_certificate(token, owner.clone()).unwrap(), &owner, token), Ok(()));
+
+        runtime::set_caller(controller);
+        crate::privacy::set_privacy_mode(true).unwrap();
+        runtime::set_caller(empty.owner);
+        assert!(matches!(get_balance_with_certificate(token, owner.clone()), Err(QueryError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_holder_count_history() {
+        use crate::runtime;
+        use crate::test_utils::{register_test_token, test_account};
+        use crate::types::constants::NANOS_PER_DAY;
+
+        let token = register_test_token(1, 0);
+        let day0 = runtime::time() / NANOS_PER_DAY;
+
+        state::seed_balance(token, test_account(1).to_key(), 10);
+        state::seed_balance(token, test_account(2).to_key(), 10);
+        runtime::advance_time(2 * NANOS_PER_DAY);
+        state::seed_balance(token, test_account(2).to_key(), 0);
+        runtime::advance_time(NANOS_PER_DAY);
+
+        let points: Vec<(u64, u64)> = get_holder_count_history(token, day0 - 1, day0 + 3)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.day, p.holder_count))
+            .collect();
+        assert_eq!(points, vec![(day0, 2), (day0 + 1, 2), (day0 + 2, 1), (day0 + 3, 1)]);
+
+        assert!(get_holder_count_history(token, day0 + 1, day0).is_err());
+        assert!(get_holder_count_history(token, 0, 366).is_err());
+
+        // Shrinking retention drops old days but keeps the carried-forward value.
+        state::set_holder_history_retention_days(2);
+        state::seed_balance(token, test_account(3).to_key(), 10);
+        let points: Vec<(u64, u64)> = get_holder_count_history(token, day0, day0 + 3)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.day, p.holder_count))
+            .collect();
+        assert_eq!(points, vec![(day0 + 2, 1), (day0 + 3, 2)]);
+    }
+
+    #[test]
+    fn test_paging_same_timestamp_transactions() {
+        use crate::operations::{mint_internal, transfer_internal};
+        use crate::runtime;
+        use crate::test_utils::{register_test_token, test_account};
+
+        let (token_a, token_b) = (register_test_token(1, 0), register_test_token(2, 0));
+        let (owner, other) = (test_account(1), test_account(2));
+        mint_internal(token_a, owner.clone(), 1_000, None, None).unwrap();
+        mint_internal(token_b, owner.clone(), 1_000, None, None).unwrap();
+
+        // One round: every block shares the timestamp, except those back-dated
+        // with created_at_time, which land later in the log but earlier in time.
+        let now = runtime::time();
+        for i in 0..40u8 {
+            let token = if i % 3 == 0 { token_b } else { token_a };
+            let created_at_time = (i % 5 == 0).then(|| now - 1_000);
+            let (from, to) = if i % 4 == 0 { (other.clone(), owner.clone()) } else { (owner.clone(), other.clone()) };
+            let amount = if i % 4 == 0 { 1 } else { 2 };
+            if i % 4 == 0 {
+                transfer_internal(token, owner.clone(), other.clone(), 1, None, Some(&[i + 1, 0xAA]), None).unwrap();
+            }
+            transfer_internal(token, from, to, amount, None, Some(&[i + 1]), created_at_time).unwrap();
+        }
+
+        let all = get_balance_changes(owner.clone(), 0, Some(1000)).unwrap();
+        assert!(all.complete);
+        let expected: Vec<u64> = all.changes.iter().map(|c| c.tx_index).collect();
+        assert_eq!(expected.len(), 2 + 40 + 10);
+        assert!(expected.windows(2).all(|w| w[0] < w[1]));
+        assert!(all.changes.iter().filter(|c| c.timestamp == now).count() > 40);
+        assert!(all.changes.windows(2).any(|w| w[0].timestamp > w[1].timestamp));
+
+        for limit in 1..=7u64 {
+            let (mut seen, mut since) = (Vec::new(), 0);
+            loop {
+                let page = get_balance_changes(owner.clone(), since, Some(limit)).unwrap();
+                seen.extend(page.changes.iter().map(|c| c.tx_index));
+                since = page.next_since_tx_index;
+                if page.complete {
+                    break;
+                }
+            }
+            assert_eq!(seen, expected, "limit {}", limit);
+            assert_eq!(since, state::get_transaction_count());
+        }
+
+        // A token filter thins pages out but never moves their boundaries.
+        let count = state::get_transaction_count();
+        let full = get_transactions(Some(token_b), None, Some(count), None, None).unwrap();
+        for length in [1, 3, 7] {
+            let mut paged = Vec::new();
+            for start in (0..count).step_by(length as usize) {
+                paged.extend(get_transactions(Some(token_b), Some(start), Some(length), None, None).unwrap());
+            }
+            assert_eq!(paged, full, "length {}", length);
+        }
+    }
+
+    #[test]
+    fn test_holder_count_history_windows_do_not_overlap() {
+        use crate::runtime;
+        use crate::test_utils::{register_test_token, test_account};
+        use crate::types::constants::NANOS_PER_DAY;
+
+        let token = register_test_token(1, 0);
+        let day0 = runtime::time() / NANOS_PER_DAY;
+        for day in 0..6u8 {
+            // Several changes within each day collapse into one point.
+            for n in 0..3u8 {
+                state::seed_balance(token, test_account(10 * day + n + 1).to_key(), 10);
+            }
+            state::seed_balance(token, test_account(10 * day + 1).to_key(), 0);
+            runtime::advance_time(NANOS_PER_DAY);
+        }
+
+        let whole = get_holder_count_history(token, day0, day0 + 5).unwrap();
+        assert_eq!(whole.iter().map(|p| p.day).collect::<Vec<_>>(), (day0..=day0 + 5).collect::<Vec<_>>());
+        for width in 1..=4u64 {
+            let mut paged = Vec::new();
+            let mut from = day0;
+            while from <= day0 + 5 {
+                paged.extend(get_holder_count_history(token, from, (from + width - 1).min(day0 + 5)).unwrap());
+                from += width;
+            }
+            assert_eq!(paged.iter().map(|p| (p.day, p.holder_count)).collect::<Vec<_>>(),
+                whole.iter().map(|p| (p.day, p.holder_count)).collect::<Vec<_>>());
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_get_balances_for_validation() {
+        use crate::test_utils::{register_test_token, test_account, test_subaccount};
+
+        let token = register_test_token(1, 0);
+        let holder = test_subaccount(1, 7);
+        state::seed_balance(token, holder.to_key(), 50);
+
+        assert!(matches!(
+            get_balances_for(Principal::anonymous(), None, None),
+            Err(QueryError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            get_balances_for(holder.owner, Some(vec![7u8; 31]), None),
+            Err(QueryError::InvalidInput(_))
+        ));
+
+        let balances = get_balances_for(holder.owner, Some(vec![7u8; 32]), None).unwrap();
+        assert_eq!(balances.len(), 1);
+        assert_eq!(balances[0].balance, 50);
+
+        let results = get_balances_for_accounts(vec![holder.clone(), test_account(2)]).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].account, holder);
+        assert_eq!(results[0].balances.len(), 1);
+        assert!(results[1].balances.is_empty());
+
+        let mut bad = test_account(2);
+        bad.subaccount = Some(vec![0u8; 31]);
+        assert!(get_balances_for_accounts(vec![holder.clone(), bad]).is_err());
+        assert!(get_balances_for_accounts(vec![holder; MAX_BALANCE_QUERY_ACCOUNTS + 1]).is_err());
+    }
+
+    // The embedded hash only describes this build if the checked-in interface is
+    // current. Run with UPDATE_CANDID=1 to rewrite it after an intended change.
+    #[test]
+    #[cfg(all(feature = "icrc3", feature = "notifications", feature = "relay", not(feature = "dev")))]
+    fn test_build_info_candid_hash() {
+        use sha2::{Digest, Sha256};
+
+        let path = format!("{}/candid/icrc151.did", env!("CARGO_MANIFEST_DIR"));
+        let exported = format!("{}\n", crate::__export_service());
+        if std::env::var("UPDATE_CANDID").is_ok() {
+            std::fs::write(&path, &exported).unwrap();
+        }
+        let checked_in = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(exported, checked_in, "candid/icrc151.did does not match the exported interface");
+
+        let info = get_build_info();
+        let hash: [u8; 32] = Sha256::digest(checked_in.as_bytes()).into();
+        assert_eq!(info.candid_hash, hash);
+        assert_eq!(info.wasm_features, enabled_features());
+
+        let changed = exported.replace("get_build_info : ()", "get_build_info_v2 : ()");
+        assert_ne!(changed, exported);
+        assert_ne!(<[u8; 32]>::from(Sha256::digest(changed.as_bytes())), info.candid_hash);
+    }
+
+    // The faucet must not exist in release builds at all, not just be switched off.
+    #[test]
+    #[cfg(not(feature = "dev"))]
+    fn test_default_interface_has_no_dev_methods() {
+        let exported = crate::__export_service();
+        assert!(!exported.contains("dev_faucet"));
+        assert!(!exported.contains("DevInitArgs"));
+        let checked_in = std::fs::read_to_string(format!("{}/candid/icrc151.did", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        assert!(!checked_in.contains("dev_faucet"));
+    }
+
+    #[test]
+    fn test_interface_version_features() {
+        let info = get_interface_version();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.features.contains(&"icrc3".to_string()), cfg!(feature = "icrc3"));
+        assert_eq!(info.features.contains(&"notifications".to_string()), cfg!(feature = "notifications"));
+        assert_eq!(info.features.contains(&"dev".to_string()), cfg!(feature = "dev"));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::types::{Account, AccountKey, TokenId};
+use crate::state;
+use crate::transaction::{StoredTxV1, OP_BURN, OP_MINT, OP_TRANSFER, OP_TRANSFER_FROM};
+use crate::privacy::require_account_reader;
+use crate::queries::{transaction_memo, QueryError, TransactionMemo};
+use crate::validation::{validate_account, validate_token_id};
+use candid::{CandidType, Nat};
+use serde::{Deserialize, Serialize};
+use num_traits::cast::ToPrimitive;
+
+
+// Matches an external statement of expected movements against the account's
+// transaction index. Read-only; nothing about a reconciliation is stored.
+
+
+pub const MAX_RECONCILE_ENTRIES: usize = 500;
+pub const MAX_RECONCILE_SCAN: usize = 5_000;
+pub const MAX_AMBIGUOUS_CANDIDATES: usize = 10;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum StatementDirection {
+    Incoming,
+    Outgoing,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StatementEntry {
+    // Empty means the entry has no reference; otherwise it must equal the memo.
+    #[serde(rename = "ref")]
+    pub reference: Vec<u8>,
+    pub direction: StatementDirection,
+    pub amount: Nat,
+    // Inclusive, in nanoseconds, compared with the transaction's timestamp.
+    pub time_window: (u64, u64),
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ReconcileResult {
+    Matched { tx_index: u64 },
+    Ambiguous { candidates: Vec<u64> },
+    Missing,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReconcileReport {
+    // One per statement entry, in order.
+    pub results: Vec<ReconcileResult>,
+    // Movements of the account in the token within the statement's overall
+    // window that no entry claimed, ascending.
+    pub unmatched: Vec<u64>,
+}
+
+
+struct Movement {
+    tx_index: u64,
+    direction: StatementDirection,
+    amount: u128,
+    timestamp: u64,
+    memo: Option<Vec<u8>>,
+}
+
+
+// Ledger time only moves forward, and a stored timestamp lies within the
+// created_at_time window around the ledger time of its block. So once a
+// block's timestamp is the widest window ever configured below `start`, nothing
+// before it can reach `start`, and once one is that far above `end`, nothing
+// after it can be at or before `end`.
+fn drift_margin() -> u64 {
+    state::widest_tx_window()
+}
+
+
+// First block that can have a timestamp at or after `start`.
+pub(crate) fn first_block_from(start: u64) -> u64 {
+    let threshold = start.saturating_sub(drift_margin());
+    let (mut lo, mut hi) = (0u64, state::get_transaction_count());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match state::get_transaction(mid) {
+            Some(tx) if tx.get_timestamp() < threshold => lo = mid + 1,
+            _ => hi = mid,
+        }
+    }
+    // Every block before a block below the threshold is also out of range,
+    // even if a later block dips below it again.
+    lo
+}
+
+
+fn movement(tx_index: u64, tx: &StoredTxV1, token_id: TokenId, account_key: AccountKey) -> Option<Movement> {
+    if tx.token_id != token_id {
+        return None;
+    }
+    let direction = match tx.op {
+        OP_TRANSFER | OP_BURN | OP_TRANSFER_FROM if tx.from_key == account_key => StatementDirection::Outgoing,
+        OP_TRANSFER | OP_MINT | OP_TRANSFER_FROM if tx.to_key == account_key => StatementDirection::Incoming,
+        _ => return None,
+    };
+    let memo = match transaction_memo(tx_index, tx) {
+        TransactionMemo::Memo(memo) | TransactionMemo::Structured { raw: memo, .. } => Some(memo),
+        TransactionMemo::NoMemo | TransactionMemo::MemoPrivate { .. } => None,
+    };
+    Some(Movement { tx_index, direction, amount: tx.get_amount(), timestamp: tx.get_timestamp(), memo })
+}
+
+
+fn is_candidate(entry: &StatementEntry, amount: u128, movement: &Movement) -> bool {
+    let (start, end) = entry.time_window;
+    movement.direction == entry.direction
+        && movement.amount == amount
+        && (start..=end).contains(&movement.timestamp)
+        && (entry.reference.is_empty() || movement.memo.as_deref() == Some(entry.reference.as_slice()))
+}
+
+
+// Each transaction is claimed by at most one entry. Entries with a reference
+// go first, then the rest, each group in statement order; an entry with exactly
+// one unclaimed candidate claims it. Rounds repeat while anything is claimed,
+// since a claim can leave another entry with a single candidate. Whatever is
+// left with several candidates is Ambiguous and lists the lowest ones.
+#[ic_cdk::query]
+pub fn reconcile(account: Account, token_id: TokenId, entries: Vec<StatementEntry>) -> Result<ReconcileReport, QueryError> {
+    validate_account(&account)?;
+    validate_token_id(&token_id)?;
+    require_account_reader(&account)?;
+    state::get_token_metadata(token_id)?;
+    if entries.is_empty() || entries.len() > MAX_RECONCILE_ENTRIES {
+        return Err(QueryError::InvalidInput(format!("Between 1 and {} entries are required", MAX_RECONCILE_ENTRIES)));
+    }
+    let mut amounts = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        if entry.time_window.0 > entry.time_window.1 {
+            return Err(QueryError::InvalidInput("A time window must not end before it starts".to_string()));
+        }
+        amounts.push(entry.amount.0.to_u128()
+            .ok_or_else(|| QueryError::InvalidInput("Amount exceeds maximum value (u128::MAX)".to_string()))?);
+    }
+    let window_start = entries.iter().map(|e| e.time_window.0).min().unwrap_or(0);
+    let window_end = entries.iter().map(|e| e.time_window.1).max().unwrap_or(0);
+
+    let account_key = account.to_key();
+    let past_window = window_end.saturating_add(drift_margin());
+    let tx_indices = state::list_account_transactions(account_key, first_block_from(window_start), MAX_RECONCILE_SCAN + 1);
+    let mut movements = Vec::new();
+    let mut reached_end = tx_indices.len() <= MAX_RECONCILE_SCAN;
+    for tx_index in tx_indices.into_iter().take(MAX_RECONCILE_SCAN) {
+        let tx = state::get_transaction(tx_index)
+            .ok_or_else(|| QueryError::InternalError(format!("Transaction {} is missing", tx_index)))?;
+        if tx.get_timestamp() > past_window {
+            reached_end = true;
+            break;
+        }
+        if let Some(movement) = movement(tx_index, &tx, token_id, account_key) {
+            if (window_start..=window_end).contains(&movement.timestamp) {
+                movements.push(movement);
+            }
+        }
+    }
+    if !reached_end {
+        return Err(QueryError::InvalidInput(format!(
+            "The statement window spans more than {} of the account's transactions; split the statement",
+            MAX_RECONCILE_SCAN
+        )));
+    }
+
+    let mut order: Vec<usize> = (0..entries.len()).filter(|i| !entries[*i].reference.is_empty()).collect();
+    order.extend((0..entries.len()).filter(|i| entries[*i].reference.is_empty()));
+    let mut claimed = vec![false; movements.len()];
+    let mut results: Vec<Option<ReconcileResult>> = vec![None; entries.len()];
+    loop {
+        let mut progress = false;
+        for &i in &order {
+            if matches!(results[i], Some(ReconcileResult::Matched { .. })) {
+                continue;
+            }
+            let candidates: Vec<usize> = (0..movements.len())
+                .filter(|m| !claimed[*m] && is_candidate(&entries[i], amounts[i], &movements[*m]))
+                .collect();
+            results[i] = Some(match candidates.as_slice() {
+                [] => ReconcileResult::Missing,
+                [only] => {
+                    claimed[*only] = true;
+                    progress = true;
+                    ReconcileResult::Matched { tx_index: movements[*only].tx_index }
+                }
+                several => ReconcileResult::Ambiguous {
+                    candidates: several.iter().take(MAX_AMBIGUOUS_CANDIDATES).map(|m| movements[*m].tx_index).collect(),
+                },
+            });
+        }
+        if !progress {
+            break;
+        }
+    }
+
+    Ok(ReconcileReport {
+        results: results.into_iter().map(|r| r.unwrap_or(ReconcileResult::Missing)).collect(),
+        unmatched: movements.iter().zip(&claimed).filter(|(_, c)| !**c).map(|(m, _)| m.tx_index).collect(),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{mint_internal, transfer_internal};
+    use crate::runtime;
+    use crate::test_utils::{register_test_token, test_account};
+
+    #[test]
+    fn test_reconcile_statement() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let (token, other_token) = (register_test_token(1, 0), register_test_token(2, 0));
+        let (alice, bob) = (test_account(1), test_account(2));
+        runtime::set_caller(controller);
+        let start = runtime::time();
+
+        let salary = mint_internal(token, alice.clone(), 1_000, Some(b"salary"), None).unwrap();
+        mint_internal(other_token, alice.clone(), 50, None, None).unwrap();
+        runtime::advance_time(10);
+        let rent = transfer_internal(token, alice.clone(), bob.clone(), 300, None, Some(b"rent"), None).unwrap();
+        runtime::advance_time(10);
+        let coffee = transfer_internal(token, alice.clone(), bob.clone(), 5, None, None, None).unwrap();
+        runtime::advance_time(10);
+        let lunch = transfer_internal(token, alice.clone(), bob.clone(), 5, None, None, None).unwrap();
+        runtime::advance_time(10);
+        let refund = transfer_internal(token, bob.clone(), alice.clone(), 5, None, None, None).unwrap();
+        let end = runtime::time();
+
+        let entry = |reference: &[u8], direction, amount: u64, time_window| StatementEntry {
+            reference: reference.to_vec(),
+            direction,
+            amount: Nat::from(amount),
+            time_window,
+        };
+        use StatementDirection::{Incoming, Outgoing};
+        let report = reconcile(alice.clone(), token, vec![
+            entry(b"", Outgoing, 5, (start, end)),
+            entry(b"rent", Outgoing, 300, (start, end)),
+            entry(b"salary", Incoming, 1_000, (start, start)),
+            entry(b"", Outgoing, 5, (start + 25, end)),
+            entry(b"", Outgoing, 7, (start, end)),
+            entry(b"wrong", Outgoing, 300, (start, end)),
+        ]).unwrap();
+        assert_eq!(report.results, vec![
+            // Ambiguous in the first round; the fourth entry then takes `lunch`.
+            ReconcileResult::Matched { tx_index: coffee },
+            ReconcileResult::Matched { tx_index: rent },
+            ReconcileResult::Matched { tx_index: salary },
+            ReconcileResult::Matched { tx_index: lunch },
+            ReconcileResult::Missing,
+            ReconcileResult::Missing,
+        ]);
+        assert_eq!(report.unmatched, vec![refund]);
+
+        let report = reconcile(alice.clone(), token, vec![entry(b"", Outgoing, 5, (start, end))]).unwrap();
+        assert_eq!(report.results, vec![ReconcileResult::Ambiguous { candidates: vec![coffee, lunch] }]);
+        assert_eq!(report.unmatched, vec![salary, rent, coffee, lunch, refund]);
+        let report = reconcile(alice.clone(), token, vec![entry(b"", Incoming, 5, (end, end + 1))]).unwrap();
+        assert_eq!((report.results, report.unmatched), (vec![ReconcileResult::Matched { tx_index: refund }], vec![]));
+
+        assert!(matches!(reconcile(alice.clone(), token, vec![]), Err(QueryError::InvalidInput(_))));
+        assert!(matches!(reconcile(alice.clone(), token, vec![entry(b"", Incoming, 5, (end, start))]), Err(QueryError::InvalidInput(_))));
+        let too_many = vec![entry(b"", Incoming, 5, (start, end)); MAX_RECONCILE_ENTRIES + 1];
+        assert!(matches!(reconcile(alice.clone(), token, too_many), Err(QueryError::InvalidInput(_))));
+        assert!(matches!(reconcile(alice, [9; 32], vec![entry(b"", Incoming, 5, (start, end))]), Err(QueryError::TokenNotFound)));
+    }
+
+    #[test]
+    fn test_first_block_from_skips_earlier_blocks() {
+        state::init_state(test_account(0xFE).owner);
+        let token = register_test_token(1, 0);
+        let hour = 3_600_000_000_000;
+        let mut blocks = Vec::new();
+        for i in 0..20u64 {
+            runtime::advance_time(hour);
+            blocks.push((runtime::time(), mint_internal(token, test_account(1), 1 + i as u128, None, None).unwrap()));
+        }
+        assert_eq!(first_block_from(0), 0);
+        for (time, tx_index) in &blocks {
+            // Never past the first block at `time`, and never more than a few blocks before it.
+            let first = first_block_from(*time);
+            assert!(first <= *tx_index && tx_index - first <= 1);
+        }
+        assert_eq!(first_block_from(u64::MAX), state::get_transaction_count());
+    }
+}
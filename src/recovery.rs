@@ -0,0 +1,181 @@
+use crate::types::{constants, StoredRecovery};
+use crate::state;
+use crate::runtime;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecoveryConfig {
+    pub recovery_principal: Principal,
+    pub delay_ns: u64,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecoveryStatus {
+    pub recovery_principal: Principal,
+    pub delay_ns: u64,
+    pub initiated_at: Option<u64>,
+    pub completes_at: Option<u64>,
+}
+
+impl From<StoredRecovery> for RecoveryStatus {
+    fn from(r: StoredRecovery) -> Self {
+        RecoveryStatus {
+            recovery_principal: r.recovery_principal,
+            delay_ns: r.delay_ns,
+            initiated_at: r.initiated_at,
+            completes_at: r.initiated_at.map(|t| t.saturating_add(r.delay_ns)),
+        }
+    }
+}
+
+
+fn configured_for_caller() -> Result<StoredRecovery, String> {
+    let recovery = state::get_recovery().ok_or("Recovery is not configured".to_string())?;
+    if recovery.recovery_principal != runtime::caller() {
+        return Err("Only the recovery principal can perform this operation".to_string());
+    }
+    Ok(recovery)
+}
+
+
+// Passing None disables recovery. Any change cancels a pending countdown.
+#[ic_cdk::update]
+pub fn configure_recovery(config: Option<RecoveryConfig>) -> Result<(), String> {
+    state::require_controller()?;
+
+    let detail = match &config {
+        Some(c) => {
+            if c.delay_ns < constants::MIN_RECOVERY_DELAY_NS {
+                return Err(format!("Recovery delay must be at least {} ns", constants::MIN_RECOVERY_DELAY_NS));
+            }
+            if c.recovery_principal == Principal::anonymous() {
+                return Err("Recovery principal cannot be anonymous".to_string());
+            }
+            state::set_recovery(Some(&StoredRecovery {
+                recovery_principal: c.recovery_principal,
+                delay_ns: c.delay_ns,
+                initiated_at: None,
+            }));
+            format!("{} after {} ns", c.recovery_principal, c.delay_ns)
+        }
+        None => {
+            state::set_recovery(None);
+            "disabled".to_string()
+        }
+    };
+
+    state::record_admin_action("configure_recovery", detail);
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn initiate_recovery() -> Result<RecoveryStatus, String> {
+    let mut recovery = configured_for_caller()?;
+    if recovery.initiated_at.is_some() {
+        return Err("Recovery is already in progress".to_string());
+    }
+
+    recovery.initiated_at = Some(runtime::time());
+    state::set_recovery(Some(&recovery));
+    state::record_admin_action("initiate_recovery", recovery.recovery_principal.to_string());
+
+    let status = RecoveryStatus::from(recovery);
+    #[cfg(feature = "notifications")]
+    crate::notifications::publish_recovery_initiated(&status);
+    Ok(status)
+}
+
+
+#[ic_cdk::update]
+pub fn veto_recovery() -> Result<(), String> {
+    state::require_controller()?;
+
+    let mut recovery = state::get_recovery().ok_or("Recovery is not configured".to_string())?;
+    if recovery.initiated_at.take().is_none() {
+        return Err("No recovery in progress".to_string());
+    }
+
+    state::set_recovery(Some(&recovery));
+    state::record_admin_action("veto_recovery", recovery.recovery_principal.to_string());
+    Ok(())
+}
+
+
+#[ic_cdk::update]
+pub fn complete_recovery() -> Result<(), String> {
+    let mut recovery = configured_for_caller()?;
+    let initiated_at = recovery.initiated_at.ok_or("No recovery in progress".to_string())?;
+    let completes_at = initiated_at.saturating_add(recovery.delay_ns);
+    if runtime::time() < completes_at {
+        return Err(format!("Recovery delay has not elapsed (completes at {})", completes_at));
+    }
+
+    state::add_controller_internal(recovery.recovery_principal)?;
+    recovery.initiated_at = None;
+    state::set_recovery(Some(&recovery));
+    state::record_admin_action("complete_recovery", recovery.recovery_principal.to_string());
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_recovery_status() -> Option<RecoveryStatus> {
+    state::get_recovery().map(RecoveryStatus::from)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_account;
+
+    #[test]
+    fn test_recovery_lifecycle() {
+        let controller = test_account(0xFE).owner;
+        let rescuer = test_account(7).owner;
+        state::init_state(controller);
+        let delay = 30 * constants::NANOS_PER_DAY;
+
+        // Inert until configured.
+        runtime::set_caller(rescuer);
+        assert!(initiate_recovery().is_err());
+        assert!(configure_recovery(Some(RecoveryConfig { recovery_principal: rescuer, delay_ns: delay })).is_err());
+
+        runtime::set_caller(controller);
+        assert!(configure_recovery(Some(RecoveryConfig { recovery_principal: rescuer, delay_ns: 1 })).is_err());
+        configure_recovery(Some(RecoveryConfig { recovery_principal: rescuer, delay_ns: delay })).unwrap();
+        assert!(initiate_recovery().is_err());
+
+        // A controller veto cancels the countdown.
+        runtime::set_caller(rescuer);
+        let status = initiate_recovery().unwrap();
+        assert_eq!(status.completes_at, Some(runtime::time() + delay));
+        assert!(initiate_recovery().is_err());
+        runtime::set_caller(controller);
+        veto_recovery().unwrap();
+        assert_eq!(get_recovery_status().unwrap().initiated_at, None);
+
+        runtime::set_caller(rescuer);
+        initiate_recovery().unwrap();
+        runtime::advance_time(delay - 1);
+        assert!(complete_recovery().is_err());
+        assert!(!state::is_controller(&rescuer));
+        runtime::advance_time(1);
+        complete_recovery().unwrap();
+        assert!(state::is_controller(&rescuer));
+        assert_eq!(get_recovery_status().unwrap().initiated_at, None);
+
+        let actions: Vec<String> = state::list_admin_actions(0, 10).into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, vec![
+            "configure_recovery",
+            "initiate_recovery",
+            "veto_recovery",
+            "initiate_recovery",
+            "complete_recovery",
+        ]);
+    }
+}
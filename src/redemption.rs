@@ -0,0 +1,200 @@
+use crate::privacy::require_token_privileged_reader;
+use crate::queries::QueryError;
+use crate::runtime;
+use crate::state;
+use crate::transaction::{StoredTxV1, OP_BURN};
+use crate::types::StoredBurnReceipt;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+
+// Burns that redeem a bridged asset record where it went: an external
+// reference given with the burn, and a proof from the destination chain
+// attached afterwards, once. Both live beside the block, keyed by its index,
+// so the block layout and hash are unchanged.
+
+
+pub const MAX_EXTERNAL_REF_LEN: usize = 128;
+pub const MAX_EXTERNAL_PROOF_LEN: usize = 256;
+// How long after a burn its proof may be attached.
+pub const PROOF_ATTACH_WINDOW_NS: u64 = 7 * 24 * 3_600 * 1_000_000_000;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BurnReceipt {
+    pub tx_index: u64,
+    pub transaction: StoredTxV1,
+    // None for burns with no receipt record, such as transfers to a burn address.
+    pub burned_by: Option<Principal>,
+    pub external_ref: Option<Vec<u8>>,
+    pub proof: Option<Vec<u8>>,
+    pub proof_attached_at: Option<u64>,
+}
+
+
+pub(crate) fn validate_external_ref(external_ref: Option<&[u8]>) -> Result<(), String> {
+    match external_ref {
+        Some(r) if r.len() > MAX_EXTERNAL_REF_LEN => {
+            Err(format!("External reference too long (max {} bytes)", MAX_EXTERNAL_REF_LEN))
+        }
+        _ => Ok(()),
+    }
+}
+
+
+pub(crate) fn record_burn(tx_index: u64, burned_by: Principal, external_ref: Option<&[u8]>) {
+    state::set_burn_receipt(tx_index, StoredBurnReceipt {
+        burned_by,
+        external_ref: external_ref.map(<[u8]>::to_vec),
+        proof: None,
+        proof_attached_at: None,
+    });
+}
+
+
+fn burn_transaction(tx_index: u64) -> Result<StoredTxV1, String> {
+    state::get_transaction(tx_index)
+        .filter(|tx| tx.op == OP_BURN)
+        .ok_or_else(|| format!("Transaction {} is not a burn", tx_index))
+}
+
+
+// The principal that made the burn, or a controller, within the attach window.
+// Burns without a receipt record only take a controller's proof.
+#[ic_cdk::update]
+pub fn attach_external_proof(tx_index: u64, proof: Vec<u8>) -> Result<(), String> {
+    if proof.is_empty() || proof.len() > MAX_EXTERNAL_PROOF_LEN {
+        return Err(format!("Proof must be 1 to {} bytes", MAX_EXTERNAL_PROOF_LEN));
+    }
+    let tx = burn_transaction(tx_index)?;
+    let caller = runtime::caller();
+    let record = state::get_burn_receipt(tx_index);
+    if !state::is_controller(&caller) && record.as_ref().is_none_or(|r| r.burned_by != caller) {
+        return Err("Only the burner or a controller may attach a proof".to_string());
+    }
+    if record.as_ref().is_some_and(|r| r.proof.is_some()) {
+        return Err(format!("A proof is already attached to burn {}", tx_index));
+    }
+    let now = runtime::time();
+    if now > tx.get_timestamp().saturating_add(PROOF_ATTACH_WINDOW_NS) {
+        return Err("The proof window for this burn has closed".to_string());
+    }
+
+    let mut record = record.unwrap_or(StoredBurnReceipt {
+        burned_by: caller,
+        external_ref: None,
+        proof: None,
+        proof_attached_at: None,
+    });
+    record.proof = Some(proof);
+    record.proof_attached_at = Some(now);
+    state::set_burn_receipt(tx_index, record);
+    Ok(())
+}
+
+
+// Readable by the burner and by whoever may read the token's transactions.
+#[ic_cdk::query]
+pub fn get_burn_receipt(tx_index: u64) -> Result<BurnReceipt, QueryError> {
+    let transaction = burn_transaction(tx_index).map_err(QueryError::InvalidInput)?;
+    let record = state::get_burn_receipt(tx_index);
+    if record.as_ref().is_none_or(|r| r.burned_by != runtime::caller()) {
+        require_token_privileged_reader(transaction.token_id)?;
+    }
+    Ok(match record {
+        Some(r) => BurnReceipt {
+            tx_index,
+            transaction,
+            burned_by: Some(r.burned_by),
+            external_ref: r.external_ref,
+            proof: r.proof,
+            proof_attached_at: r.proof_attached_at,
+        },
+        None => BurnReceipt {
+            tx_index,
+            transaction,
+            burned_by: None,
+            external_ref: None,
+            proof: None,
+            proof_attached_at: None,
+        },
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{burn_tokens, burn_tokens_from, mint_tokens, transfer, Icrc151TransferArgs, TransferResult};
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Nat;
+
+    fn setup() -> (Principal, crate::types::TokenId) {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        mint_tokens(token, test_account(1), Nat::from(1_000u64), None).unwrap();
+        mint_tokens(token, test_account(2), Nat::from(1_000u64), None).unwrap();
+        (controller, token)
+    }
+
+    #[test]
+    fn test_burn_receipt_links_ref_and_proof() {
+        let (controller, token) = setup();
+        let bridge = test_account(1).owner;
+        runtime::set_caller(bridge);
+        assert!(burn_tokens(token, Nat::from(10u64), None, Some(vec![7u8; MAX_EXTERNAL_REF_LEN + 1]), None, None).is_err());
+        let tx_index = burn_tokens(token, Nat::from(10u64), None, Some(b"dest:0xabc".to_vec()), None, None).unwrap();
+
+        let receipt = get_burn_receipt(tx_index).unwrap();
+        assert_eq!((receipt.burned_by, receipt.external_ref.as_deref()), (Some(bridge), Some(&b"dest:0xabc"[..])));
+        assert_eq!((receipt.transaction.get_amount(), receipt.proof), (10, None));
+
+        // Only the burner or a controller, and only once.
+        runtime::set_caller(test_account(2).owner);
+        assert!(attach_external_proof(tx_index, vec![1u8; 32]).unwrap_err().contains("Only the burner"));
+        runtime::set_caller(bridge);
+        assert!(attach_external_proof(tx_index, vec![1u8; MAX_EXTERNAL_PROOF_LEN + 1]).is_err());
+        runtime::advance_time(5);
+        attach_external_proof(tx_index, vec![1u8; 32]).unwrap();
+        assert!(attach_external_proof(tx_index, vec![2u8; 32]).unwrap_err().contains("already attached"));
+        runtime::set_caller(controller);
+        assert!(attach_external_proof(tx_index, vec![2u8; 32]).unwrap_err().contains("already attached"));
+
+        let receipt = get_burn_receipt(tx_index).unwrap();
+        assert_eq!((receipt.proof, receipt.proof_attached_at), (Some(vec![1u8; 32]), Some(runtime::time())));
+        assert_eq!(receipt.external_ref.as_deref(), Some(&b"dest:0xabc"[..]));
+
+        // Not a burn.
+        assert!(get_burn_receipt(0).is_err());
+        assert!(attach_external_proof(0, vec![1u8; 32]).unwrap_err().contains("not a burn"));
+    }
+
+    #[test]
+    fn test_proof_window_and_controller_burns() {
+        let (controller, token) = setup();
+        let holder = test_account(2);
+        let tx_index = burn_tokens_from(token, holder.clone(), Nat::from(5u64), None, None).unwrap();
+        let receipt = get_burn_receipt(tx_index).unwrap();
+        assert_eq!((receipt.burned_by, receipt.external_ref), (Some(controller), None));
+
+        // The holder did not make this burn.
+        runtime::set_caller(holder.owner);
+        assert!(attach_external_proof(tx_index, vec![1u8; 32]).is_err());
+
+        runtime::set_caller(controller);
+        runtime::advance_time(PROOF_ATTACH_WINDOW_NS + 1);
+        assert!(attach_external_proof(tx_index, vec![1u8; 32]).unwrap_err().contains("closed"));
+        assert_eq!(get_burn_receipt(tx_index).unwrap().proof, None);
+
+        // A transfer is no burn.
+        runtime::set_caller(holder.owner);
+        let args = Icrc151TransferArgs {
+            token_id: token, from_subaccount: None, to: test_account(3), amount: Nat::from(1u64),
+            fee: None, memo: None, created_at_time: None, private_memo: None, amount_mode: None, use_sponsorship: None, args_version: None,
+        };
+        let TransferResult::Ok(transfer_index) = transfer(args) else { panic!("transfer failed") };
+        assert!(get_burn_receipt(transfer_index).is_err());
+    }
+}
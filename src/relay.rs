@@ -0,0 +1,393 @@
+use crate::context::OperationContext;
+use crate::failures::{self, FailedOperation};
+use crate::operations::{transfer_internal, TransferError};
+use crate::runtime;
+use crate::state;
+use crate::types::{memory_ids, Account, Memo, OpKind, StoredPrincipal, TokenId};
+use candid::{CandidType, Principal};
+use ed25519_dalek::{Signature, VerifyingKey};
+use ic_stable_structures::StableBTreeMap;
+use num_traits::cast::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+
+// Transfers signed by the owner's key and submitted by anyone, so users
+// without cycles or an agent can still move funds. The owner signs the
+// digest from `get_signed_transfer_digest`; the relayer is only the message
+// caller and can optionally be paid a tip out of the owner's balance. Only
+// Ed25519 keys, whose self-authenticating principal is the owner, are
+// accepted. Per-owner nonces make every signed transfer usable once.
+
+
+pub const SIGNED_TRANSFER_DOMAIN: &[u8] = b"icrc151:signed_transfer:v1";
+// DER SubjectPublicKeyInfo header of an Ed25519 key; the raw key follows.
+pub const ED25519_DER_PREFIX: [u8; 12] = [0x30, 0x2A, 0x30, 0x05, 0x06, 0x03, 0x2B, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+
+thread_local! {
+    // Next nonce each owner must sign; absent means 0.
+    static TRANSFER_NONCES: RefCell<StableBTreeMap<StoredPrincipal, u64, state::Memory>> = RefCell::new(
+        StableBTreeMap::init(state::memory(memory_ids::TRANSFER_NONCES))
+    );
+}
+
+
+// See state::warm_up.
+pub(crate) fn warm_up() {
+    if state::holds_data(memory_ids::TRANSFER_NONCES) {
+        TRANSFER_NONCES.with(|_| ());
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SignedTransfer {
+    pub token_id: TokenId,
+    pub from: Account,
+    pub to: Account,
+    pub amount: candid::Nat,
+    pub fee: Option<candid::Nat>,
+    pub memo: Option<Memo>,
+    pub nonce: u64,
+    pub expires_at: u64,
+    // Paid from `from` to the submitting caller's default account, with its own fee.
+    pub relayer_tip: Option<candid::Nat>,
+    // When set, only this principal may submit.
+    pub relayer: Option<candid::Principal>,
+    // DER-encoded Ed25519 key; not part of the signed digest.
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SignedTransferReceipt {
+    pub tx_index: u64,
+    pub tip_tx_index: Option<u64>,
+    // Why the tip was not paid, when there was one.
+    pub tip_error: Option<TransferError>,
+}
+
+
+fn rejected(error_code: u64, message: impl Into<String>) -> TransferError {
+    TransferError::GenericError { error_code: candid::Nat::from(error_code), message: message.into() }
+}
+
+
+fn to_u128(value: &candid::Nat, what: &str) -> Result<u128, TransferError> {
+    value.0.to_u128().ok_or_else(|| rejected(400, format!("{} exceeds maximum value (u128::MAX)", what)))
+}
+
+
+fn put_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u32).to_be_bytes());
+    hasher.update(bytes);
+}
+
+
+fn put_account(hasher: &mut Sha256, account: &Account) {
+    put_bytes(hasher, account.owner.as_slice());
+    hasher.update(account.effective_subaccount());
+}
+
+
+fn put_optional_amount(hasher: &mut Sha256, amount: Option<u128>) {
+    match amount {
+        Some(amount) => {
+            hasher.update([1]);
+            hasher.update(amount.to_be_bytes());
+        }
+        None => hasher.update([0]),
+    }
+}
+
+
+// Every field but the key and the signature, length-prefixed where variable,
+// after the domain and this ledger's canister id, so a signature is good for
+// one ledger and one token only.
+fn signed_transfer_digest(ledger: Principal, signed: &SignedTransfer) -> Result<[u8; 32], TransferError> {
+    let fee = signed.fee.as_ref().map(|f| to_u128(f, "Fee")).transpose()?;
+    let tip = signed.relayer_tip.as_ref().map(|t| to_u128(t, "Tip")).transpose()?;
+
+    let mut hasher = Sha256::new();
+    put_bytes(&mut hasher, SIGNED_TRANSFER_DOMAIN);
+    put_bytes(&mut hasher, ledger.as_slice());
+    hasher.update(signed.token_id);
+    put_account(&mut hasher, &signed.from);
+    put_account(&mut hasher, &signed.to);
+    hasher.update(to_u128(&signed.amount, "Amount")?.to_be_bytes());
+    put_optional_amount(&mut hasher, fee);
+    match &signed.memo {
+        Some(memo) => {
+            hasher.update([1]);
+            put_bytes(&mut hasher, memo);
+        }
+        None => hasher.update([0]),
+    }
+    hasher.update(signed.nonce.to_be_bytes());
+    hasher.update(signed.expires_at.to_be_bytes());
+    put_optional_amount(&mut hasher, tip);
+    match &signed.relayer {
+        Some(relayer) => {
+            hasher.update([1]);
+            put_bytes(&mut hasher, relayer.as_slice());
+        }
+        None => hasher.update([0]),
+    }
+    Ok(hasher.finalize().into())
+}
+
+
+// What the owner signs. `public_key` and `signature` are ignored.
+#[ic_cdk::query]
+pub fn get_signed_transfer_digest(signed: SignedTransfer) -> Result<Vec<u8>, TransferError> {
+    signed_transfer_digest(runtime::canister_id(), &signed).map(|digest| digest.to_vec())
+}
+
+
+#[ic_cdk::query]
+pub fn get_transfer_nonce(owner: candid::Principal) -> u64 {
+    StoredPrincipal::from_principal(&owner)
+        .ok()
+        .and_then(|owner| TRANSFER_NONCES.with(|n| n.borrow().get(&owner)))
+        .unwrap_or(0)
+}
+
+
+fn verify_owner_signature(signed: &SignedTransfer, digest: &[u8; 32]) -> Result<(), TransferError> {
+    let raw_key = signed.public_key.strip_prefix(&ED25519_DER_PREFIX[..])
+        .filter(|key| key.len() == 32)
+        .ok_or_else(|| rejected(401, "Public key must be a DER-encoded Ed25519 key"))?;
+    if Principal::self_authenticating(&signed.public_key) != signed.from.owner {
+        return Err(rejected(401, "Public key does not belong to the sending account's owner"));
+    }
+    let key = VerifyingKey::from_bytes(raw_key.try_into().unwrap())
+        .map_err(|_| rejected(401, "Invalid public key"))?;
+    let signature = Signature::from_slice(&signed.signature)
+        .map_err(|_| rejected(401, "Invalid signature"))?;
+    key.verify_strict(digest, &signature).map_err(|_| rejected(401, "Invalid signature"))
+}
+
+
+// The transfer runs as if the owner had called `transfer` without a
+// created_at_time. Its nonce is spent only when it succeeds, so a signed
+// transfer refused for lack of funds can be submitted again until it expires.
+// The tip follows as a second transfer once the first has gone through; if it
+// fails, the receipt and the failure log say why.
+#[ic_cdk::update]
+pub fn transfer_signed(signed: SignedTransfer) -> Result<SignedTransferReceipt, TransferError> {
+    let relayer = runtime::caller();
+    let digest = signed_transfer_digest(runtime::canister_id(), &signed)?;
+    if signed.relayer.is_some_and(|r| r != relayer) {
+        return Err(rejected(403, "This signed transfer names another relayer"));
+    }
+    if signed.expires_at <= runtime::time() {
+        return Err(rejected(400, "Signed transfer expired"));
+    }
+    verify_owner_signature(&signed, &digest)?;
+
+    let owner = StoredPrincipal::from_principal(&signed.from.owner).map_err(|e| rejected(400, e))?;
+    let expected = get_transfer_nonce(signed.from.owner);
+    if signed.nonce != expected {
+        return Err(rejected(409, format!("Nonce mismatch: expected {}", expected)));
+    }
+
+    let amount = to_u128(&signed.amount, "Amount")?;
+    let fee = signed.fee.as_ref().map(|f| to_u128(f, "Fee")).transpose()?;
+    let tip = signed.relayer_tip.as_ref().map(|t| to_u128(t, "Tip")).transpose()?.unwrap_or(0);
+    if tip > 0 {
+        check_tip_covered(&signed, amount, tip)?;
+    }
+
+    let tx_index = transfer_internal(
+        signed.token_id, signed.from.clone(), signed.to.clone(), amount, fee, signed.memo.as_deref(), None,
+    )?;
+    TRANSFER_NONCES.with(|n| n.borrow_mut().insert(owner, expected + 1));
+
+    if tip == 0 {
+        return Ok(SignedTransferReceipt { tx_index, tip_tx_index: None, tip_error: None });
+    }
+    // Its own memo keeps the tip apart from the transfer for deduplication.
+    let watch = failures::watch(FailedOperation::RelayerTip, signed.token_id, &signed, signed.relayer_tip.as_ref());
+    let memo = format!("icrc151:relayer_tip:{}", signed.nonce);
+    let to = Account { owner: relayer, subaccount: None };
+    match transfer_internal(signed.token_id, signed.from.clone(), to, tip, fee, Some(memo.as_bytes()), None) {
+        Ok(tip_tx_index) => Ok(SignedTransferReceipt { tx_index, tip_tx_index: Some(tip_tx_index), tip_error: None }),
+        Err(error) => {
+            failures::record(watch, &error);
+            Ok(SignedTransferReceipt { tx_index, tip_tx_index: None, tip_error: Some(error) })
+        }
+    }
+}
+
+
+// Refuses up front when the account cannot pay both the transfer and the tip,
+// each with its fee, so a tip is not silently dropped for lack of funds.
+fn check_tip_covered(signed: &SignedTransfer, amount: u128, tip: u128) -> Result<(), TransferError> {
+    let ctx = OperationContext::new(OpKind::Transfer, signed.token_id, signed.from.owner, None)?;
+    let fee = ctx.metadata.fee;
+    let from_key = signed.from.to_key();
+    let fees_here = if ctx.fee_in_token() { fee.saturating_mul(2) } else { 0 };
+    let needed = amount.saturating_add(tip).saturating_add(fees_here);
+    let balance = state::get_spendable_balance(signed.token_id, from_key);
+    if balance < needed {
+        return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(balance) });
+    }
+    if !ctx.fee_in_token() {
+        let fee_token = ctx.fee_token();
+        let fee_balance = state::get_spendable_balance(fee_token, from_key);
+        if fee_balance < fee.saturating_mul(2) {
+            return Err(TransferError::InsufficientFeeFunds { fee_token, balance: candid::Nat::from(fee_balance) });
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::mint_tokens;
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Nat;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn der_key(key: &SigningKey) -> Vec<u8> {
+        let mut der = ED25519_DER_PREFIX.to_vec();
+        der.extend_from_slice(key.verifying_key().as_bytes());
+        der
+    }
+
+    fn owner_of(key: &SigningKey) -> Account {
+        Account { owner: Principal::self_authenticating(der_key(key)), subaccount: None }
+    }
+
+    fn unsigned(token: TokenId, key: &SigningKey, amount: u64, nonce: u64) -> SignedTransfer {
+        SignedTransfer {
+            token_id: token,
+            from: owner_of(key),
+            to: test_account(2),
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            nonce,
+            expires_at: runtime::time() + 60_000_000_000,
+            relayer_tip: None,
+            relayer: None,
+            public_key: der_key(key),
+            signature: vec![],
+        }
+    }
+
+    fn sign(mut signed: SignedTransfer, key: &SigningKey) -> SignedTransfer {
+        let digest = get_signed_transfer_digest(signed.clone()).unwrap();
+        signed.signature = key.sign(&digest).to_bytes().to_vec();
+        signed
+    }
+
+    fn setup(key: &SigningKey) -> TokenId {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 10);
+        mint_tokens(token, owner_of(key), Nat::from(1_000u64), None).unwrap();
+        runtime::set_caller(test_account(9).owner);
+        token
+    }
+
+    #[test]
+    fn test_relayed_transfer_pays_tip_and_spends_nonce() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let token = setup(&key);
+        let (owner, relayer) = (owner_of(&key), test_account(9));
+        assert_eq!(get_transfer_nonce(owner.owner), 0);
+
+        let signed = sign(SignedTransfer { relayer_tip: Some(Nat::from(5u64)), ..unsigned(token, &key, 100, 0) }, &key);
+        let receipt = transfer_signed(signed.clone()).unwrap();
+        assert!(receipt.tip_tx_index.is_some() && receipt.tip_error.is_none());
+        assert_eq!(state::get_balance(token, owner.to_key()), 1_000 - 100 - 5 - 2 * 10);
+        assert_eq!(state::get_balance(token, test_account(2).to_key()), 100);
+        assert_eq!(state::get_balance(token, relayer.to_key()), 5);
+        assert_eq!(get_transfer_nonce(owner.owner), 1);
+
+        // Replays fail on the nonce, whoever submits them and however late.
+        assert!(matches!(transfer_signed(signed.clone()), Err(TransferError::GenericError { error_code, .. }) if error_code == 409u64));
+        runtime::set_caller(test_account(8).owner);
+        runtime::advance_time(1);
+        assert!(transfer_signed(signed).is_err());
+        assert_eq!(state::get_balance(token, test_account(2).to_key()), 100);
+
+        // A failed transfer leaves the nonce for a later attempt.
+        let too_much = sign(unsigned(token, &key, 10_000, 1), &key);
+        assert!(matches!(transfer_signed(too_much), Err(TransferError::InsufficientFunds { .. })));
+        assert_eq!(get_transfer_nonce(owner.owner), 1);
+        transfer_signed(sign(unsigned(token, &key, 10, 1), &key)).unwrap();
+        assert_eq!(get_transfer_nonce(owner.owner), 2);
+    }
+
+    #[test]
+    fn test_refused_tip_is_reported_and_logged() {
+        use crate::failures::{get_recent_failures, set_failure_log, FailureLogSettings};
+        use crate::types::{IncomingPolicy, IncomingPolicyConfig};
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let token = setup(&key);
+        let relayer = test_account(9);
+        runtime::set_caller(test_account(0xFE).owner);
+        set_failure_log(FailureLogSettings { enabled: true, record_amounts: false }).unwrap();
+        // The relayer holds none of the token, so its policy refuses the tip.
+        state::set_incoming_policy(relayer.to_key(), IncomingPolicyConfig { policy: IncomingPolicy::DenyUnknown, allow_controller_mints: false });
+        runtime::set_caller(relayer.owner);
+
+        let signed = sign(SignedTransfer { relayer_tip: Some(Nat::from(5u64)), ..unsigned(token, &key, 100, 0) }, &key);
+        let receipt = transfer_signed(signed).unwrap();
+        assert_eq!(receipt.tip_tx_index, None);
+        assert!(receipt.tip_error.is_some());
+        assert_eq!(state::get_balance(token, test_account(2).to_key()), 100);
+        assert_eq!(state::get_balance(token, relayer.to_key()), 0);
+
+        runtime::set_caller(test_account(0xFE).owner);
+        let failures = get_recent_failures(Some(relayer.owner), None).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].operation, FailedOperation::RelayerTip);
+    }
+
+    #[test]
+    fn test_signed_transfer_rejects_wrong_key_and_tampering() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let other = SigningKey::from_bytes(&[8u8; 32]);
+        let token = setup(&key);
+        let unauthorized = |signed: SignedTransfer| matches!(
+            transfer_signed(signed), Err(TransferError::GenericError { error_code, .. }) if error_code == 401u64
+        );
+
+        // Signed by another key, presented with the owner's key or with its own.
+        assert!(unauthorized(sign(unsigned(token, &key, 100, 0), &other)));
+        let impostor = SignedTransfer { public_key: der_key(&other), ..unsigned(token, &key, 100, 0) };
+        assert!(unauthorized(sign(impostor, &other)));
+        assert!(unauthorized(SignedTransfer { public_key: vec![1, 2, 3], ..sign(unsigned(token, &key, 100, 0), &key) }));
+
+        // Any field changed after signing breaks the signature.
+        let signed = sign(unsigned(token, &key, 100, 0), &key);
+        assert!(unauthorized(SignedTransfer { amount: Nat::from(900u64), ..signed.clone() }));
+        assert!(unauthorized(SignedTransfer { to: test_account(3), ..signed.clone() }));
+        assert!(unauthorized(SignedTransfer { relayer_tip: Some(Nat::from(1u64)), ..signed.clone() }));
+        // So does submitting it to another ledger.
+        let ledger = runtime::canister_id();
+        runtime::set_canister_id(test_account(0xAA).owner);
+        assert!(unauthorized(signed.clone()));
+        runtime::set_canister_id(ledger);
+
+        // Expired, or reserved for another relayer.
+        let expired = sign(SignedTransfer { expires_at: runtime::time(), ..unsigned(token, &key, 100, 0) }, &key);
+        assert!(transfer_signed(expired).is_err());
+        let pinned = sign(SignedTransfer { relayer: Some(test_account(3).owner), ..unsigned(token, &key, 100, 0) }, &key);
+        assert!(transfer_signed(pinned.clone()).is_err());
+        assert_eq!(get_transfer_nonce(owner_of(&key).owner), 0);
+        runtime::set_caller(test_account(3).owner);
+        transfer_signed(pinned).unwrap();
+        assert_eq!(state::get_balance(token, owner_of(&key).to_key()), 890);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::context::OperationContext;
+use crate::operations::{transfer_internal, TransferError};
+use crate::privacy::require_token_account_reader;
+use crate::queries::QueryError;
+use crate::runtime;
+use crate::state;
+use crate::types::{constants, Account, OpKind, Reservation, Subaccount, TokenId};
+use crate::validation::{validate_account, validate_memo, validate_token_id};
+use candid::CandidType;
+use num_traits::cast::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+
+// Holds for two-phase flows such as checkout: funds are set aside while an
+// order is confirmed, then captured into a transfer or released. A hold moves
+// nothing and writes no block; it only lowers the balance every debit path
+// may spend. Expired holds stop counting at once and are cleared by the
+// maintenance sweep.
+
+
+pub const MAX_RESERVATION_TTL_NS: u64 = 7 * constants::NANOS_PER_DAY;
+pub const MAX_RESERVATION_REF_LEN: usize = 64;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceBreakdown {
+    pub total: u128,
+    pub available: u128,
+    pub reserved: u128,
+}
+
+
+fn rejected(message: impl Into<String>) -> TransferError {
+    TransferError::GenericError { error_code: candid::Nat::from(400u64), message: message.into() }
+}
+
+
+// The hold does not cover the fee a later capture pays; reserve it too if the
+// account must be able to pay it.
+#[ic_cdk::update]
+pub fn reserve(
+    token_id: TokenId,
+    from_subaccount: Option<Subaccount>,
+    amount: candid::Nat,
+    expires_at: u64,
+    reference: Option<Vec<u8>>,
+) -> Result<u64, TransferError> {
+    let ctx = OperationContext::new(OpKind::Transfer, token_id, runtime::caller(), None)?;
+    let account = Account { owner: ctx.caller, subaccount: from_subaccount.map(|s| s.to_vec()) };
+    validate_account(&account)?;
+    let amount = amount.0.to_u128().filter(|a| *a > 0).ok_or_else(|| rejected("Amount must be between 1 and u128::MAX"))?;
+    let now = ctx.timestamp;
+    if expires_at <= now || expires_at - now > MAX_RESERVATION_TTL_NS {
+        return Err(rejected(format!("Expiry must be in the future and at most {} ns away", MAX_RESERVATION_TTL_NS)));
+    }
+    if let Some(reference) = &reference {
+        if reference.len() > MAX_RESERVATION_REF_LEN {
+            return Err(rejected(format!("Reference too long (max {} bytes)", MAX_RESERVATION_REF_LEN)));
+        }
+        validate_memo(reference)?;
+    }
+    // The reference becomes the capture's memo.
+    crate::memo::validate_structured_memo(&ctx.metadata, reference.as_deref()).map_err(rejected)?;
+
+    let account_key = account.to_key();
+    let mut holds = state::list_account_reservations(token_id, account_key);
+    for expired in holds.iter().filter(|r| r.is_expired(now)) {
+        state::remove_reservation(expired.id);
+    }
+    holds.retain(|r| !r.is_expired(now));
+    if holds.len() >= constants::MAX_RESERVATIONS_PER_ACCOUNT {
+        return Err(rejected(format!("At most {} reservations per account and token", constants::MAX_RESERVATIONS_PER_ACCOUNT)));
+    }
+    let available = state::get_spendable_balance(token_id, account_key);
+    if available < amount {
+        return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(available) });
+    }
+
+    let id = state::next_reservation_id();
+    state::insert_reservation(Reservation { id, token_id, account, amount, reference, created_at: now, expires_at });
+    Ok(id)
+}
+
+
+fn owned_reservation(reservation_id: u64) -> Result<Reservation, TransferError> {
+    let reservation = state::get_reservation(reservation_id).ok_or_else(|| rejected("No such reservation"))?;
+    if reservation.account.owner != runtime::caller() {
+        return Err(TransferError::GenericError {
+            error_code: candid::Nat::from(403u64),
+            message: "Only the reserving account's owner can use a reservation".to_string(),
+        });
+    }
+    Ok(reservation)
+}
+
+
+// Transfers up to the reserved amount out of the held funds, plus the usual
+// fee, and ends the hold; what was not captured becomes available again. A
+// failed capture leaves the hold as it was.
+#[ic_cdk::update]
+pub fn capture(reservation_id: u64, to: Account, amount: candid::Nat) -> Result<u64, TransferError> {
+    let reservation = owned_reservation(reservation_id)?;
+    if reservation.is_expired(runtime::time()) {
+        return Err(rejected("Reservation expired"));
+    }
+    let amount = amount.0.to_u128().ok_or_else(|| rejected("Amount exceeds maximum value (u128::MAX)"))?;
+    if amount > reservation.amount {
+        return Err(rejected(format!("Amount exceeds the reserved {}", reservation.amount)));
+    }
+    let fee = state::get_token_metadata(reservation.token_id).map_err(|e| rejected(e.to_string()))?.fee;
+
+    state::remove_reservation(reservation_id);
+    let result = transfer_internal(
+        reservation.token_id,
+        reservation.account.clone(),
+        to,
+        amount,
+        Some(fee),
+        reservation.reference.as_deref(),
+        None,
+    );
+    if result.is_err() {
+        state::insert_reservation(reservation);
+    }
+    result
+}
+
+
+#[ic_cdk::update]
+pub fn release(reservation_id: u64) -> Result<(), TransferError> {
+    owned_reservation(reservation_id)?;
+    state::remove_reservation(reservation_id);
+    Ok(())
+}
+
+
+// Visible to the reserving account's owner and controllers.
+#[ic_cdk::query]
+pub fn get_reservation(reservation_id: u64) -> Option<Reservation> {
+    let reservation = state::get_reservation(reservation_id)?;
+    let caller = runtime::caller();
+    (reservation.account.owner == caller || state::is_controller(&caller)).then_some(reservation)
+}
+
+
+// `total` is what get_balance reports; `available` is what can be spent.
+#[ic_cdk::query]
+pub fn get_balance_breakdown(token_id: TokenId, account: Account) -> Result<BalanceBreakdown, QueryError> {
+    validate_token_id(&token_id)?;
+    validate_account(&account)?;
+    require_token_account_reader(token_id, &account)?;
+
+    let account_key = account.to_key();
+    let total = state::get_balance(token_id, account_key);
+    let reserved = state::get_reserved_balance(token_id, account_key);
+    Ok(BalanceBreakdown { total, available: total - reserved, reserved })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowances::{approve, transfer_from, Icrc151ApproveArgs, Icrc151TransferFromArgs};
+    use crate::maintenance::maintenance_tick;
+    use crate::operations::{burn_tokens_from, consolidate_subaccounts, mint_tokens, ConsolidationOutcome, TransferResultV2};
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Nat;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn setup(fee: u128) -> (TokenId, Account) {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, fee);
+        let owner = test_account(1);
+        runtime::set_caller(controller);
+        mint_tokens(token, owner.clone(), Nat::from(1_000u64), None).unwrap();
+        runtime::set_caller(owner.owner);
+        (token, owner)
+    }
+
+    fn hold(token: TokenId, amount: u64) -> Result<u64, TransferError> {
+        reserve(token, None, Nat::from(amount), runtime::time() + 60 * SECOND, Some(b"order-1".to_vec()))
+    }
+
+    fn breakdown(token: TokenId, account: &Account) -> (u128, u128, u128) {
+        let b = get_balance_breakdown(token, account.clone()).unwrap();
+        (b.total, b.available, b.reserved)
+    }
+
+    #[test]
+    fn test_holds_limit_every_debit_path() {
+        let (token, owner) = setup(10);
+        let id = hold(token, 600).unwrap();
+        assert_eq!(breakdown(token, &owner), (1_000, 400, 600));
+        assert!(matches!(hold(token, 401), Err(TransferError::InsufficientFunds { balance }) if balance == 400u64));
+
+        // Transfers, transfer_all and consolidation only see what is not held.
+        assert!(matches!(
+            transfer_internal(token, owner.clone(), test_account(2), 391, None, None, None),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
+        let TransferResultV2::Ok(all) = crate::operations::transfer_all(token, None, test_account(2), None) else { panic!("transfer_all failed") };
+        assert_eq!(all.amount, Nat::from(390u64));
+        assert_eq!(breakdown(token, &owner), (600, 0, 600));
+
+        // Spenders and controller burns too.
+        runtime::advance_time(1);
+        runtime::set_caller(test_account(0xFE).owner);
+        mint_tokens(token, owner.clone(), Nat::from(100u64), None).unwrap();
+        runtime::set_caller(owner.owner);
+        let spender = test_account(3);
+        approve(Icrc151ApproveArgs {
+            token_id: token, from_subaccount: None, spender: spender.clone(), amount: Nat::from(1_000u64),
+            expected_allowance: None, expires_at: None, fee: None, memo: None, created_at_time: None,
+            private_memo: None, args_version: None,
+        });
+        assert_eq!(breakdown(token, &owner), (690, 90, 600));
+        runtime::set_caller(spender.owner);
+        let result = transfer_from(Icrc151TransferFromArgs {
+            token_id: token, spender_subaccount: None, from: owner.clone(), to: spender.clone(), amount: Nat::from(81u64),
+            fee: None, memo: None, created_at_time: None, private_memo: None, args_version: None,
+        });
+        assert!(matches!(result, crate::operations::TransferResult::Err(TransferError::InsufficientFunds { .. })));
+        runtime::set_caller(test_account(0xFE).owner);
+        assert!(burn_tokens_from(token, owner.clone(), Nat::from(91u64), None, None).is_err());
+        burn_tokens_from(token, owner.clone(), Nat::from(90u64), None, None).unwrap();
+        assert_eq!(breakdown(token, &owner), (600, 0, 600));
+
+        // Releasing frees the funds.
+        runtime::set_caller(owner.owner);
+        release(id).unwrap();
+        assert_eq!(breakdown(token, &owner), (600, 600, 0));
+        assert!(release(id).is_err());
+    }
+
+    #[test]
+    fn test_capture_once_within_the_hold() {
+        let (token, owner) = setup(10);
+        let merchant = test_account(2);
+        let id = hold(token, 500).unwrap();
+
+        runtime::set_caller(merchant.owner);
+        assert!(capture(id, merchant.clone(), Nat::from(100u64)).is_err());
+        assert!(release(id).is_err());
+        assert_eq!(get_reservation(id), None);
+
+        runtime::set_caller(owner.owner);
+        assert!(capture(id, merchant.clone(), Nat::from(501u64)).is_err());
+        // Everything else is spent, so the capture's fee cannot be paid; the hold survives.
+        state::seed_balance(token, owner.to_key(), 500);
+        assert!(matches!(capture(id, merchant.clone(), Nat::from(500u64)), Err(TransferError::InsufficientFunds { .. })));
+        assert_eq!(get_reservation(id).unwrap().amount, 500);
+
+        state::seed_balance(token, owner.to_key(), 1_000);
+        let tx_index = capture(id, merchant.clone(), Nat::from(300u64)).unwrap();
+        assert_eq!(state::get_transaction(tx_index).unwrap().get_amount(), 300);
+        assert_eq!(state::get_balance(token, merchant.to_key()), 300);
+        // The uncaptured 200 is free again, and the hold is gone.
+        assert_eq!(breakdown(token, &owner), (690, 690, 0));
+        assert!(capture(id, merchant.clone(), Nat::from(1u64)).is_err());
+        assert!(release(id).is_err());
+    }
+
+    #[test]
+    fn test_holds_expire_and_are_swept() {
+        let (token, owner) = setup(0);
+        let id = hold(token, 700).unwrap();
+        assert!(reserve(token, None, Nat::from(1u64), runtime::time(), None).is_err());
+        assert!(reserve(token, None, Nat::from(1u64), runtime::time() + MAX_RESERVATION_TTL_NS + 1, None).is_err());
+        assert!(reserve(token, None, Nat::from(0u64), runtime::time() + SECOND, None).is_err());
+
+        runtime::advance_time(60 * SECOND);
+        assert_eq!(breakdown(token, &owner), (1_000, 1_000, 0));
+        assert!(capture(id, test_account(2), Nat::from(1u64)).is_err());
+        assert!(get_reservation(id).is_some());
+
+        maintenance_tick();
+        assert_eq!(get_reservation(id), None);
+        assert_eq!(state::count_expired_reservations(runtime::time(), 10), 0);
+    }
+
+    #[test]
+    fn test_reservation_cap_and_consolidation() {
+        let (token, owner) = setup(0);
+        for _ in 0..constants::MAX_RESERVATIONS_PER_ACCOUNT {
+            hold(token, 10).unwrap();
+        }
+        assert!(hold(token, 10).is_err());
+        assert_eq!(breakdown(token, &owner), (1_000, 800, 200));
+
+        // Expired holds make room without waiting for the sweep.
+        runtime::advance_time(60 * SECOND);
+        hold(token, 10).unwrap();
+        assert_eq!(state::list_account_reservations(token, owner.to_key()).len(), 1);
+
+        // Consolidation leaves held funds where they are.
+        let savings = crate::test_utils::test_subaccount(1, 7);
+        state::seed_balance(token, savings.to_key(), 50);
+        reserve(token, Some(Subaccount([7u8; 32])), Nat::from(30u64), runtime::time() + SECOND, None).unwrap();
+        let entries = consolidate_subaccounts(token, vec![Subaccount([7u8; 32])], None).unwrap();
+        assert!(matches!(&entries[0].outcome, ConsolidationOutcome::Moved { amount, .. } if *amount == 20u64));
+        assert_eq!(breakdown(token, &savings), (30, 0, 30));
+    }
+}
@@ -0,0 +1,191 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// Every proportional amount the ledger computes (the protocol's share of a
+// fee, airdrop rewards) goes through mul_div or mul_div_rem. The product
+// is taken at 256 bits, so amount * num never overflows; only a quotient that
+// does not fit in u128 fails.
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    #[default]
+    Floor,
+    Ceil,
+    // Round half to even (banker's rounding).
+    HalfEven,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Overflow;
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Proportional amount overflows")
+    }
+}
+
+
+const LOW_MASK: u128 = u64::MAX as u128;
+
+
+// (high, low) halves of the full 256-bit product.
+fn widening_mul(a: u128, b: u128) -> (u128, u128) {
+    let (a_hi, a_lo) = (a >> 64, a & LOW_MASK);
+    let (b_hi, b_lo) = (b >> 64, b & LOW_MASK);
+
+    let lo_lo = a_lo * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_lo = a_hi * b_lo;
+    let hi_hi = a_hi * b_hi;
+
+    let middle = (lo_lo >> 64) + (lo_hi & LOW_MASK) + (hi_lo & LOW_MASK);
+    let low = (lo_lo & LOW_MASK) | (middle << 64);
+    let high = hi_hi + (lo_hi >> 64) + (hi_lo >> 64) + (middle >> 64);
+    (high, low)
+}
+
+
+// Quotient and remainder of (high, low) / divisor, or None when the quotient
+// does not fit in u128.
+fn div_rem_wide(high: u128, low: u128, divisor: u128) -> Option<(u128, u128)> {
+    if high >= divisor {
+        return None;
+    }
+    if high == 0 {
+        return Some((low / divisor, low % divisor));
+    }
+
+    // Shift-subtract long division. The remainder stays below the divisor, so
+    // a bit carried out of it by the shift means it already exceeds the divisor.
+    let (mut quotient, mut remainder) = (0u128, high);
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= divisor {
+            remainder = remainder.wrapping_sub(divisor);
+            quotient |= 1;
+        }
+    }
+    Some((quotient, remainder))
+}
+
+
+// amount * num / den rounded down, and what that dropped, in 1/den units. A
+// zero denominator is reported as an overflow, as in mul_div.
+pub fn mul_div_rem(amount: u128, num: u128, den: u128) -> Result<(u128, u128), Overflow> {
+    if den == 0 {
+        return Err(Overflow);
+    }
+    let (high, low) = widening_mul(amount, num);
+    div_rem_wide(high, low, den).ok_or(Overflow)
+}
+
+
+// amount * num / den, rounded by `mode`. A zero denominator is reported as an
+// overflow: there is no amount to round to.
+pub fn mul_div(amount: u128, num: u128, den: u128, mode: RoundingMode) -> Result<u128, Overflow> {
+    let (quotient, remainder) = mul_div_rem(amount, num, den)?;
+
+    let round_up = match mode {
+        RoundingMode::Floor => false,
+        RoundingMode::Ceil => remainder > 0,
+        // remainder < den, so den - remainder cannot underflow; comparing the
+        // two halves avoids doubling the remainder.
+        RoundingMode::HalfEven => match remainder.cmp(&(den - remainder)) {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Equal => quotient % 2 == 1,
+            std::cmp::Ordering::Less => false,
+        },
+    };
+    match round_up {
+        true => quotient.checked_add(1).ok_or(Overflow),
+        false => Ok(quotient),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift64*, enough to spread inputs across the whole u128 range.
+    struct Inputs(u64);
+
+    impl Inputs {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        // Mixes small, boundary and full-width values.
+        fn next_u128(&mut self) -> u128 {
+            let wide = ((self.next_u64() as u128) << 64) | self.next_u64() as u128;
+            match self.next_u64() % 4 {
+                0 => wide % 10_001,
+                1 => u128::MAX - wide % 3,
+                2 => wide >> (self.next_u64() % 128),
+                _ => wide,
+            }
+        }
+    }
+
+    #[test]
+    fn test_mul_div_rounding() {
+        use RoundingMode::*;
+
+        assert_eq!(mul_div(7, 1, 2, Floor), Ok(3));
+        assert_eq!(mul_div(7, 1, 2, Ceil), Ok(4));
+        assert_eq!(mul_div(5, 1, 2, HalfEven), Ok(2));
+        assert_eq!(mul_div(7, 1, 2, HalfEven), Ok(4));
+        assert_eq!(mul_div(8, 1, 3, HalfEven), Ok(3));
+        assert_eq!(mul_div(6, 0, 10, Ceil), Ok(0));
+        assert_eq!(mul_div(1, 1, 0, Floor), Err(Overflow));
+
+        // The product exceeds u128 while the quotient does not.
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX, Ceil), Ok(u128::MAX));
+        assert_eq!(mul_div(u128::MAX, 10_000, 10_000, Floor), Ok(u128::MAX));
+        assert_eq!(mul_div(u128::MAX, 3, 4, Floor), Ok(u128::MAX / 4 * 3 + 2));
+        assert_eq!(mul_div(u128::MAX, 2, 1, Floor), Err(Overflow));
+        assert_eq!(mul_div(u128::MAX, 3, 2, Floor), Err(Overflow));
+
+        assert_eq!(mul_div_rem(250, 1, 100), Ok((2, 50)));
+        assert_eq!(mul_div_rem(u128::MAX, 3, 4), Ok((u128::MAX / 4 * 3 + 2, 1)));
+        assert_eq!(mul_div_rem(1, 1, 0), Err(Overflow));
+    }
+
+    #[test]
+    fn test_mul_div_properties() {
+        use RoundingMode::*;
+
+        let mut inputs = Inputs(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let amount = inputs.next_u128();
+            let den = inputs.next_u128().max(1);
+            let num = inputs.next_u128() % (den + (den < u128::MAX) as u128);
+
+            // A share of at most the whole: never overflows, and the share
+            // plus what is left is exactly the whole.
+            let floor = mul_div(amount, num, den, Floor).unwrap();
+            let ceil = mul_div(amount, num, den, Ceil).unwrap();
+            let half_even = mul_div(amount, num, den, HalfEven).unwrap();
+            for part in [floor, ceil, half_even] {
+                assert!(part <= amount);
+                assert_eq!(part + (amount - part), amount);
+            }
+            assert!(ceil - floor <= 1);
+            assert!(floor <= half_even && half_even <= ceil);
+
+            // Splitting the whole by complementary ratios, rounding both down,
+            // never hands out more than the whole.
+            let rest = mul_div(amount, den - num, den, Floor).unwrap();
+            assert!(floor.checked_add(rest).is_some_and(|sum| sum <= amount));
+            assert!(amount - floor - rest <= 1);
+        }
+    }
+}
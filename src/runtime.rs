@@ -0,0 +1,179 @@
+use candid::Principal;
+
+// Thin indirection over the system API so the ledger logic can run natively
+// (unit tests, benchmarks) where the ic0 imports are not available.
+
+#[cfg(target_arch = "wasm32")]
+pub fn time() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn canister_id() -> Principal {
+    ic_cdk::id()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn stable_pages() -> u64 {
+    ic_cdk::api::stable::stable_size()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn set_certified_data(data: &[u8; 32]) {
+    ic_cdk::api::set_certified_data(data)
+}
+
+// Only available in query calls.
+#[cfg(target_arch = "wasm32")]
+pub fn data_certificate() -> Option<Vec<u8>> {
+    ic_cdk::api::data_certificate()
+}
+
+// Instructions executed so far in the current message.
+#[cfg(target_arch = "wasm32")]
+pub fn instruction_counter() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+// Rolls back everything the message wrote.
+#[cfg(target_arch = "wasm32")]
+pub fn trap(message: &str) -> ! {
+    ic_cdk::trap(message)
+}
+
+// One-way call: only failures to enqueue are reported, never the callee's outcome.
+#[cfg(target_arch = "wasm32")]
+pub fn notify(target: Principal, method: &str, args: Vec<u8>) -> Result<(), String> {
+    ic_cdk::api::call::notify_raw(target, method, &args, 0)
+        .map_err(|code| format!("notify rejected: {:?}", code))
+}
+
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use candid::Principal;
+    use std::cell::{Cell, RefCell};
+
+    pub const DEFAULT_TIME: u64 = 1_700_000_000_000_000_000;
+
+    pub type Notification = (Principal, String, Vec<u8>);
+
+    thread_local! {
+        pub static OUTBOX: RefCell<Vec<Notification>> = const { RefCell::new(Vec::new()) };
+        pub static UNREACHABLE: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+        pub static TIME: Cell<u64> = const { Cell::new(DEFAULT_TIME) };
+        pub static STABLE_PAGES: Cell<u64> = const { Cell::new(0) };
+        pub static CALLER: RefCell<Principal> = const { RefCell::new(Principal::from_slice(&[0x01; 10])) };
+        pub static CANISTER_ID: RefCell<Principal> = const { RefCell::new(Principal::from_slice(&[0xAB; 10])) };
+        pub static CERTIFIED_DATA: Cell<[u8; 32]> = const { Cell::new([0; 32]) };
+        pub static DATA_CERTIFICATE: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn time() -> u64 {
+    native::TIME.with(|t| t.get())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn caller() -> Principal {
+    native::CALLER.with(|c| *c.borrow())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn canister_id() -> Principal {
+    native::CANISTER_ID.with(|c| *c.borrow())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn stable_pages() -> u64 {
+    native::STABLE_PAGES.with(|p| p.get())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_stable_pages(pages: u64) {
+    native::STABLE_PAGES.with(|p| p.set(pages));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_time(now: u64) {
+    native::TIME.with(|t| t.set(now));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn advance_time(delta: u64) {
+    native::TIME.with(|t| t.set(t.get() + delta));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_caller(p: Principal) {
+    native::CALLER.with(|c| *c.borrow_mut() = p);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_canister_id(p: Principal) {
+    native::CANISTER_ID.with(|c| *c.borrow_mut() = p);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_certified_data(data: &[u8; 32]) {
+    native::CERTIFIED_DATA.with(|c| c.set(*data));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn certified_data() -> [u8; 32] {
+    native::CERTIFIED_DATA.with(|c| c.get())
+}
+
+// Natively no replica signs anything; tests install a certificate of their own.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn data_certificate() -> Option<Vec<u8>> {
+    native::DATA_CERTIFICATE.with(|c| c.borrow().clone())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_data_certificate(certificate: Option<Vec<u8>>) {
+    native::DATA_CERTIFICATE.with(|c| *c.borrow_mut() = certificate);
+}
+
+// Natively nothing is counted; budgets fall back on their own estimates.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn instruction_counter() -> u64 {
+    0
+}
+
+// Natively nothing is rolled back; the panic carries the message.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn trap(message: &str) -> ! {
+    panic!("{}", message)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn notify(target: Principal, method: &str, args: Vec<u8>) -> Result<(), String> {
+    if native::UNREACHABLE.with(|u| u.borrow().contains(&target)) {
+        return Err("notify rejected: destination unreachable".to_string());
+    }
+    native::OUTBOX.with(|o| o.borrow_mut().push((target, method.to_string(), args)));
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn take_notifications() -> Vec<native::Notification> {
+    native::OUTBOX.with(|o| std::mem::take(&mut *o.borrow_mut()))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_unreachable(target: Principal, unreachable: bool) {
+    native::UNREACHABLE.with(|u| {
+        let mut list = u.borrow_mut();
+        list.retain(|p| *p != target);
+        if unreachable {
+            list.push(target);
+        }
+    });
+}
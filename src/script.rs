@@ -0,0 +1,347 @@
+use crate::allowances::{approve_checked, ApproveError, Icrc151ApproveArgs};
+use crate::context::OperationContext;
+use crate::operations::{burn_with_args, generic_error, mint_with_args, transfer_checked, Icrc151TransferArgs, TransferError};
+use crate::runtime;
+use crate::state;
+use crate::transaction::{OP_APPROVE, OP_BURN, OP_MINT, OP_TRANSFER};
+use crate::types::{Account, Memo, OpKind, TokenId};
+use crate::validation::{check_args_version, validate_account, validate_approve_params, validate_transfer_params};
+use candid::CandidType;
+use num_traits::cast::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+
+// A short, fixed sequence of the caller's own operations in one message, so
+// "approve then transfer" or "burn then mint elsewhere" needs no second round
+// trip. No loops and no conditionals: the cost is bounded by MAX_SCRIPT_OPS
+// operations. Each operation is handled as its own endpoint would handle it,
+// including authorization, and is deduplicated by its position as well.
+//
+// Atomic scripts check every operation up front (arguments, gates, minter
+// rights, duplicates) and run none when one fails. Checks that depend on
+// earlier operations, such as balances, are only made as each one runs; a
+// failure there traps, which rolls back the whole message.
+
+
+pub const MAX_SCRIPT_OPS: usize = 10;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ScriptOp {
+    Transfer(Icrc151TransferArgs),
+    Approve(Icrc151ApproveArgs),
+    // From the caller's default account.
+    Burn { token_id: TokenId, amount: candid::Nat, memo: Option<Memo> },
+    // Only for callers that may mint the token, as mint_tokens.
+    Mint { token_id: TokenId, to: Account, amount: candid::Nat, memo: Option<Memo> },
+}
+
+
+// The error each operation's own endpoint returns.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ScriptOpError {
+    Transfer(TransferError),
+    Approve(ApproveError),
+    Burn(TransferError),
+    Mint(TransferError),
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ScriptOpResult {
+    Ok(u64),
+    Err(ScriptOpError),
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ScriptError {
+    // Nothing ran because of the script as a whole.
+    Rejected(String),
+    // Atomic scripts only: nothing ran because of this operation.
+    InvalidOp { op_index: u32, error: ScriptOpError },
+}
+
+
+// Results are in operation order. Without `atomic` every operation runs and a
+// failing one changes nothing, as in batch_transfer.
+#[ic_cdk::update]
+pub fn execute_script(ops: Vec<ScriptOp>, atomic: bool) -> Result<Vec<ScriptOpResult>, ScriptError> {
+    if ops.is_empty() {
+        return Err(ScriptError::Rejected("At least one operation is required".to_string()));
+    }
+    if ops.len() > MAX_SCRIPT_OPS {
+        return Err(ScriptError::Rejected(format!("Too many operations (max {})", MAX_SCRIPT_OPS)));
+    }
+
+    if atomic {
+        for (position, op) in ops.iter().enumerate() {
+            let position = position as u32;
+            check_op(op, position).map_err(|error| ScriptError::InvalidOp { op_index: position, error })?;
+        }
+    }
+
+    let mut results = Vec::with_capacity(ops.len());
+    for (position, op) in ops.into_iter().enumerate() {
+        let position = position as u32;
+        match run_op(op, position) {
+            Ok(tx_index) => results.push(ScriptOpResult::Ok(tx_index)),
+            Err(error) if atomic => runtime::trap(&format!("Script operation {} failed: {:?}", position, error)),
+            Err(error) => results.push(ScriptOpResult::Err(error)),
+        }
+    }
+    Ok(results)
+}
+
+
+fn run_op(op: ScriptOp, position: u32) -> Result<u64, ScriptOpError> {
+    let position = Some(position);
+    match op {
+        ScriptOp::Transfer(args) => transfer_checked(args, position).map_err(ScriptOpError::Transfer),
+        ScriptOp::Approve(args) => approve_checked(args, position).map_err(ScriptOpError::Approve),
+        ScriptOp::Burn { token_id, amount, memo } => {
+            burn_with_args(token_id, amount, memo, None, None, None, position).map_err(ScriptOpError::Burn)
+        }
+        ScriptOp::Mint { token_id, to, amount, memo } => {
+            mint_with_args(token_id, to, amount, memo, position).map_err(ScriptOpError::Mint)
+        }
+    }
+}
+
+
+// Everything about `op` that does not depend on the operations before it.
+fn check_op(op: &ScriptOp, position: u32) -> Result<(), ScriptOpError> {
+    match op {
+        ScriptOp::Transfer(args) => check_transfer(args, position).map_err(ScriptOpError::Transfer),
+        ScriptOp::Approve(args) => check_approve(args, position).map_err(ScriptOpError::Approve),
+        ScriptOp::Burn { token_id, amount, memo } => {
+            check_burn(*token_id, amount, memo.as_deref(), position).map_err(ScriptOpError::Burn)
+        }
+        ScriptOp::Mint { token_id, to, amount, memo } => {
+            check_mint(*token_id, to, amount, memo.as_deref(), position).map_err(ScriptOpError::Mint)
+        }
+    }
+}
+
+
+fn to_u128(value: &candid::Nat, what: &str) -> Result<u128, String> {
+    value.0.to_u128().ok_or_else(|| format!("{} exceeds maximum value (u128::MAX)", what))
+}
+
+
+fn bad_request(message: String) -> TransferError {
+    TransferError::GenericError { error_code: candid::Nat::from(400u64), message }
+}
+
+
+fn check_transfer(args: &Icrc151TransferArgs, position: u32) -> Result<(), TransferError> {
+    check_args_version(args.args_version).map_err(|supported| TransferError::UnsupportedArgsVersion { supported })?;
+    let amount = to_u128(&args.amount, "Amount").map_err(bad_request)?;
+    let fee = args.fee.as_ref().map(|f| to_u128(f, "Fee")).transpose().map_err(bad_request)?;
+    let ctx = OperationContext::new(OpKind::Transfer, args.token_id, runtime::caller(), args.created_at_time)?
+        .in_script(Some(position));
+    let from = Account { owner: ctx.caller, subaccount: args.from_subaccount.map(|s| s.to_vec()) };
+    let memo = args.memo.as_deref();
+    let fee = ctx.standard_fee(fee)?;
+    validate_transfer_params(&from, &args.to, amount, Some(fee), memo)?;
+    crate::memo::validate_structured_memo(&ctx.metadata, memo).map_err(bad_request)?;
+
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(
+        OP_TRANSFER, ctx.caller, ctx.token_id, ctx.timestamp, memo, Some(args.to.to_key()), amount,
+    ));
+    match state::check_duplicate(dedup_key) {
+        Some(duplicate_of) => Err(TransferError::Duplicate { duplicate_of }),
+        None => Ok(()),
+    }
+}
+
+
+fn check_approve(args: &Icrc151ApproveArgs, position: u32) -> Result<(), ApproveError> {
+    let bad_request = |message| ApproveError::GenericError { error_code: candid::Nat::from(400u64), message };
+    check_args_version(args.args_version).map_err(|supported| ApproveError::UnsupportedArgsVersion { supported })?;
+    let amount = to_u128(&args.amount, "Amount").map_err(bad_request)?;
+    let fee = args.fee.as_ref().map(|f| to_u128(f, "Fee")).transpose().map_err(bad_request)?;
+    if let Some(expected) = &args.expected_allowance {
+        to_u128(expected, "Expected allowance").map_err(bad_request)?;
+    }
+    let ctx = OperationContext::new(OpKind::Approve, args.token_id, runtime::caller(), args.created_at_time)?
+        .in_script(Some(position));
+    let owner = Account { owner: ctx.caller, subaccount: args.from_subaccount.map(|s| s.to_vec()) };
+    let memo = args.memo.as_deref();
+    let fee = ctx.standard_fee(fee)?;
+    validate_approve_params(&owner, &args.spender, amount, Some(fee), memo)?;
+
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(
+        OP_APPROVE, ctx.caller, ctx.token_id, ctx.timestamp, memo, Some(args.spender.to_key()), amount,
+    ));
+    if let Some(duplicate_of) = state::check_duplicate(dedup_key) {
+        return Err(ApproveError::Duplicate { duplicate_of });
+    }
+    match args.expires_at {
+        Some(expires_at) if expires_at <= ctx.timestamp => Err(ApproveError::Expired { ledger_time: ctx.timestamp }),
+        _ => Ok(()),
+    }
+}
+
+
+fn check_burn(token_id: TokenId, amount: &candid::Nat, memo: Option<&[u8]>, position: u32) -> Result<(), TransferError> {
+    let ctx = OperationContext::new(OpKind::Burn, token_id, runtime::caller(), None)?.in_script(Some(position));
+    let amount = to_u128(amount, "Amount").map_err(|message| generic_error(400, message))?;
+    if amount == 0 {
+        return Err(TransferError::BadBurn { min_burn_amount: candid::Nat::from(1u64) });
+    }
+
+    let from = Account { owner: ctx.caller, subaccount: None };
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(OP_BURN, from.owner, token_id, ctx.timestamp, memo, None, amount));
+    match state::check_duplicate(dedup_key) {
+        Some(duplicate_of) => Err(TransferError::Duplicate { duplicate_of }),
+        None => Ok(()),
+    }
+}
+
+
+fn check_mint(token_id: TokenId, to: &Account, amount: &candid::Nat, memo: Option<&[u8]>, position: u32) -> Result<(), TransferError> {
+    crate::api_access::require_minter(token_id).map_err(|message| generic_error(403, message))?;
+    let ctx = OperationContext::privileged(OpKind::Mint, token_id, runtime::caller(), None)?.in_script(Some(position));
+    let amount = to_u128(amount, "Amount").map_err(|message| generic_error(400, message))?;
+    validate_account(to)?;
+    if amount == 0 {
+        return Err(generic_error(400, "Amount must be greater than 0"));
+    }
+    if ctx.metadata.is_immutable() {
+        return Err(state::MetadataError::TokenImmutable.into());
+    }
+
+    let dedup_key = ctx.dedup_key(state::compute_dedup_key(
+        OP_MINT, to.owner, token_id, ctx.timestamp, memo, Some(to.to_key()), amount,
+    ));
+    match state::check_duplicate(dedup_key) {
+        Some(duplicate_of) => Err(TransferError::Duplicate { duplicate_of }),
+        None => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{fee_recipient, register_test_token, test_account};
+    use candid::Nat;
+
+    fn transfer_op(token_id: TokenId, to: &Account, amount: u64) -> ScriptOp {
+        ScriptOp::Transfer(Icrc151TransferArgs {
+            token_id,
+            from_subaccount: None,
+            to: to.clone(),
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+            private_memo: None,
+            amount_mode: None,
+            use_sponsorship: None,
+            args_version: None,
+        })
+    }
+
+    fn approve_op(token_id: TokenId, spender: &Account, amount: u64) -> ScriptOp {
+        ScriptOp::Approve(Icrc151ApproveArgs {
+            token_id,
+            spender: spender.clone(),
+            amount: Nat::from(amount),
+            expires_at: None,
+            expected_allowance: None,
+            memo: None,
+            fee: None,
+            from_subaccount: None,
+            created_at_time: None,
+            private_memo: None,
+            args_version: None,
+        })
+    }
+
+    fn mint_op(token_id: TokenId, to: &Account, amount: u64) -> ScriptOp {
+        ScriptOp::Mint { token_id, to: to.clone(), amount: Nat::from(amount), memo: None }
+    }
+
+    #[test]
+    fn test_non_atomic_script_reports_each_operation() {
+        state::init_state(fee_recipient().owner);
+        let token = register_test_token(1, 10);
+        let (alice, bob, spender) = (test_account(1), test_account(2), test_account(3));
+        state::seed_balance(token, alice.to_key(), 1_000);
+
+        // The same transfer twice is two transfers: the position is in the key.
+        runtime::set_caller(alice.owner);
+        let ops = vec![
+            transfer_op(token, &bob, 100),
+            transfer_op(token, &bob, 100),
+            transfer_op(token, &bob, 10_000),
+            approve_op(token, &spender, 50),
+            mint_op(token, &alice, 5),
+        ];
+        let results = execute_script(ops.clone(), false).unwrap();
+        assert!(matches!(results[0], ScriptOpResult::Ok(_)));
+        assert!(matches!(results[1], ScriptOpResult::Ok(_)));
+        assert!(matches!(results[2], ScriptOpResult::Err(ScriptOpError::Transfer(TransferError::InsufficientFunds { .. }))));
+        assert!(matches!(results[3], ScriptOpResult::Ok(_)));
+        assert!(matches!(&results[4], ScriptOpResult::Err(ScriptOpError::Mint(TransferError::GenericError { error_code, .. })) if *error_code == 403u64));
+        assert_eq!(state::get_balance(token, bob.to_key()), 200);
+        assert_eq!(state::get_balance(token, alice.to_key()), 1_000 - 200 - 30);
+        assert_eq!(state::get_allowance(token, alice.to_key(), spender.to_key()), 50);
+
+        // Replaying the script finds each applied operation at its position.
+        let replay = execute_script(ops, false).unwrap();
+        assert!(matches!(replay[0], ScriptOpResult::Err(ScriptOpError::Transfer(TransferError::Duplicate { .. }))));
+        assert!(matches!(replay[1], ScriptOpResult::Err(ScriptOpError::Transfer(TransferError::Duplicate { .. }))));
+        assert!(matches!(replay[3], ScriptOpResult::Err(ScriptOpError::Approve(ApproveError::Duplicate { .. }))));
+        assert_eq!(state::get_balance(token, bob.to_key()), 200);
+    }
+
+    #[test]
+    fn test_atomic_script_checks_every_operation_first() {
+        let controller = fee_recipient().owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        let (alice, bob) = (test_account(1), test_account(2));
+        state::seed_balance(token, alice.to_key(), 1_000);
+
+        // Alice may not mint, so her transfer does not run either.
+        runtime::set_caller(alice.owner);
+        let result = execute_script(vec![transfer_op(token, &bob, 100), mint_op(token, &alice, 5)], true);
+        assert!(matches!(result, Err(ScriptError::InvalidOp { op_index: 1, error: ScriptOpError::Mint(_) })));
+        assert_eq!(state::get_balance(token, bob.to_key()), 0);
+        assert_eq!(state::get_transaction_count(), 0);
+
+        // A controller may burn then mint to another subaccount in one go.
+        runtime::set_caller(controller);
+        crate::operations::mint_tokens(token, Account { owner: controller, subaccount: None }, Nat::from(500u64), None).unwrap();
+        let savings = Account { owner: controller, subaccount: Some(vec![7; 32]) };
+        let burn = ScriptOp::Burn { token_id: token, amount: Nat::from(200u64), memo: None };
+        let results = execute_script(vec![burn.clone(), mint_op(token, &savings, 200)], true).unwrap();
+        assert!(results.iter().all(|r| matches!(r, ScriptOpResult::Ok(_))));
+        assert_eq!(state::get_balance(token, savings.to_key()), 200);
+
+        // A retried atomic script is refused at its first operation.
+        let retry = execute_script(vec![burn, mint_op(token, &savings, 200)], true);
+        assert!(matches!(retry, Err(ScriptError::InvalidOp { op_index: 0, error: ScriptOpError::Burn(_) })));
+
+        assert!(matches!(execute_script(vec![], true), Err(ScriptError::Rejected(_))));
+        let too_many = vec![transfer_op(token, &bob, 1); MAX_SCRIPT_OPS + 1];
+        assert!(matches!(execute_script(too_many, false), Err(ScriptError::Rejected(_))));
+    }
+
+    // Natively the trap is a panic; in a canister it also discards the
+    // transfer that ran before.
+    #[test]
+    #[should_panic(expected = "Script operation 1 failed")]
+    fn test_atomic_script_traps_when_a_later_operation_fails() {
+        state::init_state(fee_recipient().owner);
+        let token = register_test_token(1, 0);
+        let (alice, bob) = (test_account(1), test_account(2));
+        state::seed_balance(token, alice.to_key(), 150);
+
+        runtime::set_caller(alice.owner);
+        let _ = execute_script(vec![transfer_op(token, &bob, 100), transfer_op(token, &bob, 100)], true);
+    }
+}
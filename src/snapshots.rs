@@ -0,0 +1,223 @@
+use crate::allowances::hex_encode;
+use crate::privacy::require_token_privileged_reader;
+use crate::queries::{Holder, QueryError};
+use crate::state;
+use crate::types::{BalanceSnapshot, TokenId};
+use crate::validation::validate_token_id;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+
+// Frozen copies of one token's balances, for distributions that must not
+// depend on who moved funds after the cut-off (see airdrops.rs). A snapshot is
+// fixed the moment it is opened; its holders are then copied in chunks, and a
+// balance that changes before the copy reaches it is saved as it was first.
+// Snapshots are kept for audit; they are never deleted.
+
+
+// Each open snapshot adds a check to every balance change of its token.
+pub const MAX_OPEN_SNAPSHOTS: usize = 4;
+pub const DEFAULT_SNAPSHOT_CHUNK: u32 = 1_000;
+pub const MAX_SNAPSHOT_CHUNK: u32 = 5_000;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotBalancesPage {
+    pub holders: Vec<Holder>,
+    pub next_cursor: Option<Vec<u8>>,
+}
+
+
+#[ic_cdk::update]
+pub fn open_balance_snapshot(token_id: TokenId) -> Result<BalanceSnapshot, String> {
+    state::require_controller()?;
+    validate_token_id(&token_id).map_err(|e| e.to_string())?;
+    if state::is_import_pending(token_id) {
+        return Err("The token's import is not finalized yet".to_string());
+    }
+    if state::count_open_snapshots() >= MAX_OPEN_SNAPSHOTS {
+        return Err(format!("At most {} snapshots can be open at once; continue one to completion first", MAX_OPEN_SNAPSHOTS));
+    }
+    let snapshot = state::open_balance_snapshot(token_id).map_err(|e| e.to_string())?;
+    state::record_admin_action("open_balance_snapshot", format!("{}: snapshot {}", hex_encode(&token_id), snapshot.id));
+    Ok(snapshot)
+}
+
+
+// Copies the next `limit` holders. Calling it on a complete snapshot changes
+// nothing.
+#[ic_cdk::update]
+pub fn continue_balance_snapshot(snapshot_id: u64, limit: Option<u32>) -> Result<BalanceSnapshot, String> {
+    state::require_controller()?;
+    let limit = limit.unwrap_or(DEFAULT_SNAPSHOT_CHUNK).clamp(1, MAX_SNAPSHOT_CHUNK) as usize;
+    state::copy_snapshot_chunk(snapshot_id, limit).ok_or_else(|| format!("No balance snapshot {}", snapshot_id))
+}
+
+
+#[ic_cdk::query]
+pub fn get_balance_snapshot(snapshot_id: u64) -> Option<BalanceSnapshot> {
+    state::get_balance_snapshot(snapshot_id)
+}
+
+
+// Holders of a complete snapshot in account key order, readable by whoever
+// may list the token's holders.
+#[ic_cdk::query]
+pub fn list_snapshot_balances(
+    snapshot_id: u64,
+    start_after: Option<Vec<u8>>,
+    limit: Option<u64>,
+) -> Result<SnapshotBalancesPage, QueryError> {
+    let snapshot = state::get_balance_snapshot(snapshot_id)
+        .ok_or_else(|| QueryError::InvalidInput(format!("No balance snapshot {}", snapshot_id)))?;
+    require_token_privileged_reader(snapshot.token_id)?;
+    if !snapshot.complete {
+        return Err(QueryError::InvalidInput("The snapshot is still being copied".to_string()));
+    }
+    let start_after = match start_after {
+        Some(bytes) => Some(<[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| QueryError::InvalidInput("Cursor must be 32 bytes".to_string()))?),
+        None => None,
+    };
+
+    const DEFAULT_LIMIT: u64 = 100;
+    const MAX_LIMIT: u64 = 1000;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+
+    let entries = state::list_snapshot_balances(snapshot_id, start_after, limit);
+    let next_cursor = match entries.len() == limit {
+        true => entries.last().map(|(key, _)| key.to_vec()),
+        false => None,
+    };
+    Ok(SnapshotBalancesPage {
+        holders: entries
+            .into_iter()
+            .filter(|(_, balance)| *balance > 0)
+            .map(|(key, balance)| Holder {
+                account_key: key.to_vec(),
+                account: state::get_registered_account(key),
+                balance: candid::Nat::from(balance),
+            })
+            .collect(),
+        next_cursor,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime;
+    use crate::test_utils::{register_test_token, test_account, test_subaccount};
+    use crate::types::{Account, AccountKey};
+    use std::collections::BTreeMap;
+
+    fn holder(i: u32) -> Account {
+        test_subaccount((i / 256) as u8, (i % 256) as u8)
+    }
+
+    fn mint(token: TokenId, credits: &[(AccountKey, u128)]) {
+        let total = credits.iter().map(|(_, amount)| amount).sum::<u128>();
+        state::apply_movements(token, &[], credits, total as i128).unwrap();
+    }
+
+    fn move_funds(token: TokenId, from: AccountKey, to: AccountKey, amount: u128) {
+        state::apply_movements(token, &[(from, amount)], &[(to, amount)], 0).unwrap();
+    }
+
+    fn held(snapshot_id: u64) -> BTreeMap<AccountKey, u128> {
+        state::list_snapshot_balances(snapshot_id, None, usize::MAX).into_iter().filter(|(_, b)| *b > 0).collect()
+    }
+
+    #[test]
+    fn test_snapshot_keeps_balances_as_opened() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        let (a, b, c, d) = (test_account(1).to_key(), test_account(2).to_key(), test_account(3).to_key(), test_account(4).to_key());
+        mint(token, &[(a, 100), (b, 200), (c, 300)]);
+        let opened: BTreeMap<_, _> = [(a, 100), (b, 200), (c, 300)].into();
+
+        let snapshot = open_balance_snapshot(token).unwrap();
+        assert_eq!((snapshot.holders, snapshot.total_supply, snapshot.complete), (3, 600, false));
+        assert!(list_snapshot_balances(snapshot.id, None, None).is_err());
+
+        // Moves before and between chunks, including a holder that leaves and
+        // one that arrives, and an account moving twice.
+        move_funds(token, a, d, 100);
+        for _ in 0..3 {
+            let progress = continue_balance_snapshot(snapshot.id, Some(1)).unwrap();
+            move_funds(token, c, b, 10);
+            move_funds(token, b, d, 5);
+            if progress.complete {
+                break;
+            }
+        }
+        let done = continue_balance_snapshot(snapshot.id, Some(1)).unwrap();
+        assert!(done.complete);
+        assert_eq!(held(snapshot.id), opened);
+        assert_eq!(held(snapshot.id).values().sum::<u128>(), done.total_supply);
+
+        // Complete snapshots no longer track changes.
+        move_funds(token, b, a, 1);
+        assert_eq!(held(snapshot.id), opened);
+        assert_eq!(state::count_open_snapshots(), 0);
+        let page = list_snapshot_balances(snapshot.id, None, Some(2)).unwrap();
+        assert_eq!(page.holders.len(), 2);
+        let rest = list_snapshot_balances(snapshot.id, page.next_cursor, None).unwrap();
+        assert_eq!(page.holders.len() + rest.holders.len(), 3);
+        assert_eq!(rest.next_cursor, None);
+    }
+
+    #[test]
+    fn test_snapshot_of_thousands_of_holders_under_load() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 0);
+        let credits: Vec<(AccountKey, u128)> = (0..3_000).map(|i| (holder(i).to_key(), 1_000 + i as u128)).collect();
+        for chunk in credits.chunks(500) {
+            mint(token, chunk);
+        }
+        let opened: BTreeMap<_, _> = credits.iter().copied().collect();
+
+        let snapshot = open_balance_snapshot(token).unwrap();
+        let mut round = 0u32;
+        while !continue_balance_snapshot(snapshot.id, Some(700)).unwrap().complete {
+            // Drain some holders, top up others and bring in newcomers.
+            for i in 0..50 {
+                let from = holder((round * 331 + i * 59) % 3_000).to_key();
+                let to = holder(3_000 + round * 50 + i).to_key();
+                let balance = state::get_balance(token, from);
+                move_funds(token, from, to, balance);
+                move_funds(token, to, holder((i * 97) % 3_000).to_key(), 1);
+            }
+            round += 1;
+        }
+        assert!(round >= 4);
+        let taken = held(snapshot.id);
+        assert_eq!(taken, opened);
+        assert_eq!(taken.values().sum::<u128>(), snapshot.total_supply);
+    }
+
+    #[test]
+    fn test_snapshots_are_controller_only_and_bounded() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 0);
+        runtime::set_caller(test_account(1).owner);
+        assert!(open_balance_snapshot(token).is_err());
+        assert!(continue_balance_snapshot(0, None).is_err());
+
+        runtime::set_caller(controller);
+        assert!(open_balance_snapshot([9; 32]).is_err());
+        for _ in 0..MAX_OPEN_SNAPSHOTS {
+            open_balance_snapshot(token).unwrap();
+        }
+        assert!(open_balance_snapshot(token).is_err());
+        assert!(continue_balance_snapshot(0, None).unwrap().complete);
+        assert_eq!(open_balance_snapshot(token).unwrap().id, MAX_OPEN_SNAPSHOTS as u64);
+        assert!(continue_balance_snapshot(99, None).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+use crate::context::OperationContext;
+use crate::operations::TransferError;
+use crate::privacy::{require_account_reader, require_token_account_reader};
+use crate::queries::QueryError;
+use crate::runtime;
+use crate::state;
+use crate::types::{constants, Account, AccountKey, OpKind, Sponsorship, TokenId};
+use crate::validation::{validate_account, validate_token_id};
+use num_traits::cast::ToPrimitive;
+
+
+// Fee sponsorships let a dapp pay the transfer fees of a new user who holds
+// nothing but the tokens they were sent. The sponsor sets a budget aside from
+// their default account; like a reservation it stays in their balance but no
+// debit may touch it. A beneficiary's transfer that asks for it has the fee
+// drawn from the oldest live sponsorship whose budget covers the whole fee,
+// and the block names the sponsor as the fee's payer. Only fees charged in the
+// token itself can be sponsored. Expired sponsorships pay nothing; their
+// unspent budget is returned when the sponsor revokes them or the
+// maintenance sweep ends them.
+
+
+pub const DEFAULT_SPONSORSHIP_PAGE: u64 = 100;
+pub const MAX_SPONSORSHIP_PAGE: u64 = 500;
+
+
+fn rejected(message: impl Into<String>) -> TransferError {
+    TransferError::GenericError { error_code: candid::Nat::from(400u64), message: message.into() }
+}
+
+
+fn require_fee_in_token(ctx: &OperationContext) -> Result<(), TransferError> {
+    if !ctx.fee_in_token() {
+        return Err(rejected("Only fees charged in the token itself can be sponsored"));
+    }
+    Ok(())
+}
+
+
+// The budget has to cover at least one fee at today's rate; a later fee
+// increase can still leave it short, and then it simply stops covering.
+#[ic_cdk::update]
+pub fn sponsor_fees(
+    token_id: TokenId,
+    beneficiary: Account,
+    budget: candid::Nat,
+    expires_at: Option<u64>,
+) -> Result<u64, TransferError> {
+    let ctx = OperationContext::new(OpKind::Transfer, token_id, runtime::caller(), None)?;
+    require_fee_in_token(&ctx)?;
+    validate_account(&beneficiary)?;
+    let sponsor = Account { owner: ctx.caller, subaccount: None };
+    let (sponsor_key, beneficiary_key) = (sponsor.to_key(), beneficiary.to_key());
+    if sponsor_key == beneficiary_key {
+        return Err(rejected("An account cannot sponsor its own fees"));
+    }
+    let budget = budget.0.to_u128().ok_or_else(|| rejected("Budget exceeds maximum value (u128::MAX)"))?;
+    if budget == 0 || budget < ctx.metadata.fee {
+        return Err(rejected(format!("Budget must cover at least one fee of {}", ctx.metadata.fee)));
+    }
+    let now = ctx.timestamp;
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(rejected("Expiry must be in the future"));
+    }
+
+    let mut sponsorships = state::list_beneficiary_sponsorships(token_id, beneficiary_key);
+    for expired in sponsorships.iter().filter(|s| s.is_expired(now)) {
+        state::remove_sponsorship(expired.id);
+    }
+    sponsorships.retain(|s| !s.is_expired(now));
+    if sponsorships.len() >= constants::MAX_SPONSORSHIPS_PER_BENEFICIARY {
+        return Err(rejected(format!(
+            "At most {} sponsorships per beneficiary and token",
+            constants::MAX_SPONSORSHIPS_PER_BENEFICIARY
+        )));
+    }
+    let available = state::get_spendable_balance(token_id, sponsor_key);
+    if available < budget {
+        return Err(TransferError::InsufficientFunds { balance: candid::Nat::from(available) });
+    }
+
+    let id = state::next_sponsorship_id();
+    state::insert_sponsorship(Sponsorship {
+        id,
+        token_id,
+        sponsor,
+        beneficiary,
+        budget,
+        spent: 0,
+        created_at: now,
+        expires_at,
+    });
+    Ok(id)
+}
+
+
+// Ends the sponsorship, expired or not, and returns the unspent budget it
+// released back to the sponsor.
+#[ic_cdk::update]
+pub fn revoke_sponsorship(sponsorship_id: u64) -> Result<candid::Nat, TransferError> {
+    let sponsorship = state::get_sponsorship(sponsorship_id).ok_or_else(|| rejected("No such sponsorship"))?;
+    if sponsorship.sponsor.owner != runtime::caller() {
+        return Err(TransferError::GenericError {
+            error_code: candid::Nat::from(403u64),
+            message: "Only the sponsor can revoke a sponsorship".to_string(),
+        });
+    }
+    state::remove_sponsorship(sponsorship_id);
+    Ok(candid::Nat::from(sponsorship.budget))
+}
+
+
+// The sponsorship that pays `fee` for a transfer from `beneficiary_key`, or
+// None when there is no fee to pay. The fee is the one the transfer already
+// resolved, so a pinned fee that does not match is a BadFee before this runs.
+pub(crate) fn find_cover(
+    ctx: &OperationContext,
+    beneficiary_key: AccountKey,
+    fee: u128,
+) -> Result<Option<Sponsorship>, TransferError> {
+    require_fee_in_token(ctx)?;
+    if fee == 0 {
+        return Ok(None);
+    }
+    let now = runtime::time();
+    state::list_beneficiary_sponsorships(ctx.token_id, beneficiary_key)
+        .into_iter()
+        .find(|s| !s.is_expired(now) && s.budget >= fee && state::get_balance(ctx.token_id, s.sponsor.to_key()) >= fee)
+        .map(Some)
+        .ok_or_else(|| TransferError::GenericError {
+            error_code: candid::Nat::from(409u64),
+            message: format!("No live sponsorship covers the fee of {}", fee),
+        })
+}
+
+
+// Visible to the sponsor's and the beneficiary's owners and controllers.
+#[ic_cdk::query]
+pub fn get_sponsorship(sponsorship_id: u64) -> Option<Sponsorship> {
+    let sponsorship = state::get_sponsorship(sponsorship_id)?;
+    let caller = runtime::caller();
+    let visible = sponsorship.sponsor.owner == caller
+        || sponsorship.beneficiary.owner == caller
+        || state::is_controller(&caller);
+    visible.then_some(sponsorship)
+}
+
+
+// The live sponsorships that can pay the beneficiary's fees on the token,
+// oldest (and first drawn) first.
+#[ic_cdk::query]
+pub fn list_sponsorships_received(token_id: TokenId, beneficiary: Account) -> Result<Vec<Sponsorship>, QueryError> {
+    validate_token_id(&token_id)?;
+    validate_account(&beneficiary)?;
+    require_token_account_reader(token_id, &beneficiary)?;
+
+    let now = runtime::time();
+    Ok(state::list_beneficiary_sponsorships(token_id, beneficiary.to_key())
+        .into_iter()
+        .filter(|s| !s.is_expired(now))
+        .collect())
+}
+
+
+// Everything the sponsor has granted across tokens, expired ones included
+// until revoked or swept, in id order after `start_after`.
+#[ic_cdk::query]
+pub fn list_sponsorships_granted(
+    sponsor: Account,
+    start_after: Option<u64>,
+    limit: Option<u64>,
+) -> Result<Vec<Sponsorship>, QueryError> {
+    validate_account(&sponsor)?;
+    require_account_reader(&sponsor)?;
+
+    let limit = limit.unwrap_or(DEFAULT_SPONSORSHIP_PAGE).clamp(1, MAX_SPONSORSHIP_PAGE) as usize;
+    Ok(state::list_sponsor_sponsorships(sponsor.to_key(), start_after, limit))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintenance::maintenance_tick;
+    use crate::operations::{mint_tokens, transfer, Icrc151TransferArgs, TransferResult};
+    use crate::reservations::get_balance_breakdown;
+    use crate::test_utils::{register_test_token, test_account};
+    use candid::Nat;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    // A token with a fee of 10, a sponsor holding 1_000 and a beneficiary
+    // holding 100; the caller is the sponsor.
+    fn setup() -> (TokenId, Account, Account) {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 10);
+        let (sponsor, beneficiary) = (test_account(1), test_account(2));
+        runtime::set_caller(controller);
+        mint_tokens(token, sponsor.clone(), Nat::from(1_000u64), None).unwrap();
+        mint_tokens(token, beneficiary.clone(), Nat::from(100u64), None).unwrap();
+        runtime::set_caller(sponsor.owner);
+        (token, sponsor, beneficiary)
+    }
+
+    fn send(token: TokenId, amount: u64, fee: Option<u64>, use_sponsorship: bool) -> TransferResult {
+        runtime::advance_time(1);
+        transfer(Icrc151TransferArgs {
+            token_id: token,
+            from_subaccount: None,
+            to: test_account(3),
+            amount: Nat::from(amount),
+            fee: fee.map(Nat::from),
+            memo: None,
+            created_at_time: None,
+            private_memo: None,
+            amount_mode: None,
+            use_sponsorship: Some(use_sponsorship),
+            args_version: None,
+        })
+    }
+
+    fn available(token: TokenId, account: &Account) -> (u128, u128) {
+        let b = get_balance_breakdown(token, account.clone()).unwrap();
+        (b.total, b.available)
+    }
+
+    #[test]
+    fn test_sponsored_transfer_draws_the_fee_from_the_budget() {
+        let (token, sponsor, beneficiary) = setup();
+        let id = sponsor_fees(token, beneficiary.clone(), Nat::from(25u64), None).unwrap();
+        assert_eq!(available(token, &sponsor), (1_000, 975));
+
+        // The beneficiary can send everything they hold; the fee is the sponsor's.
+        runtime::set_caller(beneficiary.owner);
+        let TransferResult::Ok(tx_index) = send(token, 100, Some(10), true) else { panic!("sponsored transfer failed") };
+        let tx = state::get_transaction(tx_index).unwrap();
+        assert_eq!((tx.get_fee(), tx.get_fee_sponsor(), tx.fee_payer()), (10, Some(sponsor.to_key()), sponsor.to_key()));
+        assert!(tx.balance_accounts(&[]).contains(&sponsor.to_key()));
+        assert_eq!(state::get_balance(token, beneficiary.to_key()), 0);
+        assert_eq!(available(token, &sponsor), (990, 975));
+        let sponsorship = get_sponsorship(id).unwrap();
+        assert_eq!((sponsorship.budget, sponsorship.spent), (15, 10));
+
+        // A pinned fee that does not match is still a BadFee.
+        runtime::set_caller(test_account(0xFE).owner);
+        mint_tokens(token, beneficiary.clone(), Nat::from(100u64), None).unwrap();
+        runtime::set_caller(beneficiary.owner);
+        assert!(matches!(send(token, 10, Some(5), true), TransferResult::Err(TransferError::BadFee { expected_fee }) if expected_fee == 10u64));
+
+        // Once the budget cannot cover a whole fee, sponsored transfers fail
+        // instead of charging the sender.
+        assert!(matches!(send(token, 10, None, true), TransferResult::Ok(_)));
+        assert!(matches!(
+            send(token, 10, None, true),
+            TransferResult::Err(TransferError::GenericError { error_code, .. }) if error_code == 409u64
+        ));
+        assert_eq!(state::get_balance(token, beneficiary.to_key()), 90);
+        assert!(matches!(send(token, 10, None, false), TransferResult::Ok(_)));
+        assert_eq!(state::get_balance(token, beneficiary.to_key()), 70);
+        assert_eq!(available(token, &sponsor), (980, 975));
+    }
+
+    #[test]
+    fn test_revoke_refunds_and_expiry_stops_cover() {
+        let (token, sponsor, beneficiary) = setup();
+        assert!(sponsor_fees(token, beneficiary.clone(), Nat::from(9u64), None).is_err());
+        assert!(sponsor_fees(token, sponsor.clone(), Nat::from(10u64), None).is_err());
+        assert!(sponsor_fees(token, beneficiary.clone(), Nat::from(10u64), Some(runtime::time())).is_err());
+        assert!(matches!(
+            sponsor_fees(token, beneficiary.clone(), Nat::from(1_001u64), None),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
+
+        let open = sponsor_fees(token, beneficiary.clone(), Nat::from(300u64), None).unwrap();
+        let timed = sponsor_fees(token, beneficiary.clone(), Nat::from(200u64), Some(runtime::time() + SECOND)).unwrap();
+        assert_eq!(available(token, &sponsor), (1_000, 500));
+        let granted: Vec<u64> = list_sponsorships_granted(sponsor.clone(), None, None).unwrap().iter().map(|s| s.id).collect();
+        assert_eq!(granted, vec![open, timed]);
+        assert_eq!(list_sponsorships_granted(sponsor.clone(), Some(open), None).unwrap()[0].id, timed);
+
+        // Only the sponsor revokes; the unspent budget is free again.
+        runtime::set_caller(beneficiary.owner);
+        assert_eq!(list_sponsorships_received(token, beneficiary.clone()).unwrap().len(), 2);
+        assert!(revoke_sponsorship(open).is_err());
+        runtime::set_caller(sponsor.owner);
+        assert_eq!(revoke_sponsorship(open).unwrap(), 300u64);
+        assert!(revoke_sponsorship(open).is_err());
+        assert_eq!(available(token, &sponsor), (1_000, 800));
+
+        // Past its expiry a sponsorship pays nothing, and the sweep returns the budget.
+        runtime::advance_time(SECOND);
+        runtime::set_caller(beneficiary.owner);
+        assert!(list_sponsorships_received(token, beneficiary.clone()).unwrap().is_empty());
+        assert!(matches!(send(token, 10, None, true), TransferResult::Err(TransferError::GenericError { .. })));
+        assert_eq!(available(token, &sponsor), (1_000, 800));
+        maintenance_tick();
+        assert_eq!(get_sponsorship(timed), None);
+        assert_eq!(available(token, &sponsor), (1_000, 1_000));
+    }
+
+    #[test]
+    fn test_sponsorships_per_beneficiary_are_capped() {
+        let (token, _sponsor, beneficiary) = setup();
+        let expires_at = runtime::time() + SECOND;
+        for _ in 0..constants::MAX_SPONSORSHIPS_PER_BENEFICIARY {
+            sponsor_fees(token, beneficiary.clone(), Nat::from(10u64), Some(expires_at)).unwrap();
+        }
+        assert!(sponsor_fees(token, beneficiary.clone(), Nat::from(10u64), None).is_err());
+
+        // Expired ones make room without waiting for the sweep.
+        runtime::advance_time(SECOND);
+        sponsor_fees(token, beneficiary.clone(), Nat::from(10u64), None).unwrap();
+        assert_eq!(state::list_beneficiary_sponsorships(token, beneficiary.to_key()).len(), 1);
+    }
+}
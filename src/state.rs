@@ -1,177 +1,850 @@
 use crate::types::*;
+use crate::runtime;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     DefaultMemoryImpl, StableBTreeMap, Log, Storable,
 };
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use candid::Principal;
+use ic_certification::{fork, labeled, leaf, pruned, AsHashTree, Hash, RbTree};
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+// Tests count stable memory accesses to measure operation costs.
+#[cfg(not(test))]
+type StableMemory = DefaultMemoryImpl;
+#[cfg(test)]
+type StableMemory = crate::test_utils::CountingMemory<DefaultMemoryImpl>;
+
+pub(crate) type Memory = VirtualMemory<StableMemory>;
 
 thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static MEMORY_MANAGER: RefCell<MemoryManager<StableMemory>> = 
+        RefCell::new(MemoryManager::init(StableMemory::default()));
     
 
-    static TOKEN_REGISTRY: RefCell<StableBTreeMap<TokenId, crate::types::StoredTokenMetadata, Memory>> = RefCell::new(
+    // Values are the candid-encoded StoredTokenMetadata, kept as raw bytes so a
+    // corrupted entry fails only the calls touching that token.
+    static TOKEN_REGISTRY: RefCell<StableBTreeMap<TokenId, Vec<u8>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::TOKEN_REGISTRY)))
+            memory(memory_ids::TOKEN_REGISTRY)
         )
     );
     
     static BALANCE_STORAGE: RefCell<StableBTreeMap<[u8; 32], u128, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::BALANCE_STORAGE)))
+            memory(memory_ids::BALANCE_STORAGE)
         )
     );
     
     static ALLOWANCE_STORAGE: RefCell<StableBTreeMap<[u8; 32], u128, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::ALLOWANCE_STORAGE)))
+            bound_memory(memory_ids::ALLOWANCE_STORAGE)
         )
     );
     
     static TRANSACTION_LOG: RefCell<Log<crate::transaction::StoredTxV1, Memory, Memory>> = RefCell::new(
         Log::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::TRANSACTION_LOG))),
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::TX_INDEX_BUFFER)))
+            memory(memory_ids::TRANSACTION_LOG),
+            memory(memory_ids::TX_INDEX_BUFFER)
         ).expect("Failed to initialize transaction log")
     );
     
     static SYSTEM_STATE: RefCell<StableBTreeMap<[u8; 32], Vec<u8>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::SYSTEM_STATE)))
+            memory(memory_ids::SYSTEM_STATE)
         )
     );
 
     static CONTROLLERS: RefCell<StableBTreeMap<StoredPrincipal, u8, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::CONTROLLERS)))
+            memory(memory_ids::CONTROLLERS)
         )
     );
 
     static DEDUP_MAP: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::DEDUP_MAP)))
+            bound_memory(memory_ids::DEDUP_MAP)
         )
     );
 
     static ALLOWANCE_EXPIRY: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::ALLOWANCE_EXPIRY_INDEX)))
+            bound_memory(memory_ids::ALLOWANCE_EXPIRY_INDEX)
         )
     );
 
     static EXTENDED_MEMOS: RefCell<StableBTreeMap<u64, Vec<u8>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::EXTENDED_MEMOS)))
+            memory(memory_ids::EXTENDED_MEMOS)
         )
     );
 
     static HOLDER_COUNTS: RefCell<StableBTreeMap<TokenId, u64, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_ids::HOLDER_COUNTS)))
+            memory(memory_ids::HOLDER_COUNTS)
+        )
+    );
+
+    static ACCOUNT_REGISTRY: RefCell<StableBTreeMap<AccountKey, StoredAccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::ACCOUNT_REGISTRY)
+        )
+    );
+
+    static SPENDER_ALLOWANCES: RefCell<StableBTreeMap<[u8; 96], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            bound_memory(memory_ids::SPENDER_ALLOWANCES)
+        )
+    );
+
+    // Value: collected_by_recipient (u128 BE) ++ collected_by_treasury (u128 BE)
+    static FEE_STATS: RefCell<StableBTreeMap<TokenId, [u8; 32], Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::FEE_STATS)
+        )
+    );
+
+    static OWNER_ALLOWANCES: RefCell<StableBTreeMap<[u8; 96], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            bound_memory(memory_ids::OWNER_ALLOWANCES)
+        )
+    );
+
+    // Named subaccounts a principal has been approved on as spender.
+    static SPENDER_SUBACCOUNTS: RefCell<StableBTreeMap<[u8; 62], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::SPENDER_SUBACCOUNTS)
+        )
+    );
+
+    // Holder count as of the end of each day it changed; quiet days carry the previous value.
+    static HOLDER_COUNT_HISTORY: RefCell<StableBTreeMap<[u8; 40], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            bound_memory(memory_ids::HOLDER_COUNT_HISTORY)
+        )
+    );
+
+    // Gapless per-token sequence numbers, assigned when a transaction is appended.
+    static TOKEN_TX_SEQ: RefCell<StableBTreeMap<[u8; 44], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::TOKEN_TX_SEQ)
+        )
+    );
+
+    // Method names are zero-padded to 32 bytes, like SYSTEM_STATE keys.
+    static LEGACY_CALL_COUNTS: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::LEGACY_CALL_COUNTS)
+        )
+    );
+
+    // Accounts with a non-zero balance, per token. Lets a token's holders be listed.
+    static TOKEN_ACCOUNTS: RefCell<StableBTreeMap<[u8; 64], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::TOKEN_ACCOUNTS_INDEX)
+        )
+    );
+
+    // Bumped on every balance change of the token.
+    // Mirror of TOKEN_ACCOUNTS. Ledgers older than this index fill it with the
+    // account_tokens_index migration.
+    static ACCOUNT_TOKENS: RefCell<StableBTreeMap<[u8; 64], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::ACCOUNT_TOKENS_INDEX)
+        )
+    );
+
+    static BALANCE_SEQ: RefCell<StableBTreeMap<TokenId, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::BALANCE_SEQ)
+        )
+    );
+
+    // Bumped on every balance, allowance or metadata write of the token.
+    static TOKEN_MUTATION_SEQ: RefCell<StableBTreeMap<TokenId, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::TOKEN_MUTATION_SEQ)
+        )
+    );
+
+    static ADMIN_EXPIRED_ALLOWANCES: RefCell<StableBTreeMap<u64, crate::types::AdminExpiredAllowance, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::ADMIN_EXPIRED_ALLOWANCES)
+        )
+    );
+
+    // Transactions that moved an account's funds, in log order.
+    static ACCOUNT_TX_INDEX: RefCell<StableBTreeMap<[u8; 40], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::ACCOUNT_TX_INDEX)
+        )
+    );
+
+    // Value: authorization time. Netting needs the entry in both directions.
+    static NETTING_AUTHORIZATIONS: RefCell<StableBTreeMap<[u8; 64], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::NETTING_AUTHORIZATIONS)
+        )
+    );
+
+    static NETTING_SETTLEMENTS: RefCell<StableBTreeMap<u64, crate::types::SettlementRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::NETTING_SETTLEMENTS)
+        )
+    );
+
+    // Only accounts with a non-default policy have an entry.
+    static INCOMING_POLICIES: RefCell<StableBTreeMap<AccountKey, crate::types::IncomingPolicyConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::INCOMING_POLICIES)
+        )
+    );
+
+    // Missing for blocks appended before the hash chain existed until the
+    // block_hashes migration has run.
+    static BLOCK_HASHES: RefCell<StableBTreeMap<u64, [u8; 32], Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::BLOCK_HASHES)
+        )
+    );
+
+    // Expiring allowances in expiry order. Ledgers that had expiring allowances
+    // before this index fill it with the expiry_queue migration.
+    static ALLOWANCE_EXPIRY_QUEUE: RefCell<StableBTreeMap<[u8; 104], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            bound_memory(memory_ids::ALLOWANCE_EXPIRY_QUEUE)
+        )
+    );
+
+    // Principals that may read any account's data while privacy mode is on.
+    static AUDITORS: RefCell<StableBTreeMap<StoredPrincipal, u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::AUDITORS)
+        )
+    );
+
+    // Who may read each private memo besides controllers: the owners of the
+    // sender, recipient and spender accounts.
+    static PRIVATE_MEMO_READERS: RefCell<StableBTreeMap<[u8; 38], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::PRIVATE_MEMO_READERS)
+        )
+    );
+
+    static API_ACCESS_GRANTS: RefCell<StableBTreeMap<[u8; 62], ApiAccessGrant, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::API_ACCESS_GRANTS)
+        )
+    );
+
+    static ADMIN_AUDIT_LOG: RefCell<StableBTreeMap<u64, crate::types::AdminAuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::ADMIN_AUDIT_LOG)
+        )
+    );
+
+    static BURN_RECEIPTS: RefCell<StableBTreeMap<u64, crate::types::StoredBurnReceipt, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::BURN_RECEIPTS)
+        )
+    );
+
+    static FAILURE_LOG: RefCell<StableBTreeMap<u32, crate::types::StoredFailure, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::FAILURE_LOG)
+        )
+    );
+
+    static TOKEN_LOCALIZATIONS: RefCell<StableBTreeMap<[u8; 37], crate::types::TokenLocalization, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::TOKEN_LOCALIZATIONS)
+        )
+    );
+
+    static TOKEN_IMPORTS: RefCell<StableBTreeMap<TokenId, crate::types::TokenImport, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::TOKEN_IMPORTS)
+        )
+    );
+
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, crate::types::Reservation, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::RESERVATIONS)
+        )
+    );
+
+    static ACCOUNT_RESERVATIONS: RefCell<StableBTreeMap<[u8; 72], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::ACCOUNT_RESERVATIONS)
+        )
+    );
+
+    static RESERVATION_EXPIRY: RefCell<StableBTreeMap<[u8; 16], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::RESERVATION_EXPIRY)
+        )
+    );
+
+    static BALANCE_SNAPSHOTS: RefCell<StableBTreeMap<u64, crate::types::BalanceSnapshot, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::BALANCE_SNAPSHOTS)
+        )
+    );
+
+    // Zero marks an account that held nothing when its snapshot was opened
+    // and whose balance changed before the copy reached it.
+    static SNAPSHOT_BALANCES: RefCell<StableBTreeMap<[u8; 40], u128, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::SNAPSHOT_BALANCES)
+        )
+    );
+
+    static AIRDROPS: RefCell<StableBTreeMap<[u8; 40], crate::types::Airdrop, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::AIRDROPS)
+        )
+    );
+
+    // Every snapshot still being copied, so set_balance checks them without a
+    // stable read. Loaded on first use after an upgrade.
+    static OPEN_SNAPSHOTS: RefCell<Option<Vec<OpenSnapshot>>> = const { RefCell::new(None) };
+
+    // Every nonzero balance by token_id ++ account_key, the tree the certified
+    // data commits to (see verification.rs), and the newest block hash beside
+    // it. Heap only: an upgrade empties the tree and the certified_balances
+    // maintenance task refills it, holder by holder, in key order.
+    static CERTIFIED_BALANCES: RefCell<RbTree<[u8; 64], Vec<u8>>> = const { RefCell::new(RbTree::new()) };
+    static CERTIFIED_REFILL: Cell<CertifiedRefill> = const { Cell::new(CertifiedRefill::UpTo(None)) };
+    static CERTIFIED_TIP: Cell<Option<[u8; 32]>> = const { Cell::new(None) };
+
+    static SPONSORSHIPS: RefCell<StableBTreeMap<u64, crate::types::Sponsorship, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::SPONSORSHIPS)
+        )
+    );
+
+    static BENEFICIARY_SPONSORSHIPS: RefCell<StableBTreeMap<[u8; 72], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::BENEFICIARY_SPONSORSHIPS)
+        )
+    );
+
+    static SPONSOR_SPONSORSHIPS: RefCell<StableBTreeMap<[u8; 40], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::SPONSOR_SPONSORSHIPS)
+        )
+    );
+
+    static SPONSORSHIP_EXPIRY: RefCell<StableBTreeMap<[u8; 16], (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::SPONSORSHIP_EXPIRY)
+        )
+    );
+
+    static SPONSOR_HOLDS: RefCell<StableBTreeMap<[u8; 64], u128, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::SPONSOR_HOLDS)
+        )
+    );
+
+    // Only days on which a block was appended have an entry.
+    static DAY_FIRST_TX: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::DAY_FIRST_TX)
         )
     );
+
+    // Bumped whenever any of the account's balances changes.
+    static ACCOUNT_BALANCE_SEQ: RefCell<StableBTreeMap<AccountKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::ACCOUNT_BALANCE_SEQ)
+        )
+    );
+
+    // Only blocks whose fee was split with a commission have an entry.
+    static FEE_COMMISSIONS: RefCell<StableBTreeMap<u64, (AccountKey, u128), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            memory(memory_ids::FEE_COMMISSIONS)
+        )
+    );
+
+    // The last day known to have its DAY_FIRST_TX entry, so only the first
+    // block of a day (or after an upgrade) looks the entry up.
+    static LAST_INDEXED_DAY: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
 }
 
 
-const KEY_CONTROLLER: [u8; 32] = *b"icrc151:controller:v1\0\0\0\0\0\0\0\0\0\0\0";
-const KEY_NEXT_TOKEN_NONCE: [u8; 32] = *b"icrc151:next_token_nonce:v1\0\0\0\0\0";
-const KEY_GLOBAL_TX_COUNT: [u8; 32] = *b"icrc151:global_tx_count:v1\0\0\0\0\0\0";
+// (id, token, cursor) of a snapshot still being copied.
+type OpenSnapshot = (u64, TokenId, Option<AccountKey>);
 
 
-pub fn init_state(controller: Principal) {
-    SYSTEM_STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        
+// How much of the token accounts index the certified tree holds. Until it is
+// Complete the certified data commits to no balances at all, so a half-filled
+// tree is never certified.
+#[derive(Clone, Copy)]
+enum CertifiedRefill {
+    // The last key inserted; None before the first.
+    UpTo(Option<[u8; 64]>),
+    Complete,
+}
 
-        let controller_stored = StoredPrincipal::from_principal(&controller)
-            .expect("Invalid controller principal");
-        state.insert(KEY_CONTROLLER, controller_stored.to_bytes().to_vec());
-        
 
-        state.insert(KEY_NEXT_TOKEN_NONCE, 0u64.to_be_bytes().to_vec());
-        state.insert(KEY_GLOBAL_TX_COUNT, 0u64.to_be_bytes().to_vec());
-    });
+// Every stable structure, here and in the feature-gated subsystems, takes its
+// memory through this. The manager's borrow ends before the structure's init
+// runs, so an init that reaches for another structure cannot double-borrow.
+pub(crate) fn memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
 
-    // Seed controllers set with initial controller
-    CONTROLLERS.with(|c| {
-        let mut map = c.borrow_mut();
-        if let Ok(stored) = StoredPrincipal::from_principal(&controller) {
-            map.insert(stored, 1u8);
-        }
-    });
+
+// The maps compact_structure can move take their memory through this instead:
+// SYSTEM_STATE binds a moved map's home id to the region it lives in now.
+fn bound_memory(home: u8) -> Memory {
+    memory(bound_region(home))
 }
 
 
-pub fn get_controller() -> Option<Principal> {
-    SYSTEM_STATE.with(|s| {
-        s.borrow().get(&KEY_CONTROLLER).and_then(|bytes| {
-            if bytes.len() == 30 {
-                let stored = StoredPrincipal::from_bytes(std::borrow::Cow::Borrowed(&bytes));
-                stored.to_principal().ok()
-            } else {
-                None
-            }
-        })
-    })
+// Whether anything was ever written to the region; it does not shrink.
+pub(crate) fn holds_data(region: u8) -> bool {
+    ic_stable_structures::Memory::size(&memory(region)) > 0
 }
 
-pub fn only_controller() -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    if !is_controller(&caller) {
-        return Err("Only controller can call this method".to_string());
-    }
-    Ok(())
+
+pub fn bound_region(home: u8) -> u8 {
+    memory_bindings().into_iter().find(|(h, _)| *h == home).map_or(home, |(_, region)| region)
 }
 
 
-pub fn set_controller(new_controller: Principal) -> Result<(), String> {
-    require_controller()?;
-    SYSTEM_STATE.with(|s| -> Result<(), String> {
-        let mut state = s.borrow_mut();
-        let controller_stored = StoredPrincipal::from_principal(&new_controller)?;
-        state.insert(KEY_CONTROLLER, controller_stored.to_bytes().to_vec());
-        Ok(())
-    })?;
-    add_controller_internal(new_controller)
+// (home id, region) for every moved map; stored as byte pairs.
+pub fn memory_bindings() -> Vec<(u8, u8)> {
+    SYSTEM_STATE.with(|s| s.borrow().get(&KEY_MEMORY_BINDINGS))
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect()
 }
 
 
-pub fn next_token_nonce() -> u64 {
+fn set_memory_binding(home: u8, region: u8) {
+    let mut bindings = memory_bindings();
+    bindings.retain(|(h, _)| *h != home);
+    if region != home {
+        bindings.push((home, region));
+    }
+    let bytes = bindings.into_iter().flat_map(|(h, r)| [h, r]).collect();
     SYSTEM_STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        let current = state.get(&KEY_NEXT_TOKEN_NONCE)
-            .map(|bytes| {
-                let mut buf = [0u8; 8];
-                buf.copy_from_slice(&bytes[..8]);
-                u64::from_be_bytes(buf)
-            })
-            .unwrap_or(0);
-        
-        let next = current + 1;
-        state.insert(KEY_NEXT_TOKEN_NONCE, next.to_be_bytes().to_vec());
-        next
-    })
+        s.borrow_mut().insert(KEY_MEMORY_BINDINGS, bytes);
+    });
 }
 
 
-pub fn get_global_tx_count() -> u64 {
+// Regions a compaction moved a map out of. The memory manager never frees
+// pages, so these are handed to the next compaction before any fresh region.
+pub fn reusable_regions() -> Vec<u8> {
+    SYSTEM_STATE.with(|s| s.borrow().get(&KEY_REUSABLE_REGIONS)).unwrap_or_default()
+}
+
+
+pub fn set_reusable_regions(regions: Vec<u8>) {
     SYSTEM_STATE.with(|s| {
-        s.borrow().get(&KEY_GLOBAL_TX_COUNT)
-            .map(|bytes| {
-                let mut buf = [0u8; 8];
-                buf.copy_from_slice(&bytes[..8]);
-                u64::from_be_bytes(buf)
-            })
-            .unwrap_or(0)
+        s.borrow_mut().insert(KEY_REUSABLE_REGIONS, regions);
+    });
+}
+
+
+thread_local! {
+    // The compaction record, cached because every write to a compactable map
+    // looks at it. Outer None until the first read after an upgrade.
+    static COMPACTION: RefCell<Option<Option<StoredCompaction>>> = const { RefCell::new(None) };
+}
+
+
+fn with_compaction<R>(f: impl FnOnce(Option<&StoredCompaction>) -> R) -> R {
+    COMPACTION.with(|c| {
+        let mut cached = c.borrow_mut();
+        let compaction = cached.get_or_insert_with(|| {
+            use candid::Decode;
+            SYSTEM_STATE.with(|s| s.borrow().get(&KEY_COMPACTION))
+                .and_then(|bytes| Decode!(&bytes, StoredCompaction).ok())
+        });
+        f(compaction.as_ref())
     })
 }
 
 
-pub fn increment_tx_count() -> u64 {
-    SYSTEM_STATE.with(|s| {
+pub fn get_compaction() -> Option<StoredCompaction> {
+    with_compaction(|c| c.cloned())
+}
+
+
+pub fn set_compaction(compaction: Option<&StoredCompaction>) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        match compaction {
+            Some(c) => state.insert(KEY_COMPACTION, Encode!(c).expect("Compaction state is always encodable")),
+            None => state.remove(&KEY_COMPACTION),
+        };
+    });
+    COMPACTION.with(|c| *c.borrow_mut() = Some(compaction.cloned()));
+}
+
+
+type StableMap<K, V> = std::thread::LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>;
+
+
+// Runs a generic body over the map a StructureId names.
+macro_rules! with_structure {
+    ($structure:expr, $body:ident($($arg:expr),*)) => {
+        match $structure {
+            StructureId::DedupMap => $body(&DEDUP_MAP, $($arg),*),
+            StructureId::Allowances => $body(&ALLOWANCE_STORAGE, $($arg),*),
+            StructureId::AllowanceExpiry => $body(&ALLOWANCE_EXPIRY, $($arg),*),
+            StructureId::AllowanceExpiryQueue => $body(&ALLOWANCE_EXPIRY_QUEUE, $($arg),*),
+            StructureId::SpenderAllowances => $body(&SPENDER_ALLOWANCES, $($arg),*),
+            StructureId::OwnerAllowances => $body(&OWNER_ALLOWANCES, $($arg),*),
+            StructureId::HolderCountHistory => $body(&HOLDER_COUNT_HISTORY, $($arg),*),
+        }
+    };
+}
+
+
+// The compaction's region when it is copying `structure` and has already
+// passed `key`, so a write there has to be repeated in the copy. Keys after
+// the cursor are copied later with whatever value they have then.
+fn copied_into<K: Storable + Ord>(structure: StructureId, key: &K) -> Option<u8> {
+    with_compaction(|c| {
+        let c = c.filter(|c| c.target == structure)?;
+        let cursor = c.cursor.as_deref()?;
+        (*key <= K::from_bytes(std::borrow::Cow::Borrowed(cursor))).then_some(c.region)
+    })
+}
+
+
+// Writes to compactable maps go through these two.
+fn tracked_insert<K: Storable + Ord + Clone, V: Storable + Clone>(
+    structure: StructureId,
+    map: &'static StableMap<K, V>,
+    key: K,
+    value: V,
+) -> Option<V> {
+    if let Some(region) = copied_into(structure, &key) {
+        StableBTreeMap::<K, V, Memory>::init(memory(region)).insert(key.clone(), value.clone());
+    }
+    map.with(|m| m.borrow_mut().insert(key, value))
+}
+
+
+fn tracked_remove<K: Storable + Ord + Clone, V: Storable>(
+    structure: StructureId,
+    map: &'static StableMap<K, V>,
+    key: &K,
+) -> Option<V> {
+    if let Some(region) = copied_into(structure, key) {
+        StableBTreeMap::<K, V, Memory>::init(memory(region)).remove(key);
+    }
+    map.with(|m| m.borrow_mut().remove(key))
+}
+
+
+fn map_len<K: Storable + Ord + Clone, V: Storable>(map: &'static StableMap<K, V>) -> u64 {
+    map.with(|m| m.borrow().len())
+}
+
+
+fn region_len<K: Storable + Ord + Clone, V: Storable>(_: &'static StableMap<K, V>, region: u8) -> u64 {
+    StableBTreeMap::<K, V, Memory>::init(memory(region)).len()
+}
+
+
+fn clear_region<K: Storable + Ord + Clone, V: Storable>(_: &'static StableMap<K, V>, region: u8) {
+    StableBTreeMap::<K, V, Memory>::new(memory(region));
+}
+
+
+fn copy_entries<K: Storable + Ord + Clone, V: Storable>(
+    map: &'static StableMap<K, V>,
+    region: u8,
+    start_after: Option<&[u8]>,
+    limit: u64,
+) -> (u64, Option<Vec<u8>>) {
+    use std::ops::Bound;
+    let mut copy = StableBTreeMap::<K, V, Memory>::init(memory(region));
+    map.with(|m| {
+        let map = m.borrow();
+        let lower = match start_after {
+            Some(after) => Bound::Excluded(K::from_bytes(std::borrow::Cow::Borrowed(after))),
+            None => Bound::Unbounded,
+        };
+        let mut copied = 0;
+        let mut last = None;
+        for (key, value) in map.range((lower, Bound::Unbounded)).take(limit as usize) {
+            copy.insert(key.clone(), value);
+            last = Some(key);
+            copied += 1;
+        }
+        (copied, last.map(|key| key.to_bytes().into_owned()))
+    })
+}
+
+
+fn rebind<K: Storable + Ord + Clone, V: Storable>(map: &'static StableMap<K, V>, region: u8) {
+    map.with(|m| *m.borrow_mut() = StableBTreeMap::init(memory(region)));
+}
+
+
+pub fn structure_len(structure: StructureId) -> u64 {
+    with_structure!(structure, map_len())
+}
+
+
+pub fn compaction_region_len(structure: StructureId, region: u8) -> u64 {
+    with_structure!(structure, region_len(region))
+}
+
+
+// Starts an empty copy in `region`, dropping whatever an earlier map left there.
+pub fn clear_compaction_region(structure: StructureId, region: u8) {
+    with_structure!(structure, clear_region(region))
+}
+
+
+// Copies up to `limit` live entries after `start_after` into `region`.
+// Returns the count and the encoded key of the last one.
+pub fn copy_structure_entries(structure: StructureId, region: u8, start_after: Option<&[u8]>, limit: u64) -> (u64, Option<Vec<u8>>) {
+    with_structure!(structure, copy_entries(region, start_after, limit))
+}
+
+
+// Makes `region` the structure's memory, in the binding table for later
+// upgrades and in the open map for the rest of this one.
+pub fn bind_structure(structure: StructureId, region: u8) {
+    set_memory_binding(structure.home_memory_id(), region);
+    with_structure!(structure, rebind(region))
+}
+
+
+// Drops what an upgrade drops: the heap caches and the open compactable
+// maps, which are then reopened from the binding table.
+#[cfg(test)]
+pub(crate) fn simulate_upgrade() {
+    COMPACTION.with(|c| *c.borrow_mut() = None);
+    OFFICIAL_ACCOUNTS.with(|o| *o.borrow_mut() = None);
+    OPEN_SNAPSHOTS.with(|o| *o.borrow_mut() = None);
+    CERTIFIED_BALANCES.with(|c| *c.borrow_mut() = RbTree::new());
+    CERTIFIED_REFILL.with(|r| r.set(CertifiedRefill::UpTo(None)));
+    CERTIFIED_TIP.with(|t| t.set(None));
+    for structure in StructureId::ALL {
+        with_structure!(structure, rebind(bound_region(structure.home_memory_id())));
+    }
+}
+
+
+// Stable structures initialize on first access, which reads or writes their
+// headers. init and post_upgrade call this so that cost is paid there, not by
+// the first user call after an upgrade. Add new structures here.
+//
+// A structure whose region is still empty would write its header and allocate
+// the region's first 8 MiB bucket, so only regions that hold data are warmed.
+pub fn warm_up() {
+    macro_rules! warm {
+        ($($structure:ident => $region:expr),* $(,)?) => {
+            $(if holds_data($region) { $structure.with(|_| ()); })*
+        };
+    }
+
+    warm! {
+        TOKEN_REGISTRY => memory_ids::TOKEN_REGISTRY,
+        BALANCE_STORAGE => memory_ids::BALANCE_STORAGE,
+        ALLOWANCE_STORAGE => bound_region(memory_ids::ALLOWANCE_STORAGE),
+        TRANSACTION_LOG => memory_ids::TRANSACTION_LOG,
+        SYSTEM_STATE => memory_ids::SYSTEM_STATE,
+        CONTROLLERS => memory_ids::CONTROLLERS,
+        DEDUP_MAP => bound_region(memory_ids::DEDUP_MAP),
+        ALLOWANCE_EXPIRY => bound_region(memory_ids::ALLOWANCE_EXPIRY_INDEX),
+        EXTENDED_MEMOS => memory_ids::EXTENDED_MEMOS,
+        HOLDER_COUNTS => memory_ids::HOLDER_COUNTS,
+        ACCOUNT_REGISTRY => memory_ids::ACCOUNT_REGISTRY,
+        SPENDER_ALLOWANCES => bound_region(memory_ids::SPENDER_ALLOWANCES),
+        FEE_STATS => memory_ids::FEE_STATS,
+        OWNER_ALLOWANCES => bound_region(memory_ids::OWNER_ALLOWANCES),
+        SPENDER_SUBACCOUNTS => memory_ids::SPENDER_SUBACCOUNTS,
+        HOLDER_COUNT_HISTORY => bound_region(memory_ids::HOLDER_COUNT_HISTORY),
+        TOKEN_TX_SEQ => memory_ids::TOKEN_TX_SEQ,
+        LEGACY_CALL_COUNTS => memory_ids::LEGACY_CALL_COUNTS,
+        TOKEN_ACCOUNTS => memory_ids::TOKEN_ACCOUNTS_INDEX,
+        ACCOUNT_TOKENS => memory_ids::ACCOUNT_TOKENS_INDEX,
+        BALANCE_SEQ => memory_ids::BALANCE_SEQ,
+        TOKEN_MUTATION_SEQ => memory_ids::TOKEN_MUTATION_SEQ,
+        ADMIN_EXPIRED_ALLOWANCES => memory_ids::ADMIN_EXPIRED_ALLOWANCES,
+        ACCOUNT_TX_INDEX => memory_ids::ACCOUNT_TX_INDEX,
+        NETTING_AUTHORIZATIONS => memory_ids::NETTING_AUTHORIZATIONS,
+        NETTING_SETTLEMENTS => memory_ids::NETTING_SETTLEMENTS,
+        INCOMING_POLICIES => memory_ids::INCOMING_POLICIES,
+        BLOCK_HASHES => memory_ids::BLOCK_HASHES,
+        ALLOWANCE_EXPIRY_QUEUE => bound_region(memory_ids::ALLOWANCE_EXPIRY_QUEUE),
+        AUDITORS => memory_ids::AUDITORS,
+        PRIVATE_MEMO_READERS => memory_ids::PRIVATE_MEMO_READERS,
+        API_ACCESS_GRANTS => memory_ids::API_ACCESS_GRANTS,
+        ADMIN_AUDIT_LOG => memory_ids::ADMIN_AUDIT_LOG,
+        BURN_RECEIPTS => memory_ids::BURN_RECEIPTS,
+        FAILURE_LOG => memory_ids::FAILURE_LOG,
+        TOKEN_LOCALIZATIONS => memory_ids::TOKEN_LOCALIZATIONS,
+        TOKEN_IMPORTS => memory_ids::TOKEN_IMPORTS,
+        RESERVATIONS => memory_ids::RESERVATIONS,
+        ACCOUNT_RESERVATIONS => memory_ids::ACCOUNT_RESERVATIONS,
+        RESERVATION_EXPIRY => memory_ids::RESERVATION_EXPIRY,
+        BALANCE_SNAPSHOTS => memory_ids::BALANCE_SNAPSHOTS,
+        SNAPSHOT_BALANCES => memory_ids::SNAPSHOT_BALANCES,
+        AIRDROPS => memory_ids::AIRDROPS,
+        SPONSORSHIPS => memory_ids::SPONSORSHIPS,
+        BENEFICIARY_SPONSORSHIPS => memory_ids::BENEFICIARY_SPONSORSHIPS,
+        SPONSOR_SPONSORSHIPS => memory_ids::SPONSOR_SPONSORSHIPS,
+        SPONSORSHIP_EXPIRY => memory_ids::SPONSORSHIP_EXPIRY,
+        SPONSOR_HOLDS => memory_ids::SPONSOR_HOLDS,
+        DAY_FIRST_TX => memory_ids::DAY_FIRST_TX,
+        ACCOUNT_BALANCE_SEQ => memory_ids::ACCOUNT_BALANCE_SEQ,
+        FEE_COMMISSIONS => memory_ids::FEE_COMMISSIONS,
+    }
+    #[cfg(feature = "notifications")]
+    crate::notifications::warm_up();
+    #[cfg(feature = "relay")]
+    crate::relay::warm_up();
+    #[cfg(feature = "dev")]
+    crate::dev::warm_up();
+}
+
+
+const KEY_CONTROLLER: [u8; 32] = *b"icrc151:controller:v1\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_NEXT_TOKEN_NONCE: [u8; 32] = *b"icrc151:next_token_nonce:v1\0\0\0\0\0";
+const KEY_GLOBAL_TX_COUNT: [u8; 32] = *b"icrc151:global_tx_count:v1\0\0\0\0\0\0";
+const KEY_COMMISSION_BPS: [u8; 32] = *b"icrc151:commission_bps:v1\0\0\0\0\0\0\0";
+const KEY_PROTOCOL_TREASURY: [u8; 32] = *b"icrc151:protocol_treasury:v1\0\0\0\0";
+const KEY_ALLOWANCES_CLEANED_UP: [u8; 32] = *b"icrc151:allowances_gc:v1\0\0\0\0\0\0\0\0";
+const KEY_HOLDER_HISTORY_RETENTION: [u8; 32] = *b"icrc151:holder_hist_days:v1\0\0\0\0\0";
+const KEY_MAX_TOKENS: [u8; 32] = *b"icrc151:max_tokens:v1\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_RECOVERY: [u8; 32] = *b"icrc151:recovery:v1\0\0\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_STABLE_PAGES_SOFT_LIMIT: [u8; 32] = *b"icrc151:stable_soft_limit:v1\0\0\0\0";
+const KEY_PRIVACY_MODE: [u8; 32] = *b"icrc151:privacy_mode:v1\0\0\0\0\0\0\0\0\0";
+const KEY_FEE_GOVERNANCE: [u8; 32] = *b"icrc151:fee_governance:v1\0\0\0\0\0\0\0";
+const KEY_DEFAULT_TOKEN_FEE: [u8; 32] = *b"icrc151:default_token_fee:v1\0\0\0\0";
+const KEY_MAINTENANCE_MODE: [u8; 32] = *b"icrc151:maintenance_mode:v1\0\0\0\0\0";
+const KEY_PAUSED_OPS: [u8; 32] = *b"icrc151:paused_ops:v1\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_HOLDER_SOFT_CAP: [u8; 32] = *b"icrc151:holder_soft_cap:v1\0\0\0\0\0\0";
+const KEY_TX_WINDOW: [u8; 32] = *b"icrc151:tx_window:v1\0\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_WIDEST_TX_WINDOW: [u8; 32] = *b"icrc151:widest_tx_window:v1\0\0\0\0\0";
+const KEY_FINALITY_POLICY: [u8; 32] = *b"icrc151:finality_policy:v1\0\0\0\0\0\0";
+const KEY_MAINTENANCE_TICK: [u8; 32] = *b"icrc151:gc_tick:v1\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_OPERATION_COSTS: [u8; 32] = *b"icrc151:op_costs:v1\0\0\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_FAILURE_LOG: [u8; 32] = *b"icrc151:failure_log:v1\0\0\0\0\0\0\0\0\0\0";
+const KEY_NEXT_RESERVATION_ID: [u8; 32] = *b"icrc151:reservation_id:v1\0\0\0\0\0\0\0";
+const KEY_NEXT_SNAPSHOT_ID: [u8; 32] = *b"icrc151:snapshot_id:v1\0\0\0\0\0\0\0\0\0\0";
+const KEY_NEXT_SPONSORSHIP_ID: [u8; 32] = *b"icrc151:sponsorship_id:v1\0\0\0\0\0\0\0";
+const KEY_MEMORY_BINDINGS: [u8; 32] = *b"icrc151:memory_bindings:v1\0\0\0\0\0\0";
+const KEY_REUSABLE_REGIONS: [u8; 32] = *b"icrc151:reusable_regions:v1\0\0\0\0\0";
+const KEY_COMPACTION: [u8; 32] = *b"icrc151:compaction:v1\0\0\0\0\0\0\0\0\0\0\0";
+const KEY_DEPRECATION_MODES: [u8; 32] = *b"icrc151:deprecation_modes:v1\0\0\0\0";
+const KEY_OFFICIAL_ACCOUNTS: [u8; 32] = *b"icrc151:official_accounts:v1\0\0\0\0";
+#[cfg(feature = "notifications")]
+const KEY_NEXT_BALANCE_ALERT_ID: [u8; 32] = *b"icrc151:balance_alert_id:v1\0\0\0\0\0";
+#[cfg(feature = "dev")]
+const KEY_NON_PRODUCTION: [u8; 32] = *b"icrc151:non_production:v1\0\0\0\0\0\0\0";
+
+// Bounds the pruning done by a single holder-count change.
+const HOLDER_HISTORY_PRUNE_BATCH: usize = 16;
+
+
+pub fn init_state(controller: Principal) {
+    SYSTEM_STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        
+
+        let controller_stored = StoredPrincipal::from_principal(&controller)
+            .expect("Invalid controller principal");
+        state.insert(KEY_CONTROLLER, controller_stored.to_bytes().to_vec());
+        
+
+        state.insert(KEY_NEXT_TOKEN_NONCE, 0u64.to_be_bytes().to_vec());
+        state.insert(KEY_GLOBAL_TX_COUNT, 0u64.to_be_bytes().to_vec());
+    });
+
+    // Seed controllers set with initial controller
+    CONTROLLERS.with(|c| {
+        let mut map = c.borrow_mut();
+        if let Ok(stored) = StoredPrincipal::from_principal(&controller) {
+            map.insert(stored, 1u8);
+        }
+    });
+}
+
+
+pub fn get_controller() -> Option<Principal> {
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_CONTROLLER).and_then(|bytes| {
+            if bytes.len() == 30 {
+                let stored = StoredPrincipal::from_bytes(std::borrow::Cow::Borrowed(&bytes));
+                stored.to_principal().ok()
+            } else {
+                None
+            }
+        })
+    })
+}
+
+pub fn only_controller() -> Result<(), String> {
+    let caller = runtime::caller();
+    if !is_controller(&caller) {
+        return Err("Only controller can call this method".to_string());
+    }
+    Ok(())
+}
+
+
+pub fn set_controller(new_controller: Principal) -> Result<(), String> {
+    require_controller()?;
+    SYSTEM_STATE.with(|s| -> Result<(), String> {
+        let mut state = s.borrow_mut();
+        let controller_stored = StoredPrincipal::from_principal(&new_controller)?;
+        state.insert(KEY_CONTROLLER, controller_stored.to_bytes().to_vec());
+        Ok(())
+    })?;
+    add_controller_internal(new_controller)
+}
+
+
+pub fn next_token_nonce() -> u64 {
+    SYSTEM_STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let current = state.get(&KEY_NEXT_TOKEN_NONCE)
+            .map(|bytes| {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[..8]);
+                u64::from_be_bytes(buf)
+            })
+            .unwrap_or(0);
+        
+        let next = current + 1;
+        state.insert(KEY_NEXT_TOKEN_NONCE, next.to_be_bytes().to_vec());
+        next
+    })
+}
+
+
+pub fn get_global_tx_count() -> u64 {
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_GLOBAL_TX_COUNT)
+            .map(|bytes| {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[..8]);
+                u64::from_be_bytes(buf)
+            })
+            .unwrap_or(0)
+    })
+}
+
+
+pub fn increment_tx_count() -> u64 {
+    SYSTEM_STATE.with(|s| {
         let mut state = s.borrow_mut();
         let current = state.get(&KEY_GLOBAL_TX_COUNT)
             .map(|bytes| {
@@ -188,87 +861,2222 @@ pub fn increment_tx_count() -> u64 {
 }
 
 
-pub fn get_balance(token_id: TokenId, account_key: AccountKey) -> u128 {
-    let balance_key = hash_balance_key(token_id, account_key);
-    BALANCE_STORAGE.with(|b| {
-        b.borrow().get(&balance_key).unwrap_or(0)
-    })
+pub fn get_balance(token_id: TokenId, account_key: AccountKey) -> u128 {
+    let balance_key = hash_balance_key(token_id, account_key);
+    BALANCE_STORAGE.with(|b| {
+        b.borrow().get(&balance_key).unwrap_or(0)
+    })
+}
+
+
+// Private: balances change only through apply_movements, which refuses
+// movements that create or destroy value, or through an open import.
+fn set_balance(token_id: TokenId, account_key: AccountKey, amount: u128) {
+    let balance_key = hash_balance_key(token_id, account_key);
+
+    let old_balance = BALANCE_STORAGE.with(|b| {
+        b.borrow().get(&balance_key).unwrap_or(0)
+    });
+
+    BALANCE_STORAGE.with(|b| {
+        let mut storage = b.borrow_mut();
+        if amount == 0 {
+            storage.remove(&balance_key);
+        } else {
+            storage.insert(balance_key, amount);
+        }
+    });
+
+    if old_balance == 0 && amount > 0 {
+        increment_holder_count(token_id);
+        TOKEN_ACCOUNTS.with(|t| t.borrow_mut().insert(encode_token_account_key(token_id, account_key), ()));
+        ACCOUNT_TOKENS.with(|a| a.borrow_mut().insert(encode_account_token_key(account_key, token_id), ()));
+    } else if old_balance > 0 && amount == 0 {
+        decrement_holder_count(token_id);
+        TOKEN_ACCOUNTS.with(|t| t.borrow_mut().remove(&encode_token_account_key(token_id, account_key)));
+        ACCOUNT_TOKENS.with(|a| a.borrow_mut().remove(&encode_account_token_key(account_key, token_id)));
+    }
+
+    if old_balance != amount {
+        save_for_open_snapshots(token_id, account_key, old_balance);
+        certify_balance(token_id, account_key, amount);
+        BALANCE_SEQ.with(|s| bump_seq(&mut s.borrow_mut(), token_id));
+        bump_mutation_seq(token_id);
+        ACCOUNT_BALANCE_SEQ.with(|s| bump_seq(&mut s.borrow_mut(), account_key));
+        #[cfg(feature = "notifications")]
+        crate::notifications::check_balance_alerts(token_id, account_key, old_balance, amount);
+    }
+}
+
+
+// Sets balances directly in unit tests, bypassing the accounting check.
+#[cfg(test)]
+pub fn seed_balance(token_id: TokenId, account_key: AccountKey, amount: u128) {
+    set_balance(token_id, account_key, amount);
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MovementError {
+    // Debits plus minted supply differ from credits plus burned supply.
+    Unbalanced { sources: u128, sinks: u128 },
+    InsufficientBalance { account_key: AccountKey, balance: u128 },
+    Overflow,
+    Supply(String),
+    HolderLimitReached { max_holders: u64 },
+}
+
+impl std::fmt::Display for MovementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MovementError::Unbalanced { sources, sinks } => {
+                write!(f, "Unbalanced movements: {} debited or minted, {} credited or burned", sources, sinks)
+            }
+            MovementError::InsufficientBalance { balance, .. } => write!(f, "Insufficient balance: {}", balance),
+            MovementError::Overflow => write!(f, "Balance or supply overflow"),
+            MovementError::Supply(message) => write!(f, "{}", message),
+            MovementError::HolderLimitReached { max_holders } => {
+                write!(f, "HolderLimitReached: the token is limited to {} holders", max_holders)
+            }
+        }
+    }
+}
+
+
+// Balances and supply a movement set would leave behind, worked out from
+// reads alone.
+struct MovementPlan {
+    new_balances: Vec<(AccountKey, u128)>,
+    new_supply: Option<u128>,
+    // Accounts going from zero to a balance, and from a balance to zero.
+    joining: u64,
+    leaving: u64,
+}
+
+
+fn plan_movements(
+    token_id: TokenId,
+    debits: &[(AccountKey, u128)],
+    credits: &[(AccountKey, u128)],
+    supply_delta: i128,
+) -> Result<MovementPlan, MovementError> {
+    let sum = |legs: &[(AccountKey, u128)]| legs.iter().try_fold(0u128, |total, (_, amount)| total.checked_add(*amount));
+    let minted = supply_delta.max(0).unsigned_abs();
+    let burned = supply_delta.min(0).unsigned_abs();
+    let sources = sum(debits).and_then(|d| d.checked_add(minted)).ok_or(MovementError::Overflow)?;
+    let sinks = sum(credits).and_then(|c| c.checked_add(burned)).ok_or(MovementError::Overflow)?;
+    if sources != sinks {
+        return Err(MovementError::Unbalanced { sources, sinks });
+    }
+
+    // Net change per account, in first-seen order.
+    let mut changes: Vec<(AccountKey, u128, u128)> = Vec::new();
+    for (account_key, debit, credit) in debits.iter().map(|(k, a)| (k, *a, 0)).chain(credits.iter().map(|(k, a)| (k, 0, *a))) {
+        match changes.iter_mut().find(|(key, _, _)| key == account_key) {
+            Some(entry) => {
+                entry.1 += debit;
+                entry.2 += credit;
+            }
+            None => changes.push((*account_key, debit, credit)),
+        }
+    }
+    let mut plan = MovementPlan { new_balances: Vec::with_capacity(changes.len()), new_supply: None, joining: 0, leaving: 0 };
+    for (account_key, debit, credit) in changes {
+        if debit == credit {
+            continue;
+        }
+        let balance = get_balance(token_id, account_key);
+        let new_balance = if credit > debit {
+            balance.checked_add(credit - debit).ok_or(MovementError::Overflow)?
+        } else {
+            balance.checked_sub(debit - credit).ok_or(MovementError::InsufficientBalance { account_key, balance })?
+        };
+        plan.joining += (balance == 0 && new_balance > 0) as u64;
+        plan.leaving += (balance > 0 && new_balance == 0) as u64;
+        plan.new_balances.push((account_key, new_balance));
+    }
+
+    // Metadata is only read when the supply moves or the holder count grows.
+    if supply_delta == 0 && plan.joining <= plan.leaving {
+        return Ok(plan);
+    }
+    let metadata = get_token_metadata(token_id).map_err(|e| MovementError::Supply(e.to_string()))?;
+    if plan.joining > plan.leaving {
+        if let Some(max_holders) = metadata.max_holders {
+            if get_holder_count(token_id) + plan.joining - plan.leaving > max_holders {
+                return Err(MovementError::HolderLimitReached { max_holders });
+            }
+        }
+    }
+    if supply_delta > 0 {
+        plan.new_supply = Some(metadata.total_supply.checked_add(minted).ok_or(MovementError::Overflow)?);
+    } else if supply_delta < 0 {
+        plan.new_supply = Some(metadata.total_supply.checked_sub(burned)
+            .ok_or(MovementError::Supply("Total supply underflow".to_string()))?);
+    }
+    Ok(plan)
+}
+
+
+// Runs every check apply_movements makes, without writing.
+pub fn check_movements(
+    token_id: TokenId,
+    debits: &[(AccountKey, u128)],
+    credits: &[(AccountKey, u128)],
+    supply_delta: i128,
+) -> Result<(), MovementError> {
+    plan_movements(token_id, debits, credits, supply_delta).map(|_| ())
+}
+
+
+// The one way balances change. Debits leave accounts and credits enter them;
+// a positive `supply_delta` is minted into the credits and a negative one is
+// burned out of the debits, so debits + minted must equal credits + burned.
+// Everything is checked before anything is written, and the total supply
+// moves by exactly `supply_delta`. An account may appear on both sides.
+// A token's `max_holders` stops movements that would add holders past it;
+// movements that keep or shrink the holder count always pass.
+pub fn apply_movements(
+    token_id: TokenId,
+    debits: &[(AccountKey, u128)],
+    credits: &[(AccountKey, u128)],
+    supply_delta: i128,
+) -> Result<(), MovementError> {
+    let plan = plan_movements(token_id, debits, credits, supply_delta)?;
+    if let Some(new_supply) = plan.new_supply {
+        update_total_supply(token_id, new_supply).map_err(MovementError::Supply)?;
+    }
+    let holders_before = get_holder_count(token_id);
+    for (account_key, new_balance) in plan.new_balances {
+        set_balance(token_id, account_key, new_balance);
+    }
+    if plan.joining > plan.leaving {
+        check_holder_soft_cap(token_id, holders_before);
+    }
+    Ok(())
+}
+
+
+// Announced once per upward crossing of HOLDER_SOFT_CAP_WARNING_PERCENT of the
+// ledger-wide soft cap. Nothing is refused at the soft cap.
+fn check_holder_soft_cap(token_id: TokenId, holders_before: u64) {
+    let soft_cap = get_holder_soft_cap();
+    if soft_cap == 0 {
+        return;
+    }
+    let threshold = (soft_cap as u128 * constants::HOLDER_SOFT_CAP_WARNING_PERCENT as u128).div_ceil(100) as u64;
+    let holders = get_holder_count(token_id);
+    if holders_before < threshold && holders >= threshold {
+        #[cfg(feature = "notifications")]
+        crate::notifications::publish_holder_soft_cap_warning(token_id, holders, soft_cap);
+    }
+}
+
+
+// Imported balances are backed by the supply declared when the import opened,
+// not by movements; finalize_import checks they add up to it.
+pub(crate) fn set_imported_balance(token_id: TokenId, account_key: AccountKey, amount: u128) -> Result<(), String> {
+    if !is_import_pending(token_id) {
+        return Err("No open import for this token".to_string());
+    }
+    set_balance(token_id, account_key, amount);
+    Ok(())
+}
+
+
+fn bump_seq(seqs: &mut StableBTreeMap<[u8; 32], u64, Memory>, key: [u8; 32]) {
+    let seq = seqs.get(&key).unwrap_or(0);
+    seqs.insert(key, seq + 1);
+}
+
+
+fn bump_mutation_seq(token_id: TokenId) {
+    TOKEN_MUTATION_SEQ.with(|s| bump_seq(&mut s.borrow_mut(), token_id));
+}
+
+
+pub fn get_balance_seq(token_id: TokenId) -> u64 {
+    BALANCE_SEQ.with(|s| s.borrow().get(&token_id).unwrap_or(0))
+}
+
+
+pub fn get_mutation_seq(token_id: TokenId) -> u64 {
+    TOKEN_MUTATION_SEQ.with(|s| s.borrow().get(&token_id).unwrap_or(0))
+}
+
+
+// The treasury and its share of the block's fee, if a commission applied.
+pub fn get_fee_commission(tx_index: u64) -> Option<(AccountKey, u128)> {
+    FEE_COMMISSIONS.with(|c| c.borrow().get(&tx_index))
+}
+
+
+pub fn get_account_balance_seq(account_key: AccountKey) -> u64 {
+    ACCOUNT_BALANCE_SEQ.with(|s| s.borrow().get(&account_key).unwrap_or(0))
+}
+
+
+// Complete only once the account_tokens_index migration has finished.
+pub fn list_account_tokens(account_key: AccountKey) -> Vec<TokenId> {
+    let lower = encode_account_token_key(account_key, [0u8; 32]);
+    let upper = encode_account_token_key(account_key, [0xFF; 32]);
+
+    ACCOUNT_TOKENS.with(|a| {
+        a.borrow()
+            .range(lower..=upper)
+            .map(|(key, _)| {
+                let mut token_id = [0u8; 32];
+                token_id.copy_from_slice(&key[32..64]);
+                token_id
+            })
+            .collect()
+    })
+}
+
+
+// Copies up to `limit` TOKEN_ACCOUNTS entries after `start_after` into ACCOUNT_TOKENS.
+// Returns the number copied and the last key copied.
+pub fn backfill_account_tokens(start_after: Option<[u8; 64]>, limit: usize) -> (u64, Option<[u8; 64]>) {
+    let keys: Vec<[u8; 64]> = TOKEN_ACCOUNTS.with(|t| {
+        let map = t.borrow();
+        match start_after {
+            Some(after) => map.range(after..).map(|(key, _)| key).filter(|key| *key != after).take(limit).collect(),
+            None => map.iter().map(|(key, _)| key).take(limit).collect(),
+        }
+    });
+
+    ACCOUNT_TOKENS.with(|a| {
+        let mut map = a.borrow_mut();
+        for key in &keys {
+            let mut token_id = [0u8; 32];
+            let mut account_key = [0u8; 32];
+            token_id.copy_from_slice(&key[0..32]);
+            account_key.copy_from_slice(&key[32..64]);
+            map.insert(encode_account_token_key(account_key, token_id), ());
+        }
+    });
+
+    (keys.len() as u64, keys.last().copied())
+}
+
+
+// Returns (account_key, balance) pairs of `token_id` after `start_after`, in key order.
+pub fn list_token_holders(token_id: TokenId, start_after: Option<AccountKey>, limit: usize) -> Vec<(AccountKey, u128)> {
+    let lower = encode_token_account_key(token_id, start_after.unwrap_or([0u8; 32]));
+    let upper = encode_token_account_key(token_id, [0xFF; 32]);
+
+    TOKEN_ACCOUNTS.with(|t| {
+        t.borrow()
+            .range(lower..=upper)
+            .map(|(key, _)| key)
+            .filter(|key| start_after.is_none() || *key != lower)
+            .take(limit)
+            .map(|key| {
+                let mut account_key = [0u8; 32];
+                account_key.copy_from_slice(&key[32..64]);
+                (account_key, get_balance(token_id, account_key))
+            })
+            .collect()
+    })
+}
+
+
+fn increment_holder_count(token_id: TokenId) {
+    let count = HOLDER_COUNTS.with(|h| {
+        let mut counts = h.borrow_mut();
+        let current = counts.get(&token_id).unwrap_or(0);
+        counts.insert(token_id, current + 1);
+        current + 1
+    });
+    record_holder_count_snapshot(token_id, count);
+}
+
+fn decrement_holder_count(token_id: TokenId) {
+    let count = HOLDER_COUNTS.with(|h| {
+        let mut counts = h.borrow_mut();
+        let current = counts.get(&token_id).unwrap_or(0);
+        if current > 0 {
+            counts.insert(token_id, current - 1);
+        }
+        current.saturating_sub(1)
+    });
+    record_holder_count_snapshot(token_id, count);
+}
+
+
+fn current_day() -> u64 {
+    runtime::time() / constants::NANOS_PER_DAY
+}
+
+
+fn record_holder_count_snapshot(token_id: TokenId, count: u64) {
+    let today = current_day();
+    tracked_insert(StructureId::HolderCountHistory, &HOLDER_COUNT_HISTORY, encode_holder_history_key(token_id, today), count);
+    prune_holder_count_history(token_id, today);
+}
+
+
+// Drops snapshots older than the retention window. The newest dropped value is
+// re-anchored at the cutoff day so the series stays continuous from there.
+fn prune_holder_count_history(token_id: TokenId, today: u64) {
+    let cutoff = holder_history_cutoff(today);
+    if cutoff == 0 {
+        return;
+    }
+
+    let start = encode_holder_history_key(token_id, 0);
+    let cutoff_key = encode_holder_history_key(token_id, cutoff);
+
+    let stale: Vec<([u8; 40], u64)> = HOLDER_COUNT_HISTORY.with(|h| {
+        h.borrow()
+            .range(start..cutoff_key)
+            .take(HOLDER_HISTORY_PRUNE_BATCH)
+            .collect()
+    });
+
+    for (key, _) in &stale {
+        tracked_remove(StructureId::HolderCountHistory, &HOLDER_COUNT_HISTORY, key);
+    }
+
+    if stale.len() < HOLDER_HISTORY_PRUNE_BATCH {
+        if let Some((_, last)) = stale.last() {
+            if !HOLDER_COUNT_HISTORY.with(|h| h.borrow().contains_key(&cutoff_key)) {
+                tracked_insert(StructureId::HolderCountHistory, &HOLDER_COUNT_HISTORY, cutoff_key, *last);
+            }
+        }
+    }
+}
+
+
+fn holder_history_cutoff(today: u64) -> u64 {
+    today.saturating_sub((get_holder_history_retention_days() as u64).saturating_sub(1))
+}
+
+
+pub fn get_holder_history_retention_days() -> u32 {
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_HOLDER_HISTORY_RETENTION)
+            .map(|bytes| {
+                let mut buf = [0u8; 4];
+                buf.copy_from_slice(&bytes[..4]);
+                u32::from_be_bytes(buf)
+            })
+            .unwrap_or(constants::DEFAULT_HOLDER_HISTORY_RETENTION_DAYS)
+    })
+}
+
+
+pub fn set_holder_history_retention_days(days: u32) {
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_HOLDER_HISTORY_RETENTION, days.to_be_bytes().to_vec());
+    });
+}
+
+
+fn get_system_u64(key: &[u8; 32], default: u64) -> u64 {
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(key)
+            .map(|bytes| {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[..8]);
+                u64::from_be_bytes(buf)
+            })
+            .unwrap_or(default)
+    })
+}
+
+
+fn set_system_u64(key: [u8; 32], value: u64) {
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(key, value.to_be_bytes().to_vec());
+    });
+}
+
+
+pub fn get_max_tokens() -> u64 {
+    get_system_u64(&KEY_MAX_TOKENS, constants::DEFAULT_MAX_TOKENS)
+}
+
+
+pub fn set_max_tokens(max_tokens: u64) {
+    set_system_u64(KEY_MAX_TOKENS, max_tokens);
+}
+
+
+pub fn get_stable_pages_soft_limit() -> u64 {
+    get_system_u64(&KEY_STABLE_PAGES_SOFT_LIMIT, constants::DEFAULT_STABLE_PAGES_SOFT_LIMIT)
+}
+
+
+pub fn set_stable_pages_soft_limit(pages: u64) {
+    set_system_u64(KEY_STABLE_PAGES_SOFT_LIMIT, pages);
+}
+
+
+// Used by create_token when no fee is given.
+pub fn get_default_token_fee() -> u128 {
+    SYSTEM_STATE.with(|s| s.borrow().get(&KEY_DEFAULT_TOKEN_FEE))
+        .and_then(|bytes| bytes.try_into().ok().map(u128::from_be_bytes))
+        .unwrap_or(constants::DEFAULT_TOKEN_FEE)
+}
+
+
+pub fn set_default_token_fee(fee: u128) {
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_DEFAULT_TOKEN_FEE, fee.to_be_bytes().to_vec());
+    });
+}
+
+
+pub fn is_maintenance_mode() -> bool {
+    get_system_u64(&KEY_MAINTENANCE_MODE, 0) == 1
+}
+
+
+pub fn set_maintenance_mode(enabled: bool) {
+    set_system_u64(KEY_MAINTENANCE_MODE, enabled as u64);
+}
+
+
+// Bitmask of OpKind::bit.
+pub fn get_paused_ops_mask() -> u64 {
+    get_system_u64(&KEY_PAUSED_OPS, 0)
+}
+
+
+pub fn set_paused_ops_mask(mask: u64) {
+    set_system_u64(KEY_PAUSED_OPS, mask);
+}
+
+
+pub fn is_op_paused(op: OpKind) -> bool {
+    get_paused_ops_mask() & op.bit() != 0
+}
+
+
+// Holders per token at which controllers are warned; 0 when unset.
+pub fn get_holder_soft_cap() -> u64 {
+    get_system_u64(&KEY_HOLDER_SOFT_CAP, 0)
+}
+
+
+pub fn set_holder_soft_cap(holders: u64) {
+    set_system_u64(KEY_HOLDER_SOFT_CAP, holders);
+}
+
+
+// How far a created_at_time may lie behind and ahead of ledger time.
+pub fn get_tx_window() -> (u64, u64) {
+    SYSTEM_STATE.with(|s| s.borrow().get(&KEY_TX_WINDOW))
+        .filter(|bytes| bytes.len() == 16)
+        .map(|bytes| {
+            let (past, future) = bytes.split_at(8);
+            (u64::from_be_bytes(past.try_into().unwrap()), u64::from_be_bytes(future.try_into().unwrap()))
+        })
+        .unwrap_or((constants::MAX_PAST_DRIFT, constants::MAX_FUTURE_DRIFT))
+}
+
+
+pub fn max_past_drift() -> u64 {
+    get_tx_window().0
+}
+
+
+pub fn max_future_drift() -> u64 {
+    get_tx_window().1
+}
+
+
+// Also remembers the widest past + future span ever configured, which bounds
+// how far any stored timestamp can be from the ledger time of its block.
+pub fn set_tx_window(past: u64, future: u64) {
+    let widest = widest_tx_window().max(past.saturating_add(future));
+    set_system_u64(KEY_WIDEST_TX_WINDOW, widest);
+    let mut bytes = past.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&future.to_be_bytes());
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_TX_WINDOW, bytes);
+    });
+}
+
+
+pub fn widest_tx_window() -> u64 {
+    get_system_u64(&KEY_WIDEST_TX_WINDOW, constants::MAX_PAST_DRIFT + constants::MAX_FUTURE_DRIFT)
+}
+
+
+// (min_depth, min_age_ns); (0, 0) until a controller sets a policy.
+pub fn get_finality_policy() -> (u64, u64) {
+    SYSTEM_STATE.with(|s| s.borrow().get(&KEY_FINALITY_POLICY))
+        .filter(|bytes| bytes.len() == 16)
+        .map(|bytes| {
+            let (depth, age) = bytes.split_at(8);
+            (u64::from_be_bytes(depth.try_into().unwrap()), u64::from_be_bytes(age.try_into().unwrap()))
+        })
+        .unwrap_or((0, 0))
+}
+
+
+pub fn set_finality_policy(min_depth: u64, min_age_ns: u64) {
+    let mut bytes = min_depth.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&min_age_ns.to_be_bytes());
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_FINALITY_POLICY, bytes);
+    });
+}
+
+
+pub fn is_privacy_mode() -> bool {
+    get_system_u64(&KEY_PRIVACY_MODE, 0) == 1
+}
+
+
+pub fn set_privacy_mode(enabled: bool) {
+    set_system_u64(KEY_PRIVACY_MODE, enabled as u64);
+}
+
+
+pub fn is_auditor(p: &Principal) -> bool {
+    StoredPrincipal::from_principal(p)
+        .is_ok_and(|stored| AUDITORS.with(|a| a.borrow().contains_key(&stored)))
+}
+
+
+pub fn add_auditor(p: Principal) -> Result<(), String> {
+    let stored = StoredPrincipal::from_principal(&p)?;
+    AUDITORS.with(|a| {
+        let mut map = a.borrow_mut();
+        if !map.contains_key(&stored) && map.len() >= constants::MAX_AUDITORS {
+            return Err(format!("At most {} auditors", constants::MAX_AUDITORS));
+        }
+        map.insert(stored, 1u8);
+        Ok(())
+    })
+}
+
+
+pub fn remove_auditor(p: Principal) -> Result<(), String> {
+    let stored = StoredPrincipal::from_principal(&p)?;
+    AUDITORS.with(|a| a.borrow_mut().remove(&stored));
+    Ok(())
+}
+
+
+pub fn list_auditors() -> Vec<Principal> {
+    AUDITORS.with(|a| {
+        a.borrow().iter().filter_map(|(stored, _)| stored.to_principal().ok()).collect()
+    })
+}
+
+
+fn encode_api_grant_key(token_id: TokenId, principal: &StoredPrincipal) -> [u8; 62] {
+    let mut key = [0u8; 62];
+    key[..32].copy_from_slice(&token_id);
+    key[32..].copy_from_slice(&principal.to_bytes());
+    key
+}
+
+
+// Expired grants are returned as stored; callers decide what expiry means.
+pub fn get_api_grant(token_id: TokenId, principal: &Principal) -> Option<ApiAccessGrant> {
+    let stored = StoredPrincipal::from_principal(principal).ok()?;
+    API_ACCESS_GRANTS.with(|g| g.borrow().get(&encode_api_grant_key(token_id, &stored)))
+}
+
+
+// Replaces any grant the principal already holds for the token.
+pub fn set_api_grant(token_id: TokenId, grant: ApiAccessGrant) -> Result<(), String> {
+    let stored = StoredPrincipal::from_principal(&grant.principal)?;
+    let key = encode_api_grant_key(token_id, &stored);
+    let is_new = API_ACCESS_GRANTS.with(|g| !g.borrow().contains_key(&key));
+    if is_new && list_api_grants(token_id).len() >= constants::MAX_API_GRANTS_PER_TOKEN {
+        return Err(format!("At most {} API grants per token", constants::MAX_API_GRANTS_PER_TOKEN));
+    }
+    API_ACCESS_GRANTS.with(|g| g.borrow_mut().insert(key, grant));
+    Ok(())
+}
+
+
+pub fn remove_api_grant(token_id: TokenId, principal: &Principal) -> Option<ApiAccessGrant> {
+    let stored = StoredPrincipal::from_principal(principal).ok()?;
+    API_ACCESS_GRANTS.with(|g| g.borrow_mut().remove(&encode_api_grant_key(token_id, &stored)))
+}
+
+
+pub fn list_api_grants(token_id: TokenId) -> Vec<ApiAccessGrant> {
+    let mut start = [0u8; 62];
+    start[..32].copy_from_slice(&token_id);
+    API_ACCESS_GRANTS.with(|g| {
+        g.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key[..32] == token_id)
+            .map(|(_, grant)| grant)
+            .collect()
+    })
+}
+
+
+
+// Locales are at most five bytes ("xx-XX"); shorter ones are zero-padded.
+fn encode_localization_key(token_id: TokenId, locale: &str) -> [u8; 37] {
+    let mut key = [0u8; 37];
+    key[..32].copy_from_slice(&token_id);
+    key[32..32 + locale.len()].copy_from_slice(locale.as_bytes());
+    key
+}
+
+
+pub fn get_token_localization(token_id: TokenId, locale: &str) -> Option<crate::types::TokenLocalization> {
+    TOKEN_LOCALIZATIONS.with(|l| l.borrow().get(&encode_localization_key(token_id, locale)))
+}
+
+
+// Replaces the token's entry for the locale if there is one.
+pub fn set_token_localization(token_id: TokenId, localization: crate::types::TokenLocalization) -> Result<(), String> {
+    let key = encode_localization_key(token_id, &localization.locale);
+    let is_new = TOKEN_LOCALIZATIONS.with(|l| !l.borrow().contains_key(&key));
+    if is_new && list_token_localizations(token_id).len() >= constants::MAX_TOKEN_LOCALES {
+        return Err(format!("At most {} locales per token", constants::MAX_TOKEN_LOCALES));
+    }
+    TOKEN_LOCALIZATIONS.with(|l| l.borrow_mut().insert(key, localization));
+    Ok(())
+}
+
+
+pub fn remove_token_localization(token_id: TokenId, locale: &str) -> Option<crate::types::TokenLocalization> {
+    TOKEN_LOCALIZATIONS.with(|l| l.borrow_mut().remove(&encode_localization_key(token_id, locale)))
+}
+
+
+pub fn list_token_localizations(token_id: TokenId) -> Vec<crate::types::TokenLocalization> {
+    let mut start = [0u8; 37];
+    start[..32].copy_from_slice(&token_id);
+    TOKEN_LOCALIZATIONS.with(|l| {
+        l.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key[..32] == token_id)
+            .map(|(_, localization)| localization)
+            .collect()
+    })
+}
+
+
+fn encode_account_reservation_key(token_id: TokenId, account_key: AccountKey, id: u64) -> [u8; 72] {
+    let mut key = [0u8; 72];
+    key[..32].copy_from_slice(&token_id);
+    key[32..64].copy_from_slice(&account_key);
+    key[64..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+
+fn encode_reservation_expiry_key(expires_at: u64, id: u64) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&expires_at.to_be_bytes());
+    key[8..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+
+pub fn next_reservation_id() -> u64 {
+    let id = get_system_u64(&KEY_NEXT_RESERVATION_ID, 0);
+    set_system_u64(KEY_NEXT_RESERVATION_ID, id + 1);
+    id
+}
+
+
+pub fn get_reservation(id: u64) -> Option<crate::types::Reservation> {
+    RESERVATIONS.with(|r| r.borrow().get(&id))
+}
+
+
+pub fn insert_reservation(reservation: crate::types::Reservation) {
+    let account_key = reservation.account.to_key();
+    ACCOUNT_RESERVATIONS.with(|a| a.borrow_mut().insert(encode_account_reservation_key(reservation.token_id, account_key, reservation.id), ()));
+    RESERVATION_EXPIRY.with(|e| e.borrow_mut().insert(encode_reservation_expiry_key(reservation.expires_at, reservation.id), ()));
+    RESERVATIONS.with(|r| r.borrow_mut().insert(reservation.id, reservation));
+}
+
+
+pub fn remove_reservation(id: u64) -> Option<crate::types::Reservation> {
+    let reservation = RESERVATIONS.with(|r| r.borrow_mut().remove(&id))?;
+    let account_key = reservation.account.to_key();
+    ACCOUNT_RESERVATIONS.with(|a| a.borrow_mut().remove(&encode_account_reservation_key(reservation.token_id, account_key, id)));
+    RESERVATION_EXPIRY.with(|e| e.borrow_mut().remove(&encode_reservation_expiry_key(reservation.expires_at, id)));
+    Some(reservation)
+}
+
+
+// The account's holds on the token, expired or not.
+pub fn list_account_reservations(token_id: TokenId, account_key: AccountKey) -> Vec<crate::types::Reservation> {
+    let start = encode_account_reservation_key(token_id, account_key, 0);
+    let ids: Vec<u64> = ACCOUNT_RESERVATIONS.with(|a| {
+        a.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key[..64] == start[..64])
+            .map(|(key, _)| u64::from_be_bytes(key[64..].try_into().unwrap()))
+            .collect()
+    });
+    ids.into_iter().filter_map(get_reservation).collect()
+}
+
+
+// What unexpired holds and unspent fee sponsorships keep out of reach; never
+// more than the balance.
+pub fn get_reserved_balance(token_id: TokenId, account_key: AccountKey) -> u128 {
+    let now = runtime::time();
+    let held = list_account_reservations(token_id, account_key)
+        .iter()
+        .filter(|r| !r.is_expired(now))
+        .fold(get_sponsor_hold(token_id, account_key), |sum, r| sum.saturating_add(r.amount));
+    held.min(get_balance(token_id, account_key))
+}
+
+
+// The balance every debit path checks against.
+pub fn get_spendable_balance(token_id: TokenId, account_key: AccountKey) -> u128 {
+    get_balance(token_id, account_key) - get_reserved_balance(token_id, account_key)
+}
+
+
+// Takes the earliest hold that expired by `expired_by` off the books. Returns
+// whether there was one.
+pub fn sweep_expired_reservation(expired_by: u64) -> bool {
+    let upper = encode_reservation_expiry_key(expired_by, u64::MAX);
+    let Some(key) = RESERVATION_EXPIRY.with(|e| e.borrow().range(..=upper).next().map(|(key, _)| key)) else {
+        return false;
+    };
+    let id = u64::from_be_bytes(key[8..].try_into().unwrap());
+    if remove_reservation(id).is_none() {
+        RESERVATION_EXPIRY.with(|e| e.borrow_mut().remove(&key));
+    }
+    true
+}
+
+
+pub fn count_expired_reservations(expired_by: u64, limit: usize) -> u64 {
+    let upper = encode_reservation_expiry_key(expired_by, u64::MAX);
+    RESERVATION_EXPIRY.with(|e| e.borrow().range(..=upper).take(limit).count() as u64)
+}
+
+
+fn encode_snapshot_balance_key(snapshot_id: u64, account_key: AccountKey) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..8].copy_from_slice(&snapshot_id.to_be_bytes());
+    key[8..].copy_from_slice(&account_key);
+    key
+}
+
+
+fn encode_airdrop_key(snapshot_id: u64, reward_token: TokenId) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..8].copy_from_slice(&snapshot_id.to_be_bytes());
+    key[8..].copy_from_slice(&reward_token);
+    key
+}
+
+
+fn with_open_snapshots<R>(f: impl FnOnce(&[OpenSnapshot]) -> R) -> R {
+    OPEN_SNAPSHOTS.with(|o| {
+        let mut open = o.borrow_mut();
+        let open = open.get_or_insert_with(|| match holds_data(memory_ids::BALANCE_SNAPSHOTS) {
+            true => BALANCE_SNAPSHOTS.with(|s| s.borrow().iter()
+                .filter(|(_, snapshot)| !snapshot.complete)
+                .map(|(id, snapshot)| (id, snapshot.token_id, snapshot.cursor))
+                .collect()),
+            false => Vec::new(),
+        });
+        f(open)
+    })
+}
+
+
+fn put_balance_snapshot(snapshot: crate::types::BalanceSnapshot) {
+    BALANCE_SNAPSHOTS.with(|s| s.borrow_mut().insert(snapshot.id, snapshot));
+    OPEN_SNAPSHOTS.with(|o| *o.borrow_mut() = None);
+}
+
+
+// Before a balance an open snapshot has not copied yet first changes, the
+// snapshot keeps what it was.
+fn save_for_open_snapshots(token_id: TokenId, account_key: AccountKey, old_balance: u128) {
+    let pending: Vec<u64> = with_open_snapshots(|open| open.iter()
+        .filter(|(_, token, cursor)| *token == token_id && cursor.is_none_or(|copied| account_key > copied))
+        .map(|(id, _, _)| *id)
+        .collect());
+    for id in pending {
+        let key = encode_snapshot_balance_key(id, account_key);
+        SNAPSHOT_BALANCES.with(|s| {
+            let mut balances = s.borrow_mut();
+            if !balances.contains_key(&key) {
+                balances.insert(key, old_balance);
+            }
+        });
+    }
+}
+
+
+pub fn count_open_snapshots() -> usize {
+    with_open_snapshots(|open| open.len())
+}
+
+
+// Opens a snapshot of the token's balances as they stand now. Its holders are
+// copied by copy_snapshot_chunk.
+pub fn open_balance_snapshot(token_id: TokenId) -> Result<crate::types::BalanceSnapshot, MetadataError> {
+    let metadata = get_token_metadata(token_id)?;
+    let id = get_system_u64(&KEY_NEXT_SNAPSHOT_ID, 0);
+    set_system_u64(KEY_NEXT_SNAPSHOT_ID, id + 1);
+    let snapshot = crate::types::BalanceSnapshot {
+        id,
+        token_id,
+        taken_at: runtime::time(),
+        tx_count: get_transaction_count(),
+        holders: get_holder_count(token_id),
+        total_supply: metadata.total_supply,
+        cursor: None,
+        complete: false,
+    };
+    put_balance_snapshot(snapshot.clone());
+    Ok(snapshot)
+}
+
+
+// Copies up to `limit` holders past the cursor, leaving those a balance change
+// already saved, and completes the snapshot once none are left.
+pub fn copy_snapshot_chunk(snapshot_id: u64, limit: usize) -> Option<crate::types::BalanceSnapshot> {
+    let mut snapshot = get_balance_snapshot(snapshot_id)?;
+    if snapshot.complete {
+        return Some(snapshot);
+    }
+    let holders = list_token_holders(snapshot.token_id, snapshot.cursor, limit);
+    SNAPSHOT_BALANCES.with(|s| {
+        let mut balances = s.borrow_mut();
+        for (account_key, balance) in &holders {
+            let key = encode_snapshot_balance_key(snapshot_id, *account_key);
+            if !balances.contains_key(&key) {
+                balances.insert(key, *balance);
+            }
+        }
+    });
+    if let Some((last, _)) = holders.last() {
+        snapshot.cursor = Some(*last);
+    }
+    snapshot.complete = holders.len() < limit;
+    put_balance_snapshot(snapshot.clone());
+    Some(snapshot)
+}
+
+
+pub fn get_balance_snapshot(snapshot_id: u64) -> Option<crate::types::BalanceSnapshot> {
+    BALANCE_SNAPSHOTS.with(|s| s.borrow().get(&snapshot_id))
+}
+
+
+// Snapshot entries in account key order after `start_after`, zeros included.
+pub fn list_snapshot_balances(snapshot_id: u64, start_after: Option<AccountKey>, limit: usize) -> Vec<(AccountKey, u128)> {
+    let lower = encode_snapshot_balance_key(snapshot_id, start_after.unwrap_or([0u8; 32]));
+    let upper = encode_snapshot_balance_key(snapshot_id, [0xFF; 32]);
+    SNAPSHOT_BALANCES.with(|s| {
+        s.borrow()
+            .range(lower..=upper)
+            .filter(|(key, _)| start_after.is_none() || *key != lower)
+            .take(limit)
+            .map(|(key, balance)| {
+                let mut account_key = [0u8; 32];
+                account_key.copy_from_slice(&key[8..]);
+                (account_key, balance)
+            })
+            .collect()
+    })
+}
+
+
+pub fn get_airdrop(snapshot_id: u64, reward_token: TokenId) -> Option<crate::types::Airdrop> {
+    AIRDROPS.with(|a| a.borrow().get(&encode_airdrop_key(snapshot_id, reward_token)))
+}
+
+
+pub fn set_airdrop(airdrop: crate::types::Airdrop) {
+    let key = encode_airdrop_key(airdrop.snapshot_id, airdrop.reward_token);
+    AIRDROPS.with(|a| a.borrow_mut().insert(key, airdrop));
+}
+
+
+fn encode_beneficiary_sponsorship_key(token_id: TokenId, beneficiary_key: AccountKey, id: u64) -> [u8; 72] {
+    let mut key = [0u8; 72];
+    key[..32].copy_from_slice(&token_id);
+    key[32..64].copy_from_slice(&beneficiary_key);
+    key[64..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+
+fn encode_sponsor_sponsorship_key(sponsor_key: AccountKey, id: u64) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..32].copy_from_slice(&sponsor_key);
+    key[32..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+
+fn encode_sponsor_hold_key(token_id: TokenId, sponsor_key: AccountKey) -> [u8; 64] {
+    let mut key = [0u8; 64];
+    key[..32].copy_from_slice(&token_id);
+    key[32..].copy_from_slice(&sponsor_key);
+    key
+}
+
+
+pub fn next_sponsorship_id() -> u64 {
+    let id = get_system_u64(&KEY_NEXT_SPONSORSHIP_ID, 0);
+    set_system_u64(KEY_NEXT_SPONSORSHIP_ID, id + 1);
+    id
+}
+
+
+pub fn get_sponsorship(id: u64) -> Option<crate::types::Sponsorship> {
+    SPONSORSHIPS.with(|s| s.borrow().get(&id))
+}
+
+
+// Unspent budget of the sponsor's sponsorships on the token, expired ones
+// included until they are removed.
+pub fn get_sponsor_hold(token_id: TokenId, sponsor_key: AccountKey) -> u128 {
+    SPONSOR_HOLDS.with(|h| h.borrow().get(&encode_sponsor_hold_key(token_id, sponsor_key)).unwrap_or(0))
+}
+
+
+fn adjust_sponsor_hold(token_id: TokenId, sponsor_key: AccountKey, added: u128, removed: u128) {
+    let key = encode_sponsor_hold_key(token_id, sponsor_key);
+    SPONSOR_HOLDS.with(|h| {
+        let mut holds = h.borrow_mut();
+        let held = holds.get(&key).unwrap_or(0).saturating_add(added).saturating_sub(removed);
+        if held == 0 {
+            holds.remove(&key);
+        } else {
+            holds.insert(key, held);
+        }
+    });
+}
+
+
+pub fn insert_sponsorship(sponsorship: crate::types::Sponsorship) {
+    let sponsor_key = sponsorship.sponsor.to_key();
+    let beneficiary_key = sponsorship.beneficiary.to_key();
+    BENEFICIARY_SPONSORSHIPS.with(|b| b.borrow_mut().insert(encode_beneficiary_sponsorship_key(sponsorship.token_id, beneficiary_key, sponsorship.id), ()));
+    SPONSOR_SPONSORSHIPS.with(|s| s.borrow_mut().insert(encode_sponsor_sponsorship_key(sponsor_key, sponsorship.id), ()));
+    if let Some(expires_at) = sponsorship.expires_at {
+        SPONSORSHIP_EXPIRY.with(|e| e.borrow_mut().insert(encode_reservation_expiry_key(expires_at, sponsorship.id), ()));
+    }
+    adjust_sponsor_hold(sponsorship.token_id, sponsor_key, sponsorship.budget, 0);
+    SPONSORSHIPS.with(|s| s.borrow_mut().insert(sponsorship.id, sponsorship));
+}
+
+
+// Takes `fee` out of the budget and releases it from the sponsor's hold; the
+// caller debits it from the sponsor's balance. Returns the sponsorship as it
+// is now, or None when the budget cannot cover the fee.
+pub fn draw_sponsorship(id: u64, fee: u128) -> Option<crate::types::Sponsorship> {
+    let mut sponsorship = get_sponsorship(id).filter(|s| s.budget >= fee)?;
+    sponsorship.budget -= fee;
+    sponsorship.spent = sponsorship.spent.saturating_add(fee);
+    adjust_sponsor_hold(sponsorship.token_id, sponsorship.sponsor.to_key(), 0, fee);
+    SPONSORSHIPS.with(|s| s.borrow_mut().insert(id, sponsorship.clone()));
+    Some(sponsorship)
+}
+
+
+// Ends the sponsorship; whatever budget is left is released to the sponsor.
+pub fn remove_sponsorship(id: u64) -> Option<crate::types::Sponsorship> {
+    let sponsorship = SPONSORSHIPS.with(|s| s.borrow_mut().remove(&id))?;
+    let sponsor_key = sponsorship.sponsor.to_key();
+    let beneficiary_key = sponsorship.beneficiary.to_key();
+    BENEFICIARY_SPONSORSHIPS.with(|b| b.borrow_mut().remove(&encode_beneficiary_sponsorship_key(sponsorship.token_id, beneficiary_key, id)));
+    SPONSOR_SPONSORSHIPS.with(|s| s.borrow_mut().remove(&encode_sponsor_sponsorship_key(sponsor_key, id)));
+    if let Some(expires_at) = sponsorship.expires_at {
+        SPONSORSHIP_EXPIRY.with(|e| e.borrow_mut().remove(&encode_reservation_expiry_key(expires_at, id)));
+    }
+    adjust_sponsor_hold(sponsorship.token_id, sponsor_key, 0, sponsorship.budget);
+    Some(sponsorship)
+}
+
+
+// The beneficiary's sponsorships on the token, oldest first, expired or not.
+pub fn list_beneficiary_sponsorships(token_id: TokenId, beneficiary_key: AccountKey) -> Vec<crate::types::Sponsorship> {
+    let start = encode_beneficiary_sponsorship_key(token_id, beneficiary_key, 0);
+    let ids: Vec<u64> = BENEFICIARY_SPONSORSHIPS.with(|b| {
+        b.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key[..64] == start[..64])
+            .map(|(key, _)| u64::from_be_bytes(key[64..].try_into().unwrap()))
+            .collect()
+    });
+    ids.into_iter().filter_map(get_sponsorship).collect()
+}
+
+
+// The sponsor's sponsorships across tokens in id order, starting after
+// `start_after`.
+pub fn list_sponsor_sponsorships(sponsor_key: AccountKey, start_after: Option<u64>, limit: usize) -> Vec<crate::types::Sponsorship> {
+    let first_id = match start_after {
+        Some(id) => match id.checked_add(1) {
+            Some(next) => next,
+            None => return Vec::new(),
+        },
+        None => 0,
+    };
+    let start = encode_sponsor_sponsorship_key(sponsor_key, first_id);
+    let ids: Vec<u64> = SPONSOR_SPONSORSHIPS.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key[..32] == sponsor_key)
+            .take(limit)
+            .map(|(key, _)| u64::from_be_bytes(key[32..].try_into().unwrap()))
+            .collect()
+    });
+    ids.into_iter().filter_map(get_sponsorship).collect()
+}
+
+
+// Ends the earliest sponsorship that expired by `expired_by`, releasing its
+// budget. Returns whether there was one.
+pub fn sweep_expired_sponsorship(expired_by: u64) -> bool {
+    let upper = encode_reservation_expiry_key(expired_by, u64::MAX);
+    let Some(key) = SPONSORSHIP_EXPIRY.with(|e| e.borrow().range(..=upper).next().map(|(key, _)| key)) else {
+        return false;
+    };
+    let id = u64::from_be_bytes(key[8..].try_into().unwrap());
+    if remove_sponsorship(id).is_none() {
+        SPONSORSHIP_EXPIRY.with(|e| e.borrow_mut().remove(&key));
+    }
+    true
+}
+
+
+pub fn count_expired_sponsorships(expired_by: u64, limit: usize) -> u64 {
+    let upper = encode_reservation_expiry_key(expired_by, u64::MAX);
+    SPONSORSHIP_EXPIRY.with(|e| e.borrow().range(..=upper).take(limit).count() as u64)
+}
+
+
+pub fn get_token_import(token_id: TokenId) -> Option<crate::types::TokenImport> {
+    TOKEN_IMPORTS.with(|i| i.borrow().get(&token_id))
+}
+
+
+pub fn is_import_pending(token_id: TokenId) -> bool {
+    TOKEN_IMPORTS.with(|i| i.borrow().contains_key(&token_id))
+}
+
+
+pub fn set_token_import(token_id: TokenId, import: crate::types::TokenImport) {
+    TOKEN_IMPORTS.with(|i| i.borrow_mut().insert(token_id, import));
+}
+
+
+pub fn remove_token_import(token_id: TokenId) -> Option<crate::types::TokenImport> {
+    TOKEN_IMPORTS.with(|i| i.borrow_mut().remove(&token_id))
+}
+
+
+// Set once, at init of a dev build. There is no way to clear it.
+#[cfg(feature = "dev")]
+pub fn is_non_production() -> bool {
+    get_system_u64(&KEY_NON_PRODUCTION, 0) == 1
+}
+
+
+#[cfg(feature = "dev")]
+pub fn set_non_production() {
+    set_system_u64(KEY_NON_PRODUCTION, 1);
+}
+
+
+pub fn get_recovery() -> Option<crate::types::StoredRecovery> {
+    use candid::Decode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_RECOVERY)
+            .and_then(|bytes| Decode!(&bytes, crate::types::StoredRecovery).ok())
+    })
+}
+
+
+pub fn set_recovery(recovery: Option<&crate::types::StoredRecovery>) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        match recovery {
+            Some(r) => state.insert(KEY_RECOVERY, Encode!(r).expect("Recovery state is always encodable")),
+            None => state.remove(&KEY_RECOVERY),
+        };
+    });
+}
+
+
+#[cfg(feature = "notifications")]
+pub fn next_balance_alert_id() -> u64 {
+    let id = get_system_u64(&KEY_NEXT_BALANCE_ALERT_ID, 0);
+    set_system_u64(KEY_NEXT_BALANCE_ALERT_ID, id + 1);
+    id
+}
+
+
+pub fn get_fee_governance() -> crate::types::StoredFeeGovernance {
+    use candid::Decode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_FEE_GOVERNANCE)
+            .and_then(|bytes| Decode!(&bytes, crate::types::StoredFeeGovernance).ok())
+            .unwrap_or_default()
+    })
+}
+
+
+pub fn set_fee_governance(governance: &crate::types::StoredFeeGovernance) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_FEE_GOVERNANCE, Encode!(governance).expect("Fee governance state is always encodable"));
+    });
+}
+
+
+// "icrc151:mig:" followed by the first 20 bytes of the migration name.
+fn migration_key(name: &str) -> [u8; 32] {
+    const PREFIX: &[u8] = b"icrc151:mig:";
+    let mut key = [0u8; 32];
+    key[..PREFIX.len()].copy_from_slice(PREFIX);
+    let len = name.len().min(32 - PREFIX.len());
+    key[PREFIX.len()..PREFIX.len() + len].copy_from_slice(&name.as_bytes()[..len]);
+    key
+}
+
+
+pub fn get_migration_progress(name: &str) -> Option<crate::types::StoredMigrationProgress> {
+    use candid::Decode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&migration_key(name))
+            .and_then(|bytes| Decode!(&bytes, crate::types::StoredMigrationProgress).ok())
+    })
+}
+
+
+pub fn set_migration_progress(name: &str, progress: &crate::types::StoredMigrationProgress) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(migration_key(name), Encode!(progress).expect("Migration progress is always encodable"));
+    });
+}
+
+
+// "icrc151:gc:" followed by the first 21 bytes of the task name.
+fn maintenance_task_key(name: &str) -> [u8; 32] {
+    const PREFIX: &[u8] = b"icrc151:gc:";
+    let mut key = [0u8; 32];
+    key[..PREFIX.len()].copy_from_slice(PREFIX);
+    let len = name.len().min(32 - PREFIX.len());
+    key[PREFIX.len()..PREFIX.len() + len].copy_from_slice(&name.as_bytes()[..len]);
+    key
+}
+
+
+pub fn get_maintenance_task(name: &str) -> crate::types::StoredMaintenanceTask {
+    use candid::Decode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&maintenance_task_key(name))
+            .and_then(|bytes| Decode!(&bytes, crate::types::StoredMaintenanceTask).ok())
+            .unwrap_or_default()
+    })
+}
+
+
+pub fn set_maintenance_task(name: &str, task: &crate::types::StoredMaintenanceTask) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(maintenance_task_key(name), Encode!(task).expect("Maintenance task state is always encodable"));
+    });
+}
+
+
+pub fn get_maintenance_tick() -> crate::types::StoredMaintenanceTick {
+    use candid::Decode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_MAINTENANCE_TICK)
+            .and_then(|bytes| Decode!(&bytes, crate::types::StoredMaintenanceTick).ok())
+            .unwrap_or_default()
+    })
+}
+
+
+pub fn set_maintenance_tick(tick: &crate::types::StoredMaintenanceTick) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_MAINTENANCE_TICK, Encode!(tick).expect("Maintenance tick state is always encodable"));
+    });
+}
+
+
+pub fn get_burn_receipt(tx_index: u64) -> Option<crate::types::StoredBurnReceipt> {
+    BURN_RECEIPTS.with(|r| r.borrow().get(&tx_index))
+}
+
+
+pub fn set_burn_receipt(tx_index: u64, receipt: crate::types::StoredBurnReceipt) {
+    BURN_RECEIPTS.with(|r| {
+        r.borrow_mut().insert(tx_index, receipt);
+    });
+}
+
+
+// Bit 0: the failure log is on. Bit 1: it records amounts.
+pub fn get_failure_log_flags() -> u64 {
+    get_system_u64(&KEY_FAILURE_LOG, 0)
+}
+
+
+pub fn set_failure_log_flags(flags: u64) {
+    set_system_u64(KEY_FAILURE_LOG, flags);
+}
+
+
+pub fn set_failure(slot: u32, failure: crate::types::StoredFailure) {
+    FAILURE_LOG.with(|f| {
+        f.borrow_mut().insert(slot, failure);
+    });
+}
+
+
+pub fn list_failures() -> Vec<crate::types::StoredFailure> {
+    FAILURE_LOG.with(|f| f.borrow().iter().map(|(_, failure)| failure).collect())
+}
+
+
+pub fn clear_failures() -> u64 {
+    FAILURE_LOG.with(|f| {
+        let mut log = f.borrow_mut();
+        let slots: Vec<u32> = log.iter().map(|(slot, _)| slot).collect();
+        for slot in &slots {
+            log.remove(slot);
+        }
+        slots.len() as u64
+    })
+}
+
+
+// Controller-set operation costs; kinds not listed use the built-in defaults.
+pub fn get_operation_costs() -> Vec<(crate::costs::OperationKind, crate::costs::OperationCost)> {
+    use candid::Decode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_OPERATION_COSTS)
+            .and_then(|bytes| Decode!(&bytes, Vec<(crate::costs::OperationKind, crate::costs::OperationCost)>).ok())
+            .unwrap_or_default()
+    })
+}
+
+
+pub fn set_operation_costs(costs: &[(crate::costs::OperationKind, crate::costs::OperationCost)]) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_OPERATION_COSTS, Encode!(&costs).expect("Operation costs are always encodable"));
+    });
+}
+
+
+pub fn record_legacy_call(method: &str) {
+    let mut key = [0u8; 32];
+    let len = method.len().min(32);
+    key[..len].copy_from_slice(&method.as_bytes()[..len]);
+
+    LEGACY_CALL_COUNTS.with(|c| {
+        let mut counts = c.borrow_mut();
+        let count = counts.get(&key).unwrap_or(0);
+        counts.insert(key, count.saturating_add(1));
+    });
+}
+
+
+thread_local! {
+    // The official account registry by account key, cached because every
+    // credit looks at it. None until the first read after an upgrade.
+    static OFFICIAL_ACCOUNTS: RefCell<Option<BTreeMap<AccountKey, crate::types::OfficialAccount>>> = const { RefCell::new(None) };
+}
+
+
+fn with_official_accounts<R>(f: impl FnOnce(&BTreeMap<AccountKey, crate::types::OfficialAccount>) -> R) -> R {
+    OFFICIAL_ACCOUNTS.with(|o| {
+        let mut cached = o.borrow_mut();
+        let accounts = cached.get_or_insert_with(|| {
+            use candid::Decode;
+            SYSTEM_STATE.with(|s| s.borrow().get(&KEY_OFFICIAL_ACCOUNTS))
+                .and_then(|bytes| Decode!(&bytes, Vec<crate::types::OfficialAccount>).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|official| (official.account.to_key(), official))
+                .collect()
+        });
+        f(accounts)
+    })
+}
+
+
+pub fn get_official_account(account_key: AccountKey) -> Option<crate::types::OfficialAccount> {
+    with_official_accounts(|accounts| accounts.get(&account_key).cloned())
+}
+
+
+pub fn list_official_accounts() -> Vec<crate::types::OfficialAccount> {
+    with_official_accounts(|accounts| accounts.values().cloned().collect())
+}
+
+
+pub fn set_official_accounts(accounts: Vec<crate::types::OfficialAccount>) {
+    use candid::Encode;
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_OFFICIAL_ACCOUNTS, Encode!(&accounts).expect("Official accounts are always encodable"));
+    });
+    OFFICIAL_ACCOUNTS.with(|o| {
+        *o.borrow_mut() = Some(accounts.into_iter().map(|official| (official.account.to_key(), official)).collect());
+    });
+}
+
+
+// Methods without an entry are Allowed.
+pub fn get_deprecation_modes() -> Vec<(String, crate::types::DeprecationMode)> {
+    use candid::Decode;
+    SYSTEM_STATE.with(|s| s.borrow().get(&KEY_DEPRECATION_MODES))
+        .and_then(|bytes| Decode!(&bytes, Vec<(String, crate::types::DeprecationMode)>).ok())
+        .unwrap_or_default()
+}
+
+
+pub fn get_deprecation_mode(method: &str) -> crate::types::DeprecationMode {
+    get_deprecation_modes()
+        .into_iter()
+        .find(|(m, _)| m == method)
+        .map(|(_, mode)| mode)
+        .unwrap_or_default()
+}
+
+
+pub fn set_deprecation_mode(method: &str, mode: crate::types::DeprecationMode) {
+    use candid::Encode;
+    let mut modes = get_deprecation_modes();
+    modes.retain(|(m, _)| m != method);
+    if mode != crate::types::DeprecationMode::Allowed {
+        modes.push((method.to_string(), mode));
+    }
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_DEPRECATION_MODES, Encode!(&modes).expect("Deprecation modes are always encodable"));
+    });
+}
+
+
+pub fn list_legacy_call_counts() -> Vec<(String, u64)> {
+    LEGACY_CALL_COUNTS.with(|c| {
+        c.borrow()
+            .iter()
+            .map(|(key, count)| {
+                let len = key.iter().position(|b| *b == 0).unwrap_or(32);
+                (String::from_utf8_lossy(&key[..len]).into_owned(), count)
+            })
+            .collect()
+    })
+}
+
+
+pub fn record_admin_action(action: &str, detail: String) -> u64 {
+    ADMIN_AUDIT_LOG.with(|l| {
+        let mut log = l.borrow_mut();
+        let index = log.last_key_value().map_or(0, |(k, _)| k + 1);
+        log.insert(index, crate::types::AdminAuditEntry {
+            index,
+            caller: runtime::caller(),
+            timestamp: runtime::time(),
+            action: action.to_string(),
+            detail,
+        });
+        index
+    })
+}
+
+
+pub fn list_admin_actions(start: u64, limit: usize) -> Vec<crate::types::AdminAuditEntry> {
+    ADMIN_AUDIT_LOG.with(|l| l.borrow().range(start..).take(limit).map(|(_, e)| e).collect())
+}
+
+
+pub fn get_netting_authorization(account_key: AccountKey, counterparty_key: AccountKey) -> Option<u64> {
+    NETTING_AUTHORIZATIONS.with(|n| n.borrow().get(&encode_netting_key(account_key, counterparty_key)))
+}
+
+
+pub fn set_netting_authorization(account_key: AccountKey, counterparty_key: AccountKey, authorized_at: Option<u64>) {
+    let key = encode_netting_key(account_key, counterparty_key);
+    NETTING_AUTHORIZATIONS.with(|n| match authorized_at {
+        Some(at) => n.borrow_mut().insert(key, at),
+        None => n.borrow_mut().remove(&key),
+    });
+}
+
+
+// Assigns the next settlement index and stores the record built for it.
+pub fn record_settlement(build: impl FnOnce(u64) -> crate::types::SettlementRecord) -> u64 {
+    NETTING_SETTLEMENTS.with(|s| {
+        let mut settlements = s.borrow_mut();
+        let index = settlements.last_key_value().map_or(0, |(k, _)| k + 1);
+        settlements.insert(index, build(index));
+        index
+    })
+}
+
+
+pub fn list_settlements(start: u64, limit: usize) -> Vec<crate::types::SettlementRecord> {
+    NETTING_SETTLEMENTS.with(|s| s.borrow().range(start..).take(limit).map(|(_, r)| r).collect())
+}
+
+
+pub fn record_admin_expired_allowance(entry: crate::types::AdminExpiredAllowance) {
+    ADMIN_EXPIRED_ALLOWANCES.with(|m| m.borrow_mut().insert(entry.audit_index, entry));
+}
+
+
+pub fn list_admin_expired_allowances(start: u64, limit: usize) -> Vec<crate::types::AdminExpiredAllowance> {
+    ADMIN_EXPIRED_ALLOWANCES.with(|m| m.borrow().range(start..).take(limit).map(|(_, e)| e).collect())
+}
+
+
+pub fn get_incoming_policy(account_key: AccountKey) -> crate::types::IncomingPolicyConfig {
+    INCOMING_POLICIES.with(|p| p.borrow().get(&account_key).unwrap_or_default())
+}
+
+
+pub fn set_incoming_policy(account_key: AccountKey, config: crate::types::IncomingPolicyConfig) {
+    INCOMING_POLICIES.with(|p| {
+        let mut policies = p.borrow_mut();
+        if config == crate::types::IncomingPolicyConfig::default() {
+            policies.remove(&account_key);
+        } else {
+            policies.insert(account_key, config);
+        }
+    });
+}
+
+
+// One (day, holder_count) point per day in [from_day, to_day] for which a value
+// is known, clamped to the retention window and to today.
+pub fn get_holder_count_history(token_id: TokenId, from_day: u64, to_day: u64) -> Vec<(u64, u64)> {
+    let today = current_day();
+    let from_day = from_day.max(holder_history_cutoff(today));
+    let to_day = to_day.min(today);
+    if from_day > to_day {
+        return Vec::new();
+    }
+
+    HOLDER_COUNT_HISTORY.with(|h| {
+        let history = h.borrow();
+
+        let mut current = history
+            .iter_upper_bound(&encode_holder_history_key(token_id, from_day))
+            .next()
+            .filter(|(key, _)| key[0..32] == token_id)
+            .map(|(_, count)| count);
+
+        let mut changes = history
+            .range(encode_holder_history_key(token_id, from_day)..=encode_holder_history_key(token_id, to_day))
+            .map(|(key, count)| {
+                let mut day = [0u8; 8];
+                day.copy_from_slice(&key[32..40]);
+                (u64::from_be_bytes(day), count)
+            })
+            .peekable();
+
+        let mut series = Vec::new();
+        for day in from_day..=to_day {
+            if let Some((_, count)) = changes.next_if(|(change_day, _)| *change_day == day) {
+                current = Some(count);
+            }
+            if let Some(count) = current {
+                series.push((day, count));
+            }
+        }
+        series
+    })
+}
+
+pub fn get_holder_count(token_id: TokenId) -> u64 {
+    HOLDER_COUNTS.with(|h| {
+        h.borrow().get(&token_id).unwrap_or(0)
+    })
+}
+
+
+pub fn get_allowance(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) -> u128 {
+    let allowance_key = hash_allowance_key(token_id, owner_key, spender_key);
+    ALLOWANCE_STORAGE.with(|a| {
+        a.borrow().get(&allowance_key).unwrap_or(0)
+    })
+}
+
+
+pub fn set_allowance(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey, amount: u128) {
+    let allowance_key = hash_allowance_key(token_id, owner_key, spender_key);
+    let index_key = encode_spender_allowance_key(spender_key, token_id, owner_key);
+    let owner_index_key = encode_owner_allowance_key(owner_key, token_id, spender_key);
+    if amount == 0 {
+        tracked_remove(StructureId::Allowances, &ALLOWANCE_STORAGE, &allowance_key);
+        tracked_remove(StructureId::SpenderAllowances, &SPENDER_ALLOWANCES, &index_key);
+        tracked_remove(StructureId::OwnerAllowances, &OWNER_ALLOWANCES, &owner_index_key);
+    } else {
+        tracked_insert(StructureId::Allowances, &ALLOWANCE_STORAGE, allowance_key, amount);
+        tracked_insert(StructureId::SpenderAllowances, &SPENDER_ALLOWANCES, index_key, ());
+        tracked_insert(StructureId::OwnerAllowances, &OWNER_ALLOWANCES, owner_index_key, ());
+    }
+
+    bump_mutation_seq(token_id);
+}
+
+
+// Returns spender keys of `owner_key`'s allowances on `token_id`, after `start_after`, in key order.
+pub fn list_owner_allowances(
+    owner_key: AccountKey,
+    token_id: TokenId,
+    start_after: Option<AccountKey>,
+    limit: usize,
+) -> Vec<AccountKey> {
+    let cursor = start_after.map(|spender_key| encode_owner_allowance_key(owner_key, token_id, spender_key));
+    let lower = cursor.unwrap_or_else(|| encode_owner_allowance_key(owner_key, token_id, [0u8; 32]));
+    let upper = encode_owner_allowance_key(owner_key, token_id, [0xFF; 32]);
+
+    OWNER_ALLOWANCES.with(|i| {
+        i.borrow()
+            .range(lower..=upper)
+            .map(|(key, _)| key)
+            .filter(|key| Some(*key) != cursor)
+            .take(limit)
+            .map(|key| {
+                let mut spender_key = [0u8; 32];
+                spender_key.copy_from_slice(&key[64..96]);
+                spender_key
+            })
+            .collect()
+    })
+}
+
+
+// The default subaccount is not recorded, however it is spelled.
+pub fn record_spender_subaccount(spender: &Account) -> Result<(), String> {
+    let subaccount = match spender.named_subaccount() {
+        Some(sub) => <[u8; 32]>::try_from(sub).map_err(|_| "Subaccount must be 32 bytes".to_string())?,
+        None => return Ok(()),
+    };
+    let principal = StoredPrincipal::from_principal(&spender.owner)?;
+    SPENDER_SUBACCOUNTS.with(|s| {
+        s.borrow_mut().insert(encode_principal_subaccount_key(&principal, subaccount), ());
+    });
+    Ok(())
+}
+
+
+pub fn list_spender_subaccounts(principal: Principal, limit: usize) -> Vec<[u8; 32]> {
+    let stored = match StoredPrincipal::from_principal(&principal) {
+        Ok(p) => p,
+        Err(_) => return Vec::new(),
+    };
+    let lower = encode_principal_subaccount_key(&stored, [0u8; 32]);
+    let upper = encode_principal_subaccount_key(&stored, [0xFF; 32]);
+
+    SPENDER_SUBACCOUNTS.with(|s| {
+        s.borrow()
+            .range(lower..=upper)
+            .take(limit)
+            .map(|(key, _)| {
+                let mut subaccount = [0u8; 32];
+                subaccount.copy_from_slice(&key[30..62]);
+                subaccount
+            })
+            .collect()
+    })
+}
+
+
+// Live allowances from `owner_key` to any account of `principal`: the default
+// subaccount plus the named ones it has been approved on.
+pub fn find_principal_allowances(
+    token_id: TokenId,
+    owner_key: AccountKey,
+    principal: Principal,
+    max_subaccounts: usize,
+) -> Vec<(Account, u128, Option<u64>)> {
+    let candidates = std::iter::once(None)
+        .chain(list_spender_subaccounts(principal, max_subaccounts).into_iter().map(|s| Some(s.to_vec())));
+
+    candidates
+        .filter_map(|subaccount| {
+            let spender = Account { owner: principal, subaccount };
+            let spender_key = spender.to_key();
+            let allowance = get_allowance(token_id, owner_key, spender_key);
+            let expires_at = get_allowance_expiry(token_id, owner_key, spender_key);
+            (allowance > 0 && !is_allowance_expired(expires_at)).then_some((spender, allowance, expires_at))
+        })
+        .collect()
+}
+
+
+// Returns (token_id, spender_key) pairs for every token, in key order.
+pub fn list_owner_allowances_all_tokens(owner_key: AccountKey, limit: usize) -> Vec<(TokenId, AccountKey)> {
+    let lower = encode_owner_allowance_key(owner_key, [0u8; 32], [0u8; 32]);
+    let upper = encode_owner_allowance_key(owner_key, [0xFF; 32], [0xFF; 32]);
+
+    OWNER_ALLOWANCES.with(|i| {
+        i.borrow()
+            .range(lower..=upper)
+            .take(limit)
+            .map(|(key, _)| {
+                let mut tid = [0u8; 32];
+                let mut spender_key = [0u8; 32];
+                tid.copy_from_slice(&key[32..64]);
+                spender_key.copy_from_slice(&key[64..96]);
+                (tid, spender_key)
+            })
+            .collect()
+    })
+}
+
+
+// Live allowances `owner_key` has granted on any token, as (token_id,
+// spender_key, allowance, expires_at), skipping the first `skip` of them.
+// Dead entries are passed over without counting.
+pub fn list_live_owner_allowances(
+    owner_key: AccountKey,
+    skip: usize,
+    limit: usize,
+) -> Vec<(TokenId, AccountKey, u128, Option<u64>)> {
+    let lower = encode_owner_allowance_key(owner_key, [0u8; 32], [0u8; 32]);
+    let upper = encode_owner_allowance_key(owner_key, [0xFF; 32], [0xFF; 32]);
+
+    OWNER_ALLOWANCES.with(|i| {
+        i.borrow()
+            .range(lower..=upper)
+            .filter_map(|(key, _)| {
+                let mut tid = [0u8; 32];
+                let mut spender_key = [0u8; 32];
+                tid.copy_from_slice(&key[32..64]);
+                spender_key.copy_from_slice(&key[64..96]);
+                let allowance = get_allowance(tid, owner_key, spender_key);
+                let expires_at = get_allowance_expiry(tid, owner_key, spender_key);
+                (allowance > 0 && !is_allowance_expired(expires_at)).then_some((tid, spender_key, allowance, expires_at))
+            })
+            .skip(skip)
+            .take(limit)
+            .collect()
+    })
+}
+
+
+pub fn remove_registered_account(account_key: AccountKey) -> bool {
+    ACCOUNT_REGISTRY.with(|r| r.borrow_mut().remove(&account_key).is_some())
+}
+
+
+pub fn remove_spender_subaccount(spender: &Account) -> bool {
+    let subaccount = match spender.subaccount.as_deref().map(<[u8; 32]>::try_from) {
+        Some(Ok(sub)) => sub,
+        _ => return false,
+    };
+    let principal = match StoredPrincipal::from_principal(&spender.owner) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    SPENDER_SUBACCOUNTS.with(|s| {
+        s.borrow_mut().remove(&encode_principal_subaccount_key(&principal, subaccount)).is_some()
+    })
+}
+
+
+pub fn get_allowances_cleaned_up() -> u64 {
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_ALLOWANCES_CLEANED_UP)
+            .map(|bytes| {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[..8]);
+                u64::from_be_bytes(buf)
+            })
+            .unwrap_or(0)
+    })
+}
+
+
+pub fn add_allowances_cleaned_up(count: u64) {
+    let total = get_allowances_cleaned_up().saturating_add(count);
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_ALLOWANCES_CLEANED_UP, total.to_be_bytes().to_vec());
+    });
+}
+
+
+pub fn register_account(account: &Account) -> Result<(), String> {
+    let stored = StoredAccount::from_account(account)?;
+    ACCOUNT_REGISTRY.with(|r| {
+        r.borrow_mut().insert(account.to_key(), stored);
+    });
+    Ok(())
+}
+
+
+pub fn get_registered_account(account_key: AccountKey) -> Option<Account> {
+    ACCOUNT_REGISTRY.with(|r| {
+        r.borrow().get(&account_key).and_then(|stored| stored.to_account().ok())
+    })
+}
+
+
+// Returns (token_id, owner_key) pairs after `start_after`, in key order.
+pub fn list_spender_allowances(
+    spender_key: AccountKey,
+    token_id: Option<TokenId>,
+    start_after: Option<(TokenId, AccountKey)>,
+    limit: usize,
+) -> Vec<(TokenId, AccountKey)> {
+    let cursor = start_after.map(|(tid, owner_key)| encode_spender_allowance_key(spender_key, tid, owner_key));
+    let range_start = encode_spender_allowance_key(spender_key, token_id.unwrap_or([0u8; 32]), [0u8; 32]);
+    let lower = cursor.map_or(range_start, |c| c.max(range_start));
+    let upper = match token_id {
+        Some(tid) => encode_spender_allowance_key(spender_key, tid, [0xFF; 32]),
+        None => encode_spender_allowance_key(spender_key, [0xFF; 32], [0xFF; 32]),
+    };
+
+    SPENDER_ALLOWANCES.with(|i| {
+        i.borrow()
+            .range(lower..=upper)
+            .map(|(key, _)| key)
+            .filter(|key| Some(*key) != cursor)
+            .take(limit)
+            .map(|key| {
+                let mut tid = [0u8; 32];
+                let mut owner_key = [0u8; 32];
+                tid.copy_from_slice(&key[32..64]);
+                owner_key.copy_from_slice(&key[64..96]);
+                (tid, owner_key)
+            })
+            .collect()
+    })
+}
+
+
+// Live allowances granted to `spender_key`, on `token_id` or on any token, as
+// (token_id, owner_key, allowance, expires_at), skipping the first `skip` of
+// them. Dead entries are passed over without counting.
+pub fn list_live_spender_allowances(
+    spender_key: AccountKey,
+    token_id: Option<TokenId>,
+    skip: usize,
+    limit: usize,
+) -> Vec<(TokenId, AccountKey, u128, Option<u64>)> {
+    let lower = encode_spender_allowance_key(spender_key, token_id.unwrap_or([0u8; 32]), [0u8; 32]);
+    let upper = encode_spender_allowance_key(spender_key, token_id.unwrap_or([0xFF; 32]), [0xFF; 32]);
+
+    SPENDER_ALLOWANCES.with(|i| {
+        i.borrow()
+            .range(lower..=upper)
+            .filter_map(|(key, _)| {
+                let mut tid = [0u8; 32];
+                let mut owner_key = [0u8; 32];
+                tid.copy_from_slice(&key[32..64]);
+                owner_key.copy_from_slice(&key[64..96]);
+                let allowance = get_allowance(tid, owner_key, spender_key);
+                let expires_at = get_allowance_expiry(tid, owner_key, spender_key);
+                (allowance > 0 && !is_allowance_expired(expires_at)).then_some((tid, owner_key, allowance, expires_at))
+            })
+            .skip(skip)
+            .take(limit)
+            .collect()
+    })
+}
+
+
+pub fn get_protocol_commission_bps() -> u16 {
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_COMMISSION_BPS)
+            .map(|bytes| {
+                let mut buf = [0u8; 2];
+                buf.copy_from_slice(&bytes[..2]);
+                u16::from_be_bytes(buf)
+            })
+            .unwrap_or(0)
+    })
+}
+
+
+pub fn get_protocol_treasury() -> Option<Account> {
+    SYSTEM_STATE.with(|s| {
+        s.borrow().get(&KEY_PROTOCOL_TREASURY).and_then(|bytes| {
+            StoredAccount::from_bytes(std::borrow::Cow::Borrowed(&bytes)).to_account().ok()
+        })
+    })
+}
+
+
+pub fn set_protocol_commission(bps: u16, treasury: &Account) -> Result<(), String> {
+    let stored = StoredAccount::from_account(treasury)?;
+    SYSTEM_STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.insert(KEY_COMMISSION_BPS, bps.to_be_bytes().to_vec());
+        state.insert(KEY_PROTOCOL_TREASURY, stored.to_bytes().to_vec());
+    });
+    Ok(())
+}
+
+
+pub fn get_fee_stats(token_id: TokenId) -> (u128, u128) {
+    FEE_STATS.with(|f| {
+        f.borrow().get(&token_id)
+            .map(|bytes| {
+                let mut recipient = [0u8; 16];
+                let mut treasury = [0u8; 16];
+                recipient.copy_from_slice(&bytes[..16]);
+                treasury.copy_from_slice(&bytes[16..]);
+                (u128::from_be_bytes(recipient), u128::from_be_bytes(treasury))
+            })
+            .unwrap_or((0, 0))
+    })
+}
+
+
+pub fn record_fee_stats(token_id: TokenId, to_recipient: u128, to_treasury: u128) {
+    let (recipient, treasury) = get_fee_stats(token_id);
+    let mut value = [0u8; 32];
+    value[..16].copy_from_slice(&recipient.saturating_add(to_recipient).to_be_bytes());
+    value[16..].copy_from_slice(&treasury.saturating_add(to_treasury).to_be_bytes());
+    FEE_STATS.with(|f| {
+        f.borrow_mut().insert(token_id, value);
+    });
+}
+
+
+pub fn add_transaction(tx: crate::transaction::StoredTxV1) -> u64 {
+    add_transaction_with_memo(tx, None)
+}
+
+
+// Stores memos over INLINE_MEMO_LEN bytes alongside, so the block hash covers them.
+pub fn add_transaction_with_memo(mut tx: crate::transaction::StoredTxV1, memo: Option<&[u8]>) -> u64 {
+    let token_seq = next_token_seq(tx.token_id);
+    tx.set_token_seq(token_seq);
+
+    let tx_index = TRANSACTION_LOG.with(|log| {
+        log.borrow_mut().append(&tx).expect("Failed to append transaction")
+    });
+    TOKEN_TX_SEQ.with(|s| {
+        s.borrow_mut().insert(encode_tx_index_key(tx.token_id, token_seq), tx_index);
+    });
+    let commission = crate::fees::current_commission(&tx);
+    if let Some(commission) = commission {
+        FEE_COMMISSIONS.with(|c| c.borrow_mut().insert(tx_index, commission));
+    }
+    ACCOUNT_TX_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for account_key in tx.balance_accounts(&crate::fees::fee_credits_with(&tx, commission)) {
+            index.insert(encode_account_tx_key(account_key, tx_index), ());
+        }
+    });
+
+    let extended_memo = memo.filter(|m| m.len() > crate::types::constants::INLINE_MEMO_LEN);
+    if let Some(memo) = extended_memo {
+        store_extended_memo(tx_index, memo.to_vec());
+    }
+    chain_block(tx_index, &tx, extended_memo);
+    record_day_start(tx_index);
+    crate::activity::record_block(runtime::time());
+    tx_index
+}
+
+
+// Days are counted in ledger time, not block timestamps, which may come from
+// created_at_time. So a day's blocks are the index range up to the next
+// indexed day's first block.
+fn record_day_start(tx_index: u64) {
+    let today = current_day();
+    if LAST_INDEXED_DAY.with(|d| d.get()) == Some(today) {
+        return;
+    }
+    DAY_FIRST_TX.with(|d| {
+        let mut days = d.borrow_mut();
+        if !days.contains_key(&today) {
+            days.insert(today, tx_index);
+        }
+    });
+    LAST_INDEXED_DAY.with(|d| d.set(Some(today)));
+}
+
+
+// The earliest indexed day and its first block. Blocks before that block were
+// appended before the day index existed.
+pub fn first_indexed_day() -> Option<(u64, u64)> {
+    DAY_FIRST_TX.with(|d| d.borrow().first_key_value())
+}
+
+
+// Index of the first block appended on `day` or later, if any was.
+pub fn first_tx_from_day(day: u64) -> Option<u64> {
+    DAY_FIRST_TX.with(|d| d.borrow().range(day..).next().map(|(_, tx_index)| tx_index))
+}
+
+
+// The memo goes to EXTENDED_MEMOS whatever its length; the block keeps only
+// its commitment, so the block hash and range exports never see the plaintext.
+pub fn add_transaction_with_private_memo(mut tx: crate::transaction::StoredTxV1, memo: &[u8], readers: &[Principal]) -> u64 {
+    let next_index = get_transaction_count();
+    tx.set_private_memo(crate::proof::private_memo_commitment(next_index, &tx, memo));
+    let tx_index = add_transaction_with_memo(tx, None);
+    debug_assert_eq!(tx_index, next_index);
+
+    store_extended_memo(tx_index, memo.to_vec());
+    PRIVATE_MEMO_READERS.with(|r| {
+        let mut readers_map = r.borrow_mut();
+        for reader in readers {
+            if let Ok(stored) = StoredPrincipal::from_principal(reader) {
+                readers_map.insert(encode_memo_reader_key(tx_index, &stored), ());
+            }
+        }
+    });
+    tx_index
+}
+
+
+fn encode_memo_reader_key(tx_index: u64, reader: &StoredPrincipal) -> [u8; 38] {
+    let mut key = [0u8; 38];
+    key[..8].copy_from_slice(&tx_index.to_be_bytes());
+    key[8..].copy_from_slice(&reader.to_bytes());
+    key
+}
+
+
+pub fn is_private_memo_reader(tx_index: u64, principal: &Principal) -> bool {
+    let Ok(stored) = StoredPrincipal::from_principal(principal) else {
+        return false;
+    };
+    PRIVATE_MEMO_READERS.with(|r| r.borrow().contains_key(&encode_memo_reader_key(tx_index, &stored)))
+}
+
+
+// Blocks whose parent has no hash yet are left to the block_hashes migration.
+fn chain_block(tx_index: u64, tx: &crate::transaction::StoredTxV1, extended_memo: Option<&[u8]>) -> bool {
+    let parent = match tx_index {
+        0 => Some(crate::proof::GENESIS_HASH),
+        _ => get_block_hash(tx_index - 1),
+    };
+    let Some(parent) = parent else { return false };
+
+    let hash = crate::proof::block_hash(tx_index, &parent, tx, extended_memo);
+    BLOCK_HASHES.with(|h| h.borrow_mut().insert(tx_index, hash));
+    CERTIFIED_TIP.with(|t| t.set(Some(hash)));
+    certify();
+    true
+}
+
+
+fn certified_tip() -> [u8; 32] {
+    CERTIFIED_TIP.with(|t| {
+        let tip = t.get().unwrap_or_else(|| BLOCK_HASHES.with(|h| h.borrow().last_key_value())
+            .map_or(crate::proof::GENESIS_HASH, |(_, hash)| hash));
+        t.set(Some(tip));
+        tip
+    })
+}
+
+
+fn certified_balances_complete() -> bool {
+    CERTIFIED_REFILL.with(|r| matches!(r.get(), CertifiedRefill::Complete))
+}
+
+
+// What the certified data commits to for balances: the tree once it is
+// complete, and no balances before that.
+fn certified_balances_root() -> Hash {
+    match certified_balances_complete() {
+        true => CERTIFIED_BALANCES.with(|c| c.borrow().root_hash()),
+        false => ic_certification::empty().digest(),
+    }
+}
+
+
+// Keys the refill has not reached yet are left out; it reads their balance
+// when it gets there.
+fn certify_balance(token_id: TokenId, account_key: AccountKey, amount: u128) {
+    let label = crate::verification::balance_label(&token_id, &account_key);
+    let covered = CERTIFIED_REFILL.with(|r| match r.get() {
+        CertifiedRefill::Complete => true,
+        CertifiedRefill::UpTo(last) => last.is_some_and(|last| label <= last),
+    });
+    if covered {
+        CERTIFIED_BALANCES.with(|c| {
+            let mut tree = c.borrow_mut();
+            match amount {
+                0 => tree.delete(&label),
+                _ => tree.insert(label, crate::verification::encode_balance(amount)),
+            }
+        });
+    }
+    certify();
+}
+
+
+// Inserts the next holder into the certified tree. Once there is none left
+// the tree is complete and certified; returns false from then on.
+pub fn refill_certified_balances() -> bool {
+    let CertifiedRefill::UpTo(last) = CERTIFIED_REFILL.with(|r| r.get()) else {
+        return false;
+    };
+    let next = TOKEN_ACCOUNTS.with(|t| {
+        let accounts = t.borrow();
+        match last {
+            Some(last) => accounts.range(last..).map(|(key, ())| key).find(|key| *key != last),
+            None => accounts.iter().next().map(|(key, ())| key),
+        }
+    });
+    let Some(key) = next else {
+        CERTIFIED_REFILL.with(|r| r.set(CertifiedRefill::Complete));
+        certify();
+        return false;
+    };
+    let balance = get_balance(key[..32].try_into().unwrap(), key[32..].try_into().unwrap());
+    if balance > 0 {
+        CERTIFIED_BALANCES.with(|c| c.borrow_mut().insert(key, crate::verification::encode_balance(balance)));
+    }
+    CERTIFIED_REFILL.with(|r| r.set(CertifiedRefill::UpTo(Some(key))));
+    true
+}
+
+
+pub fn count_certified_balances_pending(limit: usize) -> u64 {
+    use std::ops::Bound;
+    let lower = match CERTIFIED_REFILL.with(|r| r.get()) {
+        CertifiedRefill::Complete => return 0,
+        CertifiedRefill::UpTo(Some(last)) => Bound::Excluded(last),
+        CertifiedRefill::UpTo(None) => Bound::Unbounded,
+    };
+    TOKEN_ACCOUNTS.with(|t| t.borrow().range((lower, Bound::Unbounded)).take(limit).count() as u64)
 }
 
 
-pub fn set_balance(token_id: TokenId, account_key: AccountKey, amount: u128) {
-    let balance_key = hash_balance_key(token_id, account_key);
+// A new ledger holds nothing yet, so its tree is complete from the start and
+// every balance genesis creates is certified as it is set. init calls this
+// before genesis.
+pub fn start_certified_balances() {
+    CERTIFIED_REFILL.with(|r| r.set(CertifiedRefill::Complete));
+    certify();
+}
 
-    let old_balance = BALANCE_STORAGE.with(|b| {
-        b.borrow().get(&balance_key).unwrap_or(0)
-    });
 
-    BALANCE_STORAGE.with(|b| {
-        let mut storage = b.borrow_mut();
-        if amount == 0 {
-            storage.remove(&balance_key);
-        } else {
-            storage.insert(balance_key, amount);
-        }
-    });
+// Sets the certified data to the root over the balances and the newest block.
+// post_upgrade calls it so the tip is certified while the tree refills.
+pub fn certify() {
+    runtime::set_certified_data(&crate::verification::certified_root(&certified_balances_root(), &certified_tip()));
+}
 
-    if old_balance == 0 && amount > 0 {
-        increment_holder_count(token_id);
-    } else if old_balance > 0 && amount == 0 {
-        decrement_holder_count(token_id);
+
+// Witnesses served beside data_certificate(): one keeps a balance's path,
+// the other only the tip. There is no balance witness while the tree refills.
+pub fn balance_witness(token_id: TokenId, account_key: AccountKey) -> Option<Vec<u8>> {
+    if !certified_balances_complete() {
+        return None;
     }
+    let label = crate::verification::balance_label(&token_id, &account_key);
+    let balances = CERTIFIED_BALANCES.with(|c| c.borrow().witness(&label));
+    Some(crate::verification::encode_witness(&fork(
+        labeled(crate::verification::BALANCES_LABEL, balances),
+        labeled(crate::verification::TIP_LABEL, leaf(certified_tip().to_vec())),
+    )))
 }
 
 
-fn increment_holder_count(token_id: TokenId) {
-    HOLDER_COUNTS.with(|h| {
-        let mut counts = h.borrow_mut();
-        let current = counts.get(&token_id).unwrap_or(0);
-        counts.insert(token_id, current + 1);
-    });
+pub fn tip_witness() -> Vec<u8> {
+    crate::verification::encode_witness(&fork(
+        labeled(crate::verification::BALANCES_LABEL, pruned(certified_balances_root())),
+        labeled(crate::verification::TIP_LABEL, leaf(certified_tip().to_vec())),
+    ))
 }
 
-fn decrement_holder_count(token_id: TokenId) {
-    HOLDER_COUNTS.with(|h| {
-        let mut counts = h.borrow_mut();
-        let current = counts.get(&token_id).unwrap_or(0);
-        if current > 0 {
-            counts.insert(token_id, current - 1);
+
+pub fn get_block_hash(tx_index: u64) -> Option<[u8; 32]> {
+    BLOCK_HASHES.with(|h| h.borrow().get(&tx_index))
+}
+
+
+// Hashes up to `limit` blocks from `start`, which must be the first unhashed one.
+pub fn backfill_block_hashes(start: u64, limit: u64) -> Result<u64, String> {
+    let end = start.saturating_add(limit).min(get_transaction_count());
+    for tx_index in start..end {
+        let tx = get_transaction(tx_index).ok_or("Transaction log is missing a block".to_string())?;
+        let memo = if tx.has_extended_memo() { get_extended_memo(tx_index) } else { None };
+        if !chain_block(tx_index, &tx, memo.as_deref()) {
+            return Err(format!("Block {} has no parent hash", tx_index));
         }
-    });
+    }
+    Ok(end.saturating_sub(start))
 }
 
-pub fn get_holder_count(token_id: TokenId) -> u64 {
-    HOLDER_COUNTS.with(|h| {
-        h.borrow().get(&token_id).unwrap_or(0)
+
+// Indices of transactions at or after `start` that moved `account_key`'s funds.
+pub fn list_account_transactions(account_key: AccountKey, start: u64, limit: usize) -> Vec<u64> {
+    ACCOUNT_TX_INDEX.with(|i| {
+        i.borrow()
+            .range(encode_account_tx_key(account_key, start)..=encode_account_tx_key(account_key, u64::MAX))
+            .take(limit)
+            .map(|(key, _)| {
+                let mut tx_index = [0u8; 8];
+                tx_index.copy_from_slice(&key[32..40]);
+                u64::from_be_bytes(tx_index)
+            })
+            .collect()
     })
 }
 
 
-pub fn get_allowance(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) -> u128 {
-    let allowance_key = hash_allowance_key(token_id, owner_key, spender_key);
-    ALLOWANCE_STORAGE.with(|a| {
-        a.borrow().get(&allowance_key).unwrap_or(0)
+fn next_token_seq(token_id: TokenId) -> u64 {
+    TOKEN_TX_SEQ.with(|s| {
+        s.borrow()
+            .iter_upper_bound(&encode_tx_index_key(token_id, u64::MAX))
+            .next()
+            .filter(|(key, _)| key[4..36] == token_id)
+            .map_or(0, |(key, _)| {
+                let mut seq = [0u8; 8];
+                seq.copy_from_slice(&key[36..44]);
+                u64::from_be_bytes(seq) + 1
+            })
     })
 }
 
 
-pub fn set_allowance(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey, amount: u128) {
-    let allowance_key = hash_allowance_key(token_id, owner_key, spender_key);
-    ALLOWANCE_STORAGE.with(|a| {
-        let mut storage = a.borrow_mut();
-        if amount == 0 {
-            storage.remove(&allowance_key);
-        } else {
-            storage.insert(allowance_key, amount);
+pub fn get_tx_index_by_token_seq(token_id: TokenId, token_seq: u64) -> Option<u64> {
+    TOKEN_TX_SEQ.with(|s| s.borrow().get(&encode_tx_index_key(token_id, token_seq)))
+}
+
+
+// The newest token seq whose transaction is at or before `tx_index`. Seqs and
+// log indices grow together, so this is a binary search over the seq index.
+pub fn token_seq_at_or_before(token_id: TokenId, tx_index: u64) -> Option<u64> {
+    let (mut low, mut high) = (0u64, next_token_seq(token_id));
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match get_tx_index_by_token_seq(token_id, mid) {
+            Some(index) if index <= tx_index => low = mid + 1,
+            _ => high = mid,
         }
-    });
+    }
+    low.checked_sub(1)
 }
 
 
-pub fn add_transaction(tx: crate::transaction::StoredTxV1) -> u64 {
-    TRANSACTION_LOG.with(|log| {
-        log.borrow_mut().append(&tx).expect("Failed to append transaction")
+// (token seq, tx index) of the token's transactions from `from_seq` down,
+// newest first.
+pub fn list_token_transactions_back(token_id: TokenId, from_seq: u64, limit: usize) -> Vec<(u64, u64)> {
+    TOKEN_TX_SEQ.with(|s| {
+        s.borrow()
+            .range(encode_tx_index_key(token_id, 0)..=encode_tx_index_key(token_id, from_seq))
+            .rev()
+            .take(limit)
+            .map(|(key, tx_index)| (u64::from_be_bytes(key[36..44].try_into().unwrap()), tx_index))
+            .collect()
     })
 }
 
@@ -288,7 +3096,7 @@ pub fn get_transaction(index: u64) -> Option<crate::transaction::StoredTxV1> {
 
 
 pub fn require_controller() -> Result<(), String> {
-    let caller = ic_cdk::caller();
+    let caller = runtime::caller();
     if !is_controller(&caller) {
         return Err("Only controller can perform this operation".to_string());
     }
@@ -328,6 +3136,32 @@ pub fn remove_controller_internal(p: Principal) -> Result<(), String> {
 }
 
 
+// For upgrade arguments, which only the canister's own controllers can pass.
+// The first principal becomes the primary controller.
+pub fn replace_controllers(controllers: &[Principal]) -> Result<(), String> {
+    if controllers.is_empty() {
+        return Err("At least one controller is required".to_string());
+    }
+    let stored: Vec<StoredPrincipal> = controllers.iter()
+        .map(StoredPrincipal::from_principal)
+        .collect::<Result<_, _>>()?;
+    SYSTEM_STATE.with(|s| {
+        s.borrow_mut().insert(KEY_CONTROLLER, stored[0].to_bytes().to_vec());
+    });
+    CONTROLLERS.with(|c| {
+        let mut map = c.borrow_mut();
+        let existing: Vec<StoredPrincipal> = map.iter().map(|(p, _)| p).collect();
+        for p in existing {
+            map.remove(&p);
+        }
+        for p in stored {
+            map.insert(p, 1u8);
+        }
+    });
+    Ok(())
+}
+
+
 pub fn list_controllers() -> Vec<Principal> {
     CONTROLLERS.with(|c| {
         c.borrow().iter().filter_map(|(stored, _)| stored.to_principal().ok()).collect()
@@ -336,7 +3170,96 @@ pub fn list_controllers() -> Vec<Principal> {
 
 
 
+// Keys cover the operation, its destination and amount (v3), so payments that
+// differ only in recipient or amount are both new. Only v3 keys are written.
+// Older keys are still read: v2 keys have the op but no destination or amount,
+// and v1 keys have no op either, so they only count as a duplicate when the
+// transaction they point at has the same op. Pruning drops them with their
+// transactions, so after one retention window they no longer match anything.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DedupKey {
+    op: u8,
+    key: [u8; 32],
+    // v2 and v1 keys for the same inputs; None where no older form existed.
+    legacy_keys: Option<([u8; 32], [u8; 32])>,
+}
+
+
+// `destination` is the recipient, or the spender for approvals; burns have none.
+// `amount` is as submitted, or the allowance for approvals.
 pub fn compute_dedup_key(
+    op: u8,
+    caller: candid::Principal,
+    token_id: crate::types::TokenId,
+    created_at_time: u64,
+    memo: Option<&[u8]>,
+    destination: Option<AccountKey>,
+    amount: u128,
+) -> DedupKey {
+    let target = (destination.unwrap_or([0u8; 32]), amount);
+    DedupKey {
+        op,
+        key: hash_dedup_inputs(b"icrc151:dedup:v3", Some(op), Some(target), caller, token_id, created_at_time, memo),
+        legacy_keys: Some((
+            hash_dedup_inputs(b"icrc151:dedup:v2", Some(op), None, caller, token_id, created_at_time, memo),
+            hash_dedup_inputs(b"icrc151:dedup:v1", None, None, caller, token_id, created_at_time, memo),
+        )),
+    }
+}
+
+
+// The payouts of one disbursement share the caller, token and timestamp, and may
+// share a memo, so the payout's position is part of its key. There is no v1 form.
+pub fn compute_payout_dedup_key(
+    caller: candid::Principal,
+    token_id: crate::types::TokenId,
+    created_at_time: u64,
+    payout_index: u32,
+    memo: Option<&[u8]>,
+) -> DedupKey {
+    let mut domain = b"icrc151:dedup:payout:v1".to_vec();
+    domain.extend_from_slice(&payout_index.to_be_bytes());
+    let key = hash_dedup_inputs(&domain, Some(crate::transaction::OP_TRANSFER), None, caller, token_id, created_at_time, memo);
+    DedupKey { op: crate::transaction::OP_TRANSFER, key, legacy_keys: None }
+}
+
+
+// Increases and decreases are keyed by their delta, apart from approvals and
+// from each other, so approving 5 and increasing by 5 are both new. There is
+// no older form.
+pub fn compute_allowance_change_dedup_key(
+    caller: candid::Principal,
+    token_id: crate::types::TokenId,
+    created_at_time: u64,
+    memo: Option<&[u8]>,
+    spender: AccountKey,
+    delta: u128,
+    increase: bool,
+) -> DedupKey {
+    let domain: &[u8] = if increase { b"icrc151:dedup:allowance_increase:v1" } else { b"icrc151:dedup:allowance_decrease:v1" };
+    let op = crate::transaction::OP_APPROVE;
+    let key = hash_dedup_inputs(domain, Some(op), Some((spender, delta)), caller, token_id, created_at_time, memo);
+    DedupKey { op, key, legacy_keys: None }
+}
+
+
+// The operations of one script share the caller and timestamp and may repeat
+// one another, so each key is rehashed with the operation's position. There is
+// no older form.
+pub fn scope_dedup_key_to_script(dedup_key: DedupKey, position: u32) -> DedupKey {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(b"icrc151:dedup:script:v1");
+    hasher.update(position.to_be_bytes());
+    hasher.update(dedup_key.key);
+    DedupKey { op: dedup_key.op, key: hasher.finalize().into(), legacy_keys: None }
+}
+
+
+fn hash_dedup_inputs(
+    domain: &[u8],
+    op: Option<u8>,
+    target: Option<(AccountKey, u128)>,
     caller: candid::Principal,
     token_id: crate::types::TokenId,
     created_at_time: u64,
@@ -344,10 +3267,17 @@ pub fn compute_dedup_key(
 ) -> [u8; 32] {
     use sha2::{Digest, Sha256};
     let mut hasher = Sha256::new();
-    hasher.update(b"icrc151:dedup:v1");
+    hasher.update(domain);
+    if let Some(op) = op {
+        hasher.update([op]);
+    }
     hasher.update(caller.as_slice());
-    hasher.update(&token_id);
-    hasher.update(&created_at_time.to_be_bytes());
+    hasher.update(token_id);
+    hasher.update(created_at_time.to_be_bytes());
+    if let Some((destination, amount)) = target {
+        hasher.update(destination);
+        hasher.update(amount.to_be_bytes());
+    }
     if let Some(memo_data) = memo {
         hasher.update(memo_data);
     }
@@ -356,31 +3286,75 @@ pub fn compute_dedup_key(
 
 
 
-pub fn check_duplicate(dedup_key: [u8; 32]) -> Option<u64> {
+pub fn check_duplicate(dedup_key: DedupKey) -> Option<u64> {
     DEDUP_MAP.with(|d| {
-        d.borrow().get(&dedup_key)
+        let map = d.borrow();
+        map.get(&dedup_key.key).or_else(|| {
+            let (v2_key, v1_key) = dedup_key.legacy_keys?;
+            map.get(&v2_key).or_else(|| {
+                map.get(&v1_key).filter(|index| get_transaction(*index).is_some_and(|tx| tx.op == dedup_key.op))
+            })
+        })
     })
 }
 
 
-pub fn record_transaction_dedup(dedup_key: [u8; 32], tx_index: u64) {
-    DEDUP_MAP.with(|d| {
-        d.borrow_mut().insert(dedup_key, tx_index);
-    });
+pub fn record_transaction_dedup(dedup_key: DedupKey, tx_index: u64) {
+    tracked_insert(StructureId::DedupMap, &DEDUP_MAP, dedup_key.key, tx_index);
+}
+
+
+// Looks at the first dedup entry after `start_after` and removes it when its
+// transaction is stamped before `stale_before`. Entries whose transaction is
+// not in the log are kept. Returns the entry's key, or None past the last one.
+pub fn prune_dedup_entry(start_after: Option<[u8; 32]>, stale_before: u64) -> Option<[u8; 32]> {
+    let (key, tx_index) = DEDUP_MAP.with(|d| {
+        let map = d.borrow();
+        match start_after {
+            Some(after) => map.range(after..).find(|(key, _)| *key != after),
+            None => map.iter().next(),
+        }
+    })?;
+    if get_transaction(tx_index).is_some_and(|tx| tx.get_timestamp() < stale_before) {
+        tracked_remove(StructureId::DedupMap, &DEDUP_MAP, &key);
+    }
+    Some(key)
 }
 
 
 pub fn register_token(token_id: crate::types::TokenId, metadata: crate::types::StoredTokenMetadata) {
     TOKEN_REGISTRY.with(|r| {
-        r.borrow_mut().insert(token_id, metadata);
+        r.borrow_mut().insert(token_id, metadata.encode());
     });
+
+    bump_mutation_seq(token_id);
 }
 
 
-pub fn get_token_metadata(token_id: crate::types::TokenId) -> Option<crate::types::StoredTokenMetadata> {
-    TOKEN_REGISTRY.with(|r| {
-        r.borrow().get(&token_id)
-    })
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataError {
+    NotFound,
+    Corrupted(String),
+    TokenImmutable,
+}
+
+impl std::fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetadataError::NotFound => write!(f, "Token not found"),
+            MetadataError::Corrupted(msg) => write!(f, "Token metadata is corrupted: {}", msg),
+            MetadataError::TokenImmutable => write!(f, "TokenImmutable: the token's configuration is frozen"),
+        }
+    }
+}
+
+
+pub fn get_token_metadata(token_id: crate::types::TokenId) -> Result<crate::types::StoredTokenMetadata, MetadataError> {
+    let bytes = TOKEN_REGISTRY.with(|r| r.borrow().get(&token_id))
+        .ok_or(MetadataError::NotFound)?;
+    let mut metadata = crate::types::StoredTokenMetadata::decode(&bytes).map_err(MetadataError::Corrupted)?;
+    metadata.apply_due_fee(runtime::time());
+    Ok(metadata)
 }
 
 
@@ -391,6 +3365,12 @@ pub fn token_exists(token_id: crate::types::TokenId) -> bool {
 }
 
 
+// Includes quarantined tokens; callers that need metadata skip those on decode.
+pub fn get_token_count() -> u64 {
+    TOKEN_REGISTRY.with(|r| r.borrow().len())
+}
+
+
 pub fn list_token_ids() -> Vec<crate::types::TokenId> {
     TOKEN_REGISTRY.with(|r| {
         let registry = r.borrow();
@@ -399,33 +3379,118 @@ pub fn list_token_ids() -> Vec<crate::types::TokenId> {
 }
 
 
-pub fn update_token_fee(token_id: crate::types::TokenId, new_fee: u128) -> Result<(), String> {
+pub fn list_quarantined_tokens() -> Vec<(crate::types::TokenId, String)> {
     TOKEN_REGISTRY.with(|r| {
-        let mut registry = r.borrow_mut();
-
-        match registry.get(&token_id) {
-            Some(mut metadata) => {
-                metadata.fee = new_fee;
-                registry.insert(token_id, metadata);
-                Ok(())
-            }
-            None => Err("Token not found".to_string())
-        }
+        r.borrow()
+            .iter()
+            .filter_map(|(token_id, bytes)| {
+                crate::types::StoredTokenMetadata::decode(&bytes)
+                    .err()
+                    .map(|e| (token_id, e))
+            })
+            .collect()
     })
 }
 
 
-pub fn update_total_supply(token_id: crate::types::TokenId, new_supply: u128) -> Result<(), String> {
+#[cfg(test)]
+pub fn insert_raw_token_metadata(token_id: crate::types::TokenId, bytes: Vec<u8>) {
     TOKEN_REGISTRY.with(|r| {
-        let mut registry = r.borrow_mut();
-        match registry.get(&token_id) {
-            Some(mut metadata) => {
-                metadata.total_supply = new_supply;
-                registry.insert(token_id, metadata);
-                Ok(())
-            }
-            None => Err("Token not found in registry".to_string()),
-        }
+        r.borrow_mut().insert(token_id, bytes);
+    });
+}
+
+
+fn update_token_metadata(
+    token_id: crate::types::TokenId,
+    update: impl FnOnce(&mut crate::types::StoredTokenMetadata),
+) -> Result<(), MetadataError> {
+    let mut metadata = get_token_metadata(token_id)?;
+    update(&mut metadata);
+    register_token(token_id, metadata);
+    Ok(())
+}
+
+
+// Configuration setters go through here so a frozen token rejects them all.
+fn update_token_config(
+    token_id: crate::types::TokenId,
+    update: impl FnOnce(&mut crate::types::StoredTokenMetadata),
+) -> Result<(), MetadataError> {
+    if get_token_metadata(token_id)?.is_immutable() {
+        return Err(MetadataError::TokenImmutable);
+    }
+    update_token_metadata(token_id, update)
+}
+
+
+pub fn update_token_fee(token_id: crate::types::TokenId, new_fee: u128) -> Result<(), String> {
+    update_token_config(token_id, |m| m.fee = new_fee).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_fee_token(token_id: crate::types::TokenId, fee_token: Option<crate::types::TokenId>) -> Result<(), String> {
+    update_token_config(token_id, |m| m.fee_token = fee_token).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_require_explicit_fee(token_id: crate::types::TokenId, required: bool) -> Result<(), String> {
+    update_token_config(token_id, |m| m.require_explicit_fee = Some(required)).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_fee_free_consolidation(token_id: crate::types::TokenId, fee_free: bool) -> Result<(), String> {
+    update_token_config(token_id, |m| m.fee_free_consolidation = Some(fee_free)).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_burn_addresses(token_id: crate::types::TokenId, accounts: Vec<Account>) -> Result<(), String> {
+    let accounts = (!accounts.is_empty()).then_some(accounts);
+    update_token_config(token_id, |m| m.burn_addresses = accounts).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_rounding_mode(token_id: crate::types::TokenId, mode: crate::rounding::RoundingMode) -> Result<(), String> {
+    update_token_config(token_id, |m| m.rounding_mode = Some(mode)).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_max_holders(token_id: crate::types::TokenId, max_holders: Option<u64>) -> Result<(), String> {
+    update_token_config(token_id, |m| m.max_holders = max_holders).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_structured_memos_required(token_id: crate::types::TokenId, required: bool) -> Result<(), String> {
+    update_token_config(token_id, |m| m.structured_memos_required = Some(required)).map_err(|e| e.to_string())
+}
+
+
+// Floor for tokens that never set a mode, and for tokens that cannot be read.
+pub fn get_token_rounding_mode(token_id: crate::types::TokenId) -> crate::rounding::RoundingMode {
+    get_token_metadata(token_id).ok().and_then(|m| m.rounding_mode).unwrap_or_default()
+}
+
+
+pub fn update_token_non_circulating_accounts(token_id: crate::types::TokenId, accounts: Vec<Account>) -> Result<(), String> {
+    let accounts = (!accounts.is_empty()).then_some(accounts);
+    update_token_config(token_id, |m| m.non_circulating_accounts = accounts).map_err(|e| e.to_string())
+}
+
+
+pub fn update_token_scheduled_fee(token_id: crate::types::TokenId, scheduled: Option<crate::types::ScheduledFee>) -> Result<(), String> {
+    update_token_config(token_id, |m| m.scheduled_fee = scheduled).map_err(|e| e.to_string())
+}
+
+
+pub fn freeze_token(token_id: crate::types::TokenId) -> Result<(), MetadataError> {
+    update_token_config(token_id, |m| m.immutable = Some(true))
+}
+
+
+fn update_total_supply(token_id: crate::types::TokenId, new_supply: u128) -> Result<(), String> {
+    update_token_metadata(token_id, |m| m.total_supply = new_supply).map_err(|e| match e {
+        MetadataError::NotFound => "Token not found in registry".to_string(),
+        other => other.to_string(),
     })
 }
 
@@ -437,9 +3502,14 @@ pub fn set_allowance_expiry(
     expires_at: u64,
 ) {
     let expiry_key = crate::types::hash_allowance_key(token_id, owner_key, spender_key);
-    ALLOWANCE_EXPIRY.with(|e| {
-        e.borrow_mut().insert(expiry_key, expires_at);
-    });
+    let previous = tracked_insert(StructureId::AllowanceExpiry, &ALLOWANCE_EXPIRY, expiry_key, expires_at);
+
+    if let Some(previous) = previous {
+        tracked_remove(StructureId::AllowanceExpiryQueue, &ALLOWANCE_EXPIRY_QUEUE, &encode_allowance_expiry_queue_key(previous, token_id, owner_key, spender_key));
+    }
+    tracked_insert(StructureId::AllowanceExpiryQueue, &ALLOWANCE_EXPIRY_QUEUE, encode_allowance_expiry_queue_key(expires_at, token_id, owner_key, spender_key), ());
+
+    bump_mutation_seq(token_id);
 }
 
 
@@ -455,14 +3525,119 @@ pub fn get_allowance_expiry(
 }
 
 
+pub fn remove_allowance_expiry(
+    token_id: crate::types::TokenId,
+    owner_key: crate::types::AccountKey,
+    spender_key: crate::types::AccountKey,
+) {
+    let expiry_key = crate::types::hash_allowance_key(token_id, owner_key, spender_key);
+    let previous = tracked_remove(StructureId::AllowanceExpiry, &ALLOWANCE_EXPIRY, &expiry_key);
+
+    if let Some(previous) = previous {
+        tracked_remove(StructureId::AllowanceExpiryQueue, &ALLOWANCE_EXPIRY_QUEUE, &encode_allowance_expiry_queue_key(previous, token_id, owner_key, spender_key));
+    }
+
+    bump_mutation_seq(token_id);
+}
+
+
+// Queue keys after `start_after` (or from `from` on) whose expiry is at most `until`.
+pub fn list_expiring_allowances(start_after: Option<[u8; 104]>, from: u64, until: u64, limit: usize) -> Vec<[u8; 104]> {
+    let range_start = encode_allowance_expiry_queue_key(from, [0u8; 32], [0u8; 32], [0u8; 32]);
+    let lower = start_after.map_or(range_start, |c| c.max(range_start));
+    let upper = encode_allowance_expiry_queue_key(until, [0xFF; 32], [0xFF; 32], [0xFF; 32]);
+    if lower > upper {
+        return Vec::new();
+    }
+
+    ALLOWANCE_EXPIRY_QUEUE.with(|q| {
+        q.borrow()
+            .range(lower..=upper)
+            .map(|(key, _)| key)
+            .filter(|key| Some(*key) != start_after)
+            .take(limit)
+            .collect()
+    })
+}
+
+
+// Queues the expiring allowances among the spender index entries after `start_after`.
+pub fn backfill_allowance_expiry_queue(start_after: Option<[u8; 96]>, limit: usize) -> (u64, Option<[u8; 96]>) {
+    let keys: Vec<[u8; 96]> = SPENDER_ALLOWANCES.with(|s| {
+        let map = s.borrow();
+        match start_after {
+            Some(after) => map.range(after..).map(|(key, _)| key).filter(|key| *key != after).take(limit).collect(),
+            None => map.iter().map(|(key, _)| key).take(limit).collect(),
+        }
+    });
+
+    for key in &keys {
+        let mut spender_key = [0u8; 32];
+        let mut token_id = [0u8; 32];
+        let mut owner_key = [0u8; 32];
+        spender_key.copy_from_slice(&key[0..32]);
+        token_id.copy_from_slice(&key[32..64]);
+        owner_key.copy_from_slice(&key[64..96]);
+        if let Some(expires_at) = get_allowance_expiry(token_id, owner_key, spender_key) {
+            tracked_insert(StructureId::AllowanceExpiryQueue, &ALLOWANCE_EXPIRY_QUEUE, encode_allowance_expiry_queue_key(expires_at, token_id, owner_key, spender_key), ());
+        }
+    }
+
+    (keys.len() as u64, keys.last().copied())
+}
+
+
+// Takes the earliest queued expiry at or before `expired_by` off the queue and
+// clears the allowance it belongs to, unless the expiry has since moved.
+// Returns whether there was one.
+pub fn sweep_expired_allowance(expired_by: u64) -> bool {
+    let upper = encode_allowance_expiry_queue_key(expired_by, [0xFF; 32], [0xFF; 32], [0xFF; 32]);
+    let Some(queue_key) = ALLOWANCE_EXPIRY_QUEUE.with(|q| q.borrow().range(..=upper).next().map(|(key, _)| key)) else {
+        return false;
+    };
+    tracked_remove(StructureId::AllowanceExpiryQueue, &ALLOWANCE_EXPIRY_QUEUE, &queue_key);
+
+    let (expires_at, token_id, owner_key, spender_key) = decode_allowance_expiry_queue_key(&queue_key);
+    if get_allowance_expiry(token_id, owner_key, spender_key) == Some(expires_at) {
+        drop_expired_allowance(token_id, owner_key, spender_key);
+    }
+    true
+}
+
+
+// Deletes an allowance found expired along with its expiry entries.
+pub fn drop_expired_allowance(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) {
+    set_allowance(token_id, owner_key, spender_key, 0);
+    remove_allowance_expiry(token_id, owner_key, spender_key);
+    add_allowances_cleaned_up(1);
+}
+
+
+// Queued expiries at or before `expired_by`, counting at most `limit`.
+pub fn count_expired_allowances(expired_by: u64, limit: usize) -> u64 {
+    let upper = encode_allowance_expiry_queue_key(expired_by, [0xFF; 32], [0xFF; 32], [0xFF; 32]);
+    ALLOWANCE_EXPIRY_QUEUE.with(|q| q.borrow().range(..=upper).take(limit).count() as u64)
+}
+
+
 pub fn is_allowance_expired(expires_at: Option<u64>) -> bool {
     match expires_at {
-        Some(exp) => ic_cdk::api::time() >= exp,
+        Some(exp) => runtime::time() >= exp,
         None => false,
     }
 }
 
 
+// What the spender can still use: zero once the expiry has passed, even before
+// the sweep or a transfer_from removes the stored amount.
+pub fn get_live_allowance(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) -> u128 {
+    match is_allowance_expired(get_allowance_expiry(token_id, owner_key, spender_key)) {
+        true => 0,
+        false => get_allowance(token_id, owner_key, spender_key),
+    }
+}
+
+
 pub fn store_extended_memo(tx_index: u64, memo: Vec<u8>) {
     EXTENDED_MEMOS.with(|m| {
         m.borrow_mut().insert(tx_index, memo);
@@ -510,6 +3685,88 @@ mod tests {
 
     }
 
+    // What a structure's first access costs shows up as stable memory
+    // accesses; natively that is the part of the first-call cost we can see.
+    #[test]
+    fn test_warm_up_skips_empty_regions() {
+        use crate::operations::{transfer, Icrc151TransferArgs, TransferResult};
+        use crate::test_utils::{measure, register_test_token, test_account};
+
+        let in_use = || (0..memory_ids::RESERVED_START).filter(|&id| holds_data(id)).collect::<Vec<_>>();
+        warm_up();
+        // Looking up where the compactable maps live reads SYSTEM_STATE.
+        assert_eq!(in_use(), vec![memory_ids::SYSTEM_STATE]);
+
+        init_state(test_account(0xFE).owner);
+        let token = register_test_token(1, 0);
+        let from = test_account(1);
+        set_balance(token, from.to_key(), 1_000);
+        runtime::set_caller(from.owner);
+        let args = Icrc151TransferArgs {
+            token_id: token, from_subaccount: None, to: test_account(2), amount: candid::Nat::from(1u64),
+            fee: None, memo: None, created_at_time: None, private_memo: None, amount_mode: None, use_sponsorship: None, args_version: None,
+        };
+        assert!(matches!(transfer(args), TransferResult::Ok(_)));
+
+        let populated = in_use();
+        assert!(populated.contains(&memory_ids::TRANSACTION_LOG) && !populated.contains(&memory_ids::SPONSORSHIPS));
+        assert_eq!(measure(warm_up).stable_writes, 0);
+        assert_eq!(in_use(), populated);
+    }
+
+    #[test]
+    fn test_block_hash_backfill() {
+        use crate::transaction::StoredTxV1;
+
+        for i in 0..5u8 {
+            add_transaction(StoredTxV1::new_mint([1; 32], [i; 32], 10, 5, None));
+        }
+        let expected: Vec<_> = (0..5).map(|i| get_block_hash(i).unwrap()).collect();
+
+        // A log written before the chain existed: new blocks wait for the backfill.
+        BLOCK_HASHES.with(|h| (0..5).for_each(|i| { h.borrow_mut().remove(&i); }));
+        add_transaction(StoredTxV1::new_mint([1; 32], [5; 32], 10, 5, None));
+        assert_eq!(get_block_hash(5), None);
+        assert!(backfill_block_hashes(3, 2).is_err());
+
+        assert_eq!(backfill_block_hashes(0, 4), Ok(4));
+        assert_eq!(backfill_block_hashes(4, 4), Ok(2));
+        assert_eq!((0..5).map(|i| get_block_hash(i).unwrap()).collect::<Vec<_>>(), expected);
+        add_transaction(StoredTxV1::new_mint([1; 32], [6; 32], 10, 5, None));
+        let no_balances = ic_certification::empty().digest();
+        assert_eq!(runtime::certified_data(), crate::verification::certified_root(&no_balances, &get_block_hash(6).unwrap()));
+    }
+
+    #[test]
+    fn test_legacy_dedup_keys_only_block_the_same_op() {
+        use crate::transaction::{StoredTxV1, OP_MINT, OP_TRANSFER};
+
+        let caller = candid::Principal::from_slice(&[7; 29]);
+        let mint_index = add_transaction(StoredTxV1::new_mint([1; 32], [2; 32], 10, 5, Some(b"m")));
+        let mint_key = compute_dedup_key(OP_MINT, caller, [1; 32], 5, Some(b"m"), Some([2; 32]), 10);
+        let (_, v1_key) = mint_key.legacy_keys.unwrap();
+        DEDUP_MAP.with(|d| d.borrow_mut().insert(v1_key, mint_index));
+
+        assert_eq!(check_duplicate(mint_key), Some(mint_index));
+        assert_eq!(check_duplicate(compute_dedup_key(OP_TRANSFER, caller, [1; 32], 5, Some(b"m"), Some([2; 32]), 10)), None);
+    }
+
+    #[test]
+    fn test_v2_dedup_keys_match_any_destination_and_amount() {
+        use crate::transaction::{StoredTxV1, OP_TRANSFER};
+
+        let caller = candid::Principal::from_slice(&[7; 29]);
+        let tx_index = add_transaction(StoredTxV1::new_transfer([1; 32], [3; 32], [4; 32], 10, 0, 5, Some(b"t")));
+        let key = compute_dedup_key(OP_TRANSFER, caller, [1; 32], 5, Some(b"t"), Some([4; 32]), 10);
+        let (v2_key, _) = key.legacy_keys.unwrap();
+        DEDUP_MAP.with(|d| d.borrow_mut().insert(v2_key, tx_index));
+
+        // Recorded before the key had a destination, so it still blocks other recipients.
+        assert_eq!(check_duplicate(key), Some(tx_index));
+        assert_eq!(check_duplicate(compute_dedup_key(OP_TRANSFER, caller, [1; 32], 5, Some(b"t"), Some([9; 32]), 99)), Some(tx_index));
+        assert_ne!(key.key, compute_dedup_key(OP_TRANSFER, caller, [1; 32], 5, Some(b"t"), Some([9; 32]), 10).key);
+    }
+
     #[test] 
     fn test_balance_operations() {
         let token_id = [1u8; 32];
@@ -524,6 +3781,36 @@ mod tests {
         assert_eq!(get_balance(token_id, account_key), 0);
     }
 
+    #[test]
+    fn test_unbalanced_movements_write_nothing() {
+        let token = crate::test_utils::register_test_token(1, 0);
+        let (alice, bob) = ([2u8; 32], [3u8; 32]);
+        apply_movements(token, &[], &[(alice, 1_000)], 1_000).unwrap();
+        assert_eq!((get_balance(token, alice), get_token_metadata(token).unwrap().total_supply), (1_000, 1_000));
+
+        // A credit with no matching debit, a debit that goes nowhere, and a
+        // burn that is not taken from any account.
+        assert_eq!(
+            apply_movements(token, &[(alice, 100)], &[(bob, 90)], 0),
+            Err(MovementError::Unbalanced { sources: 100, sinks: 90 })
+        );
+        assert!(apply_movements(token, &[(alice, 100)], &[], 0).is_err());
+        assert!(apply_movements(token, &[], &[], -100).is_err());
+        assert!(apply_movements(token, &[(alice, 100)], &[(bob, 100)], 100).is_err());
+        assert_eq!(
+            apply_movements(token, &[(alice, 1_001)], &[(bob, 1_001)], 0),
+            Err(MovementError::InsufficientBalance { account_key: alice, balance: 1_000 })
+        );
+        assert_eq!((get_balance(token, alice), get_balance(token, bob)), (1_000, 0));
+        assert_eq!(get_token_metadata(token).unwrap().total_supply, 1_000);
+
+        // An account on both sides nets out.
+        apply_movements(token, &[(alice, 300), (bob, 0)], &[(bob, 250), (alice, 50)], 0).unwrap();
+        apply_movements(token, &[(bob, 50)], &[], -50).unwrap();
+        assert_eq!((get_balance(token, alice), get_balance(token, bob)), (750, 200));
+        assert_eq!(get_token_metadata(token).unwrap().total_supply, 950);
+    }
+
     #[test]
     fn test_allowance_operations() {
         let token_id = [1u8; 32];
@@ -0,0 +1,306 @@
+use crate::privacy::require_token_account_reader;
+use crate::queries::{account_deltas, QueryError};
+use crate::state;
+use crate::runtime;
+use crate::types::{constants, Account, TokenId};
+use crate::validation::{validate_account, validate_token_id};
+use candid::CandidType;
+use num_traits::cast::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+
+// Transactions of the account a statement may walk: the day's own plus every
+// later one its closing balance is rolled back through.
+pub const MAX_STATEMENT_SCAN: usize = 10_000;
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StatementMovement {
+    pub tx_index: u64,
+    // The block's timestamp, which may come from created_at_time; the day a
+    // movement belongs to goes by when its block was appended.
+    pub timestamp: u64,
+    pub delta: candid::Int,
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DailyStatement {
+    pub account: Account,
+    pub token_id: TokenId,
+    pub day_index: u64,
+    // The blocks appended during the day are first_tx_index..end_tx_index.
+    pub first_tx_index: u64,
+    pub end_tx_index: u64,
+    pub opening_balance: u128,
+    pub closing_balance: u128,
+    pub movements: Vec<StatementMovement>,
+    // False for the current day, whose closing balance can still change.
+    pub closed: bool,
+    // Hash of block end_tx_index - 1, the chain tip when the day closed.
+    pub last_block_hash: Option<[u8; 32]>,
+    pub statement_hash: [u8; 32],
+}
+
+
+// Day boundaries are midnight UTC in ledger time. A day with no blocks is an
+// empty range. Blocks appended before the day index existed have no day, so
+// statements start with the first day the index saw whole.
+fn day_range(day_index: u64) -> Result<(u64, u64), QueryError> {
+    let tx_count = state::get_transaction_count();
+    let Some((first_day, first_tx)) = state::first_indexed_day() else {
+        return if tx_count == 0 {
+            Ok((0, 0))
+        } else {
+            Err(QueryError::InvalidInput("No statements are available until the next block is appended".to_string()))
+        };
+    };
+    if first_tx > 0 && day_index <= first_day {
+        return Err(QueryError::InvalidInput(format!("Statements are available from day {}", first_day + 1)));
+    }
+
+    let start = state::first_tx_from_day(day_index).unwrap_or(tx_count);
+    let end = state::first_tx_from_day(day_index + 1).unwrap_or(tx_count);
+    Ok((start, end))
+}
+
+
+fn to_balance(value: candid::Int) -> Result<u128, QueryError> {
+    value.0.to_u128().ok_or_else(|| QueryError::InternalError("Account history does not add up to its balance".to_string()))
+}
+
+
+// Fixed-width big-endian fields in declaration order, so anyone holding the
+// statement can recompute the hash.
+fn statement_hash(statement: &DailyStatement) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"icrc151:statement:v1");
+    hasher.update(statement.account.to_key());
+    hasher.update(statement.token_id);
+    hasher.update(statement.day_index.to_be_bytes());
+    hasher.update(statement.first_tx_index.to_be_bytes());
+    hasher.update(statement.end_tx_index.to_be_bytes());
+    hasher.update(statement.opening_balance.to_be_bytes());
+    hasher.update(statement.closing_balance.to_be_bytes());
+    hasher.update((statement.movements.len() as u64).to_be_bytes());
+    for movement in &statement.movements {
+        let negative = movement.delta.0 < 0.into();
+        let magnitude = if negative { -movement.delta.0.clone() } else { movement.delta.0.clone() };
+        hasher.update(movement.tx_index.to_be_bytes());
+        hasher.update(movement.timestamp.to_be_bytes());
+        hasher.update([negative as u8]);
+        hasher.update(magnitude.to_u128().unwrap_or(u128::MAX).to_be_bytes());
+    }
+    hasher.update([statement.closed as u8]);
+    match statement.last_block_hash {
+        Some(hash) => {
+            hasher.update([1]);
+            hasher.update(hash);
+        }
+        None => hasher.update([0]),
+    }
+    hasher.finalize().into()
+}
+
+
+// The account's balance of `token_id` at the start and end of a day, with
+// every movement between. The closing balance is the current balance rolled
+// back through the account's later movements, so opening(n + 1) is always
+// closing(n).
+#[ic_cdk::query]
+pub fn get_daily_statement(account: Account, token_id: TokenId, day_index: u64) -> Result<DailyStatement, QueryError> {
+    validate_account(&account)?;
+    validate_token_id(&token_id)?;
+    require_token_account_reader(token_id, &account)?;
+    state::get_token_metadata(token_id)?;
+
+    let today = runtime::time() / constants::NANOS_PER_DAY;
+    if day_index > today {
+        return Err(QueryError::InvalidInput(format!("Day {} has not started; today is day {}", day_index, today)));
+    }
+    let (first_tx_index, end_tx_index) = day_range(day_index)?;
+
+    let account_key = account.to_key();
+    let tx_indices = state::list_account_transactions(account_key, first_tx_index, MAX_STATEMENT_SCAN + 1);
+    if tx_indices.len() > MAX_STATEMENT_SCAN {
+        return Err(QueryError::InvalidInput(format!(
+            "The account has more than {} transactions since the day began", MAX_STATEMENT_SCAN
+        )));
+    }
+
+    let mut movements = Vec::new();
+    let mut day_total = candid::Int::from(0);
+    let mut later_total = candid::Int::from(0);
+    for tx_index in tx_indices {
+        let tx = state::get_transaction(tx_index)
+            .ok_or_else(|| QueryError::InternalError(format!("Transaction {} is missing", tx_index)))?;
+        let delta = account_deltas(tx_index, &tx, account_key)
+            .into_iter()
+            .filter(|(token, _)| *token == token_id)
+            .fold(candid::Int::from(0), |total, (_, delta)| total + delta);
+        if delta.0 == 0.into() {
+            continue;
+        }
+        if tx_index < end_tx_index {
+            day_total += delta.clone();
+            movements.push(StatementMovement { tx_index, timestamp: tx.get_timestamp(), delta });
+        } else {
+            later_total += delta;
+        }
+    }
+
+    let closing = candid::Int::from(state::get_balance(token_id, account_key)) - later_total;
+    let opening = closing.clone() - day_total;
+    let mut statement = DailyStatement {
+        account,
+        token_id,
+        day_index,
+        first_tx_index,
+        end_tx_index,
+        opening_balance: to_balance(opening)?,
+        closing_balance: to_balance(closing)?,
+        movements,
+        closed: day_index < today,
+        last_block_hash: end_tx_index.checked_sub(1).and_then(state::get_block_hash),
+        statement_hash: [0; 32],
+    };
+    statement.statement_hash = statement_hash(&statement);
+    Ok(statement)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{mint_internal, transfer, Icrc151TransferArgs, TransferResult};
+    use crate::test_utils::{fee_recipient, register_test_token, test_account};
+
+    fn send(token: TokenId, from: &Account, to: &Account, amount: u64, created_at_time: Option<u64>) {
+        let controller = runtime::caller();
+        runtime::set_caller(from.owner);
+        let result = transfer(Icrc151TransferArgs {
+            token_id: token, from_subaccount: None, to: to.clone(), amount: candid::Nat::from(amount),
+            fee: None, memo: None, created_at_time, private_memo: None,
+            amount_mode: None, use_sponsorship: None, args_version: None,
+        });
+        runtime::set_caller(controller);
+        assert!(matches!(result, TransferResult::Ok(_)), "{:?}", result);
+    }
+
+    #[test]
+    fn test_statements_chain_across_days() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 10);
+        let (alice, bob) = (test_account(1), test_account(2));
+        let start_day = runtime::time() / constants::NANOS_PER_DAY;
+        // Moves to a second past midnight.
+        let next_day = || {
+            let day = runtime::time() / constants::NANOS_PER_DAY + 1;
+            runtime::advance_time(day * constants::NANOS_PER_DAY + 1_000_000_000 - runtime::time());
+        };
+
+        // Balances seen at the end of each day, to check the rolled-back closings.
+        let mut closings = Vec::new();
+        mint_internal(token, alice.clone(), 1_000, None, None).unwrap();
+        send(token, &alice, &bob, 100, None);
+        closings.push(state::get_balance(token, alice.to_key()));
+        next_day();
+        closings.push(state::get_balance(token, alice.to_key()));
+        next_day();
+        send(token, &bob, &alice, 50, None);
+        // Stamped yesterday, yet appended today, so it belongs to today.
+        let backdated = runtime::time() - 2_000_000_000;
+        send(token, &alice, &bob, 200, Some(backdated));
+        closings.push(state::get_balance(token, alice.to_key()));
+        next_day();
+        mint_internal(token, alice.clone(), 5, None, None).unwrap();
+        closings.push(state::get_balance(token, alice.to_key()));
+
+        let statement = |day| get_daily_statement(alice.clone(), token, start_day + day).unwrap();
+        let statements: Vec<_> = (0..4).map(statement).collect();
+        assert_eq!(statements[0].opening_balance, 0);
+        for (day, s) in statements.iter().enumerate() {
+            assert_eq!(s.closing_balance, closings[day], "day {}", day);
+            let moved: i128 = s.movements.iter().map(|m| i128::try_from(m.delta.0.clone()).unwrap()).sum();
+            assert_eq!(s.opening_balance as i128 + moved, s.closing_balance as i128);
+            assert_eq!(s.statement_hash, statement_hash(s));
+        }
+        for pair in statements.windows(2) {
+            assert_eq!(pair[1].opening_balance, pair[0].closing_balance);
+            assert_eq!(pair[1].first_tx_index, pair[0].end_tx_index);
+        }
+
+        assert_eq!(statements[0].movements.iter().map(|m| m.delta.clone()).collect::<Vec<_>>(),
+            vec![candid::Int::from(1_000), candid::Int::from(-110)]);
+        assert!(statements[1].movements.is_empty());
+        assert_eq!(statements[1].first_tx_index, statements[1].end_tx_index);
+        assert_eq!(statements[1].last_block_hash, statements[0].last_block_hash);
+        assert_eq!(statements[2].movements.len(), 2);
+        assert!(statements[2].closed && !statements[3].closed);
+        assert_eq!(statements[2].last_block_hash, state::get_block_hash(statements[2].end_tx_index - 1));
+
+        // The same day reads the same until it has closed and after.
+        assert_eq!(statement(2), statements[2]);
+        send(token, &alice, &bob, 1, None);
+        assert_eq!(statement(2), statements[2]);
+        assert_ne!(statement(3).statement_hash, statements[3].statement_hash);
+
+        assert!(get_daily_statement(alice.clone(), token, start_day + 4).is_err());
+        assert!(matches!(get_daily_statement(alice.clone(), [9; 32], start_day), Err(QueryError::TokenNotFound)));
+        let before = get_daily_statement(alice.clone(), token, start_day - 1).unwrap();
+        assert_eq!((before.opening_balance, before.closing_balance, before.end_tx_index), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_statements_of_fee_collectors() {
+        let controller = test_account(0xFD).owner;
+        state::init_state(controller);
+        runtime::set_caller(controller);
+        let token = register_test_token(1, 10);
+        let (alice, bob, treasury, collector) = (test_account(1), test_account(2), test_account(3), fee_recipient());
+        let start_day = runtime::time() / constants::NANOS_PER_DAY;
+        let next_day = || {
+            let day = runtime::time() / constants::NANOS_PER_DAY + 1;
+            runtime::advance_time(day * constants::NANOS_PER_DAY + 1_000_000_000 - runtime::time());
+        };
+
+        let mut closings = Vec::new();
+        let balances = || [state::get_balance(token, collector.to_key()), state::get_balance(token, treasury.to_key())];
+        mint_internal(token, alice.clone(), 1_000, None, None).unwrap();
+        send(token, &alice, &bob, 100, None);
+        send(token, &alice, &collector, 100, None);
+        closings.push(balances());
+        next_day();
+        // The collector pays its own fee; with the commission, part of it
+        // goes to the treasury.
+        state::set_protocol_commission(1_000, &treasury).unwrap();
+        send(token, &collector, &bob, 50, None);
+        send(token, &alice, &bob, 100, None);
+        closings.push(balances());
+        next_day();
+        send(token, &bob, &alice, 20, None);
+        closings.push(balances());
+
+        for (which, account) in [&collector, &treasury].into_iter().enumerate() {
+            let statements: Vec<_> = (0..3).map(|day| get_daily_statement(account.clone(), token, start_day + day).unwrap()).collect();
+            assert_eq!(statements[0].opening_balance, 0);
+            for (day, s) in statements.iter().enumerate() {
+                assert_eq!(s.closing_balance, closings[day][which], "{:?} day {}", account, day);
+                let moved: i128 = s.movements.iter().map(|m| i128::try_from(m.delta.0.clone()).unwrap()).sum();
+                assert_eq!(s.opening_balance as i128 + moved, s.closing_balance as i128);
+            }
+            for pair in statements.windows(2) {
+                assert_eq!(pair[1].opening_balance, pair[0].closing_balance);
+            }
+        }
+
+        let collected = |day| get_daily_statement(collector.clone(), token, start_day + day).unwrap().movements
+            .iter().map(|m| i128::try_from(m.delta.0.clone()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(collected(0), vec![10, 110]);
+        assert_eq!(collected(1), vec![-51, 9]);
+        assert_eq!(collected(2), vec![9]);
+    }
+}
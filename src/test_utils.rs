@@ -0,0 +1,113 @@
+use crate::costs::OperationCost;
+use crate::types::{Account, StoredTokenMetadata, Subaccount, TokenId};
+use crate::{runtime, state};
+use candid::Principal;
+use ic_stable_structures::Memory;
+use std::cell::Cell;
+
+
+pub fn test_account(id: u8) -> Account {
+    Account {
+        owner: Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, id]),
+        subaccount: None,
+    }
+}
+
+
+pub fn test_subaccount(id: u8, sub: u8) -> Account {
+    Account {
+        owner: test_account(id).owner,
+        subaccount: Some(vec![sub; 32]),
+    }
+}
+
+
+// The subaccount of a test account, as taken by endpoint arguments.
+pub fn subaccount_of(account: &Account) -> Option<Subaccount> {
+    account.subaccount.as_deref().map(|s| Subaccount(s.try_into().expect("test subaccounts are 32 bytes")))
+}
+
+
+pub fn fee_recipient() -> Account {
+    test_account(0xFE)
+}
+
+
+pub fn test_metadata(fee: u128) -> StoredTokenMetadata {
+    StoredTokenMetadata {
+        name: "Test".to_string(),
+        symbol: "TST".to_string(),
+        decimals: 8,
+        total_supply: 0,
+        fee,
+        fee_recipient: fee_recipient(),
+        logo: None,
+        description: None,
+        created_at: 0,
+        controller: fee_recipient().owner,
+        fee_token: None,
+        require_explicit_fee: None,
+        fee_free_consolidation: None,
+        immutable: None,
+        scheduled_fee: None,
+        burn_addresses: None,
+        non_circulating_accounts: None,
+        rounding_mode: None,
+        structured_memos_required: None,
+        max_holders: None,
+    }
+}
+
+
+pub fn register_test_token(seed: u8, fee: u128) -> TokenId {
+    let token_id = [seed; 32];
+    state::register_token(token_id, test_metadata(fee));
+    token_id
+}
+
+
+thread_local! {
+    static STABLE_READS: Cell<u32> = const { Cell::new(0) };
+    static STABLE_WRITES: Cell<u32> = const { Cell::new(0) };
+}
+
+
+// Stable memory that counts read and write calls, so tests can measure what
+// an operation touches.
+#[derive(Default)]
+pub struct CountingMemory<M>(M);
+
+impl<M: Memory> Memory for CountingMemory<M> {
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        self.0.grow(pages)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        STABLE_READS.with(|r| r.set(r.get().saturating_add(1)));
+        self.0.read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        STABLE_WRITES.with(|w| w.set(w.get().saturating_add(1)));
+        self.0.write(offset, src)
+    }
+}
+
+
+// The measurement behind the default operation costs: runs `op` and reports
+// the stable memory reads and writes it made, plus the instructions counted
+// by the runtime (only non-zero when run inside a canister).
+pub fn measure(op: impl FnOnce()) -> OperationCost {
+    let (reads, writes) = (STABLE_READS.with(Cell::get), STABLE_WRITES.with(Cell::get));
+    let instructions = runtime::instruction_counter();
+    op();
+    OperationCost {
+        instructions_estimate: runtime::instruction_counter() - instructions,
+        stable_reads: STABLE_READS.with(Cell::get) - reads,
+        stable_writes: STABLE_WRITES.with(Cell::get) - writes,
+    }
+}
@@ -0,0 +1,163 @@
+use crate::runtime;
+use crate::state;
+use crate::types::{AccountKey, TokenId};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+// Heap-only trace of ledger mutations for diagnosing balance and fee
+// discrepancies. Off by default and never written to stable memory, so an
+// upgrade both clears it and turns it off. While off, a mutation path pays one
+// flag check and nothing else.
+
+
+pub const MAX_TRACE_RECORDS: usize = 10_000;
+pub const MAX_TRACE_PAGE: u32 = 1_000;
+
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static NEXT_SEQ: Cell<u64> = const { Cell::new(0) };
+    static RECORDS: RefCell<VecDeque<TraceRecord>> = const { RefCell::new(VecDeque::new()) };
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub op: String,
+    pub token_id: TokenId,
+    pub accounts: Vec<AccountKey>,
+    pub amounts: Vec<candid::Nat>,
+    // The transaction index, or the error as the caller saw it.
+    pub result: Result<u64, String>,
+}
+
+
+#[inline]
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.with(|e| e.get())
+}
+
+
+// Callers check is_enabled first so that the keys are only derived while
+// tracing is on.
+pub(crate) fn record<E: Debug>(op: &str, token_id: TokenId, accounts: &[AccountKey], amounts: &[u128], result: &Result<u64, E>) {
+    let seq = NEXT_SEQ.with(|s| s.replace(s.get() + 1));
+    let record = TraceRecord {
+        seq,
+        timestamp: runtime::time(),
+        caller: runtime::caller(),
+        op: op.to_string(),
+        token_id,
+        accounts: accounts.to_vec(),
+        amounts: amounts.iter().map(|a| candid::Nat::from(*a)).collect(),
+        result: result.as_ref().map(|tx_index| *tx_index).map_err(|e| format!("{:?}", e)),
+    };
+    RECORDS.with(|r| {
+        let mut records = r.borrow_mut();
+        if records.len() >= MAX_TRACE_RECORDS {
+            records.pop_front();
+        }
+        records.push_back(record);
+    });
+}
+
+
+#[ic_cdk::update]
+pub fn set_tracing_enabled(enabled: bool) -> Result<(), String> {
+    state::require_controller()?;
+
+    let previous = ENABLED.with(|e| e.replace(enabled));
+    state::record_admin_action("set_tracing_enabled", format!("{} -> {}", previous, enabled));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+pub fn get_tracing_enabled() -> bool {
+    is_enabled()
+}
+
+
+// Newest first. With a filter, only records touching that account.
+#[ic_cdk::query]
+pub fn get_trace(filter: Option<AccountKey>, limit: Option<u32>) -> Result<Vec<TraceRecord>, String> {
+    state::require_controller()?;
+
+    let limit = limit.unwrap_or(MAX_TRACE_PAGE).min(MAX_TRACE_PAGE) as usize;
+    Ok(RECORDS.with(|r| {
+        r.borrow()
+            .iter()
+            .rev()
+            .filter(|record| filter.is_none_or(|key| record.accounts.contains(&key)))
+            .take(limit)
+            .cloned()
+            .collect()
+    }))
+}
+
+
+#[ic_cdk::update]
+pub fn clear_trace() -> Result<(), String> {
+    state::require_controller()?;
+
+    let cleared = RECORDS.with(|r| std::mem::take(&mut *r.borrow_mut()).len());
+    state::record_admin_action("clear_trace", format!("{} records", cleared));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::{mint_internal, transfer_internal};
+    use crate::test_utils::{register_test_token, test_account};
+
+    #[test]
+    fn test_trace_records_mutations_while_enabled() {
+        let controller = test_account(0xFE).owner;
+        state::init_state(controller);
+        let token = register_test_token(1, 10);
+        let (alice, bob) = (test_account(1), test_account(2));
+
+        runtime::set_caller(controller);
+        mint_internal(token, alice.clone(), 1_000, None, None).unwrap();
+        assert!(get_trace(None, None).unwrap().is_empty());
+
+        set_tracing_enabled(true).unwrap();
+        let sent = transfer_internal(token, alice.clone(), bob.clone(), 100, None, None, None).unwrap();
+        runtime::advance_time(1);
+        transfer_internal(token, bob.clone(), alice.clone(), 5_000, None, None, None).unwrap_err();
+        mint_internal(token, bob.clone(), 7, None, None).unwrap();
+
+        let trace = get_trace(None, None).unwrap();
+        let ops: Vec<_> = trace.iter().map(|r| r.op.as_str()).collect();
+        assert_eq!(ops, ["mint", "transfer", "transfer"]);
+        assert_eq!(trace[2].result, Ok(sent));
+        assert_eq!(trace[2].accounts, vec![alice.to_key(), bob.to_key()]);
+        assert_eq!(trace[2].amounts, vec![candid::Nat::from(100u64)]);
+        assert!(trace[1].result.as_ref().unwrap_err().contains("InsufficientFunds"));
+
+        let only_alice = get_trace(Some(alice.to_key()), Some(10)).unwrap();
+        assert_eq!(only_alice.iter().map(|r| r.seq).collect::<Vec<_>>(), vec![trace[1].seq, trace[2].seq]);
+        assert_eq!(get_trace(None, Some(1)).unwrap().len(), 1);
+
+        runtime::set_caller(alice.owner);
+        assert!(get_trace(None, None).is_err());
+        assert!(clear_trace().is_err());
+        assert!(set_tracing_enabled(false).is_err());
+
+        runtime::set_caller(controller);
+        set_tracing_enabled(false).unwrap();
+        runtime::advance_time(1);
+        mint_internal(token, bob, 7, None, None).unwrap();
+        assert_eq!(get_trace(None, None).unwrap().len(), 3);
+        clear_trace().unwrap();
+        assert!(get_trace(None, None).unwrap().is_empty());
+    }
+}
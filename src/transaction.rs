@@ -1,11 +1,12 @@
-use crate::types::{TokenId, AccountKey};
+use crate::types::{TokenId, AccountKey, constants::INLINE_MEMO_LEN};
 use ic_stable_structures::Storable;
 use std::borrow::Cow;
 use candid::CandidType;
+use serde::{Deserialize, Serialize};
 
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, CandidType)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
 pub struct StoredTxV1 {
     pub op: u8,
     pub flags: u8,
@@ -17,10 +18,49 @@ pub struct StoredTxV1 {
     pub fee: [u8; 16],
     pub timestamp: [u8; 8],
     pub memo: [u8; 32],
+    #[serde(with = "reserved_bytes")]
     pub _reserved: [u8; 54],
 }
 
 
+// serde only derives arrays up to 32 elements.
+mod reserved_bytes {
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::ser::SerializeTuple;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8; 54], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(54)?;
+        for byte in bytes {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 54], D::Error> {
+        struct ReservedVisitor;
+
+        impl<'de> Visitor<'de> for ReservedVisitor {
+            type Value = [u8; 54];
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("54 bytes")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = [0u8; 54];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(i, &self))?;
+                }
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_tuple(54, ReservedVisitor)
+    }
+}
+
+
 const _: () = assert!(std::mem::size_of::<StoredTxV1>() == 256);
 
 
@@ -28,6 +68,16 @@ pub const FLAG_HAS_FEE: u8 = 1;
 pub const FLAG_HAS_MEMO: u8 = 2;
 pub const FLAG_HAS_SPENDER: u8 = 4;
 pub const FLAG_MEMO_EXTENDED: u8 = 8;
+pub const FLAG_FEE_TOKEN: u8 = 16;
+pub const FLAG_HAS_TOKEN_SEQ: u8 = 32;
+pub const FLAG_MEMO_PRIVATE: u8 = 64;
+pub const FLAG_FEE_SPONSORED: u8 = 128;
+
+pub const OP_TRANSFER: u8 = 0;
+pub const OP_MINT: u8 = 1;
+pub const OP_BURN: u8 = 2;
+pub const OP_APPROVE: u8 = 3;
+pub const OP_TRANSFER_FROM: u8 = 4;
 
 impl StoredTxV1 {
 
@@ -60,10 +110,10 @@ impl StoredTxV1 {
 
         if let Some(memo_bytes) = memo {
             tx.flags |= FLAG_HAS_MEMO;
-            let copy_len = memo_bytes.len().min(32);
+            let copy_len = memo_bytes.len().min(INLINE_MEMO_LEN);
             tx.memo[..copy_len].copy_from_slice(&memo_bytes[..copy_len]);
             
-            if memo_bytes.len() > 32 {
+            if memo_bytes.len() > INLINE_MEMO_LEN {
                 tx.flags |= FLAG_MEMO_EXTENDED;
             }
         }
@@ -183,6 +233,7 @@ impl StoredTxV1 {
     }
 
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_transfer_from(
         token_id: TokenId,
         from_key: AccountKey,
@@ -225,6 +276,115 @@ impl StoredTxV1 {
     }
 
 
+    pub fn with_fee_token(mut self, fee_token: TokenId) -> Self {
+        self.flags |= FLAG_FEE_TOKEN;
+        self._reserved[0..32].copy_from_slice(&fee_token);
+        self
+    }
+
+
+    pub fn get_fee_token(&self) -> Option<TokenId> {
+        if self.flags & FLAG_FEE_TOKEN == 0 {
+            return None;
+        }
+        let mut fee_token = [0u8; 32];
+        fee_token.copy_from_slice(&self._reserved[0..32]);
+        Some(fee_token)
+    }
+
+
+    // The inline field holds a commitment instead of the memo, which only lives
+    // in the extended memo map. Not FLAG_MEMO_EXTENDED: the plaintext stays out
+    // of range exports and the block hash.
+    pub fn set_private_memo(&mut self, commitment: [u8; 32]) {
+        self.flags = (self.flags | FLAG_HAS_MEMO | FLAG_MEMO_PRIVATE) & !FLAG_MEMO_EXTENDED;
+        self.memo = commitment;
+    }
+
+
+    // Burns converted from a transfer to a burn address keep that address in
+    // to_key, which burns otherwise leave zeroed. No balance is credited.
+    pub fn with_burn_target(mut self, target: AccountKey) -> Self {
+        self.to_key = target;
+        self
+    }
+
+
+    pub fn get_burn_target(&self) -> Option<AccountKey> {
+        (self.op == OP_BURN && self.to_key != [0; 32]).then_some(self.to_key)
+    }
+
+
+    // Burns made by burn_from name the spender whose allowance they used.
+    pub fn with_spender(mut self, spender_key: AccountKey) -> Self {
+        self.flags |= FLAG_HAS_SPENDER;
+        self.spender_key = spender_key;
+        self
+    }
+
+
+    // Stored after the fee token slot, so both can coexist.
+    pub fn set_token_seq(&mut self, seq: u64) {
+        self.flags |= FLAG_HAS_TOKEN_SEQ;
+        self._reserved[32..40].copy_from_slice(&seq.to_le_bytes());
+    }
+
+
+    pub fn get_token_seq(&self) -> Option<u64> {
+        if self.flags & FLAG_HAS_TOKEN_SEQ == 0 {
+            return None;
+        }
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&self._reserved[32..40]);
+        Some(u64::from_le_bytes(seq))
+    }
+
+
+    // A transfer whose fee a sponsorship paid keeps the sponsor in spender_key,
+    // which transfers otherwise leave zeroed.
+    pub fn with_fee_sponsor(mut self, sponsor_key: AccountKey) -> Self {
+        self.flags |= FLAG_FEE_SPONSORED;
+        self.spender_key = sponsor_key;
+        self
+    }
+
+
+    pub fn get_fee_sponsor(&self) -> Option<AccountKey> {
+        (self.flags & FLAG_FEE_SPONSORED != 0).then_some(self.spender_key)
+    }
+
+
+    // Whose balance the fee came out of.
+    pub fn fee_payer(&self) -> AccountKey {
+        self.get_fee_sponsor().unwrap_or(self.from_key)
+    }
+
+
+    // Accounts whose balance this transaction changes: the payer of the amount
+    // or fee, the receiver and whoever `fee_credits` (from fees::fee_credits)
+    // says the fee went to. Spenders only appear when they are one of those.
+    pub fn balance_accounts(&self, fee_credits: &[(AccountKey, u128)]) -> Vec<AccountKey> {
+        let mut accounts = Vec::with_capacity(2 + fee_credits.len());
+        if self.op != 1 && (self.op != 3 || self.has_fee()) {
+            accounts.push(self.from_key);
+        }
+        if matches!(self.op, 0 | 1 | 4) && !accounts.contains(&self.to_key) {
+            accounts.push(self.to_key);
+        }
+        if let Some(sponsor_key) = self.get_fee_sponsor() {
+            if !accounts.contains(&sponsor_key) {
+                accounts.push(sponsor_key);
+            }
+        }
+        for (account_key, _) in fee_credits {
+            if !accounts.contains(account_key) {
+                accounts.push(*account_key);
+            }
+        }
+        accounts
+    }
+
+
     pub fn get_amount(&self) -> u128 {
         u128::from_le_bytes(self.amount)
     }
@@ -260,6 +420,11 @@ impl StoredTxV1 {
     }
 
 
+    pub fn has_private_memo(&self) -> bool {
+        self.flags & FLAG_MEMO_PRIVATE != 0
+    }
+
+
     pub fn to_bytes(&self) -> [u8; 256] {
         let mut buf = [0u8; 256];
         buf[0] = self.op;
@@ -395,4 +560,15 @@ mod tests {
         assert_eq!(tx.timestamp, tx2.timestamp);
         assert_eq!(tx.memo, tx2.memo);
     }
+
+    #[test]
+    fn test_fee_token_roundtrip() {
+        let tx = StoredTxV1::new_transfer([1u8; 32], [2u8; 32], [3u8; 32], 1000, 10, 1693564800000000000, None);
+        assert_eq!(tx.get_fee_token(), None);
+
+        let tx = tx.with_fee_token([9u8; 32]);
+        let tx2 = StoredTxV1::from_bytes(&tx.to_bytes());
+        assert_eq!(tx2.flags & FLAG_FEE_TOKEN, FLAG_FEE_TOKEN);
+        assert_eq!(tx2.get_fee_token(), Some([9u8; 32]));
+    }
 }
\ No newline at end of file
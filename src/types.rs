@@ -10,22 +10,149 @@ pub type AccountKey = [u8; 32];
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Account {
     pub owner: Principal,
+    // Length is checked by validate_account; decoding only bounds it.
+    #[serde(deserialize_with = "deserialize_account_subaccount")]
     pub subaccount: Option<Vec<u8>>,
 }
 
+fn deserialize_account_subaccount<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+    Ok(Option::<BoundedBytes<32>>::deserialize(d)?.map(|b| b.0))
+}
+
+
+// Blob decoded from the borrowed input, so oversized values are rejected
+// before anything is allocated.
+struct BoundedBytes<const MAX: usize>(Vec<u8>);
+
+impl<'de, const MAX: usize> Deserialize<'de> for BoundedBytes<MAX> {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct BoundedVisitor<const MAX: usize>;
+
+        impl<'de, const MAX: usize> serde::de::Visitor<'de> for BoundedVisitor<MAX> {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a blob of at most {} bytes", MAX)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                if v.len() > MAX {
+                    return Err(E::invalid_length(v.len(), &self));
+                }
+                Ok(v.to_vec())
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(b) = seq.next_element::<u8>()? {
+                    if bytes.len() == MAX {
+                        return Err(serde::de::Error::invalid_length(MAX + 1, &self));
+                    }
+                    bytes.push(b);
+                }
+                Ok(bytes)
+            }
+        }
+
+        d.deserialize_bytes(BoundedVisitor::<MAX>).map(BoundedBytes)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subaccount(pub [u8; 32]);
+
+impl Subaccount {
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+impl CandidType for Subaccount {
+    fn _ty() -> candid::types::Type {
+        <Vec<u8> as CandidType>::ty()
+    }
+
+    fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        serializer.serialize_blob(&self.0)
+    }
+}
+
+impl Serialize for Subaccount {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Subaccount {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let bytes = BoundedBytes::<32>::deserialize(d)?.0;
+        <[u8; 32]>::try_from(bytes.as_slice())
+            .map(Subaccount)
+            .map_err(|_| serde::de::Error::invalid_length(bytes.len(), &"exactly 32 bytes"))
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Memo(pub Vec<u8>);
+
+impl std::ops::Deref for Memo {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl CandidType for Memo {
+    fn _ty() -> candid::types::Type {
+        <Vec<u8> as CandidType>::ty()
+    }
+
+    fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        serializer.serialize_blob(&self.0)
+    }
+}
+
+impl Serialize for Memo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Memo {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        Ok(Memo(BoundedBytes::<{ constants::MAX_MEMO_LEN }>::deserialize(d)?.0))
+    }
+}
+
 impl Account {
     pub fn to_key(&self) -> AccountKey {
         let mut hasher = Sha256::new();
         hasher.update(b"icrc151:account:v1");
         hasher.update(self.owner.as_slice());
+        hasher.update(self.effective_subaccount());
+        hasher.finalize().into()
+    }
 
-        let subaccount_32 = match &self.subaccount {
+    // No subaccount is the all-zero subaccount, as in ICRC-1.
+    pub fn effective_subaccount(&self) -> &[u8] {
+        match &self.subaccount {
             Some(sub) => sub.as_slice(),
             None => &[0u8; 32],
-        };
+        }
+    }
 
-        hasher.update(subaccount_32);
-        hasher.finalize().into()
+    // Equality of the accounts themselves, not of their encodings: `None` and
+    // the zero subaccount compare equal. Use this, not `==`, for self-checks.
+    pub fn canonical_eq(&self, other: &Account) -> bool {
+        self.owner == other.owner && self.effective_subaccount() == other.effective_subaccount()
+    }
+
+    // The subaccount if it is anything but the default one.
+    pub fn named_subaccount(&self) -> Option<&[u8]> {
+        self.subaccount.as_deref().filter(|sub| sub.iter().any(|b| *b != 0))
     }
 }
 
@@ -82,6 +209,64 @@ impl Storable for StoredPrincipal {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoredAccount {
+    pub owner: StoredPrincipal,
+    pub subaccount: Option<[u8; 32]>,
+}
+
+impl StoredAccount {
+    pub fn from_account(account: &Account) -> Result<Self, String> {
+        let subaccount = match &account.subaccount {
+            Some(sub) => Some(<[u8; 32]>::try_from(sub.as_slice())
+                .map_err(|_| format!("Invalid subaccount length: {}", sub.len()))?),
+            None => None,
+        };
+        Ok(Self {
+            owner: StoredPrincipal::from_principal(&account.owner)?,
+            subaccount,
+        })
+    }
+
+    pub fn to_account(&self) -> Result<Account, String> {
+        Ok(Account {
+            owner: self.owner.to_principal()?,
+            subaccount: self.subaccount.map(|sub| sub.to_vec()),
+        })
+    }
+}
+
+impl Storable for StoredAccount {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 63,
+            is_fixed_size: true
+        };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = [0u8; 63];
+        buf[0..30].copy_from_slice(&self.owner.to_bytes());
+        if let Some(sub) = self.subaccount {
+            buf[30] = 1;
+            buf[31..63].copy_from_slice(&sub);
+        }
+        Cow::Owned(buf.to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        assert_eq!(bytes.len(), 63, "StoredAccount must be exactly 63 bytes");
+        let owner = StoredPrincipal::from_bytes(Cow::Borrowed(&bytes[0..30]));
+        let subaccount = if bytes[30] == 1 {
+            let mut sub = [0u8; 32];
+            sub.copy_from_slice(&bytes[31..63]);
+            Some(sub)
+        } else {
+            None
+        };
+        Self { owner, subaccount }
+    }
+}
+
 pub mod memory_ids {
     pub const TOKEN_REGISTRY: u8 = 0;          // TokenId → TokenMetadata
     pub const BALANCE_STORAGE: u8 = 1;         // BalanceKey → u128
@@ -90,20 +275,99 @@ pub mod memory_ids {
     pub const TX_INDEX_RECENT: u8 = 4;         // Recent tx index (hot window)
     pub const ARCHIVE_INDEX: u8 = 5;           // start_idx → ArchiveManifest
     pub const SYSTEM_STATE: u8 = 6;            // System config and counters
-    pub const TOKEN_ACCOUNTS_INDEX: u8 = 7;    // Token→Accounts mapping
-    pub const ACCOUNT_TOKENS_INDEX: u8 = 8;    // Account→Tokens mapping
+    pub const TOKEN_ACCOUNTS_INDEX: u8 = 7;    // token_id ++ account_key → () for non-zero balances
+    pub const ACCOUNT_TOKENS_INDEX: u8 = 8;    // account_key ++ token_id → () for non-zero balances
     pub const EXTENDED_MEMOS: u8 = 9;          // Extended memo storage
     pub const ALLOWANCE_EXPIRY_INDEX: u8 = 10; // Allowance expiry index
     pub const TX_INDEX_BUFFER: u8 = 11;        // Tx index buffer for archiving
     pub const DEDUP_MAP: u8 = 12;              // Deduplication: hash → tx_index
     pub const CONTROLLERS: u8 = 13;            // Controllers set: StoredPrincipal → u8
     pub const HOLDER_COUNTS: u8 = 14;          // Holder counts: TokenId → u64
-    pub const RESERVED_START: u8 = 15;         // Reserved for future extensions
+    pub const ACCOUNT_REGISTRY: u8 = 15;       // AccountKey → StoredAccount
+    pub const SPENDER_ALLOWANCES: u8 = 16;     // spender_key ++ token_id ++ owner_key → ()
+    pub const FEE_STATS: u8 = 17;              // Fee token → collected fees (recipient, treasury)
+    pub const HOLDER_COUNT_HISTORY: u8 = 18;   // token_id ++ day → holder count
+    pub const OWNER_ALLOWANCES: u8 = 19;       // owner_key ++ token_id ++ spender_key → ()
+    pub const SPENDER_SUBACCOUNTS: u8 = 20;    // principal (30) ++ subaccount → ()
+    pub const METADATA_SUBSCRIBERS: u8 = 21;   // Subscriber principal → filter + failure count
+    pub const ADMIN_AUDIT_LOG: u8 = 22;        // Sequence number → AdminAuditEntry
+    pub const TOKEN_TX_SEQ: u8 = 23;           // "txl/" ++ token_id ++ token seq → global tx index
+    pub const LEGACY_CALL_COUNTS: u8 = 24;     // Deprecated method name → call count
+    pub const BALANCE_SEQ: u8 = 25;            // TokenId → balance mutation counter
+    pub const TOKEN_MUTATION_SEQ: u8 = 26;     // TokenId → mutation counter (balances, allowances, metadata)
+    pub const ADMIN_EXPIRED_ALLOWANCES: u8 = 27; // Audit log index → AdminExpiredAllowance
+    pub const NETTING_AUTHORIZATIONS: u8 = 28; // account_key ++ counterparty_key → authorized_at
+    pub const NETTING_SETTLEMENTS: u8 = 29;    // Settlement index → SettlementRecord
+    pub const ACCOUNT_TX_INDEX: u8 = 30;       // account_key ++ tx index → () for txs moving the account's funds
+    pub const INCOMING_POLICIES: u8 = 31;      // AccountKey → IncomingPolicyConfig (non-default only)
+    pub const BLOCK_HASHES: u8 = 32;           // tx index → chained block hash
+    pub const DEV_FAUCET_CLAIMS: u8 = 33;      // caller (30) ++ day → amount claimed (dev builds only)
+    pub const AUDITORS: u8 = 34;               // Auditor principal → u8 (privacy mode readers)
+    pub const ALLOWANCE_EXPIRY_QUEUE: u8 = 35; // expires_at ++ token_id ++ owner_key ++ spender_key → ()
+    pub const EXPIRY_SUBSCRIBERS: u8 = 36;     // Subscriber principal → allowance expiry subscription
+    pub const BALANCE_ALERTS: u8 = 37;         // account_key ++ token_id ++ alert id → BalanceAlert
+    pub const PRIVATE_MEMO_READERS: u8 = 38;   // tx index (u64 BE) ++ principal (30) → () for private memos
+    pub const API_ACCESS_GRANTS: u8 = 39;      // token_id ++ principal (30) → ApiAccessGrant
+    pub const BURN_RECEIPTS: u8 = 40;          // tx index → StoredBurnReceipt for burns made through burn_tokens*
+    pub const FAILURE_LOG: u8 = 41;            // Ring slot (u32) → StoredFailure, while the failure log is on
+    pub const TOKEN_LOCALIZATIONS: u8 = 42;    // token_id ++ locale (5, zero-padded) → TokenLocalization
+    pub const RESERVATIONS: u8 = 43;           // Reservation id → Reservation (balance hold)
+    pub const ACCOUNT_RESERVATIONS: u8 = 44;   // token_id ++ account_key ++ reservation id → ()
+    pub const RESERVATION_EXPIRY: u8 = 45;     // expires_at ++ reservation id → ()
+    pub const TRANSFER_NONCES: u8 = 46;        // Owner principal → next signed-transfer nonce (relay feature)
+    pub const TOKEN_IMPORTS: u8 = 47;          // token_id → TokenImport while the token's import is open
+    pub const BALANCE_SNAPSHOTS: u8 = 48;      // Snapshot id → BalanceSnapshot
+    pub const SNAPSHOT_BALANCES: u8 = 49;      // snapshot id ++ account_key → balance when the snapshot was taken
+    pub const AIRDROPS: u8 = 50;               // snapshot id ++ reward token_id → Airdrop
+    pub const SPONSORSHIPS: u8 = 51;           // Sponsorship id → Sponsorship (fee budget)
+    pub const BENEFICIARY_SPONSORSHIPS: u8 = 52; // token_id ++ beneficiary_key ++ sponsorship id → ()
+    pub const SPONSOR_SPONSORSHIPS: u8 = 53;   // sponsor_key ++ sponsorship id → ()
+    pub const SPONSORSHIP_EXPIRY: u8 = 54;     // expires_at ++ sponsorship id → ()
+    pub const SPONSOR_HOLDS: u8 = 55;          // token_id ++ sponsor_key → unspent budget held from the sponsor
+    pub const DAY_FIRST_TX: u8 = 56;           // Ledger day (UTC) → index of the first block appended that day
+    pub const ACCOUNT_BALANCE_SEQ: u8 = 57;    // AccountKey → counter of the account's balance changes, all tokens
+    pub const FEE_COMMISSIONS: u8 = 58;        // tx_index → (treasury key, its share of the block's fee)
+    pub const RESERVED_START: u8 = 59;         // Reserved for future extensions
+    // Regions compact_structure hands out; never used as a structure's home id.
+    pub const COMPACTION_REGIONS_START: u8 = 200;
+    pub const COMPACTION_REGIONS_END: u8 = 254; // The memory manager's last id
 }
 
 pub mod constants {
     pub const MAX_FUTURE_DRIFT: u64 = 300_000_000_000;
     pub const MAX_PAST_DRIFT: u64 = 600_000_000_000;
+    pub const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+    pub const DEFAULT_HOLDER_HISTORY_RETENTION_DAYS: u32 = 365;
+    pub const MAX_HOLDER_HISTORY_QUERY_DAYS: u64 = 366;
+    pub const DEFAULT_MAX_TOKENS: u64 = 100_000;
+    // 64 KiB pages; 400 GiB leaves headroom below the subnet's stable memory cap.
+    pub const DEFAULT_STABLE_PAGES_SOFT_LIMIT: u64 = 6_553_600;
+    // Controllers are warned when a token reaches this share of the holder soft cap.
+    pub const HOLDER_SOFT_CAP_WARNING_PERCENT: u64 = 90;
+    pub const MAX_AUDIT_LOG_PAGE: u64 = 100;
+    pub const MIN_RECOVERY_DELAY_NS: u64 = NANOS_PER_DAY;
+    pub const MAX_ADMIN_REASON_LEN: usize = 256;
+    pub const MAX_MEMO_LEN: usize = 65_536;
+    // Longer memos are kept in the extended memo store.
+    pub const INLINE_MEMO_LEN: usize = 32;
+    pub const MAX_TRANSACTIONS_PAGE: u64 = 1000;
+    pub const DEFAULT_TOKEN_FEE: u128 = 10_000;
+    pub const MAX_NETTING_ENTRIES: usize = 5_000;
+    pub const MAX_NETTING_REF_LEN: usize = 64;
+    pub const MAX_INCOMING_ALLOWLIST: usize = 100;
+    pub const MAX_OFFICIAL_ACCOUNTS: usize = 100;
+    pub const MAX_AUDITORS: u64 = 50;
+    pub const MAX_API_GRANTS_PER_TOKEN: usize = 20;
+    pub const MAX_TOKEN_LOCALES: usize = 16;
+    // Holds per account and token, which also bounds the cost of every debit check.
+    pub const MAX_RESERVATIONS_PER_ACCOUNT: usize = 20;
+    // Fee sponsorships a beneficiary may hold per token, which bounds the search
+    // a sponsored transfer makes.
+    pub const MAX_SPONSORSHIPS_PER_BENEFICIARY: usize = 10;
+    // An allowance of u128::MAX is unlimited: transfer_from never decrements it.
+    pub const UNLIMITED_ALLOWANCE: u128 = u128::MAX;
+    // Upper bound for either side of a configured created_at_time window.
+    pub const MAX_TX_WINDOW_NS: u64 = NANOS_PER_DAY;
 }
 pub fn encode_tx_index_key(token_id: TokenId, local_index: u64) -> [u8; 44] {
     let mut key = [0u8; 44];
@@ -141,28 +405,103 @@ pub fn encode_allowance_expiry_key(expires_at: u64, allowance_key: [u8; 32]) ->
     key
 }
 
+// Orders allowances by expiry, unlike the hashed expiry index.
+pub fn encode_allowance_expiry_queue_key(expires_at: u64, token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) -> [u8; 104] {
+    let mut key = [0u8; 104];
+    key[0..8].copy_from_slice(&expires_at.to_be_bytes());
+    key[8..40].copy_from_slice(&token_id);
+    key[40..72].copy_from_slice(&owner_key);
+    key[72..104].copy_from_slice(&spender_key);
+    key
+}
+
+pub fn decode_allowance_expiry_queue_key(key: &[u8; 104]) -> (u64, TokenId, AccountKey, AccountKey) {
+    let mut expires_at = [0u8; 8];
+    let mut token_id = [0u8; 32];
+    let mut owner_key = [0u8; 32];
+    let mut spender_key = [0u8; 32];
+    expires_at.copy_from_slice(&key[0..8]);
+    token_id.copy_from_slice(&key[8..40]);
+    owner_key.copy_from_slice(&key[40..72]);
+    spender_key.copy_from_slice(&key[72..104]);
+    (u64::from_be_bytes(expires_at), token_id, owner_key, spender_key)
+}
+
+pub fn encode_spender_allowance_key(spender_key: AccountKey, token_id: TokenId, owner_key: AccountKey) -> [u8; 96] {
+    let mut key = [0u8; 96];
+    key[0..32].copy_from_slice(&spender_key);
+    key[32..64].copy_from_slice(&token_id);
+    key[64..96].copy_from_slice(&owner_key);
+    key
+}
+
+pub fn encode_owner_allowance_key(owner_key: AccountKey, token_id: TokenId, spender_key: AccountKey) -> [u8; 96] {
+    let mut key = [0u8; 96];
+    key[0..32].copy_from_slice(&owner_key);
+    key[32..64].copy_from_slice(&token_id);
+    key[64..96].copy_from_slice(&spender_key);
+    key
+}
+
+pub fn encode_principal_subaccount_key(principal: &StoredPrincipal, subaccount: [u8; 32]) -> [u8; 62] {
+    let mut key = [0u8; 62];
+    key[0..30].copy_from_slice(&principal.to_bytes());
+    key[30..62].copy_from_slice(&subaccount);
+    key
+}
+
+pub fn encode_holder_history_key(token_id: TokenId, day: u64) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[0..32].copy_from_slice(&token_id);
+    key[32..40].copy_from_slice(&day.to_be_bytes());
+    key
+}
+
 pub fn hash_balance_key(token_id: TokenId, account_key: AccountKey) -> [u8; 32] {
     let mut hasher = Sha256::new();
     hasher.update(b"icrc151:balance:v1");
-    hasher.update(&token_id);
-    hasher.update(&account_key);
+    hasher.update(token_id);
+    hasher.update(account_key);
     hasher.finalize().into()
 }
 
 pub fn hash_allowance_key(token_id: TokenId, owner_key: AccountKey, spender_key: AccountKey) -> [u8; 32] {
     let mut hasher = Sha256::new();
     hasher.update(b"icrc151:allowance:v1");
-    hasher.update(&token_id);
-    hasher.update(&owner_key);
-    hasher.update(&spender_key);
+    hasher.update(token_id);
+    hasher.update(owner_key);
+    hasher.update(spender_key);
     hasher.finalize().into()
 }
 
+pub fn encode_account_tx_key(account_key: AccountKey, tx_index: u64) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..32].copy_from_slice(&account_key);
+    key[32..].copy_from_slice(&tx_index.to_be_bytes());
+    key
+}
+
+pub fn encode_netting_key(account_key: AccountKey, counterparty_key: AccountKey) -> [u8; 64] {
+    let mut key = [0u8; 64];
+    key[..32].copy_from_slice(&account_key);
+    key[32..].copy_from_slice(&counterparty_key);
+    key
+}
+
 pub fn derive_token_id(ledger_principal: Principal, nonce: u64) -> TokenId {
     let mut hasher = Sha256::new();
     hasher.update(b"icrc151:token:v1");
     hasher.update(ledger_principal.as_slice());
-    hasher.update(&nonce.to_be_bytes());
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().into()
+}
+
+pub fn derive_token_id_v2(ledger_principal: Principal, creator: Principal, namespace: &[u8]) -> TokenId {
+    let mut hasher = Sha256::new();
+    hasher.update(b"icrc151:token:v2");
+    hasher.update(ledger_principal.as_slice());
+    hasher.update(creator.as_slice());
+    hasher.update(namespace);
     hasher.finalize().into()
 }
 
@@ -178,19 +517,701 @@ pub struct StoredTokenMetadata {
     pub description: Option<String>,
     pub created_at: u64,
     pub controller: Principal,
+    pub fee_token: Option<TokenId>,
+    pub require_explicit_fee: Option<bool>,
+    pub fee_free_consolidation: Option<bool>,
+    // Set once by freeze_token_config; never cleared.
+    pub immutable: Option<bool>,
+    pub scheduled_fee: Option<ScheduledFee>,
+    // Transfers to these accounts are burned instead of credited.
+    pub burn_addresses: Option<Vec<Account>>,
+    // Left out of the circulating supply, as are the burn addresses.
+    pub non_circulating_accounts: Option<Vec<Account>>,
+    // How proportional amounts such as the protocol commission are rounded; Floor when unset.
+    pub rounding_mode: Option<crate::rounding::RoundingMode>,
+    // Movements must carry a memo in the structured memo format; see memo.rs.
+    pub structured_memos_required: Option<bool>,
+    // Movements that would add holders past this are refused.
+    pub max_holders: Option<u64>,
 }
 
-impl Storable for StoredTokenMetadata {
+#[derive(candid::CandidType, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ScheduledFee {
+    pub fee: u128,
+    pub effective_at: u64,
+}
+
+impl StoredTokenMetadata {
+    // A scheduled fee replaces `fee` from `effective_at` on, whether or not
+    // anything has written the token since.
+    pub fn apply_due_fee(&mut self, now: u64) {
+        if let Some(scheduled) = self.scheduled_fee {
+            if now >= scheduled.effective_at {
+                self.fee = scheduled.fee;
+                self.scheduled_fee = None;
+            }
+        }
+    }
+
+    pub fn is_immutable(&self) -> bool {
+        self.immutable.unwrap_or(false)
+    }
+
+    pub fn is_burn_address(&self, account_key: &AccountKey) -> bool {
+        self.burn_addresses.iter().flatten().any(|a| a.to_key() == *account_key)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        use candid::Encode;
+        Encode!(self).expect("Token metadata is always encodable")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        use candid::Decode;
+        Decode!(bytes, Self).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredRecovery {
+    pub recovery_principal: Principal,
+    pub delay_ns: u64,
+    pub initiated_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingFeeChange {
+    pub id: u64,
+    pub token_id: TokenId,
+    pub previous_fee: u128,
+    pub new_fee: u128,
+    pub proposed_by: Principal,
+    pub proposed_at: u64,
+    pub expires_at: u64,
+}
+
+// A loosened threshold waiting for a second controller.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingThresholdChange {
+    pub threshold_bps: Option<u32>,
+    pub proposed_by: Principal,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct StoredFeeGovernance {
+    pub threshold_bps: Option<u32>,
+    pub pending_threshold: Option<PendingThresholdChange>,
+    pub next_id: u64,
+    pub pending: Vec<PendingFeeChange>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct StoredMigrationProgress {
+    pub cursor: Option<Vec<u8>>,
+    pub processed: u64,
+    pub started_at: Option<u64>,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct StoredMaintenanceTask {
+    pub cursor: Option<Vec<u8>>,
+    pub paused: bool,
+    pub last_run: Option<u64>,
+    pub last_run_items: u64,
+    pub items_processed: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct StoredMaintenanceTick {
+    pub last_tick_at: Option<u64>,
+    pub last_tick_instructions: u64,
+    // Index of the task that goes first on the next tick.
+    pub next_task: u32,
+}
+
+// Stable maps compact_structure can rebuild into a fresh memory region.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructureId {
+    DedupMap,
+    Allowances,
+    AllowanceExpiry,
+    AllowanceExpiryQueue,
+    SpenderAllowances,
+    OwnerAllowances,
+    HolderCountHistory,
+}
+
+impl StructureId {
+    pub const ALL: [StructureId; 7] = [
+        StructureId::DedupMap,
+        StructureId::Allowances,
+        StructureId::AllowanceExpiry,
+        StructureId::AllowanceExpiryQueue,
+        StructureId::SpenderAllowances,
+        StructureId::OwnerAllowances,
+        StructureId::HolderCountHistory,
+    ];
+
+    // The id the structure was created under. It names the structure in the
+    // binding table even after a compaction has moved it elsewhere.
+    pub fn home_memory_id(self) -> u8 {
+        match self {
+            StructureId::DedupMap => memory_ids::DEDUP_MAP,
+            StructureId::Allowances => memory_ids::ALLOWANCE_STORAGE,
+            StructureId::AllowanceExpiry => memory_ids::ALLOWANCE_EXPIRY_INDEX,
+            StructureId::AllowanceExpiryQueue => memory_ids::ALLOWANCE_EXPIRY_QUEUE,
+            StructureId::SpenderAllowances => memory_ids::SPENDER_ALLOWANCES,
+            StructureId::OwnerAllowances => memory_ids::OWNER_ALLOWANCES,
+            StructureId::HolderCountHistory => memory_ids::HOLDER_COUNT_HISTORY,
+        }
+    }
+}
+
+// A compaction in progress: live entries up to `cursor` have been copied into
+// `region`, and later writes to them are repeated there.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredCompaction {
+    pub target: StructureId,
+    pub region: u8,
+    // Encoded key of the last entry copied.
+    pub cursor: Option<Vec<u8>>,
+    pub copied: u64,
+    pub started_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AdminAuditEntry {
+    pub index: u64,
+    pub caller: Principal,
+    pub timestamp: u64,
+    pub action: String,
+    pub detail: String,
+}
+
+impl Storable for AdminAuditEntry {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Audit entries are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// An allowance force-expired by a controller. The amount is left in place.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AdminExpiredAllowance {
+    pub audit_index: u64,
+    pub token_id: TokenId,
+    pub owner: Account,
+    pub spender: Account,
+    pub amount: u128,
+    pub previous_expiry: Option<u64>,
+    pub expired_at: u64,
+    pub caller: Principal,
+    pub reason: String,
+}
+
+impl Storable for AdminExpiredAllowance {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Expired allowance records are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Which tokens an account accepts. DenyUnknown only accepts tokens the account
+// already holds a balance of.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum IncomingPolicy {
+    #[default]
+    AcceptAll,
+    DenyUnknown,
+    Allowlist(Vec<TokenId>),
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct IncomingPolicyConfig {
+    pub policy: IncomingPolicy,
+    // Lets controller mints through regardless of `policy`.
+    pub allow_controller_mints: bool,
+}
+
+impl IncomingPolicyConfig {
+    pub fn accepts(&self, token_id: &TokenId, holds_token: bool, controller_mint: bool) -> bool {
+        if controller_mint && self.allow_controller_mints {
+            return true;
+        }
+        match &self.policy {
+            IncomingPolicy::AcceptAll => true,
+            IncomingPolicy::DenyUnknown => holds_token,
+            IncomingPolicy::Allowlist(tokens) => tokens.contains(token_id),
+        }
+    }
+}
+
+impl Storable for IncomingPolicyConfig {
     const BOUND: ic_stable_structures::storable::Bound =
         ic_stable_structures::storable::Bound::Unbounded;
 
     fn to_bytes(&self) -> Cow<'_, [u8]> {
         use candid::Encode;
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(Encode!(self).expect("Incoming policies are always encodable"))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
         use candid::Decode;
         Decode!(bytes.as_ref(), Self).unwrap()
     }
-}
\ No newline at end of file
+}
+
+
+// An account the ledger's operators vouch for, such as a fee recipient or the
+// protocol treasury. See accounts::set_official_account.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OfficialAccount {
+    pub account: Account,
+    // Only tokens whose controller is this principal may be credited while
+    // `protected` is set.
+    pub administrator: Principal,
+    pub protected: bool,
+    pub registered_at: u64,
+}
+
+
+// How a deprecated endpoint treats callers while clients move off it; see
+// queries::set_deprecation_mode.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DeprecationMode {
+    #[default]
+    Allowed,
+    // Succeeds, but each call is counted and noted in the audit log.
+    Warn,
+    Blocked,
+}
+
+
+// User-facing operations a controller can pause one by one. Each endpoint
+// belongs to exactly one kind; see operations::pause_ops.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    Transfer,
+    Approve,
+    TransferFrom,
+    Burn,
+    Mint,
+    Disburse,
+}
+
+impl OpKind {
+    pub const ALL: [OpKind; 6] = [
+        OpKind::Transfer, OpKind::Approve, OpKind::TransferFrom, OpKind::Burn, OpKind::Mint, OpKind::Disburse,
+    ];
+
+    // Position in the paused-operations bitmask. Stored, so never reorder.
+    pub fn bit(self) -> u64 {
+        1 << match self {
+            OpKind::Transfer => 0,
+            OpKind::Approve => 1,
+            OpKind::TransferFrom => 2,
+            OpKind::Burn => 3,
+            OpKind::Mint => 4,
+            OpKind::Disburse => 5,
+        }
+    }
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ApiPermissions {
+    pub can_mint: bool,
+    pub can_query_private: bool,
+    // Disburse from the granter's own accounts.
+    pub can_disburse: bool,
+}
+
+impl ApiPermissions {
+    pub fn is_empty(&self) -> bool {
+        !(self.can_mint || self.can_query_private || self.can_disburse)
+    }
+
+    pub fn within(&self, authority: &ApiPermissions) -> bool {
+        (!self.can_mint || authority.can_mint)
+            && (!self.can_query_private || authority.can_query_private)
+            && (!self.can_disburse || authority.can_disburse)
+    }
+}
+
+
+// A backend principal's access to one token, on behalf of whoever granted it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApiAccessGrant {
+    pub principal: Principal,
+    pub permissions: ApiPermissions,
+    pub granted_by: Principal,
+    pub granted_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl ApiAccessGrant {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+impl Storable for ApiAccessGrant {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("API access grants are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+// A token's name and description in one locale. Kept beside the metadata
+// rather than in it, so translations do not grow every metadata read.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenLocalization {
+    pub locale: String,
+    pub name: String,
+    // The default description shows through when absent.
+    pub description: Option<String>,
+}
+
+impl Storable for TokenLocalization {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Token localizations are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+// An open import of a token from another ledger: the supply it declared and
+// what import_balances has set so far. Deleted when the import is finalized.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenImport {
+    pub declared_supply: u128,
+    pub imported_supply: u128,
+    // Accounts with a non-zero imported balance.
+    pub accounts: u64,
+    pub opened_at: u64,
+}
+
+impl Storable for TokenImport {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Token imports are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+// A hold on part of an account's balance. Debits may not touch held funds
+// until the hold is captured, released or expires.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Reservation {
+    pub id: u64,
+    pub token_id: TokenId,
+    pub account: Account,
+    pub amount: u128,
+    pub reference: Option<Vec<u8>>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl Reservation {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+}
+
+impl Storable for Reservation {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Reservations are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+// One token's balances as they stood when the snapshot was opened. Holders are
+// copied in chunks; until `complete`, a balance the copy has not reached yet
+// is saved as it was before its first change. `cursor` is the last holder
+// copied.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceSnapshot {
+    pub id: u64,
+    pub token_id: TokenId,
+    pub taken_at: u64,
+    // Transactions before the snapshot; it holds the balances after the last.
+    pub tx_count: u64,
+    pub holders: u64,
+    pub total_supply: u128,
+    pub cursor: Option<AccountKey>,
+    pub complete: bool,
+}
+
+impl Storable for BalanceSnapshot {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Balance snapshots are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+// Rewards minted to the holders of a snapshot, numerator/denominator reward
+// units per source unit, rounded down. Kept after completion so the same
+// snapshot and reward token cannot be paid twice.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Airdrop {
+    pub snapshot_id: u64,
+    pub source_token: TokenId,
+    pub reward_token: TokenId,
+    pub numerator: u128,
+    pub denominator: u128,
+    pub min_holding: u128,
+    // The last snapshot entry handled; None before the first chunk.
+    pub cursor: Option<AccountKey>,
+    pub recipients: u64,
+    // Below min_holding, refused by their incoming policy, or owed less than one unit.
+    pub skipped: u64,
+    pub total_minted: u128,
+    // What rounding down withheld, in whole reward units and in 1/denominator units.
+    pub dust: u128,
+    pub dust_remainder: u128,
+    pub started_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for Airdrop {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Airdrops are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+// A fee budget set aside from the sponsor's balance for the beneficiary's
+// transfers. `budget` is what is left of it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Sponsorship {
+    pub id: u64,
+    pub token_id: TokenId,
+    pub sponsor: Account,
+    pub beneficiary: Account,
+    pub budget: u128,
+    pub spent: u128,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl Sponsorship {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+impl Storable for Sponsorship {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Sponsorships are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+
+// What a burn links to outside the ledger, e.g. the release of a bridged asset.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredBurnReceipt {
+    pub burned_by: Principal,
+    pub external_ref: Option<Vec<u8>>,
+    // Set once, by attach_external_proof.
+    pub proof: Option<Vec<u8>>,
+    pub proof_attached_at: Option<u64>,
+}
+
+impl Storable for StoredBurnReceipt {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Burn receipts are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// One failed update call in the failure log. Fixed-size so the ring buffer's
+// memory is bounded by its slot count; the error label is cut to fit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredFailure {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub caller: StoredPrincipal,
+    pub operation: u8,
+    pub token_id: TokenId,
+    pub error: String,
+    pub args_digest: [u8; 8],
+    // Only while the failure log records amounts.
+    pub amount: Option<u128>,
+}
+
+impl StoredFailure {
+    pub const MAX_ERROR_LEN: usize = 32;
+    const SIZE: usize = 137;
+}
+
+impl Storable for StoredFailure {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: Self::SIZE as u32,
+            is_fixed_size: true
+        };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = [0u8; Self::SIZE];
+        buf[0..8].copy_from_slice(&self.seq.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[16..46].copy_from_slice(&self.caller.to_bytes());
+        buf[46] = self.operation;
+        buf[47..79].copy_from_slice(&self.token_id);
+        let error = &self.error.as_bytes()[..self.error.len().min(Self::MAX_ERROR_LEN)];
+        buf[79] = error.len() as u8;
+        buf[80..80 + error.len()].copy_from_slice(error);
+        buf[112..120].copy_from_slice(&self.args_digest);
+        if let Some(amount) = self.amount {
+            buf[120] = 1;
+            buf[121..137].copy_from_slice(&amount.to_be_bytes());
+        }
+        Cow::Owned(buf.to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        assert_eq!(bytes.len(), Self::SIZE, "StoredFailure must be exactly 137 bytes");
+        let error_len = (bytes[79] as usize).min(Self::MAX_ERROR_LEN);
+        Self {
+            seq: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            caller: StoredPrincipal::from_bytes(Cow::Borrowed(&bytes[16..46])),
+            operation: bytes[46],
+            token_id: bytes[47..79].try_into().unwrap(),
+            error: String::from_utf8_lossy(&bytes[80..80 + error_len]).into_owned(),
+            args_digest: bytes[112..120].try_into().unwrap(),
+            amount: (bytes[120] == 1).then(|| u128::from_be_bytes(bytes[121..137].try_into().unwrap())),
+        }
+    }
+}
+
+// One settle_net call. entries_hash is also the memo of the net transfer, if any.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SettlementRecord {
+    pub index: u64,
+    pub token_id: TokenId,
+    pub initiator: Account,
+    pub counterparty: Account,
+    pub entries_hash: Vec<u8>,
+    pub entry_count: u64,
+    pub gross_to_initiator: u128,
+    pub gross_from_initiator: u128,
+    pub tx_index: Option<u64>,
+    pub timestamp: u64,
+}
+
+impl Storable for SettlementRecord {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        use candid::Encode;
+        Cow::Owned(Encode!(self).expect("Settlement records are always encodable"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        use candid::Decode;
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for StoredTokenMetadata {
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.encode())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::decode(bytes.as_ref()).unwrap()
+    }
+}
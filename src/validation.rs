@@ -1,4 +1,5 @@
 use crate::types::{Account, TokenId, AccountKey};
+use crate::runtime;
 use candid::Principal;
 
 
@@ -11,6 +12,7 @@ pub enum ValidationError {
     InvalidMemo(String),
     InvalidFee(String),
     InvalidTimestamp(String),
+    ExceedsEconomicLimit { field: &'static str, limit: u128 },
 }
 
 impl std::fmt::Display for ValidationError {
@@ -23,6 +25,7 @@ impl std::fmt::Display for ValidationError {
             ValidationError::InvalidMemo(msg) => write!(f, "Invalid memo: {}", msg),
             ValidationError::InvalidFee(msg) => write!(f, "Invalid fee: {}", msg),
             ValidationError::InvalidTimestamp(msg) => write!(f, "Invalid timestamp: {}", msg),
+            ValidationError::ExceedsEconomicLimit { field, limit } => write!(f, "{} exceeds the limit of {}", field, limit),
         }
     }
 }
@@ -86,14 +89,14 @@ pub fn validate_approve_fee(_fee: u128) -> Result<(), ValidationError> {
 
 pub fn validate_memo(memo: &[u8]) -> Result<(), ValidationError> {
 
-    if memo.len() > 65536 {
+    if memo.len() > crate::types::constants::MAX_MEMO_LEN {
         return Err(ValidationError::InvalidMemo(
             format!("Memo size {} exceeds 64KB limit", memo.len())
         ));
     }
     
 
-    if memo.len() > 0 && memo.len() <= 1024 {
+    if !memo.is_empty() && memo.len() <= 1024 {
 
         if let Ok(text) = std::str::from_utf8(memo) {
             if text.contains('\0') {
@@ -119,6 +122,17 @@ pub fn validate_token_id(token_id: &TokenId) -> Result<(), ValidationError> {
 }
 
 
+pub fn validate_token_namespace(namespace: &[u8]) -> Result<(), ValidationError> {
+    if namespace.is_empty() || namespace.len() > 64 {
+        return Err(ValidationError::InvalidTokenId(
+            format!("Namespace length {} not in range 1-64", namespace.len())
+        ));
+    }
+
+    Ok(())
+}
+
+
 pub fn validate_account_key(account_key: &AccountKey) -> Result<(), ValidationError> {
     if account_key == &[0u8; 32] {
         return Err(ValidationError::InvalidAccount(
@@ -139,8 +153,8 @@ pub fn validate_timestamp(timestamp: u64) -> Result<(), ValidationError> {
         ));
     }
 
-    let current_time = ic_cdk::api::time();
-    if timestamp > current_time + crate::types::constants::MAX_FUTURE_DRIFT {
+    let current_time = runtime::time();
+    if timestamp > current_time + crate::state::max_future_drift() {
         return Err(ValidationError::InvalidTimestamp(
             "Timestamp too far in the future".to_string()
         ));
@@ -150,6 +164,62 @@ pub fn validate_timestamp(timestamp: u64) -> Result<(), ValidationError> {
 }
 
 
+// The newest args_version this ledger understands. Bump it with every change
+// that gives an args struct a field with meaning, so a client relying on that
+// field is refused by older ledgers instead of having it silently dropped.
+// 1: private_memo on transfer, approve and transfer_from; namespace on create_token_with_args.
+// 2: amount_mode on transfer.
+pub const ARGS_VERSION: u16 = 2;
+
+
+// Err carries the supported version. Absent means the client predates
+// versioning and relies on nothing newer than version 0.
+pub fn check_args_version(args_version: Option<u16>) -> Result<(), u16> {
+    match args_version {
+        Some(version) if version > ARGS_VERSION => Err(ARGS_VERSION),
+        _ => Ok(()),
+    }
+}
+
+
+pub const MAX_DECIMALS: u8 = 18;
+pub const HIGH_PRECISION_DECIMALS: u8 = 12;
+// Clients scale by 10^decimals and fee math multiplies by basis points, so
+// high-precision tokens keep six decimal digits of headroom below u128::MAX.
+pub const HIGH_PRECISION_SUPPLY_LIMIT: u128 = u128::MAX / 1_000_000;
+
+
+// Checked when a token is created and whenever its supply cap is set or raised.
+pub fn validate_token_economics(
+    decimals: u8,
+    fee: u128,
+    initial_supply: u128,
+    max_supply: Option<u128>,
+) -> Result<(), ValidationError> {
+    if decimals > MAX_DECIMALS {
+        return Err(ValidationError::ExceedsEconomicLimit { field: "decimals", limit: MAX_DECIMALS as u128 });
+    }
+
+    let limit = if decimals >= HIGH_PRECISION_DECIMALS { HIGH_PRECISION_SUPPLY_LIMIT } else { u128::MAX };
+    if initial_supply > limit {
+        return Err(ValidationError::ExceedsEconomicLimit { field: "initial_supply", limit });
+    }
+    if fee > limit {
+        return Err(ValidationError::ExceedsEconomicLimit { field: "fee", limit });
+    }
+    if let Some(max_supply) = max_supply {
+        if max_supply > limit {
+            return Err(ValidationError::ExceedsEconomicLimit { field: "max_supply", limit });
+        }
+        if initial_supply > max_supply {
+            return Err(ValidationError::ExceedsEconomicLimit { field: "initial_supply", limit: max_supply });
+        }
+    }
+
+    Ok(())
+}
+
+
 pub fn validate_admin_principal(principal: &Principal) -> Result<(), ValidationError> {
     if *principal == Principal::anonymous() {
         return Err(ValidationError::InvalidPrincipal(
@@ -194,12 +264,8 @@ pub fn validate_transfer_params(
         validate_memo(memo_data)?;
     }
 
-    if from == to {
-        return Err(ValidationError::InvalidAccount(
-            "Cannot transfer to same account".to_string()
-        ));
-    }
-
+    // Sending to the same account is allowed, as in ICRC-1: it pays the fee
+    // and is recorded like any other transfer.
     Ok(())
 }
 
@@ -213,7 +279,9 @@ pub fn validate_approve_params(
 ) -> Result<(), ValidationError> {
     validate_account(owner)?;
     validate_account(spender)?;
-    validate_amount(amount, true)?;
+    if amount != crate::types::constants::UNLIMITED_ALLOWANCE {
+        validate_amount(amount, true)?;
+    }
 
     if let Some(fee_amount) = fee {
         validate_approve_fee(fee_amount)?;
@@ -223,7 +291,7 @@ pub fn validate_approve_params(
         validate_memo(memo_data)?;
     }
 
-    if owner == spender {
+    if owner.canonical_eq(spender) {
         return Err(ValidationError::InvalidAccount(
             "Cannot approve spending to self".to_string()
         ));
@@ -338,6 +406,36 @@ mod tests {
         assert!(validate_token_id(&zero_id).is_err());
     }
 
+    #[test]
+    fn test_validate_token_namespace() {
+        assert!(validate_token_namespace(b"bridge/sol/usdc").is_ok());
+        assert!(validate_token_namespace(&[7u8; 64]).is_ok());
+        assert!(validate_token_namespace(&[7u8; 65]).is_err());
+        assert!(validate_token_namespace(&[]).is_err());
+    }
+
+    #[test]
+    fn test_validate_token_economics() {
+        let limit = HIGH_PRECISION_SUPPLY_LIMIT;
+        assert!(validate_token_economics(11, u128::MAX, u128::MAX, None).is_ok());
+        assert!(validate_token_economics(12, limit, limit, Some(limit)).is_ok());
+        assert_eq!(
+            validate_token_economics(12, 0, limit + 1, None),
+            Err(ValidationError::ExceedsEconomicLimit { field: "initial_supply", limit })
+        );
+        assert_eq!(
+            validate_token_economics(18, limit + 1, 0, None),
+            Err(ValidationError::ExceedsEconomicLimit { field: "fee", limit })
+        );
+        assert_eq!(
+            validate_token_economics(12, 0, 0, Some(limit + 1)),
+            Err(ValidationError::ExceedsEconomicLimit { field: "max_supply", limit })
+        );
+        assert!(validate_token_economics(6, 0, 101, Some(100)).is_err());
+        assert!(validate_token_economics(18, 0, 0, None).is_ok());
+        assert!(validate_token_economics(19, 0, 0, None).is_err());
+    }
+
     #[test]
     fn test_validate_transfer_params() {
         let principal_bytes1 = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xD2];
@@ -353,7 +451,19 @@ mod tests {
         };
         
         assert!(validate_transfer_params(&from, &to, 1000, Some(10), None).is_ok());
-        assert!(validate_transfer_params(&from, &from, 1000, Some(10), None).is_err());
+        assert!(validate_transfer_params(&from, &from, 1000, Some(10), None).is_ok());
         assert!(validate_transfer_params(&from, &to, 0, Some(10), None).is_err());
+
+        // No subaccount and the zero subaccount are the same account.
+        let from_zero = Account { owner: from.owner, subaccount: Some(vec![0u8; 32]) };
+        let from_named = Account { owner: from.owner, subaccount: Some(vec![1u8; 32]) };
+        assert!(from.canonical_eq(&from_zero) && from_zero.canonical_eq(&from));
+        assert!(!from.canonical_eq(&from_named) && !from.canonical_eq(&to));
+        assert_eq!((from.named_subaccount(), from_zero.named_subaccount()), (None, None));
+        assert!(validate_transfer_params(&from, &from_zero, 1000, Some(10), None).is_ok());
+        assert!(validate_transfer_params(&from, &from_named, 1000, Some(10), None).is_ok());
+        assert!(validate_approve_params(&from, &from_zero, 1000, None, None).is_err());
+        assert!(validate_approve_params(&from_zero, &from, 1000, None, None).is_err());
+        assert!(validate_approve_params(&from, &from_named, 1000, None, None).is_ok());
     }
 }
\ No newline at end of file
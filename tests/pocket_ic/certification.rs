@@ -0,0 +1,57 @@
+use icrc151::maintenance::{MaintenanceTaskStatus, CERTIFIED_BALANCES};
+use icrc151::verification::{certified_tip, verify_balance_certificate};
+use icrc151::{CertifiedBalance, InitArgs, QueryError, RangeCommitment, TokenId, TransferResult};
+
+use crate::common::{account, principal, token_args, Ledger};
+
+
+// Real certificates, signed by the PocketIC subnet, checked with the
+// off-chain kit against the replica's root key.
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn balance_certificates_verify_offline() {
+    let ledger = Ledger::install(Some(InitArgs {
+        tokens: vec![token_args("CERT", Some(1_000_000))],
+        ..Default::default()
+    }));
+    let (tokens,): (Vec<TokenId>,) = ledger.query("list_tokens", ());
+    let token = tokens[0];
+    assert!(matches!(ledger.transfer(ledger.admin, token, principal(2), 250), TransferResult::Ok(_)));
+    let root_key = ledger.pic.root_key().expect("PocketIC exposes its root key");
+
+    let check_balances = || for owner in [ledger.admin, principal(2), principal(3)] {
+        let balance = ledger.balance(token, owner);
+        let (proof,): (Result<CertifiedBalance, QueryError>,) =
+            ledger.query("get_balance_with_certificate", (token, account(owner)));
+        let proof = proof.unwrap();
+        assert_eq!(proof.balance, candid::Nat::from(balance));
+        let verify = |balance| verify_balance_certificate(
+            &proof.certificate, &proof.witness, &root_key, ledger.id, &account(owner), &token, balance,
+        );
+        assert_eq!(verify(balance), Ok(()));
+        assert!(verify(balance + 1).is_err());
+    };
+    let check_tip = || {
+        let (count,): (u64,) = ledger.query("get_transaction_count", ());
+        let (commitment,): (Result<RangeCommitment, QueryError>,) = ledger.query("get_range_commitment", (count, count));
+        let commitment = commitment.unwrap();
+        let tip = certified_tip(
+            commitment.certificate.as_deref().unwrap(), commitment.witness.as_deref().unwrap(), &root_key, ledger.id,
+        );
+        assert_eq!(tip, Ok(commitment.end_hash));
+    };
+    check_balances();
+    check_tip();
+
+    // An upgrade certifies the tip at once and the balances once the
+    // maintenance task has refilled the tree.
+    ledger.upgrade(None).unwrap();
+    let (pending,): (Result<CertifiedBalance, QueryError>,) =
+        ledger.query("get_balance_with_certificate", (token, account(ledger.admin)));
+    assert!(matches!(pending, Err(QueryError::MigrationPending { migration }) if migration == CERTIFIED_BALANCES));
+    check_tip();
+    let (status,): (Result<MaintenanceTaskStatus, String>,) =
+        ledger.update(ledger.admin, "run_maintenance_task", (CERTIFIED_BALANCES.to_string(),));
+    assert_eq!(status.unwrap().backlog, 0);
+    check_balances();
+}
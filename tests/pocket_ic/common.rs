@@ -0,0 +1,107 @@
+use std::io::Write;
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, encode_one, Principal};
+use flate2::{write::GzEncoder, Compression};
+use icrc151::{Account, CreateTokenArgs, Icrc151TransferArgs, InitArgs, TokenId, TransferResult};
+use pocket_ic::{PocketIc, RejectResponse};
+
+const DEFAULT_WASM: &str = "target/wasm32-unknown-unknown/release/icrc151.wasm";
+const INITIAL_CYCLES: u128 = 100_000_000_000_000;
+
+
+// The uncompressed release wasm is over the 2 MiB install limit.
+fn wasm() -> Vec<u8> {
+    let path = std::env::var("ICRC151_WASM")
+        .unwrap_or_else(|_| format!("{}/{}", env!("CARGO_MANIFEST_DIR"), DEFAULT_WASM));
+    let bytes = std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+
+pub fn principal(id: u8) -> Principal {
+    Principal::self_authenticating([id; 32])
+}
+
+
+pub fn account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+
+pub fn token_args(symbol: &str, initial_supply: Option<u64>) -> CreateTokenArgs {
+    CreateTokenArgs {
+        name: format!("{} Token", symbol),
+        symbol: symbol.to_string(),
+        decimals: 8,
+        initial_supply: initial_supply.map(candid::Nat::from),
+        fee: None,
+        logo: None,
+        description: None,
+        namespace: Some(symbol.as_bytes().to_vec()),
+        args_version: None,
+    }
+}
+
+
+// A ledger installed by `admin`, which is also its only IC controller.
+pub struct Ledger {
+    pub pic: PocketIc,
+    pub id: Principal,
+    pub admin: Principal,
+    wasm: Vec<u8>,
+}
+
+impl Ledger {
+    pub fn install(args: Option<InitArgs>) -> Self {
+        let pic = PocketIc::new();
+        let admin = principal(1);
+        let id = pic.create_canister_with_settings(Some(admin), None);
+        pic.add_cycles(id, INITIAL_CYCLES);
+        let wasm = wasm();
+        pic.install_canister(id, wasm.clone(), encode_one(args).unwrap(), Some(admin));
+        Ledger { pic, id, admin, wasm }
+    }
+
+    pub fn upgrade(&self, args: Option<InitArgs>) -> Result<(), RejectResponse> {
+        self.pic.upgrade_canister(self.id, self.wasm.clone(), encode_one(args).unwrap(), Some(self.admin))
+    }
+
+    pub fn update<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(&self, sender: Principal, method: &str, args: A) -> R {
+        let reply = self.pic.update_call(self.id, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{} rejected: {:?}", method, e));
+        decode_args(&reply).unwrap()
+    }
+
+    pub fn query<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(&self, method: &str, args: A) -> R {
+        let reply = self.pic.query_call(self.id, self.admin, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{} rejected: {:?}", method, e));
+        decode_args(&reply).unwrap()
+    }
+
+    pub fn balance(&self, token_id: TokenId, owner: Principal) -> u128 {
+        let (balance,): (Result<u128, icrc151::QueryError>,) =
+            self.query("get_balance", (token_id, account(owner), None::<u64>));
+        balance.unwrap()
+    }
+
+    pub fn transfer(&self, from: Principal, token_id: TokenId, to: Principal, amount: u64) -> TransferResult {
+        let args = Icrc151TransferArgs {
+            token_id,
+            from_subaccount: None,
+            to: account(to),
+            amount: candid::Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+            private_memo: None,
+            amount_mode: None,
+            use_sponsorship: None,
+            args_version: None,
+        };
+        let (result,): (TransferResult,) = self.update(from, "transfer", (args,));
+        result
+    }
+}
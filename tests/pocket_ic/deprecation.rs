@@ -0,0 +1,105 @@
+use icrc151::{AdminAuditEntry, CreateTokenError, Deprecation, DeprecationMode, LegacyCallCount, TokenId};
+
+use crate::common::{principal, token_args, Ledger};
+
+
+fn set_mode(ledger: &Ledger, mode: DeprecationMode) {
+    let (result,): (Result<(), String>,) =
+        ledger.update(ledger.admin, "set_deprecation_mode", ("create_token".to_string(), mode));
+    result.unwrap();
+}
+
+fn mode(ledger: &Ledger) -> DeprecationMode {
+    let (deprecations,): (Vec<Deprecation>,) = ledger.query("get_deprecations", ());
+    deprecations.into_iter().find(|d| d.method == "create_token").unwrap().mode
+}
+
+fn counts(ledger: &Ledger) -> (u64, u64, u64) {
+    let (counts,): (Result<Vec<LegacyCallCount>, String>,) = ledger.query("get_legacy_call_counts", ());
+    counts.unwrap().into_iter()
+        .find(|c| c.method == "create_token")
+        .map_or((0, 0, 0), |c| (c.count, c.warned, c.blocked))
+}
+
+fn last_audit_entry(ledger: &Ledger) -> AdminAuditEntry {
+    let (entries,): (Vec<AdminAuditEntry>,) = ledger.query("get_admin_audit_log", (0u64, 1_000u64));
+    entries.into_iter().last().unwrap()
+}
+
+// The 7 positional arguments of the legacy endpoint.
+fn legacy(ledger: &Ledger, symbol: &str) -> Result<TokenId, CreateTokenError> {
+    let (result,): (Result<TokenId, CreateTokenError>,) = ledger.update(
+        ledger.admin,
+        "create_token",
+        (
+            format!("{} Token", symbol),
+            symbol.to_string(),
+            8u8,
+            Some(candid::Nat::from(1_000u64)),
+            None::<candid::Nat>,
+            None::<String>,
+            None::<String>,
+        ),
+    );
+    result
+}
+
+fn with_args(ledger: &Ledger, symbol: &str) -> Result<TokenId, CreateTokenError> {
+    let (result,): (Result<TokenId, CreateTokenError>,) =
+        ledger.update(ledger.admin, "create_token_with_args", (token_args(symbol, Some(1_000)),));
+    result
+}
+
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn allowed_legacy_create_token_matches_create_token_with_args() {
+    let ledger = Ledger::install(None);
+    assert_eq!(mode(&ledger), DeprecationMode::Allowed);
+
+    let legacy_token = legacy(&ledger, "OLD").unwrap();
+    let token = with_args(&ledger, "NEW").unwrap();
+    assert_eq!(ledger.balance(legacy_token, ledger.admin), 1_000);
+    assert_eq!(ledger.balance(token, ledger.admin), 1_000);
+    assert_eq!(counts(&ledger), (1, 0, 0));
+}
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn warn_succeeds_counts_and_notes_the_call() {
+    let ledger = Ledger::install(None);
+    set_mode(&ledger, DeprecationMode::Warn);
+    assert_eq!(mode(&ledger), DeprecationMode::Warn);
+
+    let token = legacy(&ledger, "OLD").unwrap();
+    assert_eq!(ledger.balance(token, ledger.admin), 1_000);
+    assert_eq!(counts(&ledger), (1, 1, 0));
+    let note = last_audit_entry(&ledger);
+    assert_eq!(note.action, "create_token");
+    assert!(note.detail.contains("create_token_with_args"));
+}
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn blocked_refuses_with_the_replacement_and_survives_an_upgrade() {
+    let ledger = Ledger::install(None);
+    set_mode(&ledger, DeprecationMode::Blocked);
+    ledger.upgrade(None).unwrap();
+    assert_eq!(mode(&ledger), DeprecationMode::Blocked);
+
+    assert_eq!(
+        legacy(&ledger, "OLD"),
+        Err(CreateTokenError::DeprecatedEndpoint { use_instead: "create_token_with_args".to_string() })
+    );
+    assert_eq!(counts(&ledger), (1, 0, 1));
+    let (tokens,): (Vec<TokenId>,) = ledger.query("list_tokens", ());
+    assert!(tokens.is_empty());
+    with_args(&ledger, "NEW").unwrap();
+
+    // Only controllers may change the mode.
+    let (result,): (Result<(), String>,) =
+        ledger.update(principal(2), "set_deprecation_mode", ("create_token".to_string(), DeprecationMode::Allowed));
+    assert!(result.is_err());
+    set_mode(&ledger, DeprecationMode::Allowed);
+    legacy(&ledger, "OLD").unwrap();
+}
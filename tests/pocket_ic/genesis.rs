@@ -0,0 +1,102 @@
+use candid::Nat;
+use icrc151::{InitArgs, LedgerConfig, TokenId};
+
+use crate::common::{principal, token_args, Ledger};
+
+
+fn maintenance(ledger: &Ledger) -> bool {
+    let (on,): (bool,) = ledger.query("get_maintenance_mode", ());
+    on
+}
+
+fn default_token_fee(ledger: &Ledger) -> u128 {
+    let (config,): (LedgerConfig,) = ledger.query("get_ledger_config", ());
+    config.default_token_fee
+}
+
+fn controllers(ledger: &Ledger) -> Vec<candid::Principal> {
+    let (controllers,): (Vec<candid::Principal>,) = ledger.query("list_controllers", ());
+    controllers
+}
+
+fn tokens(ledger: &Ledger) -> Vec<TokenId> {
+    let (tokens,): (Vec<TokenId>,) = ledger.query("list_tokens", ());
+    tokens
+}
+
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn install_without_args_makes_the_installer_controller() {
+    let ledger = Ledger::install(None);
+    assert_eq!(controllers(&ledger), vec![ledger.admin]);
+    assert!(!maintenance(&ledger));
+    assert!(tokens(&ledger).is_empty());
+}
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn install_with_args_applies_them() {
+    let second = principal(2);
+    let ledger = Ledger::install(Some(InitArgs {
+        controllers: vec![principal(1), second],
+        default_token_fee: Some(Nat::from(5u64)),
+        maintenance: Some(true),
+        tokens: vec![token_args("GEN", Some(1_000))],
+        tx_window: None,
+    }));
+    assert_eq!(controllers(&ledger), vec![ledger.admin, second]);
+    assert!(maintenance(&ledger));
+    assert_eq!(default_token_fee(&ledger), 5);
+    let tokens = tokens(&ledger);
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(ledger.balance(tokens[0], ledger.admin), 1_000);
+
+    let (result,): (Result<(), String>,) = ledger.update(second, "set_maintenance_mode", (false,));
+    result.unwrap();
+    assert!(!maintenance(&ledger));
+}
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn upgrade_without_args_keeps_everything() {
+    let ledger = Ledger::install(Some(InitArgs {
+        default_token_fee: Some(Nat::from(5u64)),
+        maintenance: Some(true),
+        tokens: vec![token_args("GEN", Some(1_000))],
+        ..Default::default()
+    }));
+    ledger.upgrade(None).unwrap();
+    assert_eq!(controllers(&ledger), vec![ledger.admin]);
+    assert!(maintenance(&ledger));
+    assert_eq!(default_token_fee(&ledger), 5);
+    assert_eq!(ledger.balance(tokens(&ledger)[0], ledger.admin), 1_000);
+}
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn upgrade_changes_only_the_settings_it_names() {
+    let ledger = Ledger::install(Some(InitArgs { maintenance: Some(true), ..Default::default() }));
+
+    ledger.upgrade(Some(InitArgs { default_token_fee: Some(Nat::from(7u64)), ..Default::default() })).unwrap();
+    assert_eq!(default_token_fee(&ledger), 7);
+    assert!(maintenance(&ledger));
+
+    ledger.upgrade(Some(InitArgs { maintenance: Some(false), ..Default::default() })).unwrap();
+    assert!(!maintenance(&ledger));
+    assert_eq!(default_token_fee(&ledger), 7);
+}
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn upgrade_listing_tokens_is_rejected() {
+    let ledger = Ledger::install(None);
+    let rejected = ledger.upgrade(Some(InitArgs {
+        default_token_fee: Some(Nat::from(7u64)),
+        tokens: vec![token_args("LATE", None)],
+        ..Default::default()
+    }));
+    assert!(rejected.is_err());
+    assert!(tokens(&ledger).is_empty());
+    assert_ne!(default_token_fee(&ledger), 7);
+}
@@ -0,0 +1,13 @@
+// Canister-level tests against the release wasm in a PocketIC replica. They need
+// the wasm and a PocketIC server, so they are ignored by default:
+//
+//   cargo build --target wasm32-unknown-unknown --release
+//   POCKET_IC_BIN=/path/to/pocket-ic cargo test --test pocket_ic -- --ignored
+//
+// ICRC151_WASM overrides the wasm path.
+
+mod certification;
+mod common;
+mod deprecation;
+mod genesis;
+mod warm_up;
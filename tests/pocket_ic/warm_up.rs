@@ -0,0 +1,33 @@
+use icrc151::{InitArgs, TokenId, TransferResult};
+
+use crate::common::{principal, token_args, Ledger};
+
+
+// The figures are printed so a change in either shows up in the test output:
+//
+//   cargo test --test pocket_ic -- --ignored --nocapture warm_up
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server; run with --ignored"]
+fn first_transfer_after_upgrade_costs_what_the_next_one_does() {
+    let ledger = Ledger::install(Some(InitArgs {
+        tokens: vec![token_args("WARM", Some(1_000_000))],
+        ..Default::default()
+    }));
+    let (tokens,): (Vec<TokenId>,) = ledger.query("list_tokens", ());
+    let token = tokens[0];
+    // Gives every structure a transfer touches some data, as on a live ledger.
+    for to in 2..10 {
+        assert!(matches!(ledger.transfer(ledger.admin, token, principal(to), 100), TransferResult::Ok(_)));
+    }
+    ledger.upgrade(None).unwrap();
+
+    // Both credit a new account, so they do the same work.
+    let cycles = |to: u8| {
+        let before = ledger.pic.cycle_balance(ledger.id);
+        assert!(matches!(ledger.transfer(ledger.admin, token, principal(to), 100), TransferResult::Ok(_)));
+        before - ledger.pic.cycle_balance(ledger.id)
+    };
+    let (first, second) = (cycles(20), cycles(21));
+    println!("cycles burned after upgrade: first transfer {}, second transfer {}", first, second);
+    assert!(first <= second + second / 10, "first {} second {}", first, second);
+}